@@ -0,0 +1,65 @@
+//! Snapshot costs on a large terminal: replaying a filled 400x150 screen,
+//! following its output, rendering it and reformatting its text. Resizing
+//! a live session is covered by `test_large_terminal`.
+//!
+//! Run with `cargo bench --bench large_terminal`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use ht_mcp::ht_integration::snapshot::{format_for_ai, wrap_snapshot};
+use ht_mcp::ht_integration::styled_snapshot::{ScreenTracker, StyledFormat, StyledScreen};
+
+const COLS: usize = 400;
+const ROWS: usize = 150;
+
+/// Output scrolling the screen by 50 lines, every line full and colored
+fn fill_output() -> String {
+    let mut output = String::new();
+    for line in 0..ROWS + 50 {
+        output.push_str(&format!(
+            "\x1b[3{}m{:0width$}\x1b[0m\r\n",
+            line % 8,
+            line,
+            width = COLS
+        ));
+    }
+    output
+}
+
+fn large_terminal(c: &mut Criterion) {
+    let output = fill_output();
+    let screen = StyledScreen::replay(&output, COLS, ROWS);
+    let text = screen.to_text();
+
+    c.bench_function("replay 400x150", |b| {
+        b.iter(|| StyledScreen::replay(black_box(&output), COLS, ROWS))
+    });
+    c.bench_function("track 400x150 in 4 KiB chunks", |b| {
+        let chunks: Vec<&str> = output
+            .as_bytes()
+            .chunks(4096)
+            .map(|chunk| std::str::from_utf8(chunk).unwrap())
+            .collect();
+        b.iter(|| {
+            let mut tracker = ScreenTracker::blank(COLS, ROWS);
+            for chunk in &chunks {
+                tracker.feed(black_box(chunk));
+            }
+            tracker.cursor()
+        })
+    });
+    c.bench_function("render 400x150 as ansi", |b| {
+        b.iter(|| screen.render(black_box(StyledFormat::Ansi)))
+    });
+    c.bench_function("render 400x150 as structured", |b| {
+        b.iter(|| screen.render(black_box(StyledFormat::Structured)))
+    });
+    c.bench_function("ai-compress 400x150", |b| {
+        b.iter(|| format_for_ai(black_box(&text), 2))
+    });
+    c.bench_function("word-wrap 400x150 at 120", |b| {
+        b.iter(|| wrap_snapshot(black_box(&text), 120))
+    });
+}
+
+criterion_group!(benches, large_terminal);
+criterion_main!(benches);
@@ -0,0 +1,72 @@
+//! Driving a session from Rust with a `SessionHandle` and its output stream
+//!
+//! This example embeds a `SessionManager` directly, without the MCP layer:
+//! it runs a command through a session handle, prints the raw output live
+//! as it arrives, and waits for the command to finish.
+//!
+//! Run with: `cargo run --example session_stream`
+
+use futures::StreamExt;
+use ht_mcp::ht_integration::SessionManager;
+use ht_mcp::mcp::types::{CloseSessionArgs, CreateSessionArgs};
+use std::io::Write;
+use std::time::Duration;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut manager = SessionManager::new();
+    let created = manager
+        .create_session(CreateSessionArgs {
+            command: Some(vec!["bash".to_string()]),
+            ..CreateSessionArgs::default()
+        })
+        .await?;
+    let session_id = created["sessionId"]
+        .as_str()
+        .unwrap_or_default()
+        .to_string();
+    println!("🚀 Created session {}", session_id);
+
+    // The handle is bound to the session; clone it freely
+    let handle = manager.handle(&session_id)?;
+
+    // Print output as it arrives. The stream ends when the session closes.
+    let mut output = handle.output_stream()?;
+    let printer = tokio::spawn(async move {
+        while let Some(chunk) = output.next().await {
+            match chunk {
+                Ok(bytes) => {
+                    let mut stdout = std::io::stdout();
+                    let _ = stdout.write_all(&bytes);
+                    let _ = stdout.flush();
+                }
+                Err(missed) => eprintln!("\n⚠️  {}", missed),
+            }
+        }
+    });
+
+    handle
+        .send_keys(&[
+            "for i in 1 2 3; do echo \"step $i\"; sleep 0.5; done; echo all-done",
+            "Enter",
+        ])
+        .await?;
+    handle
+        .wait_for(r"(?m)^all-done", Duration::from_secs(10))
+        .await?;
+
+    println!(
+        "\n📸 Final screen:\n{}",
+        handle.snapshot().await?.trim_end()
+    );
+
+    manager
+        .close_session(CloseSessionArgs {
+            session_id,
+            timeout_ms: None,
+        })
+        .await?;
+    printer.await?;
+    println!("✅ Session closed");
+    Ok(())
+}
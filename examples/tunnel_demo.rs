@@ -14,8 +14,7 @@
 //! ```
 
 use ht_mcp::{TunnelConfig, TunnelManager};
-use tokio;
-use tracing::{error, info};
+use tracing::info;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -27,7 +26,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     info!("🚀 Cloudflared Tunnel Demo Starting");
 
     // Create a tunnel manager
-    let mut tunnel_manager = TunnelManager::new();
+    let tunnel_manager = TunnelManager::new();
 
     println!("\n📋 Step 1: Creating a tunnel for port 8080");
 
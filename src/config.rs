@@ -0,0 +1,1040 @@
+//! Server configuration loaded from a TOML file
+
+use crate::error::{HtMcpError, Result};
+use crate::ht_integration::aliases::CommandAliases;
+use crate::ht_integration::annotations::{AnnotationPatternConfig, Annotator};
+use crate::ht_integration::command_class::{CommandClassConfig, CommandClassifier};
+use crate::ht_integration::connection_guard::ConnectionLimits;
+use crate::ht_integration::flood::FloodConfig;
+use crate::ht_integration::retention::RetentionPolicy;
+use crate::ht_integration::screen_classifier::{ScreenClassifier, ScreenRuleConfig};
+use crate::ht_integration::session_manager::{DEFAULT_COLS, DEFAULT_ROWS, MAX_TERMINAL_SIZE};
+use crate::ht_integration::storage::StorageConfig;
+use crate::ht_integration::temp_dir::TempDirConfig;
+use crate::ht_integration::warm_pool::WarmPoolConfig;
+use crate::telemetry::TelemetryConfig;
+use crate::tunnel::config::{parse_log_level, OPT_IN_TUNNEL_PROVIDERS};
+use crate::tunnel::named::NamedTunnelConfig;
+use crate::tunnel::pool::TunnelPoolConfig;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+/// Environment variable pointing at the config file
+pub const CONFIG_ENV_VAR: &str = "HT_MCP_CONFIG";
+
+/// Environment variable the state subcommands take the `state_token` from,
+/// before looking in the config
+pub const STATE_TOKEN_ENV_VAR: &str = "HT_MCP_STATE_TOKEN";
+
+/// Project config file looked up in the workspace root
+pub const PROJECT_CONFIG_FILE: &str = ".ht-mcp.toml";
+
+/// Shown by `redacted` in place of sensitive values
+pub const REDACTED: &str = "<redacted>";
+
+/// Default for `concurrent_snapshots_per_session`
+pub const DEFAULT_CONCURRENT_SNAPSHOTS: usize = 5;
+
+/// Default for `max_cols`
+pub const DEFAULT_MAX_COLS: usize = 500;
+
+/// Default for `max_rows`
+pub const DEFAULT_MAX_ROWS: usize = 200;
+
+/// Upper bound for `reconnect_grace_secs`: a week
+pub const MAX_RECONNECT_GRACE_SECS: u64 = 7 * 24 * 60 * 60;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HtMcpConfig {
+    /// Place each session's PTY process in a cgroup v2 memory cgroup when the
+    /// session asks for a memory limit (Linux only)
+    pub use_cgroup_memory_limits: bool,
+    /// Default limits for connections to session web servers; sessions can
+    /// override them at creation
+    pub connection_limits: ConnectionLimits,
+    /// Address session web servers listen on, IPv4 or IPv6 (`::` listens
+    /// dual-stack). Defaults to loopback on both families.
+    pub bind_address: Option<IpAddr>,
+    /// Address of the management HTTP server (`/events` WebSocket); not
+    /// started when unset
+    pub management_address: Option<SocketAddr>,
+    /// Allow operator tools such as `ht_drain`
+    pub enable_admin_tools: bool,
+    /// Bearer token the management server's `/state` endpoints require;
+    /// they are off without it, even with `enable_admin_tools`
+    pub state_token: Option<String>,
+    /// Let `GET /state` export the values of sessions' env variables
+    /// (`includeEnv`), which often hold credentials
+    pub allow_state_env_export: bool,
+    /// Log cloudflared's output at `cloudflared_log_level` instead of debug
+    pub log_cloudflared_output: bool,
+    /// Level for cloudflared's output when logged; `info` when unset
+    pub cloudflared_log_level: Option<String>,
+    /// Check that a new tunnel's URL answers before handing it out
+    pub verify_tunnels: bool,
+    /// Local ports `ht_create_tunnel` may publish; any port may be with
+    /// `enable_admin_tools`
+    pub tunnel_ports: Vec<u16>,
+    /// Tunnel providers besides cloudflare that sessions may name: `bore`,
+    /// which carries traffic unencrypted, and `localhost.run`, which needs
+    /// `localhost_run_host_key`. `auto` never falls back to them.
+    pub tunnel_providers: Vec<String>,
+    /// localhost.run's SSH host key as a known_hosts line, or just its type
+    /// and key; ssh refuses a server presenting any other key
+    pub localhost_run_host_key: Option<String>,
+    /// Time limit for a tool call unless the call sets `_timeout_ms`
+    pub default_tool_timeout_ms: u64,
+    /// How long before a session's lease runs out a `LeaseExpiring` event
+    /// is published
+    pub lease_warning_secs: u64,
+    /// A session taking longer than this to print its first output or show
+    /// its prompt publishes a `SlowStartup` event; 0 disables the warning
+    pub slow_startup_warning_ms: u64,
+    /// OpenTelemetry export settings (used with the `otel` feature)
+    pub telemetry: TelemetryConfig,
+    /// Where `tempDir` sessions get their scratch directories
+    pub temp_dir: TempDirConfig,
+    /// Disk usage caps for recordings, session logs and audit files
+    pub storage: StorageConfig,
+    /// Strip terminal control sequences from the output kept in session
+    /// histories; rendering and output streams still get the raw bytes
+    pub strip_controls_in_history: bool,
+    /// Extra `ht_classify_screen` rules, checked before the built-in ones
+    pub screen_rules: Vec<ScreenRuleConfig>,
+    /// Extra patterns for snapshot annotations, used alongside the built-in
+    /// error, warning, stack trace and URL patterns
+    pub annotation_patterns: Vec<AnnotationPatternConfig>,
+    /// When a session's output counts as a flood of repeated lines
+    pub output_flood: FloodConfig,
+    /// Project directory of the editor that started the server. Sessions
+    /// start here and resolve a relative `cwd` against it, and its
+    /// `.ht-mcp.toml` is merged into this config.
+    pub workspace_root: Option<PathBuf>,
+    /// Environment variables set in every session
+    pub session_env: BTreeMap<String, String>,
+    /// Take `session_env` and `aliases` from the workspace's `.ht-mcp.toml`
+    /// too. Off by default: a checked-out repository could otherwise set
+    /// `PATH` or `LD_PRELOAD` for every session, or make a command such as
+    /// `ls` run something else.
+    pub trust_project_config: bool,
+    /// Allow sessions created with `traceMode`, which log everything typed
+    /// into and printed by the PTY, passwords included
+    pub allow_trace_mode: bool,
+    /// Files, or directories of files, that `ht_type_file` may read;
+    /// relative entries are taken from `workspace_root`
+    pub type_file_paths: Vec<PathBuf>,
+    /// Snapshot requests a session serves at once; more wait briefly for a
+    /// slot and then fail, so a burst of calls cannot swamp its event loop
+    pub concurrent_snapshots_per_session: usize,
+    /// Widest terminal a session may be created or resized to
+    pub max_cols: usize,
+    /// Tallest terminal a session may be created or resized to
+    pub max_rows: usize,
+    /// How much of its past each session keeps; sessions may lower these
+    /// limits but not raise them
+    pub retention: RetentionPolicy,
+    /// Where `ht_take_baseline_snapshot` stores baselines; defaults to
+    /// `ht-mcp-baselines-<pid>` in the system temp dir, so servers running
+    /// side by side keep apart
+    pub baseline_dir: Option<PathBuf>,
+    /// Sessions started ahead of time for `create_session` to hand out
+    pub warm_pool: WarmPoolConfig,
+    /// Tunnels started ahead of time for sessions created with
+    /// `enableTunnel`
+    pub tunnel_pool: TunnelPoolConfig,
+    /// Cloudflare named tunnels giving each session's tunnel a hostname of
+    /// its own that survives restarts; sessions get quick tunnels from the
+    /// tunnel pool instead when unset
+    pub named_tunnel: NamedTunnelConfig,
+    /// Tokens put in front of every session's command, to run sessions
+    /// elsewhere, e.g. `["docker", "exec", "-it", "mycontainer"]`
+    pub command_prefix: Option<Vec<String>>,
+    /// How long sessions outlive the MCP client once its connection closes
+    /// (stdin EOF) before the server closes them and exits; 0 shuts down at
+    /// once
+    pub reconnect_grace_secs: u64,
+    /// Extra `ht_execute_command` classes, checked before the built-in
+    /// ones and replacing built-in classes of the same name
+    pub command_classes: Vec<CommandClassConfig>,
+    /// Shorthand `ht_execute_command` expands, e.g.
+    /// `deploy-staging = "./deploy.sh --env staging"`
+    pub aliases: BTreeMap<String, String>,
+}
+
+impl Default for HtMcpConfig {
+    fn default() -> Self {
+        Self {
+            use_cgroup_memory_limits: false,
+            connection_limits: ConnectionLimits::default(),
+            bind_address: None,
+            management_address: None,
+            enable_admin_tools: false,
+            state_token: None,
+            allow_state_env_export: false,
+            log_cloudflared_output: false,
+            cloudflared_log_level: None,
+            verify_tunnels: false,
+            tunnel_ports: Vec::new(),
+            tunnel_providers: Vec::new(),
+            localhost_run_host_key: None,
+            default_tool_timeout_ms: 30_000,
+            lease_warning_secs: 30,
+            slow_startup_warning_ms: 2_000,
+            telemetry: TelemetryConfig::default(),
+            temp_dir: TempDirConfig::default(),
+            storage: StorageConfig::default(),
+            strip_controls_in_history: false,
+            screen_rules: Vec::new(),
+            annotation_patterns: Vec::new(),
+            output_flood: FloodConfig::default(),
+            workspace_root: None,
+            session_env: BTreeMap::new(),
+            trust_project_config: false,
+            allow_trace_mode: false,
+            type_file_paths: Vec::new(),
+            concurrent_snapshots_per_session: DEFAULT_CONCURRENT_SNAPSHOTS,
+            max_cols: DEFAULT_MAX_COLS,
+            max_rows: DEFAULT_MAX_ROWS,
+            retention: RetentionPolicy::default(),
+            baseline_dir: None,
+            warm_pool: WarmPoolConfig::default(),
+            tunnel_pool: TunnelPoolConfig::default(),
+            named_tunnel: NamedTunnelConfig::default(),
+            command_prefix: None,
+            reconnect_grace_secs: 0,
+            command_classes: Vec::new(),
+            aliases: BTreeMap::new(),
+        }
+    }
+}
+
+impl HtMcpConfig {
+    /// Loads the config from `path`, `$HT_MCP_CONFIG`, or the default path, in
+    /// that order. A missing default config file yields the default config.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        match Self::locate(path) {
+            Some(path) => Self::from_file(&path),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// The file `load` reads, or `None` when it falls back to the defaults
+    pub fn locate(path: Option<&Path>) -> Option<PathBuf> {
+        if let Some(path) = path {
+            return Some(path.to_path_buf());
+        }
+        if let Some(path) = std::env::var_os(CONFIG_ENV_VAR) {
+            return Some(PathBuf::from(path));
+        }
+        Self::default_path().filter(|path| path.exists())
+    }
+
+    /// Reads and parses a config file
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| HtMcpError::Config(format!("Failed to read {}: {}", path.display(), e)))?;
+        Self::from_toml(&contents)
+            .map_err(|e| HtMcpError::Config(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Parses and validates a config from TOML text
+    pub fn from_toml(contents: &str) -> Result<Self> {
+        let config = Self::parse(contents)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Parses a config from TOML text without validating its values
+    pub fn parse(contents: &str) -> Result<Self> {
+        toml::from_str(contents).map_err(|e| HtMcpError::Config(e.to_string()))
+    }
+
+    /// Fails with the first of `problems`
+    pub fn validate(&self) -> Result<()> {
+        match self.problems().into_iter().next() {
+            Some((_, e)) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Every invalid value, with the top-level field it is in
+    pub fn problems(&self) -> Vec<(&'static str, HtMcpError)> {
+        let checks = [
+            // Compiled here only to reject bad rules at startup
+            (
+                "screen_rules",
+                ScreenClassifier::new(&self.screen_rules).map(drop),
+            ),
+            (
+                "annotation_patterns",
+                Annotator::new(&self.annotation_patterns).map(drop),
+            ),
+            (
+                "command_classes",
+                CommandClassifier::new(&self.command_classes).map(drop),
+            ),
+            ("aliases", CommandAliases::new(self.aliases.clone()).map(drop)),
+            ("output_flood", self.output_flood.validate()),
+            ("retention", self.retention.validate()),
+            ("warm_pool", self.warm_pool.validate()),
+            ("tunnel_pool", self.tunnel_pool.validate()),
+            ("named_tunnel", self.named_tunnel.validate()),
+            (
+                "command_prefix",
+                match &self.command_prefix {
+                    Some(prefix) if prefix.iter().all(|token| token.trim().is_empty()) => {
+                        Err(HtMcpError::Config(
+                            "command_prefix must name a program; leave it out to run commands as given"
+                                .to_string(),
+                        ))
+                    }
+                    _ => Ok(()),
+                },
+            ),
+            (
+                "concurrent_snapshots_per_session",
+                match self.concurrent_snapshots_per_session {
+                    0 => Err(HtMcpError::Config(
+                        "concurrent_snapshots_per_session must be at least 1".to_string(),
+                    )),
+                    _ => Ok(()),
+                },
+            ),
+            (
+                "reconnect_grace_secs",
+                match self.reconnect_grace_secs > MAX_RECONNECT_GRACE_SECS {
+                    true => Err(HtMcpError::Config(format!(
+                        "reconnect_grace_secs must be at most {}",
+                        MAX_RECONNECT_GRACE_SECS
+                    ))),
+                    false => Ok(()),
+                },
+            ),
+            (
+                "max_cols",
+                check_terminal_limit("max_cols", self.max_cols, DEFAULT_COLS),
+            ),
+            (
+                "max_rows",
+                check_terminal_limit("max_rows", self.max_rows, DEFAULT_ROWS),
+            ),
+            (
+                "tunnel_ports",
+                match self.tunnel_ports.contains(&0) {
+                    true => Err(HtMcpError::Config(
+                        "tunnel_ports must be between 1 and 65535".to_string(),
+                    )),
+                    false => Ok(()),
+                },
+            ),
+            ("tunnel_providers", self.check_tunnel_providers()),
+            (
+                "state_token",
+                match self.state_token.as_deref().map(str::trim) {
+                    Some("") => Err(HtMcpError::Config(
+                        "state_token must not be empty; leave it out to turn /state off"
+                            .to_string(),
+                    )),
+                    _ => Ok(()),
+                },
+            ),
+            (
+                "cloudflared_log_level",
+                self.cloudflared_log_level
+                    .as_deref()
+                    .map_or(Ok(()), |level| {
+                        parse_log_level(level)
+                            .map(drop)
+                            .map_err(|e| HtMcpError::Config(e.to_string()))
+                    }),
+            ),
+        ];
+        checks
+            .into_iter()
+            .filter_map(|(field, check)| check.err().map(|e| (field, e)))
+            .collect()
+    }
+
+    fn check_tunnel_providers(&self) -> Result<()> {
+        for provider in &self.tunnel_providers {
+            if !OPT_IN_TUNNEL_PROVIDERS.contains(&provider.as_str()) {
+                return Err(HtMcpError::Config(format!(
+                    "Unknown tunnel provider '{}' in tunnel_providers; expected one of: {}",
+                    provider,
+                    OPT_IN_TUNNEL_PROVIDERS.join(", ")
+                )));
+            }
+            if provider == "localhost.run" && self.localhost_run_host_key.is_none() {
+                return Err(HtMcpError::Config(
+                    "tunnel_providers lists localhost.run, which needs localhost_run_host_key"
+                        .to_string(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The config as JSON, with `state_token` and the values of
+    /// `session_env`, which often hold credentials, replaced
+    pub fn redacted(&self) -> serde_json::Value {
+        let mut value = serde_json::to_value(self).unwrap_or_default();
+        if self.state_token.is_some() {
+            value["state_token"] = serde_json::Value::from(REDACTED);
+        }
+        if let Some(env) = value
+            .get_mut("session_env")
+            .and_then(serde_json::Value::as_object_mut)
+        {
+            for value in env.values_mut() {
+                *value = serde_json::Value::from(REDACTED);
+            }
+        }
+        value
+    }
+
+    /// The command a session asking for `command` runs: `command_prefix`
+    /// followed by `sh -c` and `command`. The PTY joins the tokens with
+    /// spaces for a shell, so prefix tokens are quoted to stay one argument
+    /// each. `command` is shell syntax, and is passed whole as the one
+    /// argument of `sh -c`, so operators such as `;` or `&&` and expansions
+    /// run behind the prefix rather than in front of it.
+    pub fn effective_command(&self, command: &[String]) -> Vec<String> {
+        let Some(prefix) = &self.command_prefix else {
+            return command.to_vec();
+        };
+        prefix
+            .iter()
+            .map(|token| shell_quote(token))
+            .chain(["sh".to_string(), "-c".to_string()])
+            .chain([shell_quote(&command.join(" "))])
+            .collect()
+    }
+
+    pub fn baseline_dir(&self) -> PathBuf {
+        self.baseline_dir.clone().unwrap_or_else(|| {
+            std::env::temp_dir().join(format!("ht-mcp-baselines-{}", std::process::id()))
+        })
+    }
+
+    /// Resolves `workspace_root` to an absolute path and merges the
+    /// `.ht-mcp.toml` found there, returning it if there was one
+    pub fn load_workspace(&mut self) -> Result<Option<MergedProject>> {
+        let Some(root) = &self.workspace_root else {
+            return Ok(None);
+        };
+        let root = root.canonicalize().map_err(|e| {
+            HtMcpError::Config(format!("Invalid workspace_root {}: {}", root.display(), e))
+        })?;
+        if !root.is_dir() {
+            return Err(HtMcpError::Config(format!(
+                "Invalid workspace_root {}: not a directory",
+                root.display()
+            )));
+        }
+
+        let path = root.join(PROJECT_CONFIG_FILE);
+        self.workspace_root = Some(root);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let contents = std::fs::read_to_string(&path)
+            .map_err(|e| HtMcpError::Config(format!("Failed to read {}: {}", path.display(), e)))?;
+        let project = ProjectConfig::from_toml(&contents)
+            .map_err(|e| HtMcpError::Config(format!("{}: {}", path.display(), e)))?;
+        let ignored = self.merge_project(project);
+        Ok(Some(MergedProject { path, ignored }))
+    }
+
+    /// Adds a project's settings under this config's: variables and aliases
+    /// set here keep their value, project rules and patterns come after these,
+    /// command classes named here shadow the project's, and connection
+    /// limits only ever get stricter. Returns the sections left out because
+    /// `trust_project_config` is off.
+    fn merge_project(&mut self, project: ProjectConfig) -> Vec<&'static str> {
+        let mut ignored = Vec::new();
+        if !project.session_env.is_empty() && !self.trust_project_config {
+            ignored.push("session_env");
+        } else {
+            for (name, value) in project.session_env {
+                self.session_env.entry(name).or_insert(value);
+            }
+        }
+        if !project.aliases.is_empty() && !self.trust_project_config {
+            ignored.push("aliases");
+        } else {
+            for (name, command) in project.aliases {
+                self.aliases.entry(name).or_insert(command);
+            }
+        }
+        self.screen_rules.extend(project.screen_rules);
+        self.annotation_patterns.extend(project.annotation_patterns);
+        self.command_classes
+            .extend(project.command_classes.into_iter().filter(|class| {
+                !self
+                    .command_classes
+                    .iter()
+                    .any(|own| own.name == class.name)
+            }));
+
+        let limits = &mut self.connection_limits;
+        let tighter = project.connection_limits;
+        if let Some(max) = tighter.max_connections {
+            limits.max_connections = limits.max_connections.min(max);
+        }
+        if let Some(max) = tighter.max_connections_per_ip {
+            limits.max_connections_per_ip = limits.max_connections_per_ip.min(max);
+        }
+        if let Some(secs) = tighter.idle_timeout_secs {
+            limits.idle_timeout_secs = limits.idle_timeout_secs.min(secs);
+        }
+        ignored
+    }
+
+    /// `$XDG_CONFIG_HOME/ht-mcp/config.toml`, falling back to `~/.config`
+    pub fn default_path() -> Option<PathBuf> {
+        let config_home = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+        Some(config_home.join("ht-mcp").join("config.toml"))
+    }
+}
+
+/// A workspace's `.ht-mcp.toml`, as merged by `HtMcpConfig::load_workspace`
+#[derive(Debug, PartialEq)]
+pub struct MergedProject {
+    pub path: PathBuf,
+    /// Sections it set that were left out, since the server config does not
+    /// set `trust_project_config`
+    pub ignored: Vec<&'static str>,
+}
+
+/// What a workspace's `.ht-mcp.toml` may set. Settings that could loosen
+/// the server's security (admin tools, bind addresses, cgroup limits) are
+/// not accepted, and unknown keys are rejected rather than ignored.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProjectConfig {
+    pub session_env: BTreeMap<String, String>,
+    pub screen_rules: Vec<ScreenRuleConfig>,
+    pub annotation_patterns: Vec<AnnotationPatternConfig>,
+    pub command_classes: Vec<CommandClassConfig>,
+    pub aliases: BTreeMap<String, String>,
+    /// Lower the server's default connection limits; higher values are
+    /// ignored
+    pub connection_limits: ProjectConnectionLimits,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProjectConnectionLimits {
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    pub idle_timeout_secs: Option<u64>,
+}
+
+impl ProjectConfig {
+    pub fn from_toml(contents: &str) -> Result<Self> {
+        let config: Self =
+            toml::from_str(contents).map_err(|e| HtMcpError::Config(e.to_string()))?;
+        ScreenClassifier::new(&config.screen_rules)?;
+        Annotator::new(&config.annotation_patterns)?;
+        CommandClassifier::new(&config.command_classes)?;
+        CommandAliases::new(config.aliases.clone())?;
+        Ok(config)
+    }
+}
+
+/// A `max_cols` or `max_rows` must leave room for the default size of new
+/// sessions and stay within what `ht_resize_terminal` ever accepts
+fn check_terminal_limit(field: &str, value: usize, default: u16) -> Result<()> {
+    let default = usize::from(default);
+    if (default..=MAX_TERMINAL_SIZE).contains(&value) {
+        Ok(())
+    } else {
+        Err(HtMcpError::Config(format!(
+            "{} must be between {} (the default size of new sessions) and {}, got {}",
+            field, default, MAX_TERMINAL_SIZE, value
+        )))
+    }
+}
+
+/// `token` as a single `sh` word: unchanged when it holds nothing a shell
+/// would interpret, in single quotes otherwise
+fn shell_quote(token: &str) -> String {
+    let plain = !token.is_empty()
+        && token
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-+=/.,:@%".contains(c));
+    if plain {
+        token.to_string()
+    } else {
+        format!("'{}'", token.replace('\'', "'\\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_defaults_for_empty_file() {
+        let config = HtMcpConfig::from_toml("").unwrap();
+        assert!(!config.use_cgroup_memory_limits);
+        assert_eq!(config.connection_limits, ConnectionLimits::default());
+        assert_eq!(config.default_tool_timeout_ms, 30_000);
+        assert!(config
+            .baseline_dir()
+            .ends_with(format!("ht-mcp-baselines-{}", std::process::id())));
+    }
+
+    #[test]
+    fn test_parse_fields() {
+        let config = HtMcpConfig::from_toml(
+            r#"
+            use_cgroup_memory_limits = true
+
+            [connection_limits]
+            max_connections = 8
+            "#,
+        )
+        .unwrap();
+        assert!(config.use_cgroup_memory_limits);
+        assert_eq!(config.connection_limits.max_connections, 8);
+        assert_eq!(
+            config.connection_limits.idle_timeout_secs,
+            ConnectionLimits::default().idle_timeout_secs
+        );
+    }
+
+    #[test]
+    fn test_command_prefix() {
+        let config =
+            HtMcpConfig::from_toml(r#"command_prefix = ["docker", "exec", "-it", "mycontainer"]"#)
+                .unwrap();
+        assert_eq!(
+            config.effective_command(&["bash".to_string()]),
+            ["docker", "exec", "-it", "mycontainer", "sh", "-c", "bash"]
+        );
+        assert_eq!(
+            HtMcpConfig::default().effective_command(&["bash".to_string()]),
+            ["bash"]
+        );
+
+        let err = HtMcpConfig::from_toml("command_prefix = []").unwrap_err();
+        assert!(matches!(err, HtMcpError::Config(_)));
+    }
+
+    #[test]
+    fn test_command_prefix_is_quoted() {
+        let config = HtMcpConfig::from_toml(
+            r#"command_prefix = ["docker", "exec", "my container", "it's", ""]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.effective_command(&["echo $HOME".to_string()]),
+            [
+                "docker",
+                "exec",
+                "'my container'",
+                "'it'\\''s'",
+                "''",
+                "sh",
+                "-c",
+                "'echo $HOME'"
+            ]
+        );
+        // The whole command runs behind the prefix, not just its first part
+        assert_eq!(
+            config.effective_command(&["cd /srv && make".to_string(), "test".to_string()]),
+            [
+                "docker",
+                "exec",
+                "'my container'",
+                "'it'\\''s'",
+                "''",
+                "sh",
+                "-c",
+                "'cd /srv && make test'"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_toml_is_config_error() {
+        let err = HtMcpConfig::from_toml("use_cgroup_memory_limits = ").unwrap_err();
+        assert!(matches!(err, HtMcpError::Config(_)));
+    }
+
+    #[test]
+    fn test_screen_rules() {
+        let config = HtMcpConfig::from_toml(
+            r#"
+            [[screen_rules]]
+            state = "shell-prompt"
+            last_line = '^mysql> $'
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.screen_rules.len(), 1);
+        assert_eq!(config.screen_rules[0].confidence, 0.9);
+
+        let err = HtMcpConfig::from_toml(
+            r#"
+            [[screen_rules]]
+            state = "pager"
+            last_line = '(unclosed'
+            "#,
+        )
+        .unwrap_err();
+        assert!(matches!(err, HtMcpError::Config(_)));
+    }
+
+    #[test]
+    fn test_command_classes() {
+        let config = HtMcpConfig::from_toml(
+            r#"
+            [[command_classes]]
+            name = "deploy"
+            pattern = '^\./deploy\.sh'
+            timeout_ms = 900000
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.command_classes.len(), 1);
+        assert_eq!(config.command_classes[0].quiet_period_ms, None);
+
+        for contents in [
+            "[[command_classes]]\nname = \"bad\"\npattern = '(unclosed'",
+            "[[command_classes]]\nname = \"default\"\npattern = 'x'",
+            "[[command_classes]]\nname = \"slow\"\npattern = 'x'\ntimeout_ms = 0",
+        ] {
+            let err = HtMcpConfig::from_toml(contents).unwrap_err();
+            assert!(matches!(err, HtMcpError::Config(_)), "{}", contents);
+        }
+    }
+
+    #[test]
+    fn test_output_flood() {
+        let config = HtMcpConfig::from_toml(
+            r#"
+            [output_flood]
+            min_repeat_ratio = 0.5
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.output_flood.min_repeat_ratio, 0.5);
+        assert_eq!(
+            config.output_flood.window_ms,
+            FloodConfig::default().window_ms
+        );
+
+        let err = HtMcpConfig::from_toml("[output_flood]\nwindow_ms = 0").unwrap_err();
+        assert!(matches!(err, HtMcpError::Config(_)));
+    }
+
+    #[test]
+    fn test_concurrent_snapshots_per_session() {
+        assert_eq!(
+            HtMcpConfig::default().concurrent_snapshots_per_session,
+            DEFAULT_CONCURRENT_SNAPSHOTS
+        );
+        let config = HtMcpConfig::from_toml("concurrent_snapshots_per_session = 2").unwrap();
+        assert_eq!(config.concurrent_snapshots_per_session, 2);
+        let err = HtMcpConfig::from_toml("concurrent_snapshots_per_session = 0").unwrap_err();
+        assert!(matches!(err, HtMcpError::Config(_)));
+    }
+
+    #[test]
+    fn test_terminal_limits() {
+        let config = HtMcpConfig::default();
+        assert_eq!((config.max_cols, config.max_rows), (500, 200));
+        let config = HtMcpConfig::from_toml("max_cols = 1000\nmax_rows = 40").unwrap();
+        assert_eq!((config.max_cols, config.max_rows), (1000, 40));
+        for toml in ["max_cols = 80", "max_rows = 1001", "max_cols = 0"] {
+            let err = HtMcpConfig::from_toml(toml).unwrap_err();
+            assert!(matches!(err, HtMcpError::Config(_)), "{}", toml);
+        }
+    }
+
+    #[test]
+    fn test_retention() {
+        let config = HtMcpConfig::from_toml("[retention]\ncommand_history = 50").unwrap();
+        assert_eq!(config.retention.command_history, 50);
+        assert_eq!(
+            config.retention.snapshot_history,
+            RetentionPolicy::default().snapshot_history
+        );
+        let err = HtMcpConfig::from_toml("[retention]\nsnapshot_history = 0").unwrap_err();
+        assert!(matches!(err, HtMcpError::Config(_)));
+    }
+
+    #[test]
+    fn test_problems_name_every_invalid_field() {
+        let config = HtMcpConfig::parse(
+            "concurrent_snapshots_per_session = 0\nreconnect_grace_secs = 9223372036854775807\ncloudflared_log_level = \"loud\"\n[retention]\nsnapshot_history = 0",
+        )
+        .unwrap();
+        let fields: Vec<&str> = config.problems().iter().map(|(field, _)| *field).collect();
+        assert_eq!(
+            fields,
+            [
+                "retention",
+                "concurrent_snapshots_per_session",
+                "reconnect_grace_secs",
+                "cloudflared_log_level"
+            ]
+        );
+        assert!(matches!(config.validate(), Err(HtMcpError::Config(_))));
+        assert!(HtMcpConfig::default().problems().is_empty());
+    }
+
+    #[test]
+    fn test_tunnel_providers() {
+        let config = HtMcpConfig::from_toml(
+            "tunnel_providers = [\"bore\", \"localhost.run\"]\nlocalhost_run_host_key = \"ssh-ed25519 AAAA\"",
+        )
+        .unwrap();
+        assert_eq!(config.tunnel_providers, ["bore", "localhost.run"]);
+        // localhost.run is not trusted without a pinned host key
+        let err = HtMcpConfig::from_toml("tunnel_providers = [\"localhost.run\"]").unwrap_err();
+        assert!(
+            err.to_string().contains("localhost_run_host_key"),
+            "{}",
+            err
+        );
+        // cloudflare needs no opting in, and auto is not a provider
+        for provider in ["cloudflare", "auto", "ngrok"] {
+            let toml = format!("tunnel_providers = [\"{}\"]", provider);
+            assert!(HtMcpConfig::from_toml(&toml).is_err(), "{}", provider);
+        }
+    }
+
+    #[test]
+    fn test_redacted() {
+        let config = HtMcpConfig::from_toml(
+            "lease_warning_secs = 10\nstate_token = \"secret\"\n[session_env]\nAPI_TOKEN = \"secret\"",
+        )
+        .unwrap();
+        let value = config.redacted();
+        assert_eq!(value["lease_warning_secs"], 10);
+        assert_eq!(value["state_token"], REDACTED);
+        assert_eq!(value["session_env"]["API_TOKEN"], REDACTED);
+        assert!(!value.to_string().contains("secret"));
+    }
+
+    #[test]
+    fn test_warm_pool() {
+        let config = HtMcpConfig::from_toml(
+            "[warm_pool]\nsize = 2\ncommand = [\"zsh\"]\ninit_commands = [\"cd /srv\"]",
+        )
+        .unwrap();
+        assert_eq!(config.warm_pool.size, 2);
+        assert_eq!(config.warm_pool.command, ["zsh"]);
+        assert_eq!(config.warm_pool.init_commands, ["cd /srv"]);
+        let err = HtMcpConfig::from_toml("[warm_pool]\nsize = 1\ncommand = []").unwrap_err();
+        assert!(matches!(err, HtMcpError::Config(_)));
+    }
+
+    #[test]
+    fn test_tunnel_pool() {
+        let config = HtMcpConfig::from_toml("[tunnel_pool]\nsize = 2").unwrap();
+        assert_eq!(config.tunnel_pool.size, 2);
+        assert_eq!(HtMcpConfig::default().tunnel_pool.size, 0);
+        let err = HtMcpConfig::from_toml("[tunnel_pool]\nsize = 100").unwrap_err();
+        assert!(matches!(err, HtMcpError::Config(_)));
+    }
+
+    #[test]
+    fn test_named_tunnel() {
+        let config = HtMcpConfig::from_toml(
+            "[named_tunnel]\nhostname_template = \"{session-name}.example.com\"\norigin_cert = \"/etc/cloudflared/cert.pem\"",
+        )
+        .unwrap();
+        assert!(config.named_tunnel.is_enabled());
+        assert_eq!(
+            config.named_tunnel.origin_cert,
+            Some(PathBuf::from("/etc/cloudflared/cert.pem"))
+        );
+        assert!(!HtMcpConfig::default().named_tunnel.is_enabled());
+        let err = HtMcpConfig::from_toml("[named_tunnel]\nhostname_template = \"ht.example.com\"")
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::Config(_)));
+    }
+
+    fn workspace(project_toml: Option<&str>) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("ht-mcp-workspace-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&root).unwrap();
+        if let Some(contents) = project_toml {
+            std::fs::write(root.join(PROJECT_CONFIG_FILE), contents).unwrap();
+        }
+        root
+    }
+
+    #[test]
+    fn test_project_config_discovery() {
+        let root = workspace(None);
+        let mut config = HtMcpConfig {
+            workspace_root: Some(root.clone()),
+            ..HtMcpConfig::default()
+        };
+        assert_eq!(config.load_workspace().unwrap(), None);
+        assert!(config.workspace_root.as_ref().unwrap().is_absolute());
+
+        std::fs::write(
+            root.join(PROJECT_CONFIG_FILE),
+            "[session_env]\nRUST_LOG = \"debug\"\n[aliases]\nls = \"./evil\"",
+        )
+        .unwrap();
+        let mut config = HtMcpConfig {
+            workspace_root: Some(root.clone()),
+            trust_project_config: true,
+            ..HtMcpConfig::default()
+        };
+        let found = config.load_workspace().unwrap().unwrap();
+        assert!(found.path.ends_with(PROJECT_CONFIG_FILE));
+        assert!(found.ignored.is_empty());
+        assert_eq!(config.session_env["RUST_LOG"], "debug");
+
+        assert_eq!(config.aliases["ls"], "./evil");
+
+        // Without trust, the project's environment and aliases are left out
+        let mut config = HtMcpConfig {
+            workspace_root: Some(root.clone()),
+            ..HtMcpConfig::default()
+        };
+        let found = config.load_workspace().unwrap().unwrap();
+        assert_eq!(found.ignored, ["session_env", "aliases"]);
+        assert!(config.session_env.is_empty());
+        assert!(config.aliases.is_empty());
+
+        // No workspace, nothing to discover
+        assert_eq!(HtMcpConfig::default().load_workspace().unwrap(), None);
+        let mut missing = HtMcpConfig {
+            workspace_root: Some(root.join("missing")),
+            ..HtMcpConfig::default()
+        };
+        assert!(matches!(
+            missing.load_workspace(),
+            Err(HtMcpError::Config(_))
+        ));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_project_config_merges_under_global() {
+        let root = workspace(Some(
+            r#"
+            [session_env]
+            RUST_LOG = "trace"
+            DATABASE_URL = "postgres://localhost/dev"
+
+            [[annotation_patterns]]
+            kind = "ticket"
+            pattern = 'PROJ-\d+'
+
+            [[command_classes]]
+            name = "deploy"
+            pattern = '^make deploy'
+            timeout_ms = 900000
+
+            [[command_classes]]
+            name = "migrate"
+            pattern = '^\./migrate'
+            timeout_ms = 120000
+
+            [aliases]
+            t = "npm test"
+            deploy-staging = "make deploy ENV=staging"
+            "#,
+        ));
+        let mut config = HtMcpConfig::from_toml(
+            r#"
+            trust_project_config = true
+
+            [session_env]
+            RUST_LOG = "info"
+
+            [[annotation_patterns]]
+            kind = "todo"
+            pattern = 'TODO'
+
+            [[command_classes]]
+            name = "deploy"
+            pattern = '^\./deploy\.sh'
+
+            [aliases]
+            t = "cargo test"
+            "#,
+        )
+        .unwrap();
+        config.workspace_root = Some(root.clone());
+        config.load_workspace().unwrap();
+
+        // The global value wins; the project fills in the rest
+        assert_eq!(config.session_env["RUST_LOG"], "info");
+        assert_eq!(
+            config.session_env["DATABASE_URL"],
+            "postgres://localhost/dev"
+        );
+        let kinds: Vec<&str> = config
+            .annotation_patterns
+            .iter()
+            .map(|p| p.kind.as_str())
+            .collect();
+        assert_eq!(kinds, ["todo", "ticket"]);
+        // A class named in the global config shadows the project's
+        let classes: Vec<(&str, &str)> = config
+            .command_classes
+            .iter()
+            .map(|c| (c.name.as_str(), c.pattern.as_str()))
+            .collect();
+        assert_eq!(
+            classes,
+            [("deploy", r"^\./deploy\.sh"), ("migrate", r"^\./migrate")]
+        );
+        assert_eq!(config.aliases["t"], "cargo test");
+        assert_eq!(config.aliases["deploy-staging"], "make deploy ENV=staging");
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_project_config_only_tightens() {
+        let root = workspace(Some(
+            r#"
+            [connection_limits]
+            max_connections = 4
+            max_connections_per_ip = 1000
+            "#,
+        ));
+        let mut config = HtMcpConfig {
+            workspace_root: Some(root.clone()),
+            ..HtMcpConfig::default()
+        };
+        config.load_workspace().unwrap();
+        let defaults = ConnectionLimits::default();
+        assert_eq!(config.connection_limits.max_connections, 4);
+        assert_eq!(
+            config.connection_limits.max_connections_per_ip,
+            defaults.max_connections_per_ip
+        );
+        assert_eq!(
+            config.connection_limits.idle_timeout_secs,
+            defaults.idle_timeout_secs
+        );
+
+        // Server-wide security settings cannot be set from a project
+        for contents in [
+            "enable_admin_tools = true",
+            "bind_address = \"0.0.0.0\"",
+            "use_cgroup_memory_limits = false",
+            "workspace_root = \"/\"",
+        ] {
+            let err = ProjectConfig::from_toml(contents).unwrap_err();
+            assert!(matches!(err, HtMcpError::Config(_)), "{}", contents);
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
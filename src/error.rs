@@ -19,9 +19,101 @@ pub enum HtMcpError {
     #[error("Internal error: {0}")]
     Internal(String),
 
+    #[error("Configuration error: {0}")]
+    Config(String),
+
+    #[error("Timeout: {0}")]
+    Timeout(String),
+
+    /// A remote service could not be reached or failed the request, e.g. a
+    /// tunnel that never registered. Usually transient, so worth retrying.
+    #[error("Network error: {0}")]
+    NetworkError(String),
+
+    /// The server is draining for maintenance and refuses new work
+    #[error("Server draining: {0}")]
+    ServerDraining(String),
+
+    /// A per-session limit on concurrent work was reached and no slot freed
+    /// up in time
+    #[error("Resource exhausted: {0}")]
+    ResourceExhausted(String),
+
+    /// The session's process exited while the operation was waiting on it
+    #[error("Session exited: {0}")]
+    SessionExited(String),
+
+    /// Another holder has the session's interaction lock
+    #[error("Session locked: {session_id} is held by {holder} until {expires_at}")]
+    SessionLocked {
+        session_id: String,
+        holder: String,
+        expires_at: String,
+    },
+
     #[error("Serialization error: {0}")]
     Serialization(#[from] serde_json::Error),
 
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
 }
+
+impl HtMcpError {
+    /// Stable machine-readable code for this error, used in statistics and logs
+    pub fn code(&self) -> &'static str {
+        match self {
+            HtMcpError::Mcp(_) => "mcp_error",
+            HtMcpError::HtLibrary(_) => "ht_library_error",
+            HtMcpError::SessionNotFound(_) => "session_not_found",
+            HtMcpError::InvalidRequest(_) => "invalid_request",
+            HtMcpError::Internal(_) => "internal_error",
+            HtMcpError::Config(_) => "config_error",
+            HtMcpError::Timeout(_) => "timeout",
+            HtMcpError::NetworkError(_) => "network_error",
+            HtMcpError::ServerDraining(_) => "server_draining",
+            HtMcpError::ResourceExhausted(_) => "resource_exhausted",
+            HtMcpError::SessionExited(_) => "session_exited",
+            HtMcpError::SessionLocked { .. } => "session_locked",
+            HtMcpError::Serialization(_) => "serialization_error",
+            HtMcpError::Io(_) => "io_error",
+        }
+    }
+
+    /// Short troubleshooting hint for this kind of error
+    pub fn hint(&self) -> &'static str {
+        match self {
+            HtMcpError::Mcp(_) => "Check that the client speaks a supported MCP protocol version",
+            HtMcpError::HtLibrary(_) => {
+                "The terminal emulator rejected the operation; check the session state"
+            }
+            HtMcpError::SessionNotFound(_) => {
+                "The session may have been closed; use ht_list_sessions to see active sessions"
+            }
+            HtMcpError::InvalidRequest(_) => {
+                "Check the tool arguments against the tool's input schema"
+            }
+            HtMcpError::Internal(_) => "Run with --debug and check the server log for details",
+            HtMcpError::Config(_) => "Fix the config file or run without --config to use defaults",
+            HtMcpError::Timeout(_) => {
+                "Retry with a larger _timeout_ms, or raise default_tool_timeout_ms in the config"
+            }
+            HtMcpError::NetworkError(_) => {
+                "Check network connectivity and retry; the failure is usually transient"
+            }
+            HtMcpError::ServerDraining(_) => {
+                "The server is shutting down for maintenance; use another instance or retry after it restarts"
+            }
+            HtMcpError::ResourceExhausted(_) => {
+                "Too many requests are waiting on this session; retry shortly or raise concurrent_snapshots_per_session"
+            }
+            HtMcpError::SessionExited(_) => {
+                "The program in the session ended; close the session and create a new one"
+            }
+            HtMcpError::SessionLocked { .. } => {
+                "Wait for the holder to call ht_release_lock or for the lock to expire, or pass the holder's name as holder"
+            }
+            HtMcpError::Serialization(_) => "The request or response could not be (de)serialized",
+            HtMcpError::Io(_) => "Check file permissions and available system resources",
+        }
+    }
+}
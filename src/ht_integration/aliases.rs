@@ -0,0 +1,246 @@
+//! Command aliases expanded by `ht_execute_command`.
+//!
+//! An alias names a command, such as `deploy-staging` for a long, carefully
+//! quoted deploy line. A command whose first word is an alias runs the
+//! alias's command instead, with the rest of the words appended. Aliases
+//! come from the server config, the project config (for names the server
+//! config does not set) and `ht_set_alias`, whose per-session aliases
+//! shadow configured ones. An alias's command may start with another alias.
+//! As in bash, expansion stops at a word that was already expanded, so
+//! `ls = "ls --color"` runs the `ls` command rather than looping.
+
+use crate::error::{HtMcpError, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+/// A command that named an alias, and what it runs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expansion {
+    /// The alias the command started with
+    pub alias: String,
+    pub command: String,
+}
+
+/// Every alias: the configured ones, and those each session set
+#[derive(Debug, Clone, Default)]
+pub struct CommandAliases {
+    configured: Arc<BTreeMap<String, String>>,
+    sessions: Arc<Mutex<HashMap<String, BTreeMap<String, String>>>>,
+}
+
+impl CommandAliases {
+    /// The configured aliases, checked for bad names and empty commands
+    pub fn new(configured: BTreeMap<String, String>) -> Result<Self> {
+        for (name, command) in &configured {
+            let checked = validate_name(name).and_then(|_| validate_command(name, command));
+            if let Err(e) = checked {
+                return Err(HtMcpError::Config(e.to_string()));
+            }
+        }
+        Ok(Self {
+            configured: Arc::new(configured),
+            sessions: Arc::default(),
+        })
+    }
+
+    /// Sets a session's alias `name` to `command`, or removes it without a
+    /// command, returning the command it had before
+    pub fn set(
+        &self,
+        session_id: &str,
+        name: &str,
+        command: Option<&str>,
+    ) -> Result<Option<String>> {
+        validate_name(name)?;
+        let mut sessions = self.sessions.lock().unwrap();
+        let mut table = sessions.get(session_id).cloned().unwrap_or_default();
+        let previous = match command {
+            Some(command) => {
+                validate_command(name, command)?;
+                table.insert(name.to_string(), command.to_string())
+            }
+            None => table.remove(name),
+        };
+        if table.is_empty() {
+            sessions.remove(session_id);
+        } else {
+            sessions.insert(session_id.to_string(), table);
+        }
+        Ok(previous)
+    }
+
+    /// Forgets the aliases of a closed session
+    pub fn remove_session(&self, session_id: &str) {
+        self.sessions.lock().unwrap().remove(session_id);
+    }
+
+    /// What `command` runs in the session, when it starts with an alias
+    pub fn expand(&self, session_id: &str, command: &str) -> Option<Expansion> {
+        let sessions = self.sessions.lock().unwrap();
+        let no_session = BTreeMap::new();
+        let table = sessions.get(session_id).unwrap_or(&no_session);
+        let (alias, _) = split_first_word(command);
+        self.lookup(alias, table)?;
+        Some(Expansion {
+            alias: alias.to_string(),
+            command: self.resolve(command, table),
+        })
+    }
+
+    /// A session's alias `name`, or the configured one
+    fn lookup<'a>(&'a self, name: &str, session: &'a BTreeMap<String, String>) -> Option<&'a str> {
+        session
+            .get(name)
+            .or_else(|| self.configured.get(name))
+            .map(String::as_str)
+    }
+
+    /// `command` with aliases expanded until its first word is not one, or
+    /// is one that was already expanded
+    fn resolve(&self, command: &str, session: &BTreeMap<String, String>) -> String {
+        let mut command = command.trim().to_string();
+        let mut expanded: Vec<String> = Vec::new();
+        loop {
+            let (name, rest) = split_first_word(&command);
+            if expanded.iter().any(|seen| seen == name) {
+                return command;
+            }
+            let Some(expansion) = self.lookup(name, session) else {
+                return command;
+            };
+            expanded.push(name.to_string());
+            command = if rest.is_empty() {
+                expansion.to_string()
+            } else {
+                format!("{} {}", expansion, rest)
+            };
+        }
+    }
+}
+
+/// The first word of `command` and the rest, both trimmed
+fn split_first_word(command: &str) -> (&str, &str) {
+    let command = command.trim();
+    match command.split_once(char::is_whitespace) {
+        Some((first, rest)) => (first, rest.trim_start()),
+        None => (command, ""),
+    }
+}
+
+/// Alias names are single words of letters, digits, `_`, `.` and `-`
+fn validate_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && !name.starts_with('-')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'));
+    if !valid {
+        return Err(HtMcpError::InvalidRequest(format!(
+            "Invalid alias name '{}': use letters, digits, '_', '.' and '-', not starting with '-'",
+            name
+        )));
+    }
+    Ok(())
+}
+
+fn validate_command(name: &str, command: &str) -> Result<()> {
+    if command.trim().is_empty() {
+        return Err(HtMcpError::InvalidRequest(format!(
+            "Alias {} needs a command",
+            name
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn configured(aliases: &[(&str, &str)]) -> BTreeMap<String, String> {
+        aliases
+            .iter()
+            .map(|(name, command)| (name.to_string(), command.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_expand_appends_arguments() {
+        let aliases = CommandAliases::new(configured(&[(
+            "deploy-staging",
+            "./deploy.sh --env 'staging eu'",
+        )]))
+        .unwrap();
+        assert_eq!(
+            aliases.expand("s", "deploy-staging").unwrap(),
+            Expansion {
+                alias: "deploy-staging".to_string(),
+                command: "./deploy.sh --env 'staging eu'".to_string(),
+            }
+        );
+        assert_eq!(
+            aliases
+                .expand("s", "  deploy-staging   --dry-run ")
+                .unwrap()
+                .command,
+            "./deploy.sh --env 'staging eu' --dry-run"
+        );
+        // Only the first word is looked up, and only whole words match
+        assert!(aliases.expand("s", "echo deploy-staging").is_none());
+        assert!(aliases.expand("s", "deploy-staging2").is_none());
+    }
+
+    #[test]
+    fn test_session_aliases_shadow_configured_ones() {
+        let aliases = CommandAliases::new(configured(&[("t", "cargo test")])).unwrap();
+        assert_eq!(aliases.set("s", "t", Some("npm test")).unwrap(), None);
+        assert_eq!(aliases.expand("s", "t").unwrap().command, "npm test");
+        // Other sessions still see the configured alias
+        assert_eq!(aliases.expand("other", "t").unwrap().command, "cargo test");
+
+        assert_eq!(
+            aliases.set("s", "t", None).unwrap().as_deref(),
+            Some("npm test")
+        );
+        assert_eq!(aliases.expand("s", "t").unwrap().command, "cargo test");
+
+        aliases.set("s", "b", Some("make")).unwrap();
+        aliases.remove_session("s");
+        assert!(aliases.expand("s", "b").is_none());
+    }
+
+    #[test]
+    fn test_aliases_chain_but_never_recurse() {
+        let aliases =
+            CommandAliases::new(configured(&[("ci", "t --release"), ("t", "cargo test")])).unwrap();
+        let expansion = aliases.expand("s", "ci -q").unwrap();
+        assert_eq!(expansion.alias, "ci");
+        assert_eq!(expansion.command, "cargo test --release -q");
+
+        // An alias named after the command it wraps runs that command
+        aliases.set("s", "ls", Some("ls --color")).unwrap();
+        assert_eq!(
+            aliases.expand("s", "ls -l").unwrap().command,
+            "ls --color -l"
+        );
+
+        // A loop stops at the first alias seen twice
+        aliases.set("s", "cargo", Some("ci")).unwrap();
+        assert_eq!(
+            aliases.expand("s", "t").unwrap().command,
+            "t --release test"
+        );
+        let aliases = CommandAliases::new(configured(&[("a", "b"), ("b", "a x")])).unwrap();
+        assert_eq!(aliases.expand("s", "a y").unwrap().command, "a x y");
+    }
+
+    #[test]
+    fn test_names_and_commands_are_checked() {
+        let aliases = CommandAliases::default();
+        for name in ["", "-x", "two words", "a;b"] {
+            assert!(aliases.set("s", name, Some("true")).is_err(), "{}", name);
+        }
+        assert!(aliases.set("s", "empty", Some("  ")).is_err());
+        assert!(CommandAliases::new(configured(&[("bad name", "true")])).is_err());
+    }
+}
@@ -0,0 +1,283 @@
+//! Marking snapshot lines that match known patterns (errors, warnings,
+//! stack traces, URLs) so clients can highlight or jump to them without
+//! parsing the text again.
+//!
+//! Matches of one kind that overlap on a line are merged into the longest
+//! one. Kinds marked `group` (stack traces) merge matches on consecutive
+//! lines, allowing one unmatched line between frames for the source line
+//! Python prints under each frame, into a single annotation with an
+//! `endLine`.
+
+use crate::error::{HtMcpError, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+/// Built-in patterns as (kind, regex, group)
+const DEFAULT_PATTERNS: &[(&str, &str, bool)] = &[
+    // rustc / cargo: `error[E0308]: mismatched types`, `error: could not compile`
+    ("error", r"\berror(\[E\d+\])?:.*", false),
+    // node and python exceptions: `TypeError: x is undefined`
+    ("error", r"^\s*\w*(Error|Exception): .+", false),
+    ("error", r"thread '.+' panicked at .+", false),
+    // pytest: failure summaries and assertion detail lines
+    ("error", r"^(FAILED|ERROR) \S+.*", false),
+    ("error", r"^E\s{2,}.+", false),
+    ("warning", r"(?i)\bwarning(\[[\w-]+\])?:.*", false),
+    (
+        "stack-trace",
+        r"^Traceback \(most recent call last\):",
+        true,
+    ),
+    ("stack-trace", r#"^\s+File ".+", line \d+.*"#, true),
+    // node frames and rust backtrace locations: `    at main (app.js:3:9)`
+    ("stack-trace", r"^\s+at .+:\d+:\d+\)?$", true),
+    // rust backtrace frames: `   3: app::main`
+    ("stack-trace", r"^\s+\d+: [\w<>]+::\S+", true),
+    ("url", r#"https?://[^\s<>"'`)\]]+"#, false),
+];
+
+/// A pattern from the config or a request
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AnnotationPatternConfig {
+    pub kind: String,
+    pub pattern: String,
+    /// Merge matches on consecutive lines into one annotation
+    #[serde(default)]
+    pub group: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Annotation {
+    /// 1-based line number
+    pub line: usize,
+    /// Last line covered, for grouped kinds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end_line: Option<usize>,
+    pub kind: String,
+    #[serde(rename = "match")]
+    pub matched: String,
+}
+
+#[derive(Debug, Clone)]
+struct Pattern {
+    kind: String,
+    regex: Regex,
+    group: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Annotator {
+    patterns: Vec<Pattern>,
+}
+
+impl Annotator {
+    /// The default patterns followed by `configured` ones
+    pub fn new(configured: &[AnnotationPatternConfig]) -> Result<Self> {
+        let mut annotator = Self::default();
+        annotator
+            .patterns
+            .extend(compile(configured, HtMcpError::Config)?);
+        Ok(annotator)
+    }
+
+    /// These patterns plus ones given with a single request
+    pub fn with_request_patterns(&self, extra: &[AnnotationPatternConfig]) -> Result<Self> {
+        let mut annotator = self.clone();
+        annotator
+            .patterns
+            .extend(compile(extra, HtMcpError::InvalidRequest)?);
+        Ok(annotator)
+    }
+
+    pub fn annotate(&self, text: &str) -> Vec<Annotation> {
+        let mut annotations: Vec<Annotation> = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            for (pattern, matched) in self.line_matches(line) {
+                if pattern.group {
+                    let previous = annotations
+                        .iter_mut()
+                        .rev()
+                        .find(|annotation| annotation.kind == pattern.kind);
+                    if let Some(end_line) = previous.and_then(|a| a.end_line.as_mut()) {
+                        if line_number - *end_line <= 2 {
+                            *end_line = line_number;
+                            continue;
+                        }
+                    }
+                }
+                annotations.push(Annotation {
+                    line: line_number,
+                    end_line: pattern.group.then_some(line_number),
+                    kind: pattern.kind.clone(),
+                    matched: matched.to_string(),
+                });
+            }
+        }
+        annotations
+    }
+
+    /// Matches on one line, left to right, with overlapping matches of the
+    /// same kind reduced to the longest
+    fn line_matches<'a>(&'a self, line: &'a str) -> Vec<(&'a Pattern, &'a str)> {
+        let mut matches: Vec<(&Pattern, regex::Match)> = self
+            .patterns
+            .iter()
+            .flat_map(|pattern| pattern.regex.find_iter(line).map(move |m| (pattern, m)))
+            .collect();
+        // Longest first so it wins over the matches it overlaps
+        matches.sort_by_key(|(_, m)| std::cmp::Reverse(m.len()));
+
+        let mut kept: Vec<(&Pattern, regex::Match)> = Vec::new();
+        for (pattern, m) in matches {
+            let overlaps = kept.iter().any(|(other, k)| {
+                other.kind == pattern.kind && m.start() < k.end() && k.start() < m.end()
+            });
+            if !overlaps {
+                kept.push((pattern, m));
+            }
+        }
+        kept.sort_by_key(|(_, m)| m.start());
+        kept.into_iter()
+            .map(|(pattern, m)| (pattern, m.as_str()))
+            .collect()
+    }
+}
+
+impl Default for Annotator {
+    fn default() -> Self {
+        Self {
+            patterns: DEFAULT_PATTERNS
+                .iter()
+                .map(|&(kind, pattern, group)| Pattern {
+                    kind: kind.to_string(),
+                    // The table's patterns are fixed and covered by tests
+                    regex: Regex::new(pattern).expect("default annotation pattern"),
+                    group,
+                })
+                .collect(),
+        }
+    }
+}
+
+fn compile(
+    configs: &[AnnotationPatternConfig],
+    error: fn(String) -> HtMcpError,
+) -> Result<Vec<Pattern>> {
+    configs
+        .iter()
+        .map(|config| {
+            let regex = Regex::new(&config.pattern).map_err(|e| {
+                error(format!(
+                    "Invalid annotation pattern '{}': {}",
+                    config.pattern, e
+                ))
+            })?;
+            Ok(Pattern {
+                kind: config.kind.clone(),
+                regex,
+                group: config.group,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(text: &str) -> Vec<(usize, String)> {
+        Annotator::default()
+            .annotate(text)
+            .into_iter()
+            .map(|a| (a.line, a.kind))
+            .collect()
+    }
+
+    #[test]
+    fn test_default_patterns() {
+        let rustc = "   Compiling app v0.1.0\nerror[E0308]: mismatched types\nwarning: unused variable: `x`";
+        assert_eq!(
+            kinds(rustc),
+            [(2, "error".to_string()), (3, "warning".to_string())]
+        );
+
+        let pytest =
+            "test_app.py F\nE       assert 1 == 2\nFAILED test_app.py::test_sum - assert 1 == 2";
+        assert_eq!(
+            kinds(pytest),
+            [(2, "error".to_string()), (3, "error".to_string())]
+        );
+
+        let node = "TypeError: Cannot read properties of undefined";
+        assert_eq!(kinds(node), [(1, "error".to_string())]);
+
+        let annotations = Annotator::default()
+            .annotate("Listening on http://127.0.0.1:3000/ (docs: https://x.io)");
+        let urls: Vec<&str> = annotations.iter().map(|a| a.matched.as_str()).collect();
+        assert_eq!(urls, ["http://127.0.0.1:3000/", "https://x.io"]);
+
+        assert!(kinds("$ ls\nCargo.toml  src").is_empty());
+    }
+
+    #[test]
+    fn test_overlapping_matches_of_a_kind_merge() {
+        // Two error patterns match; only the longer match is kept, while
+        // the URL inside it is a different kind and stays
+        let line = "thread 'main' panicked at src/main.rs:2:5: error: see https://x.io/e";
+        let annotations = Annotator::default().annotate(line);
+        assert_eq!(annotations.len(), 2, "{:?}", annotations);
+        assert_eq!(annotations[0].kind, "error");
+        assert_eq!(annotations[0].matched, line);
+        assert_eq!(annotations[1].kind, "url");
+        assert_eq!(annotations[1].matched, "https://x.io/e");
+    }
+
+    #[test]
+    fn test_stack_traces_are_grouped() {
+        let python = "$ python app.py\nTraceback (most recent call last):\n  File \"app.py\", line 3, in <module>\n    main()\n  File \"app.py\", line 1, in main\n    def main(): 1 / 0\nZeroDivisionError: division by zero\n$ ";
+        let annotations = Annotator::default().annotate(python);
+        assert_eq!(annotations.len(), 2, "{:?}", annotations);
+        assert_eq!(annotations[0].kind, "stack-trace");
+        assert_eq!((annotations[0].line, annotations[0].end_line), (2, Some(5)));
+        assert_eq!(annotations[1].kind, "error");
+        assert_eq!(annotations[1].line, 7);
+
+        let node = "Error: boom\n    at main (/app/index.js:3:9)\n    at Object.<anonymous> (/app/index.js:5:1)\n\n\n    at later (/app/other.js:1:1)";
+        let annotations = Annotator::default().annotate(node);
+        let traces: Vec<(usize, Option<usize>)> = annotations
+            .iter()
+            .filter(|a| a.kind == "stack-trace")
+            .map(|a| (a.line, a.end_line))
+            .collect();
+        assert_eq!(traces, [(2, Some(3)), (6, Some(6))]);
+    }
+
+    #[test]
+    fn test_configured_and_request_patterns() {
+        let annotator = Annotator::new(&[AnnotationPatternConfig {
+            kind: "todo".to_string(),
+            pattern: r"TODO\(\w+\)".to_string(),
+            group: false,
+        }])
+        .unwrap();
+        let annotations = annotator.annotate("fn f() {} // TODO(ann) tidy");
+        assert_eq!(annotations[0].kind, "todo");
+        assert_eq!(annotations[0].matched, "TODO(ann)");
+
+        let invalid = [AnnotationPatternConfig {
+            kind: "bad".to_string(),
+            pattern: "(".to_string(),
+            group: false,
+        }];
+        assert!(matches!(
+            Annotator::new(&invalid),
+            Err(HtMcpError::Config(_))
+        ));
+        assert!(matches!(
+            annotator.with_request_patterns(&invalid),
+            Err(HtMcpError::InvalidRequest(_))
+        ));
+    }
+}
@@ -0,0 +1,321 @@
+//! Checks on terminal content for driving TUI tests: a list of assertions
+//! evaluated together against one capture of the screen, each reported as
+//! passed or failed with the actual value it saw.
+//!
+//! Failed assertions are a normal result. Only assertions that cannot be
+//! evaluated at all (a bad regex, line 0) are errors, and they are reported
+//! before anything is checked. Lines, rows and columns are 1-based; trailing
+//! whitespace on screen lines is ignored.
+
+use crate::error::{HtMcpError, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// One check, written as e.g. `{"contains": "ready"}` or
+/// `{"lineEquals": {"line": 1, "text": "$ ls"}}`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Assertion {
+    Contains(String),
+    NotContains(String),
+    Regex(String),
+    LineEquals { line: usize, text: String },
+    CursorAt { row: usize, col: usize },
+    SizeIs { cols: usize, rows: usize },
+}
+
+/// What the assertions are checked against
+#[derive(Debug, Clone, PartialEq)]
+pub struct Screen {
+    pub text: String,
+    pub cols: usize,
+    pub rows: usize,
+    /// 1-based (row, col), when known
+    pub cursor: Option<(usize, usize)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AssertionResult {
+    pub assertion: Assertion,
+    pub passed: bool,
+    /// What the screen had instead, for failures that have one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actual: Option<Value>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AssertionReport {
+    pub passed: bool,
+    pub failures: usize,
+    pub results: Vec<AssertionResult>,
+}
+
+/// Evaluates every assertion against `screen`. Fails only if an assertion
+/// is malformed.
+pub fn check(screen: &Screen, assertions: &[Assertion]) -> Result<AssertionReport> {
+    if assertions.is_empty() {
+        return Err(HtMcpError::InvalidRequest(
+            "assertions must list at least one check".to_string(),
+        ));
+    }
+    // Validated up front so a bad assertion never yields a partial report
+    let regexes = assertions
+        .iter()
+        .map(|assertion| match assertion {
+            Assertion::Regex(pattern) => Regex::new(pattern).map(Some).map_err(|e| {
+                HtMcpError::InvalidRequest(format!("Invalid regex assertion '{}': {}", pattern, e))
+            }),
+            Assertion::LineEquals { line: 0, .. } => Err(HtMcpError::InvalidRequest(
+                "lineEquals line numbers start at 1".to_string(),
+            )),
+            Assertion::CursorAt { row, col } if *row == 0 || *col == 0 => Err(
+                HtMcpError::InvalidRequest("cursorAt rows and columns start at 1".to_string()),
+            ),
+            _ => Ok(None),
+        })
+        .collect::<Result<Vec<Option<Regex>>>>()?;
+
+    let lines: Vec<&str> = screen.text.lines().map(str::trim_end).collect();
+    let results: Vec<AssertionResult> = assertions
+        .iter()
+        .zip(regexes)
+        .map(|(assertion, regex)| {
+            let (passed, actual) = evaluate(screen, &lines, assertion, regex.as_ref());
+            AssertionResult {
+                assertion: assertion.clone(),
+                passed,
+                actual: if passed { None } else { actual },
+            }
+        })
+        .collect();
+
+    let failures = results.iter().filter(|result| !result.passed).count();
+    Ok(AssertionReport {
+        passed: failures == 0,
+        failures,
+        results,
+    })
+}
+
+fn evaluate(
+    screen: &Screen,
+    lines: &[&str],
+    assertion: &Assertion,
+    regex: Option<&Regex>,
+) -> (bool, Option<Value>) {
+    match assertion {
+        Assertion::Contains(text) => (screen.text.contains(text.as_str()), None),
+        Assertion::NotContains(text) => {
+            if !screen.text.contains(text.as_str()) {
+                return (true, None);
+            }
+            // The lines it was found on; empty when it spans lines
+            let found: Vec<Value> = lines
+                .iter()
+                .enumerate()
+                .filter(|(_, line)| line.contains(text.as_str()))
+                .map(|(index, line)| json!({"line": index + 1, "text": line}))
+                .collect();
+            (false, Some(Value::Array(found)))
+        }
+        Assertion::Regex(_) => {
+            let regex = regex.expect("regex assertions are compiled in check");
+            (regex.is_match(&screen.text), None)
+        }
+        Assertion::LineEquals { line, text } => match lines.get(line - 1) {
+            Some(actual) => (actual == text, Some(json!(actual))),
+            None => (false, Some(Value::Null)),
+        },
+        Assertion::CursorAt { row, col } => match screen.cursor {
+            Some(cursor) => (
+                cursor == (*row, *col),
+                Some(json!({"row": cursor.0, "col": cursor.1})),
+            ),
+            None => (false, Some(Value::Null)),
+        },
+        Assertion::SizeIs { cols, rows } => (
+            (screen.cols, screen.rows) == (*cols, *rows),
+            Some(json!({"cols": screen.cols, "rows": screen.rows})),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn screen() -> Screen {
+        Screen {
+            text: "$ cargo test   \nrunning 2 tests\ntest result: ok. 2 passed\n$ ".to_string(),
+            cols: 80,
+            rows: 24,
+            cursor: Some((4, 3)),
+        }
+    }
+
+    fn single(assertion: Assertion) -> AssertionResult {
+        let report = check(&screen(), &[assertion]).unwrap();
+        assert_eq!(report.passed, report.results[0].passed);
+        assert_eq!(report.failures, usize::from(!report.passed));
+        report.results.into_iter().next().unwrap()
+    }
+
+    #[test]
+    fn test_contains() {
+        assert!(single(Assertion::Contains("2 passed".to_string())).passed);
+        let result = single(Assertion::Contains("FAILED".to_string()));
+        assert!(!result.passed);
+        assert_eq!(result.actual, None);
+    }
+
+    #[test]
+    fn test_not_contains() {
+        assert!(single(Assertion::NotContains("FAILED".to_string())).passed);
+        let result = single(Assertion::NotContains("test".to_string()));
+        assert!(!result.passed);
+        assert_eq!(
+            result.actual,
+            Some(json!([
+                {"line": 1, "text": "$ cargo test"},
+                {"line": 2, "text": "running 2 tests"},
+                {"line": 3, "text": "test result: ok. 2 passed"}
+            ]))
+        );
+        // Text spanning a line break is still found
+        assert!(!single(Assertion::NotContains("tests\ntest result".to_string())).passed);
+    }
+
+    #[test]
+    fn test_regex() {
+        assert!(
+            single(Assertion::Regex(
+                r"test result: ok\. \d+ passed".to_string()
+            ))
+            .passed
+        );
+        assert!(single(Assertion::Regex(r"(?m)^\$ $".to_string())).passed);
+        assert!(!single(Assertion::Regex(r"\d+ failed".to_string())).passed);
+    }
+
+    #[test]
+    fn test_line_equals() {
+        // Trailing padding is ignored
+        let result = single(Assertion::LineEquals {
+            line: 1,
+            text: "$ cargo test".to_string(),
+        });
+        assert!(result.passed);
+        assert_eq!(result.actual, None);
+
+        let result = single(Assertion::LineEquals {
+            line: 2,
+            text: "running 3 tests".to_string(),
+        });
+        assert!(!result.passed);
+        assert_eq!(result.actual, Some(json!("running 2 tests")));
+
+        let result = single(Assertion::LineEquals {
+            line: 10,
+            text: String::new(),
+        });
+        assert!(!result.passed);
+        assert_eq!(result.actual, Some(Value::Null));
+    }
+
+    #[test]
+    fn test_cursor_at() {
+        assert!(single(Assertion::CursorAt { row: 4, col: 3 }).passed);
+        let result = single(Assertion::CursorAt { row: 1, col: 1 });
+        assert!(!result.passed);
+        assert_eq!(result.actual, Some(json!({"row": 4, "col": 3})));
+
+        let unknown = Screen {
+            cursor: None,
+            ..screen()
+        };
+        let report = check(&unknown, &[Assertion::CursorAt { row: 4, col: 3 }]).unwrap();
+        assert!(!report.passed);
+        assert_eq!(report.results[0].actual, Some(Value::Null));
+    }
+
+    #[test]
+    fn test_size_is() {
+        assert!(single(Assertion::SizeIs { cols: 80, rows: 24 }).passed);
+        let result = single(Assertion::SizeIs {
+            cols: 120,
+            rows: 40,
+        });
+        assert!(!result.passed);
+        assert_eq!(result.actual, Some(json!({"cols": 80, "rows": 24})));
+    }
+
+    #[test]
+    fn test_report_counts_failures() {
+        let report = check(
+            &screen(),
+            &[
+                Assertion::Contains("running".to_string()),
+                Assertion::Contains("missing".to_string()),
+                Assertion::SizeIs { cols: 1, rows: 1 },
+            ],
+        )
+        .unwrap();
+        assert!(!report.passed);
+        assert_eq!(report.failures, 2);
+        let passed: Vec<bool> = report.results.iter().map(|r| r.passed).collect();
+        assert_eq!(passed, [true, false, false]);
+    }
+
+    #[test]
+    fn test_malformed_assertions_are_errors() {
+        for assertion in [
+            Assertion::Regex("(".to_string()),
+            Assertion::LineEquals {
+                line: 0,
+                text: String::new(),
+            },
+            Assertion::CursorAt { row: 0, col: 1 },
+        ] {
+            // A valid assertion alongside does not turn it into a partial report
+            let err = check(
+                &screen(),
+                &[Assertion::Contains("$".to_string()), assertion],
+            )
+            .unwrap_err();
+            assert!(matches!(err, HtMcpError::InvalidRequest(_)));
+        }
+        assert!(matches!(
+            check(&screen(), &[]),
+            Err(HtMcpError::InvalidRequest(_))
+        ));
+    }
+
+    #[test]
+    fn test_assertion_json() {
+        let assertions: Vec<Assertion> = serde_json::from_value(json!([
+            {"contains": "a"},
+            {"notContains": "b"},
+            {"regex": "c+"},
+            {"lineEquals": {"line": 2, "text": "d"}},
+            {"cursorAt": {"row": 1, "col": 5}},
+            {"sizeIs": {"cols": 80, "rows": 24}}
+        ]))
+        .unwrap();
+        assert_eq!(
+            assertions,
+            [
+                Assertion::Contains("a".to_string()),
+                Assertion::NotContains("b".to_string()),
+                Assertion::Regex("c+".to_string()),
+                Assertion::LineEquals {
+                    line: 2,
+                    text: "d".to_string()
+                },
+                Assertion::CursorAt { row: 1, col: 5 },
+                Assertion::SizeIs { cols: 80, rows: 24 },
+            ]
+        );
+        assert!(serde_json::from_value::<Assertion>(json!({"startsWith": "x"})).is_err());
+    }
+}
@@ -0,0 +1,171 @@
+//! Stored screen snapshots to compare later ones against, for regression
+//! tests of terminal programs.
+//!
+//! Baselines are plain text files at `<baseline_dir>/<session id>/<baseline
+//! id>.txt`, so they can be inspected or edited by hand. Comparison is line
+//! by line, as screen rows line up, ignoring trailing whitespace and
+//! trailing blank lines.
+
+use crate::error::{HtMcpError, Result};
+use crate::ht_integration::snapshot;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+/// Longest accepted baseline id
+const MAX_ID_LEN: usize = 128;
+
+/// A line that differs from the baseline; `None` where one side has no such
+/// line
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LineDiff {
+    /// 1-based line number
+    pub line: usize,
+    pub expected: Option<String>,
+    pub actual: Option<String>,
+}
+
+/// Where the baseline `baseline_id` of `session_id` is stored under `dir`
+pub fn path(dir: &Path, session_id: &str, baseline_id: &str) -> Result<PathBuf> {
+    // Both become path components, so neither may leave the directory
+    for (name, id) in [("sessionId", session_id), ("baselineId", baseline_id)] {
+        let valid = !id.is_empty()
+            && id.len() <= MAX_ID_LEN
+            && !id.starts_with('.')
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        if !valid {
+            return Err(HtMcpError::InvalidRequest(format!(
+                "{} '{}' must be 1-{} letters, digits, '-', '_' or '.', not starting with '.'",
+                name, id, MAX_ID_LEN
+            )));
+        }
+    }
+    Ok(dir.join(session_id).join(format!("{}.txt", baseline_id)))
+}
+
+/// Stores `text` as a baseline, replacing any earlier one with the same id
+pub fn save(dir: &Path, session_id: &str, baseline_id: &str, text: &str) -> Result<PathBuf> {
+    let path = path(dir, session_id, baseline_id)?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| {
+            HtMcpError::Internal(format!(
+                "Failed to create baseline dir {}: {}",
+                parent.display(),
+                e
+            ))
+        })?;
+    }
+    std::fs::write(&path, text).map_err(|e| {
+        HtMcpError::Internal(format!(
+            "Failed to write baseline {}: {}",
+            path.display(),
+            e
+        ))
+    })?;
+    Ok(path)
+}
+
+pub fn load(dir: &Path, session_id: &str, baseline_id: &str) -> Result<String> {
+    let path = path(dir, session_id, baseline_id)?;
+    std::fs::read_to_string(&path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => HtMcpError::InvalidRequest(format!(
+            "No baseline '{}' for session {}; take one with ht_take_baseline_snapshot",
+            baseline_id, session_id
+        )),
+        _ => HtMcpError::Internal(format!("Failed to read baseline {}: {}", path.display(), e)),
+    })
+}
+
+/// Lines of `actual` that differ from `expected`
+pub fn diff(expected: &str, actual: &str) -> Vec<LineDiff> {
+    let lines = |text| -> Vec<&str> {
+        snapshot::trim_trailing_blank_lines(text)
+            .lines()
+            .map(str::trim_end)
+            .collect()
+    };
+    let (expected, actual) = (lines(expected), lines(actual));
+    (0..expected.len().max(actual.len()))
+        .filter_map(|index| {
+            let (want, got) = (expected.get(index), actual.get(index));
+            (want != got).then(|| LineDiff {
+                line: index + 1,
+                expected: want.map(|line| line.to_string()),
+                actual: got.map(|line| line.to_string()),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        let baseline = "$ ls\nCargo.toml  src\n$ \n\n";
+        assert!(diff(baseline, "$ ls   \nCargo.toml  src\n$ ").is_empty());
+
+        assert_eq!(
+            diff(baseline, "$ ls\nCargo.lock  src\n$ \nextra"),
+            [
+                LineDiff {
+                    line: 2,
+                    expected: Some("Cargo.toml  src".to_string()),
+                    actual: Some("Cargo.lock  src".to_string()),
+                },
+                LineDiff {
+                    line: 4,
+                    expected: None,
+                    actual: Some("extra".to_string()),
+                },
+            ]
+        );
+        assert_eq!(
+            diff(baseline, "$ ls"),
+            [
+                LineDiff {
+                    line: 2,
+                    expected: Some("Cargo.toml  src".to_string()),
+                    actual: None,
+                },
+                LineDiff {
+                    line: 3,
+                    expected: Some("$".to_string()),
+                    actual: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = std::env::temp_dir().join(format!("ht-mcp-baselines-{}", uuid::Uuid::new_v4()));
+        let path = save(&dir, "s1", "login-screen", "Welcome\n").unwrap();
+        assert_eq!(path, dir.join("s1").join("login-screen.txt"));
+        assert_eq!(load(&dir, "s1", "login-screen").unwrap(), "Welcome\n");
+
+        save(&dir, "s1", "login-screen", "Welcome back\n").unwrap();
+        assert_eq!(load(&dir, "s1", "login-screen").unwrap(), "Welcome back\n");
+
+        assert!(matches!(
+            load(&dir, "s1", "missing"),
+            Err(HtMcpError::InvalidRequest(_))
+        ));
+        for id in [
+            "",
+            "../escape",
+            "a/b",
+            ".hidden",
+            &"x".repeat(MAX_ID_LEN + 1),
+        ] {
+            assert!(
+                matches!(save(&dir, "s1", id, ""), Err(HtMcpError::InvalidRequest(_))),
+                "{:?}",
+                id
+            );
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,154 @@
+//! cgroup v2 memory limits for session processes (Linux only)
+
+use crate::error::{HtMcpError, Result};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Mount point of the unified cgroup v2 hierarchy
+pub const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Parent cgroup under which per-session cgroups are created
+const PARENT_CGROUP: &str = "ht-mcp";
+
+/// A per-session cgroup with a memory limit
+#[derive(Debug, Clone)]
+pub struct MemoryCgroup {
+    path: PathBuf,
+}
+
+impl MemoryCgroup {
+    /// Whether cgroup v2 is mounted at `CGROUP_ROOT`
+    pub fn is_supported() -> bool {
+        cfg!(target_os = "linux") && Path::new(CGROUP_ROOT).join("cgroup.controllers").exists()
+    }
+
+    /// Creates `/sys/fs/cgroup/ht-mcp/<session_id>` limited to `max_bytes`
+    pub fn create(session_id: &str, max_bytes: u64) -> Result<Self> {
+        if !Self::is_supported() {
+            return Err(HtMcpError::InvalidRequest(
+                "Memory limits require Linux with cgroup v2 mounted at /sys/fs/cgroup".to_string(),
+            ));
+        }
+        Self::create_in(Path::new(CGROUP_ROOT), session_id, max_bytes)
+    }
+
+    /// Creates the session cgroup below an arbitrary cgroup v2 root
+    pub fn create_in(root: &Path, session_id: &str, max_bytes: u64) -> Result<Self> {
+        let parent = root.join(PARENT_CGROUP);
+        std::fs::create_dir_all(&parent).map_err(|e| cgroup_error(&parent, e))?;
+
+        // The memory controller must be delegated to children of the parent;
+        // this fails harmlessly when it already is (or on a test filesystem)
+        let _ = std::fs::write(parent.join("cgroup.subtree_control"), "+memory");
+
+        let path = parent.join(session_id);
+        std::fs::create_dir_all(&path).map_err(|e| cgroup_error(&path, e))?;
+        let cgroup = Self { path };
+
+        let memory_max = cgroup.path.join("memory.max");
+        if let Err(e) = std::fs::write(&memory_max, max_bytes.to_string()) {
+            let _ = std::fs::remove_dir(&cgroup.path);
+            return Err(cgroup_error(&memory_max, e));
+        }
+
+        info!(
+            "Created cgroup {} with memory.max = {} bytes",
+            cgroup.path.display(),
+            max_bytes
+        );
+        Ok(cgroup)
+    }
+
+    /// The file a process writes `0` to in order to join the cgroup, with
+    /// its future children; the PTY child does so before exec
+    pub fn procs_file(&self) -> PathBuf {
+        self.path.join("cgroup.procs")
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Removes the cgroup. The kernel refuses while processes remain in it.
+    pub fn remove(&self) -> Result<()> {
+        std::fs::remove_dir(&self.path).map_err(|e| cgroup_error(&self.path, e))
+    }
+
+    /// Removes the cgroup once its processes have exited, retrying for a few
+    /// seconds since the PTY child is signalled asynchronously on close
+    pub async fn remove_when_empty(self) {
+        for _ in 0..50 {
+            if self.remove().is_ok() {
+                info!("Removed cgroup {}", self.path.display());
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        warn!(
+            "Giving up removing cgroup {}; processes may still be running",
+            self.path.display()
+        );
+    }
+}
+
+fn cgroup_error(path: &Path, e: std::io::Error) -> HtMcpError {
+    HtMcpError::Internal(format!(
+        "cgroup operation on {} failed: {}",
+        path.display(),
+        e
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_writes_limit_and_procs() {
+        let root = tempfile::tempdir().unwrap();
+        let cgroup = MemoryCgroup::create_in(root.path(), "session-1", 64 * 1024 * 1024).unwrap();
+
+        assert_eq!(cgroup.path(), root.path().join("ht-mcp").join("session-1"));
+        let limit = std::fs::read_to_string(cgroup.path().join("memory.max")).unwrap();
+        assert_eq!(limit, "67108864");
+
+        assert_eq!(cgroup.procs_file(), cgroup.path().join("cgroup.procs"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_pty_child_joins_before_exec() {
+        use crate::ht_integration::pty::{self, SpawnOptions};
+        use tokio::sync::mpsc;
+
+        // A plain file stands in for cgroup.procs: it receives what the
+        // child writes to join
+        let root = tempfile::tempdir().unwrap();
+        let procs = root.path().join("cgroup.procs");
+        std::fs::write(&procs, "").unwrap();
+        let options = SpawnOptions {
+            cgroup_procs: Some(procs.clone()),
+            ..SpawnOptions::default()
+        };
+        let (_input_tx, input_rx) = mpsc::channel(1);
+        let (output_tx, mut output_rx) = mpsc::channel(16);
+        let (_child, future) = pty::spawn(
+            "echo started".to_string(),
+            80,
+            24,
+            &options,
+            input_rx,
+            output_tx,
+        )
+        .unwrap();
+        tokio::spawn(future);
+
+        let mut output = String::new();
+        while let Some(data) = output_rx.recv().await {
+            output.push_str(&String::from_utf8_lossy(&data));
+        }
+        assert!(output.contains("started"), "unexpected output: {}", output);
+        assert_eq!(std::fs::read_to_string(&procs).unwrap(), "0");
+    }
+}
@@ -0,0 +1,382 @@
+//! Default `timeoutMs` and quiet period for `ht_execute_command`, picked by
+//! what the command runs.
+//!
+//! Classes are rows in a table: a name, a regex for the command and the
+//! budgets that apply, or a hint to refuse the command with. The first
+//! class whose pattern matches wins; commands no class matches get the
+//! `default` class. Classes from the config are checked before the built-in
+//! ones and replace built-in classes of the same name. A call's own
+//! `timeoutMs`, `quietPeriodMs` and `commandClass` always win.
+
+use crate::error::{HtMcpError, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Class of the commands no other class matches
+pub const DEFAULT_CLASS: &str = "default";
+
+/// How long to wait for a command to finish unless its class says
+/// otherwise; below the default tool timeout so the screen is still returned
+pub const DEFAULT_COMPLETION_TIMEOUT_MS: u64 = 20_000;
+
+/// How long to wait before taking the screen when a command's end cannot be
+/// detected, unless its class says otherwise
+pub const DEFAULT_QUIET_PERIOD_MS: u64 = 1_000;
+
+/// A leading `sudo` and variable assignments, skipped before matching
+const COMMAND_PREAMBLE: &str = r"^\s*(?:sudo\s+(?:-\S+\s+)*)?(?:\w+=\S*\s+)*";
+
+struct ClassSpec {
+    name: &'static str,
+    pattern: &'static str,
+    timeout_ms: Option<u64>,
+    quiet_period_ms: Option<u64>,
+    reject: Option<&'static str>,
+}
+
+const BUILTIN_CLASSES: &[ClassSpec] = &[
+    ClassSpec {
+        name: "build",
+        pattern: r"^(?:cargo\s+(?:build|test|check|clippy|install|bench)|npm\s+(?:install|ci|i|run\s+build)|yarn(?:\s+install)?|pnpm\s+(?:install|i)|pip3?\s+install|python3?\s+-m\s+pip\s+install|make|cmake\s+--build|ninja|go\s+(?:build|test|install)|mvn|gradle|\./gradlew|docker\s+build|apt(?:-get)?\s+(?:install|upgrade))(?:\s|$)",
+        timeout_ms: Some(600_000),
+        quiet_period_ms: Some(5_000),
+        reject: None,
+    },
+    ClassSpec {
+        name: "interactive",
+        pattern: r"^(?:vi|vim|nvim|nano|emacs|less|more|man|top|htop|watch)(?:\s|$)",
+        timeout_ms: None,
+        quiet_period_ms: None,
+        reject: Some("it takes over the terminal until you quit it, so ht_execute_command cannot tell when it is done. Start it with ht_send_keys, read the screen with ht_take_snapshot and quit it with its own keys (q for less, man and top, Escape then :q! Enter for vim), or pass commandClass \"default\" to run it anyway"),
+    },
+];
+
+/// A class from the config. Budgets left out are the `default` class's.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommandClassConfig {
+    pub name: String,
+    /// Regex matched against the command, after a leading `sudo` and
+    /// variable assignments
+    pub pattern: String,
+    pub timeout_ms: Option<u64>,
+    pub quiet_period_ms: Option<u64>,
+    /// Refuse matching commands, telling the caller this instead
+    pub reject: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CommandClass {
+    pub name: String,
+    pattern: Option<Regex>,
+    pub timeout: Duration,
+    pub quiet_period: Duration,
+    pub reject: Option<String>,
+}
+
+impl CommandClass {
+    /// Fails with the class's hint when it refuses commands
+    pub fn check(&self, command: &str) -> Result<()> {
+        match &self.reject {
+            Some(hint) => Err(HtMcpError::InvalidRequest(format!(
+                "'{}' is in command class {}: {}",
+                command.trim(),
+                self.name,
+                hint
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CommandClassifier {
+    /// Matched in order; `default` is not among them
+    classes: Vec<CommandClass>,
+    default: CommandClass,
+    preamble: Regex,
+}
+
+impl CommandClassifier {
+    /// The built-in classes, preceded by `configured` ones
+    pub fn new(configured: &[CommandClassConfig]) -> Result<Self> {
+        let mut classifier = Self::default();
+        let mut classes = Vec::with_capacity(configured.len() + classifier.classes.len());
+        for (index, config) in configured.iter().enumerate() {
+            if config.name.trim().is_empty() || config.name == DEFAULT_CLASS {
+                return Err(HtMcpError::Config(format!(
+                    "command class names must be non-empty and not '{}'",
+                    DEFAULT_CLASS
+                )));
+            }
+            if configured[..index]
+                .iter()
+                .any(|other| other.name == config.name)
+            {
+                return Err(HtMcpError::Config(format!(
+                    "command class '{}' is defined twice",
+                    config.name
+                )));
+            }
+            if config.timeout_ms == Some(0) {
+                return Err(HtMcpError::Config(format!(
+                    "command class '{}' needs a timeout_ms of at least 1",
+                    config.name
+                )));
+            }
+            let pattern = Regex::new(&config.pattern).map_err(|e| {
+                HtMcpError::Config(format!(
+                    "Invalid pattern for command class '{}': {}",
+                    config.name, e
+                ))
+            })?;
+            classes.push(CommandClass {
+                name: config.name.clone(),
+                pattern: Some(pattern),
+                timeout: config
+                    .timeout_ms
+                    .map_or(classifier.default.timeout, Duration::from_millis),
+                quiet_period: config
+                    .quiet_period_ms
+                    .map_or(classifier.default.quiet_period, Duration::from_millis),
+                reject: config.reject.clone(),
+            });
+        }
+        classes.extend(
+            classifier
+                .classes
+                .drain(..)
+                .filter(|builtin| !configured.iter().any(|c| c.name == builtin.name)),
+        );
+        classifier.classes = classes;
+        Ok(classifier)
+    }
+
+    /// The first class matching `command`, or `default`
+    pub fn classify(&self, command: &str) -> &CommandClass {
+        let program = self.preamble.replace(command, "");
+        self.classes
+            .iter()
+            .find(|class| {
+                class
+                    .pattern
+                    .as_ref()
+                    .is_some_and(|pattern| pattern.is_match(&program))
+            })
+            .unwrap_or(&self.default)
+    }
+
+    /// The class named `name`, for a call that picks its own
+    pub fn get(&self, name: &str) -> Result<&CommandClass> {
+        if name == DEFAULT_CLASS {
+            return Ok(&self.default);
+        }
+        self.classes
+            .iter()
+            .find(|class| class.name == name)
+            .ok_or_else(|| {
+                let names: Vec<&str> = std::iter::once(DEFAULT_CLASS)
+                    .chain(self.classes.iter().map(|class| class.name.as_str()))
+                    .collect();
+                HtMcpError::InvalidRequest(format!(
+                    "Unknown commandClass '{}'; known classes: {}",
+                    name,
+                    names.join(", ")
+                ))
+            })
+    }
+
+    /// The class a call runs `command` under: the one it names, or the
+    /// one the command matches
+    pub fn for_call(&self, command: &str, requested: Option<&str>) -> Result<&CommandClass> {
+        match requested {
+            Some(name) => self.get(name),
+            None => Ok(self.classify(command)),
+        }
+    }
+}
+
+impl Default for CommandClassifier {
+    fn default() -> Self {
+        // The table's patterns are fixed and covered by tests
+        let regex = |pattern: &str| Regex::new(pattern).expect("built-in command class pattern");
+        let default = CommandClass {
+            name: DEFAULT_CLASS.to_string(),
+            pattern: None,
+            timeout: Duration::from_millis(DEFAULT_COMPLETION_TIMEOUT_MS),
+            quiet_period: Duration::from_millis(DEFAULT_QUIET_PERIOD_MS),
+            reject: None,
+        };
+        Self {
+            classes: BUILTIN_CLASSES
+                .iter()
+                .map(|spec| CommandClass {
+                    name: spec.name.to_string(),
+                    pattern: Some(regex(spec.pattern)),
+                    timeout: spec
+                        .timeout_ms
+                        .map_or(default.timeout, Duration::from_millis),
+                    quiet_period: spec
+                        .quiet_period_ms
+                        .map_or(default.quiet_period, Duration::from_millis),
+                    reject: spec.reject.map(str::to_string),
+                })
+                .collect(),
+            default,
+            preamble: regex(COMMAND_PREAMBLE),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn class_name<'a>(classifier: &'a CommandClassifier, command: &str) -> &'a str {
+        &classifier.classify(command).name
+    }
+
+    #[test]
+    fn test_builtin_classes() {
+        let classifier = CommandClassifier::default();
+        for command in [
+            "cargo build --release",
+            "npm install",
+            "pip install -r requirements.txt",
+            "make",
+            "make -j8 all",
+            "  sudo apt-get install -y jq",
+            "RUSTFLAGS=-Dwarnings cargo test",
+        ] {
+            assert_eq!(class_name(&classifier, command), "build", "{}", command);
+        }
+        for command in [
+            "vim src/main.rs",
+            "less log.txt",
+            "sudo -E nano /etc/hosts",
+            "top",
+        ] {
+            assert_eq!(
+                class_name(&classifier, command),
+                "interactive",
+                "{}",
+                command
+            );
+        }
+        for command in [
+            "ls -la",
+            "cargo --version",
+            "makefile-lint",
+            "echo vim",
+            "lesser",
+        ] {
+            assert_eq!(
+                class_name(&classifier, command),
+                DEFAULT_CLASS,
+                "{}",
+                command
+            );
+        }
+
+        let build = classifier.classify("cargo build");
+        assert_eq!(build.timeout, Duration::from_secs(600));
+        assert_eq!(build.quiet_period, Duration::from_secs(5));
+        let default = classifier.classify("ls");
+        assert_eq!(
+            default.timeout,
+            Duration::from_millis(DEFAULT_COMPLETION_TIMEOUT_MS)
+        );
+        assert_eq!(
+            default.quiet_period,
+            Duration::from_millis(DEFAULT_QUIET_PERIOD_MS)
+        );
+    }
+
+    #[test]
+    fn test_interactive_commands_are_rejected_with_a_hint() {
+        let classifier = CommandClassifier::default();
+        let err = classifier
+            .classify("vim notes.txt")
+            .check("vim notes.txt")
+            .unwrap_err();
+        let HtMcpError::InvalidRequest(message) = err else {
+            panic!("expected InvalidRequest, got {}", err);
+        };
+        assert!(message.contains("'vim notes.txt' is in command class interactive"));
+        assert!(message.contains("ht_send_keys"));
+        assert!(classifier
+            .classify("cargo build")
+            .check("cargo build")
+            .is_ok());
+        // Picking a class by name skips the rejection
+        let forced = classifier
+            .for_call("less log.txt", Some(DEFAULT_CLASS))
+            .unwrap();
+        assert!(forced.check("less log.txt").is_ok());
+    }
+
+    #[test]
+    fn test_configured_classes_come_first_and_replace_builtins() {
+        let configured = [
+            CommandClassConfig {
+                name: "deploy".to_string(),
+                pattern: r"^(?:\./deploy\.sh|make\s+deploy)".to_string(),
+                timeout_ms: Some(900_000),
+                quiet_period_ms: None,
+                reject: None,
+            },
+            CommandClassConfig {
+                name: "interactive".to_string(),
+                pattern: r"^vim(?:\s|$)".to_string(),
+                timeout_ms: None,
+                quiet_period_ms: None,
+                reject: Some("use the editor tools".to_string()),
+            },
+        ];
+        let classifier = CommandClassifier::new(&configured).unwrap();
+
+        // Before the built-in build class, which also matches make
+        let deploy = classifier.classify("make deploy");
+        assert_eq!(deploy.name, "deploy");
+        assert_eq!(deploy.timeout, Duration::from_secs(900));
+        assert_eq!(
+            deploy.quiet_period,
+            Duration::from_millis(DEFAULT_QUIET_PERIOD_MS)
+        );
+        assert_eq!(class_name(&classifier, "make"), "build");
+        // The configured interactive class replaced the built-in one
+        assert_eq!(class_name(&classifier, "less log.txt"), DEFAULT_CLASS);
+        let vim = classifier.classify("vim");
+        assert_eq!(vim.reject.as_deref(), Some("use the editor tools"));
+
+        assert_eq!(classifier.get("deploy").unwrap().name, "deploy");
+        let err = classifier.get("nope").unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("default, deploy, interactive, build"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_invalid_configured_classes() {
+        let class = |name: &str, pattern: &str, timeout_ms: Option<u64>| CommandClassConfig {
+            name: name.to_string(),
+            pattern: pattern.to_string(),
+            timeout_ms,
+            quiet_period_ms: None,
+            reject: None,
+        };
+        for configured in [
+            vec![class("bad", "(", None)],
+            vec![class("", "x", None)],
+            vec![class(DEFAULT_CLASS, "x", None)],
+            vec![class("slow", "x", Some(0))],
+            vec![class("twice", "x", None), class("twice", "y", None)],
+        ] {
+            let err = CommandClassifier::new(&configured).unwrap_err();
+            assert!(matches!(err, HtMcpError::Config(_)), "{}", err);
+        }
+    }
+}
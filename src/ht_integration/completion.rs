@@ -0,0 +1,258 @@
+//! Telling when a command run by `execute_command` has finished.
+//!
+//! The command is typed with an `echo` of a marker unique to the call and
+//! the command's exit status appended, so the marker shows up in the output
+//! once the shell is done with the command. The typed line itself shows the
+//! marker followed by a literal `$?`, which is not mistaken for the printed
+//! one. Only sessions running a POSIX shell get the marker; commands ending
+//! in a `#` comment would comment the echo out and need
+//! `waitForCompletion: false`.
+
+use crate::error::Result;
+use crate::ht_integration::session_handle::request_snapshot;
+use crate::ht_integration::session_manager::SessionCommand;
+use bytes::Bytes;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::{mpsc, Semaphore};
+use uuid::Uuid;
+
+/// Programs that understand `; echo ..._$?`
+const POSIX_SHELLS: &[&str] = &["ash", "bash", "dash", "ksh", "mksh", "sh", "zsh"];
+
+/// Whether a session started with `command` runs a POSIX shell
+pub fn is_posix_shell(command: &[String]) -> bool {
+    command.first().is_some_and(|program| {
+        let name = program.rsplit('/').next().unwrap_or(program);
+        POSIX_SHELLS.contains(&name)
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct Sentinel {
+    marker: String,
+}
+
+impl Default for Sentinel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sentinel {
+    pub fn new() -> Self {
+        Self {
+            marker: format!("__HT_DONE_{}", Uuid::new_v4().simple()),
+        }
+    }
+
+    /// What is typed after `command` to print the marker
+    fn suffix(&self, command: &str) -> String {
+        let command = command.trim_end();
+        // `cmd &; echo` is a syntax error, `cmd & echo` is not
+        let separator = if command.ends_with('&') && !command.ends_with("&&") {
+            " "
+        } else {
+            "; "
+        };
+        format!("{}echo {}_$?", separator, self.marker)
+    }
+
+    /// `command` followed by the echo of the marker
+    pub fn append_to(&self, command: &str) -> String {
+        format!("{}{}", command.trim_end(), self.suffix(command))
+    }
+
+    /// The exit status printed with the marker, once `output` has it
+    pub fn exit_code(&self, output: &str) -> Option<i32> {
+        self.find(output, false)
+    }
+
+    /// Like [`Sentinel::exit_code`] for output still arriving: the status
+    /// only counts once the line it is on has ended, so a status split
+    /// across reads is not cut short
+    pub fn finished(&self, output: &str) -> Option<i32> {
+        self.find(output, true)
+    }
+
+    fn find(&self, output: &str, line_ended: bool) -> Option<i32> {
+        output.match_indices(&self.marker).find_map(|(index, _)| {
+            let status = output[index + self.marker.len()..].strip_prefix('_')?;
+            let rest = status.trim_start_matches(|c: char| c.is_ascii_digit());
+            if line_ended && !rest.starts_with(['\r', '\n']) {
+                return None;
+            }
+            status[..status.len() - rest.len()].parse().ok()
+        })
+    }
+
+    /// `screen` without the echo typed after `command` and the printed
+    /// marker. Best effort: an echo wrapped across lines is left as it is.
+    pub fn strip(&self, screen: &str, command: &str) -> String {
+        let suffix = self.suffix(command);
+        let mut lines = Vec::new();
+        for line in screen.lines() {
+            if line.contains(&suffix) {
+                lines.push(line.replace(&suffix, ""));
+                continue;
+            }
+            match self.exit_code(line) {
+                Some(code) => {
+                    let rest = line.replace(&format!("{}_{}", self.marker, code), "");
+                    // A command whose output ends without a newline has
+                    // the marker on its last line
+                    if !rest.trim().is_empty() {
+                        lines.push(rest);
+                    }
+                }
+                None => lines.push(line.to_string()),
+            }
+        }
+        lines.join("\n")
+    }
+
+    /// Adds `chunk` of PTY output to `tail` and looks for the printed
+    /// marker. Only the end of `tail` that could hold the start of a marker
+    /// split across chunks is kept for the next call.
+    pub fn scan(&self, tail: &mut String, chunk: &[u8]) -> Option<i32> {
+        tail.push_str(&String::from_utf8_lossy(chunk));
+        let code = self.finished(tail);
+        // The marker, `_`, an exit status of up to three digits and the
+        // line end
+        let keep = self.marker.len() + 5;
+        if tail.len() > keep {
+            let mut start = tail.len() - keep;
+            while !tail.is_char_boundary(start) {
+                start += 1;
+            }
+            tail.drain(..start);
+        }
+        code
+    }
+
+    /// Waits until the marker is printed to `output`, subscribed before the
+    /// command was sent. Returns the exit status, or `None` after `timeout`.
+    pub async fn wait(
+        &self,
+        mut output: broadcast::Receiver<Bytes>,
+        command_tx: &mpsc::Sender<SessionCommand>,
+        snapshot_permits: &Semaphore,
+        timeout: Duration,
+    ) -> Result<Option<i32>> {
+        let finished = async {
+            let mut tail = String::new();
+            loop {
+                match output.recv().await {
+                    Ok(chunk) => {
+                        if let Some(code) = self.scan(&mut tail, &chunk) {
+                            return Ok(code);
+                        }
+                    }
+                    // Output went by unread; the screen still shows the
+                    // marker if it was in there
+                    Err(RecvError::Lagged(_)) => {
+                        tail.clear();
+                        if let Some(code) =
+                            self.exit_code(&request_snapshot(command_tx, snapshot_permits).await?)
+                        {
+                            return Ok(code);
+                        }
+                    }
+                    // The session is gone, which the caller reports
+                    Err(RecvError::Closed) => std::future::pending::<()>().await,
+                }
+            }
+        };
+        match tokio::time::timeout(timeout, finished).await {
+            Ok(result) => result.map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_posix_shell() {
+        let command = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        assert!(is_posix_shell(&command(&["bash"])));
+        assert!(is_posix_shell(&command(&["/bin/sh", "-l"])));
+        assert!(!is_posix_shell(&command(&["python3"])));
+        assert!(!is_posix_shell(&command(&["fish"])));
+        assert!(!is_posix_shell(&[]));
+    }
+
+    #[test]
+    fn test_append_to() {
+        let sentinel = Sentinel::new();
+        let marker = &sentinel.marker;
+        assert_eq!(
+            sentinel.append_to("ls -la "),
+            format!("ls -la; echo {}_$?", marker)
+        );
+        assert_eq!(
+            sentinel.append_to("sleep 5 &"),
+            format!("sleep 5 & echo {}_$?", marker)
+        );
+        assert_eq!(
+            sentinel.append_to("make && make test"),
+            format!("make && make test; echo {}_$?", marker)
+        );
+    }
+
+    #[test]
+    fn test_exit_code() {
+        let sentinel = Sentinel::new();
+        let typed = format!("$ false{}", sentinel.suffix("false"));
+        assert_eq!(sentinel.exit_code(&typed), None);
+        let printed = format!("{}\n{}_1\n$ ", typed, sentinel.marker);
+        assert_eq!(sentinel.exit_code(&printed), Some(1));
+        assert_eq!(
+            sentinel.exit_code(&format!("{}_127", sentinel.marker)),
+            Some(127)
+        );
+        assert_eq!(sentinel.exit_code("__HT_DONE_other_0"), None);
+
+        let partial = format!("{}_12", sentinel.marker);
+        assert_eq!(sentinel.finished(&partial), None);
+        assert_eq!(sentinel.finished(&format!("{}7\r\n", partial)), Some(127));
+    }
+
+    #[test]
+    fn test_scan_across_chunks() {
+        let sentinel = Sentinel::new();
+        let output = format!("building…\r\n{}_2\r\n$ ", sentinel.marker);
+        let mut tail = String::new();
+        let codes: Vec<Option<i32>> = output
+            .as_bytes()
+            .chunks(7)
+            .map(|chunk| sentinel.scan(&mut tail, chunk))
+            .collect();
+        assert_eq!(codes.iter().flatten().next(), Some(&2));
+        assert!(tail.len() <= sentinel.marker.len() + 5 + 7);
+    }
+
+    #[test]
+    fn test_strip() {
+        let sentinel = Sentinel::new();
+        let command = "printf done";
+        let screen = format!(
+            "$ ls\nfile\n$ {}\ndone{}_0\n$ ",
+            sentinel.append_to(command),
+            sentinel.marker
+        );
+        assert_eq!(
+            sentinel.strip(&screen, command),
+            "$ ls\nfile\n$ printf done\ndone\n$ "
+        );
+
+        let screen = format!(
+            "$ {}\n{}_0\n$ ",
+            sentinel.append_to("true"),
+            sentinel.marker
+        );
+        assert_eq!(sentinel.strip(&screen, "true"), "$ true\n$ ");
+    }
+}
@@ -0,0 +1,690 @@
+//! Connection limits in front of a session's web server.
+//!
+//! `http::start` serves whatever connects to its listener, so the guard owns
+//! the public listener and forwards admitted connections to the web server on
+//! a private loopback port. Connections over the limits are answered with a
+//! 503 and closed before they reach the web server.
+//!
+//! The guard also keeps the list of connected clients for
+//! `ht_get_connected_clients`, since it is the only place that sees their
+//! addresses and when they leave.
+//!
+//! What a viewer sends over its WebSocket does not go on to the web server:
+//! the guard reads it with a [`ViewerSocket`] and hands the input to the
+//! session manager, which decides whether it reaches the PTY.
+
+use crate::ht_integration::events::{EventBus, SessionEvent};
+use crate::ht_integration::viewer_input::{ViewerInput, ViewerSocket};
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+const PROXY_BUF_SIZE: usize = 16 * 1024;
+
+/// Largest request head the guard waits for before giving up on a client
+const MAX_HEAD_BYTES: usize = 16 * 1024;
+
+/// Status line of the web server accepting a WebSocket upgrade
+const UPGRADE_STATUS: &[u8] = b"HTTP/1.1 101";
+
+const REJECTION_RESPONSE: &[u8] =
+    b"HTTP/1.1 503 Service Unavailable\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+
+/// Limits applied to connections to a session's web server
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConnectionLimits {
+    /// Maximum concurrent HTTP/WebSocket connections per session
+    pub max_connections: usize,
+    /// Maximum concurrent connections from one IP address. Loopback peers
+    /// (including everything arriving through a tunnel) are exempt, since the
+    /// real client address is not visible to us.
+    pub max_connections_per_ip: usize,
+    /// Close connections with no traffic in either direction for this long
+    pub idle_timeout_secs: u64,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            max_connections: 64,
+            max_connections_per_ip: 16,
+            idle_timeout_secs: 30 * 60,
+        }
+    }
+}
+
+impl ConnectionLimits {
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+}
+
+/// Connection counters for one session's web server
+#[derive(Debug, Default)]
+pub struct ConnectionStats {
+    active: AtomicUsize,
+    accepted: AtomicU64,
+    rejected: AtomicU64,
+}
+
+impl ConnectionStats {
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    pub fn accepted(&self) -> u64 {
+        self.accepted.load(Ordering::Relaxed)
+    }
+
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    pub fn summary(&self) -> serde_json::Value {
+        serde_json::json!({
+            "active": self.active(),
+            "accepted": self.accepted(),
+            "rejected": self.rejected(),
+        })
+    }
+}
+
+/// A connection admitted to a session's web server
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientInfo {
+    #[serde(skip)]
+    pub id: u64,
+    /// RFC 3339 time the connection was accepted
+    pub connected_at: String,
+    /// Peer address; loopback for everything arriving through a tunnel
+    pub remote_addr: String,
+    /// Whether the connection was upgraded to a WebSocket, i.e. is a
+    /// terminal viewer rather than a page or asset request
+    #[serde(skip)]
+    pub websocket: bool,
+}
+
+/// Connections currently open to a session's web server
+pub type ConnectedClients = Arc<Mutex<Vec<ClientInfo>>>;
+
+/// Accepts connections on the public listeners and forwards those within the
+/// limits to the web server at `upstream`
+#[derive(Clone)]
+pub struct ConnectionGuard {
+    pub session_id: String,
+    pub limits: ConnectionLimits,
+    pub stats: Arc<ConnectionStats>,
+    pub clients: ConnectedClients,
+    pub events: EventBus,
+    /// Where input sent by WebSocket viewers goes
+    pub viewer_input: mpsc::Sender<ViewerInput>,
+}
+
+impl ConnectionGuard {
+    /// Serves every listener (e.g. one per address family) under the same
+    /// limits
+    pub async fn run(self, listeners: Vec<TcpListener>, upstream: SocketAddr) {
+        let guard = Arc::new(self);
+        let per_ip: Arc<Mutex<HashMap<IpAddr, usize>>> = Arc::default();
+        join_all(
+            listeners
+                .into_iter()
+                .map(|listener| guard.clone().serve(listener, upstream, per_ip.clone())),
+        )
+        .await;
+    }
+
+    async fn serve(
+        self: Arc<Self>,
+        listener: TcpListener,
+        upstream: SocketAddr,
+        per_ip: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    ) {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    warn!(
+                        "Accept failed on web server for session {}: {}",
+                        self.session_id, e
+                    );
+                    continue;
+                }
+            };
+
+            let slot = match self.admit(peer, &per_ip) {
+                Ok(slot) => slot,
+                Err(reason) => {
+                    self.reject(stream, peer, reason);
+                    continue;
+                }
+            };
+
+            let idle_timeout = self.limits.idle_timeout();
+            let socket = ViewerSocket::new(
+                self.session_id.clone(),
+                peer.to_string(),
+                self.viewer_input.clone(),
+            );
+            tokio::spawn(async move {
+                if let Err(e) = proxy(stream, upstream, idle_timeout, &slot, socket).await {
+                    debug!("Connection from {} ended with error: {}", peer, e);
+                }
+                drop(slot);
+            });
+        }
+    }
+
+    /// Reserves a connection slot for `peer`, or says which limit is reached
+    fn admit(
+        &self,
+        peer: SocketAddr,
+        per_ip: &Arc<Mutex<HashMap<IpAddr, usize>>>,
+    ) -> std::result::Result<ConnectionSlot, String> {
+        if self.stats.active() >= self.limits.max_connections {
+            return Err(format!(
+                "max connections ({}) reached",
+                self.limits.max_connections
+            ));
+        }
+
+        let ip = (!peer.ip().is_loopback()).then_some(peer.ip());
+        if let Some(ip) = ip {
+            let mut counts = per_ip.lock().unwrap();
+            let count = counts.entry(ip).or_default();
+            if *count >= self.limits.max_connections_per_ip {
+                return Err(format!(
+                    "max connections per IP ({}) reached",
+                    self.limits.max_connections_per_ip
+                ));
+            }
+            *count += 1;
+        }
+
+        self.stats.active.fetch_add(1, Ordering::Relaxed);
+        let id = self.stats.accepted.fetch_add(1, Ordering::Relaxed);
+        self.clients.lock().unwrap().push(ClientInfo {
+            id,
+            connected_at: chrono::Utc::now().to_rfc3339(),
+            remote_addr: peer.to_string(),
+            websocket: false,
+        });
+        Ok(ConnectionSlot {
+            stats: self.stats.clone(),
+            per_ip: per_ip.clone(),
+            ip,
+            clients: self.clients.clone(),
+            id,
+        })
+    }
+
+    fn reject(&self, mut stream: TcpStream, peer: SocketAddr, reason: String) {
+        let rejected = self.stats.rejected.fetch_add(1, Ordering::Relaxed) + 1;
+        // Every rejection is counted, but only the first of each hundred is
+        // logged so a crawler cannot flood the log
+        if rejected % 100 == 1 {
+            warn!(
+                "Rejected connection from {} to session {}: {} ({} rejected so far)",
+                peer, self.session_id, reason, rejected
+            );
+        }
+        self.events.publish(SessionEvent::ConnectionRejected {
+            session_id: self.session_id.clone(),
+            peer: peer.to_string(),
+            reason,
+        });
+        tokio::spawn(async move {
+            let _ = stream.write_all(REJECTION_RESPONSE).await;
+            let _ = stream.shutdown().await;
+        });
+    }
+}
+
+/// Releases an admitted connection's counts and client entry when dropped
+struct ConnectionSlot {
+    stats: Arc<ConnectionStats>,
+    per_ip: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    ip: Option<IpAddr>,
+    clients: ConnectedClients,
+    id: u64,
+}
+
+impl ConnectionSlot {
+    /// Marks the client a WebSocket viewer if `request`, the start of what
+    /// it sent, asks for the upgrade
+    fn inspect_request(&self, request: &[u8]) -> RequestHead {
+        let Some(head_len) = request
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .map(|pos| pos + 4)
+        else {
+            return RequestHead::Incomplete;
+        };
+        let head = String::from_utf8_lossy(&request[..head_len]).to_ascii_lowercase();
+        let websocket = head
+            .lines()
+            .take_while(|line| !line.is_empty())
+            .any(|line| line.starts_with("upgrade:") && line.contains("websocket"));
+        if !websocket {
+            return RequestHead::Other;
+        }
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(client) = clients.iter_mut().find(|client| client.id == self.id) {
+            client.websocket = true;
+        }
+        RequestHead::Viewer(head_len)
+    }
+}
+
+/// What the start of a client's first request turned out to be
+enum RequestHead {
+    /// The head has not all arrived yet
+    Incomplete,
+    /// A WebSocket upgrade; frames follow the head, which is this long
+    Viewer(usize),
+    Other,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.clients
+            .lock()
+            .unwrap()
+            .retain(|client| client.id != self.id);
+        self.stats.active.fetch_sub(1, Ordering::Relaxed);
+        if let Some(ip) = self.ip {
+            let mut counts = self.per_ip.lock().unwrap();
+            if let Some(count) = counts.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    counts.remove(&ip);
+                }
+            }
+        }
+    }
+}
+
+/// Copies bytes both ways until either side closes or the connection has been
+/// idle for `idle_timeout`. The client's first request head is shown to
+/// `slot`; once a viewer's head is through, what it sends is read by `socket`
+/// rather than copied. Only the first request may upgrade, so a connection
+/// the web server switches to a WebSocket later is closed: its frames would
+/// otherwise reach the server unread.
+async fn proxy(
+    mut client: TcpStream,
+    upstream: SocketAddr,
+    idle_timeout: Duration,
+    slot: &ConnectionSlot,
+    mut socket: ViewerSocket,
+) -> io::Result<()> {
+    let mut server = TcpStream::connect(upstream).await?;
+    let (mut client_rx, mut client_tx) = client.split();
+    let (mut server_rx, mut server_tx) = server.split();
+    let mut client_buf = vec![0u8; PROXY_BUF_SIZE];
+    let mut server_buf = vec![0u8; PROXY_BUF_SIZE];
+    let mut head = Vec::new();
+    let mut head_done = false;
+    let mut websocket = false;
+    let mut server_tail = Vec::new();
+
+    loop {
+        tokio::select! {
+            n = client_rx.read(&mut client_buf) => {
+                let n = n?;
+                if n == 0 {
+                    return Ok(());
+                }
+                let data = &client_buf[..n];
+                if websocket {
+                    socket.feed(data, &mut server_tx).await?;
+                } else if head_done {
+                    server_tx.write_all(data).await?;
+                } else {
+                    head.extend_from_slice(data);
+                    match slot.inspect_request(&head) {
+                        RequestHead::Incomplete if head.len() > MAX_HEAD_BYTES => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "request head too large",
+                            ));
+                        }
+                        RequestHead::Incomplete => {}
+                        RequestHead::Viewer(head_len) => {
+                            head_done = true;
+                            websocket = true;
+                            server_tx.write_all(&head[..head_len]).await?;
+                            socket.feed(&head[head_len..], &mut server_tx).await?;
+                        }
+                        RequestHead::Other => {
+                            head_done = true;
+                            server_tx.write_all(&head).await?;
+                        }
+                    }
+                }
+            }
+            n = server_rx.read(&mut server_buf) => {
+                let n = n?;
+                if n == 0 {
+                    return Ok(());
+                }
+                let data = &server_buf[..n];
+                if !websocket && switches_protocols(&mut server_tail, data) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "upgrade after the first request",
+                    ));
+                }
+                client_tx.write_all(data).await?;
+            }
+            _ = tokio::time::sleep(idle_timeout) => {
+                debug!("Closing connection idle for {:?}", idle_timeout);
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Whether the web server's output, read in pieces into `tail`, has
+/// accepted a WebSocket upgrade. Keeps the end of `data` in `tail` so a
+/// status line split across reads is still seen.
+fn switches_protocols(tail: &mut Vec<u8>, data: &[u8]) -> bool {
+    tail.extend_from_slice(data);
+    let found = tail
+        .windows(UPGRADE_STATUS.len())
+        .any(|window| window == UPGRADE_STATUS);
+    let keep_from = tail.len().saturating_sub(UPGRADE_STATUS.len() - 1);
+    tail.drain(..keep_from);
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Upstream that streams a tick to every connection every 20ms
+    async fn streaming_upstream() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    while stream.write_all(b"tick").await.is_ok() {
+                        tokio::time::sleep(Duration::from_millis(20)).await;
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    async fn start_guard(limits: ConnectionLimits) -> (SocketAddr, Arc<ConnectionStats>, EventBus) {
+        let upstream = streaming_upstream().await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stats = Arc::new(ConnectionStats::default());
+        let events = EventBus::new();
+        let guard = ConnectionGuard {
+            session_id: "session-1".to_string(),
+            limits,
+            stats: stats.clone(),
+            clients: ConnectedClients::default(),
+            events: events.clone(),
+            viewer_input: mpsc::channel(1).0,
+        };
+        tokio::spawn(guard.run(vec![listener], upstream));
+        (addr, stats, events)
+    }
+
+    async fn read_some(stream: &mut TcpStream) -> Vec<u8> {
+        let mut buf = [0u8; 64];
+        let n = tokio::time::timeout(Duration::from_secs(2), stream.read(&mut buf))
+            .await
+            .expect("read timed out")
+            .unwrap();
+        buf[..n].to_vec()
+    }
+
+    #[tokio::test]
+    async fn test_excess_connections_are_refused() {
+        let limits = ConnectionLimits {
+            max_connections: 3,
+            ..ConnectionLimits::default()
+        };
+        let (addr, stats, events) = start_guard(limits).await;
+        let mut event_rx = events.subscribe();
+
+        let mut viewers = Vec::new();
+        for _ in 0..3 {
+            let mut viewer = TcpStream::connect(addr).await.unwrap();
+            assert!(read_some(&mut viewer).await.starts_with(b"tick"));
+            viewers.push(viewer);
+        }
+
+        for _ in 0..2 {
+            let mut excess = TcpStream::connect(addr).await.unwrap();
+            let mut response = Vec::new();
+            tokio::time::timeout(Duration::from_secs(2), excess.read_to_end(&mut response))
+                .await
+                .expect("rejected connection should be closed")
+                .unwrap();
+            assert!(response.starts_with(b"HTTP/1.1 503"));
+        }
+
+        // Existing viewers keep streaming after the rejections
+        for viewer in &mut viewers {
+            assert!(!read_some(viewer).await.is_empty());
+        }
+
+        assert_eq!(stats.active(), 3);
+        assert_eq!(stats.accepted(), 3);
+        assert_eq!(stats.rejected(), 2);
+        assert!(matches!(
+            event_rx.recv().await.unwrap(),
+            SessionEvent::ConnectionRejected { session_id, .. } if session_id == "session-1"
+        ));
+
+        // Closing a viewer frees its slot
+        drop(viewers.pop());
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while stats.active() > 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("slot should be released");
+        let mut viewer = TcpStream::connect(addr).await.unwrap();
+        assert!(read_some(&mut viewer).await.starts_with(b"tick"));
+    }
+
+    #[tokio::test]
+    async fn test_idle_connections_are_closed() {
+        let limits = ConnectionLimits {
+            idle_timeout_secs: 1,
+            ..ConnectionLimits::default()
+        };
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        // A silent upstream that holds connections open
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((stream, _)) = upstream.accept().await {
+                held.push(stream);
+            }
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let guard = ConnectionGuard {
+            session_id: "session-1".to_string(),
+            limits,
+            stats: Arc::new(ConnectionStats::default()),
+            clients: ConnectedClients::default(),
+            events: EventBus::new(),
+            viewer_input: mpsc::channel(1).0,
+        };
+        tokio::spawn(guard.run(vec![listener], upstream_addr));
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let mut buf = Vec::new();
+        let n = tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut buf))
+            .await
+            .expect("idle connection should be closed")
+            .unwrap();
+        assert_eq!(n, 0);
+    }
+
+    #[tokio::test]
+    async fn test_connected_clients() {
+        let upstream = streaming_upstream().await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let clients = ConnectedClients::default();
+        let guard = ConnectionGuard {
+            session_id: "session-1".to_string(),
+            limits: ConnectionLimits::default(),
+            stats: Arc::new(ConnectionStats::default()),
+            clients: clients.clone(),
+            events: EventBus::new(),
+            viewer_input: mpsc::channel(1).0,
+        };
+        tokio::spawn(guard.run(vec![listener], upstream));
+
+        let mut viewer = TcpStream::connect(addr).await.unwrap();
+        viewer
+            .write_all(b"GET /ws/events HTTP/1.1\r\nHost: x\r\nUpgrade: WebSocket\r\nConnection: Upgrade\r\n\r\n")
+            .await
+            .unwrap();
+        let mut page = TcpStream::connect(addr).await.unwrap();
+        page.write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n")
+            .await
+            .unwrap();
+        read_some(&mut viewer).await;
+        read_some(&mut page).await;
+
+        let listed = clients.lock().unwrap().clone();
+        assert_eq!(listed.len(), 2);
+        let viewer_addr = viewer.local_addr().unwrap().to_string();
+        for client in &listed {
+            assert!(chrono::DateTime::parse_from_rfc3339(&client.connected_at).is_ok());
+            assert_eq!(client.websocket, client.remote_addr == viewer_addr);
+        }
+        assert!(listed.iter().any(|client| client.websocket));
+
+        // Disconnected clients leave the list
+        drop(viewer);
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while clients.lock().unwrap().len() > 1 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("client should be removed");
+        assert!(!clients.lock().unwrap()[0].websocket);
+    }
+
+    /// Upstream that records everything one connection sends, and accepts
+    /// the upgrade if `upgrade` is set
+    async fn recording_upstream(
+        upgrade: bool,
+    ) -> (SocketAddr, tokio::sync::oneshot::Receiver<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (received_tx, received_rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            if upgrade {
+                stream
+                    .write_all(b"HTTP/1.1 101 Switching Protocols\r\n\r\n")
+                    .await
+                    .unwrap();
+            }
+            let mut received = Vec::new();
+            let _ = stream.read_to_end(&mut received).await;
+            let _ = received_tx.send(received);
+        });
+        (addr, received_rx)
+    }
+
+    fn viewer_guard(session_id: &str) -> (ConnectionGuard, mpsc::Receiver<ViewerInput>) {
+        let (viewer_input, input_rx) = mpsc::channel(8);
+        let guard = ConnectionGuard {
+            session_id: session_id.to_string(),
+            limits: ConnectionLimits::default(),
+            stats: Arc::new(ConnectionStats::default()),
+            clients: ConnectedClients::default(),
+            events: EventBus::new(),
+            viewer_input,
+        };
+        (guard, input_rx)
+    }
+
+    #[tokio::test]
+    async fn test_viewer_messages_are_kept_from_the_web_server() {
+        use crate::ht_integration::viewer_input::client_frame;
+
+        let (upstream, received) = recording_upstream(true).await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (guard, mut input_rx) = viewer_guard("session-1");
+        tokio::spawn(guard.run(vec![listener], upstream));
+
+        let head: &[u8] = b"GET /ws/alis HTTP/1.1\r\nHost: x\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n";
+        let ping = client_frame(true, 0x9, b"ping");
+        let input = client_frame(true, 0x1, br#"{"type":"input","payload":"ls\r"}"#);
+        let mut viewer = TcpStream::connect(addr).await.unwrap();
+        // The head arrives in two reads and the frames with its end
+        viewer.write_all(&head[..10]).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        viewer
+            .write_all(&[&head[10..], &ping[..], &input[..]].concat())
+            .await
+            .unwrap();
+
+        let input = tokio::time::timeout(Duration::from_secs(2), input_rx.recv())
+            .await
+            .expect("input should be read")
+            .unwrap();
+        assert_eq!(input.session_id, "session-1");
+        assert_eq!(input.peer, viewer.local_addr().unwrap().to_string());
+        assert_eq!(input.keys, ["ls\r"]);
+
+        drop(viewer);
+        let received = tokio::time::timeout(Duration::from_secs(2), received)
+            .await
+            .expect("upstream should see the close")
+            .unwrap();
+        assert_eq!(received, [head, &ping[..]].concat());
+    }
+
+    #[tokio::test]
+    async fn test_late_upgrades_are_closed() {
+        let (upstream, _received) = recording_upstream(true).await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (guard, _input_rx) = viewer_guard("session-1");
+        tokio::spawn(guard.run(vec![listener], upstream));
+
+        // A page request, then an upgrade on the same connection
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\nGET /ws/alis HTTP/1.1\r\nHost: x\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\n")
+            .await
+            .unwrap();
+        let mut buf = Vec::new();
+        let n = tokio::time::timeout(Duration::from_secs(2), client.read_to_end(&mut buf))
+            .await
+            .expect("connection should be closed")
+            .unwrap_or(0);
+        assert_eq!(n, 0);
+    }
+}
@@ -0,0 +1,52 @@
+//! Removing terminal control sequences from text kept for later searching,
+//! such as command history
+
+use regex::Regex;
+use std::borrow::Cow;
+use std::sync::OnceLock;
+
+/// CSI and OSC sequences, other two-byte escapes, then lone C0 controls
+/// besides tab and newline
+const CONTROL_SEQUENCE: &str = concat!(
+    r"\x1b\[[0-?]*[ -/]*[@-~]",
+    r"|\x1b\][^\x07\x1b]*(?:\x07|\x1b\\)",
+    r"|\x1b[@-_]",
+    r"|[\x00-\x08\x0b-\x1f\x7f]",
+);
+
+fn control_sequence() -> &'static Regex {
+    static REGEX: OnceLock<Regex> = OnceLock::new();
+    REGEX.get_or_init(|| Regex::new(CONTROL_SEQUENCE).expect("control sequence pattern"))
+}
+
+/// `text` without control sequences; borrowed when there were none
+pub fn strip(text: &str) -> Cow<'_, str> {
+    control_sequence().replace_all(text, "")
+}
+
+/// Whether `text` holds a control sequence [`strip`] would remove
+pub fn contains(text: &str) -> bool {
+    control_sequence().is_match(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip() {
+        assert!(matches!(strip("ls -la"), Cow::Borrowed("ls -la")));
+        assert_eq!(strip("\x1b[1;31mred\x1b[0m text"), "red text");
+        assert_eq!(strip("\x1b]0;title\x07prompt"), "prompt");
+        assert_eq!(strip("vim\x1b:wq\r"), "vim:wq");
+        assert_eq!(strip("a\tb\nc\x08"), "a\tb\nc");
+    }
+
+    #[test]
+    fn test_contains() {
+        assert!(!contains("for f in *; do\n\techo $f; done"));
+        assert!(contains("printf '\x1b[31mred'"));
+        assert!(contains("vim\x1b:wq"));
+        assert!(contains("ls\x08"));
+    }
+}
@@ -0,0 +1,128 @@
+//! Pinning a session's PTY process to CPU cores (Linux only)
+//!
+//! The affinity is set in the forked PTY child before it execs the
+//! command, so the command never runs unpinned; processes it starts later
+//! inherit it. Elsewhere the request is
+//! accepted and reported back as a warning: macOS only offers affinity
+//! hints (`thread_policy_set`) for threads of the calling task, so it has no
+//! way to place another process.
+
+use crate::error::{HtMcpError, Result};
+
+/// Whether affinity can be applied on this platform
+pub fn is_supported() -> bool {
+    cfg!(target_os = "linux")
+}
+
+/// Online cores; core indices run from zero up to this
+pub fn core_count() -> usize {
+    #[cfg(target_os = "linux")]
+    if let Ok(Some(count)) = nix::unistd::sysconf(nix::unistd::SysconfVar::_NPROCESSORS_ONLN) {
+        return count as usize;
+    }
+    std::thread::available_parallelism()
+        .map(|count| count.get())
+        .unwrap_or(1)
+}
+
+/// Checks that `cores` names at least one core and only cores in
+/// `0..core_count`
+pub fn validate(cores: &[usize], core_count: usize) -> Result<()> {
+    if cores.is_empty() {
+        return Err(HtMcpError::InvalidRequest(
+            "cpuAffinity must list at least one core".to_string(),
+        ));
+    }
+    if let Some(core) = cores.iter().find(|&&core| core >= core_count) {
+        return Err(HtMcpError::InvalidRequest(format!(
+            "cpuAffinity core {} does not exist; cores are numbered 0 to {}",
+            core,
+            core_count - 1
+        )));
+    }
+    Ok(())
+}
+
+/// The create-response warning for a platform without affinity support
+pub fn unsupported_warning() -> String {
+    format!(
+        "cpuAffinity is not supported on {} and was ignored",
+        std::env::consts::OS
+    )
+}
+
+/// The set of `cores`, built before forking so the child only has to
+/// apply it
+#[cfg(target_os = "linux")]
+pub fn cpu_set(cores: &[usize]) -> Result<nix::sched::CpuSet> {
+    let mut set = nix::sched::CpuSet::new();
+    for &core in cores {
+        set.set(core).map_err(|e| {
+            HtMcpError::InvalidRequest(format!("cpuAffinity core {} is out of range: {}", core, e))
+        })?;
+    }
+    Ok(set)
+}
+
+/// Restricts the calling process to `set`. A single system call, so safe
+/// between fork and exec.
+#[cfg(target_os = "linux")]
+pub fn pin_self(set: &nix::sched::CpuSet) -> nix::Result<()> {
+    nix::sched::sched_setaffinity(nix::unistd::Pid::from_raw(0), set)
+}
+
+/// Cores process `pid` may run on
+#[cfg(target_os = "linux")]
+pub fn current(pid: u32) -> Result<Vec<usize>> {
+    use nix::sched::{sched_getaffinity, CpuSet};
+    use nix::unistd::Pid;
+
+    let set = sched_getaffinity(Pid::from_raw(pid as i32)).map_err(|e| {
+        HtMcpError::Internal(format!(
+            "Failed to read the CPU affinity of process {}: {}",
+            pid, e
+        ))
+    })?;
+    Ok((0..CpuSet::count())
+        .filter(|&core| set.is_set(core).unwrap_or(false))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        assert!(validate(&[0], 1).is_ok());
+        assert!(validate(&[0, 3], 4).is_ok());
+        for cores in [&[][..], &[4], &[0, 7]] {
+            let err = validate(cores, 4).unwrap_err();
+            assert!(matches!(err, HtMcpError::InvalidRequest(_)), "{:?}", cores);
+        }
+        assert!(validate(&[4], 4)
+            .unwrap_err()
+            .to_string()
+            .contains("numbered 0 to 3"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_pin_child_before_exec() {
+        use std::os::unix::process::CommandExt;
+
+        let core = current(std::process::id()).unwrap()[0];
+        let set = cpu_set(&[core]).unwrap();
+        let mut command = std::process::Command::new("sleep");
+        command.arg("5");
+        // SAFETY: pin_self is a single system call
+        unsafe {
+            command.pre_exec(move || pin_self(&set).map_err(std::io::Error::from));
+        }
+        let mut child = command.spawn().unwrap();
+
+        assert_eq!(current(child.id()).unwrap(), [core]);
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+}
@@ -0,0 +1,260 @@
+//! A starting-point Dockerfile for a session, for `ht_export_dockerfile`.
+//!
+//! It is a heuristic: packages are guessed from `apt install` lines and from
+//! well-known commands in the session's command history, the working
+//! directory is the one the session's process is in now, and the command is
+//! the one the session was created with. Values of the configured
+//! `session_env` and of the session's `env` often hold credentials, so those
+//! variables become build arguments instead of being written out.
+
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+
+pub const BASE_IMAGE: &str = "ubuntu:latest";
+
+/// Entries of the working directory listed in the export
+pub const MAX_LISTED_ENTRIES: usize = 50;
+
+/// Commands and the Ubuntu package providing them, for commands run without
+/// installing anything first
+const COMMAND_PACKAGES: &[(&str, &str)] = &[
+    ("cargo", "cargo"),
+    ("curl", "curl"),
+    ("g++", "g++"),
+    ("gcc", "gcc"),
+    ("git", "git"),
+    ("go", "golang-go"),
+    ("jq", "jq"),
+    ("less", "less"),
+    ("make", "make"),
+    ("node", "nodejs"),
+    ("npm", "npm"),
+    ("pip", "python3-pip"),
+    ("pip3", "python3-pip"),
+    ("psql", "postgresql-client"),
+    ("python", "python3"),
+    ("python3", "python3"),
+    ("rg", "ripgrep"),
+    ("rustc", "rustc"),
+    ("sqlite3", "sqlite3"),
+    ("ssh", "openssh-client"),
+    ("tmux", "tmux"),
+    ("unzip", "unzip"),
+    ("vim", "vim"),
+    ("wget", "wget"),
+    ("zip", "zip"),
+];
+
+/// What the Dockerfile is built from
+#[derive(Debug, Default)]
+pub struct SessionEnvironment<'a> {
+    pub session_id: &'a str,
+    pub command: &'a [String],
+    pub working_directory: Option<&'a str>,
+    /// Whether the working directory has files worth copying in
+    pub copy_working_directory: bool,
+    /// `user[:group]` of a session created with `runAs`
+    pub user: Option<String>,
+    /// Variables written out with their values
+    pub env: Vec<(&'a str, &'a str)>,
+    /// Variables taken from build arguments
+    pub build_args: Vec<&'a str>,
+    pub packages: Vec<String>,
+}
+
+/// Packages `history` installs with apt or needs for the commands it runs,
+/// sorted
+pub fn packages(history: &[String]) -> Vec<String> {
+    let mut packages = BTreeSet::new();
+    for line in history {
+        for command in line.split(['&', ';', '|']) {
+            let mut words = command
+                .split_whitespace()
+                .skip_while(|word| *word == "sudo" || is_assignment(word));
+            let Some(program) = words.next() else {
+                continue;
+            };
+            let program = program.rsplit('/').next().unwrap_or(program);
+            if program == "apt" || program == "apt-get" {
+                if words.next() == Some("install") {
+                    packages.extend(
+                        words
+                            .filter(|word| !word.starts_with('-'))
+                            .map(str::to_string),
+                    );
+                }
+            } else if let Some((_, package)) =
+                COMMAND_PACKAGES.iter().find(|(name, _)| *name == program)
+            {
+                packages.insert(package.to_string());
+            }
+        }
+    }
+    packages.into_iter().collect()
+}
+
+/// `NAME=value`, as written before a command
+fn is_assignment(word: &str) -> bool {
+    word.split_once('=').is_some_and(|(name, _)| {
+        !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    })
+}
+
+/// The Dockerfile text
+pub fn generate(session: &SessionEnvironment) -> String {
+    let mut lines = vec![
+        format!(
+            "# Generated by ht-mcp from session {}; review before building",
+            session.session_id
+        ),
+        format!("FROM {}", BASE_IMAGE),
+    ];
+
+    if !session.packages.is_empty() {
+        lines.push(String::new());
+        lines.push("RUN apt-get update \\".to_string());
+        lines.push("    && apt-get install -y --no-install-recommends \\".to_string());
+        for package in &session.packages {
+            lines.push(format!("        {} \\", package));
+        }
+        lines.push("    && rm -rf /var/lib/apt/lists/*".to_string());
+    }
+
+    if !session.build_args.is_empty() || !session.env.is_empty() {
+        lines.push(String::new());
+    }
+    for name in &session.build_args {
+        lines.push(format!("ARG {}", name));
+        lines.push(format!("ENV {}=${}", name, name));
+    }
+    for (name, value) in &session.env {
+        lines.push(format!("ENV {}={}", name, quote(value)));
+    }
+
+    if let Some(dir) = session.working_directory {
+        lines.push(String::new());
+        lines.push(format!("WORKDIR {}", dir));
+        if session.copy_working_directory {
+            lines.push(format!("COPY . {}", dir));
+        }
+    }
+    if let Some(user) = &session.user {
+        lines.push(format!("USER {}", user));
+    }
+
+    lines.push(String::new());
+    // Exec form, a JSON array, so arguments are not re-split by a shell
+    lines.push(format!(
+        "CMD {}",
+        serde_json::to_string(session.command).unwrap_or_default()
+    ));
+    lines.push(String::new());
+    lines.join("\n")
+}
+
+/// `value` as a double-quoted Dockerfile string
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// The directory the session's shell is in now (Linux only). The PTY runs
+/// `sh -c <command>`, so this follows the first child of `pid` down to the
+/// innermost process, which is the shell when it is idle.
+#[cfg(target_os = "linux")]
+pub fn process_cwd(pid: u32) -> Option<PathBuf> {
+    let mut pid = pid;
+    while let Some(child) = std::fs::read_to_string(format!("/proc/{0}/task/{0}/children", pid))
+        .ok()
+        .and_then(|children| children.split_whitespace().next()?.parse().ok())
+    {
+        pid = child;
+    }
+    std::fs::read_link(format!("/proc/{}/cwd", pid)).ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn process_cwd(_pid: u32) -> Option<PathBuf> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn test_packages_from_history() {
+        let history = history(&[
+            "sudo apt-get install -y build-essential libssl-dev",
+            "apt update && apt install --no-install-recommends jq",
+            "RUST_LOG=debug cargo test | less",
+            "/usr/bin/git status; ls -la",
+            "python3 -m venv .venv",
+            "echo make",
+        ]);
+        assert_eq!(
+            packages(&history),
+            [
+                "build-essential",
+                "cargo",
+                "git",
+                "jq",
+                "less",
+                "libssl-dev",
+                "python3"
+            ]
+        );
+        assert!(packages(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_generate() {
+        let command = vec!["bash".to_string(), "-l".to_string()];
+        let dockerfile = generate(&SessionEnvironment {
+            session_id: "s1",
+            command: &command,
+            working_directory: Some("/work/app"),
+            copy_working_directory: true,
+            user: Some("builder".to_string()),
+            env: vec![("TERM", "xterm-256color"), ("GREETING", "say \"hi\"")],
+            build_args: vec!["API_TOKEN"],
+            packages: vec!["curl".to_string(), "git".to_string()],
+        });
+        let expected = [
+            "# Generated by ht-mcp from session s1; review before building",
+            "FROM ubuntu:latest",
+            "",
+            "RUN apt-get update \\",
+            "    && apt-get install -y --no-install-recommends \\",
+            "        curl \\",
+            "        git \\",
+            "    && rm -rf /var/lib/apt/lists/*",
+            "",
+            "ARG API_TOKEN",
+            "ENV API_TOKEN=$API_TOKEN",
+            "ENV TERM=\"xterm-256color\"",
+            "ENV GREETING=\"say \\\"hi\\\"\"",
+            "",
+            "WORKDIR /work/app",
+            "COPY . /work/app",
+            "USER builder",
+            "",
+            "CMD [\"bash\",\"-l\"]",
+            "",
+        ];
+        assert_eq!(dockerfile, expected.join("\n"));
+
+        let minimal = generate(&SessionEnvironment {
+            session_id: "s2",
+            command: &command[..1],
+            ..SessionEnvironment::default()
+        });
+        assert_eq!(
+            minimal,
+            "# Generated by ht-mcp from session s2; review before building\nFROM ubuntu:latest\n\nCMD [\"bash\"]\n"
+        );
+    }
+}
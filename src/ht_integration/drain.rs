@@ -0,0 +1,74 @@
+//! Draining the server before maintenance: new sessions are refused while
+//! existing ones keep running until they finish or the grace period ends.
+
+use std::time::{Duration, Instant};
+
+use crate::error::{HtMcpError, Result};
+
+#[derive(Debug, Clone)]
+pub struct Drain {
+    deadline: Instant,
+    /// Exit as soon as the last session is gone instead of at the deadline
+    exit_when_empty: bool,
+}
+
+impl Drain {
+    pub fn new(grace: Duration, exit_when_empty: bool) -> Result<Self> {
+        let deadline = Instant::now().checked_add(grace).ok_or_else(|| {
+            HtMcpError::InvalidRequest(format!(
+                "A grace period of {}s is too long",
+                grace.as_secs()
+            ))
+        })?;
+        Ok(Self {
+            deadline,
+            exit_when_empty,
+        })
+    }
+
+    pub fn remaining(&self) -> Duration {
+        self.deadline.saturating_duration_since(Instant::now())
+    }
+
+    /// Whether the grace period is over and remaining sessions must go
+    pub fn is_over(&self) -> bool {
+        Instant::now() >= self.deadline
+    }
+
+    /// Whether the server can exit with `session_count` sessions left
+    pub fn is_done(&self, session_count: usize) -> bool {
+        self.is_over() || (self.exit_when_empty && session_count == 0)
+    }
+
+    /// Drain state as reported by `ht_list_sessions` and `ht_drain`
+    pub fn summary(&self) -> serde_json::Value {
+        serde_json::json!({
+            "remainingMs": self.remaining().as_millis() as u64,
+            "exitWhenEmpty": self.exit_when_empty,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_done_at_deadline_or_when_empty() {
+        let drain = Drain::new(Duration::from_secs(60), false).unwrap();
+        assert!(!drain.is_done(0), "waits out the grace period");
+
+        let drain = Drain::new(Duration::from_secs(60), true).unwrap();
+        assert!(!drain.is_done(2));
+        assert!(drain.is_done(0));
+
+        let drain = Drain::new(Duration::ZERO, false).unwrap();
+        assert!(drain.is_over());
+        assert!(drain.is_done(2));
+    }
+
+    #[test]
+    fn test_oversized_grace_is_rejected() {
+        assert!(Drain::new(Duration::from_secs(u64::MAX), true).is_err());
+    }
+}
@@ -0,0 +1,596 @@
+use crate::error::HtMcpError;
+use crate::ht_integration::assertions::Screen;
+use crate::ht_integration::control_sequences;
+use crate::ht_integration::events::{EventBus, SessionEvent};
+use crate::ht_integration::exit::{ExitWaiters, SessionExit};
+use crate::ht_integration::flood::{FloodChange, FloodDetector};
+use crate::ht_integration::metrics_history::SessionCounters;
+use crate::ht_integration::pty::PtyChild;
+use crate::ht_integration::screen_classifier::OutputActivity;
+use crate::ht_integration::session_manager::SessionCommand;
+use crate::ht_integration::snapshot::TextSnapshot;
+use crate::ht_integration::snapshot_history::SnapshotHistory;
+use crate::ht_integration::startup::StartupTiming;
+use crate::ht_integration::styled_snapshot::{ScreenTracker, StyledScreen};
+use bytes::Bytes;
+use ht_core::session::{Client, Event, Session};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::MissedTickBehavior;
+use tracing::{error, info, trace, warn};
+
+/// The terminal's EOF character (`VEOF`, Ctrl-D). In canonical mode it ends
+/// input when typed at the start of a line; after a partial line it only
+/// submits that line.
+const EOF: u8 = 0x04;
+
+/// How long to wait, once the PTY's output ends, for the child to be reaped
+/// and its exit status known
+const EXIT_STATUS_WAIT: Duration = Duration::from_secs(1);
+
+/// Per-session event loop connecting the PTY, the vt `Session`, MCP commands
+/// and web server clients.
+///
+/// PTY output goes down two taps. The raw tap gets every byte as the PTY
+/// wrote it, escape sequences included, for consumers that replay output
+/// (`output_stream` subscribers, and recordings or logs attached there).
+/// The vt tap feeds the `Session` behind snapshots, after flood collapsing.
+/// Neither tap's consumers change what the other delivers.
+pub struct EventLoop {
+    pub session_id: String,
+    pub cols: usize,
+    pub rows: usize,
+    pub input_tx: mpsc::Sender<Vec<u8>>,
+    pub output_rx: mpsc::Receiver<Vec<u8>>,
+    pub command_rx: mpsc::Receiver<SessionCommand>,
+    pub clients_rx: mpsc::Receiver<Client>,
+    /// The raw tap: output for `output_stream` subscribers, exactly as the
+    /// PTY wrote it; sending never waits on them
+    pub stream_tx: broadcast::Sender<Bytes>,
+    /// Alternate screen and output timing, for `ht_classify_screen`
+    pub activity: Arc<OutputActivity>,
+    /// Where periodic snapshots go, when enabled with `snapshotIntervalMs`
+    pub snapshot_history: Option<Arc<SnapshotHistory>>,
+    /// Remove control sequences left in the screen text before it goes into
+    /// `snapshot_history` (`strip_controls_in_history`); the vt tap has
+    /// already seen the raw output
+    pub strip_controls_in_history: bool,
+    /// Input and snapshot counts, for `ht_get_session_metrics_history`
+    pub counters: Arc<SessionCounters>,
+    /// Startup milestones; the first output is noted here
+    pub startup: Arc<StartupTiming>,
+    /// The PTY's process, for its exit status
+    pub child: PtyChild,
+    /// Operations to tell when the PTY's output ends
+    pub exit: Arc<ExitWaiters>,
+    /// Collapses repeated lines while the output floods; the vt tap sees its
+    /// output instead of the raw output
+    pub flood: FloodDetector,
+    pub events: EventBus,
+    /// Log every chunk to and from the PTY at trace level (`traceMode`)
+    pub trace: bool,
+}
+
+impl EventLoop {
+    pub async fn run(mut self) {
+        let mut session = Session::new(self.cols, self.rows);
+        // Follows the output for the cursor, which `Session` does not expose
+        let mut tracker = ScreenTracker::blank(self.cols, self.rows);
+        let mut serving = true;
+        let mut snapshot_timer = self.snapshot_history.as_ref().map(|history| {
+            let mut timer = tokio::time::interval(history.interval());
+            timer.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            timer
+        });
+        // Ends a flood once output stops, when no new chunk would
+        let mut flood_timer = tokio::time::interval(self.flood.window());
+        flood_timer.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        loop {
+            tokio::select! {
+                // Handle output from PTY
+                output = self.output_rx.recv() => {
+                    match output {
+                        Some(data) => {
+                            if self.trace {
+                                trace!("PTY→MCP: {} ({} bytes)", data.escape_ascii(), data.len());
+                            }
+                            self.raw_output(&data);
+                            let (data, change) = self.flood.process(&data, Instant::now());
+                            self.report_flood(change);
+                            vt_output(&mut session, &mut tracker, &data);
+                        }
+                        None => {
+                            info!("PTY process exited for session {}", self.session_id);
+                            self.report_exit(&session).await;
+                            break;
+                        }
+                    }
+                }
+
+                // Handle commands from MCP
+                command = self.command_rx.recv() => {
+                    match command {
+                        Some(SessionCommand::Input(seqs)) => {
+                            let data = ht_core::command::seqs_to_bytes(&seqs, session.cursor_key_app_mode());
+                            self.record_input(&data);
+                            if let Err(e) = self.input_tx.send(data).await {
+                                error!("Failed to send input to PTY: {}", e);
+                            }
+                        }
+                        Some(SessionCommand::CloseStdinAfterInput(seqs)) => {
+                            let mut data = ht_core::command::seqs_to_bytes(&seqs, session.cursor_key_app_mode());
+                            data.push(EOF);
+                            self.record_input(&data);
+                            if let Err(e) = self.input_tx.send(data).await {
+                                error!("Failed to send EOF to PTY: {}", e);
+                            }
+                        }
+                        Some(SessionCommand::Snapshot(response_tx)) => {
+                            // Get the current terminal text and send it back
+                            self.counters.record_snapshot();
+                            let _ = response_tx.send(Ok(TextSnapshot {
+                                text: session.get_text(),
+                                cursor: tracker.cursor(),
+                            }));
+                        }
+                        Some(SessionCommand::SnapshotStyled(format, response_tx)) => {
+                            self.counters.record_snapshot();
+                            let _ = response_tx.send(Ok(self.replay(&session).render(format)));
+                        }
+                        Some(SessionCommand::ScreenCells(response_tx)) => {
+                            self.counters.record_snapshot();
+                            let _ = response_tx.send(Ok(self.replay(&session)));
+                        }
+                        Some(SessionCommand::Screen(response_tx)) => {
+                            self.counters.record_snapshot();
+                            let _ = response_tx.send(Ok(self.screen(&session, &tracker)));
+                        }
+                        Some(SessionCommand::Resize(cols, rows)) => {
+                            session.resize(cols, rows);
+                            self.cols = cols;
+                            self.rows = rows;
+                            // Reflowed by the resize, so rebuilt once
+                            tracker = ScreenTracker::new(self.replay(&session));
+                            // The program only redraws for the new size once
+                            // the PTY has it too
+                            let clamp = |n: usize| u16::try_from(n).unwrap_or(u16::MAX);
+                            if let Err(e) = self.child.resize(clamp(cols), clamp(rows)) {
+                                warn!("Session {}: {}", self.session_id, e);
+                            }
+                        }
+                        None => {
+                            info!("Command channel closed for session {}", self.session_id);
+                            break;
+                        }
+                    }
+                }
+
+                _ = flood_timer.tick(), if self.flood.is_flooding() => {
+                    let (marker, change) = self.flood.tick(Instant::now());
+                    self.report_flood(change);
+                    if let Some(marker) = marker {
+                        vt_output(&mut session, &mut tracker, &marker);
+                    }
+                }
+
+                // Record a periodic snapshot
+                _ = async { snapshot_timer.as_mut().unwrap().tick().await }, if snapshot_timer.is_some() => {
+                    if let Some(history) = &self.snapshot_history {
+                        let text = session.get_text();
+                        history.push(if self.strip_controls_in_history {
+                            control_sequences::strip(&text).into_owned()
+                        } else {
+                            text
+                        });
+                    }
+                }
+
+                // Handle WebSocket clients (for webserver)
+                client = self.clients_rx.recv(), if serving => {
+                    match client {
+                        Some(client) => {
+                            // Viewers only get a subscription to output; what
+                            // they type is read by the connection guard, which
+                            // keeps it from ht's server
+                            info!("New WebSocket client connected to session {}", self.session_id);
+                            client.accept(session.subscribe());
+                        }
+                        None => {
+                            info!("Client channel closed for session {}", self.session_id);
+                            serving = false;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Tells the operations waiting on the session how the PTY ended, and
+    /// fails the screen requests already queued with `SessionExited`; the
+    /// last screen comes with the exit instead
+    async fn report_exit(&mut self, session: &Session) {
+        let reaped = async {
+            loop {
+                if let Some(status) = self.child.exit_code() {
+                    return status;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        let status = tokio::time::timeout(EXIT_STATUS_WAIT, reaped).await.ok();
+        let exit = SessionExit {
+            status,
+            screen: session.get_text(),
+        };
+        let message = format!("{}: {}", self.session_id, exit.describe());
+        self.exit.notify(exit);
+        let exited = || HtMcpError::SessionExited(message.clone());
+        while let Ok(command) = self.command_rx.try_recv() {
+            match command {
+                SessionCommand::Snapshot(response_tx) => {
+                    let _ = response_tx.send(Err(exited()));
+                }
+                SessionCommand::SnapshotStyled(_, response_tx) => {
+                    let _ = response_tx.send(Err(exited()));
+                }
+                SessionCommand::ScreenCells(response_tx) => {
+                    let _ = response_tx.send(Err(exited()));
+                }
+                SessionCommand::Screen(response_tx) => {
+                    let _ = response_tx.send(Err(exited()));
+                }
+                // Nothing reads input any more
+                SessionCommand::Input(_)
+                | SessionCommand::CloseStdinAfterInput(_)
+                | SessionCommand::Resize(..) => {}
+            }
+        }
+    }
+
+    /// The screen rebuilt from the escape sequences a new subscriber gets to
+    /// redraw it, for the styling `get_text` leaves out
+    fn replay(&self, session: &Session) -> StyledScreen {
+        let dump = match session.subscribe().init {
+            Event::Init(_, _, _, dump, _) => dump,
+            _ => String::new(),
+        };
+        StyledScreen::replay(&dump, self.cols, self.rows)
+    }
+
+    fn screen(&self, session: &Session, tracker: &ScreenTracker) -> Screen {
+        let cursor = tracker.cursor();
+        Screen {
+            text: session.get_text(),
+            cols: self.cols,
+            rows: self.rows,
+            cursor: Some((cursor.row, cursor.col)),
+        }
+    }
+
+    fn record_input(&self, data: &[u8]) {
+        self.counters.record_input(data.len());
+        if self.trace {
+            trace!("PTY←MCP: {} ({} bytes)", data.escape_ascii(), data.len());
+        }
+    }
+
+    /// The raw tap, fed every chunk of PTY output unchanged
+    fn raw_output(&self, data: &[u8]) {
+        self.startup.record_first_output();
+        self.activity.record(data);
+        if self.stream_tx.receiver_count() > 0 {
+            let _ = self.stream_tx.send(Bytes::copy_from_slice(data));
+        }
+    }
+
+    fn report_flood(&self, change: Option<FloodChange>) {
+        let session_id = self.session_id.clone();
+        match change {
+            Some(FloodChange::Started {
+                bytes_per_sec,
+                repeat_ratio,
+            }) => {
+                warn!(
+                    "Session {} is flooding output ({} bytes/s, {:.0}% repeated lines); collapsing repeats",
+                    session_id,
+                    bytes_per_sec,
+                    repeat_ratio * 100.0
+                );
+                self.events.publish(SessionEvent::OutputFlood {
+                    session_id,
+                    bytes_per_sec,
+                    repeat_ratio,
+                });
+            }
+            Some(FloodChange::Ended { collapsed_lines }) => {
+                info!(
+                    "Output flood of session {} ended after collapsing {} lines",
+                    session_id, collapsed_lines
+                );
+                self.events.publish(SessionEvent::OutputFloodEnded {
+                    session_id,
+                    collapsed_lines,
+                });
+            }
+            None => {}
+        }
+    }
+}
+
+/// The vt tap, fed output as snapshots should reflect it
+fn vt_output(session: &mut Session, tracker: &mut ScreenTracker, data: &[u8]) {
+    if !data.is_empty() {
+        let text = String::from_utf8_lossy(data).to_string();
+        tracker.feed(&text);
+        session.output(text);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ht_integration::flood::FloodConfig;
+    use crate::ht_integration::styled_snapshot::StyledFormat;
+    use tokio::sync::oneshot;
+
+    /// Colors, a window title, cursor movement, the alternate screen and a
+    /// multi-byte character, ending with a line to wait for
+    const STYLED_OUTPUT: [&[u8]; 5] = [
+        b"\x1b[1;31mred\x1b[0m plain\r\n",
+        b"\x1b]0;title\x07\x1b[2;5Hmoved\r\n",
+        b"\x1b[?1049h\x1b[Halt\x1b[?1049l",
+        "caf\u{e9}\r\n".as_bytes(),
+        b"done\r\n",
+    ];
+
+    struct Harness {
+        output_tx: mpsc::Sender<Vec<u8>>,
+        command_tx: mpsc::Sender<SessionCommand>,
+        stream_tx: broadcast::Sender<Bytes>,
+        exit: Arc<ExitWaiters>,
+        _input_rx: mpsc::Receiver<Vec<u8>>,
+        _clients_tx: mpsc::Sender<Client>,
+    }
+
+    impl Harness {
+        fn start(flood: FloodConfig) -> Self {
+            let (event_loop, harness) = Self::new(flood);
+            tokio::spawn(event_loop.run());
+            harness
+        }
+
+        /// A harness and the event loop it drives, not yet running
+        fn new(flood: FloodConfig) -> (EventLoop, Self) {
+            let (input_tx, _input_rx) = mpsc::channel(16);
+            let (output_tx, output_rx) = mpsc::channel(16);
+            let (command_tx, command_rx) = mpsc::channel(16);
+            let (_clients_tx, clients_rx) = mpsc::channel(1);
+            let (stream_tx, _) = broadcast::channel(1024);
+            let exit = Arc::new(ExitWaiters::default());
+            let event_loop = EventLoop {
+                session_id: "test".to_string(),
+                cols: 80,
+                rows: 24,
+                input_tx,
+                output_rx,
+                command_rx,
+                clients_rx,
+                stream_tx: stream_tx.clone(),
+                activity: Arc::default(),
+                snapshot_history: None,
+                strip_controls_in_history: false,
+                counters: Arc::default(),
+                startup: Arc::new(StartupTiming::new(
+                    "test",
+                    Instant::now(),
+                    Duration::ZERO,
+                    Arc::default(),
+                    EventBus::new(),
+                )),
+                child: PtyChild::new(None),
+                exit: exit.clone(),
+                flood: FloodDetector::new(flood, Arc::default(), Instant::now()),
+                events: EventBus::new(),
+                trace: false,
+            };
+            let harness = Self {
+                output_tx,
+                command_tx,
+                stream_tx,
+                exit,
+                _input_rx,
+                _clients_tx,
+            };
+            (event_loop, harness)
+        }
+
+        async fn feed(&self, chunks: &[&[u8]]) {
+            for chunk in chunks {
+                self.output_tx.send(chunk.to_vec()).await.unwrap();
+            }
+        }
+
+        async fn snapshot(&self) -> String {
+            let (response_tx, response_rx) = oneshot::channel();
+            self.command_tx
+                .send(SessionCommand::Snapshot(response_tx))
+                .await
+                .unwrap();
+            response_rx.await.unwrap().unwrap().text
+        }
+
+        /// A snapshot once `text` shows, since commands and output are
+        /// served in no particular order
+        async fn snapshot_showing(&self, text: &str) -> String {
+            for _ in 0..200 {
+                let snapshot = self.snapshot().await;
+                if snapshot.contains(text) {
+                    return snapshot;
+                }
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+            panic!("'{}' never showed", text);
+        }
+    }
+
+    async fn received(rx: &mut broadcast::Receiver<Bytes>, len: usize) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        while bytes.len() < len {
+            let chunk = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            bytes.extend_from_slice(&chunk);
+        }
+        bytes
+    }
+
+    #[tokio::test]
+    async fn test_raw_subscribers_do_not_change_snapshots() {
+        let plain = Harness::start(FloodConfig::default());
+        plain.feed(&STYLED_OUTPUT).await;
+        let without = plain.snapshot_showing("done").await;
+
+        let tapped = Harness::start(FloodConfig::default());
+        let _rx = tapped.stream_tx.subscribe();
+        tapped.feed(&STYLED_OUTPUT).await;
+        let with = tapped.snapshot_showing("done").await;
+
+        assert_eq!(without.as_bytes(), with.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_tracked_cursor_matches_the_replayed_screen() {
+        let harness = Harness::start(FloodConfig::default());
+        // A cursor move cut in two across chunks
+        harness.feed(&STYLED_OUTPUT).await;
+        harness.feed(&[b"prompt\x1b[5", b";9Hmark"]).await;
+        harness.snapshot_showing("mark").await;
+
+        let (snapshot_tx, snapshot_rx) = oneshot::channel();
+        let (cells_tx, cells_rx) = oneshot::channel();
+        harness
+            .command_tx
+            .send(SessionCommand::Snapshot(snapshot_tx))
+            .await
+            .unwrap();
+        harness
+            .command_tx
+            .send(SessionCommand::ScreenCells(cells_tx))
+            .await
+            .unwrap();
+        let tracked = snapshot_rx.await.unwrap().unwrap().cursor;
+        assert_eq!(tracked, cells_rx.await.unwrap().unwrap().cursor());
+        assert_eq!((tracked.row, tracked.col), (5, 13));
+    }
+
+    #[tokio::test]
+    async fn test_raw_tap_preserves_escape_sequences() {
+        let harness = Harness::start(FloodConfig::default());
+        let mut rx = harness.stream_tx.subscribe();
+        harness.feed(&STYLED_OUTPUT).await;
+
+        let expected = STYLED_OUTPUT.concat();
+        assert_eq!(received(&mut rx, expected.len()).await, expected);
+    }
+
+    #[tokio::test]
+    async fn test_flood_collapsing_only_reaches_the_vt_tap() {
+        let harness = Harness::start(FloodConfig {
+            min_bytes_per_sec: 1,
+            min_repeat_ratio: 0.5,
+            window_ms: 20,
+            ..FloodConfig::default()
+        });
+        let mut rx = harness.stream_tx.subscribe();
+        let repeats = b"retrying\r\n".repeat(50);
+
+        // The first window is only measured; the flood starts with the next
+        harness.feed(&[&repeats]).await;
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        harness.feed(&[&repeats, b"gave up\r\n"]).await;
+
+        let expected = [repeats.as_slice(), &repeats, b"gave up\r\n"].concat();
+        assert_eq!(received(&mut rx, expected.len()).await, expected);
+        let snapshot = harness.snapshot_showing("gave up").await;
+        assert!(snapshot.contains("[previous line repeated"), "{}", snapshot);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_history_is_stripped_of_control_sequences() {
+        let (mut event_loop, harness) = Harness::new(FloodConfig::default());
+        let history = Arc::new(SnapshotHistory::new(Duration::from_millis(10)));
+        event_loop.snapshot_history = Some(history.clone());
+        event_loop.strip_controls_in_history = true;
+        tokio::spawn(event_loop.run());
+        harness.feed(&STYLED_OUTPUT).await;
+        harness.feed(&[b"\x1b[32mgreen\x1b[0m\x07 bell\r\n"]).await;
+        harness.snapshot_showing("bell").await;
+
+        let mut entries = Vec::new();
+        for _ in 0..200 {
+            entries = history.recent(usize::MAX, None);
+            if entries.iter().any(|(_, text)| text.contains("bell")) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        let (_, text) = entries
+            .iter()
+            .find(|(_, text)| text.contains("bell"))
+            .expect("a snapshot showing the output");
+        assert!(text.contains("red plain"), "{:?}", text);
+        assert!(text.contains("green bell"), "{:?}", text);
+        assert!(!control_sequences::contains(text), "{:?}", text);
+    }
+
+    #[tokio::test]
+    async fn test_output_end_is_reported_to_waiters() {
+        let harness = Harness::start(FloodConfig::default());
+        harness.feed(&[b"last words\r\n"]).await;
+        harness.snapshot_showing("last words").await;
+        let waiter = harness.exit.register();
+
+        drop(harness.output_tx);
+        let exit = tokio::time::timeout(Duration::from_secs(5), waiter)
+            .await
+            .unwrap()
+            .unwrap();
+        // Nothing reaps a child in the harness, so the status is unknown
+        assert_eq!(exit.status, None);
+        assert!(exit.screen.contains("last words"));
+        assert_eq!(harness.exit.exit(), Some(exit));
+    }
+
+    #[tokio::test]
+    async fn test_requests_queued_at_exit_fail_with_session_exited() {
+        let (mut event_loop, harness) = Harness::new(FloodConfig::default());
+        let (snapshot_tx, snapshot_rx) = oneshot::channel();
+        let (styled_tx, styled_rx) = oneshot::channel();
+        let (cells_tx, cells_rx) = oneshot::channel();
+        let (screen_tx, screen_rx) = oneshot::channel();
+        for command in [
+            SessionCommand::Snapshot(snapshot_tx),
+            SessionCommand::SnapshotStyled(StyledFormat::Ansi, styled_tx),
+            SessionCommand::ScreenCells(cells_tx),
+            SessionCommand::Screen(screen_tx),
+        ] {
+            harness.command_tx.send(command).await.unwrap();
+        }
+
+        event_loop.report_exit(&Session::new(80, 24)).await;
+        let errors = [
+            snapshot_rx.await.unwrap().unwrap_err(),
+            styled_rx.await.unwrap().unwrap_err(),
+            cells_rx.await.unwrap().unwrap_err(),
+            screen_rx.await.unwrap().unwrap_err(),
+        ];
+        for err in errors {
+            assert!(matches!(err, HtMcpError::SessionExited(_)), "{}", err);
+            assert!(err.to_string().contains("status unknown"), "{}", err);
+        }
+        assert!(harness.exit.exit().is_some());
+    }
+}
@@ -0,0 +1,228 @@
+//! Session lifecycle events broadcast to interested listeners
+
+use crate::ht_integration::startup::StartupPhase;
+use crate::ht_integration::storage::StorageCategory;
+use crate::mcp::client_identity::ClientIdentity;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// Number of events buffered for slow subscribers before they start lagging
+const EVENT_CHANNEL_CAPACITY: usize = 256;
+
+/// Serialized as `{"type": "session.created", "data": {...}}`
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", content = "data")]
+pub enum SessionEvent {
+    /// A session finished all creation stages
+    #[serde(rename = "session.created", rename_all = "camelCase")]
+    SessionCreated {
+        session_id: String,
+        /// The MCP client that created the session, when known
+        #[serde(skip_serializing_if = "Option::is_none")]
+        created_by: Option<ClientIdentity>,
+    },
+    /// The web server refused a connection because a limit was reached
+    #[serde(rename = "session.connection_rejected", rename_all = "camelCase")]
+    ConnectionRejected {
+        session_id: String,
+        peer: String,
+        reason: String,
+    },
+    /// A session's lease runs out within the configured warning window
+    #[serde(rename = "session.lease_expiring", rename_all = "camelCase")]
+    LeaseExpiring {
+        session_id: String,
+        expires_in_ms: u64,
+    },
+    /// A holder took a session's interaction lock, or extended its own
+    #[serde(rename = "session.lock_acquired", rename_all = "camelCase")]
+    LockAcquired {
+        session_id: String,
+        holder: String,
+        expires_at: String,
+    },
+    /// A session's interaction lock was freed
+    #[serde(rename = "session.lock_released", rename_all = "camelCase")]
+    LockReleased {
+        session_id: String,
+        holder: String,
+        reason: LockReleaseReason,
+    },
+    /// A session's interaction lock was taken from its holder with
+    /// `override`
+    #[serde(rename = "session.lock_stolen", rename_all = "camelCase")]
+    LockStolen {
+        session_id: String,
+        holder: String,
+        previous_holder: String,
+    },
+    /// Input for a session went through its input policy: an MCP input
+    /// tool call, or a message typed in a web viewer. This is the audit
+    /// trail of who typed into the session; what was typed is left out.
+    #[serde(rename = "session.input", rename_all = "camelCase")]
+    Input {
+        session_id: String,
+        source: InputSource,
+        /// The tool called, for MCP input
+        #[serde(skip_serializing_if = "Option::is_none")]
+        tool: Option<String>,
+        /// The viewer's address, for web input
+        #[serde(skip_serializing_if = "Option::is_none")]
+        peer: Option<String>,
+        accepted: bool,
+        /// Why the input was refused
+        #[serde(skip_serializing_if = "Option::is_none")]
+        refused: Option<String>,
+    },
+    /// A session was closed and removed
+    #[serde(rename = "session.closed", rename_all = "camelCase")]
+    SessionClosed {
+        session_id: String,
+        reason: TerminationReason,
+        /// The MCP client that created the session, when known
+        #[serde(skip_serializing_if = "Option::is_none")]
+        created_by: Option<ClientIdentity>,
+    },
+    /// A session started printing the same line over and over at a high
+    /// rate; repeats are collapsed until the rate drops
+    #[serde(rename = "session.output_flood", rename_all = "camelCase")]
+    OutputFlood {
+        session_id: String,
+        bytes_per_sec: u64,
+        repeat_ratio: f64,
+    },
+    /// An output flood ended
+    #[serde(rename = "session.output_flood_ended", rename_all = "camelCase")]
+    OutputFloodEnded {
+        session_id: String,
+        collapsed_lines: u64,
+    },
+    /// A session took longer than `slow_startup_warning_ms` to print its
+    /// first output or show its prompt
+    #[serde(rename = "session.slow_startup", rename_all = "camelCase")]
+    SlowStartup {
+        session_id: String,
+        phase: StartupPhase,
+        elapsed_ms: u64,
+        threshold_ms: u64,
+        hint: String,
+    },
+    /// A session's web server, or the connection guard in front of it,
+    /// stopped while the session was alive
+    #[serde(rename = "session.web_server_stopped", rename_all = "camelCase")]
+    WebServerStopped {
+        session_id: String,
+        /// `web server` or `connection guard`
+        component: String,
+    },
+    /// A stopped web server is serving again
+    #[serde(rename = "session.web_server_restarted", rename_all = "camelCase")]
+    WebServerRestarted {
+        session_id: String,
+        url: String,
+        restarts: u32,
+        /// Whether the old port was taken, so `url` is new
+        port_changed: bool,
+    },
+    /// A web server kept stopping and was given up on
+    #[serde(rename = "session.web_server_failed", rename_all = "camelCase")]
+    WebServerFailed { session_id: String, restarts: u32 },
+    /// The server started draining; sessions still open when the grace
+    /// period ends are closed with `ServerShutdown`
+    #[serde(rename = "server.draining", rename_all = "camelCase")]
+    ServerDraining {
+        grace_ms: u64,
+        session_count: usize,
+        exit_when_empty: bool,
+    },
+    /// Disk usage of a storage category reached 80% of its cap
+    #[serde(rename = "storage.warning", rename_all = "camelCase")]
+    StorageWarning {
+        category: StorageCategory,
+        used_bytes: u64,
+        cap_bytes: u64,
+    },
+}
+
+impl SessionEvent {
+    /// The session the event is about, if it concerns a single session
+    pub fn session_id(&self) -> Option<&str> {
+        match self {
+            SessionEvent::SessionCreated { session_id, .. }
+            | SessionEvent::ConnectionRejected { session_id, .. }
+            | SessionEvent::LeaseExpiring { session_id, .. }
+            | SessionEvent::LockAcquired { session_id, .. }
+            | SessionEvent::LockReleased { session_id, .. }
+            | SessionEvent::LockStolen { session_id, .. }
+            | SessionEvent::Input { session_id, .. }
+            | SessionEvent::OutputFlood { session_id, .. }
+            | SessionEvent::OutputFloodEnded { session_id, .. }
+            | SessionEvent::SlowStartup { session_id, .. }
+            | SessionEvent::WebServerStopped { session_id, .. }
+            | SessionEvent::WebServerRestarted { session_id, .. }
+            | SessionEvent::WebServerFailed { session_id, .. }
+            | SessionEvent::SessionClosed { session_id, .. } => Some(session_id),
+            SessionEvent::ServerDraining { .. } | SessionEvent::StorageWarning { .. } => None,
+        }
+    }
+}
+
+/// Why a session was closed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum TerminationReason {
+    /// Closed with `ht_close_session`
+    Closed,
+    /// Its lease ran out without being renewed
+    LeaseExpired,
+    /// Still open when the server finished draining
+    ServerShutdown,
+    /// Followed a file with `ht_tail_file` that was then removed
+    TailFileRemoved,
+}
+
+/// Where input for a session came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InputSource {
+    /// An MCP tool call
+    Mcp,
+    /// A message from a web viewer's WebSocket
+    Web,
+}
+
+/// Why a session's interaction lock was freed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum LockReleaseReason {
+    /// Released with `ht_release_lock`
+    Released,
+    /// Its TTL ran out
+    Expired,
+}
+
+/// Fan-out of `SessionEvent`s. Publishing never blocks and is a no-op when
+/// nobody is subscribed.
+#[derive(Debug, Clone)]
+pub struct EventBus {
+    tx: broadcast::Sender<SessionEvent>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        Self { tx }
+    }
+
+    pub fn publish(&self, event: SessionEvent) {
+        let _ = self.tx.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<SessionEvent> {
+        self.tx.subscribe()
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,140 @@
+//! A session's PTY ending, fanned out to the operations waiting on it.
+//!
+//! `execute_command` and `SessionHandle::wait_for` poll the screen until
+//! something appears. When the shell dies under them (`exit`, `exec false`,
+//! a crash) they would poll a closed session until their timeout. Instead
+//! they register here, and the event loop completes every registration with
+//! the exit status and the last screen once the PTY's output ends.
+
+use crate::error::HtMcpError;
+use serde::Serialize;
+use std::sync::Mutex;
+use tokio::sync::oneshot;
+
+/// How a session's PTY ended
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionExit {
+    /// Exit code of the process, 128 + signal number if it was killed by a
+    /// signal; `None` when it could not be read
+    pub status: Option<i32>,
+    /// The screen as the process left it
+    pub screen: String,
+}
+
+impl SessionExit {
+    /// "session exited (status X)"
+    pub fn describe(&self) -> String {
+        match self.status {
+            Some(status) => format!("session exited (status {})", status),
+            None => "session exited (status unknown)".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ExitState {
+    exit: Option<SessionExit>,
+    waiters: Vec<oneshot::Sender<SessionExit>>,
+}
+
+/// Operations in flight on one session that want to hear about its exit
+#[derive(Debug, Default)]
+pub struct ExitWaiters {
+    state: Mutex<ExitState>,
+}
+
+impl ExitWaiters {
+    /// A receiver completed when the session exits; at once if it already
+    /// has. Dropping it unregisters.
+    pub fn register(&self) -> oneshot::Receiver<SessionExit> {
+        let (tx, rx) = oneshot::channel();
+        let mut state = self.state.lock().unwrap();
+        match &state.exit {
+            Some(exit) => {
+                let _ = tx.send(exit.clone());
+            }
+            None => {
+                state.waiters.retain(|waiter| !waiter.is_closed());
+                state.waiters.push(tx);
+            }
+        }
+        rx
+    }
+
+    /// Completes every registration with `exit`; only the first call counts
+    pub fn notify(&self, exit: SessionExit) {
+        let mut state = self.state.lock().unwrap();
+        if state.exit.is_some() {
+            return;
+        }
+        for waiter in state.waiters.drain(..) {
+            let _ = waiter.send(exit.clone());
+        }
+        state.exit = Some(exit);
+    }
+
+    /// How the session ended, once it has
+    pub fn exit(&self) -> Option<SessionExit> {
+        self.state.lock().unwrap().exit.clone()
+    }
+
+    /// `err`, from a request the session's event loop did not answer, or
+    /// `SessionExited` in its place once the session has exited
+    pub fn explain(&self, session_id: &str, err: HtMcpError) -> HtMcpError {
+        match self.exit() {
+            Some(exit) => HtMcpError::SessionExited(format!("{}: {}", session_id, exit.describe())),
+            None => err,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exited(status: Option<i32>) -> SessionExit {
+        SessionExit {
+            status,
+            screen: "$ exec false".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_exit_reaches_every_waiter() {
+        let waiters = ExitWaiters::default();
+        let first = waiters.register();
+        let second = waiters.register();
+        drop(waiters.register());
+        assert_eq!(waiters.exit(), None);
+
+        waiters.notify(exited(Some(1)));
+        assert_eq!(first.await.unwrap(), exited(Some(1)));
+        assert_eq!(second.await.unwrap(), exited(Some(1)));
+
+        // Late registrations hear at once, and the first exit sticks
+        waiters.notify(exited(None));
+        assert_eq!(waiters.register().await.unwrap(), exited(Some(1)));
+        assert_eq!(waiters.exit(), Some(exited(Some(1))));
+    }
+
+    #[test]
+    fn test_describe() {
+        assert_eq!(exited(Some(1)).describe(), "session exited (status 1)");
+        assert_eq!(exited(None).describe(), "session exited (status unknown)");
+
+        let waiters = ExitWaiters::default();
+        let closed = || HtMcpError::Internal("closed".to_string());
+        assert!(matches!(
+            waiters.explain("s1", closed()),
+            HtMcpError::Internal(_)
+        ));
+        waiters.notify(exited(Some(1)));
+        let err = waiters.explain("s1", closed());
+        assert!(matches!(err, HtMcpError::SessionExited(_)));
+        assert_eq!(
+            err.to_string(),
+            "Session exited: s1: session exited (status 1)"
+        );
+    }
+}
@@ -0,0 +1,414 @@
+//! Muting a program stuck printing the same line over and over.
+//!
+//! Output is measured in windows of `window_ms`. A window with at least
+//! `min_bytes_per_sec` of output in which at least `min_repeat_ratio` of the
+//! lines repeat the line before them starts a flood. While it lasts, a run of
+//! identical lines reaches the terminal (and so the screen, scrollback and
+//! output stream) as its first line followed by a
+//! `[previous line repeated N more times]` marker. The flood ends after a
+//! window below either threshold.
+
+use crate::error::{HtMcpError, Result};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FloodConfig {
+    pub enabled: bool,
+    /// Output rate a window must reach to count as a flood
+    pub min_bytes_per_sec: u64,
+    /// Share of a window's lines, 0 to 1, that must repeat the line before
+    pub min_repeat_ratio: f64,
+    pub window_ms: u64,
+}
+
+impl Default for FloodConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            min_bytes_per_sec: 32 * 1024,
+            min_repeat_ratio: 0.9,
+            window_ms: 1000,
+        }
+    }
+}
+
+impl FloodConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !(0.0..=1.0).contains(&self.min_repeat_ratio) {
+            return Err(HtMcpError::Config(format!(
+                "output_flood.min_repeat_ratio must be between 0 and 1, got {}",
+                self.min_repeat_ratio
+            )));
+        }
+        if self.window_ms == 0 {
+            return Err(HtMcpError::Config(
+                "output_flood.window_ms must be at least 1".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn window(&self) -> Duration {
+        Duration::from_millis(self.window_ms)
+    }
+}
+
+/// Flood state of one session, shared with `ht_list_sessions`
+#[derive(Debug, Default)]
+pub struct FloodStats {
+    active: AtomicBool,
+    floods: AtomicU64,
+    collapsed_lines: AtomicU64,
+}
+
+impl FloodStats {
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    pub fn summary(&self) -> serde_json::Value {
+        serde_json::json!({
+            "active": self.is_active(),
+            "floods": self.floods.load(Ordering::Relaxed),
+            "collapsedLines": self.collapsed_lines.load(Ordering::Relaxed),
+        })
+    }
+}
+
+/// A flood starting or ending
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FloodChange {
+    Started {
+        bytes_per_sec: u64,
+        repeat_ratio: f64,
+    },
+    Ended {
+        collapsed_lines: u64,
+    },
+}
+
+#[derive(Debug)]
+pub struct FloodDetector {
+    config: FloodConfig,
+    stats: Arc<FloodStats>,
+    window_start: Instant,
+    window_bytes: u64,
+    window_lines: u64,
+    window_repeats: u64,
+    /// Last complete line, without its line ending
+    last_line: Vec<u8>,
+    /// Start of a line not yet ended by a newline
+    partial: Vec<u8>,
+    flooding: bool,
+    /// Repeats held back since the last line that was let through
+    suppressed: u64,
+    /// Lines collapsed during the current flood
+    flood_collapsed: u64,
+}
+
+impl FloodDetector {
+    pub fn new(config: FloodConfig, stats: Arc<FloodStats>, now: Instant) -> Self {
+        Self {
+            config,
+            stats,
+            window_start: now,
+            window_bytes: 0,
+            window_lines: 0,
+            window_repeats: 0,
+            last_line: Vec::new(),
+            partial: Vec::new(),
+            flooding: false,
+            suppressed: 0,
+            flood_collapsed: 0,
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        self.config.window()
+    }
+
+    pub fn is_flooding(&self) -> bool {
+        self.flooding
+    }
+
+    /// Takes a chunk of output and returns what should reach the terminal,
+    /// with any change of flood state
+    pub fn process<'a>(
+        &mut self,
+        data: &'a [u8],
+        now: Instant,
+    ) -> (Cow<'a, [u8]>, Option<FloodChange>) {
+        if !self.config.enabled {
+            return (Cow::Borrowed(data), None);
+        }
+        let (marker, change) = self.tick(now);
+        self.window_bytes += data.len() as u64;
+
+        let mut out = marker.unwrap_or_default();
+        let passthrough = !self.flooding && out.is_empty();
+        for segment in data.split_inclusive(|&byte| byte == b'\n') {
+            let complete = segment.ends_with(b"\n");
+            // A line whose start already went out is let through whole
+            let started = !self.partial.is_empty();
+            self.partial.extend_from_slice(segment);
+            if !complete {
+                if !passthrough {
+                    out.extend_from_slice(segment);
+                }
+                continue;
+            }
+
+            let line = std::mem::take(&mut self.partial);
+            let key = trim_line_ending(&line);
+            let repeat = key == self.last_line.as_slice();
+            self.window_lines += 1;
+            if repeat {
+                self.window_repeats += 1;
+            } else {
+                self.last_line = key.to_vec();
+            }
+            if passthrough {
+                continue;
+            }
+            if repeat && self.flooding && !started {
+                self.suppressed += 1;
+                self.flood_collapsed += 1;
+                self.stats.collapsed_lines.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            out.extend(self.take_marker().unwrap_or_default());
+            out.extend_from_slice(if started {
+                // The line's start went out with an earlier chunk
+                &line[line.len() - segment.len()..]
+            } else {
+                &line
+            });
+        }
+
+        if passthrough {
+            (Cow::Borrowed(data), change)
+        } else {
+            (Cow::Owned(out), change)
+        }
+    }
+
+    /// Closes the measuring window once it has run its length. Called with
+    /// each chunk and periodically while flooding, so a flood ends even when
+    /// the output stops; the marker for held back repeats is returned then.
+    pub fn tick(&mut self, now: Instant) -> (Option<Vec<u8>>, Option<FloodChange>) {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        if !self.config.enabled || elapsed < self.config.window() {
+            return (None, None);
+        }
+
+        let bytes_per_sec = (self.window_bytes as f64 / elapsed.as_secs_f64()) as u64;
+        let repeat_ratio = if self.window_lines == 0 {
+            0.0
+        } else {
+            self.window_repeats as f64 / self.window_lines as f64
+        };
+        self.window_start = now;
+        self.window_bytes = 0;
+        self.window_lines = 0;
+        self.window_repeats = 0;
+
+        let over = bytes_per_sec >= self.config.min_bytes_per_sec
+            && repeat_ratio >= self.config.min_repeat_ratio;
+        match (self.flooding, over) {
+            (false, true) => {
+                self.flooding = true;
+                self.flood_collapsed = 0;
+                self.stats.active.store(true, Ordering::Relaxed);
+                self.stats.floods.fetch_add(1, Ordering::Relaxed);
+                let change = FloodChange::Started {
+                    bytes_per_sec,
+                    repeat_ratio,
+                };
+                (None, Some(change))
+            }
+            (true, false) => {
+                self.flooding = false;
+                self.stats.active.store(false, Ordering::Relaxed);
+                let change = FloodChange::Ended {
+                    collapsed_lines: self.flood_collapsed,
+                };
+                (self.take_marker(), Some(change))
+            }
+            _ => (None, None),
+        }
+    }
+
+    fn take_marker(&mut self) -> Option<Vec<u8>> {
+        match std::mem::take(&mut self.suppressed) {
+            0 => None,
+            count => {
+                Some(format!("[previous line repeated {} more times]\r\n", count).into_bytes())
+            }
+        }
+    }
+}
+
+fn trim_line_ending(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detector(now: Instant) -> FloodDetector {
+        FloodDetector::new(
+            FloodConfig {
+                min_bytes_per_sec: 1000,
+                ..FloodConfig::default()
+            },
+            Arc::new(FloodStats::default()),
+            now,
+        )
+    }
+
+    fn flood_chunk(repeats: usize, distinct: &str) -> Vec<u8> {
+        let mut chunk = "ERROR: connection refused\r\n".repeat(repeats);
+        chunk.push_str(distinct);
+        chunk.into_bytes()
+    }
+
+    #[test]
+    fn test_flood_collapses_repeats_and_keeps_distinct_lines() {
+        let start = Instant::now();
+        let mut detector = detector(start);
+
+        // The first window is only measured
+        let first = flood_chunk(100, "");
+        let (out, change) = detector.process(&first, start);
+        assert_eq!(out.as_ref(), first.as_slice());
+        assert_eq!(change, None);
+
+        let second = flood_chunk(50, "retrying in 1s\r\n");
+        let (out, change) = detector.process(&second, start + Duration::from_millis(1100));
+        assert!(matches!(change, Some(FloodChange::Started { .. })));
+        assert!(detector.stats.is_active());
+        let out = String::from_utf8(out.into_owned()).unwrap();
+        assert_eq!(
+            out,
+            "[previous line repeated 50 more times]\r\nretrying in 1s\r\n"
+        );
+
+        // Interleaved distinct lines survive; each run of repeats becomes
+        // its first line and a marker
+        let third = [
+            flood_chunk(1, ""),
+            flood_chunk(30, "attempt 2\r\n"),
+            flood_chunk(1, "attempt 3\r\n"),
+        ]
+        .concat();
+        let (out, _) = detector.process(&third, start + Duration::from_millis(1200));
+        let out = String::from_utf8(out.into_owned()).unwrap();
+        assert_eq!(
+            out,
+            "ERROR: connection refused\r\n\
+             [previous line repeated 30 more times]\r\n\
+             attempt 2\r\n\
+             ERROR: connection refused\r\n\
+             attempt 3\r\n"
+        );
+        assert_eq!(detector.stats.summary()["collapsedLines"], 80);
+    }
+
+    #[test]
+    fn test_flood_ends_when_output_stops() {
+        let start = Instant::now();
+        let mut detector = detector(start);
+        detector.process(&flood_chunk(100, ""), start);
+        detector.process(&flood_chunk(10, ""), start + Duration::from_millis(1100));
+        assert!(detector.is_flooding());
+
+        // The held back repeats are reported when the flood ends
+        let (marker, change) = detector.tick(start + Duration::from_millis(3000));
+        assert_eq!(
+            marker.as_deref(),
+            Some(b"[previous line repeated 10 more times]\r\n".as_slice())
+        );
+        assert_eq!(
+            change,
+            Some(FloodChange::Ended {
+                collapsed_lines: 10
+            })
+        );
+        assert!(!detector.stats.is_active());
+        assert_eq!(detector.stats.summary()["floods"], 1);
+
+        // Output is untouched again
+        let chunk = flood_chunk(3, "");
+        let (out, _) = detector.process(&chunk, start + Duration::from_millis(3100));
+        assert_eq!(out.as_ref(), chunk.as_slice());
+    }
+
+    #[test]
+    fn test_fast_varied_output_is_not_a_flood() {
+        let start = Instant::now();
+        let mut detector = detector(start);
+        let varied: String = (0..200).map(|i| format!("line {}\r\n", i)).collect();
+        detector.process(varied.as_bytes(), start);
+        let (out, change) =
+            detector.process(varied.as_bytes(), start + Duration::from_millis(1100));
+        assert_eq!(change, None);
+        assert_eq!(out.as_ref(), varied.as_bytes());
+    }
+
+    #[test]
+    fn test_lines_split_across_chunks() {
+        let start = Instant::now();
+        let mut detector = detector(start);
+        detector.process(&flood_chunk(100, ""), start);
+        detector.process(&flood_chunk(5, ""), start + Duration::from_millis(1100));
+
+        // A repeat whose start arrived in an earlier chunk is let through
+        // rather than cut in half
+        let (out, _) = detector.process(b"ERROR: conn", start + Duration::from_millis(1150));
+        assert_eq!(out.as_ref(), b"ERROR: conn");
+        let (out, _) = detector.process(
+            b"ection refused\r\nprompt$ ",
+            start + Duration::from_millis(1160),
+        );
+        assert_eq!(
+            out.as_ref(),
+            b"[previous line repeated 5 more times]\r\nection refused\r\nprompt$ ".as_slice()
+        );
+    }
+
+    #[test]
+    fn test_disabled_and_invalid_config() {
+        let start = Instant::now();
+        let mut detector = FloodDetector::new(
+            FloodConfig {
+                enabled: false,
+                min_bytes_per_sec: 1,
+                ..FloodConfig::default()
+            },
+            Arc::new(FloodStats::default()),
+            start,
+        );
+        detector.process(&flood_chunk(100, ""), start);
+        let chunk = flood_chunk(100, "");
+        let (out, change) = detector.process(&chunk, start + Duration::from_secs(2));
+        assert_eq!((out.as_ref(), change), (chunk.as_slice(), None));
+
+        let ratio = FloodConfig {
+            min_repeat_ratio: 1.5,
+            ..FloodConfig::default()
+        };
+        assert!(matches!(ratio.validate(), Err(HtMcpError::Config(_))));
+        let window = FloodConfig {
+            window_ms: 0,
+            ..FloodConfig::default()
+        };
+        assert!(matches!(window.validate(), Err(HtMcpError::Config(_))));
+    }
+}
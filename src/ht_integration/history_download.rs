@@ -0,0 +1,454 @@
+//! Bulk download of a session's retained history, for
+//! `ht_download_history`.
+//!
+//! The history (periodic snapshots and the command history) is rendered
+//! once, when a download starts, and kept under a token. Clients then pull
+//! it in chunks of the size they choose, following `next` cursors; asking
+//! for the same cursor again resumes after a dropped connection. Each chunk
+//! carries a CRC-32 of its bytes and the whole file's CRC-32 is known up
+//! front. Alternatively the whole file is served once over the management
+//! HTTP server. Retention trimming the session's history after the start
+//! does not affect a download in progress.
+
+use crate::error::{HtMcpError, Result};
+use crate::mcp::types::HistoryFormat;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// How long a download stays available after it was last read
+pub const DOWNLOAD_TTL: Duration = Duration::from_secs(600);
+
+pub const DEFAULT_CHUNK_BYTES: usize = 64 * 1024;
+/// Smallest accepted `chunkBytes`; room for any UTF-8 character
+pub const MIN_CHUNK_BYTES: usize = 16;
+pub const MAX_CHUNK_BYTES: usize = 1024 * 1024;
+
+/// Downloads kept at once across all sessions
+const MAX_DOWNLOADS: usize = 32;
+
+/// CRC-32 (IEEE) lookup table
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC-32 as computed by zlib, `cksum -o 3` and Python's `zlib.crc32`
+pub fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, &byte| {
+        CRC32_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Renders snapshots (oldest first) and commands as one file
+pub fn render(
+    format: HistoryFormat,
+    snapshots: &[(DateTime<Utc>, String)],
+    commands: &[String],
+) -> String {
+    let mut out = String::new();
+    match format {
+        HistoryFormat::Plain => {
+            for (taken_at, text) in snapshots {
+                out.push_str(&format!(
+                    "=== snapshot {} ===\n",
+                    taken_at.to_rfc3339_opts(SecondsFormat::Millis, true)
+                ));
+                out.push_str(text.trim_end());
+                out.push('\n');
+            }
+            if !commands.is_empty() {
+                out.push_str("=== commands ===\n");
+                for command in commands {
+                    out.push_str(&format!("$ {}\n", command));
+                }
+            }
+        }
+        HistoryFormat::Jsonl => {
+            for (taken_at, text) in snapshots {
+                let line = serde_json::json!({
+                    "type": "snapshot",
+                    "takenAt": taken_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+                    "text": text
+                });
+                out.push_str(&line.to_string());
+                out.push('\n');
+            }
+            for command in commands {
+                let line = serde_json::json!({"type": "command", "command": command});
+                out.push_str(&line.to_string());
+                out.push('\n');
+            }
+        }
+    }
+    out
+}
+
+#[derive(Debug)]
+struct Download {
+    session_id: String,
+    format: HistoryFormat,
+    content: Arc<str>,
+    crc32: u32,
+    /// Served once over HTTP instead of in chunks
+    one_time: bool,
+    expires_at: Instant,
+}
+
+/// A started download, as reported to the client
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadInfo {
+    pub token: String,
+    pub total_bytes: usize,
+    /// CRC-32 of the whole file, as 8 hex digits
+    pub total_crc32: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Chunk {
+    pub token: String,
+    /// Byte offset of `data` in the file
+    pub offset: usize,
+    pub data: String,
+    pub bytes: usize,
+    /// CRC-32 of `data`, as 8 hex digits
+    pub crc32: String,
+    /// Cursor of the following chunk; `None` once the file is complete
+    pub next: Option<usize>,
+    pub total_bytes: usize,
+    pub total_crc32: String,
+}
+
+/// The downloads in progress; shared with the management server for the
+/// HTTP handoff
+#[derive(Debug, Clone)]
+pub struct HistoryDownloads {
+    downloads: Arc<Mutex<HashMap<String, Download>>>,
+    ttl: Duration,
+}
+
+impl Default for HistoryDownloads {
+    fn default() -> Self {
+        Self::with_ttl(DOWNLOAD_TTL)
+    }
+}
+
+impl HistoryDownloads {
+    pub fn with_ttl(ttl: Duration) -> Self {
+        Self {
+            downloads: Arc::default(),
+            ttl,
+        }
+    }
+
+    /// Keeps `content` for downloading; `one_time` downloads are only
+    /// served whole, by [`HistoryDownloads::take`]
+    pub fn start(
+        &self,
+        session_id: &str,
+        format: HistoryFormat,
+        content: String,
+        one_time: bool,
+    ) -> Result<DownloadInfo> {
+        let now = Instant::now();
+        let mut downloads = self.downloads.lock().unwrap();
+        downloads.retain(|_, download| download.expires_at > now);
+        if downloads.len() >= MAX_DOWNLOADS {
+            return Err(HtMcpError::ResourceExhausted(format!(
+                "{} history downloads are in progress; finish one or let it expire",
+                MAX_DOWNLOADS
+            )));
+        }
+
+        let token = Uuid::new_v4().simple().to_string();
+        let crc32 = crc32(content.as_bytes());
+        let info = DownloadInfo {
+            token: token.clone(),
+            total_bytes: content.len(),
+            total_crc32: format!("{:08x}", crc32),
+        };
+        downloads.insert(
+            token,
+            Download {
+                session_id: session_id.to_string(),
+                format,
+                content: content.into(),
+                crc32,
+                one_time,
+                expires_at: now + self.ttl,
+            },
+        );
+        Ok(info)
+    }
+
+    /// The chunk of at most `chunk_bytes` starting at `cursor`, ending on a
+    /// character boundary. Reading extends the download's lifetime.
+    pub fn chunk(
+        &self,
+        token: &str,
+        session_id: &str,
+        format: Option<HistoryFormat>,
+        cursor: usize,
+        chunk_bytes: usize,
+    ) -> Result<Chunk> {
+        let now = Instant::now();
+        let mut downloads = self.downloads.lock().unwrap();
+        let download = downloads
+            .get_mut(token)
+            .filter(|download| download.expires_at > now && !download.one_time)
+            .filter(|download| download.session_id == session_id)
+            .ok_or_else(|| {
+                HtMcpError::InvalidRequest(format!(
+                    "No history download {} for session {}; it may have expired",
+                    token, session_id
+                ))
+            })?;
+        if format.is_some_and(|format| format != download.format) {
+            return Err(HtMcpError::InvalidRequest(format!(
+                "History download {} is in {:?} format",
+                token, download.format
+            )));
+        }
+        let content = &download.content;
+        if cursor > content.len() || !content.is_char_boundary(cursor) {
+            return Err(HtMcpError::InvalidRequest(format!(
+                "Cursor {} is not a position in history download {}",
+                cursor, token
+            )));
+        }
+        download.expires_at = now + self.ttl;
+
+        let mut end = (cursor + chunk_bytes).min(content.len());
+        while !content.is_char_boundary(end) {
+            end -= 1;
+        }
+        let data = &content[cursor..end];
+        Ok(Chunk {
+            token: token.to_string(),
+            offset: cursor,
+            data: data.to_string(),
+            bytes: data.len(),
+            crc32: format!("{:08x}", crc32(data.as_bytes())),
+            next: (end < content.len()).then_some(end),
+            total_bytes: content.len(),
+            total_crc32: format!("{:08x}", download.crc32),
+        })
+    }
+
+    /// Removes and returns a one-time download's file and format
+    pub fn take(&self, token: &str) -> Option<(HistoryFormat, Arc<str>)> {
+        let mut downloads = self.downloads.lock().unwrap();
+        let download = downloads.get(token)?;
+        if !download.one_time {
+            return None;
+        }
+        let download = downloads.remove(token)?;
+        (download.expires_at > Instant::now()).then_some((download.format, download.content))
+    }
+
+    /// Drops the downloads of a session that was closed
+    pub fn remove_session(&self, session_id: &str) {
+        self.downloads
+            .lock()
+            .unwrap()
+            .retain(|_, download| download.session_id != session_id);
+    }
+
+    pub fn len(&self) -> usize {
+        self.downloads.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_all(downloads: &HistoryDownloads, token: &str, chunk_bytes: usize) -> Vec<Chunk> {
+        let mut chunks = Vec::new();
+        let mut cursor = Some(0);
+        while let Some(offset) = cursor {
+            let chunk = downloads
+                .chunk(token, "s1", None, offset, chunk_bytes)
+                .unwrap();
+            cursor = chunk.next;
+            chunks.push(chunk);
+        }
+        chunks
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_render() {
+        let taken_at = DateTime::parse_from_rfc3339("2026-01-02T03:04:05.678Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let snapshots = [(taken_at, "$ ls\nfile   \n\n".to_string())];
+        let commands = ["ls".to_string()];
+
+        assert_eq!(
+            render(HistoryFormat::Plain, &snapshots, &commands),
+            "=== snapshot 2026-01-02T03:04:05.678Z ===\n$ ls\nfile\n=== commands ===\n$ ls\n"
+        );
+        let jsonl = render(HistoryFormat::Jsonl, &snapshots, &commands);
+        let lines: Vec<serde_json::Value> = jsonl
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                serde_json::json!({
+                    "type": "snapshot",
+                    "takenAt": "2026-01-02T03:04:05.678Z",
+                    "text": "$ ls\nfile   \n\n"
+                }),
+                serde_json::json!({"type": "command", "command": "ls"}),
+            ]
+        );
+        assert_eq!(render(HistoryFormat::Plain, &[], &[]), "");
+    }
+
+    #[test]
+    fn test_chunks_end_on_character_boundaries() {
+        let downloads = HistoryDownloads::default();
+        // Multi-byte characters straddle every chunk boundary
+        let content = "héllo wörld ✓ ".repeat(50);
+        let info = downloads
+            .start("s1", HistoryFormat::Plain, content.clone(), false)
+            .unwrap();
+        assert_eq!(info.total_bytes, content.len());
+        assert_eq!(
+            info.total_crc32,
+            format!("{:08x}", crc32(content.as_bytes()))
+        );
+
+        let chunks = read_all(&downloads, &info.token, MIN_CHUNK_BYTES);
+        assert!(chunks.len() > 1);
+        let mut joined = String::new();
+        for chunk in &chunks {
+            assert_eq!(chunk.offset, joined.len());
+            assert!(chunk.bytes <= MIN_CHUNK_BYTES && chunk.bytes > 0);
+            assert_eq!(chunk.crc32, format!("{:08x}", crc32(chunk.data.as_bytes())));
+            assert_eq!(chunk.total_crc32, info.total_crc32);
+            joined.push_str(&chunk.data);
+        }
+        assert_eq!(joined, content);
+        assert_eq!(chunks.last().unwrap().next, None);
+    }
+
+    #[test]
+    fn test_resume_mid_download() {
+        let downloads = HistoryDownloads::default();
+        let content = "0123456789".repeat(10);
+        let info = downloads
+            .start("s1", HistoryFormat::Jsonl, content.clone(), false)
+            .unwrap();
+        let first = downloads.chunk(&info.token, "s1", None, 0, 30).unwrap();
+        let second = downloads
+            .chunk(&info.token, "s1", None, first.next.unwrap(), 30)
+            .unwrap();
+        // The connection dropped before the second chunk arrived: asking for
+        // the same cursor again returns the same bytes, and the rest follows
+        let again = downloads
+            .chunk(&info.token, "s1", Some(HistoryFormat::Jsonl), 30, 30)
+            .unwrap();
+        assert_eq!(again, second);
+        let rest = downloads
+            .chunk(&info.token, "s1", None, again.next.unwrap(), 1000)
+            .unwrap();
+        assert_eq!(
+            format!("{}{}{}", first.data, again.data, rest.data),
+            content
+        );
+
+        for (session_id, format, cursor) in [
+            ("s2", None, 0),
+            ("s1", Some(HistoryFormat::Plain), 0),
+            ("s1", None, content.len() + 1),
+        ] {
+            let err = downloads
+                .chunk(&info.token, session_id, format, cursor, 30)
+                .unwrap_err();
+            assert!(matches!(err, HtMcpError::InvalidRequest(_)));
+        }
+        let end = downloads
+            .chunk(&info.token, "s1", None, content.len(), 30)
+            .unwrap();
+        assert_eq!((end.bytes, end.next), (0, None));
+    }
+
+    #[test]
+    fn test_token_lifecycle() {
+        let downloads = HistoryDownloads::with_ttl(Duration::from_millis(50));
+        let chunked = downloads
+            .start("s1", HistoryFormat::Plain, "chunked".to_string(), false)
+            .unwrap();
+        let one_time = downloads
+            .start("s1", HistoryFormat::Plain, "whole".to_string(), true)
+            .unwrap();
+
+        // Each kind is only served its own way, and one-time tokens once
+        assert!(downloads.take(&chunked.token).is_none());
+        assert!(downloads
+            .chunk(&one_time.token, "s1", None, 0, 100)
+            .is_err());
+        let (format, content) = downloads.take(&one_time.token).unwrap();
+        assert_eq!((format, &*content), (HistoryFormat::Plain, "whole"));
+        assert!(downloads.take(&one_time.token).is_none());
+
+        // Expiry
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(downloads.chunk(&chunked.token, "s1", None, 0, 100).is_err());
+
+        // Closing the session ends its downloads
+        let downloads = HistoryDownloads::default();
+        let other = downloads
+            .start("s2", HistoryFormat::Plain, "other".to_string(), false)
+            .unwrap();
+        downloads
+            .start("s1", HistoryFormat::Plain, "mine".to_string(), false)
+            .unwrap();
+        downloads.remove_session("s1");
+        assert_eq!(downloads.len(), 1);
+        assert!(downloads.chunk(&other.token, "s2", None, 0, 100).is_ok());
+
+        for _ in 1..MAX_DOWNLOADS {
+            downloads
+                .start("s2", HistoryFormat::Plain, String::new(), false)
+                .unwrap();
+        }
+        let err = downloads
+            .start("s2", HistoryFormat::Plain, String::new(), false)
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::ResourceExhausted(_)));
+    }
+}
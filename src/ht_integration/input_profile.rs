@@ -0,0 +1,196 @@
+//! Human-like pacing of `send_keys` input, for programs that behave
+//! differently when a whole line arrives at once (anti-bot prompts, games
+//! reading raw keystrokes).
+//!
+//! A profile turns the keys of a call into a delay before each key: a base
+//! delay with uniform jitter, plus an occasional longer pause before a new
+//! word. The delays depend only on the keys and a seed, so a call made with
+//! the same `seed` is paced the same way every time.
+
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum InputProfile {
+    /// Everything in one write, as before profiles existed
+    #[default]
+    Instant,
+    /// A quick typist: about 25 keys a second
+    FastHuman,
+    /// A hesitant typist: about 8 keys a second, often stopping between
+    /// words
+    SlowHuman,
+}
+
+/// Delays of a paced profile, in milliseconds
+struct Timing {
+    key_delay: u64,
+    /// Each key's delay is `key_delay` plus or minus up to this
+    jitter: u64,
+    /// Chance of a pause before a word that follows whitespace
+    pause_chance: f64,
+    /// Extra delay of such a pause, as a range
+    pause: (u64, u64),
+}
+
+impl InputProfile {
+    pub fn name(self) -> &'static str {
+        match self {
+            InputProfile::Instant => "instant",
+            InputProfile::FastHuman => "fast-human",
+            InputProfile::SlowHuman => "slow-human",
+        }
+    }
+
+    fn timing(self) -> Option<Timing> {
+        match self {
+            InputProfile::Instant => None,
+            InputProfile::FastHuman => Some(Timing {
+                key_delay: 40,
+                jitter: 20,
+                pause_chance: 0.1,
+                pause: (150, 400),
+            }),
+            InputProfile::SlowHuman => Some(Timing {
+                key_delay: 120,
+                jitter: 60,
+                pause_chance: 0.3,
+                pause: (300, 900),
+            }),
+        }
+    }
+
+    /// Whether input is sent at once rather than key by key
+    pub fn is_instant(self) -> bool {
+        self.timing().is_none()
+    }
+
+    /// The delay before each of `keys`, the text each key sends; the first
+    /// key goes out at once
+    pub fn delays(self, keys: &[&str], seed: u64) -> Vec<Duration> {
+        let Some(timing) = self.timing() else {
+            return vec![Duration::ZERO; keys.len()];
+        };
+        let mut jitter = Jitter::new(seed);
+        let mut delays = Vec::with_capacity(keys.len());
+        let mut previous: Option<&str> = None;
+        for key in keys {
+            let Some(previous_key) = previous else {
+                delays.push(Duration::ZERO);
+                previous = Some(key);
+                continue;
+            };
+            let mut ms = timing.key_delay - timing.jitter + jitter.below(2 * timing.jitter + 1);
+            let new_word = previous_key.ends_with(char::is_whitespace)
+                && !key.starts_with(char::is_whitespace);
+            if new_word && jitter.chance(timing.pause_chance) {
+                ms += timing.pause.0 + jitter.below(timing.pause.1 - timing.pause.0 + 1);
+            }
+            delays.push(Duration::from_millis(ms));
+            previous = Some(key);
+        }
+        delays
+    }
+}
+
+/// A seed for a call that did not give one
+pub fn random_seed() -> u64 {
+    static CALLS: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    // Distinct for calls within the clock's resolution
+    Jitter::new(nanos ^ CALLS.fetch_add(1, Ordering::Relaxed)).next_u64()
+}
+
+/// SplitMix64: small, fast and fully determined by its seed
+struct Jitter {
+    state: u64,
+}
+
+impl Jitter {
+    fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `0..n`; `n` is small, so the modulo bias is negligible
+    fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        let unit = (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+        unit < probability
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chars(text: &str) -> Vec<String> {
+        text.chars().map(String::from).collect()
+    }
+
+    fn delays(profile: InputProfile, text: &str, seed: u64) -> Vec<Duration> {
+        let keys = chars(text);
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        profile.delays(&keys, seed)
+    }
+
+    #[test]
+    fn test_same_seed_same_delays() {
+        let text = "the quick brown fox jumps over the lazy dog";
+        let first = delays(InputProfile::SlowHuman, text, 42);
+        assert_eq!(first, delays(InputProfile::SlowHuman, text, 42));
+        assert_ne!(first, delays(InputProfile::SlowHuman, text, 43));
+        assert_eq!(first.len(), text.chars().count());
+        assert_eq!(first[0], Duration::ZERO);
+    }
+
+    #[test]
+    fn test_delays_stay_within_the_profile() {
+        let text = "one two three four five six seven eight nine ten ".repeat(10);
+        for (profile, min, max) in [
+            (InputProfile::FastHuman, 20, 60 + 400),
+            (InputProfile::SlowHuman, 60, 180 + 900),
+        ] {
+            let delays = delays(profile, &text, 7);
+            let mut paused = 0;
+            for (index, delay) in delays.iter().enumerate().skip(1) {
+                let ms = delay.as_millis() as u64;
+                assert!((min..=max).contains(&ms), "{:?}: {}", profile, ms);
+                // Only a word after whitespace gets the longer pause
+                if ms > max - profile.timing().unwrap().pause.0 {
+                    paused += 1;
+                    assert_eq!(text.as_bytes()[index - 1], b' ');
+                }
+            }
+            assert!(paused > 0, "{:?} never paused", profile);
+        }
+    }
+
+    #[test]
+    fn test_instant() {
+        assert!(InputProfile::Instant.is_instant());
+        assert!(delays(InputProfile::Instant, "abc", 1)
+            .iter()
+            .all(Duration::is_zero));
+        assert_ne!(random_seed(), random_seed());
+        assert_eq!(
+            serde_json::to_value(InputProfile::SlowHuman).unwrap(),
+            "slow-human"
+        );
+    }
+}
@@ -0,0 +1,249 @@
+//! Advisory interaction locks, for agents sharing a session.
+//!
+//! A holder takes a session's lock with `ht_acquire_lock` for a TTL. While
+//! it is held, the tools in [`INPUT_TOOLS`], the ones closing the session
+//! included, fail with `SessionLocked` unless the call names the same
+//! `holder`, or passes `override: true` on a server with admin tools
+//! enabled. Reading the screen is never restricted. The lock is freed by `ht_release_lock` or when its TTL runs out; re-acquiring
+//! it as the holder extends it.
+//!
+//! Locks are policy on top of the per-session input lock, which still orders
+//! the input of whoever is allowed to send it. Web viewers name no holder, so
+//! a locked session refuses what they type as well.
+
+use crate::error::{HtMcpError, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::Value;
+use std::time::{Duration, Instant};
+
+/// Lock lifetime unless `ttlSecs` says otherwise
+pub const DEFAULT_LOCK_TTL_SECS: u64 = 300;
+
+/// Longest `ttlSecs`; a holder needing longer re-acquires
+pub const MAX_LOCK_TTL_SECS: u64 = 86_400;
+
+/// Tools that type into, resize or close a session, and so respect its
+/// lock. `ht_cancel_job` interrupts its job's session, named by the server.
+pub const INPUT_TOOLS: [&str; 13] = [
+    "ht_send_keys",
+    "ht_type_file",
+    "ht_close_stdin",
+    "ht_session_stdin_pipe",
+    "ht_execute_command",
+    "ht_check_command_exists",
+    "ht_probe_tools",
+    "ht_interactive_prompt",
+    "ht_parallel_execute",
+    "ht_resize_terminal",
+    "ht_cancel_job",
+    "ht_close_session",
+    "ht_bulk_close_sessions",
+];
+
+/// The [`INPUT_TOOLS`] that close sessions. They send no input, so a
+/// read-only session is closed by them like any other.
+pub const CLOSE_TOOLS: [&str; 2] = ["ht_close_session", "ht_bulk_close_sessions"];
+
+#[derive(Debug, Clone)]
+pub struct InteractionLock {
+    holder: String,
+    expires_at: Instant,
+    /// `expires_at` on the wall clock, for clients
+    expires_at_utc: DateTime<Utc>,
+}
+
+impl InteractionLock {
+    pub fn new(holder: String, ttl: Duration) -> Self {
+        let mut lock = Self {
+            holder,
+            expires_at: Instant::now(),
+            expires_at_utc: Utc::now(),
+        };
+        lock.extend(ttl);
+        lock
+    }
+
+    /// Makes the lock run out `ttl` from now
+    pub fn extend(&mut self, ttl: Duration) {
+        self.expires_at = Instant::now() + ttl;
+        self.expires_at_utc = Utc::now() + chrono::Duration::milliseconds(ttl.as_millis() as i64);
+    }
+
+    pub fn holder(&self) -> &str {
+        &self.holder
+    }
+
+    pub fn is_expired(&self) -> bool {
+        Instant::now() >= self.expires_at
+    }
+
+    pub fn expires_at(&self) -> String {
+        self.expires_at_utc
+            .to_rfc3339_opts(SecondsFormat::Millis, true)
+    }
+
+    /// The error for a caller that is not the holder
+    pub fn refuse(&self, session_id: &str) -> HtMcpError {
+        HtMcpError::SessionLocked {
+            session_id: session_id.to_string(),
+            holder: self.holder.clone(),
+            expires_at: self.expires_at(),
+        }
+    }
+
+    /// Lock state as reported in session info
+    pub fn summary(&self) -> Value {
+        serde_json::json!({
+            "holder": self.holder,
+            "expiresAt": self.expires_at(),
+            "expiresInMs": self.expires_at.saturating_duration_since(Instant::now()).as_millis() as u64,
+        })
+    }
+}
+
+/// Who an input tool call is made by, as far as locks are concerned
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockClaim {
+    /// Sessions the call sends input to
+    pub session_ids: Vec<String>,
+    /// The call's `holder` meta-field
+    pub holder: Option<String>,
+    /// `override: true`, allowed only with admin tools enabled
+    pub override_lock: bool,
+}
+
+impl LockClaim {
+    /// Whether the call may send input to a session locked by `lock`
+    pub fn allows(&self, lock: &InteractionLock) -> bool {
+        self.override_lock || self.holder.as_deref() == Some(lock.holder())
+    }
+}
+
+/// The lock claim of a call to `tool_name`; `None` for tools that send no
+/// input. `override` is refused unless `admin_tools` is set.
+pub fn claim(tool_name: &str, arguments: &Value, admin_tools: bool) -> Result<Option<LockClaim>> {
+    if !INPUT_TOOLS.contains(&tool_name) {
+        return Ok(None);
+    }
+    let holder = match arguments.get("holder") {
+        None | Some(Value::Null) => None,
+        Some(Value::String(holder)) if !holder.is_empty() => Some(holder.clone()),
+        Some(value) => {
+            return Err(HtMcpError::InvalidRequest(format!(
+                "Invalid arguments: holder must be a non-empty string, got {}",
+                value
+            )))
+        }
+    };
+    let override_lock = match arguments.get("override") {
+        None | Some(Value::Null) => false,
+        Some(Value::Bool(override_lock)) => *override_lock,
+        Some(value) => {
+            return Err(HtMcpError::InvalidRequest(format!(
+                "Invalid arguments: override must be a boolean, got {}",
+                value
+            )))
+        }
+    };
+    if override_lock && !admin_tools {
+        return Err(HtMcpError::InvalidRequest(
+            "override of a session lock needs enable_admin_tools in the config".to_string(),
+        ));
+    }
+    // Malformed ids are reported by the tool itself
+    let session_ids = match arguments.get("sessionIds").and_then(Value::as_array) {
+        Some(ids) => ids
+            .iter()
+            .filter_map(|id| id.as_str().map(str::to_string))
+            .collect(),
+        None => arguments
+            .get("sessionId")
+            .and_then(Value::as_str)
+            .map(|id| vec![id.to_string()])
+            .unwrap_or_default(),
+    };
+    Ok(Some(LockClaim {
+        session_ids,
+        holder,
+        override_lock,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_claim_of_input_tools_only() {
+        assert_eq!(
+            claim("ht_take_snapshot", &json!({"sessionId": "a"}), false).unwrap(),
+            None
+        );
+
+        let claimed = claim(
+            "ht_send_keys",
+            &json!({"sessionId": "a", "holder": "driver"}),
+            false,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(claimed.session_ids, vec!["a".to_string()]);
+        assert_eq!(claimed.holder.as_deref(), Some("driver"));
+        assert!(!claimed.override_lock);
+
+        let parallel = claim(
+            "ht_parallel_execute",
+            &json!({"sessionIds": ["a", "b"], "command": "ls"}),
+            false,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(parallel.session_ids, vec!["a".to_string(), "b".to_string()]);
+        assert_eq!(parallel.holder, None);
+    }
+
+    #[test]
+    fn test_override_needs_admin_tools() {
+        let arguments = json!({"sessionId": "a", "override": true});
+        let err = claim("ht_execute_command", &arguments, false).unwrap_err();
+        assert!(matches!(err, HtMcpError::InvalidRequest(_)), "{}", err);
+        assert!(
+            claim("ht_execute_command", &arguments, true)
+                .unwrap()
+                .unwrap()
+                .override_lock
+        );
+
+        let err = claim("ht_send_keys", &json!({"holder": ""}), true).unwrap_err();
+        assert!(matches!(err, HtMcpError::InvalidRequest(_)), "{}", err);
+    }
+
+    #[test]
+    fn test_lock_allows_holder_and_override() {
+        let lock = InteractionLock::new("driver".to_string(), Duration::from_secs(60));
+        let claim = |holder: Option<&str>, override_lock| LockClaim {
+            session_ids: vec!["a".to_string()],
+            holder: holder.map(str::to_string),
+            override_lock,
+        };
+        assert!(claim(Some("driver"), false).allows(&lock));
+        assert!(!claim(Some("observer"), false).allows(&lock));
+        assert!(!claim(None, false).allows(&lock));
+        assert!(claim(Some("observer"), true).allows(&lock));
+
+        let err = lock.refuse("a");
+        assert_eq!(err.code(), "session_locked");
+        assert!(err.to_string().contains("driver"), "{}", err);
+        assert_eq!(lock.summary()["holder"], "driver");
+    }
+
+    #[test]
+    fn test_lock_expires_and_extends() {
+        let mut lock = InteractionLock::new("driver".to_string(), Duration::ZERO);
+        assert!(lock.is_expired());
+        lock.extend(Duration::from_secs(60));
+        assert!(!lock.is_expired());
+        assert!(lock.summary()["expiresInMs"].as_u64().unwrap() > 59_000);
+    }
+}
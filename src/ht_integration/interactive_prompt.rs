@@ -0,0 +1,133 @@
+//! Answering a prompt once it shows up, for `ht_interactive_prompt`.
+//!
+//! The screen is polled until the last line with content matches the
+//! pattern, then the response is typed followed by Enter. Only the last line
+//! is matched so a prompt answered earlier, still on screen above, does not
+//! count. Responses are often passwords: they are neither logged nor
+//! returned.
+
+use crate::error::{HtMcpError, Result};
+use crate::ht_integration::control_sequences;
+use crate::ht_integration::exit::ExitWaiters;
+use crate::ht_integration::keys;
+use crate::ht_integration::session_handle::request_snapshot;
+use crate::ht_integration::session_manager::SessionCommand;
+use ht_core::command::InputSeq;
+use regex::Regex;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, Semaphore};
+use tracing::info;
+
+/// How long to watch for the prompt unless `timeoutMs` says otherwise
+pub const DEFAULT_PROMPT_TIMEOUT_MS: u64 = 20_000;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A prompt to watch a session for and the answer to give
+pub struct PromptWatch {
+    pub session_id: String,
+    pub pattern: Regex,
+    pub response: String,
+    pub timeout: Duration,
+    pub command_tx: Arc<mpsc::Sender<SessionCommand>>,
+    /// The session's input lock, so the answer is not typed into the
+    /// middle of paced input
+    pub input_lock: Arc<tokio::sync::Mutex<()>>,
+    pub snapshot_permits: Arc<Semaphore>,
+    pub exit: Arc<ExitWaiters>,
+}
+
+impl PromptWatch {
+    /// Waits for the prompt and answers it. A prompt that does not show up
+    /// within the timeout is reported with `promptDetected: false`; a
+    /// session exiting first fails with `SessionExited`.
+    pub async fn run(self) -> Result<serde_json::Value> {
+        let watch = async {
+            loop {
+                let screen = request_snapshot(&self.command_tx, &self.snapshot_permits).await?;
+                if prompt_shown(&screen, &self.pattern) {
+                    return Ok::<_, HtMcpError>(());
+                }
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        };
+        let exited = self.exit.register();
+        let detected = tokio::select! {
+            // Ahead of the watch, whose snapshots fail once the session is
+            // gone
+            biased;
+            Ok(exit) = exited => {
+                return Err(HtMcpError::SessionExited(format!(
+                    "{}: {} before the prompt appeared",
+                    self.session_id,
+                    exit.describe()
+                )));
+            }
+            watched = tokio::time::timeout(self.timeout, watch) => match watched {
+                Ok(result) => result.map(|_| true)?,
+                Err(_) => false,
+            },
+        };
+
+        if detected {
+            let _input = self.input_lock.lock().await;
+            self.command_tx
+                .send(SessionCommand::Input(vec![
+                    InputSeq::Standard(self.response.clone()),
+                    keys::parse("Enter"),
+                ]))
+                .await
+                .map_err(|e| {
+                    let err = HtMcpError::Internal(format!("Failed to send response: {}", e));
+                    self.exit.explain(&self.session_id, err)
+                })?;
+            info!("Answered a prompt in session {}", self.session_id);
+            // Give the program a moment to take the answer in
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+
+        Ok(serde_json::json!({
+            "sessionId": self.session_id,
+            "promptDetected": detected,
+            "responsesSent": usize::from(detected),
+            "snapshot": request_snapshot(&self.command_tx, &self.snapshot_permits).await?
+        }))
+    }
+}
+
+/// Whether the last line of `screen` with content matches `pattern`
+fn prompt_shown(screen: &str, pattern: &Regex) -> bool {
+    screen
+        .lines()
+        .rev()
+        .map(|line| control_sequences::strip(line).trim_end().to_string())
+        .find(|line| !line.is_empty())
+        .is_some_and(|line| pattern.is_match(&line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prompt_shown_on_last_line() {
+        let pattern = Regex::new(r"\[Y/n\]\s*$").unwrap();
+        assert!(prompt_shown(
+            "Reading package lists...\nDo you want to continue? [Y/n] \n\n",
+            &pattern
+        ));
+        // Answered already: the prompt is no longer the last line
+        assert!(!prompt_shown(
+            "Do you want to continue? [Y/n] y\nSetting up curl ...\n",
+            &pattern
+        ));
+        assert!(!prompt_shown("", &pattern));
+
+        let password = Regex::new(r"(?i)password.*:").unwrap();
+        assert!(prompt_shown(
+            "\x1b[1m[sudo] password for ci:\x1b[0m ",
+            &password
+        ));
+    }
+}
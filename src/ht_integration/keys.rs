@@ -0,0 +1,187 @@
+//! Key chords spelled out in full (`"Ctrl-A"`, `"Alt-F"`, `"Shift-F5"`),
+//! as agents tend to write them. They are translated here, to the bytes an
+//! xterm sends, before the remaining key names go to ht's own parser.
+
+use ht_core::command::InputSeq;
+
+/// Escape sequences xterm sends for Shift-F1 through Shift-F12
+const SHIFT_FUNCTION_KEYS: [&str; 12] = [
+    "\x1b[1;2P",
+    "\x1b[1;2Q",
+    "\x1b[1;2R",
+    "\x1b[1;2S",
+    "\x1b[15;2~",
+    "\x1b[17;2~",
+    "\x1b[18;2~",
+    "\x1b[19;2~",
+    "\x1b[20;2~",
+    "\x1b[21;2~",
+    "\x1b[23;2~",
+    "\x1b[24;2~",
+];
+
+/// Bytes for a `Ctrl-`, `Alt-` or `Shift-` chord, or `None` if `s` is not
+/// one. Modifier names are case-insensitive; the key after `Alt-` is sent
+/// as written, so `Alt-f` and `Alt-F` differ as they do in a terminal.
+pub fn parse_chord(s: &str) -> Option<Vec<u8>> {
+    let (modifier, key) = s.split_once('-')?;
+    let mut chars = key.chars();
+    let (first, rest) = (chars.next()?, chars.as_str());
+
+    if modifier.eq_ignore_ascii_case("ctrl") {
+        if !rest.is_empty() {
+            return key.eq_ignore_ascii_case("space").then(|| vec![0]);
+        }
+        return match first.to_ascii_uppercase() {
+            // A-Z give 1-26; @ [ \ ] ^ _ the control codes around them
+            c @ ('@'..='_') => Some(vec![c as u8 - b'@']),
+            '?' => Some(vec![0x7f]),
+            _ => None,
+        };
+    }
+    if modifier.eq_ignore_ascii_case("alt") {
+        return (rest.is_empty() && (first.is_ascii_graphic() || first == ' ')).then(|| {
+            let mut bytes = vec![0x1b];
+            bytes.push(first as u8);
+            bytes
+        });
+    }
+    if modifier.eq_ignore_ascii_case("shift") && first.eq_ignore_ascii_case(&'f') {
+        let number: usize = rest.parse().ok()?;
+        let seq = SHIFT_FUNCTION_KEYS.get(number.checked_sub(1)?)?;
+        return Some(seq.as_bytes().to_vec());
+    }
+    None
+}
+
+/// Input for one key name as accepted by `send_keys`: a chord, or else
+/// whatever ht's `parse_key` makes of it
+pub fn parse(key: &str) -> InputSeq {
+    match parse_chord(key) {
+        // Chord bytes are all ASCII
+        Some(bytes) => InputSeq::Standard(String::from_utf8_lossy(&bytes).into_owned()),
+        None => ht_core::api::stdio::parse_key(key.to_string()),
+    }
+}
+
+/// Input for `key` one keystroke at a time, for paced input: text that is
+/// not a key name is split into its characters, anything else is a single
+/// keystroke
+pub fn keystrokes(key: &str) -> Vec<InputSeq> {
+    match parse(key) {
+        InputSeq::Standard(text) if text == key && key.chars().nth(1).is_some() => key
+            .chars()
+            .map(|c| InputSeq::Standard(c.to_string()))
+            .collect(),
+        seq => vec![seq],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ctrl() {
+        for (index, letter) in ('A'..='Z').enumerate() {
+            let code = index as u8 + 1;
+            assert_eq!(parse_chord(&format!("Ctrl-{}", letter)), Some(vec![code]));
+            let lower = letter.to_ascii_lowercase();
+            assert_eq!(parse_chord(&format!("ctrl-{}", lower)), Some(vec![code]));
+        }
+        let table: &[(&str, u8)] = &[
+            ("Ctrl-@", 0x00),
+            ("Ctrl-Space", 0x00),
+            ("Ctrl-[", 0x1b),
+            ("Ctrl-\\", 0x1c),
+            ("Ctrl-]", 0x1d),
+            ("Ctrl-^", 0x1e),
+            ("Ctrl-_", 0x1f),
+            ("Ctrl-?", 0x7f),
+            ("CTRL-c", 0x03),
+        ];
+        for &(chord, byte) in table {
+            assert_eq!(parse_chord(chord), Some(vec![byte]), "{}", chord);
+        }
+        for chord in ["Ctrl-1", "Ctrl-AB", "Ctrl-", "Ctrl-é"] {
+            assert_eq!(parse_chord(chord), None, "{}", chord);
+        }
+    }
+
+    #[test]
+    fn test_alt() {
+        for letter in ('a'..='z').chain('A'..='Z') {
+            assert_eq!(
+                parse_chord(&format!("Alt-{}", letter)),
+                Some(vec![0x1b, letter as u8])
+            );
+        }
+        let table: &[(&str, &[u8])] = &[
+            ("Alt-.", b"\x1b."),
+            ("Alt-<", b"\x1b<"),
+            ("Alt->", b"\x1b>"),
+            ("Alt-0", b"\x1b0"),
+            ("alt-b", b"\x1bb"),
+            ("Alt- ", b"\x1b "),
+        ];
+        for &(chord, bytes) in table {
+            assert_eq!(parse_chord(chord).as_deref(), Some(bytes), "{}", chord);
+        }
+        for chord in ["Alt-Enter", "Alt-", "Alt-é"] {
+            assert_eq!(parse_chord(chord), None, "{}", chord);
+        }
+    }
+
+    #[test]
+    fn test_shift_function_keys() {
+        let table = [
+            ("Shift-F1", "\x1b[1;2P"),
+            ("Shift-F2", "\x1b[1;2Q"),
+            ("Shift-F3", "\x1b[1;2R"),
+            ("Shift-F4", "\x1b[1;2S"),
+            ("Shift-F5", "\x1b[15;2~"),
+            ("Shift-F6", "\x1b[17;2~"),
+            ("Shift-F7", "\x1b[18;2~"),
+            ("Shift-F8", "\x1b[19;2~"),
+            ("Shift-F9", "\x1b[20;2~"),
+            ("Shift-F10", "\x1b[21;2~"),
+            ("Shift-F11", "\x1b[23;2~"),
+            ("shift-f12", "\x1b[24;2~"),
+        ];
+        for (chord, seq) in table {
+            assert_eq!(
+                parse_chord(chord),
+                Some(seq.as_bytes().to_vec()),
+                "{}",
+                chord
+            );
+        }
+        for chord in ["Shift-F0", "Shift-F13", "Shift-Tab", "Shift-A"] {
+            assert_eq!(parse_chord(chord), None, "{}", chord);
+        }
+    }
+
+    #[test]
+    fn test_other_keys_are_left_to_ht() {
+        for key in ["Enter", "C-c", "^c", "ls -la", "Left", "-", "x-y"] {
+            assert_eq!(parse_chord(key), None, "{}", key);
+        }
+        assert!(matches!(parse("Ctrl-A"), InputSeq::Standard(s) if s == "\x01"));
+        assert!(matches!(parse("Enter"), InputSeq::Standard(s) if s == "\r"));
+    }
+
+    #[test]
+    fn test_keystrokes() {
+        let typed: Vec<String> = keystrokes("ls -la")
+            .into_iter()
+            .map(|seq| match seq {
+                InputSeq::Standard(s) => s,
+                InputSeq::Cursor(..) => panic!("text is not a cursor key"),
+            })
+            .collect();
+        assert_eq!(typed, ["l", "s", " ", "-", "l", "a"]);
+        for key in ["Enter", "Ctrl-A", "Left", "x"] {
+            assert_eq!(keystrokes(key).len(), 1, "{}", key);
+        }
+    }
+}
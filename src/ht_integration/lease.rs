@@ -0,0 +1,108 @@
+//! Time-boxed session leases. A session created with `leaseSecs` is closed
+//! once its lease runs out unless the owning client keeps renewing it, no
+//! matter how busy the session is.
+
+use std::time::{Duration, Instant};
+
+use crate::error::{HtMcpError, Result};
+
+#[derive(Debug, Clone)]
+pub struct Lease {
+    expires_at: Instant,
+    renewals: u32,
+    /// Whether the expiry warning went out for the current expiry
+    warned: bool,
+}
+
+impl Lease {
+    pub fn new(duration: Duration) -> Result<Self> {
+        Ok(Self {
+            expires_at: expiry(Instant::now(), duration)?,
+            renewals: 0,
+            warned: false,
+        })
+    }
+
+    /// Pushes the expiry back by `extend`. A lease that already ran out but
+    /// was not reaped yet is extended from now.
+    pub fn renew(&mut self, extend: Duration) -> Result<()> {
+        self.expires_at = expiry(self.expires_at.max(Instant::now()), extend)?;
+        self.renewals += 1;
+        self.warned = false;
+        Ok(())
+    }
+
+    pub fn remaining(&self) -> Duration {
+        self.expires_at.saturating_duration_since(Instant::now())
+    }
+
+    pub fn is_expired(&self) -> bool {
+        Instant::now() >= self.expires_at
+    }
+
+    pub fn renewals(&self) -> u32 {
+        self.renewals
+    }
+
+    /// Marks the lease warned if it expires within `window` and was not
+    /// warned about yet; returns whether a warning is due
+    pub fn take_warning(&mut self, window: Duration) -> bool {
+        if self.warned || self.remaining() > window {
+            return false;
+        }
+        self.warned = true;
+        true
+    }
+
+    /// Lease state as reported in session info
+    pub fn summary(&self) -> serde_json::Value {
+        serde_json::json!({
+            "expiresInMs": self.remaining().as_millis() as u64,
+            "renewals": self.renewals,
+        })
+    }
+}
+
+fn expiry(from: Instant, duration: Duration) -> Result<Instant> {
+    from.checked_add(duration).ok_or_else(|| {
+        HtMcpError::InvalidRequest(format!("A lease of {}s is too long", duration.as_secs()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_renew_extends_and_rearms_warning() {
+        let mut lease = Lease::new(Duration::from_secs(10)).unwrap();
+        assert!(!lease.take_warning(Duration::from_secs(5)));
+        assert!(lease.take_warning(Duration::from_secs(30)));
+        assert!(!lease.take_warning(Duration::from_secs(30)), "warned once");
+
+        lease.renew(Duration::from_secs(60)).unwrap();
+        assert_eq!(lease.renewals(), 1);
+        assert!(lease.remaining() > Duration::from_secs(60));
+        assert!(lease.take_warning(Duration::from_secs(120)));
+    }
+
+    #[test]
+    fn test_expired_lease_renews_from_now() {
+        let mut lease = Lease::new(Duration::ZERO).unwrap();
+        assert!(lease.is_expired());
+
+        lease.renew(Duration::from_secs(5)).unwrap();
+        assert!(!lease.is_expired());
+        assert!(lease.remaining() <= Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_oversized_lease_is_rejected() {
+        assert!(Lease::new(Duration::from_secs(u64::MAX)).is_err());
+
+        let mut lease = Lease::new(Duration::from_secs(10)).unwrap();
+        assert!(lease.renew(Duration::from_secs(u64::MAX)).is_err());
+        assert_eq!(lease.renewals(), 0, "a failed renewal is not counted");
+        assert!(lease.remaining() <= Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,155 @@
+//! A rolling five minutes of each session's counters and memory use, for
+//! `ht_get_session_metrics_history`: is memory growing, has output stopped,
+//! is anyone still reading the screen?
+//!
+//! A task per session samples every [`METRICS_INTERVAL`] and stops once the
+//! session, which holds the only strong reference to the history, is gone.
+
+use crate::ht_integration::process::ProcessSampler;
+use crate::ht_integration::screen_classifier::OutputActivity;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::time::MissedTickBehavior;
+
+pub const METRICS_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Points kept per session; with [`METRICS_INTERVAL`], five minutes
+pub const METRICS_HISTORY_LEN: usize = 10;
+
+pub type MetricsHistory = Arc<Mutex<VecDeque<MetricPoint>>>;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MetricPoint {
+    /// RFC 3339 time of the sample
+    pub timestamp: String,
+    /// Snapshots served so far
+    #[serde(rename = "snapshotCount")]
+    pub snapshot_count: u64,
+    #[serde(rename = "inputBytes")]
+    pub input_bytes: u64,
+    #[serde(rename = "outputBytes")]
+    pub output_bytes: u64,
+    /// `None` where process usage cannot be read
+    #[serde(rename = "memoryRssKb")]
+    pub memory_rss_kb: Option<u64>,
+}
+
+/// Input and snapshot counts, kept by the session's event loop
+#[derive(Debug, Default)]
+pub struct SessionCounters {
+    input_bytes: AtomicU64,
+    snapshots: AtomicU64,
+}
+
+impl SessionCounters {
+    pub fn record_input(&self, bytes: usize) {
+        self.input_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_snapshot(&self) {
+        self.snapshots.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn input_bytes(&self) -> u64 {
+        self.input_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn snapshots(&self) -> u64 {
+        self.snapshots.load(Ordering::Relaxed)
+    }
+}
+
+/// Appends `point`, dropping the oldest beyond [`METRICS_HISTORY_LEN`]
+pub fn record(history: &Mutex<VecDeque<MetricPoint>>, point: MetricPoint) {
+    let mut points = history.lock().unwrap();
+    points.push_back(point);
+    while points.len() > METRICS_HISTORY_LEN {
+        points.pop_front();
+    }
+}
+
+/// Samples the session every `interval`, starting now, until `history` is
+/// dropped
+pub async fn sample(
+    history: Weak<Mutex<VecDeque<MetricPoint>>>,
+    counters: Arc<SessionCounters>,
+    activity: Arc<OutputActivity>,
+    pid: Option<u32>,
+    interval: Duration,
+) {
+    let started = Instant::now();
+    let mut sampler = ProcessSampler::default();
+    let mut timer = tokio::time::interval(interval);
+    timer.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    loop {
+        timer.tick().await;
+        let Some(history) = history.upgrade() else {
+            break;
+        };
+        let memory_rss_kb = pid
+            .and_then(|pid| sampler.sample(pid, started))
+            .map(|usage| usage.rss_kb);
+        record(
+            &history,
+            MetricPoint {
+                timestamp: chrono::Utc::now().to_rfc3339(),
+                snapshot_count: counters.snapshots(),
+                input_bytes: counters.input_bytes(),
+                output_bytes: activity.output_bytes(),
+                memory_rss_kb,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sample_until_dropped() {
+        let history = MetricsHistory::default();
+        let counters = Arc::new(SessionCounters::default());
+        let activity = Arc::new(OutputActivity::default());
+        let task = tokio::spawn(sample(
+            Arc::downgrade(&history),
+            counters.clone(),
+            activity.clone(),
+            Some(std::process::id()),
+            Duration::from_millis(10),
+        ));
+
+        counters.record_input(5);
+        counters.record_snapshot();
+        activity.record(b"hello");
+        // Sampled more often than kept, so the oldest are dropped
+        while history.lock().unwrap().len() < METRICS_HISTORY_LEN {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        {
+            let points = history.lock().unwrap();
+            assert_eq!(points.len(), METRICS_HISTORY_LEN);
+            let last = points.back().unwrap();
+            assert_eq!(
+                (last.input_bytes, last.snapshot_count, last.output_bytes),
+                (5, 1, 5)
+            );
+            #[cfg(target_os = "linux")]
+            assert!(last.memory_rss_kb.is_some_and(|kb| kb > 0));
+            assert!(points
+                .iter()
+                .zip(points.iter().skip(1))
+                .all(|(a, b)| a.timestamp <= b.timestamp));
+        }
+
+        drop(history);
+        tokio::time::timeout(Duration::from_secs(1), task)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
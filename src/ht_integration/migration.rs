@@ -0,0 +1,150 @@
+//! Moving sessions between `SessionManager`s.
+//!
+//! A live PTY cannot move between processes, so a migrated session is
+//! re-spawned from its creation settings on the target and carries the
+//! source's last screen and command history as context.
+
+use crate::ht_integration::connection_guard::ConnectionLimits;
+use crate::ht_integration::input_profile::InputProfile;
+use crate::ht_integration::retention::{RetentionOverrides, RetentionPolicy};
+use crate::mcp::types::{ColorProfile, CreateSessionArgs, RunAsArgs};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Settings a session was created with, enough to recreate it elsewhere
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionConfig {
+    pub command: Vec<String>,
+    /// Resolved working directory, when one was set or a workspace root is
+    /// configured
+    #[serde(default)]
+    pub cwd: Option<String>,
+    /// Variables set with `env` at creation
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Names of `env` variables whose values were left out of an export
+    #[serde(default)]
+    pub redacted_env: Vec<String>,
+    pub enable_web_server: bool,
+    pub enable_tunnel: bool,
+    pub max_memory_mb: Option<u64>,
+    /// Limits the web server ran with, when it was enabled
+    pub connection_limits: Option<ConnectionLimits>,
+    pub run_as: Option<RunAsArgs>,
+    pub label_color: Option<String>,
+    pub label_icon: Option<String>,
+    #[serde(default)]
+    pub temp_dir: bool,
+    #[serde(default)]
+    pub snapshot_interval_ms: Option<u64>,
+    /// Initial lease; the recreated session starts a fresh one
+    #[serde(default)]
+    pub lease_secs: Option<u64>,
+    #[serde(default)]
+    pub auto_detect_prompt: bool,
+    #[serde(default)]
+    pub trace_mode: bool,
+    #[serde(default)]
+    pub retention: RetentionPolicy,
+    #[serde(default)]
+    pub color_profile: Option<ColorProfile>,
+    #[serde(default)]
+    pub cpu_affinity: Option<Vec<usize>>,
+    #[serde(default)]
+    pub input_profile: InputProfile,
+    #[serde(default)]
+    pub group_id: Option<String>,
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    /// Size asked for at creation
+    #[serde(default)]
+    pub cols: Option<usize>,
+    #[serde(default)]
+    pub rows: Option<usize>,
+    #[serde(default)]
+    pub output_webhook_url: Option<String>,
+    #[serde(default)]
+    pub output_webhook_interval_ms: Option<u64>,
+    #[serde(default)]
+    pub read_only: bool,
+    /// `tunnelName` asked for at creation
+    #[serde(default)]
+    pub tunnel_name: Option<String>,
+}
+
+impl SessionConfig {
+    /// Leaves the values of `env` out, keeping the names in `redacted_env`:
+    /// they may hold secrets that must not end up in a file
+    pub fn redact_env(&mut self) {
+        let env = std::mem::take(&mut self.env);
+        self.redacted_env.extend(env.into_keys());
+    }
+
+    /// Arguments that recreate a session with these settings
+    pub fn to_create_args(&self) -> CreateSessionArgs {
+        let limits = self.connection_limits.as_ref();
+        CreateSessionArgs {
+            command: Some(self.command.clone()),
+            cwd: self.cwd.clone(),
+            env: (!self.env.is_empty()).then(|| self.env.clone().into_iter().collect()),
+            enable_web_server: Some(self.enable_web_server),
+            enable_tunnel: Some(self.enable_tunnel),
+            idempotency_key: None,
+            max_memory_mb: self.max_memory_mb,
+            max_connections: limits.map(|l| l.max_connections),
+            max_connections_per_ip: limits.map(|l| l.max_connections_per_ip),
+            idle_timeout_secs: limits.map(|l| l.idle_timeout_secs),
+            run_as: self.run_as.clone(),
+            label_color: self.label_color.clone(),
+            label_icon: self.label_icon.clone(),
+            temp_dir: Some(self.temp_dir),
+            snapshot_interval_ms: self.snapshot_interval_ms,
+            lease_secs: self.lease_secs,
+            auto_detect_prompt: Some(self.auto_detect_prompt),
+            trace_mode: Some(self.trace_mode),
+            retention: Some(RetentionOverrides {
+                snapshot_history: Some(self.retention.snapshot_history),
+                command_history: Some(self.retention.command_history),
+            }),
+            color_profile: self.color_profile,
+            cpu_affinity: self.cpu_affinity.clone(),
+            // Ports do not carry over to another instance
+            preferred_port: None,
+            input_profile: Some(self.input_profile),
+            group_id: self.group_id.clone(),
+            tags: (!self.tags.is_empty()).then(|| self.tags.clone().into_iter().collect()),
+            cols: self.cols,
+            rows: self.rows,
+            output_webhook_url: self.output_webhook_url.clone(),
+            output_webhook_interval_ms: self.output_webhook_interval_ms,
+            read_only: Some(self.read_only),
+            tunnel_name: self.tunnel_name.clone(),
+            timeout_ms: None,
+        }
+    }
+}
+
+/// Everything needed to recreate a session on another server instance
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MigrationBundle {
+    /// Id of the session on the exporting instance
+    pub session_id: String,
+    pub config: SessionConfig,
+    /// Terminal contents at export time
+    pub snapshot: String,
+    /// Commands run through `ht_execute_command`, oldest first
+    pub history: Vec<String>,
+    /// Unix timestamp (seconds) of the export
+    pub exported_at: u64,
+}
+
+/// Context a migrated session inherited from the session it replaces
+#[derive(Debug, Clone)]
+pub struct MigratedContext {
+    pub source_session_id: String,
+    /// The source session's terminal contents when it was exported
+    pub snapshot: String,
+    pub exported_at: u64,
+}
@@ -1,5 +1,57 @@
+pub mod aliases;
+pub mod annotations;
+pub mod assertions;
+pub mod baseline;
+pub mod cgroup;
 pub mod command_bridge;
+pub mod command_class;
+pub mod completion;
+pub mod connection_guard;
+pub mod control_sequences;
+pub mod cpu_affinity;
+pub mod dockerfile;
+pub mod drain;
 pub mod event_handler;
+pub mod event_loop;
+pub mod events;
+pub mod exit;
+pub mod flood;
+pub mod history_download;
+pub mod input_profile;
+pub mod interaction_lock;
+pub mod interactive_prompt;
+pub mod keys;
+pub mod lease;
+pub mod metrics_history;
+pub mod migration;
+pub mod output_webhook;
+pub mod port_reservation;
+pub mod process;
+pub mod provenance;
+pub mod pty;
+pub mod recording;
+pub mod renderer;
+pub mod retention;
+pub mod rollback;
+pub mod run_as;
+pub mod screen_classifier;
+pub mod session_handle;
 pub mod session_manager;
+pub mod snapshot;
+pub mod snapshot_history;
+pub mod startup;
+pub mod state;
+pub mod storage;
+pub mod styled_snapshot;
+pub mod summary;
+pub mod tail_file;
+pub mod temp_dir;
+pub mod token_estimate;
+pub mod tool_probe;
+pub mod type_file;
+pub mod viewer_input;
+pub mod warm_pool;
+pub mod web_listener;
+pub mod web_supervisor;
 
 pub use session_manager::SessionManager;
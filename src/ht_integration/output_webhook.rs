@@ -0,0 +1,369 @@
+//! Session output posted to a webhook, for `outputWebhookUrl` on
+//! `ht_create_session`.
+//!
+//! The session's output lines, with control sequences stripped, are
+//! gathered as they arrive and posted every `outputWebhookIntervalMs` as
+//! `{"sessionId", "lines", "timestamp", "skippedChunks", "droppedLines"}`,
+//! the timestamp in milliseconds since the epoch. Intervals without output
+//! post nothing. A failed post is retried once, then its lines are dropped;
+//! the webhook stops once the session's output ends, after posting what is
+//! left.
+//!
+//! Posting happens in its own task, so a slow webhook never stops output
+//! from being read. Lines gathered while a post is in flight go out in the
+//! next one. Output the webhook fell too far behind to read is counted in
+//! `skippedChunks`, and lines dropped for `MAX_PENDING_LINES` in
+//! `droppedLines`, both since the last post.
+
+use crate::error::{HtMcpError, Result};
+use crate::ht_integration::control_sequences;
+use crate::mcp::types::CreateSessionArgs;
+use bytes::Bytes;
+use serde_json::json;
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc;
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, warn};
+
+/// How often lines are posted unless `outputWebhookIntervalMs` says otherwise
+pub const DEFAULT_INTERVAL_MS: u64 = 1000;
+
+/// Shortest and longest `outputWebhookIntervalMs`
+pub const MIN_INTERVAL_MS: u64 = 100;
+pub const MAX_INTERVAL_MS: u64 = 60_000;
+
+/// Lines waiting to be posted; older ones are dropped while the webhook
+/// cannot keep up
+const MAX_PENDING_LINES: usize = 10_000;
+
+/// How long one post may take
+const POST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where and how often a session posts its output
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputWebhook {
+    pub url: String,
+    pub interval: Duration,
+}
+
+impl OutputWebhook {
+    /// The webhook asked for by `args`, if any
+    pub fn from_args(args: &CreateSessionArgs) -> Result<Option<Self>> {
+        let Some(url) = &args.output_webhook_url else {
+            if args.output_webhook_interval_ms.is_some() {
+                return Err(HtMcpError::InvalidRequest(
+                    "outputWebhookIntervalMs needs outputWebhookUrl".to_string(),
+                ));
+            }
+            return Ok(None);
+        };
+        match reqwest::Url::parse(url) {
+            Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => {}
+            _ => {
+                return Err(HtMcpError::InvalidRequest(format!(
+                    "outputWebhookUrl must be an http or https URL, got '{}'",
+                    url
+                )))
+            }
+        }
+        let interval_ms = args
+            .output_webhook_interval_ms
+            .unwrap_or(DEFAULT_INTERVAL_MS);
+        if !(MIN_INTERVAL_MS..=MAX_INTERVAL_MS).contains(&interval_ms) {
+            return Err(HtMcpError::InvalidRequest(format!(
+                "outputWebhookIntervalMs must be between {} and {}, got {}",
+                MIN_INTERVAL_MS, MAX_INTERVAL_MS, interval_ms
+            )));
+        }
+        Ok(Some(Self {
+            url: url.clone(),
+            interval: Duration::from_millis(interval_ms),
+        }))
+    }
+
+    /// Posts the lines of `output` until it ends
+    pub fn spawn(self, session_id: String, mut output: broadcast::Receiver<Bytes>) {
+        let client = match reqwest::Client::builder().timeout(POST_TIMEOUT).build() {
+            Ok(client) => client,
+            Err(e) => {
+                warn!(
+                    "Output webhook for session {} not started: {}",
+                    session_id, e
+                );
+                return;
+            }
+        };
+        // One batch in flight at a time; the rest wait in `lines`
+        let (batch_tx, mut batch_rx) = mpsc::channel::<Batch>(1);
+        let interval = self.interval;
+        let poster_session = session_id.clone();
+        tokio::spawn(async move {
+            while let Some(batch) = batch_rx.recv().await {
+                self.post(&client, &poster_session, batch).await;
+            }
+        });
+        tokio::spawn(async move {
+            let mut lines = OutputLines::default();
+            let mut ticks = tokio::time::interval(interval);
+            ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                tokio::select! {
+                    received = output.recv() => match received {
+                        Ok(chunk) => lines.push(&chunk),
+                        Err(RecvError::Lagged(skipped)) => {
+                            warn!(
+                                "Output webhook for session {} skipped {} chunks",
+                                session_id, skipped
+                            );
+                            lines.skipped_chunks += skipped;
+                        }
+                        Err(RecvError::Closed) => break,
+                    },
+                    _ = ticks.tick() => {
+                        // While the last post is still going, keep gathering
+                        if let Ok(permit) = batch_tx.try_reserve() {
+                            if let Some(batch) = lines.take() {
+                                permit.send(batch);
+                            }
+                        }
+                    }
+                }
+            }
+            lines.finish();
+            if let Some(batch) = lines.take() {
+                let _ = batch_tx.send(batch).await;
+            }
+        });
+    }
+
+    /// Posts one batch, retrying once
+    async fn post(&self, client: &reqwest::Client, session_id: &str, batch: Batch) {
+        if batch.dropped_lines > 0 {
+            warn!(
+                "Output webhook for session {} fell behind and dropped {} lines",
+                session_id, batch.dropped_lines
+            );
+        }
+        let body = json!({
+            "sessionId": session_id,
+            "lines": batch.lines,
+            "timestamp": chrono::Utc::now().timestamp_millis(),
+            "skippedChunks": batch.skipped_chunks,
+            "droppedLines": batch.dropped_lines,
+        });
+        for attempt in 1..=2 {
+            let sent = client
+                .post(&self.url)
+                .json(&body)
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status);
+            match sent {
+                Ok(_) => return,
+                Err(e) if attempt == 1 => {
+                    debug!(
+                        "Output webhook for session {} failed, retrying: {}",
+                        session_id, e
+                    );
+                }
+                Err(e) => warn!(
+                    "Output webhook for session {} dropped {} lines: {}",
+                    session_id,
+                    batch.lines.len(),
+                    e
+                ),
+            }
+        }
+    }
+}
+
+/// One post's worth of output
+#[derive(Debug, PartialEq, Eq)]
+struct Batch {
+    lines: Vec<String>,
+    skipped_chunks: u64,
+    dropped_lines: u64,
+}
+
+/// Output lines not posted yet
+#[derive(Debug, Default)]
+struct OutputLines {
+    pending: VecDeque<String>,
+    /// Output after the last line end
+    partial: String,
+    /// Chunks that went by unread, since the last batch
+    skipped_chunks: u64,
+    /// Lines dropped for `MAX_PENDING_LINES`, since the last batch
+    dropped_lines: u64,
+}
+
+impl OutputLines {
+    /// Adds PTY output. Blank lines are skipped.
+    fn push(&mut self, chunk: &[u8]) {
+        self.partial.push_str(&String::from_utf8_lossy(chunk));
+        while let Some(end) = self.partial.find('\n') {
+            let line: String = self.partial.drain(..=end).collect();
+            self.add(&line);
+        }
+    }
+
+    /// Takes output left without a line end as a last line
+    fn finish(&mut self) {
+        let rest = std::mem::take(&mut self.partial);
+        self.add(&rest);
+    }
+
+    fn add(&mut self, line: &str) {
+        let line = control_sequences::strip(line).trim_end().to_string();
+        if line.is_empty() {
+            return;
+        }
+        self.pending.push_back(line);
+        if self.pending.len() > MAX_PENDING_LINES {
+            self.pending.pop_front();
+            self.dropped_lines += 1;
+        }
+    }
+
+    /// Everything gathered since the last batch, unless there is nothing
+    /// to report
+    fn take(&mut self) -> Option<Batch> {
+        if self.pending.is_empty() && self.skipped_chunks == 0 && self.dropped_lines == 0 {
+            return None;
+        }
+        Some(Batch {
+            lines: self.pending.drain(..).collect(),
+            skipped_chunks: std::mem::take(&mut self.skipped_chunks),
+            dropped_lines: std::mem::take(&mut self.dropped_lines),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::State;
+    use axum::http::StatusCode;
+    use axum::routing::post;
+    use axum::{Json, Router};
+    use serde_json::Value;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    fn args(url: Option<&str>, interval_ms: Option<u64>) -> CreateSessionArgs {
+        CreateSessionArgs {
+            output_webhook_url: url.map(str::to_string),
+            output_webhook_interval_ms: interval_ms,
+            ..CreateSessionArgs::default()
+        }
+    }
+
+    #[test]
+    fn test_from_args() {
+        assert_eq!(OutputWebhook::from_args(&args(None, None)).unwrap(), None);
+        let webhook = OutputWebhook::from_args(&args(Some("http://127.0.0.1:9/hook"), None))
+            .unwrap()
+            .unwrap();
+        assert_eq!(webhook.interval, Duration::from_millis(DEFAULT_INTERVAL_MS));
+
+        for bad in [
+            args(None, Some(500)),
+            args(Some("ftp://example.com"), None),
+            args(Some("not a url"), None),
+            args(Some("https://example.com"), Some(10)),
+        ] {
+            let err = OutputWebhook::from_args(&bad).unwrap_err();
+            assert!(matches!(err, HtMcpError::InvalidRequest(_)), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_output_lines() {
+        let mut lines = OutputLines::default();
+        lines.push(b"\x1b[32mok\x1b[0m\r\n\r\npart");
+        assert_eq!(lines.take().unwrap().lines, ["ok"]);
+        lines.push(b"ial\r\nlast");
+        lines.finish();
+        assert_eq!(lines.take().unwrap().lines, ["partial", "last"]);
+        assert_eq!(lines.take(), None);
+
+        // Losses are reported even without lines to post
+        lines.skipped_chunks = 3;
+        for i in 0..=MAX_PENDING_LINES {
+            lines.add(&i.to_string());
+        }
+        let batch = lines.take().unwrap();
+        assert_eq!(batch.lines.len(), MAX_PENDING_LINES);
+        assert_eq!(batch.lines[0], "1");
+        assert_eq!((batch.skipped_chunks, batch.dropped_lines), (3, 1));
+        lines.skipped_chunks = 2;
+        assert_eq!(
+            lines.take(),
+            Some(Batch {
+                lines: Vec::new(),
+                skipped_chunks: 2,
+                dropped_lines: 0,
+            })
+        );
+    }
+
+    #[derive(Clone, Default)]
+    struct Receiver {
+        bodies: Arc<Mutex<Vec<Value>>>,
+        /// Requests to fail before accepting any
+        failures: Arc<AtomicUsize>,
+    }
+
+    async fn receive(State(receiver): State<Receiver>, Json(body): Json<Value>) -> StatusCode {
+        let failing = receiver
+            .failures
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok();
+        if failing {
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+        receiver.bodies.lock().unwrap().push(body);
+        StatusCode::NO_CONTENT
+    }
+
+    #[tokio::test]
+    async fn test_posts_batches_and_retries_once() {
+        let receiver = Receiver::default();
+        receiver.failures.store(1, Ordering::SeqCst);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let app = Router::new()
+            .route("/hook", post(receive))
+            .with_state(receiver.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let (output_tx, output_rx) = broadcast::channel(16);
+        let webhook = OutputWebhook {
+            url,
+            interval: Duration::from_millis(MIN_INTERVAL_MS),
+        };
+        webhook.spawn("session".to_string(), output_rx);
+        output_tx.send(Bytes::from("one\r\ntwo\r\n")).unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        // Ending the output posts the unfinished line
+        output_tx.send(Bytes::from("three")).unwrap();
+        drop(output_tx);
+
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+        while receiver.bodies.lock().unwrap().len() < 2 {
+            assert!(tokio::time::Instant::now() < deadline, "webhook not called");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let bodies = receiver.bodies.lock().unwrap();
+        // The first post failed and was retried
+        assert_eq!(receiver.failures.load(Ordering::SeqCst), 0);
+        assert_eq!(bodies[0]["sessionId"], "session");
+        assert_eq!(bodies[0]["lines"], json!(["one", "two"]));
+        assert!(bodies[0]["timestamp"].as_i64().unwrap() > 0);
+        assert_eq!(bodies[0]["skippedChunks"], 0);
+        assert_eq!(bodies[0]["droppedLines"], 0);
+        assert_eq!(bodies[1]["lines"], json!(["three"]));
+    }
+}
@@ -0,0 +1,98 @@
+//! Web server ports held for sessions that are not created yet.
+//!
+//! `find_available_port` probes a port by binding and releasing it, so two
+//! creations running close together can settle on the same port. A port
+//! reserved with `ht_reserve_port` stays bound until a session created with
+//! `preferredPort` takes the listeners over, or the reservation expires.
+
+use std::collections::HashMap;
+use std::net::TcpListener;
+use std::time::{Duration, Instant};
+
+/// How long a reservation lasts when `ttlMs` is not given
+pub const DEFAULT_RESERVATION_TTL: Duration = Duration::from_secs(30);
+
+/// Longest `ttlMs` accepted
+pub const MAX_RESERVATION_TTL: Duration = Duration::from_secs(600);
+
+/// Reservations held at once
+pub const MAX_RESERVATIONS: usize = 32;
+
+/// Reserved port -> its bound listeners and when the reservation expires
+#[derive(Debug, Default)]
+pub struct PortReservations {
+    reserved: HashMap<u16, (Vec<TcpListener>, Instant)>,
+}
+
+impl PortReservations {
+    /// Holds `listeners`, bound on `port`, for `ttl`
+    pub fn insert(&mut self, port: u16, listeners: Vec<TcpListener>, ttl: Duration) {
+        self.reserved
+            .insert(port, (listeners, Instant::now() + ttl));
+    }
+
+    /// The listeners of an unexpired reservation of `port`, ending it
+    pub fn take(&mut self, port: u16) -> Option<Vec<TcpListener>> {
+        self.purge_expired();
+        self.reserved.remove(&port).map(|(listeners, _)| listeners)
+    }
+
+    pub fn contains(&self, port: u16) -> bool {
+        self.reserved.contains_key(&port)
+    }
+
+    pub fn len(&self) -> usize {
+        self.reserved.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.reserved.is_empty()
+    }
+
+    /// Releases the ports of expired reservations; returns how many
+    pub fn purge_expired(&mut self) -> usize {
+        let now = Instant::now();
+        let before = self.reserved.len();
+        self.reserved.retain(|_, (_, expires_at)| *expires_at > now);
+        before - self.reserved.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bind() -> (u16, Vec<TcpListener>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        (listener.local_addr().unwrap().port(), vec![listener])
+    }
+
+    #[test]
+    fn test_take_hands_over_the_listeners() {
+        let mut reservations = PortReservations::default();
+        let (port, listeners) = bind();
+        reservations.insert(port, listeners, DEFAULT_RESERVATION_TTL);
+        assert!(reservations.contains(port));
+        // Still bound while reserved
+        assert!(TcpListener::bind(("127.0.0.1", port)).is_err());
+
+        let listeners = reservations.take(port).unwrap();
+        assert_eq!(listeners[0].local_addr().unwrap().port(), port);
+        assert!(reservations.take(port).is_none());
+        assert_eq!(reservations.len(), 0);
+    }
+
+    #[test]
+    fn test_expired_reservations_release_their_port() {
+        let mut reservations = PortReservations::default();
+        let (port, listeners) = bind();
+        reservations.insert(port, listeners, Duration::ZERO);
+        let (kept, listeners) = bind();
+        reservations.insert(kept, listeners, DEFAULT_RESERVATION_TTL);
+
+        assert!(reservations.take(port).is_none());
+        assert!(TcpListener::bind(("127.0.0.1", port)).is_ok());
+        assert_eq!(reservations.purge_expired(), 0);
+        assert!(reservations.contains(kept));
+    }
+}
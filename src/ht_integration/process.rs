@@ -0,0 +1,152 @@
+//! Resource usage of session processes, for `ht_resource_report`.
+//!
+//! Linux reads `/proc/<pid>/stat`; macOS asks the `sysinfo` crate. Elsewhere
+//! nothing is reported. CPU usage is the share of one core used since the
+//! previous sample of the same process, so it can exceed 100 for
+//! multithreaded programs.
+
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+/// How long an `ht_resource_report` result is reused before the processes
+/// are sampled again
+pub const REPORT_CACHE_TTL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProcessUsage {
+    /// Resident set size in KiB
+    pub rss_kb: u64,
+    pub cpu_percent: f64,
+}
+
+/// Keeps what is needed between samples to turn CPU time into a percentage
+#[derive(Debug, Default)]
+pub struct ProcessSampler {
+    /// CPU time of each process at its last sample
+    #[cfg(target_os = "linux")]
+    previous: std::collections::HashMap<u32, (Duration, Instant)>,
+    #[cfg(target_os = "macos")]
+    system: sysinfo::System,
+}
+
+impl ProcessSampler {
+    /// Usage of `pid`, or `None` if it is gone or the platform is not
+    /// supported. The first sample of a process averages over the time since
+    /// `running_since`.
+    #[cfg(target_os = "linux")]
+    pub fn sample(&mut self, pid: u32, running_since: Instant) -> Option<ProcessUsage> {
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+        let (cpu_time, rss_kb) = parse_stat(&stat, clock_ticks_per_sec(), page_size())?;
+
+        let now = Instant::now();
+        let (cpu_before, since) = self
+            .previous
+            .insert(pid, (cpu_time, now))
+            .unwrap_or((Duration::ZERO, running_since));
+        let wall = now.saturating_duration_since(since);
+        let cpu_percent = if wall.is_zero() {
+            0.0
+        } else {
+            cpu_time.saturating_sub(cpu_before).as_secs_f64() / wall.as_secs_f64() * 100.0
+        };
+        Some(ProcessUsage {
+            rss_kb,
+            cpu_percent,
+        })
+    }
+
+    #[cfg(target_os = "macos")]
+    pub fn sample(&mut self, pid: u32, _running_since: Instant) -> Option<ProcessUsage> {
+        use sysinfo::{Pid, ProcessRefreshKind};
+
+        // sysinfo keeps the previous CPU time itself; the first refresh of a
+        // process reports 0%
+        let pid = Pid::from_u32(pid);
+        let refresh = ProcessRefreshKind::new().with_cpu().with_memory();
+        if !self.system.refresh_process_specifics(pid, refresh) {
+            return None;
+        }
+        let process = self.system.process(pid)?;
+        Some(ProcessUsage {
+            rss_kb: process.memory() / 1024,
+            cpu_percent: f64::from(process.cpu_usage()),
+        })
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    pub fn sample(&mut self, _pid: u32, _running_since: Instant) -> Option<ProcessUsage> {
+        None
+    }
+
+    /// Forgets processes other than `pids`, such as those of closed sessions
+    pub fn retain(&mut self, pids: &HashSet<u32>) {
+        #[cfg(target_os = "linux")]
+        self.previous.retain(|pid, _| pids.contains(pid));
+        #[cfg(not(target_os = "linux"))]
+        let _ = pids;
+    }
+}
+
+/// CPU time (user + system) and RSS in KiB from the contents of
+/// `/proc/<pid>/stat`
+#[cfg(target_os = "linux")]
+fn parse_stat(stat: &str, ticks_per_sec: u64, page_size: u64) -> Option<(Duration, u64)> {
+    // The command name (field 2) is in parentheses and may itself contain
+    // spaces and parentheses, so fields are counted from the last `)`
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    // fields[0] is field 3 (state); utime is field 14, stime 15, rss 24
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+    let rss_pages: u64 = fields.get(21)?.parse().ok()?;
+
+    let cpu_time = Duration::from_secs_f64((utime + stime) as f64 / ticks_per_sec as f64);
+    Some((cpu_time, rss_pages * page_size / 1024))
+}
+
+#[cfg(target_os = "linux")]
+fn clock_ticks_per_sec() -> u64 {
+    use nix::unistd::{sysconf, SysconfVar};
+    match sysconf(SysconfVar::CLK_TCK) {
+        Ok(Some(ticks)) if ticks > 0 => ticks as u64,
+        _ => 100,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn page_size() -> u64 {
+    use nix::unistd::{sysconf, SysconfVar};
+    match sysconf(SysconfVar::PAGE_SIZE) {
+        Ok(Some(size)) if size > 0 => size as u64,
+        _ => 4096,
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_stat() {
+        let stat = "4242 (my (odd) cmd) S 1 4242 4242 34816 4242 4194304 500 0 0 0 \
+                    250 50 0 0 20 0 1 0 12345 10000000 300 18446744073709551615";
+        let (cpu_time, rss_kb) = parse_stat(stat, 100, 4096).unwrap();
+        assert_eq!(cpu_time, Duration::from_secs(3));
+        assert_eq!(rss_kb, 1200);
+
+        assert!(parse_stat("4242 (sh) S 1", 100, 4096).is_none());
+    }
+
+    #[test]
+    fn test_sample_own_process() {
+        let mut sampler = ProcessSampler::default();
+        let usage = sampler
+            .sample(std::process::id(), Instant::now() - Duration::from_secs(1))
+            .unwrap();
+        assert!(usage.rss_kb > 0);
+        assert!(usage.cpu_percent >= 0.0);
+
+        assert!(sampler.sample(u32::MAX, Instant::now()).is_none());
+        sampler.retain(&HashSet::new());
+        assert!(sampler.previous.is_empty());
+    }
+}
@@ -0,0 +1,199 @@
+//! Provenance of snapshots shared outside the session, for
+//! `includeProvenance` and `ht_verify_snapshot`.
+//!
+//! A provenance block names the session, host, command, time and screen
+//! version a snapshot came from, with a hash of its content. The hash is
+//! over the plain text handed out, after any reformatting the call asked
+//! for, which the block lists in `transforms`. That text is normalized so
+//! that copying it around does not change it: trailing whitespace is
+//! dropped from every line, blank lines at the end are dropped, and lines
+//! are joined with `\n`. The hash is the first [`HASH_LEN`] hex digits of
+//! the SHA-256 of the result.
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Hex digits of the SHA-256 kept in a provenance hash
+pub const HASH_LEN: usize = 16;
+
+/// Provenance blocks remembered for `ht_verify_snapshot`; the oldest is
+/// forgotten first
+pub const PROVENANCE_LOG_CAPACITY: usize = 1000;
+
+/// The text a provenance hash is computed over
+pub fn normalize(text: &str) -> String {
+    let lines: Vec<&str> = text.lines().map(str::trim_end).collect();
+    let end = lines
+        .iter()
+        .rposition(|line| !line.is_empty())
+        .map_or(0, |last| last + 1);
+    lines[..end].join("\n")
+}
+
+/// The provenance hash of `text`
+pub fn content_hash(text: &str) -> String {
+    let digest = Sha256::digest(normalize(text).as_bytes());
+    let mut hash: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+    hash.truncate(HASH_LEN);
+    hash
+}
+
+/// Where a snapshot came from
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Provenance {
+    pub session_id: String,
+    pub host: String,
+    pub command: Vec<String>,
+    /// When the snapshot was taken, RFC 3339 in UTC
+    pub timestamp: String,
+    pub screen_version: u64,
+    pub hash: String,
+    /// Reformatting applied to the screen before hashing, e.g.
+    /// `wordWrap=80`; empty for the screen as captured
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub transforms: Vec<String>,
+}
+
+impl Provenance {
+    /// Provenance of `text`, taken from the session now
+    pub fn new(session_id: &str, command: &[String], screen_version: u64, text: &str) -> Self {
+        Self {
+            session_id: session_id.to_string(),
+            host: host_name(),
+            command: command.to_vec(),
+            timestamp: timestamp(SystemTime::now()),
+            screen_version,
+            hash: content_hash(text),
+            transforms: Vec::new(),
+        }
+    }
+
+    /// One line for the footer of a snapshot
+    pub fn footer(&self) -> String {
+        format!(
+            "ht-mcp session {} on {} at {}, screen version {}, sha256:{}",
+            self.session_id, self.host, self.timestamp, self.screen_version, self.hash
+        )
+    }
+}
+
+pub fn timestamp(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// The machine's host name, or `unknown`
+pub fn host_name() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .map(|name| name.trim().to_string())
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .or_else(|| std::env::var("COMPUTERNAME").ok())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Whether `hash` looks like a provenance hash
+pub fn is_valid_hash(hash: &str) -> bool {
+    hash.len() == HASH_LEN && hash.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+/// The provenance blocks handed out, newest last
+#[derive(Debug)]
+pub struct ProvenanceLog {
+    capacity: usize,
+    issued: Mutex<VecDeque<Provenance>>,
+}
+
+impl Default for ProvenanceLog {
+    fn default() -> Self {
+        Self::with_capacity(PROVENANCE_LOG_CAPACITY)
+    }
+}
+
+impl ProvenanceLog {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            capacity,
+            issued: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn record(&self, provenance: Provenance) {
+        let mut issued = self.issued.lock().unwrap();
+        while !issued.is_empty() && issued.len() >= self.capacity {
+            issued.pop_front();
+        }
+        issued.push_back(provenance);
+    }
+
+    /// The blocks handed out with `hash`, oldest first
+    pub fn find(&self, hash: &str) -> Vec<Provenance> {
+        let hash = hash.to_ascii_lowercase();
+        self.issued
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|provenance| provenance.hash == hash)
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_ignores_trailing_whitespace_and_blank_lines() {
+        let hash = content_hash("$ ls\nfile.txt\n");
+        assert_eq!(hash.len(), HASH_LEN);
+        assert!(is_valid_hash(&hash));
+        assert_eq!(content_hash("$ ls   \nfile.txt\n\n\n   \n"), hash);
+        assert_eq!(content_hash("$ ls\r\nfile.txt"), hash);
+        assert_ne!(content_hash("$ ls\nfile.txt.bak"), hash);
+        // Leading whitespace and inner blank lines are content
+        assert_ne!(content_hash(" $ ls\nfile.txt"), hash);
+        assert_ne!(content_hash("$ ls\n\nfile.txt"), hash);
+        // SHA-256 of "abc"
+        assert_eq!(content_hash("abc"), "ba7816bf8f01cfea");
+    }
+
+    #[test]
+    fn test_provenance_block() {
+        let command = vec!["bash".to_string()];
+        let provenance = Provenance::new("s1", &command, 42, "hello  \n\n");
+        assert_eq!(provenance.hash, content_hash("hello"));
+        assert!(provenance.timestamp.ends_with('Z'));
+        assert!(!provenance.host.is_empty());
+
+        let footer = provenance.footer();
+        assert!(footer.contains("session s1"));
+        assert!(footer.contains("screen version 42"));
+        assert!(footer.ends_with(&format!("sha256:{}", provenance.hash)));
+
+        let json = serde_json::to_value(&provenance).unwrap();
+        assert_eq!(json["sessionId"], "s1");
+        assert_eq!(json["screenVersion"], 42);
+        assert_eq!(json["command"], serde_json::json!(["bash"]));
+        assert!(json.get("transforms").is_none());
+    }
+
+    #[test]
+    fn test_log_finds_by_hash_and_forgets_the_oldest() {
+        let log = ProvenanceLog::with_capacity(2);
+        let command = vec!["bash".to_string()];
+        log.record(Provenance::new("a", &command, 1, "one"));
+        log.record(Provenance::new("b", &command, 2, "two"));
+        log.record(Provenance::new("c", &command, 3, "two"));
+
+        assert!(log.find(&content_hash("one")).is_empty());
+        let found = log.find(&content_hash("two").to_uppercase());
+        let sessions: Vec<&str> = found.iter().map(|p| p.session_id.as_str()).collect();
+        assert_eq!(sessions, ["b", "c"]);
+    }
+}
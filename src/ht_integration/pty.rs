@@ -0,0 +1,524 @@
+//! PTY process spawning.
+//!
+//! ht_core's `pty::spawn` does not expose the child process, which we need for
+//! resource controls such as cgroups. On Unix this module mirrors ht_core's
+//! implementation (`/bin/sh -c <command>` under `forkpty`) and additionally
+//! returns a handle to the child. Other platforms delegate to ht_core.
+
+use crate::error::Result;
+use crate::ht_integration::run_as::RunAsUser;
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock};
+use tokio::sync::mpsc;
+
+/// Future driving PTY I/O until the child exits or the input channel closes
+pub type PtyFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+/// The process running inside a session's PTY
+#[derive(Debug, Clone)]
+pub struct PtyChild {
+    /// Process id of the child, when the platform exposes it
+    pub pid: Option<u32>,
+    /// Set once the child exits on its own; 128 + signal number if it was
+    /// killed by a signal
+    exit_code: Arc<OnceLock<i32>>,
+    /// Duplicate of the PTY master, for reading terminal modes
+    #[cfg(unix)]
+    master: Option<Arc<std::os::fd::OwnedFd>>,
+}
+
+impl PtyChild {
+    pub(crate) fn new(pid: Option<u32>) -> Self {
+        Self {
+            pid,
+            exit_code: Arc::new(OnceLock::new()),
+            #[cfg(unix)]
+            master: None,
+        }
+    }
+
+    /// Whether the terminal echoes input, as programs reading passwords
+    /// turn off; `None` where the terminal modes cannot be read
+    pub fn echo_enabled(&self) -> Option<bool> {
+        #[cfg(unix)]
+        {
+            use nix::sys::termios::{tcgetattr, LocalFlags};
+            let master = self.master.as_ref()?;
+            let termios = tcgetattr(master.as_ref()).ok()?;
+            Some(termios.local_flags.contains(LocalFlags::ECHO))
+        }
+        #[cfg(not(unix))]
+        None
+    }
+
+    /// Sets the PTY's window size, which sends SIGWINCH to the program in
+    /// the foreground. A no-op where the PTY master is not available.
+    pub fn resize(&self, cols: u16, rows: u16) -> Result<()> {
+        #[cfg(unix)]
+        if let Some(master) = &self.master {
+            use std::os::fd::AsRawFd;
+            let winsize = nix::libc::winsize {
+                ws_row: rows,
+                ws_col: cols,
+                ws_xpixel: 0,
+                ws_ypixel: 0,
+            };
+            // SAFETY: TIOCSWINSZ only reads the winsize, which outlives the call
+            let result =
+                unsafe { nix::libc::ioctl(master.as_raw_fd(), nix::libc::TIOCSWINSZ, &winsize) };
+            if result == -1 {
+                return Err(crate::error::HtMcpError::Internal(format!(
+                    "Failed to resize the PTY: {}",
+                    std::io::Error::last_os_error()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Exit code of the child, if it has exited without being asked to by
+    /// the server
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code.get().copied()
+    }
+
+    /// Kills the child with SIGKILL and waits for it to exit
+    pub async fn kill(&self) {
+        #[cfg(unix)]
+        if let Some(pid) = self.pid {
+            unix::kill_and_reap(pid).await;
+        }
+    }
+
+    /// Hangs up on the child and what it started in its process group, as
+    /// closing a terminal would, then kills them if the child is still
+    /// around after `grace`. The PTY task reaps the child.
+    pub async fn terminate(&self, grace: std::time::Duration) {
+        #[cfg(unix)]
+        if let Some(pid) = self.pid {
+            unix::hang_up_group(pid, grace).await;
+        }
+        #[cfg(not(unix))]
+        let _ = grace;
+    }
+}
+
+/// How the PTY child is set up before it execs the command
+#[derive(Debug, Clone, Default)]
+pub struct SpawnOptions {
+    /// Switch to this user (and its groups) before exec; Unix only
+    pub run_as: Option<RunAsUser>,
+    /// Extra environment variables for the child
+    pub env: Vec<(String, String)>,
+    /// Directory the child starts in instead of the server's
+    pub cwd: Option<PathBuf>,
+    /// `cgroup.procs` of a cgroup the child joins before exec; Unix only
+    pub cgroup_procs: Option<PathBuf>,
+    /// Cores the child is pinned to before exec; Linux only, ignored
+    /// elsewhere
+    pub cpu_affinity: Option<Vec<usize>>,
+}
+
+/// Spawns `command` in a new PTY of the given size.
+///
+/// Input written to `input_rx` is forwarded to the PTY and everything the
+/// child prints is sent to `output_tx`. The returned future must be polled
+/// to drive that I/O; it resolves once the child has exited.
+#[cfg(unix)]
+pub fn spawn(
+    command: String,
+    cols: u16,
+    rows: u16,
+    options: &SpawnOptions,
+    input_rx: mpsc::Receiver<Vec<u8>>,
+    output_tx: mpsc::Sender<Vec<u8>>,
+) -> Result<(PtyChild, PtyFuture)> {
+    unix::spawn(command, cols, rows, options, input_rx, output_tx)
+}
+
+#[cfg(not(unix))]
+pub fn spawn(
+    command: String,
+    cols: u16,
+    rows: u16,
+    options: &SpawnOptions,
+    input_rx: mpsc::Receiver<Vec<u8>>,
+    output_tx: mpsc::Sender<Vec<u8>>,
+) -> Result<(PtyChild, PtyFuture)> {
+    use crate::error::HtMcpError;
+
+    if options.run_as.is_some() {
+        return Err(HtMcpError::InvalidRequest(
+            "runAs is only supported on Unix".to_string(),
+        ));
+    }
+    if !options.env.is_empty() {
+        return Err(HtMcpError::InvalidRequest(
+            "Setting the session environment is only supported on Unix".to_string(),
+        ));
+    }
+    if options.cwd.is_some() {
+        return Err(HtMcpError::InvalidRequest(
+            "Setting the session working directory is only supported on Unix".to_string(),
+        ));
+    }
+
+    let future = ht_core::pty::spawn(command, create_winsize(cols, rows), input_rx, output_tx)
+        .map_err(|e| HtMcpError::HtLibrary(format!("Failed to spawn PTY: {}", e)))?;
+    let future: PtyFuture = Box::pin(async move {
+        future
+            .await
+            .map_err(|e| HtMcpError::HtLibrary(format!("PTY execution error: {}", e)))
+    });
+    Ok((PtyChild::new(None), future))
+}
+
+/// Creates a Winsize struct with platform-appropriate fields
+/// This function abstracts away platform differences in the Winsize struct
+fn create_winsize(cols: u16, rows: u16) -> ht_core::pty::Winsize {
+    #[cfg(unix)]
+    {
+        ht_core::pty::Winsize {
+            ws_col: cols,
+            ws_row: rows,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        }
+    }
+
+    #[cfg(windows)]
+    {
+        ht_core::pty::Winsize {
+            ws_col: cols,
+            ws_row: rows,
+        }
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use super::{PtyChild, PtyFuture, SpawnOptions};
+    use crate::error::{HtMcpError, Result};
+    use crate::ht_integration::run_as::RunAsUser;
+    use nix::fcntl::{fcntl, FcntlArg, OFlag};
+    use nix::pty::{forkpty, Winsize};
+    use nix::sys::signal::{kill, killpg, signal, SigHandler, Signal};
+    use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+    use nix::unistd::{execve, getegid, geteuid, setgid, setuid, ForkResult, Gid, Pid, Uid};
+    use std::ffi::CString;
+    use std::io;
+    use std::os::fd::{AsRawFd, OwnedFd};
+    use std::os::unix::ffi::OsStrExt;
+    use std::sync::{Arc, OnceLock};
+    use std::time::Duration;
+    use tokio::io::unix::AsyncFd;
+    use tokio::sync::mpsc;
+    use tracing::debug;
+
+    const READ_BUF_SIZE: usize = 128 * 1024;
+    const TERM: &str = "xterm-256color";
+    /// PATH for runAs children, which do not inherit the server's environment
+    const RUN_AS_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+    const HANG_UP_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+    pub fn spawn(
+        command: String,
+        cols: u16,
+        rows: u16,
+        options: &SpawnOptions,
+        input_rx: mpsc::Receiver<Vec<u8>>,
+        output_tx: mpsc::Sender<Vec<u8>>,
+    ) -> Result<(PtyChild, PtyFuture)> {
+        // Everything the child needs is prepared before forking so the child
+        // only has to exec
+        let argv = ["/bin/sh", "-c", command.as_str()]
+            .iter()
+            .map(|arg| CString::new(*arg))
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|e| HtMcpError::InvalidRequest(format!("Invalid command: {}", e)))?;
+        let envp = child_environment(options);
+        let cwd = options
+            .cwd
+            .as_ref()
+            .map(|cwd| CString::new(cwd.as_os_str().as_bytes()))
+            .transpose()
+            .map_err(|e| HtMcpError::InvalidRequest(format!("Invalid cwd: {}", e)))?;
+        let groups: Vec<nix::libc::gid_t> = options
+            .run_as
+            .as_ref()
+            .map(|run_as| run_as.groups.clone())
+            .unwrap_or_default();
+        // Opened before forking, and closed on exec; the child joins the
+        // cgroup by writing to it
+        let cgroup_procs = options
+            .cgroup_procs
+            .as_ref()
+            .map(|path| std::fs::OpenOptions::new().write(true).open(path))
+            .transpose()
+            .map_err(|e| {
+                HtMcpError::Internal(format!("Failed to open the session cgroup: {}", e))
+            })?;
+        #[cfg(target_os = "linux")]
+        let cpu_set = options
+            .cpu_affinity
+            .as_deref()
+            .map(crate::ht_integration::cpu_affinity::cpu_set)
+            .transpose()?;
+
+        let winsize = Winsize {
+            ws_col: cols,
+            ws_row: rows,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+
+        let result = unsafe { forkpty(Some(&winsize), None) }
+            .map_err(|e| HtMcpError::HtLibrary(format!("Failed to fork PTY: {}", e)))?;
+
+        match result.fork_result {
+            ForkResult::Parent { child } => {
+                let mut pty_child = PtyChild::new(Some(child.as_raw() as u32));
+                pty_child.master = result.master.try_clone().ok().map(Arc::new);
+                let future: PtyFuture = Box::pin(drive_child(
+                    child,
+                    result.master,
+                    input_rx,
+                    output_tx,
+                    pty_child.exit_code.clone(),
+                ));
+                Ok((pty_child, future))
+            }
+            ForkResult::Child => {
+                unsafe {
+                    let _ = signal(Signal::SIGPIPE, SigHandler::SigDfl);
+                }
+                // Before dropping privileges, which may take the right to
+                // move processes between cgroups
+                if let Some(procs) = &cgroup_procs {
+                    // Writing 0 moves the writing process
+                    let written =
+                        unsafe { nix::libc::write(procs.as_raw_fd(), b"0".as_ptr().cast(), 1) };
+                    if written != 1 {
+                        let msg = b"ht-mcp: failed to join the session cgroup\r\n";
+                        unsafe {
+                            nix::libc::write(2, msg.as_ptr().cast(), msg.len());
+                            nix::libc::_exit(126)
+                        }
+                    }
+                }
+                #[cfg(target_os = "linux")]
+                if let Some(set) = &cpu_set {
+                    if crate::ht_integration::cpu_affinity::pin_self(set).is_err() {
+                        let msg = b"ht-mcp: failed to set the CPU affinity\r\n";
+                        unsafe {
+                            nix::libc::write(2, msg.as_ptr().cast(), msg.len());
+                            nix::libc::_exit(126)
+                        }
+                    }
+                }
+                if let Some(run_as) = &options.run_as {
+                    if drop_privileges(run_as, &groups).is_err() {
+                        // Only async-signal-safe calls are allowed here, so
+                        // report on the terminal with a raw write
+                        let msg = b"ht-mcp: failed to switch to the runAs user\r\n";
+                        unsafe {
+                            nix::libc::write(2, msg.as_ptr().cast(), msg.len());
+                            nix::libc::_exit(126)
+                        }
+                    }
+                }
+                if let Some(cwd) = &cwd {
+                    if unsafe { nix::libc::chdir(cwd.as_ptr()) } != 0 {
+                        let msg = b"ht-mcp: failed to change to the session cwd\r\n";
+                        unsafe {
+                            nix::libc::write(2, msg.as_ptr().cast(), msg.len());
+                            nix::libc::_exit(126)
+                        }
+                    }
+                }
+                let _ = execve(&argv[0], &argv, &envp);
+                unsafe { nix::libc::_exit(127) }
+            }
+        }
+    }
+
+    pub async fn kill_and_reap(pid: u32) {
+        let pid = Pid::from_raw(pid as i32);
+        let _ = kill(pid, Signal::SIGKILL);
+        let _ = tokio::task::spawn_blocking(move || waitpid(pid, None)).await;
+    }
+
+    /// Sends SIGHUP to the child, a session and process group leader since
+    /// `forkpty`, and its group; SIGKILL to the group if the child has not
+    /// been reaped within `grace`. Until then the pid cannot be reused.
+    pub async fn hang_up_group(pid: u32, grace: Duration) {
+        let pid = Pid::from_raw(pid as i32);
+        if kill(pid, None).is_err() {
+            return;
+        }
+        debug!("Sending SIGHUP to PTY child {} and its group", pid);
+        let _ = killpg(pid, Signal::SIGHUP);
+        let _ = kill(pid, Signal::SIGHUP);
+
+        let deadline = tokio::time::Instant::now() + grace;
+        while tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(HANG_UP_POLL_INTERVAL).await;
+            if kill(pid, None).is_err() {
+                return;
+            }
+        }
+        debug!("PTY child {} survived SIGHUP; sending SIGKILL", pid);
+        let _ = killpg(pid, Signal::SIGKILL);
+        let _ = kill(pid, Signal::SIGKILL);
+    }
+
+    /// Sets supplementary groups, then gid, then uid; the order matters since
+    /// only root may change groups. Nothing changes when the server already
+    /// runs as the user and group, so that needs no root.
+    fn drop_privileges(run_as: &RunAsUser, groups: &[nix::libc::gid_t]) -> nix::Result<()> {
+        if run_as.is_identity(geteuid().as_raw(), getegid().as_raw()) {
+            return Ok(());
+        }
+        // SAFETY: `groups` is a valid slice for the duration of the call
+        let res = unsafe { nix::libc::setgroups(groups.len() as _, groups.as_ptr()) };
+        nix::errno::Errno::result(res)?;
+        setgid(Gid::from_raw(run_as.gid))?;
+        setuid(Uid::from_raw(run_as.uid))
+    }
+
+    /// TERM set for the emulated terminal and the session's extra variables
+    /// on top of the server's environment, or for a runAs child, on top of a
+    /// minimal login environment of the target user instead, so it does not
+    /// see the server's secrets. Later overrides replace earlier ones, so the
+    /// session's variables win.
+    fn child_environment(options: &SpawnOptions) -> Vec<CString> {
+        let mut overrides = vec![("TERM".to_string(), TERM.to_string())];
+        if let Some(run_as) = &options.run_as {
+            overrides.push(("HOME".to_string(), run_as.home.display().to_string()));
+            overrides.push(("USER".to_string(), run_as.user.clone()));
+            overrides.push(("LOGNAME".to_string(), run_as.user.clone()));
+            overrides.push(("SHELL".to_string(), run_as.shell.display().to_string()));
+            overrides.push(("PATH".to_string(), RUN_AS_PATH.to_string()));
+        }
+        for (name, value) in &options.env {
+            overrides.retain(|(existing, _)| existing != name);
+            overrides.push((name.clone(), value.clone()));
+        }
+
+        let inherited = match options.run_as {
+            Some(_) => Vec::new(),
+            None => std::env::vars_os().collect(),
+        };
+        inherited
+            .into_iter()
+            .filter(|(key, _)| !overrides.iter().any(|(name, _)| key == name.as_str()))
+            .filter_map(|(key, value)| {
+                let mut entry = key.as_bytes().to_vec();
+                entry.push(b'=');
+                entry.extend_from_slice(value.as_bytes());
+                CString::new(entry).ok()
+            })
+            .chain(
+                overrides
+                    .iter()
+                    .filter_map(|(key, value)| CString::new(format!("{}={}", key, value)).ok()),
+            )
+            .collect()
+    }
+
+    async fn drive_child(
+        child: Pid,
+        master: OwnedFd,
+        input_rx: mpsc::Receiver<Vec<u8>>,
+        output_tx: mpsc::Sender<Vec<u8>>,
+        exit_code: Arc<OnceLock<i32>>,
+    ) -> Result<()> {
+        let result = do_drive_child(master, input_rx, output_tx).await;
+
+        // Reap the child, hanging up first if it is still running. Death by a
+        // signal after our hang-up is not the command's own exit status.
+        let mut hung_up = false;
+        let status = match waitpid(child, Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::StillAlive) => {
+                debug!("Sending SIGHUP to PTY child {}", child);
+                let _ = kill(child, Signal::SIGHUP);
+                hung_up = true;
+                tokio::task::spawn_blocking(move || waitpid(child, None))
+                    .await
+                    .ok()
+                    .and_then(|status| status.ok())
+            }
+            status => status.ok(),
+        };
+        match status {
+            Some(WaitStatus::Exited(_, code)) => {
+                let _ = exit_code.set(code);
+            }
+            Some(WaitStatus::Signaled(_, signal, _)) if !hung_up => {
+                let _ = exit_code.set(128 + signal as i32);
+            }
+            _ => {}
+        }
+
+        result.map_err(|e| HtMcpError::HtLibrary(format!("PTY I/O error: {}", e)))
+    }
+
+    async fn do_drive_child(
+        master: OwnedFd,
+        mut input_rx: mpsc::Receiver<Vec<u8>>,
+        output_tx: mpsc::Sender<Vec<u8>>,
+    ) -> io::Result<()> {
+        fcntl(master.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+        let master = AsyncFd::new(master)?;
+        let mut buf = vec![0u8; READ_BUF_SIZE];
+        let mut input: Vec<u8> = Vec::with_capacity(READ_BUF_SIZE);
+
+        loop {
+            tokio::select! {
+                data = input_rx.recv() => {
+                    match data {
+                        Some(data) => input.extend_from_slice(&data),
+                        None => return Ok(()),
+                    }
+                }
+
+                guard = master.readable() => {
+                    let mut guard = guard?;
+                    match guard.try_io(|fd| read(fd.get_ref(), &mut buf)) {
+                        Ok(Ok(0)) => return Ok(()),
+                        Ok(Ok(n)) => {
+                            if output_tx.send(buf[..n].to_vec()).await.is_err() {
+                                return Ok(());
+                            }
+                        }
+                        // Linux reports EIO once the child side of the PTY is closed
+                        Ok(Err(e)) if e.raw_os_error() == Some(nix::libc::EIO) => return Ok(()),
+                        Ok(Err(e)) => return Err(e),
+                        Err(_would_block) => continue,
+                    }
+                }
+
+                guard = master.writable(), if !input.is_empty() => {
+                    let mut guard = guard?;
+                    match guard.try_io(|fd| write(fd.get_ref(), &input)) {
+                        Ok(Ok(n)) => {
+                            input.drain(..n);
+                        }
+                        Ok(Err(e)) => return Err(e),
+                        Err(_would_block) => continue,
+                    }
+                }
+            }
+        }
+    }
+
+    fn read(fd: &OwnedFd, buf: &mut [u8]) -> io::Result<usize> {
+        nix::unistd::read(fd.as_raw_fd(), buf).map_err(io::Error::from)
+    }
+
+    fn write(fd: &OwnedFd, buf: &[u8]) -> io::Result<usize> {
+        nix::unistd::write(fd, buf).map_err(io::Error::from)
+    }
+}
@@ -0,0 +1,185 @@
+//! Session output recorded to disk, when the config's
+//! `storage.recordings_dir` is set.
+//!
+//! Every session gets `<recordings_dir>/<session id>.cast` in asciicast v2:
+//! a header line with the terminal size, then one `[seconds, "o", text]`
+//! line per chunk of output, escape sequences included, as the PTY wrote
+//! it. The writer counts the bytes it writes, and the session manager
+//! reports them to its `StorageManager`, so recordings count toward
+//! `max_recording_bytes` and closed ones are evicted past it.
+
+use crate::error::Result;
+use bytes::Bytes;
+use serde_json::json;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::warn;
+
+/// A session's recording file, written by a task of its own. Clones share
+/// their counts, so bytes are reported once whichever clone reports them.
+#[derive(Debug, Clone)]
+pub struct Recording {
+    pub path: PathBuf,
+    written: Arc<AtomicU64>,
+    /// Bytes already reported to the storage manager
+    reported: Arc<AtomicU64>,
+}
+
+impl Recording {
+    /// Creates `<dir>/<session_id>.cast` with its header and records
+    /// `output` into it until the output ends
+    pub fn start(
+        dir: &Path,
+        session_id: &str,
+        cols: u16,
+        rows: u16,
+        mut output: broadcast::Receiver<Bytes>,
+    ) -> Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(format!("{}.cast", session_id));
+        let mut file = std::fs::File::create(&path)?;
+        let header = format!(
+            "{}\n",
+            json!({
+                "version": 2,
+                "width": cols,
+                "height": rows,
+                "timestamp": chrono::Utc::now().timestamp(),
+            })
+        );
+        file.write_all(header.as_bytes())?;
+        let written = Arc::new(AtomicU64::new(header.len() as u64));
+
+        let mut file = tokio::fs::File::from_std(file);
+        let counter = written.clone();
+        let session_id = session_id.to_string();
+        tokio::spawn(async move {
+            let started = Instant::now();
+            let mut pending = Vec::new();
+            loop {
+                match output.recv().await {
+                    Ok(chunk) => {
+                        pending.extend_from_slice(&chunk);
+                        let Some(line) = event_line(started.elapsed(), &mut pending) else {
+                            continue;
+                        };
+                        if let Err(e) = file.write_all(line.as_bytes()).await {
+                            warn!("Stopped recording session {}: {}", session_id, e);
+                            return;
+                        }
+                        counter.fetch_add(line.len() as u64, Ordering::Relaxed);
+                    }
+                    Err(RecvError::Lagged(skipped)) => warn!(
+                        "Recording of session {} skipped {} chunks",
+                        session_id, skipped
+                    ),
+                    Err(RecvError::Closed) => break,
+                }
+            }
+            let _ = file.flush().await;
+        });
+
+        Ok(Self {
+            path,
+            written,
+            reported: Arc::default(),
+        })
+    }
+
+    /// Bytes written since the last call
+    pub fn take_unreported(&self) -> u64 {
+        let written = self.written.load(Ordering::Relaxed);
+        written - self.reported.swap(written, Ordering::Relaxed)
+    }
+}
+
+/// The output event for what `pending` holds, leaving a character cut off
+/// at its end for the next chunk; `None` if that is all there is
+fn event_line(elapsed: Duration, pending: &mut Vec<u8>) -> Option<String> {
+    let complete = pending.len() - incomplete_tail(pending);
+    if complete == 0 {
+        return None;
+    }
+    let rest = pending.split_off(complete);
+    let text = String::from_utf8_lossy(pending).into_owned();
+    *pending = rest;
+    let seconds = elapsed.as_millis() as f64 / 1000.0;
+    Some(format!("{}\n", json!([seconds, "o", text])))
+}
+
+/// Length of a UTF-8 sequence begun but not finished at the end of `bytes`
+fn incomplete_tail(bytes: &[u8]) -> usize {
+    for back in 1..=bytes.len().min(3) {
+        let byte = bytes[bytes.len() - back];
+        if byte & 0xC0 != 0x80 {
+            let needed = match byte {
+                0xC0..=0xDF => 2,
+                0xE0..=0xEF => 3,
+                0xF0..=0xF7 => 4,
+                _ => 1,
+            };
+            return if needed > back { back } else { 0 };
+        }
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_records_asciicast_and_counts_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let (output_tx, output_rx) = broadcast::channel(16);
+        let recording =
+            Recording::start(&dir.path().join("casts"), "s1", 80, 24, output_rx).unwrap();
+        assert_eq!(recording.path, dir.path().join("casts/s1.cast"));
+
+        // A character split across chunks is written whole
+        output_tx.send(Bytes::from_static(b"caf\xc3")).unwrap();
+        output_tx.send(Bytes::from_static(b"\xa9\r\n")).unwrap();
+        drop(output_tx);
+
+        let mut content = String::new();
+        for _ in 0..200 {
+            content = std::fs::read_to_string(&recording.path).unwrap();
+            if content.lines().count() == 3 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let lines: Vec<serde_json::Value> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines[0]["version"], 2);
+        assert_eq!(
+            (lines[0]["width"].clone(), lines[0]["height"].clone()),
+            (json!(80), json!(24))
+        );
+        assert_eq!(lines[1][1], "o");
+        assert_eq!(lines[1][2], "caf");
+        assert_eq!(lines[2][2], "\u{e9}\r\n");
+
+        assert_eq!(recording.take_unreported(), content.len() as u64);
+        assert_eq!(recording.take_unreported(), 0);
+    }
+
+    #[test]
+    fn test_incomplete_tail() {
+        assert_eq!(incomplete_tail(b"abc"), 0);
+        assert_eq!(incomplete_tail("é".as_bytes()), 0);
+        assert_eq!(incomplete_tail(b"a\xc3"), 1);
+        assert_eq!(incomplete_tail(b"a\xe2\x82"), 2);
+        assert_eq!(incomplete_tail(b"\xf0\x9f\x98"), 3);
+        assert_eq!(incomplete_tail(b"\xf0\x9f\x98\x80"), 0);
+        // Not UTF-8 at all: left for the lossy conversion
+        assert_eq!(incomplete_tail(b"a\xff"), 0);
+    }
+}
@@ -0,0 +1,273 @@
+//! PNG screenshots of a session, for `ht_take_snapshot_as_png`.
+//!
+//! ht_core keeps its cell grid to itself, so the screen is the one rebuilt
+//! for styled snapshots ([`StyledScreen`]). Each cell is drawn with an
+//! embedded 8x13 bitmap font in xterm's colors; characters the font lacks
+//! show as a dotted box. The image is drawn at 1x and scaled with nearest
+//! neighbour sampling, which keeps the pixels sharp. A footer, such as a
+//! provenance line, takes one more row below the screen.
+
+mod font;
+
+use crate::error::{HtMcpError, Result};
+use crate::ht_integration::styled_snapshot::{Color, Pen, StyledScreen};
+use font::{GLYPH_HEIGHT, GLYPH_WIDTH, STRIKETHROUGH_ROW, UNDERLINE_ROW};
+use image::codecs::png::PngEncoder;
+use image::imageops::{self, FilterType};
+use image::{ImageEncoder, Rgb, RgbImage};
+
+pub const DEFAULT_SCALE: f32 = 1.0;
+pub const MIN_SCALE: f32 = 0.5;
+pub const MAX_SCALE: f32 = 4.0;
+
+/// Largest image rendered, in pixels
+pub const MAX_SCREENSHOT_PIXELS: u64 = 32_000_000;
+
+const DEFAULT_FG: Rgb<u8> = Rgb([0xe5, 0xe5, 0xe5]);
+const DEFAULT_BG: Rgb<u8> = Rgb([0x00, 0x00, 0x00]);
+const FOOTER_FG: Rgb<u8> = Rgb([0x80, 0x80, 0x80]);
+
+/// The 16 named colors as xterm shows them
+const NAMED_COLORS: [[u8; 3]; 16] = [
+    [0x00, 0x00, 0x00],
+    [0xcd, 0x00, 0x00],
+    [0x00, 0xcd, 0x00],
+    [0xcd, 0xcd, 0x00],
+    [0x00, 0x00, 0xee],
+    [0xcd, 0x00, 0xcd],
+    [0x00, 0xcd, 0xcd],
+    [0xe5, 0xe5, 0xe5],
+    [0x7f, 0x7f, 0x7f],
+    [0xff, 0x00, 0x00],
+    [0x00, 0xff, 0x00],
+    [0xff, 0xff, 0x00],
+    [0x5c, 0x5c, 0xff],
+    [0xff, 0x00, 0xff],
+    [0x00, 0xff, 0xff],
+    [0xff, 0xff, 0xff],
+];
+
+/// Channel levels of the 6x6x6 color cube
+const CUBE_LEVELS: [u8; 6] = [0x00, 0x5f, 0x87, 0xaf, 0xd7, 0xff];
+
+#[derive(Debug, Clone)]
+pub struct Screenshot {
+    pub png: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Size of the image of a `cols`x`rows` screen at `scale`, refusing scales
+/// out of range and images over [`MAX_SCREENSHOT_PIXELS`]
+pub fn image_size(cols: usize, rows: usize, scale: f32) -> Result<(u32, u32)> {
+    if !(MIN_SCALE..=MAX_SCALE).contains(&scale) {
+        return Err(HtMcpError::InvalidRequest(format!(
+            "scale must be between {} and {}, got {}",
+            MIN_SCALE, MAX_SCALE, scale
+        )));
+    }
+    let width = ((cols as u32 * GLYPH_WIDTH) as f32 * scale)
+        .round()
+        .max(1.0) as u32;
+    let height = ((rows as u32 * GLYPH_HEIGHT) as f32 * scale)
+        .round()
+        .max(1.0) as u32;
+    if width as u64 * height as u64 > MAX_SCREENSHOT_PIXELS {
+        return Err(HtMcpError::InvalidRequest(format!(
+            "A {}x{} screen at scale {} is {}x{} pixels, over the {} pixel limit; use a lower scale",
+            cols, rows, scale, width, height, MAX_SCREENSHOT_PIXELS
+        )));
+    }
+    Ok((width, height))
+}
+
+/// `screen` as a PNG at `scale`, with `footer` below it, cut to the
+/// screen's width
+pub fn render_png(screen: &StyledScreen, scale: f32, footer: Option<&str>) -> Result<Screenshot> {
+    let footer_rows = footer.map_or(0, |_| 1);
+    let (width, height) = image_size(screen.cols(), screen.rows() + footer_rows, scale)?;
+    let mut image = render(screen, footer);
+    if (width, height) != image.dimensions() {
+        image = imageops::resize(&image, width, height, FilterType::Nearest);
+    }
+
+    let mut png = Vec::new();
+    PngEncoder::new(&mut png)
+        .write_image(image.as_raw(), width, height, image::ColorType::Rgb8)
+        .map_err(|e| HtMcpError::Internal(format!("Failed to encode PNG: {}", e)))?;
+    Ok(Screenshot { png, width, height })
+}
+
+/// `screen` at 1x, one glyph per cell
+fn render(screen: &StyledScreen, footer: Option<&str>) -> RgbImage {
+    let footer_rows = footer.map_or(0, |_| 1);
+    let mut image = RgbImage::new(
+        screen.cols() as u32 * GLYPH_WIDTH,
+        (screen.rows() + footer_rows) as u32 * GLYPH_HEIGHT,
+    );
+    let cursor = screen.cursor();
+    for row in 0..screen.rows() {
+        for col in 0..screen.cols() {
+            let (ch, pen) = screen.cell(row, col);
+            let at_cursor = cursor.visible && (cursor.row, cursor.col) == (row + 1, col + 1);
+            draw_cell(
+                &mut image,
+                col as u32 * GLYPH_WIDTH,
+                row as u32 * GLYPH_HEIGHT,
+                ch,
+                &pen,
+                at_cursor,
+            );
+        }
+    }
+    if let Some(footer) = footer {
+        let pen = Pen {
+            fg: Color::Rgb(FOOTER_FG[0], FOOTER_FG[1], FOOTER_FG[2]),
+            ..Pen::default()
+        };
+        let y = screen.rows() as u32 * GLYPH_HEIGHT;
+        for (col, ch) in footer.chars().take(screen.cols()).enumerate() {
+            draw_cell(&mut image, col as u32 * GLYPH_WIDTH, y, ch, &pen, false);
+        }
+    }
+    image
+}
+
+fn draw_cell(image: &mut RgbImage, x: u32, y: u32, ch: char, pen: &Pen, at_cursor: bool) {
+    let (mut fg, mut bg) = colors(pen);
+    // The cursor is drawn as a block, inverting the cell under it
+    if pen.inverse != at_cursor {
+        std::mem::swap(&mut fg, &mut bg);
+    }
+    if pen.faint {
+        fg = blend(fg, bg);
+    }
+
+    let glyph = font::glyph(ch);
+    for dy in 0..GLYPH_HEIGHT {
+        let mut bits = glyph[dy as usize];
+        if pen.bold {
+            // Double-struck, one pixel to the right
+            bits |= bits >> 1;
+        }
+        let line = (pen.underline && dy == UNDERLINE_ROW)
+            || (pen.strikethrough && dy == STRIKETHROUGH_ROW);
+        for dx in 0..GLYPH_WIDTH {
+            let on = line || bits & (0x80 >> dx) != 0;
+            image.put_pixel(x + dx, y + dy, if on { fg } else { bg });
+        }
+    }
+}
+
+/// Foreground and background of `pen` before inversion. Bold text in one
+/// of the first 8 colors is shown in the bright variant, as xterm does.
+fn colors(pen: &Pen) -> (Rgb<u8>, Rgb<u8>) {
+    let fg = match pen.fg {
+        Color::Indexed(n) if pen.bold && n < 8 => palette(n + 8),
+        color => rgb(color).unwrap_or(DEFAULT_FG),
+    };
+    (fg, rgb(pen.bg).unwrap_or(DEFAULT_BG))
+}
+
+fn rgb(color: Color) -> Option<Rgb<u8>> {
+    match color {
+        Color::Default => None,
+        Color::Indexed(n) => Some(palette(n)),
+        Color::Rgb(r, g, b) => Some(Rgb([r, g, b])),
+    }
+}
+
+/// Color `n` of xterm's 256-color palette
+fn palette(n: u8) -> Rgb<u8> {
+    match n {
+        0..=15 => Rgb(NAMED_COLORS[n as usize]),
+        16..=231 => {
+            let n = n - 16;
+            Rgb([
+                CUBE_LEVELS[(n / 36) as usize],
+                CUBE_LEVELS[(n / 6 % 6) as usize],
+                CUBE_LEVELS[(n % 6) as usize],
+            ])
+        }
+        _ => {
+            let level = 8 + 10 * (n - 232);
+            Rgb([level, level, level])
+        }
+    }
+}
+
+/// Halfway between `a` and `b`
+fn blend(a: Rgb<u8>, b: Rgb<u8>) -> Rgb<u8> {
+    Rgb([0, 1, 2].map(|i| ((a[i] as u16 + b[i] as u16) / 2) as u8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_palette() {
+        assert_eq!(palette(1), Rgb([0xcd, 0x00, 0x00]));
+        assert_eq!(palette(16), Rgb([0, 0, 0]));
+        assert_eq!(palette(208), Rgb([0xff, 0x87, 0x00]));
+        assert_eq!(palette(231), Rgb([0xff, 0xff, 0xff]));
+        assert_eq!(palette(232), Rgb([8, 8, 8]));
+        assert_eq!(palette(255), Rgb([238, 238, 238]));
+    }
+
+    #[test]
+    fn test_image_size_limits() {
+        assert_eq!(image_size(80, 24, 1.0).unwrap(), (640, 312));
+        assert_eq!(image_size(80, 24, 2.5).unwrap(), (1600, 780));
+        for scale in [0.0, 0.25, 5.0, f32::NAN] {
+            let err = image_size(80, 24, scale).unwrap_err();
+            assert!(matches!(err, HtMcpError::InvalidRequest(_)), "{}", err);
+        }
+        assert!(image_size(1000, 1000, 4.0).is_err());
+    }
+
+    #[test]
+    fn test_render_draws_cells_in_their_colors() {
+        // Hidden cursor, so only the text is drawn
+        let screen = StyledScreen::replay("\x1b[41mA\x1b[0m \x1b[7mB\x1b[?25l", 3, 1);
+        let image = render(&screen, None);
+        assert_eq!(image.dimensions(), (24, 13));
+
+        let red = palette(1);
+        let cell_pixels = |col: u32| {
+            (0..GLYPH_HEIGHT).flat_map(move |y| (0..GLYPH_WIDTH).map(move |x| (col * 8 + x, y)))
+        };
+        // "A" in the default color on red
+        assert!(cell_pixels(0).any(|(x, y)| *image.get_pixel(x, y) == red));
+        assert!(cell_pixels(0).any(|(x, y)| *image.get_pixel(x, y) == DEFAULT_FG));
+        // A blank cell is all background
+        assert!(cell_pixels(1).all(|(x, y)| *image.get_pixel(x, y) == DEFAULT_BG));
+        // Inverse "B": light background, dark glyph
+        assert_eq!(*image.get_pixel(16, 0), DEFAULT_FG);
+        assert!(cell_pixels(2).any(|(x, y)| *image.get_pixel(x, y) == DEFAULT_BG));
+    }
+
+    #[test]
+    fn test_render_png() {
+        let screen = StyledScreen::replay("$ ls", 10, 2);
+        let screenshot = render_png(&screen, 2.0, None).unwrap();
+        assert_eq!((screenshot.width, screenshot.height), (160, 52));
+        assert!(screenshot.png.starts_with(b"\x89PNG\r\n\x1a\n"));
+
+        let decoded = image::load_from_memory(&screenshot.png).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (160, 52));
+    }
+
+    #[test]
+    fn test_render_footer() {
+        let screen = StyledScreen::replay("\x1b[?25l", 4, 1);
+        let image = render(&screen, Some("sha256:0123"));
+        assert_eq!(image.dimensions(), (32, 26));
+        // The screen row stays blank, the footer is drawn in gray
+        assert!((0..13).all(|y| (0..32).all(|x| *image.get_pixel(x, y) == DEFAULT_BG)));
+        assert!((13..26).any(|y| (0..32).any(|x| *image.get_pixel(x, y) == FOOTER_FG)));
+
+        let screenshot = render_png(&screen, 1.0, Some("footer")).unwrap();
+        assert_eq!((screenshot.width, screenshot.height), (32, 26));
+    }
+}
@@ -0,0 +1,219 @@
+//! How much of its past a session keeps: one policy covering every store
+//! that grows with the session's lifetime, enforced in one place.
+//!
+//! The config's `[retention]` policy is both the default and the hard limit.
+//! Sessions may lower any category, when created or later with
+//! `ht_set_retention`, but not raise it past the config. Lowering a limit
+//! trims the store immediately, oldest entries first.
+//!
+//! Scrollback belongs to the terminal emulator and recordings are capped
+//! server-wide by `[storage]`, so neither is covered here.
+
+use crate::error::{HtMcpError, Result};
+use crate::ht_integration::snapshot_history::{SnapshotHistory, SNAPSHOT_HISTORY_CAPACITY};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// Commands kept per session by default
+pub const DEFAULT_COMMAND_HISTORY: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetentionPolicy {
+    /// Periodic snapshots kept, for sessions with `snapshotIntervalMs`
+    pub snapshot_history: usize,
+    /// Commands run through `ht_execute_command` kept in the history
+    pub command_history: usize,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self {
+            snapshot_history: SNAPSHOT_HISTORY_CAPACITY,
+            command_history: DEFAULT_COMMAND_HISTORY,
+        }
+    }
+}
+
+/// Per-session changes to a policy, as given in a request
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub struct RetentionOverrides {
+    #[serde(rename = "snapshotHistory")]
+    pub snapshot_history: Option<usize>,
+    #[serde(rename = "commandHistory")]
+    pub command_history: Option<usize>,
+}
+
+impl RetentionPolicy {
+    pub fn validate(&self) -> Result<()> {
+        check(self, HtMcpError::Config)
+    }
+
+    /// This policy with `overrides` applied, which must stay within
+    /// `limits`
+    pub fn with_overrides(
+        &self,
+        overrides: &RetentionOverrides,
+        limits: &RetentionPolicy,
+    ) -> Result<RetentionPolicy> {
+        let policy = RetentionPolicy {
+            snapshot_history: overrides.snapshot_history.unwrap_or(self.snapshot_history),
+            command_history: overrides.command_history.unwrap_or(self.command_history),
+        };
+        check(&policy, HtMcpError::InvalidRequest)?;
+        for (name, value, limit) in [
+            (
+                "snapshotHistory",
+                policy.snapshot_history,
+                limits.snapshot_history,
+            ),
+            (
+                "commandHistory",
+                policy.command_history,
+                limits.command_history,
+            ),
+        ] {
+            if value > limit {
+                return Err(HtMcpError::InvalidRequest(format!(
+                    "{} of {} is over the server's retention limit of {}",
+                    name, value, limit
+                )));
+            }
+        }
+        Ok(policy)
+    }
+
+    /// Trims each store down to this policy
+    pub fn enforce(&self, commands: &mut Vec<String>, snapshots: Option<&SnapshotHistory>) {
+        if commands.len() > self.command_history {
+            commands.drain(..commands.len() - self.command_history);
+        }
+        if let Some(snapshots) = snapshots {
+            snapshots.set_capacity(self.snapshot_history);
+        }
+    }
+
+    /// Each category's limit and what is stored now, i.e. what a client can
+    /// still retrieve
+    pub fn report(&self, commands: &[String], snapshots: Option<&SnapshotHistory>) -> Value {
+        json!({
+            "snapshotHistory": {
+                "limit": self.snapshot_history,
+                "stored": snapshots.map_or(0, SnapshotHistory::len)
+            },
+            "commandHistory": {
+                "limit": self.command_history,
+                "stored": commands.len()
+            }
+        })
+    }
+}
+
+fn check(policy: &RetentionPolicy, error: fn(String) -> HtMcpError) -> Result<()> {
+    // A snapshot history that keeps nothing would only cost the timer
+    if policy.snapshot_history == 0 {
+        return Err(error(
+            "snapshot history retention must keep at least 1 snapshot; leave snapshotIntervalMs unset instead"
+                .to_string(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn commands(n: usize) -> Vec<String> {
+        (1..=n).map(|i| format!("cmd {}", i)).collect()
+    }
+
+    #[test]
+    fn test_overrides_stay_within_limits() {
+        let limits = RetentionPolicy::default();
+        let policy = limits
+            .with_overrides(
+                &RetentionOverrides {
+                    command_history: Some(10),
+                    ..RetentionOverrides::default()
+                },
+                &limits,
+            )
+            .unwrap();
+        assert_eq!(policy.command_history, 10);
+        assert_eq!(policy.snapshot_history, limits.snapshot_history);
+
+        for overrides in [
+            RetentionOverrides {
+                command_history: Some(limits.command_history + 1),
+                ..RetentionOverrides::default()
+            },
+            RetentionOverrides {
+                snapshot_history: Some(0),
+                ..RetentionOverrides::default()
+            },
+        ] {
+            let err = policy.with_overrides(&overrides, &limits).unwrap_err();
+            assert!(matches!(err, HtMcpError::InvalidRequest(_)));
+        }
+
+        let bad = RetentionPolicy {
+            snapshot_history: 0,
+            ..RetentionPolicy::default()
+        };
+        assert!(matches!(bad.validate(), Err(HtMcpError::Config(_))));
+    }
+
+    #[test]
+    fn test_enforce_trims_command_history() {
+        let mut history = commands(5);
+        let policy = RetentionPolicy {
+            command_history: 2,
+            ..RetentionPolicy::default()
+        };
+        policy.enforce(&mut history, None);
+        assert_eq!(history, ["cmd 4", "cmd 5"]);
+
+        policy.enforce(&mut history, None);
+        assert_eq!(history.len(), 2);
+
+        RetentionPolicy {
+            command_history: 0,
+            ..policy
+        }
+        .enforce(&mut history, None);
+        assert!(history.is_empty());
+    }
+
+    #[test]
+    fn test_enforce_trims_snapshot_history() {
+        let snapshots = SnapshotHistory::new(Duration::from_secs(1));
+        for text in ["a", "b", "c", "d"] {
+            snapshots.push(text.to_string());
+        }
+        let policy = RetentionPolicy {
+            snapshot_history: 2,
+            ..RetentionPolicy::default()
+        };
+        policy.enforce(&mut Vec::new(), Some(&snapshots));
+        let kept: Vec<String> = snapshots
+            .recent(10, None)
+            .into_iter()
+            .map(|(_, text)| text)
+            .collect();
+        assert_eq!(kept, ["c", "d"]);
+
+        // The lowered capacity holds for later snapshots
+        snapshots.push("e".to_string());
+        assert_eq!(snapshots.len(), 2);
+
+        assert_eq!(
+            policy.report(&commands(1), Some(&snapshots)),
+            json!({
+                "snapshotHistory": {"limit": 2, "stored": 2},
+                "commandHistory": {"limit": DEFAULT_COMMAND_HISTORY, "stored": 1}
+            })
+        );
+    }
+}
@@ -0,0 +1,166 @@
+use crate::error::HtMcpError;
+use crate::ht_integration::cgroup::MemoryCgroup;
+use crate::ht_integration::pty::PtyChild;
+use crate::ht_integration::temp_dir::SessionTempDir;
+use crate::tunnel::TunnelManager;
+use std::fmt;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+/// A stage of session creation, in the order the stages run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CreateStage {
+    Listener,
+    WebServer,
+    Tunnel,
+    TempDir,
+    MemoryLimit,
+    Pty,
+    EventLoop,
+}
+
+impl CreateStage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CreateStage::Listener => "listener",
+            CreateStage::WebServer => "web server",
+            CreateStage::Tunnel => "tunnel",
+            CreateStage::TempDir => "temp dir",
+            CreateStage::Pty => "pty",
+            CreateStage::MemoryLimit => "memory limit",
+            CreateStage::EventLoop => "event loop",
+        }
+    }
+
+    /// Wraps an error so it says which stage failed
+    pub fn error(&self, err: impl fmt::Display) -> HtMcpError {
+        HtMcpError::Internal(format!(
+            "Session creation failed at {} stage: {}",
+            self, err
+        ))
+    }
+}
+
+impl fmt::Display for CreateStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+enum UndoAction {
+    AbortTask(JoinHandle<()>),
+    StopTunnel(String),
+    RemoveCgroup(MemoryCgroup),
+    KillChild(PtyChild),
+    RemoveTempDir {
+        temp_dir: SessionTempDir,
+        keep: bool,
+    },
+}
+
+/// Undo actions registered by completed creation stages.
+///
+/// On failure `unwind` runs them in reverse order, so nothing created by a
+/// partially constructed session outlives the failed `create_session` call.
+/// If creation is cancelled instead (e.g. by a tool timeout), dropping the
+/// rollback undoes what it can without awaiting.
+#[derive(Default)]
+pub struct Rollback {
+    actions: Vec<(CreateStage, UndoAction)>,
+}
+
+impl Rollback {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a task to abort (and await) on rollback
+    pub fn abort_task(&mut self, stage: CreateStage, handle: JoinHandle<()>) {
+        self.actions.push((stage, UndoAction::AbortTask(handle)));
+    }
+
+    /// Registers a tunnel to stop on rollback
+    pub fn stop_tunnel(&mut self, stage: CreateStage, tunnel_id: String) {
+        self.actions
+            .push((stage, UndoAction::StopTunnel(tunnel_id)));
+    }
+
+    /// Registers a PTY child to kill (and reap) on rollback
+    pub fn kill_child(&mut self, stage: CreateStage, child: PtyChild) {
+        self.actions.push((stage, UndoAction::KillChild(child)));
+    }
+
+    /// Registers a session temp dir to remove on rollback, or to keep for
+    /// inspection if `keep` is set
+    pub fn remove_temp_dir(&mut self, stage: CreateStage, temp_dir: SessionTempDir, keep: bool) {
+        self.actions
+            .push((stage, UndoAction::RemoveTempDir { temp_dir, keep }));
+    }
+
+    /// Registers a cgroup to remove on rollback
+    pub fn remove_cgroup(&mut self, stage: CreateStage, cgroup: MemoryCgroup) {
+        self.actions.push((stage, UndoAction::RemoveCgroup(cgroup)));
+    }
+
+    /// Undoes every completed stage, most recent first
+    pub async fn unwind(mut self, tunnel_manager: &mut TunnelManager) {
+        while let Some((stage, action)) = self.actions.pop() {
+            info!("Rolling back {} stage", stage);
+            match action {
+                UndoAction::AbortTask(handle) => {
+                    handle.abort();
+                    // Awaiting guarantees the task (and anything it owns, like a
+                    // bound listener) has been dropped before we return
+                    let _ = handle.await;
+                }
+                UndoAction::StopTunnel(tunnel_id) => {
+                    if let Err(e) = tunnel_manager.stop_tunnel(&tunnel_id).await {
+                        warn!("Failed to stop tunnel {} during rollback: {}", tunnel_id, e);
+                    }
+                }
+                UndoAction::KillChild(child) => {
+                    // Until it execs, a freshly forked child holds copies of our
+                    // sockets, so it must be gone before ports count as released
+                    child.kill().await;
+                }
+                UndoAction::RemoveTempDir { temp_dir, keep } => temp_dir.cleanup(keep),
+                UndoAction::RemoveCgroup(cgroup) => {
+                    // The killed child may not have left the cgroup yet, so
+                    // wait for it to empty in the background
+                    tokio::spawn(cgroup.remove_when_empty());
+                }
+            }
+        }
+    }
+
+    /// Keeps everything created so far; called once all stages succeeded
+    pub fn commit(mut self) {
+        self.actions.clear();
+    }
+}
+
+impl Drop for Rollback {
+    fn drop(&mut self) {
+        while let Some((stage, action)) = self.actions.pop() {
+            info!("Rolling back {} stage of cancelled session creation", stage);
+            match action {
+                UndoAction::AbortTask(handle) => handle.abort(),
+                UndoAction::StopTunnel(tunnel_id) => {
+                    // Stopping needs the tunnel manager; the tunnel stays
+                    // registered there and is stopped with the others
+                    warn!(
+                        "Tunnel {} of a cancelled session creation is left running",
+                        tunnel_id
+                    );
+                }
+                UndoAction::KillChild(child) => {
+                    tokio::spawn(async move { child.kill().await });
+                }
+                UndoAction::RemoveTempDir { temp_dir, keep } => temp_dir.cleanup(keep),
+                UndoAction::RemoveCgroup(cgroup) => {
+                    tokio::spawn(cgroup.remove_when_empty());
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,299 @@
+//! Running a session's PTY process as a different user (Unix only)
+
+use crate::error::{HtMcpError, Result};
+use crate::mcp::types::RunAsArgs;
+use std::path::PathBuf;
+
+/// A validated target identity for a PTY child
+#[derive(Debug, Clone)]
+pub struct RunAsUser {
+    pub user: String,
+    pub group: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub home: PathBuf,
+    /// Login shell from the passwd entry
+    pub shell: PathBuf,
+    /// Supplementary groups, including `gid`
+    pub groups: Vec<u32>,
+}
+
+impl RunAsUser {
+    /// Looks up the user and group and checks the server may switch to them.
+    /// The group defaults to the user's primary group.
+    #[cfg(unix)]
+    pub fn resolve(args: &RunAsArgs) -> Result<Self> {
+        use nix::unistd::{getegid, geteuid, Group, User};
+
+        let user = User::from_name(&args.user)
+            .map_err(|e| lookup_error("user", &args.user, e))?
+            .ok_or_else(|| {
+                HtMcpError::InvalidRequest(format!("runAs user '{}' does not exist", args.user))
+            })?;
+        let group = match &args.group {
+            Some(name) => Group::from_name(name)
+                .map_err(|e| lookup_error("group", name, e))?
+                .ok_or_else(|| {
+                    HtMcpError::InvalidRequest(format!("runAs group '{}' does not exist", name))
+                })?,
+            None => Group::from_gid(user.gid)
+                .map_err(|e| lookup_error("group", &user.gid.to_string(), e))?
+                .ok_or_else(|| {
+                    HtMcpError::InvalidRequest(format!(
+                        "Primary group {} of runAs user '{}' does not exist",
+                        user.gid, user.name
+                    ))
+                })?,
+        };
+
+        let run_as = Self {
+            groups: supplementary_groups(&user, group.gid),
+            user: user.name,
+            group: group.name,
+            uid: user.uid.as_raw(),
+            gid: group.gid.as_raw(),
+            home: user.dir,
+            shell: user.shell,
+        };
+        run_as.check_permission(geteuid().as_raw(), getegid().as_raw())?;
+        Ok(run_as)
+    }
+
+    #[cfg(not(unix))]
+    pub fn resolve(_args: &RunAsArgs) -> Result<Self> {
+        Err(HtMcpError::InvalidRequest(
+            "runAs is only supported on Unix".to_string(),
+        ))
+    }
+
+    /// Switching identity needs root unless the target is the server's own
+    /// user and group, which the child then keeps without switching
+    fn check_permission(&self, server_uid: u32, server_gid: u32) -> Result<()> {
+        if server_uid == 0 || self.is_identity(server_uid, server_gid) {
+            return Ok(());
+        }
+        Err(HtMcpError::InvalidRequest(format!(
+            "Cannot run session as '{}': the server (uid {}, gid {}) lacks permission to switch users or groups; run it as root",
+            self.describe(),
+            server_uid,
+            server_gid
+        )))
+    }
+
+    /// Whether this is the identity `uid`/`gid`, so there is nothing to
+    /// switch
+    pub fn is_identity(&self, uid: u32, gid: u32) -> bool {
+        self.uid == uid && self.gid == gid
+    }
+
+    /// `user:group`, as shown in session info
+    pub fn describe(&self) -> String {
+        format!("{}:{}", self.user, self.group)
+    }
+}
+
+#[cfg(unix)]
+fn lookup_error(kind: &str, name: &str, e: nix::Error) -> HtMcpError {
+    HtMcpError::Internal(format!(
+        "Failed to look up runAs {} '{}': {}",
+        kind, name, e
+    ))
+}
+
+#[cfg(all(unix, not(target_vendor = "apple")))]
+fn supplementary_groups(user: &nix::unistd::User, gid: nix::unistd::Gid) -> Vec<u32> {
+    std::ffi::CString::new(user.name.as_str())
+        .ok()
+        .and_then(|name| nix::unistd::getgrouplist(&name, gid).ok())
+        .map(|groups| groups.into_iter().map(|g| g.as_raw()).collect())
+        .unwrap_or_else(|| vec![gid.as_raw()])
+}
+
+// getgrouplist is not exposed for Apple targets; the child then keeps only
+// its primary group
+#[cfg(all(unix, target_vendor = "apple"))]
+fn supplementary_groups(_user: &nix::unistd::User, gid: nix::unistd::Gid) -> Vec<u32> {
+    vec![gid.as_raw()]
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use nix::unistd::geteuid;
+
+    fn args(user: &str, group: Option<&str>) -> RunAsArgs {
+        RunAsArgs {
+            user: user.to_string(),
+            group: group.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_unknown_user_is_rejected() {
+        let err = RunAsUser::resolve(&args("ht-mcp-no-such-user", None)).unwrap_err();
+        assert!(matches!(err, HtMcpError::InvalidRequest(_)));
+        assert!(err.to_string().contains("does not exist"));
+    }
+
+    #[test]
+    fn test_unknown_group_is_rejected() {
+        let err = RunAsUser::resolve(&args("root", Some("ht-mcp-no-such-group"))).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("group 'ht-mcp-no-such-group' does not exist"));
+    }
+
+    #[test]
+    fn test_switching_users_requires_root() {
+        let nobody = RunAsUser {
+            user: "nobody".to_string(),
+            group: "nogroup".to_string(),
+            uid: 65534,
+            gid: 65534,
+            home: PathBuf::from("/nonexistent"),
+            shell: PathBuf::from("/usr/sbin/nologin"),
+            groups: vec![65534],
+        };
+        let err = nobody.check_permission(1000, 1000).unwrap_err();
+        assert!(err.to_string().contains("lacks permission"));
+        assert!(nobody.check_permission(0, 0).is_ok());
+        assert!(nobody.check_permission(65534, 65534).is_ok());
+        // The server's own user in another group is still a switch
+        let err = nobody.check_permission(65534, 1000).unwrap_err();
+        assert!(err.to_string().contains("lacks permission"));
+    }
+
+    #[test]
+    fn test_resolve_defaults_to_primary_group() {
+        if !geteuid().is_root() {
+            eprintln!("skipping: needs root to resolve another user");
+            return;
+        }
+        let root = RunAsUser::resolve(&args("root", None)).unwrap();
+        assert_eq!(root.uid, 0);
+        assert_eq!(root.gid, 0);
+        assert!(root.groups.contains(&0));
+    }
+
+    #[tokio::test]
+    async fn test_pty_runs_as_own_user_without_root() {
+        use crate::ht_integration::pty::{self, SpawnOptions};
+        use nix::unistd::{getegid, Group, User};
+        use tokio::sync::mpsc;
+
+        let user = User::from_uid(geteuid()).unwrap().unwrap();
+        let group = Group::from_gid(getegid()).unwrap().unwrap();
+        let own = RunAsUser::resolve(&args(&user.name, Some(&group.name))).unwrap();
+        let options = SpawnOptions {
+            run_as: Some(own),
+            ..SpawnOptions::default()
+        };
+        let (_input_tx, input_rx) = mpsc::channel(1);
+        let (output_tx, mut output_rx) = mpsc::channel(16);
+        let (_child, future) = pty::spawn(
+            "echo \"uid=$(id -u)\"".to_string(),
+            80,
+            24,
+            &options,
+            input_rx,
+            output_tx,
+        )
+        .unwrap();
+        tokio::spawn(future);
+
+        let mut output = String::new();
+        while let Some(data) = output_rx.recv().await {
+            output.push_str(&String::from_utf8_lossy(&data));
+        }
+        assert!(
+            output.contains(&format!("uid={}", user.uid)),
+            "unexpected output: {}",
+            output
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pty_run_as_gets_minimal_environment() {
+        use crate::ht_integration::pty::{self, SpawnOptions};
+        use nix::unistd::{getegid, Group, User};
+        use tokio::sync::mpsc;
+
+        std::env::set_var("HT_MCP_RUN_AS_SERVER_SECRET", "leaked");
+        let user = User::from_uid(geteuid()).unwrap().unwrap();
+        let group = Group::from_gid(getegid()).unwrap().unwrap();
+        let own = RunAsUser::resolve(&args(&user.name, Some(&group.name))).unwrap();
+        let options = SpawnOptions {
+            run_as: Some(own.clone()),
+            env: vec![("REQUESTED".to_string(), "yes".to_string())],
+            ..SpawnOptions::default()
+        };
+        let (_input_tx, input_rx) = mpsc::channel(1);
+        let (output_tx, mut output_rx) = mpsc::channel(16);
+        let (_child, future) = pty::spawn(
+            "echo \"secret=$HT_MCP_RUN_AS_SERVER_SECRET requested=$REQUESTED home=$HOME shell=$SHELL\""
+                .to_string(),
+            80,
+            24,
+            &options,
+            input_rx,
+            output_tx,
+        )
+        .unwrap();
+        tokio::spawn(future);
+
+        let mut output = String::new();
+        while let Some(data) = output_rx.recv().await {
+            output.push_str(&String::from_utf8_lossy(&data));
+        }
+        assert!(
+            output.contains(&format!(
+                "secret= requested=yes home={} shell={}",
+                own.home.display(),
+                own.shell.display()
+            )),
+            "unexpected output: {}",
+            output
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pty_runs_as_target_user() {
+        use crate::ht_integration::pty::{self, SpawnOptions};
+        use tokio::sync::mpsc;
+
+        if !geteuid().is_root() {
+            eprintln!("skipping: needs root to switch users");
+            return;
+        }
+        let nobody = RunAsUser::resolve(&args("nobody", None)).unwrap();
+        let options = SpawnOptions {
+            run_as: Some(nobody.clone()),
+            ..SpawnOptions::default()
+        };
+        let (_input_tx, input_rx) = mpsc::channel(1);
+        let (output_tx, mut output_rx) = mpsc::channel(16);
+        let (_child, future) = pty::spawn(
+            "echo \"uid=$(id -u) gid=$(id -g) user=$USER\"".to_string(),
+            80,
+            24,
+            &options,
+            input_rx,
+            output_tx,
+        )
+        .unwrap();
+        tokio::spawn(future);
+
+        let mut output = String::new();
+        while let Some(data) = output_rx.recv().await {
+            output.push_str(&String::from_utf8_lossy(&data));
+        }
+        assert!(
+            output.contains(&format!(
+                "uid={} gid={} user=nobody",
+                nobody.uid, nobody.gid
+            )),
+            "unexpected output: {}",
+            output
+        );
+    }
+}
@@ -0,0 +1,519 @@
+//! Heuristic classification of what a session's terminal is showing, so
+//! agents don't have to infer it from raw snapshots.
+//!
+//! Rules are rows in a table: each names a state, a confidence and the
+//! conditions that must all hold. Every matching rule is evidence for its
+//! state, and the verdict is the state of the most confident match. Rules
+//! from the config are checked before the built-in ones and win ties.
+
+use crate::error::{HtMcpError, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Output within this long counts as the terminal still being busy
+pub const ACTIVITY_WINDOW: Duration = Duration::from_millis(500);
+
+/// Control sequences switching to and from the alternate screen buffer
+const ALT_SCREEN_ENTER: [&[u8]; 3] = [b"\x1b[?1049h", b"\x1b[?1047h", b"\x1b[?47h"];
+const ALT_SCREEN_EXIT: [&[u8]; 3] = [b"\x1b[?1049l", b"\x1b[?1047l", b"\x1b[?47l"];
+
+/// A line ending the way common shell and REPL prompts do
+const PROMPT: &str = r"[$#%>❯]\s*$";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ScreenState {
+    ShellPrompt,
+    RunningCommand,
+    FullScreenApp,
+    PasswordPrompt,
+    Pager,
+    Unknown,
+}
+
+/// Terminal facts besides the screen text
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ScreenSignals {
+    pub alt_screen: bool,
+    /// Whether the PTY echoes input; `None` where it cannot be read
+    pub echo: Option<bool>,
+    /// Whether output arrived within the last `ACTIVITY_WINDOW`
+    pub recent_output: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ScreenVerdict {
+    pub state: ScreenState,
+    /// Descriptions of the rules that matched for `state`, most confident
+    /// first
+    pub evidence: Vec<String>,
+    pub confidence: f32,
+}
+
+/// A condition of a built-in rule
+#[derive(Debug, Clone, Copy)]
+enum Condition {
+    AltScreen(bool),
+    EchoOff,
+    RecentOutput(bool),
+    /// The last non-blank line matches
+    LastLine(&'static str),
+    /// The last non-blank line does not match
+    NotLastLine(&'static str),
+}
+
+struct RuleSpec {
+    state: ScreenState,
+    confidence: f32,
+    evidence: &'static str,
+    conditions: &'static [Condition],
+}
+
+const BUILTIN_RULES: &[RuleSpec] = &[
+    RuleSpec {
+        state: ScreenState::PasswordPrompt,
+        confidence: 0.95,
+        evidence: "input echo is off at a line ending in ':'",
+        conditions: &[Condition::EchoOff, Condition::LastLine(r":\s*$")],
+    },
+    RuleSpec {
+        state: ScreenState::PasswordPrompt,
+        confidence: 0.85,
+        evidence: "last line asks for a password",
+        conditions: &[Condition::LastLine(
+            r"(?i)(password|passphrase|passcode)[^:]*:\s*$",
+        )],
+    },
+    RuleSpec {
+        state: ScreenState::Pager,
+        confidence: 0.9,
+        evidence: "pager end marker \"(END)\"",
+        conditions: &[Condition::LastLine(r"\(END\)\s*$")],
+    },
+    RuleSpec {
+        state: ScreenState::Pager,
+        confidence: 0.9,
+        evidence: "pager marker \"--More--\"",
+        conditions: &[Condition::LastLine(r"--More--")],
+    },
+    RuleSpec {
+        state: ScreenState::Pager,
+        confidence: 0.8,
+        evidence: "alternate screen with a bare ':' command line",
+        conditions: &[Condition::AltScreen(true), Condition::LastLine(r"^:\s*$")],
+    },
+    RuleSpec {
+        state: ScreenState::FullScreenApp,
+        confidence: 0.75,
+        evidence: "alternate screen buffer is active",
+        conditions: &[Condition::AltScreen(true)],
+    },
+    RuleSpec {
+        state: ScreenState::ShellPrompt,
+        confidence: 0.85,
+        evidence: "idle at a line ending in a prompt character",
+        conditions: &[
+            Condition::AltScreen(false),
+            Condition::RecentOutput(false),
+            Condition::LastLine(PROMPT),
+        ],
+    },
+    RuleSpec {
+        state: ScreenState::ShellPrompt,
+        confidence: 0.6,
+        evidence: "last line ends in a prompt character",
+        conditions: &[Condition::AltScreen(false), Condition::LastLine(PROMPT)],
+    },
+    RuleSpec {
+        state: ScreenState::RunningCommand,
+        confidence: 0.7,
+        evidence: "output is still arriving",
+        conditions: &[
+            Condition::AltScreen(false),
+            Condition::RecentOutput(true),
+            Condition::LastLine(r"\S"),
+            Condition::NotLastLine(PROMPT),
+        ],
+    },
+    RuleSpec {
+        state: ScreenState::RunningCommand,
+        confidence: 0.5,
+        evidence: "last line is not a prompt",
+        conditions: &[
+            Condition::AltScreen(false),
+            Condition::LastLine(r"\S"),
+            Condition::NotLastLine(PROMPT),
+        ],
+    },
+];
+
+/// An extra rule from the config, e.g. for a custom prompt. At least one of
+/// `last_line` and `screen` must be set.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScreenRuleConfig {
+    pub state: ScreenState,
+    /// Regex the last non-blank line must match
+    pub last_line: Option<String>,
+    /// Regex that must match somewhere on the screen
+    pub screen: Option<String>,
+    #[serde(default = "default_rule_confidence")]
+    pub confidence: f32,
+}
+
+fn default_rule_confidence() -> f32 {
+    0.9
+}
+
+#[derive(Debug, Clone)]
+enum Check {
+    AltScreen(bool),
+    EchoOff,
+    RecentOutput(bool),
+    LastLine(Regex),
+    NotLastLine(Regex),
+    Screen(Regex),
+}
+
+impl Check {
+    fn holds(&self, screen: &str, last_line: &str, signals: &ScreenSignals) -> bool {
+        match self {
+            Check::AltScreen(on) => signals.alt_screen == *on,
+            Check::EchoOff => signals.echo == Some(false),
+            Check::RecentOutput(recent) => signals.recent_output == *recent,
+            Check::LastLine(regex) => regex.is_match(last_line),
+            Check::NotLastLine(regex) => !regex.is_match(last_line),
+            Check::Screen(regex) => regex.is_match(screen),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    state: ScreenState,
+    confidence: f32,
+    evidence: String,
+    checks: Vec<Check>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ScreenClassifier {
+    rules: Vec<Rule>,
+}
+
+impl ScreenClassifier {
+    /// The built-in rules, preceded by `custom` ones
+    pub fn new(custom: &[ScreenRuleConfig]) -> Result<Self> {
+        let mut rules = custom
+            .iter()
+            .map(compile_custom_rule)
+            .collect::<Result<Vec<_>>>()?;
+        rules.extend(BUILTIN_RULES.iter().map(compile_builtin_rule));
+        Ok(Self { rules })
+    }
+
+    pub fn classify(&self, screen: &str, signals: &ScreenSignals) -> ScreenVerdict {
+        let last_line = screen
+            .lines()
+            .rev()
+            .find(|line| !line.trim().is_empty())
+            .unwrap_or("")
+            .trim_end();
+
+        let mut matched: Vec<&Rule> = self
+            .rules
+            .iter()
+            .filter(|rule| {
+                rule.checks
+                    .iter()
+                    .all(|check| check.holds(screen, last_line, signals))
+            })
+            .collect();
+        // Stable, so earlier (custom) rules win ties
+        matched.sort_by(|a, b| b.confidence.total_cmp(&a.confidence));
+
+        match matched.first() {
+            Some(best) => ScreenVerdict {
+                state: best.state,
+                evidence: matched
+                    .iter()
+                    .filter(|rule| rule.state == best.state)
+                    .map(|rule| rule.evidence.clone())
+                    .collect(),
+                confidence: best.confidence,
+            },
+            None => ScreenVerdict {
+                state: ScreenState::Unknown,
+                evidence: Vec::new(),
+                confidence: 0.0,
+            },
+        }
+    }
+}
+
+impl Default for ScreenClassifier {
+    fn default() -> Self {
+        Self {
+            rules: BUILTIN_RULES.iter().map(compile_builtin_rule).collect(),
+        }
+    }
+}
+
+fn compile_builtin_rule(spec: &RuleSpec) -> Rule {
+    // The table's patterns are fixed and covered by tests
+    let regex = |pattern: &str| Regex::new(pattern).expect("built-in screen rule pattern");
+    Rule {
+        state: spec.state,
+        confidence: spec.confidence,
+        evidence: spec.evidence.to_string(),
+        checks: spec
+            .conditions
+            .iter()
+            .map(|condition| match condition {
+                Condition::AltScreen(on) => Check::AltScreen(*on),
+                Condition::EchoOff => Check::EchoOff,
+                Condition::RecentOutput(recent) => Check::RecentOutput(*recent),
+                Condition::LastLine(pattern) => Check::LastLine(regex(pattern)),
+                Condition::NotLastLine(pattern) => Check::NotLastLine(regex(pattern)),
+            })
+            .collect(),
+    }
+}
+
+fn compile_custom_rule(config: &ScreenRuleConfig) -> Result<Rule> {
+    let regex = |field: &str, pattern: &str| {
+        Regex::new(pattern).map_err(|e| {
+            HtMcpError::Config(format!(
+                "Invalid screen rule {} '{}': {}",
+                field, pattern, e
+            ))
+        })
+    };
+
+    let mut checks = Vec::new();
+    let mut evidence = Vec::new();
+    if let Some(pattern) = &config.last_line {
+        checks.push(Check::LastLine(regex("last_line", pattern)?));
+        evidence.push(format!("last line matches configured /{}/", pattern));
+    }
+    if let Some(pattern) = &config.screen {
+        checks.push(Check::Screen(regex("screen", pattern)?));
+        evidence.push(format!("screen matches configured /{}/", pattern));
+    }
+    if checks.is_empty() {
+        return Err(HtMcpError::Config(
+            "A screen rule needs last_line or screen".to_string(),
+        ));
+    }
+    if !(0.0..=1.0).contains(&config.confidence) {
+        return Err(HtMcpError::Config(format!(
+            "Screen rule confidence must be between 0 and 1, got {}",
+            config.confidence
+        )));
+    }
+
+    Ok(Rule {
+        state: config.state,
+        confidence: config.confidence,
+        evidence: evidence.join(" and "),
+        checks,
+    })
+}
+
+/// What a session's raw output says about the terminal: whether the
+/// alternate screen is active, when output last arrived and how much there
+/// has been. Fed by the session's event loop.
+#[derive(Debug, Default)]
+pub struct OutputActivity {
+    alt_screen: AtomicBool,
+    last_output: Mutex<Option<Instant>>,
+    output_bytes: AtomicU64,
+}
+
+impl OutputActivity {
+    pub fn record(&self, data: &[u8]) {
+        *self.last_output.lock().unwrap() = Some(Instant::now());
+        self.output_bytes
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+
+        let last = |sequences: &[&[u8]]| {
+            sequences
+                .iter()
+                .filter_map(|sequence| rfind(data, sequence))
+                .max()
+        };
+        match (last(&ALT_SCREEN_ENTER), last(&ALT_SCREEN_EXIT)) {
+            (Some(enter), exit) if exit.map_or(true, |exit| enter > exit) => {
+                self.alt_screen.store(true, Ordering::Relaxed)
+            }
+            (_, Some(_)) => self.alt_screen.store(false, Ordering::Relaxed),
+            _ => {}
+        }
+    }
+
+    /// Bytes of output the session has produced so far
+    pub fn output_bytes(&self) -> u64 {
+        self.output_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn signals(&self, echo: Option<bool>) -> ScreenSignals {
+        let recent_output = self
+            .last_output
+            .lock()
+            .unwrap()
+            .is_some_and(|at| at.elapsed() < ACTIVITY_WINDOW);
+        ScreenSignals {
+            alt_screen: self.alt_screen.load(Ordering::Relaxed),
+            echo,
+            recent_output,
+        }
+    }
+}
+
+fn rfind(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .rposition(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn classify(screen: &str, signals: ScreenSignals) -> ScreenVerdict {
+        ScreenClassifier::default().classify(screen, &signals)
+    }
+
+    fn idle() -> ScreenSignals {
+        ScreenSignals {
+            echo: Some(true),
+            ..ScreenSignals::default()
+        }
+    }
+
+    #[test]
+    fn test_shell_prompt() {
+        let verdict = classify("$ ls\nCargo.toml  src\nuser@host:~/crate$ ", idle());
+        assert_eq!(verdict.state, ScreenState::ShellPrompt);
+        assert_eq!(verdict.confidence, 0.85);
+        assert_eq!(verdict.evidence.len(), 2);
+
+        let busy = ScreenSignals {
+            recent_output: true,
+            ..idle()
+        };
+        let verdict = classify("root@host:/# ", busy);
+        assert_eq!(verdict.state, ScreenState::ShellPrompt);
+        assert_eq!(verdict.confidence, 0.6);
+    }
+
+    #[test]
+    fn test_running_command() {
+        let busy = ScreenSignals {
+            recent_output: true,
+            ..idle()
+        };
+        let verdict = classify("$ cargo build\n   Compiling serde v1.0", busy);
+        assert_eq!(verdict.state, ScreenState::RunningCommand);
+        assert_eq!(verdict.confidence, 0.7);
+
+        let verdict = classify("$ sleep 100", idle());
+        assert_eq!(verdict.state, ScreenState::RunningCommand);
+        assert_eq!(verdict.confidence, 0.5);
+    }
+
+    #[test]
+    fn test_password_prompt() {
+        let verdict = classify("$ sudo ls\n[sudo] password for user: ", idle());
+        assert_eq!(verdict.state, ScreenState::PasswordPrompt);
+        assert_eq!(verdict.confidence, 0.85);
+
+        let no_echo = ScreenSignals {
+            echo: Some(false),
+            ..idle()
+        };
+        let verdict = classify("$ ssh host\nEnter PIN for key: ", no_echo);
+        assert_eq!(verdict.state, ScreenState::PasswordPrompt);
+        assert_eq!(verdict.confidence, 0.95);
+    }
+
+    #[test]
+    fn test_pager() {
+        let alt = ScreenSignals {
+            alt_screen: true,
+            ..idle()
+        };
+        assert_eq!(
+            classify("line 1\nline 2\n(END)", alt).state,
+            ScreenState::Pager
+        );
+        assert_eq!(classify("line 1\nline 2\n:", alt).state, ScreenState::Pager);
+        assert_eq!(
+            classify("line 1\n--More--(42%)", idle()).state,
+            ScreenState::Pager
+        );
+    }
+
+    #[test]
+    fn test_full_screen_app() {
+        let alt = ScreenSignals {
+            alt_screen: true,
+            ..idle()
+        };
+        let verdict = classify("fn main() {}\n~\n~\n-- INSERT --", alt);
+        assert_eq!(verdict.state, ScreenState::FullScreenApp);
+        assert_eq!(verdict.evidence, ["alternate screen buffer is active"]);
+    }
+
+    #[test]
+    fn test_blank_screen_is_unknown() {
+        let verdict = classify("\n\n", idle());
+        assert_eq!(verdict.state, ScreenState::Unknown);
+        assert_eq!(verdict.confidence, 0.0);
+    }
+
+    #[test]
+    fn test_custom_rules_come_first() {
+        let classifier = ScreenClassifier::new(&[ScreenRuleConfig {
+            state: ScreenState::ShellPrompt,
+            last_line: Some(r"^\[db\] ready$".to_string()),
+            screen: None,
+            confidence: 0.9,
+        }])
+        .unwrap();
+        let verdict = classifier.classify("[db] ready", &idle());
+        assert_eq!(verdict.state, ScreenState::ShellPrompt);
+        assert_eq!(
+            verdict.evidence,
+            [r"last line matches configured /^\[db\] ready$/"]
+        );
+
+        let invalid = ScreenRuleConfig {
+            state: ScreenState::Pager,
+            last_line: Some("(".to_string()),
+            screen: None,
+            confidence: 0.9,
+        };
+        assert!(matches!(
+            ScreenClassifier::new(&[invalid]),
+            Err(HtMcpError::Config(_))
+        ));
+    }
+
+    #[test]
+    fn test_alt_screen_tracking() {
+        let activity = OutputActivity::default();
+        activity.record(b"\x1b[?1049h\x1b[H~");
+        assert!(activity.signals(None).alt_screen);
+        assert!(activity.signals(None).recent_output);
+        // The last switch in a chunk wins
+        activity.record(b"\x1b[?1049lbye\x1b[?1049h");
+        assert!(activity.signals(None).alt_screen);
+        activity.record(b"\x1b[?1049l$ ");
+        assert!(!activity.signals(None).alt_screen);
+        activity.record(b"plain output");
+        assert!(!activity.signals(None).alt_screen);
+    }
+}
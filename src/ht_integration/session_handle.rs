@@ -0,0 +1,314 @@
+//! Async API for library consumers embedding `SessionManager`: a cloneable
+//! handle bound to one session, and a `Stream` of the session's raw output.
+
+use crate::error::{HtMcpError, Result};
+use crate::ht_integration::assertions::{self, Assertion, AssertionReport, Screen};
+use crate::ht_integration::exit::ExitWaiters;
+use crate::ht_integration::keys;
+use crate::ht_integration::session_manager::SessionCommand;
+use crate::ht_integration::snapshot::TextSnapshot;
+use crate::ht_integration::styled_snapshot::{StyledFormat, StyledScreen, StyledSnapshot};
+use bytes::Bytes;
+use futures::stream::{BoxStream, StreamExt};
+use regex::Regex;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc, oneshot, Semaphore};
+
+/// PTY output chunks buffered per session for slow stream consumers; older
+/// chunks are dropped, and reported as missed, once a consumer falls behind
+pub const OUTPUT_STREAM_CAPACITY: usize = 1024;
+
+/// How long a snapshot request waits for the event loop
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a snapshot request waits for a slot before failing with
+/// `ResourceExhausted`
+const SNAPSHOT_PERMIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often `wait_for` checks the screen
+const WAIT_FOR_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A session's raw PTY output; see [`output_stream`]
+pub type OutputStream = BoxStream<'static, std::result::Result<Bytes, MissedOutput>>;
+
+/// An output stream fell behind and this many chunks were dropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("output stream lagged; {0} chunks missed")]
+pub struct MissedOutput(pub u64);
+
+/// Output chunks as they arrive from the PTY. A consumer too slow to keep up
+/// gets `Err(MissedOutput)` in place of the dropped chunks instead of holding
+/// up the session. The stream ends when the session's event loop exits.
+pub fn output_stream(rx: broadcast::Receiver<Bytes>) -> OutputStream {
+    futures::stream::unfold(rx, |mut rx| async move {
+        match rx.recv().await {
+            Ok(chunk) => Some((Ok(chunk), rx)),
+            Err(RecvError::Lagged(missed)) => Some((Err(MissedOutput(missed)), rx)),
+            Err(RecvError::Closed) => None,
+        }
+    })
+    .boxed()
+}
+
+/// Asks the event loop for the current screen text
+pub async fn request_snapshot(
+    command_tx: &mpsc::Sender<SessionCommand>,
+    permits: &Semaphore,
+) -> Result<String> {
+    request_text_snapshot(command_tx, permits)
+        .await
+        .map(|snapshot| snapshot.text)
+}
+
+/// Asks the event loop for the current screen text and cursor
+pub async fn request_text_snapshot(
+    command_tx: &mpsc::Sender<SessionCommand>,
+    permits: &Semaphore,
+) -> Result<TextSnapshot> {
+    request(command_tx, permits, "snapshot", SessionCommand::Snapshot).await
+}
+
+/// Asks the event loop for the screen with its styling, in `format`
+pub async fn request_styled_snapshot(
+    command_tx: &mpsc::Sender<SessionCommand>,
+    permits: &Semaphore,
+    format: StyledFormat,
+) -> Result<StyledSnapshot> {
+    request(command_tx, permits, "snapshot", |response_tx| {
+        SessionCommand::SnapshotStyled(format, response_tx)
+    })
+    .await
+}
+
+/// Asks the event loop for the screen's cells and cursor
+pub async fn request_screen_cells(
+    command_tx: &mpsc::Sender<SessionCommand>,
+    permits: &Semaphore,
+) -> Result<StyledScreen> {
+    request(command_tx, permits, "snapshot", SessionCommand::ScreenCells).await
+}
+
+/// Asks the event loop for the screen text and terminal size
+pub async fn request_screen(
+    command_tx: &mpsc::Sender<SessionCommand>,
+    permits: &Semaphore,
+) -> Result<Screen> {
+    request(command_tx, permits, "screen", SessionCommand::Screen).await
+}
+
+/// Sends one screen request to the event loop and waits for the answer,
+/// holding one of the session's snapshot slots (`permits`, sized by
+/// `concurrent_snapshots_per_session`) until it comes. A request that
+/// gets no slot within [`SNAPSHOT_PERMIT_TIMEOUT`] fails with
+/// `ResourceExhausted`.
+async fn request<T>(
+    command_tx: &mpsc::Sender<SessionCommand>,
+    permits: &Semaphore,
+    what: &str,
+    command: impl FnOnce(oneshot::Sender<Result<T>>) -> SessionCommand,
+) -> Result<T> {
+    let _permit = tokio::time::timeout(SNAPSHOT_PERMIT_TIMEOUT, permits.acquire())
+        .await
+        .map_err(|_| {
+            HtMcpError::ResourceExhausted(format!(
+                "Too many {} requests in flight for the session",
+                what
+            ))
+        })?
+        .map_err(|e| HtMcpError::Internal(format!("Snapshot slots closed: {}", e)))?;
+
+    let (response_tx, response_rx) = oneshot::channel();
+    command_tx
+        .send(command(response_tx))
+        .await
+        .map_err(|e| HtMcpError::Internal(format!("Failed to send {} command: {}", what, e)))?;
+
+    tokio::time::timeout(SNAPSHOT_TIMEOUT, response_rx)
+        .await
+        .map_err(|_| HtMcpError::Internal(format!("The {} request timed out", what)))?
+        .map_err(|e| HtMcpError::Internal(format!("Failed to receive {}: {}", what, e)))?
+}
+
+/// Cheap cloneable handle to one session. It does not keep the session
+/// alive: once the session is closed every method fails with
+/// `SessionNotFound`.
+#[derive(Debug, Clone)]
+pub struct SessionHandle {
+    session_id: String,
+    command_tx: mpsc::WeakSender<SessionCommand>,
+    output_tx: broadcast::WeakSender<Bytes>,
+    /// How the session's process ended, once it has
+    exit: Arc<ExitWaiters>,
+    snapshot_permits: Arc<Semaphore>,
+}
+
+impl SessionHandle {
+    pub(crate) fn new(
+        session_id: String,
+        command_tx: &mpsc::Sender<SessionCommand>,
+        output_tx: &broadcast::Sender<Bytes>,
+        exit: Arc<ExitWaiters>,
+        snapshot_permits: Arc<Semaphore>,
+    ) -> Self {
+        Self {
+            session_id,
+            command_tx: command_tx.downgrade(),
+            output_tx: output_tx.downgrade(),
+            exit,
+            snapshot_permits,
+        }
+    }
+
+    pub fn session_id(&self) -> &str {
+        &self.session_id
+    }
+
+    /// Sends keys, named as for `ht_send_keys` (`"Enter"`, `"C-c"`, ...)
+    pub async fn send_keys(&self, keys: &[&str]) -> Result<()> {
+        let seqs = keys.iter().map(|key| keys::parse(key)).collect();
+        self.command_tx()?
+            .send(SessionCommand::Input(seqs))
+            .await
+            .map_err(|_| self.closed())
+    }
+
+    /// Types `input` (keys as for `send_keys`), then sends the raw EOF byte
+    pub async fn close_stdin_after(&self, input: &[&str]) -> Result<()> {
+        let seqs = input.iter().map(|key| keys::parse(key)).collect();
+        self.command_tx()?
+            .send(SessionCommand::CloseStdinAfterInput(seqs))
+            .await
+            .map_err(|_| self.closed())
+    }
+
+    /// The current screen text
+    pub async fn snapshot(&self) -> Result<String> {
+        request_snapshot(&self.command_tx()?, &self.snapshot_permits)
+            .await
+            .map_err(|e| self.exit.explain(&self.session_id, e))
+    }
+
+    /// Checks `assertions` against one capture of the screen; see
+    /// [`assertions::check`]
+    pub async fn assert_screen(&self, assertions: &[Assertion]) -> Result<AssertionReport> {
+        let screen = request_screen(&self.command_tx()?, &self.snapshot_permits).await?;
+        assertions::check(&screen, assertions)
+    }
+
+    /// Resizes the session's virtual terminal
+    pub async fn resize(&self, cols: usize, rows: usize) -> Result<()> {
+        self.command_tx()?
+            .send(SessionCommand::Resize(cols, rows))
+            .await
+            .map_err(|_| self.closed())
+    }
+
+    /// Waits until `pattern` (a regex) matches the screen, returning the
+    /// screen text, or fails with `Timeout` after `timeout`. Fails early
+    /// with `SessionExited` if the session's process exits without the
+    /// last screen matching.
+    pub async fn wait_for(&self, pattern: &str, timeout: Duration) -> Result<String> {
+        let regex = Regex::new(pattern).map_err(|e| {
+            HtMcpError::InvalidRequest(format!("Invalid pattern '{}': {}", pattern, e))
+        })?;
+        let wait = async {
+            loop {
+                let screen = self.snapshot().await?;
+                if regex.is_match(&screen) {
+                    return Ok(screen);
+                }
+                tokio::time::sleep(WAIT_FOR_POLL_INTERVAL).await;
+            }
+        };
+        let exited = self.exit.register();
+        tokio::select! {
+            // Ahead of the wait, whose snapshots fail once the session is gone
+            biased;
+            Ok(exit) = exited => {
+                if regex.is_match(&exit.screen) {
+                    return Ok(exit.screen);
+                }
+                Err(HtMcpError::SessionExited(format!(
+                    "{}: {} before '{}' appeared",
+                    self.session_id,
+                    exit.describe(),
+                    pattern
+                )))
+            }
+            waited = tokio::time::timeout(timeout, wait) => waited.map_err(|_| {
+                HtMcpError::Timeout(format!(
+                    "'{}' did not appear in session {} within {} ms",
+                    pattern,
+                    self.session_id,
+                    timeout.as_millis()
+                ))
+            })?,
+        }
+    }
+
+    /// The session's output from now on; see [`output_stream`]
+    pub fn output_stream(&self) -> Result<OutputStream> {
+        let output_tx = self.output_tx.upgrade().ok_or_else(|| self.closed())?;
+        Ok(output_stream(output_tx.subscribe()))
+    }
+
+    fn command_tx(&self) -> Result<mpsc::Sender<SessionCommand>> {
+        self.command_tx.upgrade().ok_or_else(|| self.closed())
+    }
+
+    fn closed(&self) -> HtMcpError {
+        HtMcpError::SessionNotFound(self.session_id.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_lagging_stream_reports_missed_chunks() {
+        let (tx, rx) = broadcast::channel(2);
+        let mut stream = output_stream(rx);
+        for chunk in ["a", "b", "c", "d"] {
+            tx.send(Bytes::from(chunk)).unwrap();
+        }
+        drop(tx);
+
+        assert_eq!(stream.next().await, Some(Err(MissedOutput(2))));
+        assert_eq!(stream.next().await, Some(Ok(Bytes::from("c"))));
+        assert_eq!(stream.next().await, Some(Ok(Bytes::from("d"))));
+        assert_eq!(stream.next().await, None, "ends with the session");
+    }
+
+    #[tokio::test]
+    async fn test_slow_stream_never_blocks_the_sender() {
+        let (tx, rx) = broadcast::channel(OUTPUT_STREAM_CAPACITY);
+        let _stream = output_stream(rx);
+        // Never polled, yet sending well past capacity doesn't block
+        for _ in 0..OUTPUT_STREAM_CAPACITY * 4 {
+            tx.send(Bytes::from_static(b"x")).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handle_fails_once_session_is_gone() {
+        let (command_tx, _command_rx) = mpsc::channel(1);
+        let (output_tx, _) = broadcast::channel(1);
+        let handle = SessionHandle::new(
+            "session-1".to_string(),
+            &command_tx,
+            &output_tx,
+            Arc::default(),
+            Arc::new(Semaphore::new(1)),
+        );
+        drop((command_tx, output_tx));
+
+        assert!(matches!(
+            handle.send_keys(&["Enter"]).await,
+            Err(HtMcpError::SessionNotFound(_))
+        ));
+        assert!(handle.output_stream().is_err());
+    }
+}
@@ -1,20 +1,109 @@
+use crate::config::{HtMcpConfig, DEFAULT_CONCURRENT_SNAPSHOTS};
 use crate::error::{HtMcpError, Result};
+use crate::ht_integration::aliases::CommandAliases;
+use crate::ht_integration::annotations::{AnnotationPatternConfig, Annotator};
+use crate::ht_integration::assertions::{self, Screen};
+use crate::ht_integration::baseline;
+use crate::ht_integration::cgroup::MemoryCgroup;
+use crate::ht_integration::command_class::{CommandClass, CommandClassifier};
+use crate::ht_integration::completion::{self, Sentinel};
+use crate::ht_integration::connection_guard::{
+    ConnectedClients, ConnectionGuard, ConnectionLimits, ConnectionStats,
+};
+use crate::ht_integration::control_sequences;
+use crate::ht_integration::cpu_affinity;
+use crate::ht_integration::dockerfile;
+use crate::ht_integration::drain::Drain;
+use crate::ht_integration::event_loop::EventLoop;
+use crate::ht_integration::events::{
+    EventBus, InputSource, LockReleaseReason, SessionEvent, TerminationReason,
+};
+use crate::ht_integration::exit::ExitWaiters;
+use crate::ht_integration::flood::{FloodConfig, FloodDetector, FloodStats};
+use crate::ht_integration::history_download::{self, HistoryDownloads};
+use crate::ht_integration::input_profile::{random_seed, InputProfile};
+use crate::ht_integration::interaction_lock::{
+    self, InteractionLock, LockClaim, CLOSE_TOOLS, DEFAULT_LOCK_TTL_SECS, MAX_LOCK_TTL_SECS,
+};
+use crate::ht_integration::interactive_prompt::{PromptWatch, DEFAULT_PROMPT_TIMEOUT_MS};
+use crate::ht_integration::keys;
+use crate::ht_integration::lease::Lease;
+use crate::ht_integration::metrics_history::{
+    self, MetricsHistory, SessionCounters, METRICS_INTERVAL,
+};
+use crate::ht_integration::migration::{MigratedContext, MigrationBundle, SessionConfig};
+use crate::ht_integration::output_webhook::OutputWebhook;
+use crate::ht_integration::port_reservation::{
+    PortReservations, DEFAULT_RESERVATION_TTL, MAX_RESERVATIONS, MAX_RESERVATION_TTL,
+};
+use crate::ht_integration::process::{ProcessSampler, REPORT_CACHE_TTL};
+use crate::ht_integration::provenance::{self, Provenance, ProvenanceLog};
+use crate::ht_integration::pty::{self, PtyChild, SpawnOptions};
+use crate::ht_integration::recording::Recording;
+use crate::ht_integration::renderer;
+use crate::ht_integration::retention::RetentionPolicy;
+use crate::ht_integration::rollback::{CreateStage, Rollback};
+use crate::ht_integration::run_as::RunAsUser;
+use crate::ht_integration::screen_classifier::{OutputActivity, ScreenClassifier};
+use crate::ht_integration::session_handle::{
+    self, request_screen, request_screen_cells, request_snapshot, request_styled_snapshot,
+    request_text_snapshot, OutputStream, SessionHandle, OUTPUT_STREAM_CAPACITY,
+};
+use crate::ht_integration::snapshot::{self, TextSnapshot, DEFAULT_MAX_CONSECUTIVE_BLANK_LINES};
+use crate::ht_integration::snapshot_history::{SnapshotHistory, MIN_SNAPSHOT_INTERVAL_MS};
+use crate::ht_integration::startup::{StartupSamples, StartupTiming};
+use crate::ht_integration::state::{self, StateManifest};
+use crate::ht_integration::storage::{StorageCategory, StorageManager};
+use crate::ht_integration::styled_snapshot::{
+    StyledFormat, StyledScreen, StyledSnapshot, BYTES_PER_CELL,
+};
+use crate::ht_integration::summary;
+use crate::ht_integration::tail_file::{
+    self, TailBuffer, TailSession, DEFAULT_TAIL_LINES, MAX_TAIL_LINES, TAIL_PAGE_LINES,
+};
+use crate::ht_integration::temp_dir::{SessionTempDir, SESSION_TMP_ENV_VAR};
+use crate::ht_integration::token_estimate::{self, TextSize};
+use crate::ht_integration::tool_probe::{self, Probe, ProbeShell, ShellInfo, ToolInfo, ToolProbe};
+use crate::ht_integration::type_file;
+use crate::ht_integration::viewer_input::{ViewerInput, VIEWER_INPUT_CAPACITY};
+use crate::ht_integration::warm_pool::{WarmPool, WarmPoolConfig};
+use crate::ht_integration::web_listener;
+use crate::ht_integration::web_supervisor::{WebServerStatus, WebSupervisor};
+use crate::mcp::client_identity::ClientIdentity;
+use crate::mcp::progress::Progress;
 use crate::mcp::types::*;
-use crate::tunnel::TunnelManager;
-use ht_core::{api::http, pty, pty::Winsize, session::Session};
-use std::collections::HashMap;
-use std::net::{SocketAddr, TcpListener};
-use std::sync::Arc;
-use tokio::sync::{mpsc, oneshot};
+use crate::tunnel::config::{validate_provider, OPT_IN_TUNNEL_PROVIDERS};
+use crate::tunnel::named::{self, NamedTunnelConfig};
+use crate::tunnel::pool::{Relay, TunnelPoolConfig};
+use crate::tunnel::{DetachedTunnel, TunnelConfig, TunnelInfo, TunnelManager};
+use base64::Engine;
+use bytes::Bytes;
+use futures::future::join_all;
+use ht_core::command::InputSeq;
+use regex::Regex;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{broadcast, mpsc, oneshot, OwnedMutexGuard, Semaphore};
+use tokio::task::{AbortHandle, JoinHandle};
 use uuid::Uuid;
 
-use tracing::{error, info, warn};
+use tracing::{debug, error, info, warn};
 
 // Enhanced command type that supports responses
 #[derive(Debug)]
 pub enum SessionCommand {
     Input(Vec<ht_core::command::InputSeq>),
-    Snapshot(oneshot::Sender<String>),
+    Snapshot(oneshot::Sender<Result<TextSnapshot>>),
+    /// The screen with its colors and attributes, in the given format
+    SnapshotStyled(StyledFormat, oneshot::Sender<Result<StyledSnapshot>>),
+    /// Every cell with its styling and the cursor, for screenshots
+    ScreenCells(oneshot::Sender<Result<StyledScreen>>),
+    /// Screen text with the terminal's size, for `assert_screen`
+    Screen(oneshot::Sender<Result<Screen>>),
+    /// Writes the input, then the raw EOF byte (`0x04`)
+    CloseStdinAfterInput(Vec<ht_core::command::InputSeq>),
     Resize(usize, usize),
 }
 
@@ -23,366 +112,10463 @@ pub struct SessionInfo {
     pub id: String,
     pub internal_id: Uuid,
     pub created_at: std::time::SystemTime,
-    pub web_server_url: Option<String>,
+    /// Where the web server is, when enabled; kept current by its supervisor
+    pub web_server: Option<Arc<WebServerStatus>>,
+    /// The web server's supervisor; aborting it stops the web server
+    web_supervisor: Option<AbortHandle>,
     pub tunnel_url: Option<String>,
-    pub is_alive: bool,
+    /// `TunnelManager` id of the session's tunnel
+    pub tunnel_id: Option<String>,
     pub command: Vec<String>,
+    /// What the PTY runs: `command` after the configured `command_prefix`
+    pub effective_command: Vec<String>,
     pub command_tx: Arc<mpsc::Sender<SessionCommand>>,
+    /// Raw PTY output fanned out to `output_stream` subscribers
+    pub stream_tx: broadcast::Sender<Bytes>,
+    /// The process running in the PTY
+    pub child: PtyChild,
+    /// What the raw output says about the terminal, for `classify_screen`
+    pub activity: Arc<OutputActivity>,
+    /// Output flood state, for `list_sessions`
+    pub flood_stats: Arc<FloodStats>,
+    pub memory_cgroup: Option<MemoryCgroup>,
+    /// Web server connection counters, when the web server is enabled
+    pub connection_stats: Option<Arc<ConnectionStats>>,
+    /// Connections open to the web server; always empty without one
+    pub connected_clients: ConnectedClients,
+    /// `user:group` the PTY process runs as, when set with `runAs`
+    pub run_as: Option<String>,
+    /// Scratch directory, when created with `tempDir`
+    pub temp_dir: Option<SessionTempDir>,
+    /// Periodic snapshots, when enabled with `snapshotIntervalMs`
+    pub snapshot_history: Option<Arc<SnapshotHistory>>,
+    /// Counters and memory use sampled every 30 seconds, oldest first
+    pub metrics_history: MetricsHistory,
+    /// Time from spawning the PTY to first output and to the prompt
+    pub startup: Arc<StartupTiming>,
+    /// Operations waiting on the session, told when its process exits
+    pub exit: Arc<ExitWaiters>,
+    /// Input bytes and snapshots, kept by the event loop
+    pub counters: Arc<SessionCounters>,
+    /// Expiry of a session created with `leaseSecs`
+    pub lease: Option<Lease>,
+    /// Advisory lock taken with `ht_acquire_lock`; may have expired without
+    /// being cleared yet
+    pub lock: Option<InteractionLock>,
+    /// When input was last let through, from an input tool or a web viewer
+    pub last_input_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// The file followed, for sessions created by `ht_tail_file`
+    pub tail: Option<TailSession>,
+    /// Settings the session was created with
+    pub config: SessionConfig,
+    /// Commands run through `execute_command`, oldest first
+    pub history: Vec<String>,
+    /// Shell prompt set with `autoDetectPrompt`; its return marks a command
+    /// as finished
+    pub prompt_marker: Option<String>,
+    /// Set when the session was imported from another server instance
+    pub migrated_from: Option<MigratedContext>,
+    /// Trace span open for the session's lifetime
+    pub span: tracing::Span,
+    /// Slots for snapshot requests in flight, sized by
+    /// `concurrent_snapshots_per_session`
+    pub snapshot_permits: Arc<Semaphore>,
+    /// The MCP client that created the session, when known
+    pub created_by: Option<ClientIdentity>,
+    /// Held while `send_keys` input or a `type_file` file is sent, so paced
+    /// input is not interleaved with another call's
+    pub input_lock: Arc<tokio::sync::Mutex<()>>,
+    /// What `ht_probe_tools` found; a new session starts without it
+    pub tool_probe: ToolProbe,
+    /// The session's output recording, with `storage.recordings_dir` set
+    pub recording: Option<Recording>,
 }
 
-pub struct SessionManager {
-    sessions: HashMap<String, SessionInfo>,
-    tunnel_manager: TunnelManager,
+/// Keys of a `send_keys` call, with the delay before each write
+pub struct PendingKeys {
+    session_id: String,
+    keys: Vec<String>,
+    profile: InputProfile,
+    seed: Option<u64>,
+    keystrokes: Vec<(Duration, Vec<InputSeq>)>,
+    command_tx: Arc<mpsc::Sender<SessionCommand>>,
+    input_lock: Arc<tokio::sync::Mutex<()>>,
+    exit: Arc<ExitWaiters>,
 }
 
-impl SessionManager {
-    pub fn new() -> Self {
-        Self {
-            sessions: HashMap::new(),
-            tunnel_manager: TunnelManager::new(),
+impl PendingKeys {
+    pub async fn send(self) -> Result<serde_json::Value> {
+        // A call arriving during paced input goes after it, not into it
+        let input = self.input_lock.clone().lock_owned().await;
+        self.send_holding(input).await
+    }
+
+    /// Like [`Self::send`], for callers holding the manager, which must not
+    /// wait on another call's paced input: a session busy with one fails
+    /// with `ResourceExhausted`
+    pub async fn try_send(self) -> Result<serde_json::Value> {
+        let input = self.input_lock.clone().try_lock_owned().map_err(|_| {
+            HtMcpError::ResourceExhausted(format!(
+                "Session {} is busy with another call's input; retry once it is sent",
+                self.session_id
+            ))
+        })?;
+        self.send_holding(input).await
+    }
+
+    /// Writes the keys from a task of their own that holds `input` to the
+    /// last keystroke, so a call dropped at its timeout still finishes its
+    /// input instead of leaving it half typed
+    async fn send_holding(self, input: OwnedMutexGuard<()>) -> Result<serde_json::Value> {
+        tokio::spawn(async move {
+            let _input = input;
+            self.write().await
+        })
+        .await
+        .map_err(|e| HtMcpError::Internal(format!("Sending keys failed: {}", e)))?
+    }
+
+    async fn write(self) -> Result<serde_json::Value> {
+        let started = Instant::now();
+        for (delay, seqs) in self.keystrokes {
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+            self.command_tx
+                .send(SessionCommand::Input(seqs))
+                .await
+                .map_err(|e| {
+                    let err = HtMcpError::Internal(format!("Failed to send keys: {}", e));
+                    self.exit.explain(&self.session_id, err)
+                })?;
+        }
+
+        info!("Sent keys {:?} to session {}", self.keys, self.session_id);
+
+        let mut result = serde_json::json!({
+            "success": true,
+            "message": format!("Keys sent successfully to session {}", self.session_id),
+            "sessionId": self.session_id,
+            "keys": self.keys
+        });
+        if let Some(seed) = self.seed {
+            result["inputProfile"] = serde_json::json!(self.profile);
+            result["seed"] = serde_json::json!(seed);
+            result["typedMs"] = serde_json::json!(started.elapsed().as_millis() as u64);
         }
+        Ok(result)
     }
+}
 
-    pub async fn create_session(&mut self, args: CreateSessionArgs) -> Result<serde_json::Value> {
-        let session_id = Uuid::new_v4().to_string();
-        let command = args.command.unwrap_or_else(|| vec!["bash".to_string()]);
-        let enable_web_server = args.enable_web_server.unwrap_or(false);
-        let enable_tunnel = args.enable_tunnel.unwrap_or(false);
-        let internal_id = Uuid::new_v4();
+/// A file read for `type_file` and cut into chunks, to be typed without
+/// holding the manager: with the delay between chunks a large file takes
+/// seconds, and other sessions must not wait on it
+pub struct PendingTypeFile {
+    session_id: String,
+    path: PathBuf,
+    bytes: usize,
+    lines: usize,
+    chunks: Vec<String>,
+    delay: Duration,
+    command_tx: Arc<mpsc::Sender<SessionCommand>>,
+    input_lock: Arc<tokio::sync::Mutex<()>>,
+    exit: Arc<ExitWaiters>,
+}
 
-        // Create channels for communication
-        let (input_tx, input_rx) = mpsc::channel::<Vec<u8>>(1024);
-        let (output_tx, mut output_rx) = mpsc::channel::<Vec<u8>>(1024);
-        let (command_tx, mut command_rx) = mpsc::channel::<SessionCommand>(1024);
-        let (clients_tx, mut clients_rx) = mpsc::channel(1);
-
-        // Create a platform-agnostic terminal size
-        // Using a helper function to maintain a clean interface
-        let size = create_winsize(120, 40);
-        let cols = size.ws_col as usize;
-        let rows = size.ws_row as usize;
-
-        // Start HTTP server if enabled - we need to clone clients_tx for the HTTP server
-        let (web_server_url, tunnel_url, _clients_tx_for_session) = if enable_web_server {
-            let port = self.find_available_port().await?;
-            let addr = SocketAddr::from(([127, 0, 0, 1], port));
-            let listener = TcpListener::bind(addr).map_err(|e| {
-                HtMcpError::Internal(format!("Failed to bind to port {}: {}", port, e))
-            })?;
+impl PendingTypeFile {
+    /// Types the chunks after any paced input already being sent, reporting
+    /// each one to `progress`. Like [`PendingKeys`], they are written from
+    /// a task of their own, so a call dropped at its timeout does not leave
+    /// the file half typed.
+    pub async fn send(self, progress: Option<Progress>) -> Result<serde_json::Value> {
+        let input = self.input_lock.clone().lock_owned().await;
+        tokio::spawn(async move {
+            let _input = input;
+            self.write(progress).await
+        })
+        .await
+        .map_err(|e| HtMcpError::Internal(format!("Typing the file failed: {}", e)))?
+    }
 
-            let url = format!("http://127.0.0.1:{}", port);
+    async fn write(self, progress: Option<Progress>) -> Result<serde_json::Value> {
+        let total = self.chunks.len();
+        for (index, chunk) in self.chunks.into_iter().enumerate() {
+            if index > 0 && !self.delay.is_zero() {
+                tokio::time::sleep(self.delay).await;
+            }
+            self.command_tx
+                .send(SessionCommand::Input(vec![InputSeq::Standard(chunk)]))
+                .await
+                .map_err(|e| {
+                    let err = HtMcpError::Internal(format!("Failed to type file: {}", e));
+                    self.exit.explain(&self.session_id, err)
+                })?;
+            if let Some(progress) = &progress {
+                progress.report(index as u64 + 1, total as u64);
+            }
+            debug!(
+                "Typed chunk {}/{} of {} into session {}",
+                index + 1,
+                total,
+                self.path.display(),
+                self.session_id
+            );
+        }
 
-            // Clone clients_tx for the HTTP server
-            let clients_tx_for_http = clients_tx.clone();
+        info!(
+            "Typed {} ({} bytes) into session {}",
+            self.path.display(),
+            self.bytes,
+            self.session_id
+        );
+        Ok(serde_json::json!({
+            "success": true,
+            "sessionId": self.session_id,
+            "path": self.path.display().to_string(),
+            "bytes": self.bytes,
+            "lines": self.lines,
+            "chunks": total
+        }))
+    }
+}
 
-            // Start the HTTP server with HT's native implementation
-            tokio::spawn(async move {
-                if let Ok(server_future) = http::start(listener, clients_tx_for_http).await {
-                    if let Err(e) = server_future.await {
-                        error!("HTTP server error: {}", e);
-                    }
-                }
-            });
+/// An `execute_command` call checked against its session, to be typed and
+/// waited for without holding the manager: a command can run for minutes,
+/// and other sessions must not wait on it
+pub struct PendingCommand {
+    args: ExecuteCommandArgs,
+    /// The alias the command was expanded from, reported with the result
+    alias: Option<serde_json::Value>,
+    annotator: Option<Annotator>,
+    terminator: Vec<String>,
+    class_name: String,
+    completion_timeout: Duration,
+    quiet_period: Duration,
+    prompt_marker: Option<String>,
+    sentinel: Option<Sentinel>,
+    /// The typed command and its terminator, sent in one write
+    input: Vec<InputSeq>,
+    command_tx: Arc<mpsc::Sender<SessionCommand>>,
+    stream_tx: broadcast::Sender<Bytes>,
+    input_lock: Arc<tokio::sync::Mutex<()>>,
+    snapshot_permits: Arc<Semaphore>,
+    exit: Arc<ExitWaiters>,
+}
 
-            // Start tunnel if enabled
-            let tunnel_url = if enable_tunnel {
-                match self.tunnel_manager.create_simple_tunnel(port).await {
-                    Ok(tunnel_info) => {
-                        info!(
-                            "Tunnel created for session {}: {}",
-                            session_id, tunnel_info.url
-                        );
-                        Some(tunnel_info.url)
-                    }
-                    Err(e) => {
-                        error!("Failed to create tunnel for session {}: {}", session_id, e);
-                        None
-                    }
-                }
-            } else {
-                None
-            };
+/// A `check_command_exists` lookup, typed and waited for without holding
+/// the manager like any other command
+pub struct PendingCommandCheck {
+    command: String,
+    lookup: PendingCommand,
+}
 
-            info!("Started HT native webserver on {}", url);
-            (Some(url), tunnel_url, clients_tx)
-        } else {
-            (None, None, clients_tx)
+impl PendingCommandCheck {
+    pub async fn run(self) -> Result<serde_json::Value> {
+        let session_id = self.lookup.args.session_id.clone();
+        let result = self.lookup.run().await?.result;
+        if result["sessionExited"] == true {
+            return Err(HtMcpError::SessionExited(format!(
+                "Session {} exited while looking up {}",
+                session_id, self.command
+            )));
+        }
+        if result["timedOut"] == true {
+            return Err(HtMcpError::Timeout(format!(
+                "Looking up {} in session {} did not finish",
+                self.command, session_id
+            )));
+        }
+        let output = result["output"].as_str().unwrap_or_default();
+        let path = parse_command_check(output).ok_or_else(|| {
+            HtMcpError::Internal(format!(
+                "Could not find the result of looking up {} on the screen",
+                self.command
+            ))
+        })?;
+
+        Ok(serde_json::json!({
+            "sessionId": session_id,
+            "command": self.command,
+            "exists": path.is_some(),
+            "path": path
+        }))
+    }
+}
+
+/// A `probe_tools` call, typed and read without holding the manager. A
+/// call the session's cache answers has nothing to run.
+pub struct PendingProbe {
+    session_id: String,
+    tools: Vec<String>,
+    run: Option<ProbeRun>,
+}
+
+struct ProbeRun {
+    probe: Probe,
+    /// Subscribed before the probe is typed, so no record is missed
+    output: broadcast::Receiver<Bytes>,
+    keys: PendingKeys,
+    program: String,
+}
+
+/// What [`PendingProbe::run`] found, for [`SessionManager::finish_probe`]
+/// to cache and report
+pub struct FinishedProbe {
+    session_id: String,
+    tools: Vec<String>,
+    found: Option<(ShellInfo, Vec<(String, ToolInfo)>)>,
+}
+
+impl PendingProbe {
+    pub async fn run(self) -> Result<FinishedProbe> {
+        let found = match self.run {
+            Some(run) => {
+                run.keys.send().await?;
+                let text = run
+                    .probe
+                    .collect(run.output, tool_probe::PROBE_TIMEOUT)
+                    .await?;
+                let (shell_info, found) = run.probe.parse(&text, &run.program)?;
+                debug!(
+                    "Probed {} tools in session {}",
+                    found.len(),
+                    self.session_id
+                );
+                Some((shell_info, found))
+            }
+            None => None,
         };
+        Ok(FinishedProbe {
+            session_id: self.session_id,
+            tools: self.tools,
+            found,
+        })
+    }
+}
 
-        // Start PTY process
-        let command_str = command.join(" ");
-        let _pty_handle = tokio::spawn(async move {
-            match pty::spawn(command_str, size, input_rx, output_tx) {
-                Ok(future) => {
-                    if let Err(e) = future.await {
-                        error!("PTY execution error: {}", e);
-                    }
-                }
-                Err(e) => {
-                    error!("PTY spawn error: {}", e);
-                }
+/// The commands of a `parallel_execute` call, run together without
+/// holding the manager; a session that failed its checks keeps the error
+/// for its entry
+pub struct PendingParallel {
+    command: String,
+    session_ids: Vec<String>,
+    commands: Vec<Result<PendingCommand>>,
+}
+
+/// What [`PendingParallel::run`] got from each session, for
+/// [`SessionManager::finish_parallel`] to record
+pub struct FinishedParallel {
+    command: String,
+    session_ids: Vec<String>,
+    outcomes: Vec<Result<FinishedCommand>>,
+}
+
+impl PendingParallel {
+    pub async fn run(self) -> FinishedParallel {
+        let outcomes = join_all(
+            self.commands
+                .into_iter()
+                .map(|command| async move { command?.run().await }),
+        )
+        .await;
+        FinishedParallel {
+            command: self.command,
+            session_ids: self.session_ids,
+            outcomes,
+        }
+    }
+}
+
+/// A command run by [`PendingCommand::run`], for
+/// [`SessionManager::finish_command`] to record
+pub struct FinishedCommand {
+    args: ExecuteCommandArgs,
+    alias: Option<serde_json::Value>,
+    annotator: Option<Annotator>,
+    result: serde_json::Value,
+    duration: Duration,
+}
+
+impl PendingCommand {
+    /// Types the command, submits it and snapshots the result. If the
+    /// session's process exits first, returns the last screen with
+    /// `sessionExited` and the exit status instead of waiting for the
+    /// prompt or a timeout.
+    pub async fn run(mut self) -> Result<FinishedCommand> {
+        let started = Instant::now();
+        let input = std::mem::take(&mut self.input);
+        let exited = self.exit.register();
+        let result = tokio::select! {
+            // Ahead of the command, whose snapshots fail once the session
+            // is gone
+            biased;
+            Ok(exit) = exited => {
+                warn!(
+                    "Command in session {} cut short: {}",
+                    self.args.session_id,
+                    exit.describe()
+                );
+                serde_json::json!({
+                    "command": self.args.command,
+                    "sessionId": self.args.session_id,
+                    "terminator": self.terminator,
+                    "output": exit.screen,
+                    "sessionExited": true,
+                    "exitStatus": exit.status
+                })
             }
-        });
+            result = self.run_to_completion(input) => result?,
+        };
+        Ok(FinishedCommand {
+            args: self.args,
+            alias: self.alias,
+            annotator: self.annotator,
+            result,
+            duration: started.elapsed(),
+        })
+    }
 
-        // Start session event loop
-        let session_id_clone = session_id.clone();
-        tokio::spawn(async move {
-            let mut session = Session::new(cols, rows);
-            let mut serving = true;
+    async fn run_to_completion(&self, input: Vec<InputSeq>) -> Result<serde_json::Value> {
+        let session_id = &self.args.session_id;
+        let before = match &self.prompt_marker {
+            Some(_) => Some(self.snapshot().await?),
+            None => None,
+        };
+        // Without a prompt marker, a shell echoes a marker after the
+        // command; the output is watched from before the command is sent
+        let output = self.sentinel.as_ref().map(|_| self.stream_tx.subscribe());
 
-            loop {
-                tokio::select! {
-                    // Handle output from PTY
-                    output = output_rx.recv() => {
-                        match output {
-                            Some(data) => {
-                                session.output(String::from_utf8_lossy(&data).to_string());
-                            }
-                            None => {
-                                info!("PTY process exited for session {}", session_id_clone);
-                                break;
-                            }
-                        }
-                    }
+        // A single write is either sent whole or not at all, so a call
+        // dropped at its timeout never leaves the command half typed
+        {
+            let _input = self.input_lock.lock().await;
+            self.command_tx
+                .send(SessionCommand::Input(input))
+                .await
+                .map_err(|e| {
+                    let err = HtMcpError::Internal(format!("Failed to send keys: {}", e));
+                    self.exit.explain(session_id, err)
+                })?;
+        }
 
-                    // Handle commands from MCP
-                    command = command_rx.recv() => {
-                        match command {
-                            Some(SessionCommand::Input(seqs)) => {
-                                let data = ht_core::command::seqs_to_bytes(&seqs, session.cursor_key_app_mode());
-                                if let Err(e) = input_tx.send(data).await {
-                                    error!("Failed to send input to PTY: {}", e);
-                                }
-                            }
-                            Some(SessionCommand::Snapshot(response_tx)) => {
-                                // Get the current terminal text and send it back
-                                let text = session.get_text();
-                                let _ = response_tx.send(text);
-                            }
-                            Some(SessionCommand::Resize(cols, rows)) => {
-                                session.resize(cols, rows);
-                            }
-                            None => {
-                                info!("Command channel closed for session {}", session_id_clone);
-                                break;
-                            }
+        // Wait for the prompt or the echoed marker, or the quiet period
+        // without either
+        let mut exit_code = None;
+        let prompt_returned = match (&self.prompt_marker, before) {
+            (Some(marker), Some(before)) => {
+                let returned = async {
+                    loop {
+                        let screen =
+                            request_snapshot(&self.command_tx, &self.snapshot_permits).await?;
+                        if screen != before && prompt_shown(&screen, marker) {
+                            return Ok::<_, HtMcpError>(());
                         }
+                        tokio::time::sleep(PROMPT_POLL_INTERVAL).await;
                     }
-
-                    // Handle WebSocket clients (for webserver)
-                    client = clients_rx.recv(), if serving => {
-                        match client {
-                            Some(client) => {
-                                info!("New WebSocket client connected to session {}", session_id_clone);
-                                client.accept(session.subscribe());
-                            }
-                            None => {
-                                info!("Client channel closed for session {}", session_id_clone);
-                                serving = false;
-                            }
-                        }
+                };
+                // A timeoutMs given with the call is a limit; the class's
+                // timeout only ever lengthens the default wait
+                let wait = match self.args.completion_timeout_ms {
+                    Some(_) => self.completion_timeout,
+                    None => PROMPT_RETURN_TIMEOUT.max(self.completion_timeout),
+                };
+                match tokio::time::timeout(wait, returned).await {
+                    Ok(result) => result.map(|_| Some(true))?,
+                    Err(_) => Some(false),
+                }
+            }
+            _ => {
+                match (&self.sentinel, output) {
+                    (Some(sentinel), Some(output)) => {
+                        exit_code = sentinel
+                            .wait(
+                                output,
+                                &self.command_tx,
+                                &self.snapshot_permits,
+                                self.completion_timeout,
+                            )
+                            .await?;
                     }
+                    _ => tokio::time::sleep(self.quiet_period).await,
                 }
+                None
             }
-        });
-
-        // Create the session info
-        let session_info = SessionInfo {
-            id: session_id.clone(),
-            internal_id,
-            created_at: std::time::SystemTime::now(),
-            web_server_url: web_server_url.clone(),
-            tunnel_url: tunnel_url.clone(),
-            is_alive: true,
-            command: command.clone(),
-            command_tx: Arc::new(command_tx),
         };
 
-        self.sessions.insert(session_id.clone(), session_info);
+        info!("Taking snapshot for session {}", session_id);
+        let screen = self.snapshot().await?;
 
-        let result = CreateSessionResult {
-            session_id,
-            message: "HT session created successfully".to_string(),
-            web_server_enabled: enable_web_server,
-            web_server_url,
-            tunnel_enabled: enable_tunnel,
-            tunnel_url,
-        };
+        let mut result = serde_json::json!({
+            "command": self.args.command,
+            "sessionId": session_id,
+            "terminator": self.terminator,
+            "output": screen,
+            "commandClass": self.class_name,
+            "timeoutMs": self.completion_timeout.as_millis() as u64,
+            "quietPeriodMs": self.quiet_period.as_millis() as u64
+        });
+        if let Some(returned) = prompt_returned {
+            result["promptReturned"] = serde_json::json!(returned);
+        }
+        if let Some(sentinel) = &self.sentinel {
+            result["output"] = serde_json::json!(sentinel.strip(&screen, &self.args.command));
+            result["exitCode"] = serde_json::json!(exit_code);
+            result["timedOut"] = serde_json::json!(exit_code.is_none());
+        }
+        Ok(result)
+    }
 
-        info!("Created HT session with native webserver: {:?}", result);
-        Ok(serde_json::to_value(result)?)
+    /// The current screen
+    async fn snapshot(&self) -> Result<String> {
+        request_snapshot(&self.command_tx, &self.snapshot_permits)
+            .await
+            .map_err(|e| self.exit.explain(&self.args.session_id, e))
+    }
+}
+
+impl SessionInfo {
+    pub fn web_server_url(&self) -> Option<String> {
+        self.web_server.as_ref().map(|status| status.url())
     }
 
-    /// Find an available port for the webserver
-    /// Uses port range 3618-3999 to avoid conflicts with common development servers
-    /// (Next.js: 3000, React: 3001, etc.)
-    async fn find_available_port(&self) -> Result<u16> {
-        for port in 3618..3999 {
-            if let Ok(listener) = TcpListener::bind(format!("127.0.0.1:{}", port)) {
-                drop(listener);
-                return Ok(port);
-            }
+    /// The session's entry in `list_sessions`
+    pub fn summary(&self) -> serde_json::Value {
+        serde_json::json!({
+            "id": self.id,
+            "isAlive": self.is_alive(),
+            "createdAt": self.created_at.duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default().as_secs(),
+            "command": self.command,
+            "effectiveCommand": self.effective_command,
+            "cwd": self.config.cwd,
+            "webServerUrl": self.web_server_url(),
+            "webServerRestarts": self.web_server.as_ref().map(|status| status.restarts()),
+            "webServerFailed": self.web_server.as_ref().map(|status| status.failed()),
+            "tunnelUrl": self.tunnel_url,
+            "tunnelId": self.tunnel_id,
+            "connections": self.connection_stats.as_ref().map(|stats| stats.summary()),
+            "outputFlood": self.flood_stats.summary(),
+            "runAs": self.run_as,
+            "labelColor": self.config.label_color,
+            "labelIcon": self.config.label_icon,
+            "tempDir": self.temp_dir.as_ref().map(|dir| dir.path()),
+            "snapshotIntervalMs": self.config.snapshot_interval_ms,
+            "traceMode": self.config.trace_mode,
+            "startup": self.startup.summary(),
+            "colorProfile": self.config.color_profile,
+            "cpuAffinity": self.config.cpu_affinity,
+            "inputProfile": self.config.input_profile,
+            "groupId": self.config.group_id,
+            "tags": self.config.tags,
+            "tailPath": self.tail.as_ref().map(|tail| &tail.path),
+            "retention": self.config.retention.report(&self.history, self.snapshot_history.as_deref()),
+            "lease": self.lease.as_ref().map(Lease::summary),
+            "lock": self.lock.as_ref().filter(|lock| !lock.is_expired()).map(InteractionLock::summary),
+            "readOnly": self.config.read_only,
+            "lastInputAt": self.last_input_at.map(|at| at.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)),
+            "exitCode": self.child.exit_code(),
+            "migratedFrom": self.migrated_from.as_ref().map(|m| &m.source_session_id),
+            "createdBy": self.created_by
+        })
+    }
+
+    /// Clears the interaction lock if its TTL ran out, returning its holder
+    fn expire_lock(&mut self) -> Option<String> {
+        if !self.lock.as_ref().is_some_and(InteractionLock::is_expired) {
+            return None;
         }
-        Err(HtMcpError::Internal("No available ports found".to_string()))
+        self.lock.take().map(|lock| lock.holder().to_string())
     }
 
-    pub async fn send_keys(&mut self, args: SendKeysArgs) -> Result<serde_json::Value> {
-        let session = self
-            .sessions
-            .get(&args.session_id)
-            .ok_or_else(|| HtMcpError::SessionNotFound(args.session_id.clone()))?;
+    /// Whether the session's process is still running; false once its PTY
+    /// output has ended
+    pub fn is_alive(&self) -> bool {
+        self.exit.exit().is_none()
+    }
 
-        // Convert keys to InputSeq format using HT's native key parsing
-        let input_seqs: Vec<ht_core::command::InputSeq> = args
-            .keys
-            .iter()
-            .map(|key| ht_core::api::stdio::parse_key(key.clone()))
-            .collect();
+    /// Fails with `SessionExited` once the session has exited, so input is
+    /// refused rather than dropped
+    pub fn ensure_alive(&self) -> Result<()> {
+        match self.exit.exit() {
+            Some(exit) => Err(HtMcpError::SessionExited(format!(
+                "{}: {}",
+                self.id,
+                exit.describe()
+            ))),
+            None => Ok(()),
+        }
+    }
 
-        // Send keys via the command channel
-        session
-            .command_tx
-            .send(SessionCommand::Input(input_seqs))
+    /// The current screen text
+    pub async fn snapshot(&self) -> Result<String> {
+        self.text_snapshot().await.map(|snapshot| snapshot.text)
+    }
+
+    /// Like [`Self::snapshot`], with the cursor
+    pub async fn text_snapshot(&self) -> Result<TextSnapshot> {
+        request_text_snapshot(&self.command_tx, &self.snapshot_permits)
             .await
-            .map_err(|e| HtMcpError::Internal(format!("Failed to send keys: {}", e)))?;
+            .map_err(|e| self.exit.explain(&self.id, e))
+    }
 
-        info!("Sent keys {:?} to session {}", args.keys, args.session_id);
+    /// Like [`Self::snapshot`], keeping the screen's styling
+    pub async fn styled_snapshot(&self, format: StyledFormat) -> Result<StyledSnapshot> {
+        request_styled_snapshot(&self.command_tx, &self.snapshot_permits, format)
+            .await
+            .map_err(|e| self.exit.explain(&self.id, e))
+    }
 
-        Ok(serde_json::json!({
-            "success": true,
-            "message": format!("Keys sent successfully to session {}", args.session_id),
-            "sessionId": args.session_id,
-            "keys": args.keys
-        }))
+    /// The screen's cells, for rendering it as an image
+    pub async fn screen_cells(&self) -> Result<StyledScreen> {
+        request_screen_cells(&self.command_tx, &self.snapshot_permits)
+            .await
+            .map_err(|e| self.exit.explain(&self.id, e))
     }
+}
 
-    pub async fn take_snapshot(&self, args: TakeSnapshotArgs) -> Result<serde_json::Value> {
-        let session = self
-            .sessions
-            .get(&args.session_id)
-            .ok_or_else(|| HtMcpError::SessionNotFound(args.session_id.clone()))?;
+/// How long a new tail session gets to print the first lines of a file
+const TAIL_FIRST_LINES_WAIT: Duration = Duration::from_secs(2);
 
-        info!("Taking snapshot for session {}", args.session_id);
+/// Default terminal size for new sessions
+pub const DEFAULT_COLS: u16 = 120;
+pub const DEFAULT_ROWS: u16 = 40;
 
-        // Create a response channel for the snapshot
-        let (response_tx, response_rx) = oneshot::channel();
+/// Most columns or rows `max_cols` and `max_rows` may allow
+pub const MAX_TERMINAL_SIZE: usize = 1000;
 
-        // Send snapshot command with response channel
-        session
-            .command_tx
-            .send(SessionCommand::Snapshot(response_tx))
-            .await
-            .map_err(|e| HtMcpError::Internal(format!("Failed to send snapshot command: {}", e)))?;
+/// Longest `timeoutSecs` of `ht_create_tunnel`
+pub const MAX_TUNNEL_TIMEOUT_SECS: u64 = 300;
 
-        // Wait for the response with a timeout
-        let snapshot = tokio::time::timeout(tokio::time::Duration::from_secs(5), response_rx)
-            .await
-            .map_err(|_| HtMcpError::Internal("Snapshot request timed out".to_string()))?
-            .map_err(|e| HtMcpError::Internal(format!("Failed to receive snapshot: {}", e)))?;
+/// Ports web servers are given, clear of common development servers
+/// (Next.js: 3000, React: 3001, etc.)
+pub const WEB_SERVER_PORTS: std::ops::Range<u16> = 3618..3999;
 
-        info!(
-            "Received snapshot for session {}: {} chars",
-            args.session_id,
-            snapshot.len()
-        );
+/// Longest accepted `labelIcon`, in characters
+const MAX_LABEL_ICON_LEN: usize = 32;
 
-        Ok(serde_json::json!({
-            "sessionId": args.session_id,
-            "snapshot": snapshot
-        }))
-    }
+/// Snapshots returned by `get_snapshot_history` unless `limit` is given
+const DEFAULT_SNAPSHOT_HISTORY_LIMIT: usize = 10;
 
-    pub async fn execute_command(&mut self, args: ExecuteCommandArgs) -> Result<serde_json::Value> {
-        // Send command
-        self.send_keys(SendKeysArgs {
-            session_id: args.session_id.clone(),
-            keys: vec![args.command.clone()],
-        })
-        .await?;
+/// Entries per page of `list_sessions` and `list_tunnels` unless `pageSize`
+/// is given
+const DEFAULT_PAGE_SIZE: usize = 20;
 
-        // Send Enter
-        self.send_keys(SendKeysArgs {
-            session_id: args.session_id.clone(),
-            keys: vec!["Enter".to_string()],
-        })
-        .await?;
+/// How long `autoDetectPrompt` waits for the shell to show the marker
+const PROMPT_READY_TIMEOUT: Duration = Duration::from_secs(10);
 
-        // Wait for command to execute
-        tokio::time::sleep(tokio::time::Duration::from_millis(1000)).await;
+/// How long `execute_command` waits for the prompt to come back before
+/// returning the screen as it is, unless the call gives `timeoutMs`
+const PROMPT_RETURN_TIMEOUT: Duration = Duration::from_secs(60);
 
-        // Take snapshot
-        let snapshot_result = self
-            .take_snapshot(TakeSnapshotArgs {
-                session_id: args.session_id.clone(),
-            })
-            .await?;
+const PROMPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
 
-        Ok(serde_json::json!({
-            "command": args.command,
-            "sessionId": args.session_id,
-            "output": snapshot_result["snapshot"]
-        }))
-    }
+/// How long an idempotency key maps to the session it created
+pub const IDEMPOTENCY_KEY_TTL: Duration = Duration::from_secs(60);
 
-    pub async fn list_sessions(&self) -> Result<serde_json::Value> {
-        let sessions: Vec<serde_json::Value> = self
-            .sessions
-            .values()
-            .map(|session| {
-                serde_json::json!({
-                    "id": session.id,
-                    "isAlive": session.is_alive,
-                    "createdAt": session.created_at.duration_since(std::time::UNIX_EPOCH)
-                        .unwrap_or_default().as_secs(),
-                    "command": session.command,
-                    "webServerUrl": session.web_server_url,
-                    "tunnelUrl": session.tunnel_url
-                })
-            })
-            .collect();
+/// How long a closed session's process has to exit after SIGHUP before it
+/// is killed
+const CHILD_HANG_UP_GRACE: Duration = Duration::from_secs(2);
 
-        Ok(serde_json::json!({
-            "sessions": sessions,
-            "count": sessions.len()
-        }))
-    }
+/// Longest `shutdown` waits for the sessions' processes to exit
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
-    pub async fn close_session(&mut self, args: CloseSessionArgs) -> Result<serde_json::Value> {
-        let session = self
-            .sessions
-            .remove(&args.session_id)
-            .ok_or_else(|| HtMcpError::SessionNotFound(args.session_id.clone()))?;
+pub struct SessionManager {
+    config: HtMcpConfig,
+    sessions: HashMap<String, SessionInfo>,
+    tunnel_manager: TunnelManager,
+    events: EventBus,
+    storage: StorageManager,
+    screen_classifier: ScreenClassifier,
+    annotator: Annotator,
+    /// Default budgets for `execute_command`, shared with the server for
+    /// the call's timeout
+    command_classifier: Arc<CommandClassifier>,
+    /// Aliases `execute_command` and `parallel_execute` expand, shared with
+    /// the server so a call's timeout comes from the expanded command's class
+    aliases: CommandAliases,
+    process_sampler: ProcessSampler,
+    /// Last `resource_report` result and when it was taken
+    resource_report: Option<(Instant, serde_json::Value)>,
+    /// Set once `start_drain` was called; new sessions are refused from then on
+    drain: Option<Drain>,
+    /// Idempotency key -> (session id, time the key was recorded)
+    idempotency_cache: HashMap<String, (String, Instant)>,
+    /// Idle sessions for `create_session` to hand out; not in `sessions`
+    /// until adopted
+    warm_pool: WarmPool<SessionInfo>,
+    /// History downloads in progress, shared with the management server
+    downloads: HistoryDownloads,
+    /// Recent session startup times, for `ht_stats`
+    startup_samples: Arc<Mutex<StartupSamples>>,
+    /// Web server ports held by `reserve_port` until a session claims them
+    reserved_ports: PortReservations,
+    /// Provenance blocks handed out, for `verify_snapshot`
+    provenance: ProvenanceLog,
+    /// Where connection guards send what web viewers type
+    viewer_input_tx: mpsc::Sender<ViewerInput>,
+    /// Taken by the server, which feeds it back through
+    /// `prepare_viewer_input`
+    viewer_input_rx: Option<mpsc::Receiver<ViewerInput>>,
+    /// Stage at which `create_session` fails artificially
+    #[cfg(test)]
+    fail_at_stage: Option<CreateStage>,
+}
 
-        // Close the command channel to trigger session shutdown
-        drop(session.command_tx);
+/// A tunnel left on the port its session's web server moved away from,
+/// taken out of the manager so [`TunnelRetarget::run`] can replace it
+/// without holding the manager: starting a tunnel takes seconds
+pub struct TunnelRetarget {
+    session_id: String,
+    old: DetachedTunnel,
+    /// The URLs of the old tunnel, carried over to its replacement
+    url_history: Vec<(String, SystemTime)>,
+    config: TunnelConfig,
+}
 
-        info!("Closed session {}", args.session_id);
+/// A tunnel replaced by [`TunnelRetarget::run`], for
+/// [`SessionManager::finish_tunnel_retarget`] to hand to its session
+pub struct RetargetedTunnel {
+    session_id: String,
+    url_history: Vec<(String, SystemTime)>,
+    replacement: Result<DetachedTunnel>,
+}
 
-        Ok(serde_json::json!({
-            "success": true,
-            "message": format!("Session {} closed successfully", args.session_id)
-        }))
+impl TunnelRetarget {
+    /// Stops the old tunnel and starts one on the web server's new port
+    pub async fn run(self) -> RetargetedTunnel {
+        if let Err(e) = self.old.stop().await {
+            warn!("Failed to stop tunnel: {}", e);
+        }
+        RetargetedTunnel {
+            session_id: self.session_id,
+            url_history: self.url_history,
+            replacement: TunnelManager::start(self.config).await,
+        }
     }
 }
 
-/// Creates a Winsize struct with platform-appropriate fields
-/// This function abstracts away platform differences in the Winsize struct
-fn create_winsize(cols: u16, rows: u16) -> Winsize {
-    #[cfg(unix)]
-    {
-        Winsize {
-            ws_col: cols,
-            ws_row: rows,
-            ws_xpixel: 0,
-            ws_ypixel: 0,
+/// Tunnels the pool is short of, to be started by [`TunnelRefill::run`]
+/// without holding the manager
+pub struct TunnelRefill {
+    count: usize,
+    config: TunnelConfig,
+    /// Pooled tunnels are named tunnels when this is enabled
+    named: NamedTunnelConfig,
+}
+
+impl TunnelRefill {
+    /// Starts the tunnels, each forwarding to a relay of its own. Stops at
+    /// the first failure, as the next attempt would most likely fail too.
+    pub async fn run(self) -> Vec<DetachedTunnel> {
+        let mut started = Vec::with_capacity(self.count);
+        for _ in 0..self.count {
+            let mut config = self.config.clone();
+            if let Some(named) = self.named.for_pool() {
+                config = config.with_named(named);
+            }
+            match start_pooled_tunnel(config).await {
+                Ok(tunnel) => started.push(tunnel),
+                Err(e) => {
+                    warn!("Failed to start a pooled tunnel: {}", e);
+                    break;
+                }
+            }
         }
+        started
     }
+}
 
-    #[cfg(windows)]
-    {
-        Winsize {
-            ws_col: cols,
-            ws_row: rows,
+/// Sessions the warm pool is short of, to be started by
+/// [`WarmPoolRefill::run`] without holding the manager
+pub struct WarmPoolRefill {
+    count: usize,
+    args: CreateSessionArgs,
+    /// Pooled sessions have no web server or tunnel, so a manager without
+    /// sessions sharing the real one's config can start them
+    spawner: SessionManager,
+}
+
+impl WarmPoolRefill {
+    /// Starts the sessions. Stops at the first failure, as the next attempt
+    /// would most likely fail too.
+    pub async fn run(mut self) -> Vec<SessionInfo> {
+        let mut started = Vec::with_capacity(self.count);
+        for _ in 0..self.count {
+            match self.spawner.spawn_session(&self.args, None).await {
+                Ok(session) => started.push(session),
+                Err(e) => {
+                    warn!("Failed to start a warm pool session: {}", e);
+                    break;
+                }
+            }
+        }
+        started
+    }
+}
+
+async fn start_pooled_tunnel(mut config: TunnelConfig) -> Result<DetachedTunnel> {
+    let relay = Relay::bind().await?;
+    config.port = relay.port();
+    // An idle relay forwards nowhere, so every check would fail
+    config.verify_on_create = false;
+    Ok(TunnelManager::start(config).await?.with_relay(relay))
+}
+
+/// Tunnel that session creation fails without, for
+/// `create_session_with_tunnel`
+struct RequiredTunnel {
+    provider: Option<String>,
+    /// Upper bound on waiting for the tunnel's public URL
+    timeout: Option<Duration>,
+}
+
+/// Handles to a session whose creation stages all completed
+struct StartedSession {
+    web_server: Option<Arc<WebServerStatus>>,
+    web_supervisor: Option<AbortHandle>,
+    tunnel_url: Option<String>,
+    tunnel_id: Option<String>,
+    command_tx: mpsc::Sender<SessionCommand>,
+    stream_tx: broadcast::Sender<Bytes>,
+    child: PtyChild,
+    activity: Arc<OutputActivity>,
+    flood_stats: Arc<FloodStats>,
+    memory_cgroup: Option<MemoryCgroup>,
+    connection_stats: Option<Arc<ConnectionStats>>,
+    connected_clients: ConnectedClients,
+    run_as: Option<String>,
+    temp_dir: Option<SessionTempDir>,
+    snapshot_history: Option<Arc<SnapshotHistory>>,
+    metrics_history: MetricsHistory,
+    startup: Arc<StartupTiming>,
+    exit: Arc<ExitWaiters>,
+    counters: Arc<SessionCounters>,
+    recording: Option<Recording>,
+}
+
+impl SessionManager {
+    pub fn new() -> Self {
+        Self::with_config(HtMcpConfig::default())
+    }
+
+    pub fn with_config(mut config: HtMcpConfig) -> Self {
+        let events = EventBus::new();
+        // `HtMcpConfig::from_toml` already rejects bad rules; this only
+        // catches configs built in code
+        let screen_classifier = ScreenClassifier::new(&config.screen_rules).unwrap_or_else(|e| {
+            warn!("Ignoring configured screen rules: {}", e);
+            ScreenClassifier::default()
+        });
+        let annotator = Annotator::new(&config.annotation_patterns).unwrap_or_else(|e| {
+            warn!("Ignoring configured annotation patterns: {}", e);
+            Annotator::default()
+        });
+        let command_classifier =
+            CommandClassifier::new(&config.command_classes).unwrap_or_else(|e| {
+                warn!("Ignoring configured command classes: {}", e);
+                CommandClassifier::default()
+            });
+        let aliases = CommandAliases::new(config.aliases.clone()).unwrap_or_else(|e| {
+            warn!("Ignoring configured aliases: {}", e);
+            CommandAliases::default()
+        });
+        if let Err(e) = config.retention.validate() {
+            warn!("Ignoring configured retention policy: {}", e);
+            config.retention = RetentionPolicy::default();
+        }
+        if let Err(e) = config.output_flood.validate() {
+            warn!("Ignoring configured output flood thresholds: {}", e);
+            config.output_flood = FloodConfig::default();
+        }
+        if config.concurrent_snapshots_per_session == 0 {
+            warn!("Ignoring concurrent_snapshots_per_session = 0");
+            config.concurrent_snapshots_per_session = DEFAULT_CONCURRENT_SNAPSHOTS;
+        }
+        if let Err(e) = config.warm_pool.validate() {
+            warn!("Disabling the warm pool: {}", e);
+            config.warm_pool = WarmPoolConfig::default();
+        }
+        if let Err(e) = config.tunnel_pool.validate() {
+            warn!("Disabling the tunnel pool: {}", e);
+            config.tunnel_pool = TunnelPoolConfig::default();
+        }
+        if let Err(e) = config.named_tunnel.validate() {
+            warn!("Disabling named tunnels: {}", e);
+            config.named_tunnel = NamedTunnelConfig::default();
+        }
+        let terminal_limits = [
+            ("max_cols", &mut config.max_cols, DEFAULT_COLS),
+            ("max_rows", &mut config.max_rows, DEFAULT_ROWS),
+        ];
+        for (setting, max, default) in terminal_limits {
+            let clamped = (*max).clamp(usize::from(default), MAX_TERMINAL_SIZE);
+            if clamped != *max {
+                warn!("Clamping {} = {} to {}", setting, max, clamped);
+                *max = clamped;
+            }
+        }
+        let (viewer_input_tx, viewer_input_rx) = mpsc::channel(VIEWER_INPUT_CAPACITY);
+        Self {
+            storage: StorageManager::new(config.storage.clone(), events.clone()),
+            config,
+            sessions: HashMap::new(),
+            tunnel_manager: TunnelManager::new(),
+            events,
+            screen_classifier,
+            annotator,
+            command_classifier: Arc::new(command_classifier),
+            aliases,
+            process_sampler: ProcessSampler::default(),
+            resource_report: None,
+            drain: None,
+            idempotency_cache: HashMap::new(),
+            reserved_ports: PortReservations::default(),
+            provenance: ProvenanceLog::default(),
+            warm_pool: WarmPool::default(),
+            downloads: HistoryDownloads::default(),
+            startup_samples: Arc::default(),
+            viewer_input_tx,
+            viewer_input_rx: Some(viewer_input_rx),
+            #[cfg(test)]
+            fail_at_stage: None,
+        }
+    }
+
+    /// Disk usage accounting for files written on behalf of sessions
+    pub fn storage(&mut self) -> &mut StorageManager {
+        &mut self.storage
+    }
+
+    /// The bus session events are published on, for long-lived subscribers
+    /// that shouldn't need the manager to subscribe
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    /// Percentiles of recent session startup times, for `ht_stats`
+    pub fn startup_summary(&self) -> serde_json::Value {
+        self.startup_samples.lock().unwrap().summary()
+    }
+
+    /// History downloads, for the management server's HTTP handoff
+    pub fn downloads(&self) -> &HistoryDownloads {
+        &self.downloads
+    }
+
+    /// Subscribes to events from all sessions
+    pub fn subscribe_events(&self) -> broadcast::Receiver<SessionEvent> {
+        self.events.subscribe()
+    }
+
+    pub async fn create_session(&mut self, args: CreateSessionArgs) -> Result<serde_json::Value> {
+        self.create_session_by(args, None).await
+    }
+
+    /// Creates a session stamped with the client that asked for it
+    pub async fn create_session_by(
+        &mut self,
+        args: CreateSessionArgs,
+        created_by: Option<ClientIdentity>,
+    ) -> Result<serde_json::Value> {
+        let (result, _) = self.create(args, None, created_by).await?;
+        Ok(serde_json::to_value(result)?)
+    }
+
+    pub async fn create_session_with_tunnel(
+        &mut self,
+        args: CreateSessionWithTunnelArgs,
+    ) -> Result<serde_json::Value> {
+        self.create_session_with_tunnel_by(args, None).await
+    }
+
+    /// Creates a session with its web server and a tunnel to it in one step,
+    /// stamped with the client that asked for it. If the tunnel cannot be
+    /// created the whole session is rolled back.
+    pub async fn create_session_with_tunnel_by(
+        &mut self,
+        args: CreateSessionWithTunnelArgs,
+        created_by: Option<ClientIdentity>,
+    ) -> Result<serde_json::Value> {
+        if let Some(provider) = &args.tunnel_provider {
+            validate_provider(provider)?;
+            if OPT_IN_TUNNEL_PROVIDERS.contains(&provider.as_str())
+                && !self.config.tunnel_providers.contains(provider)
+            {
+                return Err(HtMcpError::InvalidRequest(format!(
+                    "Tunnel provider {} is not enabled; list it in the config's tunnel_providers to use it",
+                    provider
+                )));
+            }
+        }
+        let required_tunnel = RequiredTunnel {
+            provider: args.tunnel_provider,
+            timeout: args.tunnel_timeout_ms.map(Duration::from_millis),
+        };
+        let create_args = CreateSessionArgs {
+            command: args.command,
+            cwd: None,
+            env: None,
+            enable_web_server: Some(true),
+            enable_tunnel: Some(true),
+            idempotency_key: None,
+            max_memory_mb: None,
+            max_connections: None,
+            max_connections_per_ip: None,
+            idle_timeout_secs: None,
+            run_as: None,
+            label_color: None,
+            label_icon: None,
+            temp_dir: None,
+            snapshot_interval_ms: None,
+            lease_secs: None,
+            auto_detect_prompt: None,
+            trace_mode: None,
+            retention: None,
+            color_profile: None,
+            cpu_affinity: None,
+            preferred_port: None,
+            input_profile: None,
+            group_id: None,
+            tags: None,
+            cols: None,
+            rows: None,
+            output_webhook_url: None,
+            output_webhook_interval_ms: None,
+            read_only: None,
+            tunnel_name: None,
+            timeout_ms: args.timeout_ms,
+        };
+
+        let (result, tunnel_id) = self
+            .create(create_args, Some(required_tunnel), created_by)
+            .await?;
+        Ok(serde_json::json!({
+            "sessionId": result.session_id,
+            "webServerUrl": result.web_server_url,
+            "tunnelUrl": result.tunnel_url,
+            "tunnelId": tunnel_id,
+        }))
+    }
+
+    /// Creates a session, also returning the id of its tunnel when one was
+    /// started
+    async fn create(
+        &mut self,
+        args: CreateSessionArgs,
+        required_tunnel: Option<RequiredTunnel>,
+        created_by: Option<ClientIdentity>,
+    ) -> Result<(CreateSessionResult, Option<String>)> {
+        self.purge_expired_idempotency_keys();
+
+        // A retried call with a known key gets the original session back
+        if let Some(key) = &args.idempotency_key {
+            if let Some(session) = self
+                .idempotency_cache
+                .get(key)
+                .and_then(|(session_id, _)| self.sessions.get(session_id))
+            {
+                info!(
+                    "Idempotency key {} matched existing session {}",
+                    key, session.id
+                );
+                let result = CreateSessionResult {
+                    session_id: session.id.clone(),
+                    message: "HT session already created for this idempotency key".to_string(),
+                    web_server_enabled: session.web_server.is_some(),
+                    web_server_url: session.web_server_url(),
+                    tunnel_enabled: session.tunnel_url.is_some(),
+                    tunnel_url: session.tunnel_url.clone(),
+                    temp_dir: session
+                        .temp_dir
+                        .as_ref()
+                        .map(|dir| dir.path().display().to_string()),
+                    prompt_marker: session.prompt_marker.clone(),
+                    first_output_ms: session.startup.first_output_ms(),
+                    prompt_ready_ms: session.startup.prompt_ready_ms(),
+                    idempotent_replay: true,
+                    from_pool: false,
+                    warnings: Vec::new(),
+                };
+                return Ok((result, None));
+            }
+        }
+
+        self.refuse_if_draining("new sessions")?;
+        validate_labels(&args)?;
+        if args.group_id.as_deref() == Some("") {
+            return Err(HtMcpError::InvalidRequest(
+                "groupId must not be empty".to_string(),
+            ));
+        }
+        validate_snapshot_interval(&args)?;
+        self.terminal_size(&args)?;
+        OutputWebhook::from_args(&args)?;
+        self.session_retention(&args)?;
+        if args.trace_mode.unwrap_or(false) && !self.config.allow_trace_mode {
+            return Err(HtMcpError::InvalidRequest(
+                "traceMode logs everything typed into the session; set allow_trace_mode in the config to use it"
+                    .to_string(),
+            ));
+        }
+        if args.lease_secs == Some(0) {
+            return Err(HtMcpError::InvalidRequest(
+                "leaseSecs must be at least 1".to_string(),
+            ));
+        }
+        if let Some(secs) = args.lease_secs {
+            Lease::new(Duration::from_secs(secs))?;
+        }
+        if let Some(max_memory_mb) = args.max_memory_mb {
+            memory_limit_bytes(max_memory_mb)?;
+        }
+        if args.preferred_port.is_some() && !args.enable_web_server.unwrap_or(false) {
+            return Err(HtMcpError::InvalidRequest(
+                "preferredPort is the web server's port; it needs enableWebServer".to_string(),
+            ));
+        }
+        if let Some(name) = &args.tunnel_name {
+            self.validate_tunnel_name(name, &args)?;
+        }
+        let mut warnings = Vec::new();
+        if let Some(cores) = &args.cpu_affinity {
+            cpu_affinity::validate(cores, cpu_affinity::core_count())?;
+            if !cpu_affinity::is_supported() {
+                warnings.push(cpu_affinity::unsupported_warning());
+            }
+        }
+        if args.max_memory_mb.is_some() && !self.config.use_cgroup_memory_limits {
+            warnings.push(
+                "maxMemoryMb was ignored: use_cgroup_memory_limits is disabled in the server config"
+                    .to_string(),
+            );
+        }
+
+        let pooled = if self.config.warm_pool.matches(&args) {
+            self.adopt_pooled(&args)
+        } else {
+            None
+        };
+        let from_pool = pooled.is_some();
+        let mut session_info = match pooled {
+            Some(session_info) => session_info,
+            None => {
+                let session_info = self.spawn_session(&args, required_tunnel.as_ref()).await?;
+                track_recording(&mut self.storage, &session_info);
+                session_info
+            }
+        };
+        session_info.created_by = created_by;
+        let session_id = session_info.id.clone();
+        let tunnel_id = session_info.tunnel_id.clone();
+        let run_as = session_info.run_as.clone();
+        if let Some(warning) = self.named_tunnel_fallback_warning(&session_info) {
+            warnings.push(warning);
+        }
+        let mut result = CreateSessionResult {
+            session_id: session_id.clone(),
+            message: "HT session created successfully".to_string(),
+            web_server_enabled: session_info.config.enable_web_server,
+            web_server_url: session_info.web_server_url(),
+            tunnel_enabled: session_info.config.enable_tunnel,
+            tunnel_url: session_info.tunnel_url.clone(),
+            temp_dir: session_info
+                .temp_dir
+                .as_ref()
+                .map(|dir| dir.path().display().to_string()),
+            prompt_marker: None,
+            first_output_ms: session_info.startup.first_output_ms(),
+            prompt_ready_ms: None,
+            idempotent_replay: false,
+            from_pool,
+            warnings,
+        };
+
+        let created_by = session_info.created_by.clone();
+        self.sessions.insert(session_id.clone(), session_info);
+        self.events.publish(SessionEvent::SessionCreated {
+            session_id: session_id.clone(),
+            created_by,
+        });
+
+        if from_pool {
+            for command in self.config.warm_pool.init_commands.clone() {
+                let keys = SendKeysArgs {
+                    session_id: session_id.clone(),
+                    keys: vec![command, "Enter".to_string()],
+                    input_profile: Some(InputProfile::Instant),
+                    seed: None,
+                    timeout_ms: None,
+                };
+                if let Err(e) = self.send_keys(keys).await {
+                    error!("Failed to initialise pooled session {}: {}", session_id, e);
+                    self.end_session(&session_id, TerminationReason::Closed)
+                        .await?;
+                    return Err(e);
+                }
+            }
+        }
+
+        if args.auto_detect_prompt.unwrap_or(false) {
+            let marker = format!("HTMCP_READY_{}> ", Uuid::new_v4().simple());
+            if let Err(e) = self.inject_prompt_marker(&session_id, &marker).await {
+                error!("Failed to set the prompt of session {}: {}", session_id, e);
+                self.end_session(&session_id, TerminationReason::Closed)
+                    .await?;
+                return Err(e);
+            }
+            result.prompt_marker = Some(marker);
+            let startup = &self.sessions[&session_id].startup;
+            // A pooled session was spawned long before it was asked for
+            if !from_pool {
+                startup.record_prompt_ready();
+                result.prompt_ready_ms = startup.prompt_ready_ms();
+            }
+            result.first_output_ms = startup.first_output_ms();
+        }
+
+        if let Some(key) = args.idempotency_key {
+            self.idempotency_cache
+                .insert(key, (session_id.clone(), Instant::now()));
+        }
+
+        info!(
+            "Created HT session with native webserver: {:?} (running as {})",
+            result,
+            run_as.as_deref().unwrap_or("server user")
+        );
+        Ok((result, tunnel_id))
+    }
+
+    /// Starts a session for `args` without registering it or tracking its
+    /// recording; creation errors are rolled back before returning
+    async fn spawn_session(
+        &mut self,
+        args: &CreateSessionArgs,
+        required_tunnel: Option<&RequiredTunnel>,
+    ) -> Result<SessionInfo> {
+        let retention = self.session_retention(args)?;
+        let cwd = self.resolve_cwd(args.cwd.as_deref())?;
+        let env: BTreeMap<String, String> =
+            args.env.clone().unwrap_or_default().into_iter().collect();
+        validate_env(&env)?;
+
+        let session_id = Uuid::new_v4().to_string();
+        let command = args
+            .command
+            .clone()
+            .unwrap_or_else(|| vec!["bash".to_string()]);
+        let effective_command = self.config.effective_command(&command);
+        let enable_web_server = args.enable_web_server.unwrap_or(false);
+        let enable_tunnel = args.enable_tunnel.unwrap_or(false);
+        let internal_id = Uuid::new_v4();
+
+        let mut rollback = Rollback::new();
+        let started = self
+            .start_session(
+                &session_id,
+                &effective_command,
+                cwd.clone(),
+                args,
+                required_tunnel,
+                &mut rollback,
+            )
+            .await;
+        let StartedSession {
+            web_server,
+            web_supervisor,
+            tunnel_url,
+            tunnel_id,
+            command_tx,
+            stream_tx,
+            child,
+            activity,
+            flood_stats,
+            memory_cgroup,
+            connection_stats,
+            connected_clients,
+            run_as,
+            temp_dir,
+            snapshot_history,
+            metrics_history,
+            startup,
+            exit,
+            counters,
+            recording,
+        } = match started {
+            Ok(started) => {
+                rollback.commit();
+                started
+            }
+            Err(e) => {
+                error!("Failed to create session {}: {}", session_id, e);
+                rollback.unwind(&mut self.tunnel_manager).await;
+                return Err(e);
+            }
+        };
+
+        let config = SessionConfig {
+            command: command.clone(),
+            cwd: cwd.map(|cwd| cwd.display().to_string()),
+            env,
+            redacted_env: Vec::new(),
+            enable_web_server,
+            enable_tunnel,
+            max_memory_mb: args.max_memory_mb,
+            connection_limits: enable_web_server.then(|| self.connection_limits(args)),
+            run_as: args.run_as.clone(),
+            label_color: args.label_color.clone(),
+            label_icon: args.label_icon.clone(),
+            temp_dir: temp_dir.is_some(),
+            snapshot_interval_ms: args.snapshot_interval_ms,
+            lease_secs: args.lease_secs,
+            auto_detect_prompt: args.auto_detect_prompt.unwrap_or(false),
+            trace_mode: args.trace_mode.unwrap_or(false),
+            retention,
+            color_profile: args.color_profile,
+            cpu_affinity: args.cpu_affinity.clone(),
+            input_profile: args.input_profile.unwrap_or_default(),
+            group_id: args.group_id.clone(),
+            tags: session_tags(args),
+            cols: args.cols,
+            rows: args.rows,
+            output_webhook_url: args.output_webhook_url.clone(),
+            output_webhook_interval_ms: args.output_webhook_interval_ms,
+            read_only: args.read_only.unwrap_or(false),
+            tunnel_name: args.tunnel_name.clone(),
+        };
+
+        Ok(SessionInfo {
+            id: session_id.clone(),
+            internal_id,
+            created_at: std::time::SystemTime::now(),
+            web_server,
+            web_supervisor,
+            tunnel_url,
+            tunnel_id,
+            command: command.clone(),
+            effective_command,
+            command_tx: Arc::new(command_tx),
+            stream_tx,
+            child,
+            activity,
+            flood_stats,
+            memory_cgroup,
+            connection_stats,
+            connected_clients,
+            run_as,
+            temp_dir,
+            snapshot_history,
+            metrics_history,
+            startup,
+            exit,
+            counters,
+            lease: args
+                .lease_secs
+                .map(|secs| Lease::new(Duration::from_secs(secs)))
+                .transpose()?,
+            lock: None,
+            last_input_at: None,
+            tail: None,
+            config,
+            history: Vec::new(),
+            prompt_marker: None,
+            migrated_from: None,
+            span: crate::telemetry::session_span(&session_id, &command),
+            snapshot_permits: Arc::new(Semaphore::new(
+                self.config.concurrent_snapshots_per_session,
+            )),
+            created_by: None,
+            input_lock: Arc::default(),
+            tool_probe: ToolProbe::default(),
+            recording,
+        })
+    }
+
+    /// Runs the creation stages in order, registering an undo action for each
+    /// completed stage. Errors name the stage that failed.
+    async fn start_session(
+        &mut self,
+        session_id: &str,
+        command: &[String],
+        cwd: Option<PathBuf>,
+        args: &CreateSessionArgs,
+        required_tunnel: Option<&RequiredTunnel>,
+        rollback: &mut Rollback,
+    ) -> Result<StartedSession> {
+        let enable_web_server = args.enable_web_server.unwrap_or(false);
+        let enable_tunnel = args.enable_tunnel.unwrap_or(false);
+        let (cols, rows) = self.terminal_size(args)?;
+        // Validated up front so a bad runAs is refused before anything starts
+        let mut spawn_options = SpawnOptions {
+            run_as: args.run_as.as_ref().map(RunAsUser::resolve).transpose()?,
+            env: self
+                .config
+                .session_env
+                .iter()
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect(),
+            cwd,
+            cgroup_procs: None,
+            cpu_affinity: args
+                .cpu_affinity
+                .clone()
+                .filter(|_| cpu_affinity::is_supported()),
+        };
+        if let Some(profile) = args.color_profile {
+            spawn_options.env.extend(
+                profile
+                    .env()
+                    .iter()
+                    .map(|&(name, value)| (name.to_string(), value.to_string())),
+            );
+        }
+        // In name order, so the child sees the same environment every time
+        let mut env: Vec<_> = args.env.iter().flatten().collect();
+        env.sort();
+        spawn_options.env.extend(
+            env.into_iter()
+                .map(|(name, value)| (name.clone(), value.clone())),
+        );
+
+        // Create channels for communication
+        let (input_tx, input_rx) = mpsc::channel::<Vec<u8>>(1024);
+        let (output_tx, output_rx) = mpsc::channel::<Vec<u8>>(1024);
+        let (command_tx, command_rx) = mpsc::channel::<SessionCommand>(1024);
+        let (stream_tx, _) = broadcast::channel::<Bytes>(OUTPUT_STREAM_CAPACITY);
+        // Subscribed before the command starts so no output is missed
+        let webhook =
+            OutputWebhook::from_args(args)?.map(|webhook| (webhook, stream_tx.subscribe()));
+        let recording_output = self
+            .config
+            .storage
+            .recordings_dir
+            .as_ref()
+            .map(|dir| (dir.clone(), stream_tx.subscribe()));
+        let (clients_tx, clients_rx) = mpsc::channel(1);
+        let connected_clients = ConnectedClients::default();
+
+        let (web_server, web_supervisor, tunnel, connection_stats) = if enable_web_server {
+            // Listener stage
+            let listeners = match args.preferred_port {
+                Some(port) => self.preferred_port_listeners(port),
+                None => {
+                    let port = self
+                        .find_available_port()
+                        .await
+                        .map_err(|e| CreateStage::Listener.error(e))?;
+                    web_listener::bind(self.config.bind_address, port).map_err(|e| {
+                        HtMcpError::Internal(format!("Failed to bind to port {}: {}", port, e))
+                    })
+                }
+            }
+            .map_err(|e| CreateStage::Listener.error(e))?;
+            let public_addr = listeners[0]
+                .local_addr()
+                .map_err(|e| CreateStage::Listener.error(e))?;
+            let port = public_addr.port();
+            let url = web_listener::local_url(public_addr);
+            self.injected_failure(CreateStage::Listener)?;
+
+            // Web server stage: HT's native HTTP server behind the connection
+            // guard, restarted by the supervisor if either stops
+            let connection_stats = Arc::new(ConnectionStats::default());
+            let status = Arc::new(WebServerStatus::new(url.clone(), port));
+            let supervisor = WebSupervisor {
+                session_id: session_id.to_string(),
+                status: status.clone(),
+                bind_address: self.config.bind_address,
+                guard: ConnectionGuard {
+                    session_id: session_id.to_string(),
+                    limits: self.connection_limits(args),
+                    stats: connection_stats.clone(),
+                    clients: connected_clients.clone(),
+                    events: self.events.clone(),
+                    viewer_input: self.viewer_input_tx.clone(),
+                },
+                clients_tx,
+                events: self.events.clone(),
+            };
+            let serving = supervisor
+                .start(listeners)
+                .await
+                .map_err(|e| CreateStage::WebServer.error(e))?;
+            let supervisor_handle = tokio::spawn(supervisor.run(serving));
+            let web_supervisor = supervisor_handle.abort_handle();
+            rollback.abort_task(CreateStage::WebServer, supervisor_handle);
+            self.injected_failure(CreateStage::WebServer)?;
+            info!("Started HT native webserver on {}", url);
+
+            // Tunnel stage; a tunnel failure is only fatal to the session
+            // when the tunnel is required. Pooled tunnels have the default
+            // provider, so a session naming one starts its own. A pooled
+            // named tunnel gets the session's hostname routed to it.
+            let poolable = match required_tunnel {
+                Some(required) => required.provider.is_none(),
+                None => enable_tunnel,
+            } && self.config.tunnel_pool.size > 0;
+            let tunnel_name = args.tunnel_name.as_deref().unwrap_or(&session_id);
+            let pooled = if poolable {
+                let hostname = self
+                    .config
+                    .named_tunnel
+                    .for_session(tunnel_name)
+                    .ok()
+                    .flatten()
+                    .and_then(|spec| spec.hostname);
+                self.tunnel_manager
+                    .lease_pooled(port, hostname.as_deref())
+                    .await
+            } else {
+                None
+            };
+            let tunnel = if let Some(tunnel_info) = pooled {
+                info!(
+                    "Tunnel leased for session {} from the pool: {}",
+                    session_id, tunnel_info.url
+                );
+                rollback.stop_tunnel(CreateStage::Tunnel, tunnel_info.id.clone());
+                Some(tunnel_info)
+            } else if let Some(required) = required_tunnel {
+                let mut config = self.session_tunnel_config(port, tunnel_name);
+                if let Some(provider) = &required.provider {
+                    config = config.with_provider(provider.clone());
+                }
+                let created = match required.timeout {
+                    Some(timeout) => {
+                        tokio::time::timeout(timeout, self.tunnel_manager.create_tunnel(config))
+                            .await
+                            .map_err(|_| {
+                                CreateStage::Tunnel.error(format!(
+                                    "tunnel not ready within {}ms",
+                                    timeout.as_millis()
+                                ))
+                            })?
+                    }
+                    None => self.tunnel_manager.create_tunnel(config).await,
+                };
+                let tunnel_info = created.map_err(|e| CreateStage::Tunnel.error(e))?;
+                info!(
+                    "Tunnel created for session {}: {}",
+                    session_id, tunnel_info.url
+                );
+                rollback.stop_tunnel(CreateStage::Tunnel, tunnel_info.id.clone());
+                Some(tunnel_info)
+            } else if enable_tunnel {
+                match self
+                    .tunnel_manager
+                    .create_tunnel(self.session_tunnel_config(port, tunnel_name))
+                    .await
+                {
+                    Ok(tunnel_info) => {
+                        info!(
+                            "Tunnel created for session {}: {}",
+                            session_id, tunnel_info.url
+                        );
+                        rollback.stop_tunnel(CreateStage::Tunnel, tunnel_info.id.clone());
+                        Some(tunnel_info)
+                    }
+                    Err(e) => {
+                        error!("Failed to create tunnel for session {}: {}", session_id, e);
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+            self.injected_failure(CreateStage::Tunnel)?;
+
+            (
+                Some(status),
+                Some(web_supervisor),
+                tunnel,
+                Some(connection_stats),
+            )
+        } else {
+            drop(clients_tx);
+            (None, None, None, None)
+        };
+        let (tunnel_url, tunnel_id) = match tunnel {
+            Some(tunnel_info) => (Some(tunnel_info.url), Some(tunnel_info.id)),
+            None => (None, None),
+        };
+
+        // Temp dir stage
+        let temp_dir = if args.temp_dir.unwrap_or(false) {
+            let temp_dir = SessionTempDir::create(&self.config.temp_dir.root(), session_id)
+                .map_err(|e| CreateStage::TempDir.error(e))?;
+            rollback.remove_temp_dir(
+                CreateStage::TempDir,
+                temp_dir.clone(),
+                self.config.temp_dir.keep_on_error,
+            );
+            #[cfg(unix)]
+            if let Some(run_as) = &spawn_options.run_as {
+                temp_dir
+                    .set_owner(run_as.uid, run_as.gid)
+                    .map_err(|e| CreateStage::TempDir.error(e))?;
+            }
+            spawn_options.env.push((
+                SESSION_TMP_ENV_VAR.to_string(),
+                temp_dir.path().display().to_string(),
+            ));
+            Some(temp_dir)
+        } else {
+            None
+        };
+        self.injected_failure(CreateStage::TempDir)?;
+
+        // Memory limit stage; the child joins the cgroup before it execs the
+        // command, so nothing it starts escapes the limit
+        let memory_cgroup = match args.max_memory_mb {
+            Some(max_memory_mb) if self.config.use_cgroup_memory_limits => {
+                let cgroup = MemoryCgroup::create(session_id, memory_limit_bytes(max_memory_mb)?)
+                    .map_err(|e| CreateStage::MemoryLimit.error(e))?;
+                rollback.remove_cgroup(CreateStage::MemoryLimit, cgroup.clone());
+                spawn_options.cgroup_procs = Some(cgroup.procs_file());
+                Some(cgroup)
+            }
+            Some(_) => {
+                warn!(
+                    "Ignoring maxMemoryMb for session {}: use_cgroup_memory_limits is disabled",
+                    session_id
+                );
+                None
+            }
+            None => None,
+        };
+        self.injected_failure(CreateStage::MemoryLimit)?;
+
+        // PTY stage
+        let command_str = command.join(" ");
+        let startup = Arc::new(StartupTiming::new(
+            session_id,
+            Instant::now(),
+            Duration::from_millis(self.config.slow_startup_warning_ms),
+            self.startup_samples.clone(),
+            self.events.clone(),
+        ));
+        let (child, pty_future) =
+            pty::spawn(command_str, cols, rows, &spawn_options, input_rx, output_tx)
+                .map_err(|e| CreateStage::Pty.error(e))?;
+        let pty_handle = tokio::spawn(async move {
+            if let Err(e) = pty_future.await {
+                error!("PTY execution error: {}", e);
+            }
+        });
+        rollback.abort_task(CreateStage::Pty, pty_handle);
+        rollback.kill_child(CreateStage::Pty, child.clone());
+        self.injected_failure(CreateStage::Pty)?;
+
+        // Event loop stage
+        let retention = self.session_retention(args)?;
+        let snapshot_history = args.snapshot_interval_ms.map(|ms| {
+            Arc::new(SnapshotHistory::with_capacity(
+                Duration::from_millis(ms),
+                retention.snapshot_history,
+            ))
+        });
+        let activity = Arc::new(OutputActivity::default());
+        let flood_stats = Arc::new(FloodStats::default());
+        let counters = Arc::new(SessionCounters::default());
+        let exit = Arc::new(ExitWaiters::default());
+        let event_loop = EventLoop {
+            session_id: session_id.to_string(),
+            cols: usize::from(cols),
+            rows: usize::from(rows),
+            input_tx,
+            output_rx,
+            command_rx,
+            clients_rx,
+            stream_tx: stream_tx.clone(),
+            activity: activity.clone(),
+            snapshot_history: snapshot_history.clone(),
+            strip_controls_in_history: self.config.strip_controls_in_history,
+            counters: counters.clone(),
+            startup: startup.clone(),
+            child: child.clone(),
+            exit: exit.clone(),
+            flood: FloodDetector::new(
+                self.config.output_flood.clone(),
+                flood_stats.clone(),
+                Instant::now(),
+            ),
+            events: self.events.clone(),
+            trace: args.trace_mode.unwrap_or(false),
+        };
+        let event_loop_handle = tokio::spawn(event_loop.run());
+        // Give the loop a chance to run so an immediate panic is caught here.
+        // A loop that already finished cleanly just ran a short-lived command.
+        tokio::task::yield_now().await;
+        if event_loop_handle.is_finished() {
+            if let Err(e) = event_loop_handle.await {
+                return Err(CreateStage::EventLoop.error(e));
+            }
+        } else {
+            rollback.abort_task(CreateStage::EventLoop, event_loop_handle);
+        }
+        self.injected_failure(CreateStage::EventLoop)?;
+
+        // Stops by itself once the session, and with it the history, is gone
+        let metrics_history = MetricsHistory::default();
+        tokio::spawn(metrics_history::sample(
+            Arc::downgrade(&metrics_history),
+            counters.clone(),
+            activity.clone(),
+            child.pid,
+            METRICS_INTERVAL,
+        ));
+        // Also stops by itself, once the session's output ends
+        if let Some((webhook, output)) = webhook {
+            webhook.spawn(session_id.to_string(), output);
+        }
+        // Started last, so a session that failed to start leaves no file
+        let recording = match recording_output {
+            Some((dir, output)) => match Recording::start(&dir, session_id, cols, rows, output) {
+                Ok(recording) => Some(recording),
+                Err(e) => {
+                    warn!("Not recording session {}: {}", session_id, e);
+                    None
+                }
+            },
+            None => None,
+        };
+
+        Ok(StartedSession {
+            web_server,
+            web_supervisor,
+            tunnel_url,
+            tunnel_id,
+            command_tx,
+            stream_tx,
+            child,
+            activity,
+            flood_stats,
+            memory_cgroup,
+            connection_stats,
+            connected_clients,
+            run_as: spawn_options.run_as.map(|run_as| run_as.describe()),
+            temp_dir,
+            snapshot_history,
+            metrics_history,
+            startup,
+            exit,
+            counters,
+            recording,
+        })
+    }
+
+    /// The size a session is created at: `cols` and `rows`, or the default
+    fn terminal_size(&self, args: &CreateSessionArgs) -> Result<(u16, u16)> {
+        let cols = args.cols.unwrap_or(usize::from(DEFAULT_COLS));
+        let rows = args.rows.unwrap_or(usize::from(DEFAULT_ROWS));
+        self.check_terminal_size(cols, rows)?;
+        // Within `MAX_TERMINAL_SIZE`, as `with_config` clamps the limits
+        let size = |n: usize| {
+            u16::try_from(n)
+                .map_err(|_| HtMcpError::InvalidRequest(format!("{} is too large a size", n)))
+        };
+        Ok((size(cols)?, size(rows)?))
+    }
+
+    /// Refuses a terminal larger than `max_cols` by `max_rows`
+    fn check_terminal_size(&self, cols: usize, rows: usize) -> Result<()> {
+        let limits = [
+            ("cols", cols, self.config.max_cols, "max_cols"),
+            ("rows", rows, self.config.max_rows, "max_rows"),
+        ];
+        for (name, value, max, setting) in limits {
+            if !(1..=max).contains(&value) {
+                return Err(HtMcpError::InvalidRequest(format!(
+                    "{} must be between 1 and {} (the server's {}), got {}",
+                    name, max, setting, value
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Tunnel settings for a session web server on `port`
+    fn tunnel_config(&self, port: u16) -> TunnelConfig {
+        let mut config = TunnelConfig::new(port)
+            .with_log_cloudflared_output(self.config.log_cloudflared_output)
+            .with_verify_on_create(self.config.verify_tunnels);
+        if let Some(level) = &self.config.cloudflared_log_level {
+            config = config.with_cloudflared_log_level(level.clone());
+        }
+        if let Some(host_key) = &self.config.localhost_run_host_key {
+            config = config.with_localhost_run_host_key(host_key.clone());
+        }
+        config
+    }
+
+    /// Tunnel settings for the web server of the session whose tunnel is
+    /// called `tunnel_name`: with named tunnels configured, its named tunnel
+    fn session_tunnel_config(&self, port: u16, tunnel_name: &str) -> TunnelConfig {
+        let config = self.tunnel_config(port);
+        match self.config.named_tunnel.for_session(tunnel_name) {
+            Ok(Some(spec)) => config.with_named(spec),
+            Ok(None) => config,
+            Err(e) => {
+                warn!("Starting a quick tunnel instead of a named tunnel: {}", e);
+                config
+            }
+        }
+    }
+
+    /// Checks `tunnelName`: a valid name, for a session with a named
+    /// tunnel, and not the name of another live session's tunnel, which
+    /// would take that tunnel over
+    fn validate_tunnel_name(&self, name: &str, args: &CreateSessionArgs) -> Result<()> {
+        named::validate_session_name(name)?;
+        if !self.config.named_tunnel.is_enabled() {
+            return Err(HtMcpError::InvalidRequest(
+                "tunnelName names a named tunnel; set [named_tunnel] hostname_template in the config to use it"
+                    .to_string(),
+            ));
+        }
+        if !args.enable_tunnel.unwrap_or(false) {
+            return Err(HtMcpError::InvalidRequest(
+                "tunnelName names the session's tunnel; it needs enableTunnel".to_string(),
+            ));
+        }
+        let taken_by = self.sessions.values().find(|session| {
+            session.config.enable_tunnel
+                && session.config.tunnel_name.as_deref().unwrap_or(&session.id) == name
+        });
+        if let Some(session) = taken_by {
+            return Err(HtMcpError::InvalidRequest(format!(
+                "tunnelName '{}' is already used by session {}",
+                name, session.id
+            )));
+        }
+        Ok(())
+    }
+
+    /// A warning for a session that was to get a named tunnel but got a
+    /// quick tunnel, whose URL changes when it restarts
+    fn named_tunnel_fallback_warning(&mut self, session: &SessionInfo) -> Option<String> {
+        if !self.config.named_tunnel.is_enabled() {
+            return None;
+        }
+        let tunnel = self
+            .tunnel_manager
+            .get_tunnel(session.tunnel_id.as_ref()?)?;
+        (!tunnel.hostname_stable).then(|| {
+            format!(
+                "The named tunnel could not be set up, so the session has a quick tunnel ({}) whose URL changes when it restarts; see the server log",
+                tunnel.url
+            )
+        })
+    }
+
+    /// The server's retention policy with the session's overrides
+    fn session_retention(&self, args: &CreateSessionArgs) -> Result<RetentionPolicy> {
+        let limits = &self.config.retention;
+        limits.with_overrides(&args.retention.unwrap_or_default(), limits)
+    }
+
+    /// The configured connection limits with the session's overrides applied
+    fn connection_limits(&self, args: &CreateSessionArgs) -> ConnectionLimits {
+        let defaults = &self.config.connection_limits;
+        ConnectionLimits {
+            max_connections: args.max_connections.unwrap_or(defaults.max_connections),
+            max_connections_per_ip: args
+                .max_connections_per_ip
+                .unwrap_or(defaults.max_connections_per_ip),
+            idle_timeout_secs: args.idle_timeout_secs.unwrap_or(defaults.idle_timeout_secs),
+        }
+    }
+
+    /// The directory a new session starts in: `cwd` resolved against the
+    /// workspace root, or the workspace root itself. `None` leaves the
+    /// server's own working directory.
+    fn resolve_cwd(&self, cwd: Option<&str>) -> Result<Option<PathBuf>> {
+        let workspace_root = self.config.workspace_root.as_deref();
+        let dir = match (cwd, workspace_root) {
+            (Some(cwd), Some(root)) => root.join(cwd),
+            (Some(cwd), None) => PathBuf::from(cwd),
+            (None, Some(root)) => root.to_path_buf(),
+            (None, None) => return Ok(None),
+        };
+        // Absolute `cwd`s replace the root in `join`
+        let dir = dir.canonicalize().map_err(|e| {
+            HtMcpError::InvalidRequest(format!("Invalid cwd {}: {}", dir.display(), e))
+        })?;
+        if !dir.is_dir() {
+            return Err(HtMcpError::InvalidRequest(format!(
+                "Invalid cwd {}: not a directory",
+                dir.display()
+            )));
+        }
+        Ok(Some(dir))
+    }
+
+    /// Fails creation at the configured stage so tests can exercise rollback
+    fn injected_failure(&self, stage: CreateStage) -> Result<()> {
+        #[cfg(test)]
+        if self.fail_at_stage == Some(stage) {
+            return Err(stage.error("injected failure"));
+        }
+        let _ = stage;
+        Ok(())
+    }
+
+    /// Drops idempotency keys older than `IDEMPOTENCY_KEY_TTL`
+    pub fn purge_expired_idempotency_keys(&mut self) {
+        self.idempotency_cache
+            .retain(|_, (_, recorded_at)| recorded_at.elapsed() < IDEMPOTENCY_KEY_TTL);
+    }
+
+    /// Find an available port for the webserver in [`WEB_SERVER_PORTS`],
+    /// probing the address it will be bound on and skipping reserved ports
+    async fn find_available_port(&self) -> Result<u16> {
+        for port in WEB_SERVER_PORTS {
+            if self.reserved_ports.contains(port) {
+                continue;
+            }
+            if let Ok(listeners) = web_listener::bind(self.config.bind_address, port) {
+                drop(listeners);
+                return Ok(port);
+            }
+        }
+        Err(HtMcpError::Internal("No available ports found".to_string()))
+    }
+
+    /// Public listeners on `port` for a session created with
+    /// `preferredPort`: the reservation's, or bound now if it is not reserved
+    fn preferred_port_listeners(&mut self, port: u16) -> Result<Vec<std::net::TcpListener>> {
+        if let Some(listeners) = self.reserved_ports.take(port) {
+            info!("Using reserved port {}", port);
+            return Ok(listeners);
+        }
+        web_listener::bind(self.config.bind_address, port).map_err(|e| {
+            HtMcpError::Internal(format!(
+                "Failed to bind to port {}: {}; reserve it first with ht_reserve_port",
+                port, e
+            ))
+        })
+    }
+
+    /// Binds a free web server port and holds it for `ttlMs`, so a session
+    /// created with it as `preferredPort` is sure to get it
+    pub fn reserve_port(&mut self, args: ReservePortArgs) -> Result<serde_json::Value> {
+        self.refuse_if_draining("port reservations")?;
+        let ttl = match args.ttl_ms {
+            None => DEFAULT_RESERVATION_TTL,
+            Some(0) => {
+                return Err(HtMcpError::InvalidRequest(
+                    "ttlMs must be at least 1".to_string(),
+                ))
+            }
+            Some(ms) if Duration::from_millis(ms) > MAX_RESERVATION_TTL => {
+                return Err(HtMcpError::InvalidRequest(format!(
+                    "ttlMs must be at most {}",
+                    MAX_RESERVATION_TTL.as_millis()
+                )))
+            }
+            Some(ms) => Duration::from_millis(ms),
+        };
+        self.reserved_ports.purge_expired();
+        if self.reserved_ports.len() >= MAX_RESERVATIONS {
+            return Err(HtMcpError::ResourceExhausted(format!(
+                "{} ports are reserved; use one or let it expire",
+                MAX_RESERVATIONS
+            )));
+        }
+
+        let (port, listeners) = WEB_SERVER_PORTS
+            .filter(|port| !self.reserved_ports.contains(*port))
+            .find_map(|port| {
+                web_listener::bind(self.config.bind_address, port)
+                    .ok()
+                    .map(|listeners| (port, listeners))
+            })
+            .ok_or_else(|| HtMcpError::Internal("No available ports found".to_string()))?;
+        self.reserved_ports.insert(port, listeners, ttl);
+        info!("Reserved port {} for {}ms", port, ttl.as_millis());
+
+        Ok(serde_json::json!({
+            "port": port,
+            "expiresInMs": ttl.as_millis() as u64,
+        }))
+    }
+
+    /// Releases the ports of reservations no session claimed in time
+    pub fn purge_expired_port_reservations(&mut self) {
+        let released = self.reserved_ports.purge_expired();
+        if released > 0 {
+            info!("Released {} expired port reservation(s)", released);
+        }
+    }
+
+    /// Sends keys while the manager is held; see [`PendingKeys::try_send`]
+    pub async fn send_keys(&self, args: SendKeysArgs) -> Result<serde_json::Value> {
+        self.prepare_keys(args)?.try_send().await
+    }
+
+    /// Checks an `interactive_prompt` call and captures what watching the
+    /// session needs, so the watch runs without the manager
+    pub fn prepare_interactive_prompt(&self, args: InteractivePromptArgs) -> Result<PromptWatch> {
+        let session = self
+            .sessions
+            .get(&args.session_id)
+            .ok_or_else(|| HtMcpError::SessionNotFound(args.session_id.clone()))?;
+        session.ensure_alive()?;
+        let pattern = Regex::new(&args.prompt_pattern).map_err(|e| {
+            HtMcpError::InvalidRequest(format!(
+                "Invalid promptPattern '{}': {}",
+                args.prompt_pattern, e
+            ))
+        })?;
+        let timeout_ms = match args.prompt_timeout_ms {
+            Some(0) => {
+                return Err(HtMcpError::InvalidRequest(
+                    "timeoutMs must be at least 1".to_string(),
+                ))
+            }
+            ms => ms.unwrap_or(DEFAULT_PROMPT_TIMEOUT_MS),
+        };
+
+        Ok(PromptWatch {
+            session_id: args.session_id,
+            pattern,
+            response: args.response,
+            timeout: Duration::from_millis(timeout_ms),
+            command_tx: session.command_tx.clone(),
+            input_lock: session.input_lock.clone(),
+            snapshot_permits: session.snapshot_permits.clone(),
+            exit: session.exit.clone(),
+        })
+    }
+
+    /// The input of a `send_keys` call, to be sent without holding the
+    /// manager: paced input can take seconds, and other sessions must not
+    /// wait on it
+    pub fn prepare_keys(&self, args: SendKeysArgs) -> Result<PendingKeys> {
+        let session = self
+            .sessions
+            .get(&args.session_id)
+            .ok_or_else(|| HtMcpError::SessionNotFound(args.session_id.clone()))?;
+        session.ensure_alive()?;
+
+        let profile = args.input_profile.unwrap_or(session.config.input_profile);
+        let seed = (!profile.is_instant()).then(|| args.seed.unwrap_or_else(random_seed));
+        let keystrokes = match seed {
+            // Spelled-out chords first, then HT's native key parsing
+            None => vec![(
+                Duration::ZERO,
+                args.keys.iter().map(|key| keys::parse(key)).collect(),
+            )],
+            Some(seed) => {
+                let seqs: Vec<InputSeq> = args
+                    .keys
+                    .iter()
+                    .flat_map(|key| keys::keystrokes(key))
+                    .collect();
+                let texts: Vec<&str> = seqs
+                    .iter()
+                    .map(|seq| match seq {
+                        InputSeq::Standard(text) => text.as_str(),
+                        InputSeq::Cursor(..) => "",
+                    })
+                    .collect();
+                let delays = profile.delays(&texts, seed);
+                delays
+                    .into_iter()
+                    .zip(seqs)
+                    .map(|(delay, seq)| (delay, vec![seq]))
+                    .collect()
+            }
+        };
+
+        Ok(PendingKeys {
+            session_id: args.session_id,
+            keys: args.keys,
+            profile,
+            seed,
+            keystrokes,
+            command_tx: session.command_tx.clone(),
+            input_lock: session.input_lock.clone(),
+            exit: session.exit.clone(),
+        })
+    }
+
+    /// Sets the shell prompt of a new session to `marker` and waits until the
+    /// shell shows it, which also tells that the shell is ready for input
+    pub async fn inject_prompt_marker(&mut self, session_id: &str, marker: &str) -> Result<()> {
+        let (command_tx, snapshot_permits, startup) = self
+            .sessions
+            .get(session_id)
+            .map(|session| {
+                (
+                    session.command_tx.clone(),
+                    session.snapshot_permits.clone(),
+                    session.startup.clone(),
+                )
+            })
+            .ok_or_else(|| HtMcpError::SessionNotFound(session_id.to_string()))?;
+
+        // The terminal echoes what is typed, so typing before the shell has
+        // printed anything would pass the echo off as its first output
+        let _ = tokio::time::timeout(PROMPT_READY_TIMEOUT, async {
+            while startup.first_output_ms().is_none() {
+                tokio::time::sleep(PROMPT_POLL_INTERVAL).await;
+            }
+        })
+        .await;
+        self.send_keys(SendKeysArgs {
+            session_id: session_id.to_string(),
+            keys: vec![
+                format!("PS1='{}' PROMPT_COMMAND=''", marker),
+                "Enter".to_string(),
+            ],
+            input_profile: Some(InputProfile::Instant),
+            seed: None,
+            timeout_ms: None,
+        })
+        .await?;
+        let wait = async {
+            loop {
+                if prompt_shown(
+                    &request_snapshot(&command_tx, &snapshot_permits).await?,
+                    marker,
+                ) {
+                    return Ok::<_, HtMcpError>(());
+                }
+                tokio::time::sleep(PROMPT_POLL_INTERVAL).await;
+            }
+        };
+        tokio::time::timeout(PROMPT_READY_TIMEOUT, wait)
+            .await
+            .map_err(|_| {
+                HtMcpError::Timeout(format!(
+                    "The shell of session {} did not show the prompt marker within {} ms; autoDetectPrompt needs a POSIX shell",
+                    session_id,
+                    PROMPT_READY_TIMEOUT.as_millis()
+                ))
+            })??;
+
+        if let Some(session) = self.sessions.get_mut(session_id) {
+            session.prompt_marker = Some(marker.to_string());
+        }
+        info!("Session {} prompt set to {:?}", session_id, marker);
+        Ok(())
+    }
+
+    /// Reads a server-local file and cuts it into the chunks to type into
+    /// the session, to be sent without holding the manager
+    pub fn prepare_type_file(&self, args: TypeFileArgs) -> Result<PendingTypeFile> {
+        let session = self
+            .sessions
+            .get(&args.session_id)
+            .ok_or_else(|| HtMcpError::SessionNotFound(args.session_id.clone()))?;
+        session.ensure_alive()?;
+        if args.chunk_bytes == Some(0) {
+            return Err(HtMcpError::InvalidRequest(
+                "chunkBytes must be at least 1".to_string(),
+            ));
+        }
+        let path = type_file::resolve_allowed(
+            &args.path,
+            &self.config.type_file_paths,
+            self.config.workspace_root.as_deref(),
+        )?;
+        let text = type_file::read_text(&path)?;
+        let chunks = type_file::chunks(
+            &text,
+            args.chunk_bytes.unwrap_or(type_file::DEFAULT_CHUNK_BYTES),
+            args.bracketed_paste.unwrap_or(false),
+        );
+        let delay = args
+            .chunk_delay_ms
+            .map(Duration::from_millis)
+            .unwrap_or(type_file::DEFAULT_CHUNK_DELAY);
+
+        Ok(PendingTypeFile {
+            session_id: args.session_id,
+            path,
+            bytes: text.len(),
+            lines: text.lines().count(),
+            chunks,
+            delay,
+            command_tx: session.command_tx.clone(),
+            input_lock: session.input_lock.clone(),
+            exit: session.exit.clone(),
+        })
+    }
+
+    /// Writes the raw EOF byte to the PTY, ending input for programs that
+    /// read until end of file (`cat`, `sort`). Unlike `send_keys(["C-d"])`
+    /// this does not depend on the key parser.
+    pub async fn close_stdin(&self, args: CloseStdinArgs) -> Result<serde_json::Value> {
+        let session = self
+            .sessions
+            .get(&args.session_id)
+            .ok_or_else(|| HtMcpError::SessionNotFound(args.session_id.clone()))?;
+        session.ensure_alive()?;
+
+        session
+            .command_tx
+            .send(SessionCommand::CloseStdinAfterInput(Vec::new()))
+            .await
+            .map_err(|e| {
+                let err = HtMcpError::Internal(format!("Failed to send EOF: {}", e));
+                session.exit.explain(&args.session_id, err)
+            })?;
+
+        info!("Sent EOF to session {}", args.session_id);
+
+        Ok(serde_json::json!({
+            "success": true,
+            "sessionId": args.session_id
+        }))
+    }
+
+    /// Writes `data` to the PTY as is, for a command reading stdin, then
+    /// with `closeAfter` the EOF byte. EOF only ends input at the start of
+    /// a line, so after data not ending in a newline it is sent twice: once
+    /// to hand over the partial line, once to end input.
+    pub async fn pipe_stdin(&self, args: PipeStdinArgs) -> Result<serde_json::Value> {
+        let session = self
+            .sessions
+            .get(&args.session_id)
+            .ok_or_else(|| HtMcpError::SessionNotFound(args.session_id.clone()))?;
+        session.ensure_alive()?;
+
+        let close_after = args.close_after.unwrap_or(false);
+        let bytes = args.data.len();
+        let mid_line = !args.data.is_empty() && !args.data.ends_with('\n');
+        let mut seqs = vec![InputSeq::Standard(args.data)];
+        let command = if close_after {
+            if mid_line {
+                seqs.push(InputSeq::Standard("\u{4}".to_string()));
+            }
+            SessionCommand::CloseStdinAfterInput(seqs)
+        } else {
+            SessionCommand::Input(seqs)
+        };
+        session.command_tx.send(command).await.map_err(|e| {
+            let err = HtMcpError::Internal(format!("Failed to pipe to stdin: {}", e));
+            session.exit.explain(&args.session_id, err)
+        })?;
+
+        info!(
+            "Piped {} bytes to session {}{}",
+            bytes,
+            args.session_id,
+            if close_after { " and sent EOF" } else { "" }
+        );
+        Ok(serde_json::json!({
+            "success": true,
+            "sessionId": args.session_id,
+            "bytes": bytes,
+            "closed": close_after
+        }))
+    }
+
+    /// A handle for driving the session without passing its id around
+    pub fn handle(&self, session_id: &str) -> Result<SessionHandle> {
+        let session = self
+            .sessions
+            .get(session_id)
+            .ok_or_else(|| HtMcpError::SessionNotFound(session_id.to_string()))?;
+        Ok(SessionHandle::new(
+            session_id.to_string(),
+            &session.command_tx,
+            &session.stream_tx,
+            session.exit.clone(),
+            session.snapshot_permits.clone(),
+        ))
+    }
+
+    /// The session's raw output from now on; see
+    /// [`session_handle::output_stream`]
+    pub fn output_stream(&self, session_id: &str) -> Result<OutputStream> {
+        let session = self
+            .sessions
+            .get(session_id)
+            .ok_or_else(|| HtMcpError::SessionNotFound(session_id.to_string()))?;
+        Ok(session_handle::output_stream(session.stream_tx.subscribe()))
+    }
+
+    pub async fn take_snapshot(&self, args: TakeSnapshotArgs) -> Result<serde_json::Value> {
+        let session = self
+            .sessions
+            .get(&args.session_id)
+            .ok_or_else(|| HtMcpError::SessionNotFound(args.session_id.clone()))?;
+
+        let annotator = self.request_annotator(args.annotate, args.annotate_patterns.as_deref())?;
+        if args.word_wrap == Some(0) {
+            return Err(HtMcpError::InvalidRequest(
+                "wordWrap must be at least 1".to_string(),
+            ));
+        }
+        validate_max_tokens(args.max_tokens)?;
+        let styled = styled_format(args.format);
+        if styled.is_some() {
+            // Wrapping would split escape sequences and styled runs, and
+            // annotations would count escape sequences as text
+            if args.word_wrap.is_some() || annotator.is_some() {
+                return Err(HtMcpError::InvalidRequest(
+                    "wordWrap and annotate need format plain or ai-compressed".to_string(),
+                ));
+            }
+            if styled == Some(StyledFormat::Structured) && args.max_tokens.is_some() {
+                return Err(HtMcpError::InvalidRequest(
+                    "maxTokens does not apply to format structured".to_string(),
+                ));
+            }
+        }
+
+        info!("Taking snapshot for session {}", args.session_id);
+
+        // Read first, so the version is never newer than the screen
+        let screen_version = session.activity.output_bytes();
+        let (mut snapshot, styled_lines, cursor) = match styled {
+            Some(format) => {
+                let styled = session.styled_snapshot(format).await?;
+                (styled.text, styled.lines, styled.cursor)
+            }
+            None => {
+                let plain = session.text_snapshot().await?;
+                (plain.text, None, plain.cursor)
+            }
+        };
+
+        info!(
+            "Received snapshot for session {}: {} chars",
+            args.session_id,
+            snapshot.len()
+        );
+        let mut transforms = Vec::new();
+
+        if args.trim_trailing_blank_lines.unwrap_or(false) {
+            snapshot = snapshot::trim_trailing_blank_lines(&snapshot).to_string();
+        }
+        if args.format == Some(SnapshotFormat::AiCompressed) {
+            snapshot = snapshot::format_for_ai(&snapshot, DEFAULT_MAX_CONSECUTIVE_BLANK_LINES);
+            transforms.push("aiCompressed".to_string());
+        }
+        // Before annotating, so annotation line numbers match the lines returned
+        if let Some(width) = args.word_wrap {
+            snapshot = snapshot::wrap_snapshot(&snapshot, width);
+            transforms.push(format!("wordWrap={}", width));
+        }
+
+        if args.probe_only.unwrap_or(false) {
+            let mut size = TextSize::of(&snapshot);
+            if let Some(lines) = &styled_lines {
+                let lines = lines.to_string();
+                size.bytes += lines.len();
+                size.estimated_tokens += token_estimate::estimate_tokens(&lines);
+            }
+            let mut result = size.json();
+            result["sessionId"] = serde_json::json!(args.session_id);
+            result["probeOnly"] = serde_json::json!(true);
+            result["truncated"] = serde_json::json!(args
+                .max_tokens
+                .is_some_and(|max_tokens| size.estimated_tokens > max_tokens));
+            result["screenVersion"] = serde_json::json!(screen_version);
+            return Ok(result);
+        }
+        let mut omitted_lines = None;
+        if let Some(max_tokens) = args.max_tokens {
+            if let (kept, Some(dropped)) = token_estimate::truncate_to_budget(&snapshot, max_tokens)
+            {
+                snapshot = kept.to_string();
+                omitted_lines = Some(dropped);
+                transforms.push(format!("maxTokens={}", max_tokens));
+            }
+        }
+        // Over the text returned, so a snapshot shared as is verifies
+        let provenance = args
+            .include_provenance
+            .unwrap_or(false)
+            .then(|| Provenance {
+                transforms,
+                ..Provenance::new(
+                    &args.session_id,
+                    &session.command,
+                    screen_version,
+                    &control_sequences::strip(&snapshot),
+                )
+            });
+
+        let annotations = annotator.map(|annotator| annotator.annotate(&snapshot));
+        let estimated_tokens = token_estimate::estimate_tokens(&snapshot);
+        let mut result = serde_json::to_value(SnapshotResult {
+            session_id: args.session_id,
+            line_count: snapshot.lines().count(),
+            non_empty_line_count: snapshot
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .count(),
+            snapshot,
+            cursor_row: cursor.row,
+            cursor_col: cursor.col,
+            cursor_visible: cursor.visible,
+        })?;
+        if let Some(annotations) = annotations {
+            result["annotations"] = serde_json::json!(annotations);
+        }
+        if let Some(lines) = styled_lines {
+            result["styledLines"] = lines;
+        }
+        if let Some(provenance) = provenance {
+            result["provenance"] = serde_json::to_value(&provenance)?;
+            self.provenance.record(provenance);
+        }
+        if let Some(width) = args.word_wrap {
+            result["wrapped"] = serde_json::json!(true);
+            result["wrapWidth"] = serde_json::json!(width);
+        }
+        if args.max_tokens.is_some() {
+            result["truncated"] = serde_json::json!(omitted_lines.is_some());
+            result["estimatedTokens"] = serde_json::json!(estimated_tokens);
+            if let Some(omitted) = omitted_lines {
+                result["omittedLines"] = serde_json::json!(omitted);
+            }
+        }
+        result["screenVersion"] = serde_json::json!(screen_version);
+        Ok(result)
+    }
+
+    /// The screen drawn as a PNG, base64-encoded
+    pub async fn take_snapshot_as_png(
+        &self,
+        args: TakeScreenshotArgs,
+    ) -> Result<serde_json::Value> {
+        let session = self
+            .sessions
+            .get(&args.session_id)
+            .ok_or_else(|| HtMcpError::SessionNotFound(args.session_id.clone()))?;
+        let scale = args.scale.unwrap_or(renderer::DEFAULT_SCALE);
+
+        info!(
+            "Taking screenshot of session {} at scale {}",
+            args.session_id, scale
+        );
+        let screen_version = session.activity.output_bytes();
+        let screen = session.screen_cells().await?;
+        let provenance = args.include_provenance.unwrap_or(false).then(|| {
+            Provenance::new(
+                &args.session_id,
+                &session.command,
+                screen_version,
+                &screen.to_text(),
+            )
+        });
+        let footer = provenance.as_ref().map(Provenance::footer);
+        let screenshot = tokio::task::spawn_blocking(move || {
+            renderer::render_png(&screen, scale, footer.as_deref())
+        })
+        .await
+        .map_err(|e| HtMcpError::Internal(format!("Screenshot rendering failed: {}", e)))??;
+
+        let mut result = serde_json::json!({
+            "sessionId": args.session_id,
+            "width": screenshot.width,
+            "height": screenshot.height,
+            "scale": scale,
+            "mimeType": "image/png",
+            "png": base64::engine::general_purpose::STANDARD.encode(&screenshot.png),
+        });
+        if let Some(provenance) = provenance {
+            result["provenance"] = serde_json::to_value(&provenance)?;
+            self.provenance.record(provenance);
+        }
+        Ok(result)
+    }
+
+    /// Looks `hash` up among the provenance blocks handed out and the
+    /// snapshot history kept by live sessions, and checks `text` against it
+    pub async fn verify_snapshot(&self, args: VerifySnapshotArgs) -> Result<serde_json::Value> {
+        let hash = args.hash.trim();
+        let hash = hash
+            .strip_prefix("sha256:")
+            .unwrap_or(hash)
+            .to_ascii_lowercase();
+        if !provenance::is_valid_hash(&hash) {
+            return Err(HtMcpError::InvalidRequest(format!(
+                "hash must be the {} hex digits of a provenance block, got {:?}",
+                provenance::HASH_LEN,
+                args.hash
+            )));
+        }
+        let in_scope = |session_id: &str| {
+            args.session_id
+                .as_deref()
+                .map_or(true, |wanted| wanted == session_id)
+        };
+
+        let issued: Vec<Provenance> = self
+            .provenance
+            .find(&hash)
+            .into_iter()
+            .filter(|provenance| in_scope(&provenance.session_id))
+            .collect();
+        let now = SystemTime::now();
+        let mut history = Vec::new();
+        for session in self
+            .sessions
+            .values()
+            .filter(|session| in_scope(&session.id))
+        {
+            let Some(snapshots) = &session.snapshot_history else {
+                continue;
+            };
+            for (taken_at, text) in snapshots.recent(usize::MAX, None) {
+                if provenance::content_hash(&text) == hash {
+                    let taken_at = now.checked_sub(taken_at.elapsed()).unwrap_or(now);
+                    history.push(serde_json::json!({
+                        "sessionId": session.id,
+                        "takenAt": provenance::timestamp(taken_at),
+                    }));
+                }
+            }
+        }
+
+        let known = !issued.is_empty() || !history.is_empty();
+        let content_hash = args.text.as_deref().map(provenance::content_hash);
+        let content_matches = content_hash.as_ref().map(|content| *content == hash);
+        let mut result = serde_json::json!({
+            "hash": hash,
+            "known": known,
+            "verified": known && content_matches != Some(false),
+            "issued": issued,
+            "history": history,
+        });
+        if let Some(content_hash) = content_hash {
+            result["contentHash"] = serde_json::json!(content_hash);
+            result["contentMatches"] = serde_json::json!(content_matches);
+        }
+        Ok(result)
+    }
+
+    /// The annotator for a call, if it asked for annotations; giving
+    /// patterns implies `annotate` unless it is explicitly false
+    fn request_annotator(
+        &self,
+        annotate: Option<bool>,
+        patterns: Option<&[AnnotationPatternConfig]>,
+    ) -> Result<Option<Annotator>> {
+        if !annotate.unwrap_or(patterns.is_some()) {
+            return Ok(None);
+        }
+        match patterns {
+            Some(patterns) => self.annotator.with_request_patterns(patterns).map(Some),
+            None => Ok(Some(self.annotator.clone())),
+        }
+    }
+
+    /// Stores the current screen as the baseline `baselineId`
+    pub async fn take_baseline_snapshot(
+        &self,
+        args: BaselineSnapshotArgs,
+    ) -> Result<serde_json::Value> {
+        let session = self
+            .sessions
+            .get(&args.session_id)
+            .ok_or_else(|| HtMcpError::SessionNotFound(args.session_id.clone()))?;
+        let snapshot = session.snapshot().await?;
+        let path = baseline::save(
+            &self.config.baseline_dir(),
+            &args.session_id,
+            &args.baseline_id,
+            &snapshot,
+        )?;
+
+        info!(
+            "Saved baseline {} of session {} to {}",
+            args.baseline_id,
+            args.session_id,
+            path.display()
+        );
+        Ok(serde_json::json!({
+            "sessionId": args.session_id,
+            "baselineId": args.baseline_id,
+            "path": path.display().to_string(),
+        }))
+    }
+
+    /// Compares the current screen with the baseline `baselineId`
+    pub async fn compare_to_baseline(
+        &self,
+        args: CompareSnapshotArgs,
+    ) -> Result<serde_json::Value> {
+        let session = self
+            .sessions
+            .get(&args.session_id)
+            .ok_or_else(|| HtMcpError::SessionNotFound(args.session_id.clone()))?;
+        let expected = baseline::load(
+            &self.config.baseline_dir(),
+            &args.session_id,
+            &args.baseline_id,
+        )?;
+        let actual = session.snapshot().await?;
+        let diff = baseline::diff(&expected, &actual);
+
+        Ok(serde_json::json!({
+            "sessionId": args.session_id,
+            "baselineId": args.baseline_id,
+            "matches": diff.is_empty(),
+            "diff": diff,
+        }))
+    }
+
+    /// Best guess at what the session is showing (shell prompt, pager,
+    /// password prompt, ...) with the evidence for it
+    pub async fn classify_screen(&self, args: ClassifyScreenArgs) -> Result<serde_json::Value> {
+        let session = self
+            .sessions
+            .get(&args.session_id)
+            .ok_or_else(|| HtMcpError::SessionNotFound(args.session_id.clone()))?;
+
+        let snapshot = session.snapshot().await?;
+        let signals = session.activity.signals(session.child.echo_enabled());
+        let verdict = self.screen_classifier.classify(&snapshot, &signals);
+
+        Ok(serde_json::json!({
+            "sessionId": args.session_id,
+            "state": verdict.state,
+            "evidence": verdict.evidence,
+            "confidence": verdict.confidence,
+        }))
+    }
+
+    /// Checks the session's screen against `assertions`. Failed assertions
+    /// are part of the result; only malformed ones are errors.
+    pub async fn assert_screen(&self, args: AssertScreenArgs) -> Result<serde_json::Value> {
+        let session = self
+            .sessions
+            .get(&args.session_id)
+            .ok_or_else(|| HtMcpError::SessionNotFound(args.session_id.clone()))?;
+
+        let screen = request_screen(&session.command_tx, &session.snapshot_permits).await?;
+        let report = assertions::check(&screen, &args.assertions)?;
+
+        let mut result = serde_json::json!({
+            "sessionId": args.session_id,
+            "passed": report.passed,
+            "failures": report.failures,
+            "results": report.results,
+        });
+        if !report.passed && args.attach_snapshot_on_failure.unwrap_or(true) {
+            result["snapshot"] = serde_json::json!(screen.text);
+        }
+        Ok(result)
+    }
+
+    /// Returns the session's periodic snapshots as `{elapsedMs, snapshot}`
+    /// entries, oldest first
+    pub fn get_snapshot_history(&self, args: GetSnapshotHistoryArgs) -> Result<serde_json::Value> {
+        let session = self
+            .sessions
+            .get(&args.session_id)
+            .ok_or_else(|| HtMcpError::SessionNotFound(args.session_id.clone()))?;
+        let history = session.snapshot_history.as_ref().ok_or_else(|| {
+            HtMcpError::InvalidRequest(format!(
+                "Session {} does not record snapshot history; create it with snapshotIntervalMs",
+                args.session_id
+            ))
+        })?;
+
+        validate_max_tokens(args.max_tokens)?;
+
+        let screen_version = session.activity.output_bytes();
+        let mut recent = history.recent(
+            args.limit.unwrap_or(DEFAULT_SNAPSHOT_HISTORY_LIMIT),
+            args.since_ms.map(Duration::from_millis),
+        );
+        let tokens: usize = recent
+            .iter()
+            .map(|(_, snapshot)| token_estimate::estimate_tokens(snapshot))
+            .sum();
+
+        if args.probe_only.unwrap_or(false) {
+            let bytes: usize = recent.iter().map(|(_, snapshot)| snapshot.len()).sum();
+            let lines: usize = recent
+                .iter()
+                .map(|(_, snapshot)| snapshot.lines().count())
+                .sum();
+            return Ok(serde_json::json!({
+                "sessionId": args.session_id,
+                "probeOnly": true,
+                "snapshotCount": recent.len(),
+                "bytes": bytes,
+                "lineCount": lines,
+                "estimatedTokens": tokens,
+                "truncated": args.max_tokens.is_some_and(|max_tokens| tokens > max_tokens),
+                "screenVersion": screen_version
+            }));
+        }
+
+        // Newest snapshots first into the budget; the newest is cut if it
+        // alone is over
+        let mut omitted_snapshots = 0;
+        if let Some(max_tokens) = args.max_tokens.filter(|&max_tokens| tokens > max_tokens) {
+            let mut spent = 0;
+            let fitting = recent
+                .iter()
+                .rev()
+                .take_while(|(_, snapshot)| {
+                    spent += token_estimate::estimate_tokens(snapshot);
+                    spent <= max_tokens
+                })
+                .count();
+            omitted_snapshots = recent.len() - fitting.max(1);
+            recent.drain(..omitted_snapshots);
+            if fitting == 0 {
+                if let Some((_, newest)) = recent.last_mut() {
+                    *newest = token_estimate::truncate_to_budget(newest, max_tokens)
+                        .0
+                        .to_string();
+                }
+            }
+        }
+
+        let snapshots: Vec<serde_json::Value> = recent
+            .into_iter()
+            .map(|(taken_at, snapshot)| {
+                serde_json::json!({
+                    "elapsedMs": taken_at.elapsed().as_millis() as u64,
+                    "snapshot": snapshot
+                })
+            })
+            .collect();
+
+        let mut result = serde_json::json!({
+            "sessionId": args.session_id,
+            "intervalMs": history.interval().as_millis() as u64,
+            "snapshots": snapshots,
+            "screenVersion": screen_version
+        });
+        if let Some(max_tokens) = args.max_tokens {
+            result["truncated"] = serde_json::json!(tokens > max_tokens);
+            result["omittedSnapshots"] = serde_json::json!(omitted_snapshots);
+        }
+        Ok(result)
+    }
+
+    /// Starts or continues a bulk download of a session's snapshot and
+    /// command history. A new download captures the history as it is now.
+    pub fn download_history(&self, args: DownloadHistoryArgs) -> Result<serde_json::Value> {
+        let session = self
+            .sessions
+            .get(&args.session_id)
+            .ok_or_else(|| HtMcpError::SessionNotFound(args.session_id.clone()))?;
+        let chunk_bytes = args
+            .chunk_bytes
+            .unwrap_or(history_download::DEFAULT_CHUNK_BYTES);
+        if !(history_download::MIN_CHUNK_BYTES..=history_download::MAX_CHUNK_BYTES)
+            .contains(&chunk_bytes)
+        {
+            return Err(HtMcpError::InvalidRequest(format!(
+                "chunkBytes must be between {} and {}",
+                history_download::MIN_CHUNK_BYTES,
+                history_download::MAX_CHUNK_BYTES
+            )));
+        }
+
+        if let Some(token) = &args.token {
+            let chunk = self.downloads.chunk(
+                token,
+                &args.session_id,
+                args.format,
+                args.cursor.unwrap_or(0),
+                chunk_bytes,
+            )?;
+            return Ok(serde_json::to_value(chunk)?);
+        }
+
+        let via_url = args.via_url.unwrap_or(false);
+        let management_address = if via_url {
+            Some(self.config.management_address.ok_or_else(|| {
+                HtMcpError::InvalidRequest(
+                    "viaUrl needs the management server; set management_address in the config"
+                        .to_string(),
+                )
+            })?)
+        } else {
+            None
+        };
+        let format = args.format.unwrap_or(HistoryFormat::Plain);
+        let now = chrono::Utc::now();
+        let snapshots: Vec<_> = session
+            .snapshot_history
+            .as_ref()
+            .map(|history| history.recent(usize::MAX, None))
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(taken_at, text)| {
+                let age = chrono::Duration::from_std(taken_at.elapsed()).unwrap_or_default();
+                (now - age, text)
+            })
+            .collect();
+        let content = history_download::render(format, &snapshots, &session.history);
+        let info = self
+            .downloads
+            .start(&args.session_id, format, content, via_url)?;
+
+        if let Some(address) = management_address {
+            let url = format!(
+                "{}/downloads/{}",
+                web_listener::local_url(address),
+                info.token
+            );
+            let mut result = serde_json::to_value(info)?;
+            result["url"] = serde_json::Value::from(url);
+            return Ok(result);
+        }
+        let chunk = self.downloads.chunk(
+            &info.token,
+            &args.session_id,
+            None,
+            args.cursor.unwrap_or(0),
+            chunk_bytes,
+        )?;
+        Ok(serde_json::to_value(chunk)?)
+    }
+
+    /// Resizes the session's terminal and its PTY, so the program running in
+    /// it sees the new size
+    pub async fn resize_session(&self, args: ResizeArgs) -> Result<serde_json::Value> {
+        let session = self
+            .sessions
+            .get(&args.session_id)
+            .ok_or_else(|| HtMcpError::SessionNotFound(args.session_id.clone()))?;
+        session.ensure_alive()?;
+        self.check_terminal_size(args.cols, args.rows)?;
+
+        let before = request_screen(&session.command_tx, &session.snapshot_permits)
+            .await
+            .map_err(|e| session.exit.explain(&args.session_id, e))?;
+        session
+            .command_tx
+            .send(SessionCommand::Resize(args.cols, args.rows))
+            .await
+            .map_err(|e| {
+                let err = HtMcpError::Internal(format!("Failed to resize session: {}", e));
+                session.exit.explain(&args.session_id, err)
+            })?;
+        info!(
+            "Resized session {} from {}x{} to {}x{}",
+            args.session_id, before.cols, before.rows, args.cols, args.rows
+        );
+
+        Ok(serde_json::json!({
+            "sessionId": args.session_id,
+            "oldSize": {"cols": before.cols, "rows": before.rows},
+            "newSize": {"cols": args.cols, "rows": args.rows},
+        }))
+    }
+
+    /// WebSocket viewers connected to a session's web server
+    pub fn get_connected_clients(
+        &self,
+        args: GetConnectedClientsArgs,
+    ) -> Result<serde_json::Value> {
+        let session = self
+            .sessions
+            .get(&args.session_id)
+            .ok_or_else(|| HtMcpError::SessionNotFound(args.session_id.clone()))?;
+        if session.web_server.is_none() {
+            return Err(HtMcpError::InvalidRequest(format!(
+                "Session {} has no web server",
+                args.session_id
+            )));
+        }
+        let clients: Vec<_> = session
+            .connected_clients
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|client| client.websocket)
+            .cloned()
+            .collect();
+        Ok(serde_json::json!({
+            "sessionId": args.session_id,
+            "count": clients.len(),
+            "clients": clients
+        }))
+    }
+
+    pub fn get_session_metrics_history(
+        &self,
+        args: GetSessionMetricsHistoryArgs,
+    ) -> Result<serde_json::Value> {
+        let session = self
+            .sessions
+            .get(&args.session_id)
+            .ok_or_else(|| HtMcpError::SessionNotFound(args.session_id.clone()))?;
+        let history: Vec<_> = session
+            .metrics_history
+            .lock()
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect();
+
+        Ok(serde_json::json!({
+            "sessionId": args.session_id,
+            "intervalSecs": METRICS_INTERVAL.as_secs(),
+            "history": history
+        }))
+    }
+
+    /// A Dockerfile approximating the session's environment, see
+    /// [`dockerfile`]
+    pub fn export_dockerfile(&self, args: ExportDockerfileArgs) -> Result<serde_json::Value> {
+        let session = self
+            .sessions
+            .get(&args.session_id)
+            .ok_or_else(|| HtMcpError::SessionNotFound(args.session_id.clone()))?;
+
+        // Where the shell is now, which may have moved on from the cwd the
+        // session was created in
+        let working_directory = session
+            .child
+            .pid
+            .and_then(dockerfile::process_cwd)
+            .or_else(|| session.config.cwd.as_ref().map(PathBuf::from));
+        let mut entries: Vec<String> = working_directory
+            .as_ref()
+            .and_then(|dir| std::fs::read_dir(dir).ok())
+            .map(|dir| {
+                dir.filter_map(|entry| entry.ok())
+                    .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                    .collect()
+            })
+            .unwrap_or_default();
+        entries.sort();
+        let working_directory = working_directory.map(|dir| dir.display().to_string());
+
+        let packages = dockerfile::packages(&session.history);
+        // The session's own `env` may hold credentials just the same
+        let build_args: Vec<&str> = self
+            .config
+            .session_env
+            .keys()
+            .chain(session.config.env.keys())
+            .map(String::as_str)
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        let env = session
+            .config
+            .color_profile
+            .map(|profile| profile.env().to_vec())
+            .unwrap_or_default();
+        let text = dockerfile::generate(&dockerfile::SessionEnvironment {
+            session_id: &args.session_id,
+            command: &session.command,
+            working_directory: working_directory.as_deref(),
+            copy_working_directory: !entries.is_empty(),
+            user: session
+                .config
+                .run_as
+                .as_ref()
+                .map(|run_as| match &run_as.group {
+                    Some(group) => format!("{}:{}", run_as.user, group),
+                    None => run_as.user.clone(),
+                }),
+            env,
+            build_args: build_args.clone(),
+            packages: packages.clone(),
+        });
+        entries.truncate(dockerfile::MAX_LISTED_ENTRIES);
+
+        Ok(serde_json::json!({
+            "sessionId": args.session_id,
+            "dockerfile": text,
+            "packages": packages,
+            "buildArgs": build_args,
+            "workingDirectory": working_directory,
+            "workingDirectoryEntries": entries,
+            "note": "Heuristic: review before building. Build from the working directory, passing each build argument with --build-arg NAME=value."
+        }))
+    }
+
+    pub async fn execute_command(&mut self, args: ExecuteCommandArgs) -> Result<serde_json::Value> {
+        let command = self.prepare_command(args)?;
+        let finished = command.run().await?;
+        self.finish_command(finished)
+    }
+
+    /// Checks an `execute_command` call and captures what running it needs,
+    /// so [`PendingCommand::run`] runs the command without the manager
+    pub fn prepare_command(&self, mut args: ExecuteCommandArgs) -> Result<PendingCommand> {
+        // Checked first so bad patterns are reported before the command runs
+        let annotator = self.request_annotator(args.annotate, args.annotate_patterns.as_deref())?;
+        let alias = self.expand_alias(&mut args);
+        let mut command = self.prepare_run(args)?;
+        command.alias = alias;
+        command.annotator = annotator;
+        Ok(command)
+    }
+
+    /// Replaces a command starting with an alias by what the alias runs,
+    /// returning the `alias` to report. Done before anything else sees the
+    /// command, so command classes judge what actually runs.
+    fn expand_alias(&self, args: &mut ExecuteCommandArgs) -> Option<serde_json::Value> {
+        let expansion = self.aliases.expand(&args.session_id, &args.command)?;
+        info!(
+            "Session {} runs alias {}: {}",
+            args.session_id, expansion.alias, expansion.command
+        );
+        let invoked = std::mem::replace(&mut args.command, expansion.command);
+        Some(serde_json::json!({"name": expansion.alias, "invoked": invoked}))
+    }
+
+    /// Records a command run by [`PendingCommand::run`] in its session's
+    /// history and adds the annotations and summary the call asked for
+    pub fn finish_command(&mut self, finished: FinishedCommand) -> Result<serde_json::Value> {
+        let FinishedCommand {
+            args,
+            alias,
+            annotator,
+            mut result,
+            duration,
+        } = finished;
+        if let Some(alias) = alias {
+            result["alias"] = alias;
+        }
+        let exit_code = result["exitCode"].as_i64().map(|code| code as i32);
+        if !result["terminator"].as_array().map_or(true, Vec::is_empty) {
+            self.record_history(&args.session_id, &args.command);
+        }
+
+        let output = result["output"].as_str().unwrap_or_default();
+        let annotations = match (&annotator, args.summarize.unwrap_or(false)) {
+            (Some(annotator), _) => Some(annotator.annotate(output)),
+            // The summary is built from annotations even when they aren't
+            // returned
+            (None, true) => Some(self.annotator.annotate(output)),
+            (None, false) => None,
+        };
+        if args.summarize.unwrap_or(false) {
+            let annotations = annotations.as_deref().unwrap_or_default();
+            result["summary"] =
+                serde_json::json!(summary::summarize(output, annotations, duration, exit_code));
+        }
+        if annotator.is_some() {
+            result["annotations"] = serde_json::json!(annotations);
+        }
+        Ok(result)
+    }
+
+    /// Whether `command` is available in the session's shell, looked up
+    /// with `command -v` at the prompt; `path` is where it was found, or
+    /// just its name for builtins, functions and aliases
+    pub async fn check_command_exists(
+        &self,
+        args: CheckCommandExistsArgs,
+    ) -> Result<serde_json::Value> {
+        self.prepare_command_check(args)?.run().await
+    }
+
+    /// Checks a `check_command_exists` call and prepares the lookup, so
+    /// [`PendingCommandCheck::run`] waits for it without the manager
+    pub fn prepare_command_check(
+        &self,
+        args: CheckCommandExistsArgs,
+    ) -> Result<PendingCommandCheck> {
+        validate_command_name(&args.command)?;
+        let session = self
+            .sessions
+            .get(&args.session_id)
+            .ok_or_else(|| HtMcpError::SessionNotFound(args.session_id.clone()))?;
+        if !completion::is_posix_shell(&session.command) {
+            return Err(HtMcpError::InvalidRequest(format!(
+                "Session {} does not run a POSIX shell, so command -v is not available",
+                args.session_id
+            )));
+        }
+
+        let probe = ExecuteCommandArgs {
+            session_id: args.session_id.clone(),
+            command: format!(
+                "command -v {} 2>/dev/null && echo EXISTS || echo MISSING",
+                args.command
+            ),
+            terminator: None,
+            append_terminator: None,
+            annotate: None,
+            annotate_patterns: None,
+            summarize: None,
+            wait_for_completion: None,
+            completion_timeout_ms: None,
+            quiet_period_ms: None,
+            // The looked-up name may be in the interactive class
+            command_class: Some("default".to_string()),
+            timeout_ms: None,
+        };
+        Ok(PendingCommandCheck {
+            lookup: self.prepare_run(probe)?,
+            command: args.command,
+        })
+    }
+
+    /// Looks up `tools` in the session's shell with a single probe command,
+    /// along with the shell and its version. Tools an earlier probe found
+    /// are answered from the session's cache unless `refresh` is set.
+    pub async fn probe_tools(&mut self, args: ProbeToolsArgs) -> Result<serde_json::Value> {
+        let probe = self.prepare_probe(args)?;
+        let finished = probe.run().await?;
+        self.finish_probe(finished)
+    }
+
+    /// Checks a `probe_tools` call and, unless the cache answers it,
+    /// prepares the probe command, so [`PendingProbe::run`] runs it
+    /// without the manager
+    pub fn prepare_probe(&self, args: ProbeToolsArgs) -> Result<PendingProbe> {
+        let mut tools: Vec<String> = match args.tools {
+            Some(tools) => tools,
+            None => tool_probe::DEFAULT_TOOLS
+                .iter()
+                .map(|tool| tool.to_string())
+                .collect(),
+        };
+        if tools.is_empty() || tools.len() > tool_probe::MAX_TOOLS {
+            return Err(HtMcpError::InvalidRequest(format!(
+                "tools must name between 1 and {} programs",
+                tool_probe::MAX_TOOLS
+            )));
+        }
+        for tool in &tools {
+            validate_command_name(tool)?;
+        }
+        let mut seen = HashSet::new();
+        tools.retain(|tool| seen.insert(tool.clone()));
+
+        let session = self
+            .sessions
+            .get(&args.session_id)
+            .ok_or_else(|| HtMcpError::SessionNotFound(args.session_id.clone()))?;
+        let shell = ProbeShell::for_command(&session.command).ok_or_else(|| {
+            HtMcpError::InvalidRequest(format!(
+                "Session {} does not run a shell that can be probed (bash, zsh, sh, fish or PowerShell)",
+                args.session_id
+            ))
+        })?;
+        let refresh = args.refresh.unwrap_or(false);
+        let unknown: Vec<String> = tools
+            .iter()
+            .filter(|tool| refresh || !session.tool_probe.tools.contains_key(*tool))
+            .cloned()
+            .collect();
+        if unknown.is_empty() && session.tool_probe.shell.is_some() {
+            return Ok(PendingProbe {
+                session_id: args.session_id,
+                tools,
+                run: None,
+            });
+        }
+
+        session.ensure_alive()?;
+        let probe = Probe::new(shell, unknown);
+        let keys = self.prepare_keys(SendKeysArgs {
+            session_id: args.session_id.clone(),
+            keys: vec![probe.command(), "Enter".to_string()],
+            input_profile: Some(InputProfile::Instant),
+            seed: None,
+            timeout_ms: None,
+        })?;
+        Ok(PendingProbe {
+            session_id: args.session_id,
+            tools,
+            run: Some(ProbeRun {
+                probe,
+                output: session.stream_tx.subscribe(),
+                keys,
+                program: session.command.first().cloned().unwrap_or_default(),
+            }),
+        })
+    }
+
+    /// Caches what [`PendingProbe::run`] found in its session and reports
+    /// the probed tools
+    pub fn finish_probe(&mut self, finished: FinishedProbe) -> Result<serde_json::Value> {
+        let FinishedProbe {
+            session_id,
+            tools,
+            found,
+        } = finished;
+        let cached = found.is_none();
+        let session = self
+            .sessions
+            .get_mut(&session_id)
+            .ok_or_else(|| HtMcpError::SessionNotFound(session_id.clone()))?;
+        if let Some((shell_info, found)) = found {
+            session.tool_probe.shell = Some(shell_info);
+            session.tool_probe.tools.extend(found);
+        }
+
+        let found: serde_json::Map<String, serde_json::Value> = tools
+            .iter()
+            .filter_map(|tool| {
+                let info = session.tool_probe.tools.get(tool)?;
+                Some((tool.clone(), serde_json::json!(info)))
+            })
+            .collect();
+        Ok(serde_json::json!({
+            "sessionId": session_id,
+            "shell": session.tool_probe.shell,
+            "tools": found,
+            "cached": cached
+        }))
+    }
+
+    /// Snapshots every session in a group concurrently, oldest session
+    /// first. A session failing, or taking longer than half the default tool
+    /// timeout, is reported in its entry without holding up the others.
+    pub async fn group_snapshot(&self, args: GroupSnapshotArgs) -> Result<serde_json::Value> {
+        let mut members: Vec<&SessionInfo> = self
+            .sessions
+            .values()
+            .filter(|session| session.config.group_id.as_deref() == Some(args.group_id.as_str()))
+            .collect();
+        if members.is_empty() {
+            return Err(HtMcpError::InvalidRequest(format!(
+                "No session is in group {}",
+                args.group_id
+            )));
+        }
+        members.sort_by_key(|session| session.created_at);
+
+        let timeout = Duration::from_millis(self.config.default_tool_timeout_ms / 2);
+        let styled = styled_format(args.format);
+        let outcomes = join_all(members.iter().map(|session| async move {
+            let snapshot = async {
+                match styled {
+                    Some(format) => {
+                        let styled = session.styled_snapshot(format).await?;
+                        Ok((styled.text, styled.lines))
+                    }
+                    None => Ok((session.snapshot().await?, None)),
+                }
+            };
+            tokio::time::timeout(timeout, snapshot)
+                .await
+                .unwrap_or_else(|_| {
+                    Err(HtMcpError::Timeout(format!(
+                        "Snapshot of session {} took longer than {} ms",
+                        session.id,
+                        timeout.as_millis()
+                    )))
+                })
+        }))
+        .await;
+
+        let mut failed = 0;
+        let mut snapshots = Vec::with_capacity(outcomes.len());
+        for (session, outcome) in members.iter().zip(outcomes) {
+            match outcome {
+                Ok((mut snapshot, styled_lines)) => {
+                    if args.format == Some(SnapshotFormat::AiCompressed) {
+                        snapshot =
+                            snapshot::format_for_ai(&snapshot, DEFAULT_MAX_CONSECUTIVE_BLANK_LINES);
+                    }
+                    let mut entry = serde_json::json!({
+                        "sessionId": session.id,
+                        "snapshot": snapshot,
+                        "error": null
+                    });
+                    if let Some(lines) = styled_lines {
+                        entry["styledLines"] = lines;
+                    }
+                    snapshots.push(entry);
+                }
+                Err(e) => {
+                    warn!("Group snapshot failed for session {}: {}", session.id, e);
+                    failed += 1;
+                    snapshots.push(serde_json::json!({
+                        "sessionId": session.id,
+                        "snapshot": null,
+                        "error": e.to_string()
+                    }));
+                }
+            }
+        }
+
+        Ok(serde_json::json!({
+            "groupId": args.group_id,
+            "snapshots": snapshots,
+            "succeeded": snapshots.len() - failed,
+            "failed": failed
+        }))
+    }
+
+    /// Runs `command` in every listed session concurrently. A failure in one
+    /// session is reported in its entry and does not stop the others.
+    pub async fn parallel_execute(
+        &mut self,
+        args: ParallelExecuteArgs,
+    ) -> Result<serde_json::Value> {
+        let parallel = self.prepare_parallel(args)?;
+        let finished = parallel.run().await;
+        self.finish_parallel(finished)
+    }
+
+    /// Checks a `parallel_execute` call and prepares the command for each
+    /// session, so [`PendingParallel::run`] runs them without the manager
+    pub fn prepare_parallel(&self, args: ParallelExecuteArgs) -> Result<PendingParallel> {
+        if args.session_ids.is_empty() {
+            return Err(HtMcpError::InvalidRequest(
+                "sessionIds must list at least one session".to_string(),
+            ));
+        }
+        let mut seen = HashSet::new();
+        if let Some(duplicate) = args.session_ids.iter().find(|id| !seen.insert(*id)) {
+            return Err(HtMcpError::InvalidRequest(format!(
+                "sessionIds lists session {} more than once",
+                duplicate
+            )));
+        }
+
+        let command_args: Vec<ExecuteCommandArgs> = args
+            .session_ids
+            .iter()
+            .map(|session_id| ExecuteCommandArgs {
+                session_id: session_id.clone(),
+                command: args.command.clone(),
+                terminator: None,
+                append_terminator: None,
+                annotate: None,
+                annotate_patterns: None,
+                summarize: None,
+                wait_for_completion: None,
+                completion_timeout_ms: None,
+                quiet_period_ms: None,
+                command_class: None,
+                timeout_ms: None,
+            })
+            .collect();
+        let commands: Vec<Result<PendingCommand>> = command_args
+            .into_iter()
+            .map(|mut args| {
+                let alias = self.expand_alias(&mut args);
+                let mut command = self.prepare_run(args)?;
+                command.alias = alias;
+                Ok(command)
+            })
+            .collect();
+        Ok(PendingParallel {
+            command: args.command,
+            session_ids: args.session_ids,
+            commands,
+        })
+    }
+
+    /// Records the commands run by [`PendingParallel::run`] in their
+    /// sessions' histories and reports each session's output
+    pub fn finish_parallel(&mut self, finished: FinishedParallel) -> Result<serde_json::Value> {
+        let FinishedParallel {
+            command,
+            session_ids,
+            outcomes,
+        } = finished;
+        let mut failed = 0;
+        let mut results = Vec::with_capacity(outcomes.len());
+        for (session_id, outcome) in session_ids.iter().zip(outcomes) {
+            match outcome {
+                Ok(finished) => {
+                    self.record_history(session_id, &finished.args.command);
+                    let mut result = serde_json::json!({
+                        "sessionId": session_id,
+                        "output": finished.result["output"],
+                        "error": null
+                    });
+                    if let Some(alias) = finished.alias {
+                        result["command"] = finished.args.command.into();
+                        result["alias"] = alias;
+                    }
+                    results.push(result);
+                }
+                Err(e) => {
+                    warn!("Parallel command failed in session {}: {}", session_id, e);
+                    failed += 1;
+                    results.push(serde_json::json!({
+                        "sessionId": session_id,
+                        "output": null,
+                        "error": e.to_string()
+                    }));
+                }
+            }
+        }
+
+        Ok(serde_json::json!({
+            "command": command,
+            "results": results,
+            "succeeded": results.len() - failed,
+            "failed": failed
+        }))
+    }
+
+    /// Checks a command against its session and class, without expanding
+    /// aliases or touching the session's history. A session that had
+    /// already exited fails with `SessionExited`.
+    fn prepare_run(&self, args: ExecuteCommandArgs) -> Result<PendingCommand> {
+        let session = self
+            .sessions
+            .get(&args.session_id)
+            .ok_or_else(|| HtMcpError::SessionNotFound(args.session_id.clone()))?;
+        session.ensure_alive()?;
+        let terminator = command_terminator(&args);
+        let class = self
+            .command_classifier
+            .for_call(&args.command, args.command_class.as_deref())?;
+        // A command only typed, not submitted, is harmless
+        if !terminator.is_empty() {
+            class.check(&args.command)?;
+        }
+        let completion_timeout = completion_timeout(&args, class)?;
+        let quiet_period = args
+            .quiet_period_ms
+            .map_or(class.quiet_period, Duration::from_millis);
+        let prompt_marker = session
+            .prompt_marker
+            .clone()
+            .filter(|_| !terminator.is_empty());
+        let sentinel = (prompt_marker.is_none()
+            && !terminator.is_empty()
+            && args.wait_for_completion.unwrap_or(true)
+            && completion::is_posix_shell(&session.command))
+        .then(Sentinel::new);
+        let typed = match &sentinel {
+            Some(sentinel) => sentinel.append_to(&args.command),
+            None => args.command.clone(),
+        };
+        let input = std::iter::once(&typed)
+            .chain(&terminator)
+            .map(|key| keys::parse(key))
+            .collect();
+
+        Ok(PendingCommand {
+            alias: None,
+            annotator: None,
+            terminator,
+            class_name: class.name.clone(),
+            completion_timeout,
+            quiet_period,
+            prompt_marker,
+            sentinel,
+            input,
+            command_tx: session.command_tx.clone(),
+            stream_tx: session.stream_tx.clone(),
+            input_lock: session.input_lock.clone(),
+            snapshot_permits: session.snapshot_permits.clone(),
+            exit: session.exit.clone(),
+            args,
+        })
+    }
+
+    /// The command classes `execute_command` picks budgets from
+    pub fn command_classifier(&self) -> Arc<CommandClassifier> {
+        self.command_classifier.clone()
+    }
+
+    pub fn aliases(&self) -> CommandAliases {
+        self.aliases.clone()
+    }
+
+    /// Sets or removes one of a session's aliases, which shadow configured
+    /// aliases of the same name
+    pub fn set_alias(&mut self, args: SetAliasArgs) -> Result<serde_json::Value> {
+        if !self.sessions.contains_key(&args.session_id) {
+            return Err(HtMcpError::SessionNotFound(args.session_id));
+        }
+        let previous = self
+            .aliases
+            .set(&args.session_id, &args.name, args.command.as_deref())?;
+        info!(
+            "Session {} alias {}: {}",
+            args.session_id,
+            args.name,
+            args.command.as_deref().unwrap_or("(removed)")
+        );
+        Ok(serde_json::json!({
+            "sessionId": args.session_id,
+            "name": args.name,
+            "command": args.command,
+            "previous": previous
+        }))
+    }
+
+    fn record_history(&mut self, session_id: &str, command: &str) {
+        if let Some(session) = self.sessions.get_mut(session_id) {
+            session.history.push(command.to_string());
+            session
+                .config
+                .retention
+                .enforce(&mut session.history, session.snapshot_history.as_deref());
+        }
+    }
+
+    pub async fn list_sessions(&self, args: ListSessionsArgs) -> Result<serde_json::Value> {
+        let page = Page::of(
+            self.sessions
+                .values()
+                .map(|session| (session.created_at, session)),
+            args.page,
+            args.page_size,
+        )?;
+        let sessions: Vec<serde_json::Value> =
+            page.items.iter().map(|session| session.summary()).collect();
+
+        Ok(serde_json::json!({
+            "sessions": sessions,
+            "count": sessions.len(),
+            "page": page.page,
+            "pageSize": page.page_size,
+            "totalCount": page.total_count,
+            "hasMore": page.has_more(),
+            "draining": self.drain.as_ref().map(Drain::summary)
+        }))
+    }
+
+    /// One session's `list_sessions` entry with its uptime, terminal size
+    /// and activity counts
+    pub async fn get_session_info(&self, args: GetSessionInfoArgs) -> Result<serde_json::Value> {
+        let session = self
+            .sessions
+            .get(&args.session_id)
+            .ok_or_else(|| HtMcpError::SessionNotFound(args.session_id.clone()))?;
+        // Before reading the size, which counts as a snapshot itself
+        let snapshots = session.counters.snapshots();
+        // The size of an exited session's terminal went with its event loop
+        let screen = match session.is_alive() {
+            true => Some(
+                request_screen(&session.command_tx, &session.snapshot_permits)
+                    .await
+                    .map_err(|e| session.exit.explain(&args.session_id, e)),
+            ),
+            false => None,
+        };
+        let (cols, rows) = match screen {
+            Some(Ok(screen)) => (Some(screen.cols), Some(screen.rows)),
+            // Exited in the meantime
+            Some(Err(HtMcpError::SessionExited(_))) | None => (None, None),
+            Some(Err(e)) => return Err(e),
+        };
+
+        let mut info = session.summary();
+        info["uptimeSecs"] =
+            serde_json::json!(session.created_at.elapsed().unwrap_or_default().as_secs());
+        info["cols"] = serde_json::json!(cols);
+        info["rows"] = serde_json::json!(rows);
+        info["snapshotsTaken"] = serde_json::json!(snapshots);
+        info["inputBytes"] = serde_json::json!(session.counters.input_bytes());
+        info["outputBytes"] = serde_json::json!(session.activity.output_bytes());
+        info["commandsRun"] = serde_json::json!(session.history.len());
+        Ok(info)
+    }
+
+    /// Follows a file in a tail session, created unless one follows the file
+    /// already, and returns the lines it printed from `cursor` on that
+    /// match `filterPattern`
+    pub async fn tail_file(
+        &mut self,
+        args: TailFileArgs,
+        created_by: Option<ClientIdentity>,
+    ) -> Result<serde_json::Value> {
+        let lines = args.lines.unwrap_or(DEFAULT_TAIL_LINES);
+        if !(1..=MAX_TAIL_LINES).contains(&lines) {
+            return Err(HtMcpError::InvalidRequest(format!(
+                "lines must be between 1 and {}",
+                MAX_TAIL_LINES
+            )));
+        }
+        let filter = match args.filter_pattern.as_deref().map(Regex::new) {
+            Some(Ok(filter)) => Some(filter),
+            Some(Err(e)) => {
+                return Err(HtMcpError::InvalidRequest(format!(
+                    "Invalid filterPattern: {}",
+                    e
+                )))
+            }
+            None => None,
+        };
+        let path = std::fs::canonicalize(&args.path)
+            .ok()
+            .filter(|path| path.is_file())
+            .ok_or_else(|| {
+                HtMcpError::InvalidRequest(format!(
+                    "{} is not a file that can be tailed",
+                    args.path
+                ))
+            })?;
+
+        let existing = self
+            .sessions
+            .values()
+            .find(|session| session.tail.as_ref().is_some_and(|tail| tail.path == path))
+            .map(|session| session.id.clone());
+        let reused = existing.is_some();
+        let session_id = match existing {
+            Some(session_id) => session_id,
+            None => {
+                self.start_tail(path, lines, args.follow.unwrap_or(true), created_by)
+                    .await?
+            }
+        };
+
+        let session = &self.sessions[&session_id];
+        let Some(tail) = session.tail.as_ref() else {
+            return Err(HtMcpError::Internal(format!(
+                "Session {} lost its tail",
+                session_id
+            )));
+        };
+        let page = tail
+            .buffer
+            .page(args.cursor.unwrap_or(0), filter.as_ref(), TAIL_PAGE_LINES);
+        let lines: Vec<serde_json::Value> = page
+            .lines
+            .iter()
+            .map(|(line, text)| serde_json::json!({"line": line, "text": text}))
+            .collect();
+        Ok(serde_json::json!({
+            "sessionId": session_id,
+            "path": tail.path,
+            "follow": tail.follow,
+            "reused": reused,
+            "isAlive": session.is_alive(),
+            "lines": lines,
+            "nextCursor": page.next_cursor,
+            "hasMore": page.next_cursor < tail.buffer.end(),
+            "droppedLines": page.dropped,
+        }))
+    }
+
+    /// Creates the tail session of `path` and waits briefly for the first
+    /// lines of a non-empty file
+    async fn start_tail(
+        &mut self,
+        path: PathBuf,
+        lines: usize,
+        follow: bool,
+        created_by: Option<ClientIdentity>,
+    ) -> Result<String> {
+        let command =
+            tail_file::tail_command(&path.to_string_lossy(), lines, follow, cfg!(windows));
+        let created = self
+            .create_session_by(
+                CreateSessionArgs {
+                    command: Some(command),
+                    enable_web_server: Some(false),
+                    ..Default::default()
+                },
+                created_by,
+            )
+            .await?;
+        let session_id = created["sessionId"]
+            .as_str()
+            .ok_or_else(|| HtMcpError::Internal("Created session has no id".to_string()))?
+            .to_string();
+        let session = self
+            .sessions
+            .get_mut(&session_id)
+            .ok_or_else(|| HtMcpError::SessionNotFound(session_id.clone()))?;
+
+        let buffer = Arc::new(TailBuffer::default());
+        buffer.collect(session.stream_tx.subscribe());
+        session.tail = Some(TailSession {
+            path: path.clone(),
+            follow,
+            buffer: Arc::clone(&buffer),
+        });
+        // The command waits for this to start, now that the buffer listens
+        session
+            .command_tx
+            .send(SessionCommand::Input(vec![keys::parse("Enter")]))
+            .await
+            .map_err(|e| HtMcpError::Internal(format!("Failed to start tail: {}", e)))?;
+        info!("Session {} tails {}", session_id, path.display());
+
+        if std::fs::metadata(&path).is_ok_and(|metadata| metadata.len() > 0) {
+            let deadline = Instant::now() + TAIL_FIRST_LINES_WAIT;
+            while buffer.end() == 0 && Instant::now() < deadline {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        }
+        Ok(session_id)
+    }
+
+    /// Closes tail sessions whose file was removed
+    pub async fn reap_vanished_tail_files(&mut self) {
+        let vanished: Vec<String> = self
+            .sessions
+            .values()
+            .filter(|session| {
+                session
+                    .tail
+                    .as_ref()
+                    .is_some_and(|tail| !tail.path.exists())
+            })
+            .map(|session| session.id.clone())
+            .collect();
+        for session_id in vanished {
+            info!("File tailed by session {} is gone", session_id);
+            if let Err(e) = self
+                .end_session(&session_id, TerminationReason::TailFileRemoved)
+                .await
+            {
+                warn!("Failed to close session {}: {}", session_id, e);
+            }
+        }
+    }
+
+    /// Memory, CPU and output volume of each session's process, newest
+    /// session first, and what the terminal size limits cost. Reuses the
+    /// previous report for `REPORT_CACHE_TTL`.
+    pub fn resource_report(&mut self, _args: GetResourceReportArgs) -> Result<serde_json::Value> {
+        if let Some((taken_at, report)) = &self.resource_report {
+            if taken_at.elapsed() < REPORT_CACHE_TTL {
+                let mut report = report.clone();
+                report["ageMs"] = serde_json::json!(taken_at.elapsed().as_millis() as u64);
+                return Ok(report);
+            }
+        }
+
+        self.account_recordings();
+        let mut sessions: Vec<&SessionInfo> = self.sessions.values().collect();
+        sessions.sort_by_key(|session| std::cmp::Reverse(session.created_at));
+        let pids: HashSet<u32> = sessions.iter().filter_map(|s| s.child.pid).collect();
+        self.process_sampler.retain(&pids);
+
+        let (mut total_rss_kb, mut total_cpu_percent, mut total_output_bytes) = (0, 0.0, 0);
+        let mut entries = Vec::with_capacity(sessions.len());
+        for session in sessions {
+            let uptime = session.created_at.elapsed().unwrap_or_default();
+            let running_since = Instant::now()
+                .checked_sub(uptime)
+                .unwrap_or_else(Instant::now);
+            let usage = session
+                .child
+                .pid
+                .and_then(|pid| self.process_sampler.sample(pid, running_since));
+            let output_bytes = session.activity.output_bytes();
+
+            if let Some(usage) = usage {
+                total_rss_kb += usage.rss_kb;
+                total_cpu_percent += usage.cpu_percent;
+            }
+            total_output_bytes += output_bytes;
+            entries.push(serde_json::json!({
+                "sessionId": session.id,
+                "pid": session.child.pid,
+                "memoryRssKb": usage.map(|u| u.rss_kb),
+                "cpuPercent": usage.map(|u| u.cpu_percent),
+                "outputBytes": output_bytes,
+                "uptimeSecs": uptime.as_secs()
+            }));
+        }
+
+        let report = serde_json::json!({
+            "sessions": entries,
+            "totals": {
+                "sessionCount": entries.len(),
+                "memoryRssKb": total_rss_kb,
+                "cpuPercent": total_cpu_percent,
+                "outputBytes": total_output_bytes
+            },
+            "limits": self.terminal_limits(),
+            "storage": self.storage.usage_summary(),
+            "ageMs": 0
+        });
+        self.resource_report = Some((Instant::now(), report.clone()));
+        Ok(report)
+    }
+
+    /// `max_cols` and `max_rows` with the memory a screen grid takes per
+    /// cell and at the largest size. Each session keeps one grid; a styled
+    /// snapshot replays onto another, and the alternate screen doubles both.
+    fn terminal_limits(&self) -> serde_json::Value {
+        let (max_cols, max_rows) = (self.config.max_cols, self.config.max_rows);
+        serde_json::json!({
+            "maxCols": max_cols,
+            "maxRows": max_rows,
+            "bytesPerCell": BYTES_PER_CELL,
+            "maxGridBytes": max_cols * max_rows * BYTES_PER_CELL
+        })
+    }
+
+    /// Tunnels with the session each one serves
+    pub fn list_tunnels(&mut self, args: ListTunnelsArgs) -> Result<serde_json::Value> {
+        let page = Page::of(
+            self.tunnel_manager
+                .list_tunnels()
+                .into_iter()
+                .map(|tunnel| (tunnel.created_at, tunnel)),
+            args.page,
+            args.page_size,
+        )?;
+        let tunnels: Vec<serde_json::Value> = page
+            .items
+            .iter()
+            .map(|tunnel| {
+                let session = self
+                    .sessions
+                    .values()
+                    .find(|session| session.tunnel_id.as_deref() == Some(tunnel.id.as_str()));
+                serde_json::json!({
+                    "id": tunnel.id,
+                    "url": tunnel.url,
+                    "localPort": tunnel.local_port,
+                    "provider": tunnel.provider,
+                    "isActive": tunnel.is_active,
+                    "hostnameStable": tunnel.hostname_stable,
+                    "tunnelUrlHistory": tunnel.url_history_json(),
+                    "createdAt": tunnel.created_at.duration_since(std::time::UNIX_EPOCH)
+                        .unwrap_or_default().as_secs(),
+                    "sessionId": session.map(|session| &session.id),
+                    "createdBy": session.and_then(|session| session.created_by.as_ref()),
+                    "pool": self.tunnel_manager.pool_entry(&tunnel.id)
+                })
+            })
+            .collect();
+
+        Ok(serde_json::json!({
+            "tunnels": tunnels,
+            "pool": self.tunnel_pool_summary(),
+            "page": page.page,
+            "pageSize": page.page_size,
+            "totalCount": page.total_count,
+            "hasMore": page.has_more()
+        }))
+    }
+
+    /// Captures what another instance needs to recreate the session: its
+    /// creation settings, current screen and command history. The session
+    /// itself keeps running.
+    pub async fn export_session_for_migration(&self, session_id: &str) -> Result<MigrationBundle> {
+        let session = self
+            .sessions
+            .get(session_id)
+            .ok_or_else(|| HtMcpError::SessionNotFound(session_id.to_string()))?;
+
+        let snapshot_result = self
+            .take_snapshot(TakeSnapshotArgs {
+                session_id: session_id.to_string(),
+                format: None,
+                trim_trailing_blank_lines: None,
+                annotate: None,
+                annotate_patterns: None,
+                word_wrap: None,
+                probe_only: None,
+                max_tokens: None,
+                include_provenance: None,
+                timeout_ms: None,
+            })
+            .await?;
+
+        Ok(MigrationBundle {
+            session_id: session_id.to_string(),
+            config: session.config.clone(),
+            snapshot: snapshot_result["snapshot"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            history: session.history.clone(),
+            exported_at: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        })
+    }
+
+    /// Re-spawns an exported session here with its settings and history.
+    /// The new PTY starts fresh; the source's last screen is kept as context.
+    /// Returns the new session id.
+    pub async fn import_migrated_session(&mut self, bundle: MigrationBundle) -> Result<String> {
+        let result = self.create_session(bundle.config.to_create_args()).await?;
+        let session_id = result["sessionId"]
+            .as_str()
+            .ok_or_else(|| HtMcpError::Internal("Created session has no id".to_string()))?
+            .to_string();
+
+        let session = self
+            .sessions
+            .get_mut(&session_id)
+            .ok_or_else(|| HtMcpError::SessionNotFound(session_id.clone()))?;
+        session.history = bundle.history;
+        session
+            .config
+            .retention
+            .enforce(&mut session.history, session.snapshot_history.as_deref());
+        session.migrated_from = Some(MigratedContext {
+            source_session_id: bundle.session_id.clone(),
+            snapshot: bundle.snapshot,
+            exported_at: bundle.exported_at,
+        });
+
+        info!("Imported session {} as {}", bundle.session_id, session_id);
+        Ok(session_id)
+    }
+
+    /// Moves a session to `target`: exports it, imports it there and closes
+    /// it here. The source session is left untouched if the import fails.
+    pub async fn migrate_session(
+        &mut self,
+        session_id: &str,
+        target: &mut SessionManager,
+    ) -> Result<String> {
+        let bundle = self.export_session_for_migration(session_id).await?;
+        let new_session_id = target.import_migrated_session(bundle).await?;
+        self.close_session(CloseSessionArgs {
+            session_id: session_id.to_string(),
+            timeout_ms: None,
+        })
+        .await?;
+        Ok(new_session_id)
+    }
+
+    /// The server's state for `ht_import_state` on another host: live
+    /// sessions as definitions to recreate, and the recording records
+    pub async fn state_manifest(&self, include_env: bool) -> Result<StateManifest> {
+        let mut session_ids: Vec<&String> = self.sessions.keys().collect();
+        session_ids.sort();
+        let mut sessions = Vec::with_capacity(session_ids.len());
+        for session_id in session_ids {
+            let mut bundle = self.export_session_for_migration(session_id).await?;
+            if !include_env {
+                bundle.config.redact_env();
+            }
+            sessions.push(bundle);
+        }
+        let recordings: Vec<_> = self.storage.recordings().cloned().collect();
+        Ok(StateManifest::new(sessions, recordings))
+    }
+
+    /// Writes the [`state_manifest`](Self::state_manifest) archive to a
+    /// server-local file
+    pub async fn export_state(&self, args: ExportStateArgs) -> Result<serde_json::Value> {
+        let include_env = args.include_env.unwrap_or(false);
+        let manifest = self.state_manifest(include_env).await?;
+        let path = PathBuf::from(&args.path);
+        let recording_files = state::write(&path, &manifest)?;
+        info!(
+            "Exported {} sessions and {} recordings to {}",
+            manifest.sessions.len(),
+            manifest.recordings.len(),
+            path.display()
+        );
+        Ok(serde_json::json!({
+            "path": path.display().to_string(),
+            "formatVersion": manifest.format_version,
+            "sessions": manifest.sessions.len(),
+            "recordings": manifest.recordings.len(),
+            "recordingFiles": recording_files,
+            "envIncluded": include_env
+        }))
+    }
+
+    /// Imports a server-local archive written by `export_state`
+    pub async fn import_state(&mut self, args: ImportStateArgs) -> Result<serde_json::Value> {
+        let path = PathBuf::from(&args.path);
+        self.import_state_from(|| state::open(&path), &args.path, args.recordings_remap)
+            .await
+    }
+
+    /// Recreates the sessions of a state archive and adopts its recording
+    /// records, writing the recording files it holds under the remap's
+    /// target. `open` is called once per pass over the archive; `name` says
+    /// where it came from. The whole manifest is validated first; sessions
+    /// that fail to start are reported and the rest still imported.
+    pub async fn import_state_from<R: std::io::Read>(
+        &mut self,
+        open: impl Fn() -> Result<R>,
+        name: &str,
+        recordings_remap: Option<state::PathRemap>,
+    ) -> Result<serde_json::Value> {
+        self.refuse_if_draining("new sessions")?;
+        if let Some(remap) = &recordings_remap {
+            remap.check_target(self.config.storage.recordings_dir.as_deref())?;
+        }
+        let mut manifest = state::read_manifest(open()?, name)?;
+
+        // Recording files are only written where the remap puts them
+        let mut targets = HashMap::new();
+        for recording in &mut manifest.recordings {
+            let entry = state::recording_entry(recording);
+            let (Some(path), Some(remap)) = (&recording.path, &recordings_remap) else {
+                continue;
+            };
+            let path = remap.apply(path);
+            if let Some(entry) = entry.filter(|_| remap.covers(&path) && !path.exists()) {
+                targets.insert(entry, path.clone());
+            }
+            recording.path = Some(path);
+        }
+        let recording_files = if targets.is_empty() {
+            0
+        } else {
+            state::extract_recordings(open()?, name, &targets)?.len()
+        };
+
+        let mut warnings = Vec::new();
+        let mut recordings = 0;
+        for recording in manifest.recordings {
+            if let Some(path) = recording.path.as_ref().filter(|path| !path.exists()) {
+                warnings.push(format!(
+                    "Recording {} of session {} does not exist here",
+                    path.display(),
+                    recording.session_id
+                ));
+            }
+            self.storage.import_recording(recording);
+            recordings += 1;
+        }
+
+        let mut sessions = Vec::new();
+        for bundle in manifest.sessions {
+            let source_session_id = bundle.session_id.clone();
+            let redacted_env = bundle.config.redacted_env.clone();
+            match self.import_migrated_session(bundle).await {
+                Ok(session_id) => {
+                    if !redacted_env.is_empty() {
+                        warnings.push(format!(
+                            "Session {} was recreated without its env variables {}, whose values were not exported",
+                            source_session_id,
+                            redacted_env.join(", ")
+                        ));
+                    }
+                    sessions.push(serde_json::json!({
+                        "sourceSessionId": source_session_id,
+                        "sessionId": session_id
+                    }))
+                }
+                Err(e) => warnings.push(format!(
+                    "Session {} was not recreated: {}",
+                    source_session_id, e
+                )),
+            }
+        }
+
+        info!(
+            "Imported {} sessions and {} recordings from {}",
+            sessions.len(),
+            recordings,
+            name
+        );
+        Ok(serde_json::json!({
+            "sessions": sessions,
+            "recordings": recordings,
+            "recordingFiles": recording_files,
+            "warnings": warnings
+        }))
+    }
+
+    /// Relaunches the cloudflared process behind a session's tunnel, e.g.
+    /// after TryCloudflare dropped it. The web server keeps running on the
+    /// same port; only the public URL changes.
+    pub async fn reconnect_tunnel(
+        &mut self,
+        args: ReconnectTunnelArgs,
+    ) -> Result<serde_json::Value> {
+        self.refuse_if_draining("new tunnels")?;
+        let session = self
+            .sessions
+            .get_mut(&args.session_id)
+            .ok_or_else(|| HtMcpError::SessionNotFound(args.session_id.clone()))?;
+        let tunnel_id = session.tunnel_id.clone().ok_or_else(|| {
+            HtMcpError::InvalidRequest(format!(
+                "Session {} has no tunnel to reconnect",
+                args.session_id
+            ))
+        })?;
+
+        let tunnel_info = self.tunnel_manager.restart_tunnel(&tunnel_id).await?;
+        let previous_url = session.tunnel_url.replace(tunnel_info.url.clone());
+        warn!(
+            "Reconnected tunnel of session {}: {} -> {}",
+            args.session_id,
+            previous_url.as_deref().unwrap_or("none"),
+            tunnel_info.url
+        );
+
+        Ok(serde_json::json!({
+            "sessionId": args.session_id,
+            "tunnelId": tunnel_id,
+            "tunnelUrl": tunnel_info.url,
+            "previousTunnelUrl": previous_url,
+            "tunnelUrlHistory": tunnel_info.url_history_json(),
+        }))
+    }
+
+    /// Exposes any local port through a tunnel of its own, not tied to a
+    /// session; it runs until `ht_stop_tunnel` or shutdown
+    pub async fn create_tunnel(&mut self, args: CreateTunnelArgs) -> Result<serde_json::Value> {
+        self.refuse_if_draining("new tunnels")?;
+        if args.port == 0 {
+            return Err(HtMcpError::InvalidRequest(
+                "port must be between 1 and 65535".to_string(),
+            ));
+        }
+        // Publishes whatever listens there, not just session web servers
+        if !self.config.enable_admin_tools && !self.config.tunnel_ports.contains(&args.port) {
+            return Err(HtMcpError::InvalidRequest(format!(
+                "Port {} is not in tunnel_ports; list it in the config, or set enable_admin_tools to publish any port",
+                args.port
+            )));
+        }
+        let mut config = self.tunnel_config(args.port);
+        if let Some(timeout_secs) = args.timeout_secs {
+            if !(1..=MAX_TUNNEL_TIMEOUT_SECS).contains(&timeout_secs) {
+                return Err(HtMcpError::InvalidRequest(format!(
+                    "timeoutSecs must be between 1 and {}, got {}",
+                    MAX_TUNNEL_TIMEOUT_SECS, timeout_secs
+                )));
+            }
+            config = config.with_timeout(timeout_secs);
+        }
+
+        let tunnel = self.tunnel_manager.create_tunnel(config).await?;
+        info!(
+            "Tunnel {} exposes port {}: {}",
+            tunnel.id, tunnel.local_port, tunnel.url
+        );
+        Ok(serde_json::json!({
+            "tunnelId": tunnel.id,
+            "url": tunnel.url,
+            "localPort": tunnel.local_port,
+            "provider": tunnel.provider,
+            "createdAt": tunnel.created_at.duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default().as_secs(),
+        }))
+    }
+
+    /// Stops a tunnel. A session whose tunnel it was keeps running without
+    /// one.
+    pub async fn stop_tunnel(&mut self, args: StopTunnelArgs) -> Result<serde_json::Value> {
+        if self.tunnel_manager.get_tunnel(&args.tunnel_id).is_none() {
+            return Err(HtMcpError::InvalidRequest(format!(
+                "No tunnel {}; ht_list_tunnels lists them",
+                args.tunnel_id
+            )));
+        }
+        self.tunnel_manager.stop_tunnel(&args.tunnel_id).await?;
+
+        let session = self
+            .sessions
+            .values_mut()
+            .find(|session| session.tunnel_id.as_deref() == Some(args.tunnel_id.as_str()));
+        let session_id = session.map(|session| {
+            session.tunnel_id = None;
+            session.tunnel_url = None;
+            session.id.clone()
+        });
+        Ok(serde_json::json!({
+            "tunnelId": args.tunnel_id,
+            "stopped": true,
+            "sessionId": session_id,
+        }))
+    }
+
+    /// Extends a session's lease by `extendSecs`
+    pub fn renew_lease(&mut self, args: RenewLeaseArgs) -> Result<serde_json::Value> {
+        let session = self
+            .sessions
+            .get_mut(&args.session_id)
+            .ok_or_else(|| HtMcpError::SessionNotFound(args.session_id.clone()))?;
+        let lease = session.lease.as_mut().ok_or_else(|| {
+            HtMcpError::InvalidRequest(format!(
+                "Session {} has no lease; create it with leaseSecs",
+                args.session_id
+            ))
+        })?;
+        if args.extend_secs == 0 {
+            return Err(HtMcpError::InvalidRequest(
+                "extendSecs must be at least 1".to_string(),
+            ));
+        }
+
+        lease.renew(Duration::from_secs(args.extend_secs))?;
+        info!(
+            "Renewed lease of session {} ({} renewals)",
+            args.session_id,
+            lease.renewals()
+        );
+        Ok(serde_json::json!({
+            "sessionId": args.session_id,
+            "lease": lease.summary(),
+        }))
+    }
+
+    /// Gives `holder` the session's interaction lock for `ttlSecs`, or
+    /// restarts the TTL if `holder` has it already. Another holder's lock is
+    /// refused with `SessionLocked` unless `override` takes it over.
+    pub fn acquire_lock(&mut self, args: AcquireLockArgs) -> Result<serde_json::Value> {
+        let ttl_secs = args.ttl_secs.unwrap_or(DEFAULT_LOCK_TTL_SECS);
+        if !(1..=MAX_LOCK_TTL_SECS).contains(&ttl_secs) {
+            return Err(HtMcpError::InvalidRequest(format!(
+                "ttlSecs must be between 1 and {}",
+                MAX_LOCK_TTL_SECS
+            )));
+        }
+        if args.holder.is_empty() {
+            return Err(HtMcpError::InvalidRequest(
+                "holder must not be empty".to_string(),
+            ));
+        }
+        let override_lock = self.lock_override(args.override_lock)?;
+        let session = self
+            .sessions
+            .get_mut(&args.session_id)
+            .ok_or_else(|| HtMcpError::SessionNotFound(args.session_id.clone()))?;
+        if let Some(expired) = session.expire_lock() {
+            publish_lock_expired(&self.events, &args.session_id, expired);
+        }
+
+        let ttl = Duration::from_secs(ttl_secs);
+        let (lock, previous_holder) = match session.lock.take() {
+            Some(mut lock) if lock.holder() == args.holder => {
+                lock.extend(ttl);
+                (lock, None)
+            }
+            Some(lock) if !override_lock => {
+                let err = lock.refuse(&args.session_id);
+                session.lock = Some(lock);
+                return Err(err);
+            }
+            previous => (
+                InteractionLock::new(args.holder.clone(), ttl),
+                previous.map(|lock| lock.holder().to_string()),
+            ),
+        };
+        let summary = lock.summary();
+        let expires_at = lock.expires_at();
+        session.lock = Some(lock);
+        match &previous_holder {
+            Some(previous) => {
+                warn!(
+                    "{} took the lock of session {} from {}",
+                    args.holder, args.session_id, previous
+                );
+                self.events.publish(SessionEvent::LockStolen {
+                    session_id: args.session_id.clone(),
+                    holder: args.holder.clone(),
+                    previous_holder: previous.clone(),
+                });
+            }
+            None => {
+                info!(
+                    "{} holds the lock of session {}",
+                    args.holder, args.session_id
+                );
+                self.events.publish(SessionEvent::LockAcquired {
+                    session_id: args.session_id.clone(),
+                    holder: args.holder.clone(),
+                    expires_at,
+                });
+            }
+        }
+        Ok(serde_json::json!({
+            "sessionId": args.session_id,
+            "lock": summary,
+            "previousHolder": previous_holder,
+        }))
+    }
+
+    /// Frees the session's interaction lock. Releasing an unlocked session
+    /// succeeds with `released: false`; another holder's lock needs
+    /// `override`.
+    pub fn release_lock(&mut self, args: ReleaseLockArgs) -> Result<serde_json::Value> {
+        let override_lock = self.lock_override(args.override_lock)?;
+        let session = self
+            .sessions
+            .get_mut(&args.session_id)
+            .ok_or_else(|| HtMcpError::SessionNotFound(args.session_id.clone()))?;
+        if let Some(expired) = session.expire_lock() {
+            publish_lock_expired(&self.events, &args.session_id, expired);
+        }
+
+        let holder = match session.lock.as_ref() {
+            None => None,
+            Some(lock) if lock.holder() != args.holder && !override_lock => {
+                return Err(lock.refuse(&args.session_id));
+            }
+            Some(lock) => Some(lock.holder().to_string()),
+        };
+        if let Some(holder) = &holder {
+            session.lock = None;
+            info!(
+                "Released the lock of session {} held by {}",
+                args.session_id, holder
+            );
+            self.events.publish(SessionEvent::LockReleased {
+                session_id: args.session_id.clone(),
+                holder: holder.clone(),
+                reason: LockReleaseReason::Released,
+            });
+        }
+        Ok(serde_json::json!({
+            "sessionId": args.session_id,
+            "released": holder.is_some(),
+            "holder": holder,
+        }))
+    }
+
+    /// Refuses a call to an input tool that would type into a tail session,
+    /// a read-only session, or a session locked by someone else. Other
+    /// tools, and sessions that do not exist, are left to the tool. Input
+    /// to existing sessions is audited either way.
+    pub fn check_input_allowed(
+        &mut self,
+        tool_name: &str,
+        arguments: &serde_json::Value,
+    ) -> Result<()> {
+        let Some(mut claim) =
+            interaction_lock::claim(tool_name, arguments, self.config.enable_admin_tools)?
+        else {
+            return Ok(());
+        };
+        if tool_name == "ht_bulk_close_sessions" && claim.session_ids.is_empty() {
+            claim.session_ids = self.bulk_close_candidates(arguments);
+        }
+        let source = InputAudit::Mcp(tool_name);
+        for session_id in &claim.session_ids {
+            if let Err(e) = self.input_policy(session_id, &claim, &source) {
+                self.audit_input(session_id, &source, Some(&e));
+                return Err(e);
+            }
+        }
+        // Only once every session let the call through, since a refusal
+        // fails the whole call. A close is not input to record.
+        if CLOSE_TOOLS.contains(&tool_name) {
+            return Ok(());
+        }
+        for session_id in &claim.session_ids {
+            self.audit_input(session_id, &source, None);
+        }
+        Ok(())
+    }
+
+    /// The sessions an `ht_bulk_close_sessions` call without `sessionIds`
+    /// closes: all of them, or those with all of its `filterTags`. Bad
+    /// arguments are left for the tool to refuse.
+    fn bulk_close_candidates(&self, arguments: &serde_json::Value) -> Vec<String> {
+        let filter_tags: HashMap<String, String> = arguments
+            .get("filterTags")
+            .and_then(|tags| serde_json::from_value(tags.clone()).ok())
+            .unwrap_or_default();
+        self.sessions
+            .values()
+            .filter(|session| {
+                filter_tags
+                    .iter()
+                    .all(|(name, value)| session.config.tags.get(name) == Some(value))
+            })
+            .map(|session| session.id.clone())
+            .collect()
+    }
+
+    /// Input a web viewer typed, to be sent without holding the manager.
+    /// It goes through the same policy as input tools; a viewer names no
+    /// lock holder, so a locked session refuses it.
+    pub fn prepare_viewer_input(&mut self, input: ViewerInput) -> Result<PendingKeys> {
+        if !self.sessions.contains_key(&input.session_id) {
+            return Err(HtMcpError::SessionNotFound(input.session_id));
+        }
+        let claim = LockClaim {
+            session_ids: vec![input.session_id.clone()],
+            holder: None,
+            override_lock: false,
+        };
+        let source = InputAudit::Web(&input.peer);
+        let allowed = self.input_policy(&input.session_id, &claim, &source);
+        self.audit_input(&input.session_id, &source, allowed.as_ref().err());
+        allowed?;
+
+        let session = &self.sessions[&input.session_id];
+        session.ensure_alive()?;
+        Ok(PendingKeys {
+            session_id: input.session_id,
+            keys: input.keys,
+            profile: InputProfile::Instant,
+            seed: None,
+            keystrokes: vec![(Duration::ZERO, input.seqs)],
+            command_tx: session.command_tx.clone(),
+            input_lock: session.input_lock.clone(),
+            exit: session.exit.clone(),
+        })
+    }
+
+    /// The receiving end of what web viewers type, for the server to feed
+    /// to [`Self::prepare_viewer_input`]; `None` once taken
+    pub fn take_viewer_input(&mut self) -> Option<mpsc::Receiver<ViewerInput>> {
+        self.viewer_input_rx.take()
+    }
+
+    /// Whether `claim` may send input to `session_id`; a session that does
+    /// not exist is left to the caller
+    fn input_policy(
+        &mut self,
+        session_id: &str,
+        claim: &LockClaim,
+        source: &InputAudit,
+    ) -> Result<()> {
+        let Some(session) = self.sessions.get_mut(session_id) else {
+            return Ok(());
+        };
+        // Closing sends no input, so only another holder's lock stands in
+        // its way
+        let closes = matches!(source, InputAudit::Mcp(tool) if CLOSE_TOOLS.contains(tool));
+        if !closes {
+            if let Some(tail) = &session.tail {
+                return Err(HtMcpError::InvalidRequest(format!(
+                    "Session {} follows {} and takes no input",
+                    session_id,
+                    tail.path.display()
+                )));
+            }
+            if session.config.read_only {
+                return Err(HtMcpError::InvalidRequest(format!(
+                    "Session {} is read-only and takes no input",
+                    session_id
+                )));
+            }
+        }
+        if let Some(expired) = session.expire_lock() {
+            publish_lock_expired(&self.events, session_id, expired);
+        }
+        let Some(lock) = session.lock.as_ref() else {
+            return Ok(());
+        };
+        if !claim.allows(lock) {
+            return Err(lock.refuse(session_id));
+        }
+        if claim.holder.as_deref() != Some(lock.holder()) {
+            warn!(
+                "{} overrode the lock of session {} held by {}",
+                source,
+                session_id,
+                lock.holder()
+            );
+        }
+        Ok(())
+    }
+
+    /// Logs and publishes input to `session_id`, refused with `refused` or
+    /// let through
+    fn audit_input(&mut self, session_id: &str, source: &InputAudit, refused: Option<&HtMcpError>) {
+        let Some(session) = self.sessions.get_mut(session_id) else {
+            return;
+        };
+        match refused {
+            Some(e) => info!(
+                "Refused input to session {} from {}: {}",
+                session_id, source, e
+            ),
+            None => {
+                info!("Input to session {} from {}", session_id, source);
+                session.last_input_at = Some(chrono::Utc::now());
+            }
+        }
+        let (source, tool, peer) = match source {
+            InputAudit::Mcp(tool) => (InputSource::Mcp, Some(tool.to_string()), None),
+            InputAudit::Web(peer) => (InputSource::Web, None, Some(peer.to_string())),
+        };
+        self.events.publish(SessionEvent::Input {
+            session_id: session_id.to_string(),
+            source,
+            tool,
+            peer,
+            accepted: refused.is_none(),
+            refused: refused.map(ToString::to_string),
+        });
+    }
+
+    /// `override` of a lock tool, refused unless admin tools are enabled
+    fn lock_override(&self, override_lock: Option<bool>) -> Result<bool> {
+        let override_lock = override_lock.unwrap_or(false);
+        if override_lock && !self.config.enable_admin_tools {
+            return Err(HtMcpError::InvalidRequest(
+                "override of a session lock needs enable_admin_tools in the config".to_string(),
+            ));
+        }
+        Ok(override_lock)
+    }
+
+    /// Changes how much history a session keeps, trimming what is over the
+    /// new limits right away
+    pub fn set_retention(&mut self, args: SetRetentionArgs) -> Result<serde_json::Value> {
+        let session = self
+            .sessions
+            .get_mut(&args.session_id)
+            .ok_or_else(|| HtMcpError::SessionNotFound(args.session_id.clone()))?;
+        let retention = session
+            .config
+            .retention
+            .with_overrides(&args.retention, &self.config.retention)?;
+
+        session.config.retention = retention;
+        retention.enforce(&mut session.history, session.snapshot_history.as_deref());
+        info!(
+            "Set retention of session {}: {:?}",
+            args.session_id, retention
+        );
+        Ok(serde_json::json!({
+            "sessionId": args.session_id,
+            "retention": retention.report(&session.history, session.snapshot_history.as_deref()),
+        }))
+    }
+
+    /// Takes out the tunnels whose session's web server a restart moved to
+    /// another port, for [`TunnelRetarget::run`] to point at the new port.
+    /// Their sessions show no tunnel until the replacement is handed back.
+    pub fn prepare_tunnel_retargets(&mut self) -> Vec<TunnelRetarget> {
+        let moved: Vec<(String, TunnelInfo, u16)> = self
+            .sessions
+            .values()
+            .filter_map(|session| {
+                let port = session.web_server.as_ref()?.port();
+                let tunnel = self
+                    .tunnel_manager
+                    .get_tunnel(session.tunnel_id.as_ref()?)?;
+                (tunnel.local_port != port).then(|| (session.id.clone(), tunnel, port))
+            })
+            .collect();
+
+        let mut retargets = Vec::with_capacity(moved.len());
+        for (session_id, tunnel, port) in moved {
+            // A pooled tunnel only needs its relay pointed at the new port
+            if self.tunnel_manager.repoint_pooled(&tunnel.id, port) {
+                info!(
+                    "Tunnel of session {} follows its web server to port {}",
+                    session_id, port
+                );
+                continue;
+            }
+            let Some(old) = self.tunnel_manager.detach(&tunnel.id) else {
+                continue;
+            };
+            // A named tunnel comes back under the same name, so with the
+            // same hostname
+            let tunnel_name = self
+                .sessions
+                .get(&session_id)
+                .and_then(|session| session.config.tunnel_name.clone())
+                .unwrap_or_else(|| session_id.clone());
+            let config = self
+                .session_tunnel_config(port, &tunnel_name)
+                .with_provider(tunnel.provider);
+            if let Some(session) = self.sessions.get_mut(&session_id) {
+                session.tunnel_id = None;
+                session.tunnel_url = None;
+            }
+            retargets.push(TunnelRetarget {
+                session_id,
+                old,
+                url_history: tunnel.tunnel_url_history,
+                config,
+            });
+        }
+        retargets
+    }
+
+    /// Hands a replacement tunnel to its session, or stops it if the
+    /// session ended while it was being started
+    pub fn finish_tunnel_retarget(&mut self, retargeted: RetargetedTunnel) {
+        let RetargetedTunnel {
+            session_id,
+            url_history,
+            replacement,
+        } = retargeted;
+        let replacement = match replacement {
+            Ok(replacement) => replacement,
+            Err(e) => {
+                error!(
+                    "Failed to re-create the tunnel of session {}: {}",
+                    session_id, e
+                );
+                return;
+            }
+        };
+        let Some(session) = self.sessions.get_mut(&session_id) else {
+            tokio::spawn(async move {
+                if let Err(e) = replacement.stop().await {
+                    warn!("Failed to stop tunnel: {}", e);
+                }
+            });
+            return;
+        };
+        let tunnel_info = self.tunnel_manager.attach(replacement);
+        info!(
+            "Tunnel of session {} follows its web server to port {}: {}",
+            session_id, tunnel_info.local_port, tunnel_info.url
+        );
+        // Reported as one tunnel whose URL changed
+        self.tunnel_manager
+            .carry_url_history(&tunnel_info.id, url_history);
+        session.tunnel_id = Some(tunnel_info.id);
+        session.tunnel_url = Some(tunnel_info.url);
+    }
+
+    /// Warns about leases running out within `lease_warning_secs` and closes
+    /// sessions whose lease expired. Expiring sessions are always warned
+    /// about before they are closed.
+    pub async fn reap_expired_leases(&mut self) {
+        let warning_window = Duration::from_secs(self.config.lease_warning_secs);
+        let mut expired = Vec::new();
+        for session in self.sessions.values_mut() {
+            let Some(lease) = session.lease.as_mut() else {
+                continue;
+            };
+            if lease.take_warning(warning_window) {
+                warn!(
+                    "Lease of session {} expires in {:?}",
+                    session.id,
+                    lease.remaining()
+                );
+                self.events.publish(SessionEvent::LeaseExpiring {
+                    session_id: session.id.clone(),
+                    expires_in_ms: lease.remaining().as_millis() as u64,
+                });
+            }
+            if lease.is_expired() {
+                expired.push(session.id.clone());
+            }
+        }
+
+        for session_id in expired {
+            info!("Lease of session {} expired", session_id);
+            if let Err(e) = self
+                .end_session(&session_id, TerminationReason::LeaseExpired)
+                .await
+            {
+                warn!("Failed to close session {}: {}", session_id, e);
+            }
+        }
+    }
+
+    /// Frees interaction locks whose TTL ran out, so their release is
+    /// announced even if nobody touches the session
+    pub fn reap_expired_locks(&mut self) {
+        for session in self.sessions.values_mut() {
+            if let Some(expired) = session.expire_lock() {
+                publish_lock_expired(&self.events, &session.id, expired);
+            }
+        }
+    }
+
+    /// Stops accepting new sessions and tunnels; existing sessions keep
+    /// working until `grace` runs out. Calling it again restarts the grace
+    /// period with the new settings.
+    pub fn start_drain(
+        &mut self,
+        grace: Duration,
+        exit_when_empty: bool,
+    ) -> Result<serde_json::Value> {
+        let drain = Drain::new(grace, exit_when_empty)?;
+        warn!(
+            "Draining: refusing new sessions, closing {} remaining in {:?}",
+            self.sessions.len(),
+            grace
+        );
+        self.events.publish(SessionEvent::ServerDraining {
+            grace_ms: grace.as_millis() as u64,
+            session_count: self.sessions.len(),
+            exit_when_empty,
+        });
+        let summary = drain.summary();
+        self.drain = Some(drain);
+        // Dropping a pooled session closes its command channel, ending it
+        let pooled = self.warm_pool.clear();
+        if !pooled.is_empty() {
+            info!("Closing {} idle warm pool sessions", pooled.len());
+        }
+        for session in &pooled {
+            retire_recording(&mut self.storage, session);
+        }
+
+        Ok(serde_json::json!({
+            "draining": summary,
+            "sessionCount": self.sessions.len()
+        }))
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.drain.is_some()
+    }
+
+    /// Closes the sessions left once the grace period is over. Returns
+    /// whether draining finished and the server can exit.
+    pub async fn check_drain(&mut self) -> bool {
+        let Some(drain) = &self.drain else {
+            return false;
+        };
+        if drain.is_over() {
+            let remaining: Vec<String> = self.sessions.keys().cloned().collect();
+            for session_id in remaining {
+                info!("Closing session {} at end of drain", session_id);
+                if let Err(e) = self
+                    .end_session(&session_id, TerminationReason::ServerShutdown)
+                    .await
+                {
+                    warn!("Failed to close session {}: {}", session_id, e);
+                }
+            }
+            return true;
+        }
+        drain.is_done(self.sessions.len())
+    }
+
+    /// The sessions the warm pool is short of, after dropping idle ones
+    /// whose process has exited; `None` if it is full, disabled, or the
+    /// server is draining
+    pub fn prepare_warm_pool_refill(&mut self) -> Option<WarmPoolRefill> {
+        let exited = self
+            .warm_pool
+            .remove_unless(|session| session.child.exit_code().is_none());
+        for session in exited {
+            warn!("Dropping warm pool session {}, which exited", session.id);
+            retire_recording(&mut self.storage, &session);
+        }
+        if self.drain.is_some() {
+            return None;
+        }
+        let count = self.warm_pool.deficit(self.config.warm_pool.size);
+        (count > 0).then(|| WarmPoolRefill {
+            count,
+            args: CreateSessionArgs {
+                command: Some(self.config.warm_pool.command.clone()),
+                ..CreateSessionArgs::default()
+            },
+            spawner: self.spawner(),
+        })
+    }
+
+    /// Adds sessions started by [`WarmPoolRefill::run`] to the pool, or
+    /// drops them if the server started draining meanwhile
+    pub fn finish_warm_pool_refill(&mut self, sessions: Vec<SessionInfo>) {
+        for session in sessions {
+            track_recording(&mut self.storage, &session);
+            if self.drain.is_some() {
+                // Dropping a pooled session closes its command channel,
+                // ending it
+                retire_recording(&mut self.storage, &session);
+                continue;
+            }
+            debug!("Started warm pool session {}", session.id);
+            self.warm_pool.push(session);
+        }
+    }
+
+    /// A manager without sessions sharing this one's config, event bus and
+    /// startup samples, for starting sessions outside this one's lock
+    fn spawner(&self) -> SessionManager {
+        let mut spawner = SessionManager::with_config(self.config.clone());
+        spawner.events = self.events.clone();
+        spawner.startup_samples = self.startup_samples.clone();
+        #[cfg(test)]
+        {
+            spawner.fail_at_stage = self.fail_at_stage;
+        }
+        spawner
+    }
+
+    /// An idle session from the warm pool, set up for `args`; `None` when
+    /// the pool is empty
+    fn adopt_pooled(&mut self, args: &CreateSessionArgs) -> Option<SessionInfo> {
+        let mut session = loop {
+            let session = self.warm_pool.take()?;
+            if session.child.exit_code().is_none() {
+                break session;
+            }
+            warn!("Dropping warm pool session {}, which exited", session.id);
+            retire_recording(&mut self.storage, &session);
+        };
+        session.created_at = SystemTime::now();
+        session.config.label_color = args.label_color.clone();
+        session.config.label_icon = args.label_icon.clone();
+        session.config.input_profile = args.input_profile.unwrap_or_default();
+        session.config.read_only = args.read_only.unwrap_or(false);
+        session.config.group_id = args.group_id.clone();
+        session.config.tags = session_tags(args);
+        session.config.lease_secs = args.lease_secs;
+        // create_session already checked that the lease fits
+        session.lease = args
+            .lease_secs
+            .and_then(|secs| Lease::new(Duration::from_secs(secs)).ok());
+        info!("Adopting warm pool session {}", session.id);
+        Some(session)
+    }
+
+    /// Reports what session recordings have written since the last call to
+    /// the storage manager, which evicts closed recordings past the cap
+    pub fn account_recordings(&mut self) {
+        for session in self.sessions.values().chain(self.warm_pool.iter()) {
+            report_recording(&mut self.storage, session);
+        }
+    }
+
+    /// The tunnels the tunnel pool is short of; `None` if it is full,
+    /// disabled, or the server is draining
+    pub fn prepare_tunnel_refill(&mut self) -> Option<TunnelRefill> {
+        if self.drain.is_some() {
+            return None;
+        }
+        let count = self
+            .tunnel_manager
+            .pool_deficit(self.config.tunnel_pool.size);
+        (count > 0).then(|| TunnelRefill {
+            count,
+            // Pointed at its relay once that is bound
+            config: self.tunnel_config(0),
+            named: self.config.named_tunnel.clone(),
+        })
+    }
+
+    /// Adds tunnels started by [`TunnelRefill::run`] to the pool, or stops
+    /// them if the server started draining meanwhile
+    pub fn finish_tunnel_refill(&mut self, tunnels: Vec<DetachedTunnel>) {
+        for tunnel in tunnels {
+            if self.drain.is_some() {
+                tokio::spawn(async move {
+                    if let Err(e) = tunnel.stop().await {
+                        warn!("Failed to stop tunnel: {}", e);
+                    }
+                });
+                continue;
+            }
+            debug!("Pooled tunnel {}", tunnel.id);
+            self.tunnel_manager.add_pooled(tunnel);
+        }
+    }
+
+    /// Idle and leased pooled tunnels and how often a session found one
+    pub fn tunnel_pool_summary(&self) -> serde_json::Value {
+        self.tunnel_manager
+            .pool_summary(self.config.tunnel_pool.size)
+    }
+
+    /// Idle sessions and how often `create_session` was served from the pool
+    pub fn warm_pool_summary(&self) -> serde_json::Value {
+        self.warm_pool.summary(self.config.warm_pool.size)
+    }
+
+    fn refuse_if_draining(&self, what: &str) -> Result<()> {
+        match &self.drain {
+            Some(drain) => Err(HtMcpError::ServerDraining(format!(
+                "Not accepting {}; shutting down in {} s",
+                what,
+                drain.remaining().as_secs()
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Closes a session along with its web server and tunnel, reporting
+    /// whether it had them
+    pub async fn close_session(&mut self, args: CloseSessionArgs) -> Result<serde_json::Value> {
+        let web_server_stopped = self
+            .sessions
+            .get(&args.session_id)
+            .is_some_and(|session| session.web_supervisor.is_some());
+        let tunnel_stopped = self
+            .end_session(&args.session_id, TerminationReason::Closed)
+            .await?;
+
+        Ok(serde_json::json!({
+            "success": true,
+            "sessionId": args.session_id,
+            "webServerStopped": web_server_stopped,
+            "tunnelStopped": tunnel_stopped,
+            "message": format!("Session {} closed successfully", args.session_id)
+        }))
+    }
+
+    /// Closes the listed sessions, or every session with `closeAll`,
+    /// keeping to those with all of `filterTags`. The sessions' resources
+    /// are released concurrently; a session that cannot be closed is
+    /// reported in `failed` without keeping the others open.
+    pub async fn bulk_close(&mut self, args: BulkCloseSessionsArgs) -> Result<serde_json::Value> {
+        let close_all = args.close_all.unwrap_or(false);
+        if close_all && args.session_ids.is_some() {
+            return Err(HtMcpError::InvalidRequest(
+                "Pass either sessionIds or closeAll, not both".to_string(),
+            ));
+        }
+        if args.filter_tags.as_ref().is_some_and(HashMap::is_empty) {
+            return Err(HtMcpError::InvalidRequest(
+                "filterTags must name at least one tag".to_string(),
+            ));
+        }
+        let filter_tags = args.filter_tags.unwrap_or_default();
+        let has_tags = |session: &SessionInfo| {
+            filter_tags
+                .iter()
+                .all(|(name, value)| session.config.tags.get(name) == Some(value))
+        };
+
+        let mut failed = Vec::new();
+        let targets: Vec<String> = match args.session_ids {
+            Some(session_ids) => {
+                let mut targets: Vec<String> = Vec::with_capacity(session_ids.len());
+                for session_id in session_ids {
+                    if targets.contains(&session_id) {
+                        continue;
+                    }
+                    match self.sessions.get(&session_id) {
+                        Some(session) if !has_tags(session) => failed.push(serde_json::json!({
+                            "sessionId": session_id,
+                            "error": "Session does not have all of filterTags"
+                        })),
+                        _ => targets.push(session_id),
+                    }
+                }
+                targets
+            }
+            None if close_all || !filter_tags.is_empty() => {
+                let mut sessions: Vec<&SessionInfo> =
+                    self.sessions.values().filter(|s| has_tags(s)).collect();
+                sessions.sort_by_key(|session| session.created_at);
+                sessions
+                    .into_iter()
+                    .map(|session| session.id.clone())
+                    .collect()
+            }
+            None => {
+                return Err(HtMcpError::InvalidRequest(
+                    "Pass sessionIds, closeAll or filterTags".to_string(),
+                ))
+            }
+        };
+
+        let mut detached = Vec::with_capacity(targets.len());
+        let mut tunnel_ids = Vec::new();
+        for session_id in &targets {
+            match self.detach_session(session_id, TerminationReason::Closed) {
+                Ok(session) => {
+                    tunnel_ids.extend(session.tunnel_id.clone());
+                    detached.push(session);
+                }
+                Err(e) => failed.push(serde_json::json!({
+                    "sessionId": session_id,
+                    "error": e.to_string()
+                })),
+            }
+        }
+
+        // Removing temp dirs touches the disk, so each session is released
+        // on a blocking thread of its own
+        let keep_on_error = self.config.temp_dir.keep_on_error;
+        let outcomes = join_all(detached.into_iter().map(|session| async move {
+            let session_id = session.id.clone();
+            let released =
+                tokio::task::spawn_blocking(move || release_session(session, keep_on_error)).await;
+            (session_id, released)
+        }))
+        .await;
+        for tunnel_id in tunnel_ids {
+            self.stop_session_tunnel(Some(&tunnel_id)).await;
+        }
+
+        let mut closed = Vec::with_capacity(outcomes.len());
+        for (session_id, released) in outcomes {
+            match released {
+                Ok(_exited) => closed.push(session_id),
+                Err(e) => {
+                    warn!("Failed to release session {}: {}", session_id, e);
+                    failed.push(serde_json::json!({
+                        "sessionId": session_id,
+                        "error": format!("Closed, but releasing its resources failed: {}", e)
+                    }));
+                }
+            }
+        }
+
+        Ok(serde_json::json!({
+            "closed": closed,
+            "failed": failed
+        }))
+    }
+
+    /// Stops the tunnel of a closed session, a pooled one too: its URL was
+    /// handed out, so it is never leased again. Whether there was one and
+    /// that worked.
+    async fn stop_session_tunnel(&mut self, tunnel_id: Option<&str>) -> bool {
+        let Some(tunnel_id) = tunnel_id else {
+            return false;
+        };
+        match self.tunnel_manager.stop_tunnel(tunnel_id).await {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("Failed to stop tunnel {}: {}", tunnel_id, e);
+                false
+            }
+        }
+    }
+
+    /// Removes a session, releases its resources and stops its tunnel;
+    /// whether it had a tunnel and it stopped. Every way a session ends but
+    /// `bulk_close` and `shutdown`, which stop the tunnels themselves, goes
+    /// through here.
+    async fn end_session(&mut self, session_id: &str, reason: TerminationReason) -> Result<bool> {
+        let session = self.detach_session(session_id, reason)?;
+        let tunnel_id = session.tunnel_id.clone();
+        release_session(session, self.config.temp_dir.keep_on_error);
+        Ok(self.stop_session_tunnel(tunnel_id.as_deref()).await)
+    }
+
+    /// Closes every session, pooled ones included, waits up to
+    /// [`SHUTDOWN_TIMEOUT`] for their processes to exit and stops all
+    /// tunnels. For when the server exits, so nothing is left orphaned.
+    pub async fn shutdown(&mut self) {
+        let session_ids: Vec<String> = self.sessions.keys().cloned().collect();
+        info!("Shutting down; closing {} sessions", session_ids.len());
+        let keep_on_error = self.config.temp_dir.keep_on_error;
+        let mut exits = Vec::with_capacity(session_ids.len());
+        for session_id in session_ids {
+            match self.detach_session(&session_id, TerminationReason::ServerShutdown) {
+                Ok(session) => exits.push(release_session(session, keep_on_error)),
+                Err(e) => warn!("Failed to close session {}: {}", session_id, e),
+            }
+        }
+        for session in self.warm_pool.clear() {
+            retire_recording(&mut self.storage, &session);
+            exits.push(release_session(session, keep_on_error));
+        }
+
+        if tokio::time::timeout(SHUTDOWN_TIMEOUT, join_all(exits))
+            .await
+            .is_err()
+        {
+            warn!(
+                "Session processes still exiting after {:?}; shutting down anyway",
+                SHUTDOWN_TIMEOUT
+            );
+        }
+        if let Err(e) = self.tunnel_manager.stop_all_tunnels().await {
+            warn!("Failed to stop tunnels: {}", e);
+        }
+    }
+
+    /// Removes a session and announces it closed, leaving its resources to
+    /// [`release_session`]
+    fn detach_session(
+        &mut self,
+        session_id: &str,
+        reason: TerminationReason,
+    ) -> Result<SessionInfo> {
+        let session = self
+            .sessions
+            .remove(session_id)
+            .ok_or_else(|| HtMcpError::SessionNotFound(session_id.to_string()))?;
+
+        retire_recording(&mut self.storage, &session);
+        self.downloads.remove_session(session_id);
+        self.aliases.remove_session(session_id);
+
+        self.events.publish(SessionEvent::SessionClosed {
+            session_id: session_id.to_string(),
+            reason,
+            created_by: session.created_by.clone(),
+        });
+        info!("Closed session {} ({:?})", session_id, reason);
+        Ok(session)
+    }
+}
+
+/// Reports what a session's recording wrote since the last report
+fn report_recording(storage: &mut StorageManager, session: &SessionInfo) {
+    let Some(recording) = &session.recording else {
+        return;
+    };
+    let bytes = recording.take_unreported();
+    if bytes > 0 {
+        // Recording writes are never refused; older recordings are evicted
+        let _ = storage.record_write(StorageCategory::Recordings, Some(&session.id), bytes);
+    }
+}
+
+/// Starts counting a new session's recording toward the storage cap
+fn track_recording(storage: &mut StorageManager, session: &SessionInfo) {
+    if let Some(recording) = &session.recording {
+        storage.track_recording(&session.id, recording.path.clone());
+    }
+}
+
+/// Reports the last of a session's recording and closes it, making it
+/// eligible for eviction
+fn retire_recording(storage: &mut StorageManager, session: &SessionInfo) {
+    report_recording(storage, session);
+    storage.close_recording(&session.id);
+}
+
+/// Shuts down a removed session and frees what it holds; its temp dir is
+/// kept when it failed and `keep_temp_dir_on_error` is set. The returned
+/// task finishes once the session's process is gone.
+fn release_session(session: SessionInfo, keep_temp_dir_on_error: bool) -> JoinHandle<()> {
+    let failed = session.child.exit_code().is_some_and(|code| code != 0);
+
+    // Close the command channel to trigger session shutdown, and hang up on
+    // the process so commands like `sleep` do not outlive the session
+    drop(session.command_tx);
+    // Dropping what the supervisor serves stops the web server and frees
+    // its port
+    if let Some(supervisor) = session.web_supervisor {
+        supervisor.abort();
+    }
+    let child = session.child;
+    let exited = tokio::spawn(async move { child.terminate(CHILD_HANG_UP_GRACE).await });
+
+    if let Some(cgroup) = session.memory_cgroup {
+        tokio::spawn(cgroup.remove_when_empty());
+    }
+
+    if let Some(temp_dir) = session.temp_dir {
+        temp_dir.cleanup(failed && keep_temp_dir_on_error);
+    }
+    exited
+}
+
+/// Where input checked by [`SessionManager::input_policy`] comes from
+enum InputAudit<'a> {
+    /// An input tool, by name
+    Mcp(&'a str),
+    /// A web viewer, by address
+    Web(&'a str),
+}
+
+impl std::fmt::Display for InputAudit<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InputAudit::Mcp(tool) => write!(f, "{}", tool),
+            InputAudit::Web(peer) => write!(f, "web viewer {}", peer),
+        }
+    }
+}
+
+/// Announces that `holder`'s lock of a session ran out
+fn publish_lock_expired(events: &EventBus, session_id: &str, holder: String) {
+    info!("Lock of session {} held by {} expired", session_id, holder);
+    events.publish(SessionEvent::LockReleased {
+        session_id: session_id.to_string(),
+        holder,
+        reason: LockReleaseReason::Expired,
+    });
+}
+
+/// Keys submitting the command: `terminator`, `Enter` by default, or none
+/// when `appendTerminator` is false
+fn command_terminator(args: &ExecuteCommandArgs) -> Vec<String> {
+    if args.append_terminator.unwrap_or(true) {
+        args.terminator
+            .clone()
+            .unwrap_or_else(|| vec!["Enter".to_string()])
+    } else {
+        Vec::new()
+    }
+}
+
+/// Rejects `env` entries the OS cannot pass to a process: names that are
+/// empty or contain `=`, and NUL bytes anywhere
+fn validate_env(env: &BTreeMap<String, String>) -> Result<()> {
+    for (name, value) in env {
+        if name.is_empty() || name.contains(['=', '\0']) {
+            return Err(HtMcpError::InvalidRequest(format!(
+                "Invalid env variable name {:?}: it must be non-empty and contain no '=' or NUL",
+                name
+            )));
+        }
+        if value.contains('\0') {
+            return Err(HtMcpError::InvalidRequest(format!(
+                "Invalid value for env variable {}: it contains a NUL byte",
+                name
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Accepts program names only, so a name cannot smuggle shell syntax or an
+/// option into `command -v`
+fn validate_command_name(command: &str) -> Result<()> {
+    let valid = !command.is_empty()
+        && !command.starts_with('-')
+        && command
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'));
+    if valid {
+        Ok(())
+    } else {
+        Err(HtMcpError::InvalidRequest(format!(
+            "Invalid command {:?}: use a program name of letters, digits, '_', '.' and '-', not starting with '-'",
+            command
+        )))
+    }
+}
+
+/// The result of `command -v NAME && echo EXISTS || echo MISSING` on the
+/// screen: `Some(path)` when it exists. The typed command line holds both
+/// words too, so only lines with nothing else count.
+fn parse_command_check(screen: &str) -> Option<Option<String>> {
+    let lines: Vec<&str> = screen.lines().map(str::trim).collect();
+    let at = lines
+        .iter()
+        .rposition(|line| *line == "EXISTS" || *line == "MISSING")?;
+    if lines[at] == "MISSING" {
+        return Some(None);
+    }
+    let path = at.checked_sub(1).map(|before| lines[before])?;
+    Some(Some(path.to_string()))
+}
+
+/// The styled snapshot a `format` needs, `None` for the plain ones
+fn styled_format(format: Option<SnapshotFormat>) -> Option<StyledFormat> {
+    match format {
+        Some(SnapshotFormat::Ansi) => Some(StyledFormat::Ansi),
+        Some(SnapshotFormat::Structured) => Some(StyledFormat::Structured),
+        _ => None,
+    }
+}
+
+/// Rejects a `maxTokens` of zero, which would leave nothing to return
+fn validate_max_tokens(max_tokens: Option<usize>) -> Result<()> {
+    match max_tokens {
+        Some(0) => Err(HtMcpError::InvalidRequest(
+            "maxTokens must be at least 1".to_string(),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// `maxMemoryMb` in bytes, for the session's memory cgroup
+fn memory_limit_bytes(max_memory_mb: u64) -> Result<u64> {
+    max_memory_mb.checked_mul(1024 * 1024).ok_or_else(|| {
+        HtMcpError::InvalidRequest(format!("maxMemoryMb {} is too large", max_memory_mb))
+    })
+}
+
+/// How long to wait for the command to finish: the call's `timeoutMs`, or
+/// its command class's
+fn completion_timeout(args: &ExecuteCommandArgs, class: &CommandClass) -> Result<Duration> {
+    match args.completion_timeout_ms {
+        Some(0) => Err(HtMcpError::InvalidRequest(
+            "timeoutMs must be at least 1".to_string(),
+        )),
+        ms => Ok(ms.map_or(class.timeout, Duration::from_millis)),
+    }
+}
+
+/// Whether the shell is waiting at `marker` with nothing typed: the last
+/// line with content ends with it
+fn prompt_shown(screen: &str, marker: &str) -> bool {
+    screen
+        .lines()
+        .rev()
+        .map(|line| control_sequences::strip(line).trim_end().to_string())
+        .find(|line| !line.is_empty())
+        .is_some_and(|line| line.ends_with(marker.trim_end()))
+}
+
+/// Checks the decorative label fields: a `#rgb`/`#rrggbb` color and a short icon
+fn validate_labels(args: &CreateSessionArgs) -> Result<()> {
+    if let Some(color) = &args.label_color {
+        let hex = color.strip_prefix('#').unwrap_or_default();
+        if !matches!(hex.len(), 3 | 6) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(HtMcpError::InvalidRequest(format!(
+                "labelColor must be a hex color like \"#ff6600\", got \"{}\"",
+                color
+            )));
+        }
+    }
+    if let Some(icon) = &args.label_icon {
+        if icon.chars().count() > MAX_LABEL_ICON_LEN {
+            return Err(HtMcpError::InvalidRequest(format!(
+                "labelIcon must be at most {} characters",
+                MAX_LABEL_ICON_LEN
+            )));
+        }
+    }
+    if args
+        .tags
+        .as_ref()
+        .is_some_and(|tags| tags.keys().any(|name| name.is_empty()))
+    {
+        return Err(HtMcpError::InvalidRequest(
+            "tags names must not be empty".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// The `tags` a session is created with, sorted by name
+fn session_tags(args: &CreateSessionArgs) -> BTreeMap<String, String> {
+    args.tags.clone().unwrap_or_default().into_iter().collect()
+}
+
+/// Rejects snapshot intervals short enough to keep the event loop busy
+fn validate_snapshot_interval(args: &CreateSessionArgs) -> Result<()> {
+    match args.snapshot_interval_ms {
+        Some(ms) if ms < MIN_SNAPSHOT_INTERVAL_MS => Err(HtMcpError::InvalidRequest(format!(
+            "snapshotIntervalMs must be at least {}, got {}",
+            MIN_SNAPSHOT_INTERVAL_MS, ms
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// One page of a listing, newest first
+struct Page<T> {
+    items: Vec<T>,
+    page: usize,
+    page_size: usize,
+    total_count: usize,
+}
+
+impl<T> Page<T> {
+    /// Sorts `items` by creation time, newest first, and keeps the 1-based
+    /// `page` of `page_size` entries
+    fn of(
+        items: impl Iterator<Item = (SystemTime, T)>,
+        page: Option<usize>,
+        page_size: Option<usize>,
+    ) -> Result<Self> {
+        let page = page.unwrap_or(1);
+        let page_size = page_size.unwrap_or(DEFAULT_PAGE_SIZE);
+        if page == 0 || page_size == 0 {
+            return Err(HtMcpError::InvalidRequest(
+                "page and pageSize must be at least 1".to_string(),
+            ));
+        }
+
+        let mut items: Vec<(SystemTime, T)> = items.collect();
+        items.sort_by(|(a, _), (b, _)| b.cmp(a));
+        let total_count = items.len();
+        let items = items
+            .into_iter()
+            .skip((page - 1).saturating_mul(page_size))
+            .take(page_size)
+            .map(|(_, item)| item)
+            .collect();
+        Ok(Self {
+            items,
+            page,
+            page_size,
+            total_count,
+        })
+    }
+
+    fn has_more(&self) -> bool {
+        self.page.saturating_mul(self.page_size) < self.total_count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ht_integration::assertions::Assertion;
+    use crate::ht_integration::retention::RetentionOverrides;
+    use crate::ht_integration::startup::StartupPhase;
+    use crate::ht_integration::storage::StorageConfig;
+    use crate::ht_integration::temp_dir::TempDirConfig;
+    use crate::ht_integration::web_supervisor::MAX_WEB_SERVER_RESTARTS;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    fn web_session_args() -> CreateSessionArgs {
+        CreateSessionArgs {
+            command: Some(vec!["bash".to_string()]),
+            cwd: None,
+            env: None,
+            enable_web_server: Some(true),
+            enable_tunnel: Some(false),
+            idempotency_key: None,
+            max_memory_mb: None,
+            max_connections: None,
+            max_connections_per_ip: None,
+            idle_timeout_secs: None,
+            run_as: None,
+            label_color: None,
+            label_icon: None,
+            temp_dir: None,
+            snapshot_interval_ms: None,
+            lease_secs: None,
+            auto_detect_prompt: None,
+            trace_mode: None,
+            retention: None,
+            color_profile: None,
+            cpu_affinity: None,
+            preferred_port: None,
+            input_profile: None,
+            group_id: None,
+            tags: None,
+            cols: None,
+            rows: None,
+            output_webhook_url: None,
+            output_webhook_interval_ms: None,
+            read_only: None,
+            tunnel_name: None,
+            timeout_ms: None,
+        }
+    }
+
+    async fn assert_rollback_at(stage: CreateStage) {
+        let mut manager = SessionManager::new();
+        manager.fail_at_stage = Some(stage);
+        let port = manager.find_available_port().await.unwrap();
+
+        let err = manager
+            .create_session(web_session_args())
+            .await
+            .expect_err("creation should fail");
+
+        assert!(
+            err.to_string().contains(stage.as_str()),
+            "error should name the {} stage: {}",
+            stage,
+            err
+        );
+        assert!(manager.sessions.is_empty());
+        assert_eq!(manager.tunnel_manager.tunnel_count(), 0);
+        assert!(
+            std::net::TcpListener::bind(("127.0.0.1", port)).is_ok(),
+            "port {} should be released after rolling back the {} stage",
+            port,
+            stage
+        );
+    }
+
+    #[test]
+    fn test_validate_labels() {
+        let with_labels = |color: &str, icon: &str| CreateSessionArgs {
+            label_color: Some(color.to_string()),
+            label_icon: Some(icon.to_string()),
+            ..web_session_args()
+        };
+        assert!(validate_labels(&with_labels("#ff6600", "🐍")).is_ok());
+        assert!(validate_labels(&with_labels("#F60", "rust")).is_ok());
+        for color in ["ff6600", "#ff660", "#gg6600", "#"] {
+            let err = validate_labels(&with_labels(color, "rust")).unwrap_err();
+            assert!(matches!(err, HtMcpError::InvalidRequest(_)), "{}", color);
+        }
+        assert!(validate_labels(&with_labels("#fff", &"x".repeat(33))).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_migrate_session_between_managers() {
+        let mut source = SessionManager::new();
+        let mut target = SessionManager::new();
+        let created = source
+            .create_session(CreateSessionArgs {
+                enable_web_server: Some(false),
+                ..web_session_args()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        source
+            .execute_command(ExecuteCommandArgs {
+                session_id: session_id.clone(),
+                command: "echo migration-marker".to_string(),
+                terminator: None,
+                append_terminator: None,
+                annotate: None,
+                annotate_patterns: None,
+                summarize: None,
+                wait_for_completion: None,
+                completion_timeout_ms: None,
+                quiet_period_ms: None,
+                command_class: None,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+
+        // The bundle must survive a trip between instances
+        let bundle = source
+            .export_session_for_migration(&session_id)
+            .await
+            .unwrap();
+        let bundle: MigrationBundle =
+            serde_json::from_str(&serde_json::to_string(&bundle).unwrap()).unwrap();
+        assert_eq!(bundle.history, vec!["echo migration-marker"]);
+
+        let new_id = target.import_migrated_session(bundle).await.unwrap();
+        let migrated = &target.sessions[&new_id];
+        assert_ne!(new_id, session_id);
+        assert_eq!(migrated.command, vec!["bash"]);
+        assert!(migrated.web_server.is_none());
+        assert_eq!(migrated.history, vec!["echo migration-marker"]);
+        let context = migrated.migrated_from.as_ref().unwrap();
+        assert_eq!(context.source_session_id, session_id);
+        assert!(context.snapshot.contains("migration-marker"));
+
+        // migrate_session also closes the source
+        let moved_id = target.migrate_session(&new_id, &mut source).await.unwrap();
+        assert!(target.sessions.is_empty());
+        assert_eq!(source.sessions[&moved_id].history.len(), 1);
+    }
+
+    fn temp_dir_manager(keep_on_error: bool) -> (SessionManager, PathBuf) {
+        let root = std::env::temp_dir().join(format!("ht-mcp-session-tmp-{}", Uuid::new_v4()));
+        let manager = SessionManager::with_config(HtMcpConfig {
+            temp_dir: TempDirConfig {
+                root: Some(root.clone()),
+                keep_on_error,
+            },
+            ..HtMcpConfig::default()
+        });
+        (manager, root)
+    }
+
+    /// Runs `command` in a `tempDir` session and waits for it to exit.
+    /// Returns the session id and the temp dir path.
+    async fn run_in_temp_dir(manager: &mut SessionManager, command: &str) -> (String, PathBuf) {
+        let created = manager
+            .create_session(CreateSessionArgs {
+                command: Some(vec![command.to_string()]),
+                enable_web_server: Some(false),
+                temp_dir: Some(true),
+                ..web_session_args()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        let path = PathBuf::from(created["tempDir"].as_str().unwrap());
+        assert!(path.is_dir());
+
+        let child = manager.sessions[&session_id].child.clone();
+        for _ in 0..100 {
+            if child.exit_code().is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        (session_id, path)
+    }
+
+    #[tokio::test]
+    async fn test_temp_dir_removed_on_close() {
+        let (mut manager, root) = temp_dir_manager(true);
+        let (session_id, path) = run_in_temp_dir(&mut manager, "test -w \"$HT_SESSION_TMP\"").await;
+        assert_eq!(manager.sessions[&session_id].child.exit_code(), Some(0));
+
+        manager
+            .close_session(CloseSessionArgs {
+                session_id,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        assert!(!path.exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_temp_dir_kept_on_error() {
+        for keep_on_error in [true, false] {
+            let (mut manager, root) = temp_dir_manager(keep_on_error);
+            let (session_id, path) =
+                run_in_temp_dir(&mut manager, "test -w \"$HT_SESSION_TMP\" && exit 3").await;
+            assert_eq!(manager.sessions[&session_id].child.exit_code(), Some(3));
+
+            manager
+                .close_session(CloseSessionArgs {
+                    session_id,
+                    timeout_ms: None,
+                })
+                .await
+                .unwrap();
+            assert_eq!(path.exists(), keep_on_error);
+            std::fs::remove_dir_all(&root).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rollback_removes_temp_dir() {
+        let (mut manager, root) = temp_dir_manager(false);
+        manager.fail_at_stage = Some(CreateStage::Pty);
+
+        let err = manager
+            .create_session(CreateSessionArgs {
+                temp_dir: Some(true),
+                ..web_session_args()
+            })
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("pty"));
+        assert_eq!(std::fs::read_dir(&root).unwrap().count(), 0);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ai_compressed_snapshot() {
+        let mut manager = SessionManager::new();
+        let created = manager
+            .create_session(CreateSessionArgs {
+                command: Some(vec![
+                    "printf '[%s]\\n\\n\\n\\ndone\\n' $(printf '#%.0s' $(seq 30)); sleep 30"
+                        .to_string(),
+                ]),
+                ..CreateSessionArgs::default()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        let handle = manager.handle(&session_id).unwrap();
+        handle
+            .wait_for("done", Duration::from_secs(5))
+            .await
+            .unwrap();
+
+        let snapshot = manager
+            .take_snapshot(TakeSnapshotArgs {
+                session_id: session_id.clone(),
+                format: Some(SnapshotFormat::AiCompressed),
+                trim_trailing_blank_lines: None,
+                annotate: None,
+                annotate_patterns: None,
+                word_wrap: None,
+                probe_only: None,
+                max_tokens: None,
+                include_provenance: None,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(snapshot["snapshot"], "[###...30 chars...###]\n\ndone");
+        assert_eq!(snapshot["lineCount"], 3);
+        assert_eq!(snapshot["nonEmptyLineCount"], 2);
+        assert!(snapshot.get("annotations").is_none());
+
+        // Plain, with only the rows below the output dropped
+        let snapshot = manager
+            .take_snapshot(TakeSnapshotArgs {
+                session_id: session_id.clone(),
+                format: None,
+                trim_trailing_blank_lines: Some(true),
+                annotate: None,
+                annotate_patterns: None,
+                word_wrap: None,
+                probe_only: None,
+                max_tokens: None,
+                include_provenance: None,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        let text = snapshot["snapshot"].as_str().unwrap();
+        assert!(text.ends_with("done"), "{:?}", text);
+        assert_eq!(snapshot["lineCount"], text.lines().count());
+        assert_eq!(snapshot["nonEmptyLineCount"], 2);
+        assert!(snapshot.get("wrapped").is_none());
+
+        // The 32-column bar wrapped at 10
+        let snapshot = manager
+            .take_snapshot(TakeSnapshotArgs {
+                session_id: session_id.clone(),
+                format: None,
+                trim_trailing_blank_lines: Some(true),
+                annotate: None,
+                annotate_patterns: None,
+                word_wrap: Some(10),
+                probe_only: None,
+                max_tokens: None,
+                include_provenance: None,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(snapshot["wrapped"], true);
+        assert_eq!(snapshot["wrapWidth"], 10);
+        let text = snapshot["snapshot"].as_str().unwrap();
+        assert!(text.starts_with("[#########\n##########\n"), "{:?}", text);
+        assert!(text.lines().all(|line| line.chars().count() <= 10));
+
+        manager
+            .close_session(CloseSessionArgs {
+                session_id,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_annotated_snapshot() {
+        let mut manager = SessionManager::new();
+        let created = manager
+            .create_session(CreateSessionArgs {
+                command: Some(vec![
+                    "printf 'error: could not compile\\nsee http://localhost:8080/x\\nTICKET-42 done\\n'; sleep 30"
+                        .to_string(),
+                ]),
+                ..CreateSessionArgs::default()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        let handle = manager.handle(&session_id).unwrap();
+        handle
+            .wait_for("done", Duration::from_secs(5))
+            .await
+            .unwrap();
+
+        let snapshot = manager
+            .take_snapshot(TakeSnapshotArgs {
+                session_id: session_id.clone(),
+                format: Some(SnapshotFormat::AiCompressed),
+                trim_trailing_blank_lines: None,
+                annotate: None,
+                annotate_patterns: Some(vec![AnnotationPatternConfig {
+                    kind: "ticket".to_string(),
+                    pattern: r"TICKET-\d+".to_string(),
+                    group: false,
+                }]),
+                word_wrap: None,
+                probe_only: None,
+                max_tokens: None,
+                include_provenance: None,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            snapshot["annotations"],
+            serde_json::json!([
+                {"line": 1, "kind": "error", "match": "error: could not compile"},
+                {"line": 2, "kind": "url", "match": "http://localhost:8080/x"},
+                {"line": 3, "kind": "ticket", "match": "TICKET-42"}
+            ])
+        );
+
+        let err = manager
+            .take_snapshot(TakeSnapshotArgs {
+                session_id: session_id.clone(),
+                format: None,
+                trim_trailing_blank_lines: None,
+                annotate: Some(true),
+                annotate_patterns: Some(vec![AnnotationPatternConfig {
+                    kind: "bad".to_string(),
+                    pattern: "(".to_string(),
+                    group: false,
+                }]),
+                word_wrap: None,
+                probe_only: None,
+                max_tokens: None,
+                include_provenance: None,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::InvalidRequest(_)));
+
+        manager
+            .close_session(CloseSessionArgs {
+                session_id,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_strip_controls_in_history() {
+        let mut manager = SessionManager::with_config(HtMcpConfig {
+            strip_controls_in_history: true,
+            ..HtMcpConfig::default()
+        });
+        let created = manager
+            .create_session(CreateSessionArgs {
+                snapshot_interval_ms: Some(100),
+                ..CreateSessionArgs::default()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+
+        // Escape sequences in the program's output, not in the command
+        let command = "printf '\\033[31mred\\033[0m \\033]0;title\\007done\\n'";
+        manager
+            .execute_command(execute_args(&session_id, command))
+            .await
+            .unwrap();
+        assert_eq!(manager.sessions[&session_id].history, [command]);
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let snapshots = manager.sessions[&session_id]
+            .snapshot_history
+            .as_ref()
+            .unwrap()
+            .recent(usize::MAX, None);
+        let (_, text) = snapshots.last().unwrap();
+        assert!(text.contains("red done"), "{:?}", text);
+        assert!(!control_sequences::contains(text), "{:?}", text);
+
+        manager
+            .close_session(CloseSessionArgs {
+                session_id,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_probe_and_token_budget() {
+        let mut manager = SessionManager::new();
+        let created = manager
+            .create_session(CreateSessionArgs {
+                enable_web_server: Some(false),
+                snapshot_interval_ms: Some(100),
+                ..web_session_args()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        manager
+            .execute_command(execute_args(&session_id, "seq 1 40"))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let snapshot_args = |probe_only, max_tokens| TakeSnapshotArgs {
+            session_id: session_id.clone(),
+            format: None,
+            trim_trailing_blank_lines: Some(true),
+            annotate: None,
+            annotate_patterns: None,
+            word_wrap: None,
+            probe_only,
+            max_tokens,
+            include_provenance: None,
+            timeout_ms: None,
+        };
+
+        let full = manager
+            .take_snapshot(snapshot_args(None, None))
+            .await
+            .unwrap();
+        let text = full["snapshot"].as_str().unwrap();
+        let probe = manager
+            .take_snapshot(snapshot_args(Some(true), Some(10)))
+            .await
+            .unwrap();
+        assert!(probe.get("snapshot").is_none());
+        assert_eq!(probe["bytes"], text.len());
+        assert_eq!(probe["lineCount"], text.lines().count());
+        assert_eq!(
+            probe["estimatedTokens"],
+            token_estimate::estimate_tokens(text)
+        );
+        assert_eq!(probe["truncated"], true);
+        assert_eq!(probe["screenVersion"], full["screenVersion"]);
+
+        let cut = manager
+            .take_snapshot(snapshot_args(None, Some(10)))
+            .await
+            .unwrap();
+        assert_eq!(cut["truncated"], true);
+        assert!(cut["estimatedTokens"].as_u64().unwrap() <= 10);
+        assert!(cut["omittedLines"].as_u64().unwrap() > 0);
+        assert!(text.ends_with(cut["snapshot"].as_str().unwrap()));
+
+        let history_args = |probe_only, max_tokens| GetSnapshotHistoryArgs {
+            session_id: session_id.clone(),
+            limit: Some(2),
+            since_ms: None,
+            probe_only,
+            max_tokens,
+            timeout_ms: None,
+        };
+        let probe = manager
+            .get_snapshot_history(history_args(Some(true), None))
+            .unwrap();
+        assert_eq!(probe["snapshotCount"], 2, "{}", probe);
+        assert_eq!(probe["truncated"], false);
+        let one_screen = token_estimate::estimate_tokens(text) + 20;
+        let history = manager
+            .get_snapshot_history(history_args(None, Some(one_screen)))
+            .unwrap();
+        assert_eq!(history["truncated"], true);
+        assert_eq!(history["snapshots"].as_array().unwrap().len(), 1);
+        assert_eq!(history["omittedSnapshots"], 1);
+
+        let err = manager
+            .take_snapshot(snapshot_args(None, Some(0)))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::InvalidRequest(_)));
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_reports_cursor() {
+        let mut manager = SessionManager::new();
+        let created = manager
+            .create_session(CreateSessionArgs {
+                command: Some(vec!["printf 'Password: '; sleep 30".to_string()]),
+                ..CreateSessionArgs::default()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        let handle = manager.handle(&session_id).unwrap();
+        handle
+            .wait_for("Password:", Duration::from_secs(5))
+            .await
+            .unwrap();
+
+        let snapshot = manager
+            .take_snapshot(TakeSnapshotArgs {
+                session_id: session_id.clone(),
+                format: None,
+                trim_trailing_blank_lines: None,
+                annotate: None,
+                annotate_patterns: None,
+                word_wrap: None,
+                probe_only: None,
+                max_tokens: None,
+                include_provenance: None,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(snapshot["cursorRow"], 1);
+        assert_eq!(snapshot["cursorCol"], 11);
+        assert_eq!(snapshot["cursorVisible"], true);
+        let report = handle
+            .assert_screen(&[Assertion::CursorAt { row: 1, col: 11 }])
+            .await
+            .unwrap();
+        assert!(report.passed);
+
+        manager
+            .close_session(CloseSessionArgs {
+                session_id,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_take_snapshot_as_png() {
+        let mut manager = SessionManager::new();
+        let created = manager
+            .create_session(CreateSessionArgs {
+                command: Some(vec!["printf 'ready'; sleep 30".to_string()]),
+                ..CreateSessionArgs::default()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        let handle = manager.handle(&session_id).unwrap();
+        handle
+            .wait_for("ready", Duration::from_secs(5))
+            .await
+            .unwrap();
+
+        let screenshot = manager
+            .take_snapshot_as_png(TakeScreenshotArgs {
+                session_id: session_id.clone(),
+                scale: Some(2.0),
+                include_provenance: None,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(screenshot["mimeType"], "image/png");
+        let png = base64::engine::general_purpose::STANDARD
+            .decode(screenshot["png"].as_str().unwrap())
+            .unwrap();
+        let image = image::load_from_memory(&png).unwrap();
+        assert_eq!(screenshot["width"], image.width());
+        assert_eq!(screenshot["height"], image.height());
+        assert_eq!(image.width() % 16, 0);
+        assert_eq!(image.height() % 26, 0);
+
+        let err = manager
+            .take_snapshot_as_png(TakeScreenshotArgs {
+                session_id: session_id.clone(),
+                scale: Some(10.0),
+                include_provenance: None,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::InvalidRequest(_)), "{}", err);
+
+        manager
+            .close_session(CloseSessionArgs {
+                session_id,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_provenance_verifies_against_issued_blocks_and_history() {
+        let mut manager = SessionManager::new();
+        let created = manager
+            .create_session(CreateSessionArgs {
+                command: Some(vec!["printf 'ready'; sleep 30".to_string()]),
+                snapshot_interval_ms: Some(100),
+                ..CreateSessionArgs::default()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        manager
+            .handle(&session_id)
+            .unwrap()
+            .wait_for("ready", Duration::from_secs(5))
+            .await
+            .unwrap();
+        // Let the history catch up with the screen
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        let snapshot = manager
+            .take_snapshot(TakeSnapshotArgs {
+                session_id: session_id.clone(),
+                format: None,
+                trim_trailing_blank_lines: Some(true),
+                annotate: None,
+                annotate_patterns: None,
+                word_wrap: None,
+                probe_only: None,
+                max_tokens: None,
+                include_provenance: Some(true),
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        let text = snapshot["snapshot"].as_str().unwrap().to_string();
+        let provenance = &snapshot["provenance"];
+        assert_eq!(provenance["sessionId"], session_id.as_str());
+        assert_eq!(provenance["screenVersion"], snapshot["screenVersion"]);
+        let hash = provenance["hash"].as_str().unwrap().to_string();
+        assert_eq!(hash, provenance::content_hash(&text));
+
+        let verify = |hash: &str, text: Option<&str>, session_id: Option<&str>| {
+            manager.verify_snapshot(VerifySnapshotArgs {
+                hash: hash.to_string(),
+                text: text.map(str::to_string),
+                session_id: session_id.map(str::to_string),
+                timeout_ms: None,
+            })
+        };
+        let result = verify(&hash, None, None).await.unwrap();
+        assert_eq!(result["known"], true);
+        assert_eq!(result["verified"], true);
+        assert_eq!(result["issued"].as_array().unwrap().len(), 1);
+        assert_eq!(result["history"][0]["sessionId"], session_id.as_str());
+
+        let result = verify(&format!("sha256:{}", hash), Some(&text), None)
+            .await
+            .unwrap();
+        assert_eq!(result["contentMatches"], true);
+        assert_eq!(result["verified"], true);
+
+        let tampered = text.replace("ready", "steady");
+        let result = verify(&hash, Some(&tampered), None).await.unwrap();
+        assert_eq!(result["known"], true);
+        assert_eq!(result["contentMatches"], false);
+        assert_eq!(result["verified"], false);
+
+        let result = verify(&hash, None, Some("other-session")).await.unwrap();
+        assert_eq!(result["known"], false);
+        let result = verify("0123456789abcdef", None, None).await.unwrap();
+        assert_eq!(result["verified"], false);
+        let err = verify("not-a-hash", None, None).await.unwrap_err();
+        assert!(matches!(err, HtMcpError::InvalidRequest(_)), "{}", err);
+
+        let screenshot = manager
+            .take_snapshot_as_png(TakeScreenshotArgs {
+                session_id: session_id.clone(),
+                scale: None,
+                include_provenance: Some(true),
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(screenshot["provenance"]["hash"], hash.as_str());
+        let result = verify(&hash, None, None).await.unwrap();
+        assert_eq!(result["issued"].as_array().unwrap().len(), 2);
+
+        // Reformatted snapshots hash what they return and say how
+        let wrapped = manager
+            .take_snapshot(TakeSnapshotArgs {
+                session_id: session_id.clone(),
+                format: None,
+                trim_trailing_blank_lines: Some(true),
+                annotate: None,
+                annotate_patterns: None,
+                word_wrap: Some(3),
+                probe_only: None,
+                max_tokens: None,
+                include_provenance: Some(true),
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        let wrapped_text = wrapped["snapshot"].as_str().unwrap();
+        let wrapped_hash = wrapped["provenance"]["hash"].as_str().unwrap();
+        assert_ne!(wrapped_hash, hash);
+        assert_eq!(
+            wrapped["provenance"]["transforms"],
+            serde_json::json!(["wordWrap=3"])
+        );
+        let result = verify(wrapped_hash, Some(wrapped_text), None)
+            .await
+            .unwrap();
+        assert_eq!(result["contentMatches"], true);
+        assert_eq!(result["verified"], true);
+
+        manager
+            .close_session(CloseSessionArgs {
+                session_id,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_styled_snapshot_formats() {
+        let mut manager = SessionManager::new();
+        let created = manager
+            .create_session(CreateSessionArgs {
+                enable_web_server: Some(false),
+                ..web_session_args()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        manager
+            .execute_command(execute_args(
+                &session_id,
+                r"printf 'ok \033[1;32mgreen\033[0m\n'",
+            ))
+            .await
+            .unwrap();
+        let snapshot_args = |format, word_wrap, max_tokens| TakeSnapshotArgs {
+            session_id: session_id.clone(),
+            format: Some(format),
+            trim_trailing_blank_lines: None,
+            annotate: None,
+            annotate_patterns: None,
+            word_wrap,
+            probe_only: None,
+            max_tokens,
+            include_provenance: None,
+            timeout_ms: None,
+        };
+
+        let ansi = manager
+            .take_snapshot(snapshot_args(SnapshotFormat::Ansi, None, None))
+            .await
+            .unwrap();
+        let text = ansi["snapshot"].as_str().unwrap();
+        assert!(text.contains("ok \x1b[0;1;32mgreen\x1b[0m"), "{:?}", text);
+
+        let structured = manager
+            .take_snapshot(snapshot_args(SnapshotFormat::Structured, None, None))
+            .await
+            .unwrap();
+        assert!(structured["snapshot"]
+            .as_str()
+            .unwrap()
+            .lines()
+            .any(|line| line == "ok green"));
+        let runs: Vec<&serde_json::Value> = structured["styledLines"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .flat_map(|line| line["runs"].as_array().unwrap())
+            .collect();
+        assert!(runs.contains(&&serde_json::json!({
+            "col": 3, "text": "green", "fg": 2, "bold": true
+        })));
+
+        for (format, word_wrap, max_tokens) in [
+            (SnapshotFormat::Ansi, Some(10), None),
+            (SnapshotFormat::Structured, None, Some(10)),
+        ] {
+            let err = manager
+                .take_snapshot(snapshot_args(format, word_wrap, max_tokens))
+                .await
+                .unwrap_err();
+            assert!(matches!(err, HtMcpError::InvalidRequest(_)), "{}", err);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_snapshot_history() {
+        let mut manager = SessionManager::new();
+        let created = manager
+            .create_session(CreateSessionArgs {
+                enable_web_server: Some(false),
+                snapshot_interval_ms: Some(100),
+                ..web_session_args()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        manager
+            .send_keys(SendKeysArgs {
+                session_id: session_id.clone(),
+                keys: vec!["echo history-marker".to_string(), "Enter".to_string()],
+                input_profile: None,
+                seed: None,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let history_args = |limit, since_ms| GetSnapshotHistoryArgs {
+            session_id: session_id.clone(),
+            limit,
+            since_ms,
+            probe_only: None,
+            max_tokens: None,
+            timeout_ms: None,
+        };
+        let history = manager
+            .get_snapshot_history(history_args(None, None))
+            .unwrap();
+        let snapshots = history["snapshots"].as_array().unwrap();
+        assert!(snapshots.len() >= 3, "{:?}", snapshots);
+        let elapsed: Vec<u64> = snapshots
+            .iter()
+            .map(|entry| entry["elapsedMs"].as_u64().unwrap())
+            .collect();
+        assert!(
+            elapsed.windows(2).all(|pair| pair[0] >= pair[1]),
+            "oldest first"
+        );
+        assert!(snapshots.last().unwrap()["snapshot"]
+            .as_str()
+            .unwrap()
+            .contains("history-marker"));
+
+        let limited = manager
+            .get_snapshot_history(history_args(Some(1), None))
+            .unwrap();
+        assert_eq!(limited["snapshots"].as_array().unwrap().len(), 1);
+        let recent = manager
+            .get_snapshot_history(history_args(None, Some(150)))
+            .unwrap();
+        assert!(recent["snapshots"].as_array().unwrap().len() < snapshots.len());
+
+        let without = manager
+            .create_session(CreateSessionArgs {
+                enable_web_server: Some(false),
+                ..web_session_args()
+            })
+            .await
+            .unwrap();
+        let err = manager
+            .get_snapshot_history(GetSnapshotHistoryArgs {
+                session_id: without["sessionId"].as_str().unwrap().to_string(),
+                limit: None,
+                since_ms: None,
+                probe_only: None,
+                max_tokens: None,
+                timeout_ms: None,
+            })
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::InvalidRequest(_)));
+
+        let err = manager
+            .create_session(CreateSessionArgs {
+                snapshot_interval_ms: Some(10),
+                ..web_session_args()
+            })
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("snapshotIntervalMs"));
+    }
+
+    fn execute_args(session_id: &str, command: &str) -> ExecuteCommandArgs {
+        ExecuteCommandArgs {
+            session_id: session_id.to_string(),
+            command: command.to_string(),
+            terminator: None,
+            append_terminator: None,
+            annotate: None,
+            annotate_patterns: None,
+            summarize: None,
+            wait_for_completion: None,
+            completion_timeout_ms: None,
+            quiet_period_ms: None,
+            command_class: None,
+            timeout_ms: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_execute_command_with_custom_terminator() {
+        if std::process::Command::new("python3")
+            .arg("--version")
+            .output()
+            .is_err()
+        {
+            eprintln!("skipping: python3 is not installed");
+            return;
+        }
+        let mut manager = SessionManager::new();
+        let created = manager
+            .create_session(CreateSessionArgs {
+                command: Some(vec!["PYTHON_BASIC_REPL=1 python3 -q".to_string()]),
+                enable_web_server: Some(false),
+                ..web_session_args()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap();
+
+        // A block is only run once an empty line ends it
+        let result = manager
+            .execute_command(ExecuteCommandArgs {
+                terminator: Some(vec!["Enter".to_string(), "Enter".to_string()]),
+                ..execute_args(session_id, "for i in range(3): print(i * 7001)")
+            })
+            .await
+            .unwrap();
+        assert_eq!(result["terminator"], serde_json::json!(["Enter", "Enter"]));
+        assert!(
+            result["output"].as_str().unwrap().contains("14002"),
+            "{}",
+            result["output"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_execute_command_without_terminator() {
+        let mut manager = SessionManager::new();
+        let created = manager
+            .create_session(CreateSessionArgs {
+                enable_web_server: Some(false),
+                ..web_session_args()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap();
+
+        let result = manager
+            .execute_command(ExecuteCommandArgs {
+                append_terminator: Some(false),
+                ..execute_args(session_id, "echo typed-only")
+            })
+            .await
+            .unwrap();
+        assert_eq!(result["terminator"], serde_json::json!([]));
+        let output = result["output"].as_str().unwrap();
+        assert!(output.contains("echo typed-only"), "{}", output);
+        assert!(
+            !output.lines().any(|line| line.trim() == "typed-only"),
+            "the command must not run: {}",
+            output
+        );
+        assert!(manager.sessions[session_id].history.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_execute_command_waits_for_completion() {
+        let mut manager = SessionManager::new();
+        let created = manager
+            .create_session(CreateSessionArgs {
+                enable_web_server: Some(false),
+                ..web_session_args()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap();
+
+        let result = manager
+            .execute_command(execute_args(session_id, "sleep 0.2; echo slept"))
+            .await
+            .unwrap();
+        assert_eq!(result["exitCode"], 0);
+        assert_eq!(result["timedOut"], false);
+        let output = result["output"].as_str().unwrap();
+        assert!(
+            output
+                .lines()
+                .any(|line| control_sequences::strip(line).trim() == "slept"),
+            "{}",
+            output
+        );
+        assert!(!output.contains("__HT_DONE_"), "{}", output);
+
+        let result = manager
+            .execute_command(ExecuteCommandArgs {
+                summarize: Some(true),
+                ..execute_args(session_id, "false")
+            })
+            .await
+            .unwrap();
+        assert_eq!(result["exitCode"], 1);
+        assert_eq!(result["summary"]["exitCode"], 1);
+        assert_eq!(
+            manager.sessions[session_id].history,
+            ["sleep 0.2; echo slept", "false"]
+        );
+
+        let started = Instant::now();
+        let result = manager
+            .execute_command(ExecuteCommandArgs {
+                completion_timeout_ms: Some(300),
+                ..execute_args(session_id, "sleep 2")
+            })
+            .await
+            .unwrap();
+        assert!(started.elapsed() < Duration::from_millis(1500));
+        assert_eq!(result["timedOut"], true);
+        assert!(result["exitCode"].is_null());
+
+        let err = manager
+            .execute_command(ExecuteCommandArgs {
+                completion_timeout_ms: Some(0),
+                ..execute_args(session_id, "true")
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::InvalidRequest(_)), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_execute_command_classes() {
+        let mut manager = SessionManager::new();
+        let created = manager
+            .create_session(CreateSessionArgs {
+                enable_web_server: Some(false),
+                ..web_session_args()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap();
+
+        let result = manager
+            .execute_command(execute_args(session_id, "echo classified"))
+            .await
+            .unwrap();
+        assert_eq!(result["commandClass"], "default");
+        assert_eq!(result["timeoutMs"], 20_000);
+        assert_eq!(result["quietPeriodMs"], 1_000);
+
+        // The call's own budgets and class win over the command's
+        let result = manager
+            .execute_command(ExecuteCommandArgs {
+                completion_timeout_ms: Some(5_000),
+                quiet_period_ms: Some(200),
+                command_class: Some("build".to_string()),
+                ..execute_args(session_id, "echo built")
+            })
+            .await
+            .unwrap();
+        assert_eq!(result["commandClass"], "build");
+        assert_eq!(result["timeoutMs"], 5_000);
+        assert_eq!(result["quietPeriodMs"], 200);
+
+        // Interactive programs are refused before anything is typed
+        let err = manager
+            .execute_command(execute_args(session_id, "vim notes.txt"))
+            .await
+            .unwrap_err();
+        let HtMcpError::InvalidRequest(message) = &err else {
+            panic!("expected InvalidRequest, got {}", err);
+        };
+        assert!(message.contains("command class interactive"), "{}", message);
+        assert!(message.contains("ht_send_keys"), "{}", message);
+        assert!(!manager.sessions[session_id]
+            .history
+            .iter()
+            .any(|command| command.starts_with("vim")));
+
+        let err = manager
+            .execute_command(ExecuteCommandArgs {
+                command_class: Some("nope".to_string()),
+                ..execute_args(session_id, "true")
+            })
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("known classes"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_execute_command_expands_aliases() {
+        let mut manager = SessionManager::with_config(HtMcpConfig {
+            aliases: BTreeMap::from([("greet".to_string(), "echo hello".to_string())]),
+            ..HtMcpConfig::default()
+        });
+        let created = manager
+            .create_session(CreateSessionArgs {
+                enable_web_server: Some(false),
+                ..web_session_args()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        let set_alias = |name: &str, command: &str| SetAliasArgs {
+            session_id: session_id.clone(),
+            name: name.to_string(),
+            command: Some(command.to_string()),
+            timeout_ms: None,
+        };
+
+        let result = manager
+            .execute_command(execute_args(&session_id, "greet world"))
+            .await
+            .unwrap();
+        assert_eq!(result["command"], "echo hello world");
+        assert_eq!(
+            result["alias"],
+            serde_json::json!({"name": "greet", "invoked": "greet world"})
+        );
+        assert!(result["output"].as_str().unwrap().contains("hello world"));
+        assert_eq!(
+            manager.sessions[&session_id].history.last().unwrap(),
+            "echo hello world"
+        );
+
+        // Command classes judge the expansion, not the alias
+        manager.set_alias(set_alias("edit", "vim")).unwrap();
+        let err = manager
+            .execute_command(execute_args(&session_id, "edit notes.txt"))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("interactive"), "{}", err);
+
+        // Expansion stops at a word already expanded
+        manager.set_alias(set_alias("echo", "greet")).unwrap();
+        let result = manager
+            .execute_command(execute_args(&session_id, "echo there"))
+            .await
+            .unwrap();
+        assert_eq!(result["command"], "echo hello there");
+
+        // Parallel runs expand aliases too
+        let result = manager
+            .parallel_execute(ParallelExecuteArgs {
+                session_ids: vec![session_id.clone()],
+                command: "greet again".to_string(),
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(result["results"][0]["command"], "echo hello again");
+        assert_eq!(result["results"][0]["alias"]["name"], "greet");
+        assert!(result["results"][0]["output"]
+            .as_str()
+            .unwrap()
+            .contains("hello again"));
+
+        manager
+            .close_session(CloseSessionArgs {
+                session_id: session_id.clone(),
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        assert!(manager.aliases.expand(&session_id, "edit").is_none());
+        let err = manager.set_alias(set_alias("edit", "vim")).unwrap_err();
+        assert!(matches!(err, HtMcpError::SessionNotFound(_)));
+    }
+
+    #[tokio::test]
+    async fn test_execute_command_without_waiting() {
+        let mut manager = SessionManager::new();
+        let created = manager
+            .create_session(CreateSessionArgs {
+                enable_web_server: Some(false),
+                ..web_session_args()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap();
+
+        let result = manager
+            .execute_command(ExecuteCommandArgs {
+                wait_for_completion: Some(false),
+                ..execute_args(session_id, "echo fixed-wait")
+            })
+            .await
+            .unwrap();
+        assert!(result.get("exitCode").is_none());
+        assert!(result.get("timedOut").is_none());
+        let output = result["output"].as_str().unwrap();
+        assert!(
+            output
+                .lines()
+                .any(|line| control_sequences::strip(line).trim() == "fixed-wait"),
+            "{}",
+            output
+        );
+    }
+
+    #[tokio::test]
+    async fn test_interactive_prompt() {
+        let mut manager = SessionManager::new();
+        let created = manager
+            .create_session(CreateSessionArgs {
+                enable_web_server: Some(false),
+                ..web_session_args()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap();
+        let prompt_args = |pattern: &str, timeout_ms| InteractivePromptArgs {
+            session_id: session_id.to_string(),
+            prompt_pattern: pattern.to_string(),
+            response: "yes".to_string(),
+            prompt_timeout_ms: Some(timeout_ms),
+            timeout_ms: None,
+        };
+
+        manager
+            .send_keys(SendKeysArgs {
+                session_id: session_id.to_string(),
+                keys: vec![
+                    "sleep 0.3; read -p 'Proceed? [y/N] ' a; echo \"got-$a\"".to_string(),
+                    "Enter".to_string(),
+                ],
+                input_profile: None,
+                seed: None,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        let result = manager
+            .prepare_interactive_prompt(prompt_args(r"\[y/N\]\s*$", 5000))
+            .unwrap()
+            .run()
+            .await
+            .unwrap();
+        assert_eq!(result["promptDetected"], true);
+        assert_eq!(result["responsesSent"], 1);
+        assert!(!result.to_string().contains("\"yes\""), "{}", result);
+        manager
+            .handle(session_id)
+            .unwrap()
+            .wait_for("got-yes", Duration::from_secs(5))
+            .await
+            .unwrap();
+
+        let started = Instant::now();
+        let result = manager
+            .prepare_interactive_prompt(prompt_args("never-shown:", 200))
+            .unwrap()
+            .run()
+            .await
+            .unwrap();
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(result["promptDetected"], false);
+        assert_eq!(result["responsesSent"], 0);
+
+        let err = manager
+            .prepare_interactive_prompt(prompt_args("[unclosed", 200))
+            .err()
+            .unwrap();
+        assert!(matches!(err, HtMcpError::InvalidRequest(_)), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_execute_command_summary() {
+        let mut manager = SessionManager::new();
+        let created = manager
+            .create_session(CreateSessionArgs {
+                enable_web_server: Some(false),
+                ..web_session_args()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap();
+
+        let result = manager
+            .execute_command(ExecuteCommandArgs {
+                summarize: Some(true),
+                ..execute_args(session_id, "printf 'error: boom\\n  --> src/app.rs:4:2\\n'")
+            })
+            .await
+            .unwrap();
+        let summary = &result["summary"];
+        assert!(summary["errorLines"].as_u64().unwrap() >= 1, "{}", summary);
+        assert_eq!(
+            summary["files"][0],
+            serde_json::json!({"path": "src/app.rs", "line": 4, "column": 2})
+        );
+        assert!(summary["durationMs"].is_u64());
+        assert_eq!(summary["exitCode"], 0);
+        // Annotations are used, not returned, unless asked for
+        assert!(result.get("annotations").is_none());
+    }
+
+    #[test]
+    fn test_memory_limit_bytes() {
+        assert_eq!(memory_limit_bytes(64).unwrap(), 64 * 1024 * 1024);
+        assert!(matches!(
+            memory_limit_bytes(u64::MAX),
+            Err(HtMcpError::InvalidRequest(_))
+        ));
+    }
+
+    #[test]
+    fn test_prompt_shown() {
+        let marker = "HTMCP_READY_1> ";
+        assert!(prompt_shown(
+            "HTMCP_READY_1> ls\nfile.txt\nHTMCP_READY_1> \n\n",
+            marker
+        ));
+        // Colored, or after bracketed paste mode is switched on
+        assert!(prompt_shown("done\n\x1b[?2004hHTMCP_READY_1> ", marker));
+        assert!(!prompt_shown("HTMCP_READY_1> sleep 5", marker));
+        assert!(!prompt_shown("$ ", marker));
+        assert!(!prompt_shown("", marker));
+    }
+
+    #[test]
+    fn test_parse_command_check() {
+        let typed = "$ command -v jq 2>/dev/null && echo EXISTS || echo MISSING";
+        assert_eq!(
+            parse_command_check(&format!("{}\n/usr/bin/jq\nEXISTS\n$ \n", typed)),
+            Some(Some("/usr/bin/jq".to_string()))
+        );
+        assert_eq!(
+            parse_command_check(&format!("{}\nMISSING\n$ \n", typed)),
+            Some(None)
+        );
+        // Only the typed command line: the lookup has not printed yet
+        assert_eq!(parse_command_check(typed), None);
+    }
+
+    #[tokio::test]
+    async fn test_check_command_exists() {
+        let mut manager = SessionManager::new();
+        let created = manager
+            .create_session(CreateSessionArgs {
+                enable_web_server: Some(false),
+                ..web_session_args()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        let check = |command: &str| CheckCommandExistsArgs {
+            session_id: session_id.clone(),
+            command: command.to_string(),
+            timeout_ms: None,
+        };
+
+        let result = manager.check_command_exists(check("sh")).await.unwrap();
+        assert_eq!(result["exists"], true);
+        assert!(
+            result["path"].as_str().unwrap().ends_with("/sh"),
+            "{}",
+            result
+        );
+
+        let result = manager
+            .check_command_exists(check("htmcp-no-such-program"))
+            .await
+            .unwrap();
+        assert_eq!(result["exists"], false);
+        assert!(result["path"].is_null());
+
+        for command in ["ls; rm -rf x", "$(id)", "-p", "a b", ""] {
+            let err = manager
+                .check_command_exists(check(command))
+                .await
+                .unwrap_err();
+            assert!(matches!(err, HtMcpError::InvalidRequest(_)), "{}", command);
+        }
+
+        manager
+            .close_session(CloseSessionArgs {
+                session_id,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_probe_tools() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut manager = SessionManager::new();
+        let created = manager
+            .create_session(CreateSessionArgs {
+                enable_web_server: Some(false),
+                ..web_session_args()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        let probe = |tools: &[&str], refresh: bool| ProbeToolsArgs {
+            session_id: session_id.clone(),
+            tools: Some(tools.iter().map(|tool| tool.to_string()).collect()),
+            refresh: Some(refresh),
+            timeout_ms: None,
+        };
+
+        let result = manager
+            .probe_tools(probe(&["sed", "htmcp-no-such-program"], false))
+            .await
+            .unwrap();
+        assert_eq!(result["cached"], false);
+        assert_eq!(result["shell"]["name"], "bash");
+        assert!(result["shell"]["version"].as_str().is_some(), "{}", result);
+        let sed = &result["tools"]["sed"];
+        assert_eq!(sed["present"], true);
+        assert!(sed["path"].as_str().unwrap().ends_with("/sed"), "{}", sed);
+        assert!(sed["flavor"].is_string(), "{}", sed);
+        assert_eq!(
+            result["tools"]["htmcp-no-such-program"],
+            serde_json::json!({"present": false, "path": null, "version": null})
+        );
+
+        // Asked again, the tools come from the session's cache
+        let again = manager.probe_tools(probe(&["sed"], false)).await.unwrap();
+        assert_eq!(again["cached"], true);
+        assert_eq!(again["tools"]["sed"], *sed);
+
+        // A PATH with a jq and without sed, seen once the probe is refreshed
+        let dir = std::env::temp_dir().join(format!("ht-mcp-probe-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let jq = dir.join("jq");
+        std::fs::write(&jq, "#!/bin/sh\necho jq-1.7.1\n").unwrap();
+        std::fs::set_permissions(&jq, std::fs::Permissions::from_mode(0o755)).unwrap();
+        manager
+            .execute_command(execute_args(
+                &session_id,
+                &format!("export PATH={}", dir.display()),
+            ))
+            .await
+            .unwrap();
+        let result = manager
+            .probe_tools(probe(&["jq", "sed"], true))
+            .await
+            .unwrap();
+        assert_eq!(result["cached"], false);
+        assert_eq!(
+            result["tools"]["jq"],
+            serde_json::json!({
+                "present": true,
+                "path": jq.display().to_string(),
+                "version": "jq-1.7.1"
+            })
+        );
+        assert_eq!(result["tools"]["sed"]["present"], false);
+
+        for tools in [&["$(id)"][..], &[][..]] {
+            let err = manager.probe_tools(probe(tools, false)).await.unwrap_err();
+            assert!(matches!(err, HtMcpError::InvalidRequest(_)), "{:?}", tools);
+        }
+
+        manager
+            .close_session(CloseSessionArgs {
+                session_id,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_auto_detect_prompt() {
+        let mut manager = SessionManager::new();
+        let created = manager
+            .create_session(CreateSessionArgs {
+                enable_web_server: Some(false),
+                auto_detect_prompt: Some(true),
+                ..web_session_args()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap();
+        let marker = created["promptMarker"].as_str().unwrap().to_string();
+        assert!(marker.starts_with("HTMCP_READY_"));
+        assert_eq!(
+            manager.sessions[session_id].prompt_marker.as_deref(),
+            Some(marker.as_str())
+        );
+
+        // Output after the old fixed one-second wait is still captured
+        let result = manager
+            .execute_command(execute_args(session_id, "sleep 1.5; echo slow-done"))
+            .await
+            .unwrap();
+        assert_eq!(result["promptReturned"], true);
+        let output = result["output"].as_str().unwrap();
+        assert!(
+            output
+                .lines()
+                .any(|line| line.trim_end().ends_with("slow-done") && !line.contains("echo")),
+            "{}",
+            output
+        );
+
+        // A shorter timeoutMs cuts the wait for the prompt short
+        let started = Instant::now();
+        let result = manager
+            .execute_command(ExecuteCommandArgs {
+                completion_timeout_ms: Some(300),
+                ..execute_args(session_id, "sleep 5")
+            })
+            .await
+            .unwrap();
+        assert!(started.elapsed() < Duration::from_millis(1500));
+        assert_eq!(result["promptReturned"], false);
+        assert_eq!(result["timeoutMs"], 300);
+    }
+
+    #[tokio::test]
+    async fn test_execute_command_returns_when_the_shell_exits() {
+        let mut manager = SessionManager::new();
+        let created = manager
+            .create_session(CreateSessionArgs {
+                enable_web_server: Some(false),
+                auto_detect_prompt: Some(true),
+                ..web_session_args()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        let handle = manager.handle(&session_id).unwrap();
+
+        // The prompt never returns; without the exit this would wait a minute
+        let started = Instant::now();
+        let result = manager
+            .execute_command(execute_args(&session_id, "exec false"))
+            .await
+            .unwrap();
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert_eq!(result["sessionExited"], true, "{}", result);
+        assert_eq!(result["exitStatus"], 1, "{}", result);
+        assert!(result["output"].as_str().unwrap().contains("exec false"));
+
+        let started = Instant::now();
+        let err = handle
+            .wait_for("never-printed", Duration::from_secs(30))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::SessionExited(_)), "{}", err);
+        assert!(err.to_string().contains("status 1"), "{}", err);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        let err = manager.sessions[&session_id].snapshot().await.unwrap_err();
+        assert!(matches!(err, HtMcpError::SessionExited(_)), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_exited_session_is_reported_dead_and_refuses_input() {
+        let mut manager = SessionManager::new();
+        let created = manager
+            .create_session(CreateSessionArgs {
+                enable_web_server: Some(false),
+                ..web_session_args()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        let listed = manager
+            .list_sessions(ListSessionsArgs::default())
+            .await
+            .unwrap();
+        assert_eq!(listed["sessions"][0]["isAlive"], true);
+
+        let exited = manager.sessions[&session_id].exit.register();
+        manager
+            .send_keys(SendKeysArgs {
+                session_id: session_id.clone(),
+                keys: vec!["exit 3".to_string(), "Enter".to_string()],
+                input_profile: None,
+                seed: None,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(5), exited)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let listed = manager
+            .list_sessions(ListSessionsArgs::default())
+            .await
+            .unwrap();
+        assert_eq!(listed["sessions"][0]["isAlive"], false);
+        assert_eq!(listed["sessions"][0]["exitCode"], 3);
+
+        let err = manager
+            .send_keys(SendKeysArgs {
+                session_id: session_id.clone(),
+                keys: vec!["echo lost".to_string()],
+                input_profile: None,
+                seed: None,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::SessionExited(_)), "{}", err);
+        assert!(err.to_string().contains("status 3"), "{}", err);
+        let err = manager
+            .execute_command(execute_args(&session_id, "echo lost"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::SessionExited(_)), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_group_snapshot() {
+        let mut manager = SessionManager::new();
+        let mut members = Vec::new();
+        for group_id in ["svc", "other", "svc"] {
+            let created = manager
+                .create_session(CreateSessionArgs {
+                    enable_web_server: Some(false),
+                    group_id: Some(group_id.to_string()),
+                    ..web_session_args()
+                })
+                .await
+                .unwrap();
+            if group_id == "svc" {
+                members.push(created["sessionId"].as_str().unwrap().to_string());
+            }
+            // Distinct creation times, for the order
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        for session_id in &members {
+            manager
+                .execute_command(execute_args(session_id, &format!("echo in-{}", session_id)))
+                .await
+                .unwrap();
+        }
+
+        let result = manager
+            .group_snapshot(GroupSnapshotArgs {
+                group_id: "svc".to_string(),
+                format: Some(SnapshotFormat::AiCompressed),
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(result["succeeded"], 2);
+        assert_eq!(result["failed"], 0);
+        let snapshots = result["snapshots"].as_array().unwrap();
+        let ids: Vec<&str> = snapshots
+            .iter()
+            .map(|entry| entry["sessionId"].as_str().unwrap())
+            .collect();
+        assert_eq!(ids, members);
+        for entry in snapshots {
+            let text = entry["snapshot"].as_str().unwrap();
+            assert!(text.contains(&format!("in-{}", entry["sessionId"].as_str().unwrap())));
+        }
+
+        // Styled formats style every member's screen
+        manager
+            .execute_command(execute_args(
+                &members[0],
+                r"printf 'ok \033[1;32mgreen\033[0m\n'",
+            ))
+            .await
+            .unwrap();
+        let styled = |format| GroupSnapshotArgs {
+            group_id: "svc".to_string(),
+            format: Some(format),
+            timeout_ms: None,
+        };
+        let ansi = manager
+            .group_snapshot(styled(SnapshotFormat::Ansi))
+            .await
+            .unwrap();
+        let text = ansi["snapshots"][0]["snapshot"].as_str().unwrap();
+        assert!(text.contains("ok \x1b[0;1;32mgreen\x1b[0m"), "{:?}", text);
+        assert!(ansi["snapshots"][0].get("styledLines").is_none());
+        let structured = manager
+            .group_snapshot(styled(SnapshotFormat::Structured))
+            .await
+            .unwrap();
+        assert_eq!(structured["succeeded"], 2);
+        let entries = structured["snapshots"].as_array().unwrap();
+        assert!(entries.iter().all(|entry| entry["styledLines"].is_array()));
+        let runs: Vec<&serde_json::Value> = entries[0]["styledLines"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .flat_map(|line| line["runs"].as_array().unwrap())
+            .collect();
+        assert!(runs.contains(&&serde_json::json!({
+            "col": 3, "text": "green", "fg": 2, "bold": true
+        })));
+
+        let err = manager
+            .group_snapshot(GroupSnapshotArgs {
+                group_id: "missing".to_string(),
+                format: None,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::InvalidRequest(_)), "{}", err);
+        let err = manager
+            .create_session(CreateSessionArgs {
+                group_id: Some(String::new()),
+                ..web_session_args()
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::InvalidRequest(_)), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_bulk_close() {
+        let mut manager = SessionManager::new();
+        let mut created = Vec::new();
+        for project in ["web", "web", "api"] {
+            let result = manager
+                .create_session(CreateSessionArgs {
+                    enable_web_server: Some(false),
+                    tags: Some(HashMap::from([(
+                        "project".to_string(),
+                        project.to_string(),
+                    )])),
+                    ..web_session_args()
+                })
+                .await
+                .unwrap();
+            created.push(result["sessionId"].as_str().unwrap().to_string());
+        }
+        assert_eq!(
+            manager.sessions[&created[0]].summary()["tags"],
+            serde_json::json!({"project": "web"})
+        );
+
+        // Unknown and untagged sessions fail without keeping the rest open
+        let result = manager
+            .bulk_close(BulkCloseSessionsArgs {
+                session_ids: Some(vec![
+                    created[0].clone(),
+                    "missing".to_string(),
+                    created[2].clone(),
+                ]),
+                close_all: None,
+                filter_tags: Some(HashMap::from([("project".to_string(), "web".to_string())])),
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(result["closed"], serde_json::json!([created[0]]));
+        let failed: Vec<&str> = result["failed"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| entry["sessionId"].as_str().unwrap())
+            .collect();
+        assert_eq!(failed, [created[2].as_str(), "missing"]);
+        assert!(!manager.sessions.contains_key(&created[0]));
+        assert!(manager.sessions.contains_key(&created[2]));
+
+        let result = manager
+            .bulk_close(BulkCloseSessionsArgs {
+                session_ids: None,
+                close_all: Some(true),
+                filter_tags: None,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(result["closed"].as_array().unwrap().len(), 2);
+        assert_eq!(result["failed"], serde_json::json!([]));
+        assert!(manager.sessions.is_empty());
+
+        for args in [
+            BulkCloseSessionsArgs {
+                session_ids: None,
+                close_all: None,
+                filter_tags: None,
+                timeout_ms: None,
+            },
+            BulkCloseSessionsArgs {
+                session_ids: Some(vec![created[1].clone()]),
+                close_all: Some(true),
+                filter_tags: None,
+                timeout_ms: None,
+            },
+            BulkCloseSessionsArgs {
+                session_ids: None,
+                close_all: None,
+                filter_tags: Some(HashMap::new()),
+                timeout_ms: None,
+            },
+        ] {
+            let err = manager.bulk_close(args).await.unwrap_err();
+            assert!(matches!(err, HtMcpError::InvalidRequest(_)), "{}", err);
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_closing_kills_the_session_process_group() {
+        use nix::sys::signal::killpg;
+        use nix::unistd::Pid;
+
+        let mut manager = SessionManager::new();
+        let mut groups = Vec::new();
+        for _ in 0..2 {
+            // Ignoring SIGHUP, which `sleep` inherits, only delays the kill
+            let created = manager
+                .create_session(CreateSessionArgs {
+                    command: Some(vec!["trap '' HUP; sleep 10000".to_string()]),
+                    ..CreateSessionArgs::default()
+                })
+                .await
+                .unwrap();
+            let session_id = created["sessionId"].as_str().unwrap().to_string();
+            let pid = manager.sessions[&session_id].child.pid.unwrap();
+            groups.push((session_id, Pid::from_raw(pid as i32)));
+        }
+
+        manager
+            .close_session(CloseSessionArgs {
+                session_id: groups[0].0.clone(),
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        manager.shutdown().await;
+        assert!(manager.sessions.is_empty());
+
+        let deadline = Instant::now() + CHILD_HANG_UP_GRACE + Duration::from_secs(5);
+        for (session_id, group) in groups {
+            while killpg(group, None).is_ok() {
+                assert!(
+                    Instant::now() < deadline,
+                    "processes of session {} are still running",
+                    session_id
+                );
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_closing_stops_the_web_server() {
+        let mut manager = SessionManager::new();
+        let created = manager.create_session(web_session_args()).await.unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        let port = manager.sessions[&session_id]
+            .web_server
+            .as_ref()
+            .unwrap()
+            .port();
+
+        let closed = manager
+            .close_session(CloseSessionArgs {
+                session_id,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(closed["webServerStopped"], true);
+        assert_eq!(closed["tunnelStopped"], false);
+
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while web_listener::bind(None, port).is_err() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the web server's port should be released");
+
+        let created = manager
+            .create_session(CreateSessionArgs {
+                enable_web_server: Some(false),
+                ..web_session_args()
+            })
+            .await
+            .unwrap();
+        let closed = manager
+            .close_session(CloseSessionArgs {
+                session_id: created["sessionId"].as_str().unwrap().to_string(),
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(closed["webServerStopped"], false);
+    }
+
+    /// A tunnel on a fixed port that records being stopped
+    struct StubTunnel {
+        port: u16,
+        stopped: Arc<AtomicBool>,
+    }
+
+    #[async_trait::async_trait]
+    impl crate::tunnel::provider::Tunnel for StubTunnel {
+        fn provider(&self) -> &str {
+            "stub"
+        }
+
+        fn url(&self) -> &str {
+            "https://stub.example"
+        }
+
+        fn local_port(&self) -> u16 {
+            self.port
+        }
+
+        fn created_at(&self) -> SystemTime {
+            SystemTime::UNIX_EPOCH
+        }
+
+        fn is_running(&mut self) -> bool {
+            !self.stopped.load(Ordering::SeqCst)
+        }
+
+        async fn restart(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        async fn stop(&mut self) -> Result<()> {
+            self.stopped.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    fn stub_tunnel(port: u16) -> (DetachedTunnel, Arc<AtomicBool>) {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let tunnel = DetachedTunnel::new(Box::new(StubTunnel {
+            port,
+            stopped: stopped.clone(),
+        }));
+        (tunnel, stopped)
+    }
+
+    #[tokio::test]
+    async fn test_tunnels_follow_a_moved_web_server() {
+        let mut manager = SessionManager::new();
+        let created = manager.create_session(web_session_args()).await.unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        let port = manager.sessions[&session_id]
+            .web_server
+            .as_ref()
+            .unwrap()
+            .port();
+        let attach_stale = |manager: &mut SessionManager| {
+            let (tunnel, stopped) = stub_tunnel(port.wrapping_add(1));
+            let tunnel = manager.tunnel_manager.attach(tunnel);
+            let session = manager.sessions.get_mut(&session_id).unwrap();
+            session.tunnel_id = Some(tunnel.id);
+            session.tunnel_url = Some(tunnel.url);
+            stopped
+        };
+
+        // Taken out, so replacing it does not need the manager
+        let old_stopped = attach_stale(&mut manager);
+        let mut retargets = manager.prepare_tunnel_retargets();
+        assert_eq!(retargets.len(), 1);
+        assert_eq!(manager.tunnel_manager.tunnel_count(), 0);
+        assert!(manager.sessions[&session_id].tunnel_id.is_none());
+        assert!(manager.prepare_tunnel_retargets().is_empty());
+
+        let retarget = retargets.pop().unwrap();
+        assert_eq!(retarget.config.port, port);
+        retarget.old.stop().await.unwrap();
+        assert!(old_stopped.load(Ordering::SeqCst));
+        let (replacement, _) = stub_tunnel(port);
+        manager.finish_tunnel_retarget(RetargetedTunnel {
+            session_id: session_id.clone(),
+            url_history: retarget.url_history,
+            replacement: Ok(replacement),
+        });
+        let tunnel_id = manager.sessions[&session_id].tunnel_id.clone().unwrap();
+        let tunnel = manager.tunnel_manager.get_tunnel(&tunnel_id).unwrap();
+        assert_eq!(tunnel.local_port, port);
+        assert_eq!(tunnel.tunnel_url_history.len(), 2);
+        // On the right port now, so left alone
+        assert!(manager.prepare_tunnel_retargets().is_empty());
+
+        // A replacement that fails to start leaves the session without one
+        manager.tunnel_manager.detach(&tunnel_id);
+        let old_stopped = attach_stale(&mut manager);
+        let mut retarget = manager.prepare_tunnel_retargets().pop().unwrap();
+        retarget.config = retarget.config.with_provider("none".to_string());
+        let retargeted = retarget.run().await;
+        assert!(old_stopped.load(Ordering::SeqCst));
+        manager.finish_tunnel_retarget(retargeted);
+        assert!(manager.sessions[&session_id].tunnel_id.is_none());
+        assert_eq!(manager.tunnel_manager.tunnel_count(), 0);
+
+        // One for a session that ended meanwhile is stopped
+        let (replacement, stopped) = stub_tunnel(port);
+        manager
+            .close_session(CloseSessionArgs {
+                session_id: session_id.clone(),
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        manager.finish_tunnel_retarget(RetargetedTunnel {
+            session_id,
+            url_history: Vec::new(),
+            replacement: Ok(replacement),
+        });
+        assert_eq!(manager.tunnel_manager.tunnel_count(), 0);
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while !stopped.load(Ordering::SeqCst) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the orphaned replacement should be stopped");
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_names_are_checked() {
+        let tunnel_args = |name: &str, enable_tunnel: bool| CreateSessionArgs {
+            enable_tunnel: Some(enable_tunnel),
+            tunnel_name: Some(name.to_string()),
+            ..web_session_args()
+        };
+        let err = SessionManager::new()
+            .create_session(tunnel_args("demo", true))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("[named_tunnel]"), "{}", err);
+
+        let credentials_dir = tempfile::tempdir().unwrap();
+        let mut manager = SessionManager::with_config(HtMcpConfig {
+            named_tunnel: NamedTunnelConfig {
+                hostname_template: Some("{session-name}.example.com".to_string()),
+                origin_cert: None,
+                credentials_dir: Some(credentials_dir.path().to_path_buf()),
+            },
+            ..HtMcpConfig::default()
+        });
+        for (name, enable_tunnel, problem) in [
+            ("Demo", true, "lowercase"),
+            ("demo-", true, "lowercase"),
+            ("demo", false, "enableTunnel"),
+        ] {
+            let err = manager
+                .create_session(tunnel_args(name, enable_tunnel))
+                .await
+                .unwrap_err();
+            assert!(err.to_string().contains(problem), "{}: {}", name, err);
+        }
+
+        // Without cloudflared the session gets no tunnel, but keeps its name
+        let created = manager
+            .create_session(tunnel_args("demo", true))
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        assert_eq!(
+            manager.sessions[&session_id].config.tunnel_name.as_deref(),
+            Some("demo")
+        );
+        let err = manager
+            .create_session(tunnel_args("demo", true))
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains(&format!("already used by session {}", session_id)),
+            "{}",
+            err
+        );
+        // Free again once the session is closed
+        manager
+            .close_session(CloseSessionArgs {
+                session_id,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        assert!(manager
+            .create_session(tunnel_args("demo", true))
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_sessions_lease_pooled_tunnels() {
+        let mut manager = SessionManager::with_config(HtMcpConfig {
+            tunnel_pool: TunnelPoolConfig { size: 1 },
+            ..HtMcpConfig::default()
+        });
+        let (tunnel, stopped) = stub_tunnel(0);
+        let tunnel_id = tunnel.id.clone();
+        manager
+            .tunnel_manager
+            .add_pooled(tunnel.with_relay(Relay::bind().await.unwrap()));
+        assert!(manager.prepare_tunnel_refill().is_none());
+
+        let created = manager
+            .create_session(CreateSessionArgs {
+                enable_tunnel: Some(true),
+                ..web_session_args()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        let session = &manager.sessions[&session_id];
+        assert_eq!(session.tunnel_id.as_deref(), Some(tunnel_id.as_str()));
+        let port = session.web_server.as_ref().unwrap().port();
+        assert_eq!(
+            manager
+                .tunnel_manager
+                .get_tunnel(&tunnel_id)
+                .unwrap()
+                .local_port,
+            port
+        );
+        let listed = manager.list_tunnels(ListTunnelsArgs::default()).unwrap();
+        assert_eq!(listed["tunnels"][0]["sessionId"], session_id);
+        assert_eq!(listed["tunnels"][0]["pool"]["state"], "leased");
+        assert_eq!(listed["pool"]["leased"], 1);
+        // Leased, so the pool is one short
+        assert_eq!(manager.prepare_tunnel_refill().unwrap().count, 1);
+
+        let closed = manager
+            .close_session(CloseSessionArgs {
+                session_id,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        // Stopped, so the URL the session had is never handed out again
+        assert_eq!(closed["tunnelStopped"], true);
+        assert!(stopped.load(Ordering::SeqCst));
+        assert!(manager.tunnel_manager.pool_entry(&tunnel_id).is_none());
+        assert_eq!(manager.tunnel_pool_summary()["hits"], 1);
+        assert_eq!(manager.prepare_tunnel_refill().unwrap().count, 1);
+
+        // Tunnels started for the pool once draining are stopped instead
+        manager.start_drain(Duration::from_secs(60), false).unwrap();
+        assert!(manager.prepare_tunnel_refill().is_none());
+        let (late, late_stopped) = stub_tunnel(0);
+        manager.finish_tunnel_refill(vec![late]);
+        assert_eq!(manager.tunnel_manager.tunnel_count(), 1);
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while !late_stopped.load(Ordering::SeqCst) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("a tunnel started while draining should be stopped");
+    }
+
+    #[tokio::test]
+    async fn test_get_session_info() {
+        let mut manager = SessionManager::new();
+        let created = manager
+            .create_session(CreateSessionArgs {
+                enable_web_server: Some(false),
+                ..web_session_args()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        let info_args = || GetSessionInfoArgs {
+            session_id: session_id.clone(),
+            timeout_ms: None,
+        };
+
+        let info = manager.get_session_info(info_args()).await.unwrap();
+        assert_eq!(info["id"], session_id.as_str());
+        assert_eq!(info["isAlive"], true);
+        assert_eq!(info["cols"], DEFAULT_COLS);
+        assert_eq!(info["rows"], DEFAULT_ROWS);
+        let snapshots = info["snapshotsTaken"].as_u64().unwrap();
+        assert!(info["uptimeSecs"].is_u64());
+
+        manager
+            .take_snapshot(TakeSnapshotArgs {
+                session_id: session_id.clone(),
+                format: None,
+                trim_trailing_blank_lines: None,
+                annotate: None,
+                annotate_patterns: None,
+                word_wrap: None,
+                probe_only: None,
+                max_tokens: None,
+                include_provenance: None,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        let info = manager.get_session_info(info_args()).await.unwrap();
+        assert!(info["snapshotsTaken"].as_u64().unwrap() > snapshots);
+
+        let err = manager
+            .get_session_info(GetSessionInfoArgs {
+                session_id: "missing".to_string(),
+                timeout_ms: None,
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::SessionNotFound(_)), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_tail_file_filters_updates_and_closes_with_the_file() {
+        let mut manager = SessionManager::new();
+        let path = std::env::temp_dir().join(format!("ht-mcp-tail-{}.log", Uuid::new_v4()));
+        std::fs::write(&path, "INFO boot\nERROR disk\nINFO ready\n").unwrap();
+        let tail_args = |cursor: Option<u64>| TailFileArgs {
+            path: path.to_string_lossy().to_string(),
+            lines: None,
+            follow: None,
+            filter_pattern: Some("ERROR".to_string()),
+            cursor,
+            timeout_ms: None,
+        };
+        let texts = |result: &serde_json::Value| -> Vec<String> {
+            result["lines"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|line| line["text"].as_str().unwrap().to_string())
+                .collect()
+        };
+
+        let first = manager.tail_file(tail_args(None), None).await.unwrap();
+        let session_id = first["sessionId"].as_str().unwrap().to_string();
+        assert_eq!(first["reused"], false);
+        assert_eq!(texts(&first), ["ERROR disk"]);
+        assert_eq!(first["lines"][0]["line"], 1);
+        assert_eq!(first["nextCursor"], 3);
+
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        std::io::Write::write_all(&mut file, b"INFO tick\nERROR again\n").unwrap();
+        let cursor = first["nextCursor"].as_u64();
+        let mut update = serde_json::Value::Null;
+        for _ in 0..50 {
+            update = manager.tail_file(tail_args(cursor), None).await.unwrap();
+            if update["nextCursor"] == 5 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(update["sessionId"], session_id.as_str());
+        assert_eq!(update["reused"], true);
+        assert_eq!(texts(&update), ["ERROR again"]);
+
+        // Tail sessions take no input
+        let err = manager
+            .check_input_allowed(
+                "ht_send_keys",
+                &serde_json::json!({"sessionId": session_id}),
+            )
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::InvalidRequest(_)), "{}", err);
+        assert!(manager
+            .check_input_allowed(
+                "ht_take_snapshot",
+                &serde_json::json!({"sessionId": session_id})
+            )
+            .is_ok());
+
+        manager.reap_vanished_tail_files().await;
+        assert!(manager.sessions.contains_key(&session_id));
+        std::fs::remove_file(&path).unwrap();
+        manager.reap_vanished_tail_files().await;
+        assert!(!manager.sessions.contains_key(&session_id));
+
+        let err = manager.tail_file(tail_args(None), None).await.unwrap_err();
+        assert!(matches!(err, HtMcpError::InvalidRequest(_)), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_parallel_execute() {
+        let mut manager = SessionManager::new();
+        let mut session_ids = Vec::new();
+        for _ in 0..2 {
+            let created = manager
+                .create_session(CreateSessionArgs {
+                    enable_web_server: Some(false),
+                    ..web_session_args()
+                })
+                .await
+                .unwrap();
+            session_ids.push(created["sessionId"].as_str().unwrap().to_string());
+        }
+        session_ids.push("missing".to_string());
+
+        let started = Instant::now();
+        let result = manager
+            .parallel_execute(ParallelExecuteArgs {
+                session_ids: session_ids.clone(),
+                command: "echo parallel-marker".to_string(),
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        // Each command waits a second for output; run serially this would take 2s
+        assert!(started.elapsed() < Duration::from_millis(1900));
+
+        assert_eq!(result["succeeded"], 2);
+        assert_eq!(result["failed"], 1);
+        let results = result["results"].as_array().unwrap();
+        for (entry, session_id) in results.iter().zip(&session_ids) {
+            assert_eq!(entry["sessionId"], serde_json::json!(session_id));
+        }
+        for entry in &results[..2] {
+            assert!(entry["error"].is_null());
+            assert!(entry["output"]
+                .as_str()
+                .unwrap()
+                .contains("parallel-marker"));
+        }
+        assert!(results[2]["output"].is_null());
+        assert!(results[2]["error"]
+            .as_str()
+            .unwrap()
+            .contains("Session not found"));
+        assert_eq!(manager.sessions[&session_ids[0]].history.len(), 1);
+
+        let err = manager
+            .parallel_execute(ParallelExecuteArgs {
+                session_ids: vec![session_ids[0].clone(), session_ids[0].clone()],
+                command: "true".to_string(),
+                timeout_ms: None,
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::InvalidRequest(_)));
+    }
+
+    #[tokio::test]
+    async fn test_web_server_over_ipv6() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        if std::net::TcpListener::bind("[::1]:0").is_err() {
+            eprintln!("skipping: no IPv6 on this host");
+            return;
+        }
+        let mut manager = SessionManager::with_config(HtMcpConfig {
+            bind_address: Some("::1".parse().unwrap()),
+            ..HtMcpConfig::default()
+        });
+        let created = manager.create_session(web_session_args()).await.unwrap();
+        let url = created["webServerUrl"].as_str().unwrap();
+        let authority = url.strip_prefix("http://").unwrap();
+        assert!(authority.starts_with("[::1]:"), "{}", url);
+
+        let mut stream = tokio::net::TcpStream::connect(authority).await.unwrap();
+        stream
+            .write_all(
+                format!(
+                    "GET / HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                    authority
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+            .await
+            .expect("viewer page should load")
+            .unwrap();
+        assert!(
+            response.starts_with(b"HTTP/1.1 200"),
+            "{}",
+            String::from_utf8_lossy(&response)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_session_with_tunnel_rolls_back_on_tunnel_failure() {
+        let mut manager = SessionManager::new();
+        let port = manager.find_available_port().await.unwrap();
+
+        // Whether or not cloudflared is installed, no tunnel is up within 1ms
+        let err = manager
+            .create_session_with_tunnel(CreateSessionWithTunnelArgs {
+                command: None,
+                tunnel_provider: None,
+                tunnel_timeout_ms: Some(1),
+                timeout_ms: None,
+            })
+            .await
+            .expect_err("creation should fail without a tunnel");
+
+        assert!(err.to_string().contains("tunnel"), "{}", err);
+        assert!(manager.sessions.is_empty());
+        assert_eq!(manager.tunnel_manager.tunnel_count(), 0);
+        assert!(std::net::TcpListener::bind(("127.0.0.1", port)).is_ok());
+
+        let err = manager
+            .create_session_with_tunnel(CreateSessionWithTunnelArgs {
+                command: None,
+                tunnel_provider: Some("ngrok".to_string()),
+                tunnel_timeout_ms: None,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::InvalidRequest(_)));
+
+        // bore and localhost.run only once the config enables them
+        for provider in ["bore", "localhost.run"] {
+            let err = manager
+                .create_session_with_tunnel(CreateSessionWithTunnelArgs {
+                    command: None,
+                    tunnel_provider: Some(provider.to_string()),
+                    tunnel_timeout_ms: None,
+                    timeout_ms: None,
+                })
+                .await
+                .unwrap_err();
+            assert!(err.to_string().contains("tunnel_providers"), "{}", err);
+        }
+        assert!(manager.sessions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_renew_lease() {
+        let mut manager = SessionManager::new();
+        let leased = manager
+            .create_session(CreateSessionArgs {
+                enable_web_server: Some(false),
+                lease_secs: Some(60),
+                ..web_session_args()
+            })
+            .await
+            .unwrap();
+        let session_id = leased["sessionId"].as_str().unwrap().to_string();
+
+        let renewed = manager
+            .renew_lease(RenewLeaseArgs {
+                session_id: session_id.clone(),
+                extend_secs: 30,
+                timeout_ms: None,
+            })
+            .unwrap();
+        assert_eq!(renewed["lease"]["renewals"], 1);
+        assert!(renewed["lease"]["expiresInMs"].as_u64().unwrap() > 60_000);
+
+        let unleased = manager
+            .create_session(CreateSessionArgs {
+                enable_web_server: Some(false),
+                ..web_session_args()
+            })
+            .await
+            .unwrap();
+        let err = manager
+            .renew_lease(RenewLeaseArgs {
+                session_id: unleased["sessionId"].as_str().unwrap().to_string(),
+                extend_secs: 30,
+                timeout_ms: None,
+            })
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::InvalidRequest(_)));
+    }
+
+    /// The next `session.input` event, skipping others
+    fn next_input_event(events: &mut broadcast::Receiver<SessionEvent>) -> SessionEvent {
+        loop {
+            let event = events.try_recv().expect("an input event");
+            if matches!(event, SessionEvent::Input { .. }) {
+                return event;
+            }
+        }
+    }
+
+    fn viewer_input(session_id: &str, text: &str) -> ViewerInput {
+        ViewerInput {
+            session_id: session_id.to_string(),
+            peer: "203.0.113.7:5000".to_string(),
+            keys: vec![text.to_string()],
+            seqs: vec![InputSeq::Standard(text.to_string())],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_viewer_input_goes_through_input_policy() {
+        let mut manager = SessionManager::new();
+        let created = manager
+            .create_session(CreateSessionArgs {
+                enable_web_server: Some(false),
+                ..web_session_args()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        let mut events = manager.subscribe_events();
+        assert!(manager.sessions[&session_id].summary()["lastInputAt"].is_null());
+
+        manager
+            .prepare_viewer_input(viewer_input(&session_id, "echo viewer-marker\r"))
+            .unwrap()
+            .send()
+            .await
+            .unwrap();
+        let mut screen = String::new();
+        for _ in 0..50 {
+            screen = manager.sessions[&session_id].snapshot().await.unwrap();
+            if screen.matches("viewer-marker").count() >= 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert!(screen.matches("viewer-marker").count() >= 2, "{}", screen);
+        assert!(matches!(
+            next_input_event(&mut events),
+            SessionEvent::Input {
+                source: InputSource::Web,
+                tool: None,
+                peer: Some(peer),
+                accepted: true,
+                refused: None,
+                ..
+            } if peer == "203.0.113.7:5000"
+        ));
+        assert!(manager.sessions[&session_id].summary()["lastInputAt"].is_string());
+
+        // A viewer holds no lock, so a locked session refuses it
+        manager.sessions.get_mut(&session_id).unwrap().lock = Some(InteractionLock::new(
+            "driver".to_string(),
+            Duration::from_secs(60),
+        ));
+        let err = manager
+            .prepare_viewer_input(viewer_input(&session_id, "x"))
+            .err()
+            .unwrap();
+        assert!(matches!(err, HtMcpError::SessionLocked { .. }), "{}", err);
+        assert!(matches!(
+            next_input_event(&mut events),
+            SessionEvent::Input {
+                source: InputSource::Web,
+                accepted: false,
+                refused: Some(_),
+                ..
+            }
+        ));
+
+        let err = manager
+            .prepare_viewer_input(viewer_input("missing", "x"))
+            .err()
+            .unwrap();
+        assert!(matches!(err, HtMcpError::SessionNotFound(_)));
+    }
+
+    #[tokio::test]
+    async fn test_read_only_sessions_refuse_all_input() {
+        let mut manager = SessionManager::new();
+        let created = manager
+            .create_session(CreateSessionArgs {
+                enable_web_server: Some(false),
+                read_only: Some(true),
+                ..web_session_args()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        let summary = manager.sessions[&session_id].summary();
+        assert_eq!(summary["readOnly"], true);
+        let mut events = manager.subscribe_events();
+
+        let err = manager
+            .prepare_viewer_input(viewer_input(&session_id, "x"))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("read-only"), "{}", err);
+        let err = manager
+            .check_input_allowed(
+                "ht_send_keys",
+                &serde_json::json!({"sessionId": session_id, "keys": ["x"]}),
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("read-only"), "{}", err);
+        for source in [InputSource::Web, InputSource::Mcp] {
+            let event = next_input_event(&mut events);
+            assert!(
+                matches!(
+                    &event,
+                    SessionEvent::Input { source: s, accepted: false, .. } if *s == source
+                ),
+                "{:?}",
+                event
+            );
+        }
+        assert!(manager.sessions[&session_id].last_input_at.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_mcp_input_is_audited() {
+        let mut manager = SessionManager::new();
+        let created = manager
+            .create_session(CreateSessionArgs {
+                enable_web_server: Some(false),
+                ..web_session_args()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        let mut events = manager.subscribe_events();
+
+        manager
+            .check_input_allowed(
+                "ht_send_keys",
+                &serde_json::json!({"sessionId": session_id, "keys": ["x"]}),
+            )
+            .unwrap();
+        let event = next_input_event(&mut events);
+        assert!(
+            matches!(
+                &event,
+                SessionEvent::Input {
+                    source: InputSource::Mcp,
+                    tool: Some(tool),
+                    peer: None,
+                    accepted: true,
+                    ..
+                } if tool == "ht_send_keys"
+            ),
+            "{:?}",
+            event
+        );
+        assert!(manager.sessions[&session_id].last_input_at.is_some());
+
+        // Reading tools are not input
+        manager
+            .check_input_allowed(
+                "ht_take_snapshot",
+                &serde_json::json!({"sessionId": session_id}),
+            )
+            .unwrap();
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_expired_locks_are_reaped() {
+        let mut manager = SessionManager::new();
+        let created = manager
+            .create_session(CreateSessionArgs {
+                enable_web_server: Some(false),
+                ..web_session_args()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        let mut events = manager.subscribe_events();
+
+        let err = manager
+            .acquire_lock(AcquireLockArgs {
+                session_id: session_id.clone(),
+                holder: "driver".to_string(),
+                ttl_secs: Some(60),
+                override_lock: Some(true),
+                timeout_ms: None,
+            })
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::InvalidRequest(_)), "{}", err);
+        let err = manager
+            .acquire_lock(AcquireLockArgs {
+                session_id: session_id.clone(),
+                holder: "driver".to_string(),
+                ttl_secs: Some(0),
+                override_lock: None,
+                timeout_ms: None,
+            })
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::InvalidRequest(_)), "{}", err);
+
+        manager.sessions.get_mut(&session_id).unwrap().lock =
+            Some(InteractionLock::new("driver".to_string(), Duration::ZERO));
+        // An expired lock is not reported even before it is reaped
+        let listed = manager.sessions[&session_id].summary();
+        assert!(listed["lock"].is_null());
+
+        manager.reap_expired_locks();
+        assert!(manager.sessions[&session_id].lock.is_none());
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            SessionEvent::LockReleased {
+                session_id: released,
+                holder,
+                reason: LockReleaseReason::Expired,
+            } if released == session_id && holder == "driver"
+        ));
+        manager.reap_expired_locks();
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_expired_lease_closes_session_after_warning() {
+        let mut manager = SessionManager::new();
+        let leased = manager
+            .create_session(CreateSessionArgs {
+                enable_web_server: Some(false),
+                lease_secs: Some(1),
+                ..web_session_args()
+            })
+            .await
+            .unwrap();
+        let leased_id = leased["sessionId"].as_str().unwrap().to_string();
+        let unleased = manager
+            .create_session(CreateSessionArgs {
+                enable_web_server: Some(false),
+                ..web_session_args()
+            })
+            .await
+            .unwrap();
+        let mut events = manager.subscribe_events();
+
+        // Within the 30s default warning window, but not expired yet
+        manager.reap_expired_leases().await;
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            SessionEvent::LeaseExpiring { session_id, .. } if session_id == leased_id
+        ));
+        assert_eq!(manager.sessions.len(), 2);
+
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        manager.reap_expired_leases().await;
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            SessionEvent::SessionClosed {
+                session_id,
+                reason: TerminationReason::LeaseExpired,
+                ..
+            } if session_id == leased_id
+        ));
+        assert!(!manager.sessions.contains_key(&leased_id));
+        assert!(manager
+            .sessions
+            .contains_key(unleased["sessionId"].as_str().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_classify_screen() {
+        async fn wait_for_state(
+            manager: &SessionManager,
+            session_id: &str,
+            state: &str,
+        ) -> serde_json::Value {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            loop {
+                let verdict = manager
+                    .classify_screen(ClassifyScreenArgs {
+                        session_id: session_id.to_string(),
+                        timeout_ms: None,
+                    })
+                    .await
+                    .unwrap();
+                if verdict["state"] == state || Instant::now() > deadline {
+                    return verdict;
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+
+        let mut manager = SessionManager::new();
+        let created = manager
+            .create_session(CreateSessionArgs {
+                command: Some(vec!["sh".to_string()]),
+                ..CreateSessionArgs::default()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        let handle = manager.handle(&session_id).unwrap();
+
+        let verdict = wait_for_state(&manager, &session_id, "shell-prompt").await;
+        assert_eq!(verdict["state"], "shell-prompt", "{}", verdict);
+
+        // Echo off at a prompt, as programs reading secrets do
+        handle
+            .send_keys(&["stty -echo; printf 'Token: '; read x", "Enter"])
+            .await
+            .unwrap();
+        let verdict = wait_for_state(&manager, &session_id, "password-prompt").await;
+        assert_eq!(verdict["state"], "password-prompt", "{}", verdict);
+        assert_eq!(
+            verdict["evidence"][0],
+            "input echo is off at a line ending in ':'"
+        );
+
+        handle
+            .send_keys(&[
+                "x",
+                "Enter",
+                "stty echo; printf '\\033[?1049h'; sleep 30",
+                "Enter",
+            ])
+            .await
+            .unwrap();
+        let verdict = wait_for_state(&manager, &session_id, "full-screen-app").await;
+        assert_eq!(verdict["state"], "full-screen-app", "{}", verdict);
+
+        manager
+            .close_session(CloseSessionArgs {
+                session_id,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_session_handle_and_output_stream() {
+        use futures::StreamExt;
+
+        let mut manager = SessionManager::new();
+        let created = manager
+            .create_session(CreateSessionArgs {
+                enable_web_server: Some(false),
+                ..web_session_args()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        let handle = manager.handle(&session_id).unwrap();
+        let mut output = handle.output_stream().unwrap();
+
+        handle
+            .send_keys(&["echo handle-$((6*7))", "Enter"])
+            .await
+            .unwrap();
+        let screen = handle
+            .wait_for("handle-42", Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert!(screen.contains("handle-42"));
+
+        let mut streamed = Vec::new();
+        while !String::from_utf8_lossy(&streamed).contains("handle-42") {
+            let chunk = tokio::time::timeout(Duration::from_secs(5), output.next())
+                .await
+                .expect("output should be streamed")
+                .unwrap()
+                .unwrap();
+            streamed.extend_from_slice(&chunk);
+        }
+
+        let err = handle
+            .wait_for("never-printed", Duration::from_millis(300))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::Timeout(_)));
+
+        manager
+            .close_session(CloseSessionArgs {
+                session_id,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        let ended = tokio::time::timeout(Duration::from_secs(5), async {
+            while output.next().await.is_some() {}
+        })
+        .await;
+        assert!(ended.is_ok(), "the stream ends with the session");
+        assert!(handle.snapshot().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_tunnel_needs_a_tunnel() {
+        let mut manager = SessionManager::new();
+        let created = manager.create_session(web_session_args()).await.unwrap();
+
+        let err = manager
+            .reconnect_tunnel(ReconnectTunnelArgs {
+                session_id: created["sessionId"].as_str().unwrap().to_string(),
+                timeout_ms: None,
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::InvalidRequest(_)));
+
+        let err = manager
+            .reconnect_tunnel(ReconnectTunnelArgs {
+                session_id: "missing".to_string(),
+                timeout_ms: None,
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::SessionNotFound(_)));
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_tools_check_their_arguments() {
+        let mut manager = SessionManager::with_config(HtMcpConfig {
+            tunnel_ports: vec![8080],
+            ..HtMcpConfig::default()
+        });
+        for (port, timeout_secs) in [(0, None), (8080, Some(0)), (8080, Some(301))] {
+            let err = manager
+                .create_tunnel(CreateTunnelArgs {
+                    port,
+                    timeout_secs,
+                    timeout_ms: None,
+                })
+                .await
+                .unwrap_err();
+            assert!(matches!(err, HtMcpError::InvalidRequest(_)), "{}", err);
+        }
+
+        let err = manager
+            .stop_tunnel(StopTunnelArgs {
+                tunnel_id: "missing".to_string(),
+                timeout_ms: None,
+            })
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("ht_list_tunnels"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_create_tunnel_needs_a_listed_port_or_admin_tools() {
+        let args = |port| CreateTunnelArgs {
+            port,
+            // Out of range, so a call that gets past the port check fails
+            // without starting a tunnel
+            timeout_secs: Some(0),
+            timeout_ms: None,
+        };
+        let mut manager = SessionManager::with_config(HtMcpConfig {
+            tunnel_ports: vec![3000],
+            ..HtMcpConfig::default()
+        });
+        let err = manager.create_tunnel(args(22)).await.unwrap_err();
+        assert!(err.to_string().contains("not in tunnel_ports"), "{}", err);
+        let err = manager.create_tunnel(args(3000)).await.unwrap_err();
+        assert!(err.to_string().contains("timeoutSecs"), "{}", err);
+
+        let mut manager = SessionManager::with_config(HtMcpConfig {
+            enable_admin_tools: true,
+            ..HtMcpConfig::default()
+        });
+        let err = manager.create_tunnel(args(22)).await.unwrap_err();
+        assert!(err.to_string().contains("timeoutSecs"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_rollback_at_listener_stage() {
+        assert_rollback_at(CreateStage::Listener).await;
+    }
+
+    #[tokio::test]
+    async fn test_rollback_at_web_server_stage() {
+        assert_rollback_at(CreateStage::WebServer).await;
+    }
+
+    #[tokio::test]
+    async fn test_rollback_at_pty_stage() {
+        assert_rollback_at(CreateStage::Pty).await;
+    }
+
+    #[tokio::test]
+    async fn test_rollback_at_event_loop_stage() {
+        assert_rollback_at(CreateStage::EventLoop).await;
+    }
+
+    #[test]
+    fn test_purge_expired_idempotency_keys() {
+        let mut manager = SessionManager::new();
+        let expired_at = Instant::now()
+            .checked_sub(IDEMPOTENCY_KEY_TTL + Duration::from_secs(1))
+            .unwrap();
+        manager
+            .idempotency_cache
+            .insert("old".to_string(), ("session-a".to_string(), expired_at));
+        manager.idempotency_cache.insert(
+            "fresh".to_string(),
+            ("session-b".to_string(), Instant::now()),
+        );
+
+        manager.purge_expired_idempotency_keys();
+
+        assert!(!manager.idempotency_cache.contains_key("old"));
+        assert!(manager.idempotency_cache.contains_key("fresh"));
+    }
+
+    #[test]
+    fn test_page_of() {
+        let now = SystemTime::now();
+        let items = (0..5u64).map(|i| (now + Duration::from_secs(i), i));
+
+        let page = Page::of(items.clone(), None, Some(2)).unwrap();
+        assert_eq!(page.items, [4, 3]);
+        assert_eq!(page.total_count, 5);
+        assert!(page.has_more());
+
+        let page = Page::of(items.clone(), Some(3), Some(2)).unwrap();
+        assert_eq!(page.items, [0]);
+        assert!(!page.has_more());
+
+        let page = Page::of(items.clone(), Some(9), None).unwrap();
+        assert!(page.items.is_empty());
+        assert_eq!(page.page_size, DEFAULT_PAGE_SIZE);
+
+        assert!(matches!(
+            Page::of(items, Some(0), None),
+            Err(HtMcpError::InvalidRequest(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_list_sessions_pages_newest_first() {
+        let mut manager = SessionManager::new();
+        let mut session_ids = Vec::new();
+        for _ in 0..3 {
+            let created = manager
+                .create_session(CreateSessionArgs {
+                    command: Some(vec!["sh".to_string()]),
+                    ..CreateSessionArgs::default()
+                })
+                .await
+                .unwrap();
+            session_ids.push(created["sessionId"].as_str().unwrap().to_string());
+        }
+
+        let listed = manager
+            .list_sessions(ListSessionsArgs {
+                page_size: Some(2),
+                ..ListSessionsArgs::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(listed["count"], 2);
+        assert_eq!(listed["totalCount"], 3);
+        assert_eq!(listed["hasMore"], true);
+        assert_eq!(listed["sessions"][0]["id"], session_ids[2]);
+        assert_eq!(listed["sessions"][1]["id"], session_ids[1]);
+
+        let listed = manager
+            .list_sessions(ListSessionsArgs {
+                page: Some(2),
+                page_size: Some(2),
+                ..ListSessionsArgs::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(listed["sessions"][0]["id"], session_ids[0]);
+        assert_eq!(listed["hasMore"], false);
+
+        let tunnels = manager.list_tunnels(ListTunnelsArgs::default()).unwrap();
+        assert_eq!(tunnels["totalCount"], 0);
+        assert_eq!(tunnels["pageSize"], DEFAULT_PAGE_SIZE);
+
+        for session_id in session_ids {
+            manager
+                .close_session(CloseSessionArgs {
+                    session_id,
+                    timeout_ms: None,
+                })
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resource_report() {
+        let mut manager = SessionManager::new();
+        let created = manager
+            .create_session(CreateSessionArgs {
+                command: Some(vec!["echo report-marker; sleep 30".to_string()]),
+                ..CreateSessionArgs::default()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        manager
+            .handle(&session_id)
+            .unwrap()
+            .wait_for("report-marker", Duration::from_secs(5))
+            .await
+            .unwrap();
+
+        let report = manager
+            .resource_report(GetResourceReportArgs::default())
+            .unwrap();
+        let entry = &report["sessions"][0];
+        assert_eq!(entry["sessionId"], session_id);
+        assert!(entry["outputBytes"].as_u64().unwrap() >= "report-marker".len() as u64);
+        if cfg!(target_os = "linux") {
+            assert!(entry["memoryRssKb"].as_u64().unwrap() > 0);
+            assert!(entry["cpuPercent"].as_f64().is_some());
+        }
+        assert_eq!(report["totals"]["sessionCount"], 1);
+        assert_eq!(report["limits"]["maxCols"], 500);
+        assert_eq!(report["limits"]["bytesPerCell"], BYTES_PER_CELL);
+        assert_eq!(report["limits"]["maxGridBytes"], 500 * 200 * BYTES_PER_CELL);
+
+        // A second report within the cache window is the same sample
+        let cached = manager
+            .resource_report(GetResourceReportArgs::default())
+            .unwrap();
+        assert_eq!(cached["sessions"], report["sessions"]);
+
+        manager
+            .close_session(CloseSessionArgs {
+                session_id,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_assert_screen() {
+        let mut manager = SessionManager::new();
+        let created = manager
+            .create_session(CreateSessionArgs {
+                command: Some(vec!["echo build ok; sleep 30".to_string()]),
+                ..CreateSessionArgs::default()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        let handle = manager.handle(&session_id).unwrap();
+        handle
+            .wait_for("build ok", Duration::from_secs(5))
+            .await
+            .unwrap();
+
+        let assertions: Vec<Assertion> = serde_json::from_value(serde_json::json!([
+            {"lineEquals": {"line": 1, "text": "build ok"}},
+            {"sizeIs": {"cols": DEFAULT_COLS, "rows": DEFAULT_ROWS}},
+            {"notContains": "error"}
+        ]))
+        .unwrap();
+        let passed = manager
+            .assert_screen(AssertScreenArgs {
+                session_id: session_id.clone(),
+                assertions,
+                attach_snapshot_on_failure: None,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(passed["passed"], true, "{}", passed);
+        assert!(passed.get("snapshot").is_none());
+
+        // Failures are a result with the actual values, not an error
+        handle.resize(100, 30).await.unwrap();
+        let failed = manager
+            .assert_screen(AssertScreenArgs {
+                session_id: session_id.clone(),
+                assertions: vec![
+                    Assertion::Contains("build failed".to_string()),
+                    Assertion::SizeIs {
+                        cols: DEFAULT_COLS as usize,
+                        rows: DEFAULT_ROWS as usize,
+                    },
+                ],
+                attach_snapshot_on_failure: None,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(failed["passed"], false);
+        assert_eq!(failed["failures"], 2);
+        assert_eq!(
+            failed["results"][1]["actual"],
+            serde_json::json!({"cols": 100, "rows": 30})
+        );
+        assert!(failed["snapshot"].as_str().unwrap().contains("build ok"));
+
+        let err = manager
+            .assert_screen(AssertScreenArgs {
+                session_id: session_id.clone(),
+                assertions: vec![Assertion::Regex("(".to_string())],
+                attach_snapshot_on_failure: None,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::InvalidRequest(_)));
+
+        manager
+            .close_session(CloseSessionArgs {
+                session_id,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pipe_stdin() {
+        let mut manager = SessionManager::new();
+        let created = manager
+            .create_session(CreateSessionArgs {
+                command: Some(vec!["sort; echo sort-done; sleep 30".to_string()]),
+                ..CreateSessionArgs::default()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        let handle = manager.handle(&session_id).unwrap();
+
+        // Key names are data here, and the last line has no newline
+        let result = manager
+            .pipe_stdin(PipeStdinArgs {
+                session_id: session_id.clone(),
+                data: "pear\nEnter\napple".to_string(),
+                close_after: Some(true),
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(result["bytes"], 16);
+        assert_eq!(result["closed"], true);
+        handle
+            .wait_for("sort-done", Duration::from_secs(5))
+            .await
+            .unwrap();
+        let snapshot = manager.sessions[&session_id].snapshot().await.unwrap();
+        let lines: Vec<&str> = snapshot.lines().map(str::trim_end).collect();
+        assert!(
+            lines.join("\n").contains("Enter\npear\nsort-done"),
+            "{}",
+            snapshot
+        );
+
+        manager
+            .close_session(CloseSessionArgs {
+                session_id,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_close_stdin_ends_input() {
+        let mut manager = SessionManager::new();
+        let created = manager
+            .create_session(CreateSessionArgs {
+                command: Some(vec!["sort; echo sort-done; sleep 30".to_string()]),
+                ..CreateSessionArgs::default()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        let handle = manager.handle(&session_id).unwrap();
+
+        handle
+            .send_keys(&["pear", "Enter", "apple", "Enter"])
+            .await
+            .unwrap();
+        let result = manager
+            .close_stdin(CloseStdinArgs {
+                session_id: session_id.clone(),
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(result["success"], true);
+        handle
+            .wait_for("sort-done", Duration::from_secs(5))
+            .await
+            .unwrap();
+
+        manager
+            .close_session(CloseSessionArgs {
+                session_id,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_command_prefix_wraps_session_commands() {
+        let mut manager = SessionManager::with_config(HtMcpConfig {
+            command_prefix: Some(vec!["env".to_string(), "HT_PREFIXED=yes".to_string()]),
+            ..HtMcpConfig::default()
+        });
+        // Expanded behind the prefix, after env set the variable
+        let command = vec!["echo prefixed=$HT_PREFIXED; sleep 30".to_string()];
+        let created = manager
+            .create_session(CreateSessionArgs {
+                command: Some(command.clone()),
+                ..CreateSessionArgs::default()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        manager
+            .handle(&session_id)
+            .unwrap()
+            .wait_for("prefixed=yes", Duration::from_secs(5))
+            .await
+            .unwrap();
+
+        let summary = manager.sessions[&session_id].summary();
+        assert_eq!(summary["command"], serde_json::json!(command));
+        assert_eq!(
+            summary["effectiveCommand"],
+            serde_json::json!([
+                "env",
+                "HT_PREFIXED=yes",
+                "sh",
+                "-c",
+                "'echo prefixed=$HT_PREFIXED; sleep 30'"
+            ])
+        );
+
+        manager
+            .close_session(CloseSessionArgs {
+                session_id,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_session_output_is_recorded_and_accounted() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = SessionManager::with_config(HtMcpConfig {
+            storage: StorageConfig {
+                recordings_dir: Some(dir.path().to_path_buf()),
+                ..StorageConfig::default()
+            },
+            ..HtMcpConfig::default()
+        });
+        let created = manager
+            .create_session(CreateSessionArgs {
+                command: Some(vec![
+                    "sh".to_string(),
+                    "-c".to_string(),
+                    "'echo recorded-output; sleep 30'".to_string(),
+                ]),
+                ..CreateSessionArgs::default()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        let path = dir.path().join(format!("{}.cast", session_id));
+        let mut content = String::new();
+        for _ in 0..200 {
+            content = std::fs::read_to_string(&path).unwrap_or_default();
+            if content.contains("recorded-output") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(25)).await;
+        }
+        assert!(content.contains("recorded-output"), "{}", content);
+
+        let report = manager
+            .resource_report(GetResourceReportArgs::default())
+            .unwrap();
+        assert!(
+            report["storage"]["recordings"]["usedBytes"]
+                .as_u64()
+                .unwrap()
+                > 0
+        );
+
+        manager
+            .close_session(CloseSessionArgs {
+                session_id: session_id.clone(),
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        let recording = manager.storage.recording(&session_id).unwrap();
+        assert!(!recording.active);
+        assert!(recording.bytes >= content.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn test_cwd_resolves_against_workspace_root() {
+        let root = std::env::temp_dir().join(format!("ht-mcp-ws-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        let root = root.canonicalize().unwrap();
+        let mut manager = SessionManager::with_config(HtMcpConfig {
+            workspace_root: Some(root.clone()),
+            session_env: [("HT_TEST_VAR".to_string(), "from-config".to_string())].into(),
+            ..HtMcpConfig::default()
+        });
+
+        let created = manager
+            .create_session(CreateSessionArgs {
+                command: Some(vec!["echo \"in $(pwd) $HT_TEST_VAR\"; sleep 30".to_string()]),
+                cwd: Some("sub".to_string()),
+                ..CreateSessionArgs::default()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        let expected = format!("in {} from-config", root.join("sub").display());
+        manager
+            .handle(&session_id)
+            .unwrap()
+            .wait_for(&expected, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(
+            manager.sessions[&session_id].config.cwd,
+            Some(root.join("sub").display().to_string())
+        );
+        let listed = manager
+            .list_sessions(ListSessionsArgs::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            listed["sessions"][0]["cwd"],
+            root.join("sub").display().to_string()
+        );
+
+        let err = manager
+            .create_session(CreateSessionArgs {
+                cwd: Some("missing".to_string()),
+                ..CreateSessionArgs::default()
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::InvalidRequest(_)));
+
+        manager
+            .close_session(CloseSessionArgs {
+                session_id,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_session_env_overrides_config() {
+        let mut manager = SessionManager::with_config(HtMcpConfig {
+            session_env: [
+                ("HT_TEST_A".to_string(), "config-a".to_string()),
+                ("HT_TEST_B".to_string(), "config-b".to_string()),
+            ]
+            .into(),
+            ..HtMcpConfig::default()
+        });
+
+        let created = manager
+            .create_session(CreateSessionArgs {
+                command: Some(vec![
+                    "echo \"env $HT_TEST_A $HT_TEST_B\"; sleep 30".to_string()
+                ]),
+                env: Some([("HT_TEST_B".to_string(), "session-b".to_string())].into()),
+                ..CreateSessionArgs::default()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        manager
+            .handle(&session_id)
+            .unwrap()
+            .wait_for("env config-a session-b", Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(
+            manager.sessions[&session_id].config.env,
+            BTreeMap::from([("HT_TEST_B".to_string(), "session-b".to_string())])
+        );
+
+        for (name, value) in [("", "x"), ("A=B", "x"), ("A", "x\0y")] {
+            let err = manager
+                .create_session(CreateSessionArgs {
+                    env: Some([(name.to_string(), value.to_string())].into()),
+                    ..CreateSessionArgs::default()
+                })
+                .await
+                .unwrap_err();
+            assert!(matches!(err, HtMcpError::InvalidRequest(_)), "{}", err);
+        }
+
+        manager
+            .close_session(CloseSessionArgs {
+                session_id,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+    }
+
+    /// Log output collected by a test's subscriber
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl CapturedLogs {
+        /// The logged lines mentioning `text` in a PTY trace
+        fn traced(&self, text: &str) -> Vec<String> {
+            String::from_utf8_lossy(&self.0.lock().unwrap())
+                .lines()
+                .filter(|line| {
+                    (line.contains("PTY→MCP: ") || line.contains("PTY←MCP: "))
+                        && line.contains(text)
+                })
+                .map(str::to_string)
+                .collect()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_trace_mode_needs_config() {
+        // Event loops run on the test's thread, so they log here
+        let logs = CapturedLogs::default();
+        let writer = logs.clone();
+        let _subscriber = tracing::subscriber::set_default(
+            tracing_subscriber::fmt()
+                .with_max_level(tracing::Level::TRACE)
+                .with_ansi(false)
+                .with_writer(move || writer.clone())
+                .finish(),
+        );
+
+        let mut manager = SessionManager::new();
+        let trace_args = || CreateSessionArgs {
+            command: Some(vec!["echo traced; sleep 30".to_string()]),
+            trace_mode: Some(true),
+            ..CreateSessionArgs::default()
+        };
+        let err = manager.create_session(trace_args()).await.unwrap_err();
+        assert!(matches!(err, HtMcpError::InvalidRequest(_)));
+        assert!(manager.sessions.is_empty());
+
+        let mut manager = SessionManager::with_config(HtMcpConfig {
+            allow_trace_mode: true,
+            ..HtMcpConfig::default()
+        });
+        let created = manager.create_session(trace_args()).await.unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        assert!(manager.sessions[&session_id].config.trace_mode);
+        let handle = manager.handle(&session_id).unwrap();
+        handle
+            .wait_for("traced", Duration::from_secs(5))
+            .await
+            .unwrap();
+        // The terminal echoes what is typed, after the input was traced
+        handle.send_keys(&["typed-in"]).await.unwrap();
+        handle
+            .wait_for("typed-in", Duration::from_secs(5))
+            .await
+            .unwrap();
+
+        let output = logs.traced("traced");
+        assert!(
+            output.iter().any(|line| line.contains("PTY→MCP: ")),
+            "{:?}",
+            output
+        );
+        let input = logs.traced("typed-in");
+        assert!(
+            input
+                .iter()
+                .any(|line| line.contains("PTY←MCP: typed-in (8 bytes)")),
+            "{:?}",
+            input
+        );
+
+        // Sessions without traceMode log none of their traffic
+        let untraced = manager
+            .create_session(CreateSessionArgs {
+                command: Some(vec!["echo quiet-session; sleep 30".to_string()]),
+                ..CreateSessionArgs::default()
+            })
+            .await
+            .unwrap();
+        let untraced_id = untraced["sessionId"].as_str().unwrap().to_string();
+        manager
+            .handle(&untraced_id)
+            .unwrap()
+            .wait_for("quiet-session", Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(logs.traced("quiet-session"), Vec::<String>::new());
+
+        for session_id in [session_id, untraced_id] {
+            manager
+                .close_session(CloseSessionArgs {
+                    session_id,
+                    timeout_ms: None,
+                })
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_type_file_into_cat() {
+        let root = std::env::temp_dir().join(format!("ht-mcp-type-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&root).unwrap();
+        let root = root.canonicalize().unwrap();
+        let fixture: String = (1..=200)
+            .map(|n| format!("fixture line {:03}: the quick brown fox\n", n))
+            .collect();
+        assert!(fixture.len() > 4096);
+        std::fs::write(root.join("fixture.txt"), &fixture).unwrap();
+
+        let mut manager = SessionManager::with_config(HtMcpConfig {
+            workspace_root: Some(root.clone()),
+            type_file_paths: vec![PathBuf::from(".")],
+            ..HtMcpConfig::default()
+        });
+        // What cat receives is compared, since its echo and output interleave
+        let created = manager
+            .create_session(CreateSessionArgs {
+                command: Some(vec![
+                    "cat > typed.txt; echo typed-done; sleep 30".to_string()
+                ]),
+                ..CreateSessionArgs::default()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+
+        let (notifications, mut reported) = mpsc::unbounded_channel();
+        let progress = Progress::requested(
+            &serde_json::json!({"_meta": {"progressToken": 7}}),
+            &notifications,
+        );
+        let typed = manager
+            .prepare_type_file(TypeFileArgs {
+                session_id: session_id.clone(),
+                path: "fixture.txt".to_string(),
+                chunk_bytes: Some(512),
+                chunk_delay_ms: Some(1),
+                bracketed_paste: None,
+                timeout_ms: None,
+            })
+            .unwrap()
+            .send(progress)
+            .await
+            .unwrap();
+        let total = type_file::chunks(&fixture, 512, false).len();
+        assert_eq!(typed["bytes"], fixture.len());
+        assert_eq!(typed["lines"], 200);
+        assert_eq!(typed["chunks"], total);
+        // One notification per chunk, counting up to the total
+        for done in 1..=total {
+            let params = &reported.try_recv().unwrap()["params"];
+            assert_eq!(params["progressToken"], 7);
+            assert_eq!(params["progress"], done);
+            assert_eq!(params["total"], total);
+        }
+        assert!(reported.try_recv().is_err());
+
+        manager
+            .close_stdin(CloseStdinArgs {
+                session_id: session_id.clone(),
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        let screen = manager
+            .handle(&session_id)
+            .unwrap()
+            .wait_for("typed-done", Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert!(screen.contains("fixture line 200"));
+        assert_eq!(
+            std::fs::read_to_string(root.join("typed.txt")).unwrap(),
+            fixture
+        );
+
+        let err = manager
+            .prepare_type_file(TypeFileArgs {
+                session_id: session_id.clone(),
+                path: "/etc/hostname".to_string(),
+                chunk_bytes: None,
+                chunk_delay_ms: None,
+                bracketed_paste: None,
+                timeout_ms: None,
+            })
+            .err()
+            .unwrap();
+        assert!(matches!(err, HtMcpError::InvalidRequest(_)));
+
+        manager
+            .close_session(CloseSessionArgs {
+                session_id,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_snapshot_limit() {
+        let mut manager = SessionManager::with_config(HtMcpConfig {
+            concurrent_snapshots_per_session: 1,
+            ..HtMcpConfig::default()
+        });
+        let created = manager
+            .create_session(CreateSessionArgs {
+                enable_web_server: Some(false),
+                ..web_session_args()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        let args = || TakeSnapshotArgs {
+            session_id: session_id.clone(),
+            format: None,
+            trim_trailing_blank_lines: None,
+            annotate: None,
+            annotate_patterns: None,
+            word_wrap: None,
+            probe_only: None,
+            max_tokens: None,
+            include_provenance: None,
+            timeout_ms: None,
+        };
+
+        // The only slot is taken, as by a request the event loop is serving
+        let permits = manager.sessions[&session_id].snapshot_permits.clone();
+        let held = permits.acquire().await.unwrap();
+        let err = manager.take_snapshot(args()).await.unwrap_err();
+        assert!(matches!(err, HtMcpError::ResourceExhausted(_)));
+        assert_eq!(err.code(), "resource_exhausted");
+        // Requests made without the manager, as by a handle or a command
+        // waiting for its prompt, count too
+        let err = manager
+            .handle(&session_id)
+            .unwrap()
+            .snapshot()
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::ResourceExhausted(_)));
+
+        drop(held);
+        manager.take_snapshot(args()).await.unwrap();
+        let results = join_all((0..10).map(|_| manager.take_snapshot(args()))).await;
+        assert!(results.iter().all(Result::is_ok));
+        assert_eq!(permits.available_permits(), 1);
+
+        manager
+            .close_session(CloseSessionArgs {
+                session_id,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_retention_policy() {
+        let mut manager = SessionManager::with_config(HtMcpConfig {
+            retention: RetentionPolicy {
+                snapshot_history: 10,
+                command_history: 5,
+            },
+            ..HtMcpConfig::default()
+        });
+        let args = |command_history| CreateSessionArgs {
+            enable_web_server: Some(false),
+            snapshot_interval_ms: Some(100),
+            retention: Some(RetentionOverrides {
+                command_history: Some(command_history),
+                ..RetentionOverrides::default()
+            }),
+            ..web_session_args()
+        };
+        // Sessions cannot keep more than the server allows
+        let err = manager.create_session(args(6)).await.unwrap_err();
+        assert!(matches!(err, HtMcpError::InvalidRequest(_)));
+
+        let created = manager.create_session(args(3)).await.unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        for n in 1..=5 {
+            manager.record_history(&session_id, &format!("echo {}", n));
+        }
+        assert_eq!(
+            manager.sessions[&session_id].history,
+            ["echo 3", "echo 4", "echo 5"]
+        );
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let listed = manager
+            .list_sessions(ListSessionsArgs::default())
+            .await
+            .unwrap();
+        let retention = &listed["sessions"][0]["retention"];
+        assert_eq!(
+            retention["commandHistory"],
+            serde_json::json!({"limit": 3, "stored": 3})
+        );
+        assert_eq!(retention["snapshotHistory"]["limit"], 10);
+        assert!(retention["snapshotHistory"]["stored"].as_u64().unwrap() >= 2);
+
+        let set = |snapshot_history, command_history| SetRetentionArgs {
+            session_id: session_id.clone(),
+            retention: RetentionOverrides {
+                snapshot_history,
+                command_history,
+            },
+            timeout_ms: None,
+        };
+        let result = manager.set_retention(set(Some(1), Some(1))).unwrap();
+        assert_eq!(
+            result["retention"],
+            serde_json::json!({
+                "snapshotHistory": {"limit": 1, "stored": 1},
+                "commandHistory": {"limit": 1, "stored": 1}
+            })
+        );
+        assert_eq!(manager.sessions[&session_id].history, ["echo 5"]);
+
+        // Raising a limit again is allowed up to the server's
+        let result = manager.set_retention(set(None, Some(5))).unwrap();
+        assert_eq!(result["retention"]["commandHistory"]["limit"], 5);
+        assert_eq!(result["retention"]["snapshotHistory"]["limit"], 1);
+        let err = manager.set_retention(set(Some(11), None)).unwrap_err();
+        assert!(matches!(err, HtMcpError::InvalidRequest(_)));
+
+        manager
+            .close_session(CloseSessionArgs {
+                session_id,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_compare_to_baseline() {
+        let dir = std::env::temp_dir().join(format!("ht-mcp-baselines-{}", Uuid::new_v4()));
+        let mut manager = SessionManager::with_config(HtMcpConfig {
+            baseline_dir: Some(dir.clone()),
+            ..HtMcpConfig::default()
+        });
+        let created = manager
+            .create_session(CreateSessionArgs {
+                command: Some(vec!["echo baseline-ready; sleep 30".to_string()]),
+                ..CreateSessionArgs::default()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        manager
+            .handle(&session_id)
+            .unwrap()
+            .wait_for("baseline-ready", Duration::from_secs(5))
+            .await
+            .unwrap();
+
+        let baseline_args = || BaselineSnapshotArgs {
+            session_id: session_id.clone(),
+            baseline_id: "ready".to_string(),
+            timeout_ms: None,
+        };
+        let compare_args = |baseline_id: &str| CompareSnapshotArgs {
+            session_id: session_id.clone(),
+            baseline_id: baseline_id.to_string(),
+            timeout_ms: None,
+        };
+        let saved = manager
+            .take_baseline_snapshot(baseline_args())
+            .await
+            .unwrap();
+        assert_eq!(
+            saved["path"],
+            dir.join(&session_id)
+                .join("ready.txt")
+                .display()
+                .to_string()
+        );
+        let compared = manager
+            .compare_to_baseline(compare_args("ready"))
+            .await
+            .unwrap();
+        assert_eq!(compared["matches"], true);
+        assert_eq!(compared["diff"], serde_json::json!([]));
+
+        // Typed text is echoed by the terminal, changing the screen
+        let handle = manager.handle(&session_id).unwrap();
+        handle.send_keys(&["changed-screen"]).await.unwrap();
+        handle
+            .wait_for("changed-screen", Duration::from_secs(5))
+            .await
+            .unwrap();
+        let compared = manager
+            .compare_to_baseline(compare_args("ready"))
+            .await
+            .unwrap();
+        assert_eq!(compared["matches"], false);
+        let diff = compared["diff"].as_array().unwrap();
+        assert!(diff.iter().any(|d| d["actual"]
+            .as_str()
+            .unwrap_or("")
+            .contains("changed-screen")));
+
+        let err = manager
+            .compare_to_baseline(compare_args("never-taken"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::InvalidRequest(_)));
+
+        manager
+            .close_session(CloseSessionArgs {
+                session_id,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_warm_pool() {
+        let mut manager = SessionManager::with_config(HtMcpConfig {
+            warm_pool: WarmPoolConfig {
+                size: 1,
+                init_commands: vec!["echo pooled-$((40 + 2))".to_string()],
+                ..WarmPoolConfig::default()
+            },
+            ..HtMcpConfig::default()
+        });
+        let started = manager.prepare_warm_pool_refill().unwrap().run().await;
+        manager.finish_warm_pool_refill(started);
+        assert!(manager.sessions.is_empty());
+        assert_eq!(manager.warm_pool_summary()["idle"], 1);
+
+        // Adoption: labels applied, init commands run
+        let adopted = manager
+            .create_session(CreateSessionArgs {
+                label_color: Some("#00ff00".to_string()),
+                ..CreateSessionArgs::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(adopted["fromPool"], true);
+        let adopted_id = adopted["sessionId"].as_str().unwrap().to_string();
+        assert_eq!(
+            manager.sessions[&adopted_id].config.label_color.as_deref(),
+            Some("#00ff00")
+        );
+        manager
+            .handle(&adopted_id)
+            .unwrap()
+            .wait_for("pooled-42", Duration::from_secs(5))
+            .await
+            .unwrap();
+
+        // Empty pool: cold creation; other commands never use the pool
+        let cold = manager
+            .create_session(CreateSessionArgs::default())
+            .await
+            .unwrap();
+        assert_eq!(cold["fromPool"], false);
+        let other = manager
+            .create_session(CreateSessionArgs {
+                command: Some(vec!["sh".to_string()]),
+                ..CreateSessionArgs::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(other["fromPool"], false);
+        assert_eq!(
+            manager.warm_pool_summary(),
+            serde_json::json!({"target": 1, "idle": 0, "hits": 1, "misses": 1})
+        );
+
+        // Replenishment, then the pool is emptied when draining
+        let refill = manager.prepare_warm_pool_refill().unwrap();
+        assert_eq!(refill.count, 1);
+        let started = refill.run().await;
+        manager.finish_warm_pool_refill(started);
+        assert_eq!(manager.warm_pool_summary()["idle"], 1);
+        assert!(manager.prepare_warm_pool_refill().is_none());
+        manager.start_drain(Duration::from_secs(60), false).unwrap();
+        assert_eq!(manager.warm_pool_summary()["idle"], 0);
+        assert!(manager.prepare_warm_pool_refill().is_none());
+
+        // Sessions started for the pool once draining are dropped instead
+        let late = manager
+            .spawner()
+            .spawn_session(&CreateSessionArgs::default(), None)
+            .await
+            .unwrap();
+        manager.finish_warm_pool_refill(vec![late]);
+        assert_eq!(manager.warm_pool_summary()["idle"], 0);
+
+        for created in [adopted, cold, other] {
+            manager
+                .close_session(CloseSessionArgs {
+                    session_id: created["sessionId"].as_str().unwrap().to_string(),
+                    timeout_ms: None,
+                })
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_session_metrics_history() {
+        let mut manager = SessionManager::new();
+        let created = manager
+            .create_session(CreateSessionArgs::default())
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        let args = || GetSessionMetricsHistoryArgs {
+            session_id: session_id.clone(),
+            timeout_ms: None,
+        };
+
+        // The first sample is taken as the session starts
+        let mut result = manager.get_session_metrics_history(args()).unwrap();
+        for _ in 0..100 {
+            if !result["history"].as_array().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            result = manager.get_session_metrics_history(args()).unwrap();
+        }
+        assert_eq!(result["intervalSecs"], 30);
+        let history = result["history"].as_array().unwrap();
+        assert_eq!(history.len(), 1);
+        for field in ["timestamp", "snapshotCount", "inputBytes", "outputBytes"] {
+            assert!(!history[0][field].is_null(), "{}", field);
+        }
+        // Null when the process could not be sampled, as right after exec
+        assert!(history[0].get("memoryRssKb").is_some());
+
+        let err = manager
+            .get_session_metrics_history(GetSessionMetricsHistoryArgs {
+                session_id: "missing".to_string(),
+                timeout_ms: None,
+            })
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::SessionNotFound(_)));
+
+        manager
+            .close_session(CloseSessionArgs {
+                session_id,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_export_and_import_state() {
+        let dir = std::env::temp_dir().join(format!("ht-mcp-state-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(dir.join("old")).unwrap();
+        std::fs::write(dir.join("old/s.cast"), "recorded").unwrap();
+        let state_path = dir.join("state.tar.gz");
+        let written = |path: &Path| serde_json::to_string(&state::read(path).unwrap()).unwrap();
+
+        let mut source = SessionManager::new();
+        let mut source_ids = Vec::new();
+        for marker in ["first-state-marker", "second-state-marker"] {
+            let created = source
+                .create_session(CreateSessionArgs {
+                    env: Some(HashMap::from([(
+                        "API_TOKEN".to_string(),
+                        "state-secret".to_string(),
+                    )])),
+                    ..CreateSessionArgs::default()
+                })
+                .await
+                .unwrap();
+            let session_id = created["sessionId"].as_str().unwrap().to_string();
+            source
+                .execute_command(ExecuteCommandArgs {
+                    session_id: session_id.clone(),
+                    command: format!("echo {}", marker),
+                    terminator: None,
+                    append_terminator: None,
+                    annotate: None,
+                    annotate_patterns: None,
+                    summarize: None,
+                    wait_for_completion: None,
+                    completion_timeout_ms: None,
+                    quiet_period_ms: None,
+                    command_class: None,
+                    timeout_ms: None,
+                })
+                .await
+                .unwrap();
+            source_ids.push(session_id);
+        }
+        source
+            .storage
+            .track_recording(&source_ids[0], dir.join("old/s.cast"));
+
+        let exported = source
+            .export_state(ExportStateArgs {
+                path: state_path.display().to_string(),
+                include_env: None,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(exported["envIncluded"], false);
+        // Only the variable's name is written
+        let manifest = written(&state_path);
+        assert!(manifest.contains("API_TOKEN"));
+        assert!(!manifest.contains("state-secret"));
+        assert_eq!(exported["sessions"], 2);
+        assert_eq!(exported["recordings"], 1);
+        assert_eq!(exported["recordingFiles"], 1);
+        assert_eq!(exported["formatVersion"], state::STATE_FORMAT_VERSION);
+
+        let mut target = SessionManager::with_config(HtMcpConfig {
+            storage: StorageConfig {
+                recordings_dir: Some(dir.join("new")),
+                ..StorageConfig::default()
+            },
+            ..HtMcpConfig::default()
+        });
+        // Recording files are only written under recordings_dir
+        let err = target
+            .import_state(ImportStateArgs {
+                path: state_path.display().to_string(),
+                recordings_remap: Some(state::PathRemap {
+                    from: dir.join("old"),
+                    to: dir.join("new/../elsewhere"),
+                }),
+                timeout_ms: None,
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::InvalidRequest(_)));
+        let imported = target
+            .import_state(ImportStateArgs {
+                path: state_path.display().to_string(),
+                recordings_remap: Some(state::PathRemap {
+                    from: dir.join("old"),
+                    to: dir.join("new"),
+                }),
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        let warnings = imported["warnings"].as_array().unwrap();
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings.iter().all(|warning| warning
+            .as_str()
+            .unwrap()
+            .contains("without its env variables API_TOKEN")));
+        assert_eq!(imported["recordings"], 1);
+        // The recording file came along, under the remapped directory
+        assert_eq!(imported["recordingFiles"], 1);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("new/s.cast")).unwrap(),
+            "recorded"
+        );
+        let sessions = imported["sessions"].as_array().unwrap();
+        assert_eq!(sessions.len(), 2);
+        for entry in sessions {
+            let source_id = entry["sourceSessionId"].as_str().unwrap();
+            let marker = if source_id == source_ids[0] {
+                "first-state-marker"
+            } else {
+                assert_eq!(source_id, source_ids[1]);
+                "second-state-marker"
+            };
+            let session = &target.sessions[entry["sessionId"].as_str().unwrap()];
+            assert_eq!(session.command, vec!["bash"]);
+            assert!(session.config.env.is_empty());
+            assert!(session.history.contains(&format!("echo {}", marker)));
+        }
+        // The imported sessions record under recordings_dir too
+        let recording = target
+            .storage
+            .recordings()
+            .find(|recording| recording.session_id == source_ids[0])
+            .unwrap();
+        assert_eq!(recording.path.as_deref(), Some(&*dir.join("new/s.cast")));
+        assert!(!recording.active);
+
+        // Values are written when asked for
+        source
+            .export_state(ExportStateArgs {
+                path: state_path.display().to_string(),
+                include_env: Some(true),
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        assert!(written(&state_path).contains("state-secret"));
+
+        // A file from a newer format is refused before anything changes
+        let mut manifest = state::read(&state_path).unwrap();
+        manifest.format_version = state::STATE_FORMAT_VERSION + 1;
+        state::write(&state_path, &manifest).unwrap();
+        let err = target
+            .import_state(ImportStateArgs {
+                path: state_path.display().to_string(),
+                recordings_remap: None,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::InvalidRequest(_)));
+        assert_eq!(target.sessions.len(), 2);
+
+        for manager in [&mut source, &mut target] {
+            let ids: Vec<String> = manager.sessions.keys().cloned().collect();
+            for session_id in ids {
+                manager
+                    .close_session(CloseSessionArgs {
+                        session_id,
+                        timeout_ms: None,
+                    })
+                    .await
+                    .unwrap();
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_color_profile_sets_term() {
+        let mut manager = SessionManager::new();
+        let table = [
+            (None, "term=xterm-256color colorterm="),
+            (Some(ColorProfile::Basic), "term=xterm colorterm="),
+            (
+                Some(ColorProfile::Color256),
+                "term=xterm-256color colorterm=",
+            ),
+            (
+                Some(ColorProfile::TrueColor),
+                "term=xterm-256color colorterm=truecolor",
+            ),
+        ];
+        for (color_profile, expected) in table {
+            let created = manager
+                .create_session(CreateSessionArgs {
+                    command: Some(vec![
+                        "echo \"term=$TERM colorterm=$COLORTERM\"; sleep 30".to_string()
+                    ]),
+                    color_profile,
+                    ..CreateSessionArgs::default()
+                })
+                .await
+                .unwrap();
+            let session_id = created["sessionId"].as_str().unwrap().to_string();
+            manager
+                .handle(&session_id)
+                .unwrap()
+                .wait_for(expected, Duration::from_secs(5))
+                .await
+                .unwrap();
+            manager
+                .close_session(CloseSessionArgs {
+                    session_id,
+                    timeout_ms: None,
+                })
+                .await
+                .unwrap();
+        }
+
+        let args: CreateSessionArgs =
+            serde_json::from_value(serde_json::json!({"colorProfile": "truecolor"})).unwrap();
+        assert_eq!(args.color_profile, Some(ColorProfile::TrueColor));
+        assert!(serde_json::from_value::<CreateSessionArgs>(
+            serde_json::json!({"colorProfile": "16"})
+        )
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_memory_limit_without_cgroups_warns() {
+        let mut manager = SessionManager::new();
+        let created = manager
+            .create_session(CreateSessionArgs {
+                max_memory_mb: Some(64),
+                ..CreateSessionArgs::default()
+            })
+            .await
+            .unwrap();
+        let warnings = created["warnings"].as_array().unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0]
+            .as_str()
+            .unwrap()
+            .contains("use_cgroup_memory_limits"));
+
+        manager
+            .close_session(CloseSessionArgs {
+                session_id: created["sessionId"].as_str().unwrap().to_string(),
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cpu_affinity() {
+        let mut manager = SessionManager::new();
+        let err = manager
+            .create_session(CreateSessionArgs {
+                cpu_affinity: Some(vec![cpu_affinity::core_count()]),
+                ..CreateSessionArgs::default()
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::InvalidRequest(_)), "{}", err);
+        assert!(manager.sessions.is_empty());
+
+        // A core this process may use, in case the tests are pinned
+        #[cfg(target_os = "linux")]
+        let core = cpu_affinity::current(std::process::id()).unwrap()[0];
+        #[cfg(not(target_os = "linux"))]
+        let core = 0;
+        let created = manager
+            .create_session(CreateSessionArgs {
+                cpu_affinity: Some(vec![core]),
+                ..CreateSessionArgs::default()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        let session = &manager.sessions[&session_id];
+        assert_eq!(session.config.cpu_affinity, Some(vec![core]));
+        #[cfg(target_os = "linux")]
+        {
+            assert!(created.get("warnings").is_none(), "{}", created);
+            let pid = session.child.pid.unwrap();
+            assert_eq!(cpu_affinity::current(pid).unwrap(), [core]);
+        }
+        #[cfg(not(target_os = "linux"))]
+        assert_eq!(created["warnings"].as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_download_history() {
+        let mut manager = SessionManager::with_config(HtMcpConfig {
+            management_address: Some("127.0.0.1:3617".parse().unwrap()),
+            ..HtMcpConfig::default()
+        });
+        let created = manager
+            .create_session(CreateSessionArgs {
+                snapshot_interval_ms: Some(100),
+                ..CreateSessionArgs::default()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        for n in 0..3 {
+            manager
+                .execute_command(ExecuteCommandArgs {
+                    session_id: session_id.clone(),
+                    command: format!("echo download-{}", n),
+                    terminator: None,
+                    append_terminator: None,
+                    annotate: None,
+                    annotate_patterns: None,
+                    summarize: None,
+                    // A second per command for the periodic snapshots
+                    wait_for_completion: Some(false),
+                    completion_timeout_ms: None,
+                    quiet_period_ms: None,
+                    command_class: None,
+                    timeout_ms: None,
+                })
+                .await
+                .unwrap();
+        }
+        let args = |token: Option<&str>, cursor: Option<usize>| DownloadHistoryArgs {
+            session_id: session_id.clone(),
+            format: Some(HistoryFormat::Jsonl),
+            chunk_bytes: Some(history_download::MIN_CHUNK_BYTES * 8),
+            token: token.map(str::to_string),
+            cursor,
+            via_url: None,
+            timeout_ms: None,
+        };
+
+        let first = manager.download_history(args(None, None)).unwrap();
+        let token = first["token"].as_str().unwrap().to_string();
+        let total_bytes = first["totalBytes"].as_u64().unwrap() as usize;
+        assert!(first["next"].is_u64());
+
+        // Retention drops most of the history while the download is running
+        manager
+            .set_retention(SetRetentionArgs {
+                session_id: session_id.clone(),
+                retention: RetentionOverrides {
+                    snapshot_history: Some(1),
+                    command_history: Some(1),
+                },
+                timeout_ms: None,
+            })
+            .unwrap();
+
+        let mut content = first["data"].as_str().unwrap().to_string();
+        let mut cursor = first["next"].as_u64().map(|next| next as usize);
+        let mut resumed = false;
+        while let Some(offset) = cursor {
+            let chunk = manager
+                .download_history(args(Some(&token), Some(offset)))
+                .unwrap();
+            if !resumed {
+                // Lost on the way: the same cursor gives the same chunk
+                resumed = true;
+                let again = manager
+                    .download_history(args(Some(&token), Some(offset)))
+                    .unwrap();
+                assert_eq!(again, chunk);
+            }
+            assert_eq!(chunk["offset"].as_u64().unwrap() as usize, content.len());
+            content.push_str(chunk["data"].as_str().unwrap());
+            cursor = chunk["next"].as_u64().map(|next| next as usize);
+        }
+        assert_eq!(content.len(), total_bytes);
+        assert_eq!(
+            first["totalCrc32"],
+            format!("{:08x}", history_download::crc32(content.as_bytes()))
+        );
+        let lines: Vec<serde_json::Value> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let commands: Vec<&str> = lines
+            .iter()
+            .filter_map(|line| line["command"].as_str())
+            .collect();
+        assert_eq!(
+            commands,
+            ["echo download-0", "echo download-1", "echo download-2"]
+        );
+        assert!(
+            lines
+                .iter()
+                .filter(|line| line["type"] == "snapshot")
+                .count()
+                > 1
+        );
+
+        // A download started now sees the trimmed history
+        let trimmed = manager.download_history(args(None, None)).unwrap();
+        assert!((trimmed["totalBytes"].as_u64().unwrap() as usize) < total_bytes);
+
+        let via_url = manager
+            .download_history(DownloadHistoryArgs {
+                via_url: Some(true),
+                ..args(None, None)
+            })
+            .unwrap();
+        let url_token = via_url["token"].as_str().unwrap();
+        assert_eq!(
+            via_url["url"],
+            format!("http://127.0.0.1:3617/downloads/{}", url_token)
+        );
+        assert!(manager.downloads().take(url_token).is_some());
+
+        for chunk_bytes in [history_download::MIN_CHUNK_BYTES - 1, usize::MAX] {
+            let err = manager
+                .download_history(DownloadHistoryArgs {
+                    chunk_bytes: Some(chunk_bytes),
+                    ..args(None, None)
+                })
+                .unwrap_err();
+            assert!(matches!(err, HtMcpError::InvalidRequest(_)));
+        }
+
+        // Closing the session ends its downloads
+        manager
+            .close_session(CloseSessionArgs {
+                session_id: session_id.clone(),
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        assert!(manager.downloads().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_slow_startup_is_measured() {
+        let mut manager = SessionManager::with_config(HtMcpConfig {
+            slow_startup_warning_ms: 1_000,
+            ..HtMcpConfig::default()
+        });
+        let mut events = manager.events().subscribe();
+        let created = manager
+            .create_session(CreateSessionArgs {
+                command: Some(vec!["bash -c 'sleep 2; exec bash'".to_string()]),
+                auto_detect_prompt: Some(true),
+                ..CreateSessionArgs::default()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        let first_output_ms = created["firstOutputMs"].as_u64().unwrap();
+        let prompt_ready_ms = created["promptReadyMs"].as_u64().unwrap();
+        assert!(first_output_ms >= 2_000, "{}", first_output_ms);
+        assert!(prompt_ready_ms >= first_output_ms);
+
+        let mut phases = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            if let SessionEvent::SlowStartup {
+                session_id: id,
+                phase,
+                threshold_ms,
+                ..
+            } = event
+            {
+                assert_eq!((id.as_str(), threshold_ms), (session_id.as_str(), 1_000));
+                phases.push(phase);
+            }
+        }
+        assert_eq!(
+            phases,
+            [StartupPhase::FirstOutput, StartupPhase::PromptReady]
+        );
+
+        let listed = manager
+            .list_sessions(ListSessionsArgs::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            listed["sessions"][0]["startup"],
+            serde_json::json!({
+                "firstOutputMs": first_output_ms,
+                "promptReadyMs": prompt_ready_ms
+            })
+        );
+        let summary = manager.startup_summary();
+        assert_eq!(summary["firstOutput"]["samples"], 1);
+        assert_eq!(summary["promptReady"]["p50Ms"], prompt_ready_ms);
+
+        manager
+            .close_session(CloseSessionArgs {
+                session_id,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reserved_port_is_used_by_preferred_port() {
+        let mut manager = SessionManager::new();
+        let reserved = manager
+            .reserve_port(ReservePortArgs {
+                ttl_ms: None,
+                timeout_ms: None,
+            })
+            .unwrap();
+        let port = reserved["port"].as_u64().unwrap() as u16;
+        assert_eq!(reserved["expiresInMs"], 30_000);
+        // Other sessions are not offered the reserved port
+        assert_ne!(manager.find_available_port().await.unwrap(), port);
+
+        let created = manager
+            .create_session(CreateSessionArgs {
+                preferred_port: Some(port),
+                ..web_session_args()
+            })
+            .await
+            .unwrap();
+        let url = created["webServerUrl"].as_str().unwrap();
+        assert!(url.ends_with(&format!(":{}", port)), "{}", url);
+        assert!(!manager.reserved_ports.contains(port));
+
+        for ttl_ms in [0, 600_001] {
+            let err = manager
+                .reserve_port(ReservePortArgs {
+                    ttl_ms: Some(ttl_ms),
+                    timeout_ms: None,
+                })
+                .unwrap_err();
+            assert!(matches!(err, HtMcpError::InvalidRequest(_)));
+        }
+        let err = manager
+            .create_session(CreateSessionArgs {
+                preferred_port: Some(port),
+                ..CreateSessionArgs::default()
+            })
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("enableWebServer"), "{}", err);
+
+        manager
+            .close_session(CloseSessionArgs {
+                session_id: created["sessionId"].as_str().unwrap().to_string(),
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_resize_reaches_the_pty() {
+        let mut manager = SessionManager::new();
+        let created = manager
+            .create_session(CreateSessionArgs::default())
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        let resize = |cols, rows| ResizeArgs {
+            session_id: session_id.clone(),
+            cols,
+            rows,
+            timeout_ms: None,
+        };
+
+        let resized = manager.resize_session(resize(60, 20)).await.unwrap();
+        assert_eq!(
+            resized["oldSize"],
+            serde_json::json!({"cols": 120, "rows": 40})
+        );
+        assert_eq!(
+            resized["newSize"],
+            serde_json::json!({"cols": 60, "rows": 20})
+        );
+
+        manager
+            .send_keys(SendKeysArgs {
+                session_id: session_id.clone(),
+                keys: vec!["echo size=$(stty size)".to_string(), "Enter".to_string()],
+                input_profile: None,
+                seed: None,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        let screen = manager
+            .handle(&session_id)
+            .unwrap()
+            .wait_for("size=20 60", Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert!(screen.lines().all(|line| line.chars().count() <= 60));
+
+        for (cols, rows) in [(0, 20), (60, MAX_TERMINAL_SIZE + 1)] {
+            let err = manager
+                .resize_session(resize(cols, rows))
+                .await
+                .unwrap_err();
+            assert!(matches!(err, HtMcpError::InvalidRequest(_)));
+        }
+
+        manager
+            .close_session(CloseSessionArgs {
+                session_id,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_large_terminal() {
+        let mut manager = SessionManager::new();
+        let created = manager
+            .create_session(CreateSessionArgs {
+                cols: Some(400),
+                rows: Some(150),
+                ..CreateSessionArgs::default()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        let info_args = || GetSessionInfoArgs {
+            session_id: session_id.clone(),
+            timeout_ms: None,
+        };
+
+        manager
+            .send_keys(SendKeysArgs {
+                session_id: session_id.clone(),
+                keys: vec![
+                    "printf '%0400d\\n' $(seq 1 200); echo filled-$((1+1))".to_string(),
+                    "Enter".to_string(),
+                ],
+                input_profile: None,
+                seed: None,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        manager
+            .handle(&session_id)
+            .unwrap()
+            .wait_for("filled-2", Duration::from_secs(10))
+            .await
+            .unwrap();
+        let snapshot = manager
+            .take_snapshot(TakeSnapshotArgs {
+                session_id: session_id.clone(),
+                format: None,
+                trim_trailing_blank_lines: None,
+                annotate: None,
+                annotate_patterns: None,
+                word_wrap: None,
+                probe_only: None,
+                max_tokens: None,
+                include_provenance: None,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        let text = snapshot["snapshot"].as_str().unwrap();
+        let last = format!("{:0400}", 200);
+        assert!(text.lines().any(|line| line.trim_end() == last));
+        assert!(text.lines().all(|line| line.chars().count() <= 400));
+
+        // Shrinking and growing back keeps the session usable
+        for (cols, rows) in [(80, 24), (400, 150)] {
+            manager
+                .resize_session(ResizeArgs {
+                    session_id: session_id.clone(),
+                    cols,
+                    rows,
+                    timeout_ms: None,
+                })
+                .await
+                .unwrap();
+            let info = manager.get_session_info(info_args()).await.unwrap();
+            assert_eq!(
+                (info["cols"].clone(), info["rows"].clone()),
+                (serde_json::json!(cols), serde_json::json!(rows))
+            );
+        }
+
+        // Past the configured limits in either tool
+        let err = manager
+            .resize_session(ResizeArgs {
+                session_id: session_id.clone(),
+                cols: 501,
+                rows: 150,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("max_cols"), "{}", err);
+        let err = manager
+            .create_session(CreateSessionArgs {
+                rows: Some(201),
+                ..CreateSessionArgs::default()
+            })
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("max_rows"), "{}", err);
+
+        // Limits that skipped config validation are clamped
+        let unchecked = SessionManager::with_config(HtMcpConfig {
+            max_cols: 70_000,
+            max_rows: 0,
+            ..HtMcpConfig::default()
+        });
+        assert_eq!(
+            (unchecked.config.max_cols, unchecked.config.max_rows),
+            (MAX_TERMINAL_SIZE, usize::from(DEFAULT_ROWS))
+        );
+
+        manager
+            .close_session(CloseSessionArgs {
+                session_id,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_input_profile() {
+        let mut manager = SessionManager::new();
+        let created = manager
+            .create_session(CreateSessionArgs {
+                input_profile: Some(InputProfile::FastHuman),
+                ..CreateSessionArgs::default()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        let listed = manager.list_sessions(Default::default()).await.unwrap();
+        assert_eq!(listed["sessions"][0]["inputProfile"], "fast-human");
+
+        let keys = |input_profile| SendKeysArgs {
+            session_id: session_id.clone(),
+            keys: vec!["echo typed-$((6*7))".to_string(), "Enter".to_string()],
+            input_profile,
+            seed: Some(3),
+            timeout_ms: None,
+        };
+        let sent = manager.send_keys(keys(None)).await.unwrap();
+        assert_eq!(sent["inputProfile"], "fast-human");
+        assert_eq!(sent["seed"], 3);
+        // 17 keystrokes, the 16 after the first at least 20 ms apart
+        assert!(sent["typedMs"].as_u64().unwrap() >= 320, "{}", sent);
+        manager
+            .handle(&session_id)
+            .unwrap()
+            .wait_for("typed-42", Duration::from_secs(5))
+            .await
+            .unwrap();
+
+        let sent = manager
+            .send_keys(keys(Some(InputProfile::Instant)))
+            .await
+            .unwrap();
+        assert!(sent.get("seed").is_none(), "{}", sent);
+
+        // The same seed gives the same plan
+        let plan = |manager: &SessionManager| {
+            manager
+                .prepare_keys(keys(None))
+                .unwrap()
+                .keystrokes
+                .into_iter()
+                .map(|(delay, _)| delay)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(plan(&manager), plan(&manager));
+
+        // Paced input dropped halfway is still typed to the end
+        let pending = manager.prepare_keys(keys(None)).unwrap();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), pending.send())
+                .await
+                .is_err()
+        );
+        // Meanwhile a caller holding the manager does not wait for it
+        let err = manager
+            .send_keys(keys(Some(InputProfile::Instant)))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::ResourceExhausted(_)), "{}", err);
+        manager
+            .handle(&session_id)
+            .unwrap()
+            // Once for each of the three sends that went through
+            .wait_for("(?s)(typed-42.*){3}", Duration::from_secs(5))
+            .await
+            .unwrap();
+
+        manager
+            .close_session(CloseSessionArgs {
+                session_id,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_export_dockerfile() {
+        let root = std::env::temp_dir().join(format!("ht-mcp-dockerfile-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("sub").join("Cargo.toml"), "").unwrap();
+        let root = root.canonicalize().unwrap();
+        let mut manager = SessionManager::with_config(HtMcpConfig {
+            session_env: [("API_TOKEN".to_string(), "secret".to_string())].into(),
+            ..HtMcpConfig::default()
+        });
+        let created = manager
+            .create_session(CreateSessionArgs {
+                cwd: Some(root.display().to_string()),
+                color_profile: Some(ColorProfile::Color256),
+                ..CreateSessionArgs::default()
+            })
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        let session = manager.sessions.get_mut(&session_id).unwrap();
+        session
+            .history
+            .push("sudo apt-get install -y jq".to_string());
+        session.history.push("git status".to_string());
+
+        #[cfg(target_os = "linux")]
+        {
+            manager
+                .send_keys(SendKeysArgs {
+                    session_id: session_id.clone(),
+                    keys: vec![
+                        "cd sub && echo moved-$((1+1))".to_string(),
+                        "Enter".to_string(),
+                    ],
+                    input_profile: None,
+                    seed: None,
+                    timeout_ms: None,
+                })
+                .await
+                .unwrap();
+            manager
+                .handle(&session_id)
+                .unwrap()
+                .wait_for("moved-2", Duration::from_secs(5))
+                .await
+                .unwrap();
+        }
+
+        let exported = manager
+            .export_dockerfile(ExportDockerfileArgs {
+                session_id: session_id.clone(),
+                timeout_ms: None,
+            })
+            .unwrap();
+        let dockerfile = exported["dockerfile"].as_str().unwrap();
+        assert_eq!(exported["packages"], serde_json::json!(["git", "jq"]));
+        assert_eq!(exported["buildArgs"], serde_json::json!(["API_TOKEN"]));
+        assert!(dockerfile.contains("ARG API_TOKEN\n"), "{}", dockerfile);
+        assert!(!dockerfile.contains("secret"), "{}", dockerfile);
+        assert!(dockerfile.contains("ENV TERM=\"xterm-256color\""));
+        assert!(dockerfile.ends_with("CMD [\"bash\"]\n"));
+        #[cfg(target_os = "linux")]
+        {
+            let sub = root.join("sub").display().to_string();
+            assert_eq!(exported["workingDirectory"], sub);
+            assert_eq!(
+                exported["workingDirectoryEntries"],
+                serde_json::json!(["Cargo.toml"])
+            );
+            assert!(dockerfile.contains(&format!("WORKDIR {}\nCOPY . {}\n", sub, sub)));
+        }
+
+        manager
+            .close_session(CloseSessionArgs {
+                session_id,
+                timeout_ms: None,
+            })
+            .await
+            .unwrap();
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    /// Status line of a GET of the web server's viewer page
+    async fn viewer_status(url: &str) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let authority = url.strip_prefix("http://").unwrap();
+        let mut stream = tokio::net::TcpStream::connect(authority).await.unwrap();
+        stream
+            .write_all(
+                format!(
+                    "GET / HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                    authority
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        let _ =
+            tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response)).await;
+        String::from_utf8_lossy(&response)
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .to_string()
+    }
+
+    async fn wait_for_restarts(status: &WebServerStatus, restarts: u32) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while status.restarts() < restarts || status.failed() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("web server should restart");
+    }
+
+    #[tokio::test]
+    async fn test_web_server_restarts_after_crash() {
+        let mut manager = SessionManager::new();
+        let mut events = manager.events().subscribe();
+        let created = manager.create_session(web_session_args()).await.unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        let url = created["webServerUrl"].as_str().unwrap().to_string();
+        let status = manager.sessions[&session_id].web_server.clone().unwrap();
+        assert!(viewer_status(&url).await.starts_with("HTTP/1.1 200"));
+
+        // The port is still free, so the web server comes back on it
+        status.kill_server();
+        wait_for_restarts(&status, 1).await;
+        assert_eq!(status.url(), url);
+        assert!(viewer_status(&url).await.starts_with("HTTP/1.1 200"));
+
+        // Taken in the meantime: the web server moves
+        let port = status.port();
+        status.kill_server();
+        let squatter = tokio::time::timeout(Duration::from_secs(1), async {
+            loop {
+                if let Ok(listeners) = web_listener::bind(None, port) {
+                    return listeners;
+                }
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .expect("the old port should be released");
+        wait_for_restarts(&status, 2).await;
+        assert_ne!(status.port(), port);
+        let moved_url = status.url();
+        assert!(viewer_status(&moved_url).await.starts_with("HTTP/1.1 200"));
+        drop(squatter);
+
+        let listed = manager.list_sessions(Default::default()).await.unwrap();
+        let listed = &listed["sessions"][0];
+        assert_eq!(listed["webServerUrl"], moved_url);
+        assert_eq!(listed["webServerRestarts"], 2);
+        assert_eq!(listed["webServerFailed"], false);
+
+        let mut seen = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            match event {
+                SessionEvent::WebServerStopped { component, .. } => seen.push(component),
+                SessionEvent::WebServerRestarted { port_changed, .. } => {
+                    seen.push(format!("restarted, moved: {}", port_changed))
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(
+            seen,
+            [
+                "web server",
+                "restarted, moved: false",
+                "web server",
+                "restarted, moved: true"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_web_server_is_given_up_on() {
+        let mut manager = SessionManager::new();
+        let mut events = manager.events().subscribe();
+        let created = manager.create_session(web_session_args()).await.unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        let status = manager.sessions[&session_id].web_server.clone().unwrap();
+
+        for restarts in 1..=MAX_WEB_SERVER_RESTARTS {
+            status.kill_server();
+            wait_for_restarts(&status, restarts).await;
+        }
+        status.kill_server();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !status.failed() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("web server should be given up on");
+
+        let listed = manager.list_sessions(Default::default()).await.unwrap();
+        assert_eq!(listed["sessions"][0]["webServerFailed"], true);
+        let mut failed = None;
+        while let Ok(event) = events.try_recv() {
+            if let SessionEvent::WebServerFailed { restarts, .. } = event {
+                failed = Some(restarts);
+            }
         }
+        assert_eq!(failed, Some(MAX_WEB_SERVER_RESTARTS));
     }
 }
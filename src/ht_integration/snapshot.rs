@@ -0,0 +1,185 @@
+//! Post-processing of snapshot text before it is handed to a client
+
+/// Where the cursor is, 1-based like `cursorAt` assertions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cursor {
+    pub row: usize,
+    pub col: usize,
+    /// Hidden by the program (`ESC [ ? 25 l`), as full-screen programs do
+    /// while drawing
+    pub visible: bool,
+}
+
+/// The screen text with the cursor, as sent back by the event loop
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextSnapshot {
+    pub text: String,
+    pub cursor: Cursor,
+}
+
+/// Runs of one character at least this long are shortened by `format_for_ai`
+const MIN_COMPRESSED_RUN: usize = 16;
+
+/// Characters kept on each side of a shortened run
+const RUN_EDGE: usize = 3;
+
+/// Blank lines kept in a row by `ht_take_snapshot` with `ai-compressed`
+pub const DEFAULT_MAX_CONSECUTIVE_BLANK_LINES: usize = 1;
+
+/// Compresses a snapshot to spend fewer tokens on layout: lines are trimmed,
+/// blank lines at the edges are dropped and runs of more than
+/// `max_consecutive_blank_lines` are cut down, and long runs of a repeated
+/// non-space character (progress bars, rulers) become `===...N chars...===`.
+pub fn format_for_ai(snapshot: &str, max_consecutive_blank_lines: usize) -> String {
+    let mut out = String::with_capacity(snapshot.len());
+    // Blank lines seen since the last line with content, written only once
+    // another such line follows
+    let mut blank_run = 0;
+    for line in snapshot.lines().map(str::trim) {
+        if line.is_empty() {
+            blank_run += 1;
+            continue;
+        }
+        if !out.is_empty() {
+            let newlines = blank_run.min(max_consecutive_blank_lines) + 1;
+            out.extend(std::iter::repeat('\n').take(newlines));
+        }
+        blank_run = 0;
+        compress_runs_into(line, &mut out);
+    }
+    out
+}
+
+/// Drops the blank lines below the last line with content, such as the
+/// unused rows of a terminal taller than its output. Lines above it are kept
+/// as they are, trailing spaces included.
+pub fn trim_trailing_blank_lines(snapshot: &str) -> &str {
+    let mut end = 0;
+    let mut offset = 0;
+    for line in snapshot.split_inclusive('\n') {
+        let content = line.trim_end_matches(['\r', '\n']);
+        if !content.trim().is_empty() {
+            end = offset + content.len();
+        }
+        offset += line.len();
+    }
+    &snapshot[..end]
+}
+
+/// Wraps lines longer than `width` characters at spaces, breaking words
+/// that do not fit on a line of their own. Lines within `width` are left as
+/// they are; spaces at a break are dropped.
+pub fn wrap_snapshot(snapshot: &str, width: usize) -> String {
+    let width = width.max(1);
+    let mut out = String::with_capacity(snapshot.len());
+    for (i, line) in snapshot.split('\n').enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+        if line.chars().count() <= width {
+            out.push_str(line);
+            continue;
+        }
+        let mut rest: Vec<char> = line.chars().collect();
+        let mut wrapped = false;
+        while rest.len() > width {
+            // The last space that leaves something on this line; indentation
+            // alone does not count
+            let content_start = rest.iter().position(|c| *c != ' ').unwrap_or(rest.len());
+            let split = (content_start + 1..=width)
+                .rev()
+                .find(|&i| rest[i] == ' ')
+                .unwrap_or(width);
+            let head = rest[..split]
+                .iter()
+                .rposition(|c| !c.is_whitespace())
+                .map_or(0, |last| last + 1);
+            if wrapped {
+                out.push('\n');
+            }
+            out.extend(&rest[..head]);
+            wrapped = true;
+            let next = rest[split..]
+                .iter()
+                .position(|c| *c != ' ')
+                .map_or(rest.len(), |skip| split + skip);
+            rest.drain(..next);
+        }
+        if !rest.is_empty() {
+            out.push('\n');
+            out.extend(rest);
+        }
+    }
+    out
+}
+
+/// Appends `line` to `out` with its long runs shortened
+fn compress_runs_into(line: &str, out: &mut String) {
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        let mut len = 1;
+        while chars.next_if_eq(&c).is_some() {
+            len += 1;
+        }
+        if len >= MIN_COMPRESSED_RUN && !c.is_whitespace() {
+            let edge = std::iter::repeat(c).take(RUN_EDGE);
+            out.extend(edge.clone());
+            out.push_str(&format!("...{} chars...", len));
+            out.extend(edge);
+        } else {
+            out.extend(std::iter::repeat(c).take(len));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collapses_blank_lines() {
+        let snapshot = "\n\n$ ls\n\n\n\nfile.txt\n\n\n\n";
+        assert_eq!(format_for_ai(snapshot, 1), "$ ls\n\nfile.txt");
+        assert_eq!(format_for_ai(snapshot, 0), "$ ls\nfile.txt");
+        assert_eq!(format_for_ai("\n \n", 1), "");
+    }
+
+    #[test]
+    fn test_trims_lines_and_shortens_runs() {
+        let bar = format!("  [{}>   ] 50%   ", "=".repeat(40));
+        assert_eq!(format_for_ai(&bar, 1), "[===...40 chars...===>   ] 50%");
+
+        // Short runs and whitespace runs are left alone
+        let table = format!("a{}b ----- c", " ".repeat(20));
+        assert_eq!(format_for_ai(&table, 1), table);
+    }
+
+    #[test]
+    fn test_trim_trailing_blank_lines() {
+        let snapshot = "$ ls  \n\nfile.txt\n$ \n      \n\n      ";
+        assert_eq!(
+            trim_trailing_blank_lines(snapshot),
+            "$ ls  \n\nfile.txt\n$ "
+        );
+        assert_eq!(trim_trailing_blank_lines("\n   \n"), "");
+        assert_eq!(trim_trailing_blank_lines("done"), "done");
+    }
+
+    #[test]
+    fn test_wrap_snapshot() {
+        assert_eq!(
+            wrap_snapshot("the quick brown fox jumps\nshort", 10),
+            "the quick\nbrown fox\njumps\nshort"
+        );
+        // Lines within the width keep their spacing
+        assert_eq!(wrap_snapshot("a    b   \n", 10), "a    b   \n");
+        // Words longer than the width are broken
+        assert_eq!(wrap_snapshot("abcdefghijkl mn", 5), "abcde\nfghij\nkl mn");
+        // Indentation stays with the first line
+        assert_eq!(
+            wrap_snapshot("    indented text", 10),
+            "    indent\ned text"
+        );
+        assert_eq!(wrap_snapshot(&"x".repeat(6), 3), "xxx\nxxx");
+    }
+}
@@ -0,0 +1,120 @@
+//! Ring buffer of periodic terminal snapshots, for replaying what a session
+//! showed over the recent past
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Snapshots kept per session by default; the oldest is dropped first
+pub const SNAPSHOT_HISTORY_CAPACITY: usize = 120;
+
+/// Shortest accepted `snapshotIntervalMs`
+pub const MIN_SNAPSHOT_INTERVAL_MS: u64 = 100;
+
+/// Timestamped snapshots taken every `interval` by the session's event loop
+#[derive(Debug)]
+pub struct SnapshotHistory {
+    interval: Duration,
+    capacity: AtomicUsize,
+    entries: Mutex<VecDeque<(Instant, String)>>,
+}
+
+impl SnapshotHistory {
+    pub fn new(interval: Duration) -> Self {
+        Self::with_capacity(interval, SNAPSHOT_HISTORY_CAPACITY)
+    }
+
+    pub fn with_capacity(interval: Duration, capacity: usize) -> Self {
+        Self {
+            interval,
+            capacity: AtomicUsize::new(capacity),
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Changes how many snapshots are kept, dropping the oldest ones over
+    /// the new capacity
+    pub fn set_capacity(&self, capacity: usize) {
+        let mut entries = self.entries.lock().unwrap();
+        self.capacity.store(capacity, Ordering::Relaxed);
+        while entries.len() > capacity {
+            entries.pop_front();
+        }
+    }
+
+    pub fn push(&self, snapshot: String) {
+        self.push_at(Instant::now(), snapshot);
+    }
+
+    fn push_at(&self, taken_at: Instant, snapshot: String) {
+        let mut entries = self.entries.lock().unwrap();
+        let capacity = self.capacity.load(Ordering::Relaxed);
+        while !entries.is_empty() && entries.len() >= capacity {
+            entries.pop_front();
+        }
+        entries.push_back((taken_at, snapshot));
+    }
+
+    /// The newest `limit` snapshots taken within `since` of now (all stored
+    /// snapshots when `since` is `None`), oldest first
+    pub fn recent(&self, limit: usize, since: Option<Duration>) -> Vec<(Instant, String)> {
+        let now = Instant::now();
+        let entries = self.entries.lock().unwrap();
+        let mut recent: Vec<(Instant, String)> = entries
+            .iter()
+            .rev()
+            .take_while(|(taken_at, _)| since.map_or(true, |since| now - *taken_at <= since))
+            .take(limit)
+            .cloned()
+            .collect();
+        recent.reverse();
+        recent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(entries: &[(Instant, String)]) -> Vec<&str> {
+        entries.iter().map(|(_, text)| text.as_str()).collect()
+    }
+
+    #[test]
+    fn test_drops_oldest_past_capacity() {
+        let history = SnapshotHistory::with_capacity(Duration::from_secs(1), 3);
+        for text in ["a", "b", "c", "d"] {
+            history.push(text.to_string());
+        }
+        assert_eq!(texts(&history.recent(10, None)), ["b", "c", "d"]);
+        assert_eq!(texts(&history.recent(2, None)), ["c", "d"]);
+    }
+
+    #[test]
+    fn test_since_filters_old_entries() {
+        let history = SnapshotHistory::new(Duration::from_secs(1));
+        let now = Instant::now();
+        history.push_at(now - Duration::from_secs(30), "old".to_string());
+        history.push_at(now - Duration::from_secs(5), "recent".to_string());
+        history.push_at(now, "now".to_string());
+
+        assert_eq!(
+            texts(&history.recent(10, Some(Duration::from_secs(10)))),
+            ["recent", "now"]
+        );
+        assert_eq!(texts(&history.recent(10, None)), ["old", "recent", "now"]);
+    }
+}
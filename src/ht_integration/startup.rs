@@ -0,0 +1,229 @@
+//! How long sessions take to start: from spawning the PTY to the first
+//! byte of output, and to the prompt showing when `autoDetectPrompt` waits
+//! for it. Slow shell startup (network home directories, heavy rc files)
+//! otherwise shows up only as flaky first commands.
+
+use crate::ht_integration::events::{EventBus, SessionEvent};
+use crate::mcp::stats::percentile;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Startup times kept for the percentiles in `ht_stats`
+const STARTUP_SAMPLE_LIMIT: usize = 1000;
+
+const SLOW_STARTUP_HINT: &str =
+    "check the shell's rc files (.bashrc, .profile, ...) and whether the home directory is on a network filesystem";
+
+/// Which startup milestone was slow
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum StartupPhase {
+    FirstOutput,
+    PromptReady,
+}
+
+/// Recent startup times across sessions
+#[derive(Debug, Default)]
+pub struct StartupSamples {
+    first_output_ms: VecDeque<u64>,
+    prompt_ready_ms: VecDeque<u64>,
+}
+
+impl StartupSamples {
+    fn record(&mut self, phase: StartupPhase, ms: u64) {
+        let samples = match phase {
+            StartupPhase::FirstOutput => &mut self.first_output_ms,
+            StartupPhase::PromptReady => &mut self.prompt_ready_ms,
+        };
+        if samples.len() == STARTUP_SAMPLE_LIMIT {
+            samples.pop_front();
+        }
+        samples.push_back(ms);
+    }
+
+    pub fn summary(&self) -> serde_json::Value {
+        let describe = |samples: &VecDeque<u64>| {
+            let mut sorted: Vec<u64> = samples.iter().copied().collect();
+            sorted.sort_unstable();
+            serde_json::json!({
+                "samples": sorted.len(),
+                "p50Ms": percentile(&sorted, 50.0),
+                "p95Ms": percentile(&sorted, 95.0),
+                "maxMs": sorted.last()
+            })
+        };
+        serde_json::json!({
+            "firstOutput": describe(&self.first_output_ms),
+            "promptReady": describe(&self.prompt_ready_ms)
+        })
+    }
+}
+
+/// One session's startup milestones, in milliseconds since the PTY was
+/// spawned
+#[derive(Debug)]
+pub struct StartupTiming {
+    session_id: String,
+    spawned_at: Instant,
+    first_output_ms: OnceLock<u64>,
+    prompt_ready_ms: OnceLock<u64>,
+    /// Milestones later than this publish a `SlowStartup` event; zero never
+    warn_after: Duration,
+    samples: Arc<Mutex<StartupSamples>>,
+    events: EventBus,
+}
+
+impl StartupTiming {
+    pub fn new(
+        session_id: &str,
+        spawned_at: Instant,
+        warn_after: Duration,
+        samples: Arc<Mutex<StartupSamples>>,
+        events: EventBus,
+    ) -> Self {
+        Self {
+            session_id: session_id.to_string(),
+            spawned_at,
+            first_output_ms: OnceLock::new(),
+            prompt_ready_ms: OnceLock::new(),
+            warn_after,
+            samples,
+            events,
+        }
+    }
+
+    /// Notes that output arrived; only the first call counts
+    pub fn record_first_output(&self) {
+        if self.first_output_ms.get().is_none() {
+            self.record(StartupPhase::FirstOutput, &self.first_output_ms);
+        }
+    }
+
+    /// Notes that the shell showed its prompt; only the first call counts
+    pub fn record_prompt_ready(&self) {
+        if self.prompt_ready_ms.get().is_none() {
+            self.record(StartupPhase::PromptReady, &self.prompt_ready_ms);
+        }
+    }
+
+    fn record(&self, phase: StartupPhase, slot: &OnceLock<u64>) {
+        let elapsed = self.spawned_at.elapsed();
+        let ms = elapsed.as_millis() as u64;
+        if slot.set(ms).is_err() {
+            return;
+        }
+        self.samples.lock().unwrap().record(phase, ms);
+        if !self.warn_after.is_zero() && elapsed > self.warn_after {
+            warn!(
+                "Session {} took {} ms to reach {:?} (hint: {})",
+                self.session_id, ms, phase, SLOW_STARTUP_HINT
+            );
+            self.events.publish(SessionEvent::SlowStartup {
+                session_id: self.session_id.clone(),
+                phase,
+                elapsed_ms: ms,
+                threshold_ms: self.warn_after.as_millis() as u64,
+                hint: SLOW_STARTUP_HINT.to_string(),
+            });
+        }
+    }
+
+    pub fn first_output_ms(&self) -> Option<u64> {
+        self.first_output_ms.get().copied()
+    }
+
+    pub fn prompt_ready_ms(&self) -> Option<u64> {
+        self.prompt_ready_ms.get().copied()
+    }
+
+    pub fn summary(&self) -> serde_json::Value {
+        serde_json::json!({
+            "firstOutputMs": self.first_output_ms(),
+            "promptReadyMs": self.prompt_ready_ms()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start(spawned_at: Instant, warn_after: Duration) -> (StartupTiming, EventBus) {
+        let events = EventBus::new();
+        let timing =
+            StartupTiming::new("s1", spawned_at, warn_after, Arc::default(), events.clone());
+        (timing, events)
+    }
+
+    #[test]
+    fn test_only_the_first_milestone_counts() {
+        let spawned_at = Instant::now() - Duration::from_millis(50);
+        let (timing, _) = start(spawned_at, Duration::ZERO);
+        assert_eq!(timing.first_output_ms(), None);
+
+        timing.record_first_output();
+        let first = timing.first_output_ms().unwrap();
+        assert!(first >= 50);
+        std::thread::sleep(Duration::from_millis(5));
+        timing.record_first_output();
+        assert_eq!(timing.first_output_ms(), Some(first));
+        assert_eq!(timing.prompt_ready_ms(), None);
+
+        timing.record_prompt_ready();
+        assert!(timing.prompt_ready_ms().unwrap() >= first);
+        let summary = timing.samples.lock().unwrap().summary();
+        assert_eq!(summary["firstOutput"]["samples"], 1);
+        assert_eq!(summary["promptReady"]["samples"], 1);
+    }
+
+    #[test]
+    fn test_slow_startup_publishes_event() {
+        let slow = Instant::now() - Duration::from_millis(200);
+        let (timing, events) = start(slow, Duration::from_millis(100));
+        let mut rx = events.subscribe();
+        timing.record_first_output();
+        match rx.try_recv().unwrap() {
+            SessionEvent::SlowStartup {
+                session_id,
+                phase,
+                elapsed_ms,
+                threshold_ms,
+                ..
+            } => {
+                assert_eq!(session_id, "s1");
+                assert_eq!(phase, StartupPhase::FirstOutput);
+                assert!(elapsed_ms >= 200);
+                assert_eq!(threshold_ms, 100);
+            }
+            other => panic!("unexpected event {:?}", other),
+        }
+
+        // Fast, or with the warning disabled: no event
+        for (spawned_at, warn_after) in [
+            (Instant::now(), Duration::from_secs(10)),
+            (slow, Duration::ZERO),
+        ] {
+            let (timing, events) = start(spawned_at, warn_after);
+            let mut rx = events.subscribe();
+            timing.record_first_output();
+            assert!(rx.try_recv().is_err());
+        }
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let mut samples = StartupSamples::default();
+        for ms in 1..=100 {
+            samples.record(StartupPhase::FirstOutput, ms);
+        }
+        let summary = samples.summary();
+        assert_eq!(
+            summary["firstOutput"],
+            serde_json::json!({"samples": 100, "p50Ms": 50, "p95Ms": 95, "maxMs": 100})
+        );
+        assert_eq!(summary["promptReady"]["p50Ms"], serde_json::Value::Null);
+    }
+}
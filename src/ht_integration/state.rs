@@ -0,0 +1,429 @@
+//! The server's state as a single versioned archive, for moving ht-mcp to a
+//! new host with `ht_export_state` and `ht_import_state`, or the
+//! `export-state` and `import-state` subcommands.
+//!
+//! The archive is a gzipped tar file. Its first entry is `manifest.json`, a
+//! [`StateManifest`]; the recording files that still existed at export
+//! follow as `recordings/<session id>/<file name>`.
+//!
+//! Live PTYs cannot move, so sessions are exported as definitions (as for
+//! migration) and recreated on import. Recording records carry their paths,
+//! which can be remapped when the recordings directory moves. Recording
+//! files are only written out on import under the remap's target, and never
+//! over an existing file.
+//!
+//! Compatibility: `formatVersion` is bumped for changes older readers would
+//! misread, and files from a newer format are refused. Additions older
+//! readers can safely skip are new fields, which are ignored. An addition
+//! that must not be skipped is named in `mustUnderstand`, and readers that
+//! do not know it refuse the file.
+
+use crate::error::{HtMcpError, Result};
+use crate::ht_integration::migration::MigrationBundle;
+use crate::ht_integration::storage::RecordingRecord;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+/// Format written by this build
+pub const STATE_FORMAT_VERSION: u32 = 1;
+
+/// The manifest's name in the archive, where it is the first entry
+const MANIFEST_ENTRY: &str = "manifest.json";
+
+/// Largest manifest read from an archive
+const MAX_MANIFEST_BYTES: u64 = 256 * 1024 * 1024;
+
+/// `mustUnderstand` entries this build knows
+const KNOWN_FEATURES: [&str; 2] = ["sessions", "recordings"];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StateManifest {
+    pub format_version: u32,
+    /// Version of the ht-mcp that wrote the file, for diagnostics
+    pub server_version: String,
+    /// Unix timestamp (seconds) of the export
+    pub exported_at: u64,
+    /// Parts of the file a reader must not ignore
+    #[serde(default)]
+    pub must_understand: Vec<String>,
+    /// Live sessions, to be recreated on import
+    #[serde(default)]
+    pub sessions: Vec<MigrationBundle>,
+    #[serde(default)]
+    pub recordings: Vec<RecordingRecord>,
+}
+
+/// Rewrites recording paths under `from` to the same place under `to`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PathRemap {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+impl PathRemap {
+    pub fn apply(&self, path: &Path) -> PathBuf {
+        match path.strip_prefix(&self.from) {
+            Ok(rest) => self.to.join(rest),
+            Err(_) => path.to_path_buf(),
+        }
+    }
+
+    /// Whether `path` is under `to`, without `..` leading back out of it
+    pub fn covers(&self, path: &Path) -> bool {
+        path.starts_with(&self.to)
+            && !path
+                .components()
+                .any(|component| component == Component::ParentDir)
+    }
+
+    /// Refuses a target outside the server's `recordings_dir`, since the
+    /// import writes recording files under it
+    pub fn check_target(&self, recordings_dir: Option<&Path>) -> Result<()> {
+        let Some(recordings_dir) = recordings_dir else {
+            return Err(HtMcpError::InvalidRequest(
+                "A recordings remap needs storage.recordings_dir in the config".to_string(),
+            ));
+        };
+        let inside = PathRemap {
+            from: self.from.clone(),
+            to: recordings_dir.to_path_buf(),
+        };
+        if !inside.covers(&self.to) {
+            return Err(HtMcpError::InvalidRequest(format!(
+                "The recordings remap target {} is not under recordings_dir {}",
+                self.to.display(),
+                recordings_dir.display()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Where a recording's file is kept in the archive
+pub fn recording_entry(recording: &RecordingRecord) -> Option<String> {
+    let name = recording.path.as_ref()?.file_name()?.to_str()?;
+    Some(format!("recordings/{}/{}", recording.session_id, name))
+}
+
+impl StateManifest {
+    pub fn new(sessions: Vec<MigrationBundle>, recordings: Vec<RecordingRecord>) -> Self {
+        Self {
+            format_version: STATE_FORMAT_VERSION,
+            server_version: env!("CARGO_PKG_VERSION").to_string(),
+            exported_at: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            must_understand: KNOWN_FEATURES.iter().map(|f| f.to_string()).collect(),
+            sessions,
+            recordings,
+        }
+    }
+
+    /// Checks that this build can import the file, before anything is
+    /// changed
+    pub fn validate(&self) -> Result<()> {
+        if self.format_version == 0 || self.format_version > STATE_FORMAT_VERSION {
+            return Err(HtMcpError::InvalidRequest(format!(
+                "State file has format version {} (written by ht-mcp {}); this ht-mcp reads version {}",
+                self.format_version, self.server_version, STATE_FORMAT_VERSION
+            )));
+        }
+        let unknown: Vec<&str> = self
+            .must_understand
+            .iter()
+            .map(String::as_str)
+            .filter(|feature| !KNOWN_FEATURES.contains(feature))
+            .collect();
+        if !unknown.is_empty() {
+            return Err(HtMcpError::InvalidRequest(format!(
+                "State file needs features this ht-mcp does not support: {}",
+                unknown.join(", ")
+            )));
+        }
+
+        let mut ids = HashSet::new();
+        for session in &self.sessions {
+            if !ids.insert(session.session_id.as_str()) {
+                return Err(HtMcpError::InvalidRequest(format!(
+                    "State file lists session {} twice",
+                    session.session_id
+                )));
+            }
+            if session.config.command.is_empty() {
+                return Err(HtMcpError::InvalidRequest(format!(
+                    "Session {} in the state file has no command",
+                    session.session_id
+                )));
+            }
+        }
+        let mut ids = HashSet::new();
+        for recording in &self.recordings {
+            if !ids.insert(recording.session_id.as_str()) {
+                return Err(HtMcpError::InvalidRequest(format!(
+                    "State file lists the recording of session {} twice",
+                    recording.session_id
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes the archive to `path`, returning how many recording files it holds
+pub fn write(path: &Path, manifest: &StateManifest) -> Result<usize> {
+    let failed = |e: std::io::Error| {
+        HtMcpError::InvalidRequest(format!("Failed to write {}: {}", path.display(), e))
+    };
+    let file = File::create(path).map_err(failed)?;
+    write_archive(file, manifest).map_err(failed)
+}
+
+/// Writes `manifest` and the recording files that still exist as a gzipped
+/// tar archive, returning how many recording files it holds. A recording
+/// whose file is gone keeps its record.
+pub fn write_archive<W: Write>(writer: W, manifest: &StateManifest) -> std::io::Result<usize> {
+    let mut archive = tar::Builder::new(GzEncoder::new(writer, flate2::Compression::default()));
+    let json = serde_json::to_vec_pretty(manifest)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(json.len() as u64);
+    header.set_mode(0o600);
+    header.set_mtime(manifest.exported_at);
+    archive.append_data(&mut header, MANIFEST_ENTRY, json.as_slice())?;
+
+    let mut files = 0;
+    for recording in &manifest.recordings {
+        let (Some(path), Some(entry)) = (&recording.path, recording_entry(recording)) else {
+            continue;
+        };
+        let Ok(mut file) = File::open(path) else {
+            continue;
+        };
+        archive.append_file(entry, &mut file)?;
+        files += 1;
+    }
+    archive.into_inner()?.finish()?.flush()?;
+    Ok(files)
+}
+
+/// Reads and validates the manifest of the archive at `path`
+pub fn read(path: &Path) -> Result<StateManifest> {
+    read_manifest(open(path)?, &path.display().to_string())
+}
+
+/// Opens an archive for [`read_manifest`] or [`extract_recordings`]
+pub fn open(path: &Path) -> Result<File> {
+    File::open(path).map_err(|e| {
+        HtMcpError::InvalidRequest(format!("Failed to read {}: {}", path.display(), e))
+    })
+}
+
+/// Reads and validates the manifest of an archive; `name` says where it
+/// came from in errors
+pub fn read_manifest<R: Read>(reader: R, name: &str) -> Result<StateManifest> {
+    let invalid = |e: &dyn std::fmt::Display| {
+        HtMcpError::InvalidRequest(format!("{} is not an ht-mcp state archive: {}", name, e))
+    };
+    let mut archive = tar::Archive::new(GzDecoder::new(reader));
+    let mut entries = archive.entries().map_err(|e| invalid(&e))?;
+    let entry = entries
+        .next()
+        .ok_or_else(|| invalid(&"it is empty"))?
+        .map_err(|e| invalid(&e))?;
+    let is_manifest = entry
+        .path()
+        .map_or(false, |path| path == Path::new(MANIFEST_ENTRY));
+    if !is_manifest {
+        return Err(invalid(&format!(
+            "{} is not its first entry",
+            MANIFEST_ENTRY
+        )));
+    }
+    if entry.size() > MAX_MANIFEST_BYTES {
+        return Err(invalid(&format!("{} is too large", MANIFEST_ENTRY)));
+    }
+    let manifest: StateManifest = serde_json::from_reader(entry).map_err(|e| invalid(&e))?;
+    manifest.validate()?;
+    Ok(manifest)
+}
+
+/// Writes the archive's recording files named in `targets` (archive entry
+/// to path), creating missing directories but never replacing a file.
+/// Returns the entries written.
+pub fn extract_recordings<R: Read>(
+    reader: R,
+    name: &str,
+    targets: &HashMap<String, PathBuf>,
+) -> Result<HashSet<String>> {
+    let failed = |e: std::io::Error| {
+        HtMcpError::InvalidRequest(format!("Failed to extract recordings from {}: {}", name, e))
+    };
+    let mut archive = tar::Archive::new(GzDecoder::new(reader));
+    let mut written = HashSet::new();
+    for entry in archive.entries().map_err(failed)? {
+        let mut entry = entry.map_err(failed)?;
+        let Some(entry_name) = entry
+            .path()
+            .ok()
+            .and_then(|p| p.to_str().map(str::to_string))
+        else {
+            continue;
+        };
+        let Some(target) = targets.get(&entry_name) else {
+            continue;
+        };
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent).map_err(failed)?;
+        }
+        let mut file = match OpenOptions::new().write(true).create_new(true).open(target) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(failed(e)),
+        };
+        std::io::copy(&mut entry, &mut file).map_err(failed)?;
+        written.insert(entry_name);
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn manifest(extra: serde_json::Value) -> Result<StateManifest> {
+        let mut value = json!({
+            "formatVersion": 1,
+            "serverVersion": "0.1.0",
+            "exportedAt": 0,
+            "mustUnderstand": ["sessions"],
+            "sessions": [],
+            "recordings": []
+        });
+        for (key, field) in extra.as_object().unwrap() {
+            value[key] = field.clone();
+        }
+        let manifest: StateManifest = serde_json::from_value(value)?;
+        manifest.validate()?;
+        Ok(manifest)
+    }
+
+    #[test]
+    fn test_version_checks() {
+        assert!(manifest(json!({})).is_ok());
+        // Fields from newer writers that may be skipped are ignored
+        assert!(manifest(json!({"templates": [{"name": "x"}]})).is_ok());
+
+        for extra in [
+            json!({"formatVersion": STATE_FORMAT_VERSION + 1}),
+            json!({"formatVersion": 0}),
+            json!({"mustUnderstand": ["sessions", "templates"]}),
+        ] {
+            let err = manifest(extra.clone()).unwrap_err();
+            assert!(matches!(err, HtMcpError::InvalidRequest(_)), "{}", extra);
+        }
+    }
+
+    #[test]
+    fn test_duplicates_are_refused() {
+        let session = json!({
+            "sessionId": "s1",
+            "config": {
+                "command": ["bash"],
+                "enableWebServer": false,
+                "enableTunnel": false,
+                "maxMemoryMb": null,
+                "connectionLimits": null,
+                "runAs": null,
+                "labelColor": null,
+                "labelIcon": null
+            },
+            "snapshot": "",
+            "history": [],
+            "exportedAt": 0
+        });
+        assert!(manifest(json!({"sessions": [session.clone()]})).is_ok());
+        let err = manifest(json!({"sessions": [session.clone(), session]})).unwrap_err();
+        assert!(matches!(err, HtMcpError::InvalidRequest(_)));
+    }
+
+    #[test]
+    fn test_archive_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = dir.path().join("old/s1.cast");
+        std::fs::create_dir_all(old.parent().unwrap()).unwrap();
+        std::fs::write(&old, "recorded").unwrap();
+        let record = |session_id: &str, path: PathBuf| -> RecordingRecord {
+            serde_json::from_value(json!({
+                "sessionId": session_id,
+                "path": path,
+                "bytes": 8,
+                "active": false,
+                "clearedReason": null
+            }))
+            .unwrap()
+        };
+        let exported = StateManifest::new(
+            Vec::new(),
+            vec![
+                record("s1", old.clone()),
+                // Evicted since: the record still goes, without a file
+                record("s2", dir.path().join("old/s2.cast")),
+            ],
+        );
+        let archive_path = dir.path().join("state.tar.gz");
+        assert_eq!(write(&archive_path, &exported).unwrap(), 1);
+
+        let manifest = read(&archive_path).unwrap();
+        assert_eq!(manifest.recordings, exported.recordings);
+        let entry = recording_entry(&manifest.recordings[0]).unwrap();
+        assert_eq!(entry, "recordings/s1/s1.cast");
+
+        let new = dir.path().join("new/s1.cast");
+        let targets = HashMap::from([(entry.clone(), new.clone())]);
+        let name = archive_path.display().to_string();
+        let written = extract_recordings(open(&archive_path).unwrap(), &name, &targets).unwrap();
+        assert_eq!(written, HashSet::from([entry]));
+        assert_eq!(std::fs::read_to_string(&new).unwrap(), "recorded");
+
+        // Existing files are left alone
+        std::fs::write(&new, "newer").unwrap();
+        let written = extract_recordings(open(&archive_path).unwrap(), &name, &targets).unwrap();
+        assert!(written.is_empty());
+        assert_eq!(std::fs::read_to_string(&new).unwrap(), "newer");
+
+        // The old single-JSON format, or anything else, is refused
+        std::fs::write(&archive_path, serde_json::to_vec(&exported).unwrap()).unwrap();
+        let err = read(&archive_path).unwrap_err();
+        assert!(
+            err.to_string().contains("not an ht-mcp state archive"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_path_remap() {
+        let remap = PathRemap {
+            from: PathBuf::from("/old/recordings"),
+            to: PathBuf::from("/srv/recordings"),
+        };
+        assert_eq!(
+            remap.apply(Path::new("/old/recordings/s1.cast")),
+            Path::new("/srv/recordings/s1.cast")
+        );
+        assert_eq!(
+            remap.apply(Path::new("/elsewhere/s1.cast")),
+            Path::new("/elsewhere/s1.cast")
+        );
+        assert!(remap.covers(Path::new("/srv/recordings/s1/s1.cast")));
+        assert!(!remap.covers(Path::new("/srv/recordings/../etc/cron.d/x")));
+        assert!(!remap.covers(Path::new("/elsewhere/s1.cast")));
+    }
+}
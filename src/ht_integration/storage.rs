@@ -0,0 +1,406 @@
+//! Disk usage accounting for files the server writes on behalf of sessions.
+//!
+//! Writers report the bytes they write per category. Recordings over their
+//! cap are evicted oldest-closed-first; active recordings are never deleted.
+//! Session logs and audit files have no eviction policy, so writes past
+//! their caps are refused instead.
+
+use crate::error::{HtMcpError, Result};
+use crate::ht_integration::events::{EventBus, SessionEvent};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use tracing::{info, warn};
+
+/// Fraction of a cap at which a `StorageWarning` event is published
+const WARNING_THRESHOLD: f64 = 0.8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum StorageCategory {
+    Recordings,
+    SessionLogs,
+    Audit,
+}
+
+impl StorageCategory {
+    pub const ALL: [StorageCategory; 3] = [
+        StorageCategory::Recordings,
+        StorageCategory::SessionLogs,
+        StorageCategory::Audit,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StorageCategory::Recordings => "recordings",
+            StorageCategory::SessionLogs => "sessionLogs",
+            StorageCategory::Audit => "audit",
+        }
+    }
+}
+
+/// Byte caps per category; unset means unlimited
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StorageConfig {
+    /// Where every session's output is recorded (`<session id>.cast`);
+    /// unset records nothing
+    pub recordings_dir: Option<PathBuf>,
+    pub max_recording_bytes: Option<u64>,
+    pub max_session_log_bytes: Option<u64>,
+    pub max_audit_bytes: Option<u64>,
+}
+
+impl StorageConfig {
+    pub fn cap(&self, category: StorageCategory) -> Option<u64> {
+        match category {
+            StorageCategory::Recordings => self.max_recording_bytes,
+            StorageCategory::SessionLogs => self.max_session_log_bytes,
+            StorageCategory::Audit => self.max_audit_bytes,
+        }
+    }
+}
+
+/// A session's recording file. `path` is cleared, with a reason, once the
+/// file has been evicted.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecordingRecord {
+    pub session_id: String,
+    pub path: Option<PathBuf>,
+    pub bytes: u64,
+    pub active: bool,
+    pub cleared_reason: Option<String>,
+    /// Order in which the session closed; eviction starts with the lowest
+    #[serde(skip)]
+    closed_seq: Option<u64>,
+}
+
+#[derive(Debug)]
+pub struct StorageManager {
+    config: StorageConfig,
+    usage: HashMap<StorageCategory, u64>,
+    recordings: HashMap<String, RecordingRecord>,
+    /// Categories whose warning was published and has not yet re-armed
+    warned: HashSet<StorageCategory>,
+    next_closed_seq: u64,
+    events: EventBus,
+}
+
+impl StorageManager {
+    pub fn new(config: StorageConfig, events: EventBus) -> Self {
+        Self {
+            config,
+            usage: HashMap::new(),
+            recordings: HashMap::new(),
+            warned: HashSet::new(),
+            next_closed_seq: 0,
+            events,
+        }
+    }
+
+    /// Starts tracking an active session's recording file
+    pub fn track_recording(&mut self, session_id: &str, path: PathBuf) {
+        self.recordings.insert(
+            session_id.to_string(),
+            RecordingRecord {
+                session_id: session_id.to_string(),
+                path: Some(path),
+                bytes: 0,
+                active: true,
+                cleared_reason: None,
+                closed_seq: None,
+            },
+        );
+    }
+
+    /// Marks a session's recording closed, making it eligible for eviction
+    pub fn close_recording(&mut self, session_id: &str) {
+        if let Some(recording) = self.recordings.get_mut(session_id) {
+            if recording.active {
+                recording.active = false;
+                recording.closed_seq = Some(self.next_closed_seq);
+                self.next_closed_seq += 1;
+            }
+        }
+        self.enforce_recording_cap();
+    }
+
+    pub fn recording(&self, session_id: &str) -> Option<&RecordingRecord> {
+        self.recordings.get(session_id)
+    }
+
+    pub fn recordings(&self) -> impl Iterator<Item = &RecordingRecord> {
+        self.recordings.values()
+    }
+
+    /// Adds a recording carried over from another instance. It is closed,
+    /// as the session that wrote it is gone, and counts toward the cap.
+    pub fn import_recording(&mut self, mut recording: RecordingRecord) {
+        recording.active = false;
+        recording.closed_seq = Some(self.next_closed_seq);
+        self.next_closed_seq += 1;
+        let used = self.used(StorageCategory::Recordings);
+        self.usage
+            .insert(StorageCategory::Recordings, used + recording.bytes);
+        self.recordings
+            .insert(recording.session_id.clone(), recording);
+        self.enforce_recording_cap();
+    }
+
+    /// Accounts for `bytes` written in `category`. Recording writes are
+    /// attributed to `session_id`'s recording and may evict older ones;
+    /// session log and audit writes that would exceed their cap are refused.
+    pub fn record_write(
+        &mut self,
+        category: StorageCategory,
+        session_id: Option<&str>,
+        bytes: u64,
+    ) -> Result<()> {
+        let used = self.used(category);
+        if category != StorageCategory::Recordings {
+            if let Some(cap) = self.config.cap(category) {
+                if used + bytes > cap {
+                    return Err(HtMcpError::Internal(format!(
+                        "Storage cap for {} reached ({} of {} bytes used)",
+                        category.as_str(),
+                        used,
+                        cap
+                    )));
+                }
+            }
+        }
+
+        self.usage.insert(category, used + bytes);
+        if category == StorageCategory::Recordings {
+            if let Some(recording) = session_id.and_then(|id| self.recordings.get_mut(id)) {
+                recording.bytes += bytes;
+            }
+            self.enforce_recording_cap();
+        }
+        self.check_warning(category);
+        Ok(())
+    }
+
+    pub fn used(&self, category: StorageCategory) -> u64 {
+        self.usage.get(&category).copied().unwrap_or(0)
+    }
+
+    /// Bytes used and cap per category
+    pub fn usage_summary(&self) -> serde_json::Value {
+        let categories: serde_json::Map<String, serde_json::Value> = StorageCategory::ALL
+            .iter()
+            .map(|category| {
+                (
+                    category.as_str().to_string(),
+                    serde_json::json!({
+                        "usedBytes": self.used(*category),
+                        "capBytes": self.config.cap(*category),
+                    }),
+                )
+            })
+            .collect();
+        serde_json::Value::Object(categories)
+    }
+
+    /// Deletes closed recordings, oldest closed first, until recordings fit
+    /// their cap again
+    fn enforce_recording_cap(&mut self) {
+        let Some(cap) = self.config.max_recording_bytes else {
+            return;
+        };
+
+        while self.used(StorageCategory::Recordings) > cap {
+            let Some(victim) = self
+                .recordings
+                .values_mut()
+                .filter(|recording| !recording.active && recording.path.is_some())
+                .min_by_key(|recording| recording.closed_seq)
+            else {
+                warn!(
+                    "Recordings use {} bytes, over the {} byte cap, but only active recordings remain",
+                    self.used(StorageCategory::Recordings),
+                    cap
+                );
+                break;
+            };
+
+            let path = victim.path.take().unwrap_or_default();
+            if let Err(e) = std::fs::remove_file(&path) {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    warn!("Failed to delete recording {}: {}", path.display(), e);
+                }
+            }
+            info!(
+                "Evicted recording {} of closed session {} ({} bytes) to stay under the {} byte cap",
+                path.display(),
+                victim.session_id,
+                victim.bytes,
+                cap
+            );
+            victim.cleared_reason =
+                Some(format!("evicted: recordings exceeded the {} byte cap", cap));
+            let freed = std::mem::take(&mut victim.bytes);
+            let used = self.used(StorageCategory::Recordings);
+            self.usage
+                .insert(StorageCategory::Recordings, used.saturating_sub(freed));
+        }
+        self.check_warning(StorageCategory::Recordings);
+    }
+
+    /// Publishes a warning once usage crosses the threshold; re-arms when it
+    /// drops back below
+    fn check_warning(&mut self, category: StorageCategory) {
+        let Some(cap) = self.config.cap(category) else {
+            return;
+        };
+        let used = self.used(category);
+        if (used as f64) < cap as f64 * WARNING_THRESHOLD {
+            self.warned.remove(&category);
+        } else if self.warned.insert(category) {
+            warn!(
+                "Storage for {} at {} of {} bytes",
+                category.as_str(),
+                used,
+                cap
+            );
+            self.events.publish(SessionEvent::StorageWarning {
+                category,
+                used_bytes: used,
+                cap_bytes: cap,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manager(
+        config: StorageConfig,
+    ) -> (
+        StorageManager,
+        tokio::sync::broadcast::Receiver<SessionEvent>,
+    ) {
+        let events = EventBus::new();
+        let rx = events.subscribe();
+        (StorageManager::new(config, events), rx)
+    }
+
+    /// Writes a synthetic recording file and reports it
+    fn write_recording(
+        storage: &mut StorageManager,
+        dir: &std::path::Path,
+        session_id: &str,
+        bytes: usize,
+    ) -> PathBuf {
+        let path = dir.join(format!("{}.cast", session_id));
+        std::fs::write(&path, vec![b'x'; bytes]).unwrap();
+        storage.track_recording(session_id, path.clone());
+        storage
+            .record_write(StorageCategory::Recordings, Some(session_id), bytes as u64)
+            .unwrap();
+        path
+    }
+
+    #[test]
+    fn test_evicts_oldest_closed_recording_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut storage, _rx) = manager(StorageConfig {
+            max_recording_bytes: Some(250),
+            ..StorageConfig::default()
+        });
+
+        let first = write_recording(&mut storage, dir.path(), "a", 100);
+        let second = write_recording(&mut storage, dir.path(), "b", 100);
+        // "b" closes before "a", so it is the older closed recording
+        storage.close_recording("b");
+        storage.close_recording("a");
+
+        write_recording(&mut storage, dir.path(), "c", 100);
+
+        assert!(!second.exists());
+        assert!(first.exists());
+        assert_eq!(storage.used(StorageCategory::Recordings), 200);
+        let evicted = storage.recording("b").unwrap();
+        assert!(evicted.path.is_none());
+        assert!(evicted
+            .cleared_reason
+            .as_deref()
+            .unwrap()
+            .contains("250 byte cap"));
+        assert!(storage.recording("a").unwrap().path.is_some());
+    }
+
+    #[test]
+    fn test_never_evicts_active_recordings() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut storage, _rx) = manager(StorageConfig {
+            max_recording_bytes: Some(150),
+            ..StorageConfig::default()
+        });
+
+        let first = write_recording(&mut storage, dir.path(), "a", 100);
+        let second = write_recording(&mut storage, dir.path(), "b", 100);
+
+        assert!(first.exists() && second.exists());
+        assert_eq!(storage.used(StorageCategory::Recordings), 200);
+
+        // Once closed, the over-cap recording goes
+        storage.close_recording("a");
+        assert!(!first.exists());
+        assert!(second.exists());
+        assert_eq!(storage.used(StorageCategory::Recordings), 100);
+    }
+
+    #[test]
+    fn test_refuses_log_and_audit_writes_over_cap() {
+        let (mut storage, _rx) = manager(StorageConfig {
+            max_session_log_bytes: Some(100),
+            ..StorageConfig::default()
+        });
+        storage
+            .record_write(StorageCategory::SessionLogs, Some("a"), 100)
+            .unwrap();
+        assert!(storage
+            .record_write(StorageCategory::SessionLogs, Some("a"), 1)
+            .is_err());
+        // Uncapped categories are only counted
+        storage
+            .record_write(StorageCategory::Audit, None, 10_000)
+            .unwrap();
+        assert_eq!(storage.usage_summary()["audit"]["usedBytes"], 10_000);
+    }
+
+    #[test]
+    fn test_warns_once_at_eighty_percent() {
+        let (mut storage, mut rx) = manager(StorageConfig {
+            max_audit_bytes: Some(100),
+            ..StorageConfig::default()
+        });
+        storage
+            .record_write(StorageCategory::Audit, None, 79)
+            .unwrap();
+        assert!(rx.try_recv().is_err());
+
+        storage
+            .record_write(StorageCategory::Audit, None, 1)
+            .unwrap();
+        storage
+            .record_write(StorageCategory::Audit, None, 5)
+            .unwrap();
+        match rx.try_recv().unwrap() {
+            SessionEvent::StorageWarning {
+                category,
+                used_bytes,
+                cap_bytes,
+            } => {
+                assert_eq!(category, StorageCategory::Audit);
+                assert_eq!((used_bytes, cap_bytes), (80, 100));
+            }
+            other => panic!("unexpected event {:?}", other),
+        }
+        assert!(rx.try_recv().is_err(), "the warning is not repeated");
+    }
+}
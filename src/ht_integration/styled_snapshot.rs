@@ -0,0 +1,869 @@
+//! Snapshots that keep colors and text attributes, for `ht_take_snapshot`
+//! with `format: "ansi"` or `"structured"`.
+//!
+//! `Session::get_text` drops all styling, so the screen is rebuilt from the
+//! dump ht_core hands to new subscribers: the escape sequences that redraw
+//! the screen as it is, pen and all. [`StyledScreen::replay`] interprets
+//! them on a grid of the terminal's size. Only what such a dump (and a
+//! typical full-screen program) uses is understood; other sequences are
+//! skipped. The replay also tells where the cursor is, which ht_core does
+//! not expose otherwise; [`ScreenTracker`] keeps a screen fed with output as
+//! it arrives for that, so plain snapshots need no replay. Characters take
+//! the columns `unicode-width` gives them, as in ht_core's own terminal: a
+//! double-width one covers the cell to its right too.
+
+use crate::ht_integration::snapshot::Cursor;
+use serde_json::{json, Map, Value};
+use std::iter::Peekable;
+use unicode_width::UnicodeWidthChar;
+
+/// How a styled snapshot is returned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StyledFormat {
+    /// The text re-encoded with SGR sequences
+    Ansi,
+    /// The plain text plus styled runs per line as JSON
+    Structured,
+}
+
+/// A styled snapshot as sent back by the event loop
+#[derive(Debug, Clone, PartialEq)]
+pub struct StyledSnapshot {
+    /// ANSI text for [`StyledFormat::Ansi`], plain text otherwise
+    pub text: String,
+    /// The styled runs of each non-blank line, for [`StyledFormat::Structured`]
+    pub lines: Option<Value>,
+    pub cursor: Cursor,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Color {
+    #[default]
+    Default,
+    /// One of the 256 palette colors; 0-15 are the named ones
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+impl Color {
+    /// SGR parameters selecting this color; `base` is 30 for the
+    /// foreground and 40 for the background
+    fn sgr(self, base: u8) -> Option<String> {
+        match self {
+            Color::Default => None,
+            Color::Indexed(n) if n < 8 => Some((base + n).to_string()),
+            Color::Indexed(n) if n < 16 => Some((base + 60 + n - 8).to_string()),
+            Color::Indexed(n) => Some(format!("{};5;{}", base + 8, n)),
+            Color::Rgb(r, g, b) => Some(format!("{};2;{};{};{}", base + 8, r, g, b)),
+        }
+    }
+
+    fn json(self) -> Option<Value> {
+        match self {
+            Color::Default => None,
+            Color::Indexed(n) => Some(json!(n)),
+            Color::Rgb(r, g, b) => Some(json!(format!("#{:02x}{:02x}{:02x}", r, g, b))),
+        }
+    }
+}
+
+/// Colors and attributes of a cell
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Pen {
+    pub fg: Color,
+    pub bg: Color,
+    pub bold: bool,
+    pub faint: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub blink: bool,
+    pub inverse: bool,
+    pub strikethrough: bool,
+}
+
+impl Pen {
+    fn is_default(&self) -> bool {
+        *self == Pen::default()
+    }
+
+    fn attributes(&self) -> [(&'static str, bool, &'static str); 7] {
+        [
+            ("bold", self.bold, "1"),
+            ("faint", self.faint, "2"),
+            ("italic", self.italic, "3"),
+            ("underline", self.underline, "4"),
+            ("blink", self.blink, "5"),
+            ("inverse", self.inverse, "7"),
+            ("strikethrough", self.strikethrough, "9"),
+        ]
+    }
+
+    /// The SGR sequence switching from any pen to this one
+    fn sgr(&self) -> String {
+        let mut params = vec!["0".to_string()];
+        params.extend(
+            self.attributes()
+                .iter()
+                .filter(|(_, on, _)| *on)
+                .map(|(_, _, code)| code.to_string()),
+        );
+        params.extend(self.fg.sgr(30));
+        params.extend(self.bg.sgr(40));
+        format!("\x1b[{}m", params.join(";"))
+    }
+
+    /// Applies the SGR parameters `params`
+    fn apply_sgr(&mut self, params: &[u16]) {
+        if params.is_empty() {
+            *self = Pen::default();
+            return;
+        }
+        let mut i = 0;
+        while i < params.len() {
+            match params[i] {
+                0 => *self = Pen::default(),
+                1 => self.bold = true,
+                2 => self.faint = true,
+                3 => self.italic = true,
+                4 => self.underline = true,
+                5 | 6 => self.blink = true,
+                7 => self.inverse = true,
+                9 => self.strikethrough = true,
+                22 => {
+                    self.bold = false;
+                    self.faint = false;
+                }
+                23 => self.italic = false,
+                24 => self.underline = false,
+                25 => self.blink = false,
+                27 => self.inverse = false,
+                29 => self.strikethrough = false,
+                n @ 30..=37 => self.fg = Color::Indexed((n - 30) as u8),
+                39 => self.fg = Color::Default,
+                n @ 40..=47 => self.bg = Color::Indexed((n - 40) as u8),
+                49 => self.bg = Color::Default,
+                n @ 90..=97 => self.fg = Color::Indexed((n - 90 + 8) as u8),
+                n @ 100..=107 => self.bg = Color::Indexed((n - 100 + 8) as u8),
+                n @ (38 | 48) => {
+                    let color = match params.get(i + 1) {
+                        Some(5) => {
+                            let color = params.get(i + 2).map(|&n| Color::Indexed(n as u8));
+                            i += 2;
+                            color
+                        }
+                        Some(2) => {
+                            let color = match params.get(i + 2..i + 5) {
+                                Some(&[r, g, b]) => Some(Color::Rgb(r as u8, g as u8, b as u8)),
+                                _ => None,
+                            };
+                            i += 4;
+                            color
+                        }
+                        _ => None,
+                    };
+                    if let Some(color) = color {
+                        if n == 38 {
+                            self.fg = color;
+                        } else {
+                            self.bg = color;
+                        }
+                    }
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cell {
+    ch: char,
+    pen: Pen,
+}
+
+/// Stands in the cell a double-width character to its left covers
+const WIDE_TAIL: char = '\0';
+
+impl Cell {
+    fn is_wide_tail(&self) -> bool {
+        self.ch == WIDE_TAIL
+    }
+
+    /// An erased cell, which keeps the background of `pen`
+    fn blank(pen: Pen) -> Self {
+        Self {
+            ch: ' ',
+            pen: Pen {
+                bg: pen.bg,
+                ..Pen::default()
+            },
+        }
+    }
+}
+
+type Grid = Vec<Vec<Cell>>;
+
+/// Bytes a grid takes per cell, for the limits `ht_resource_report` shows
+pub const BYTES_PER_CELL: usize = std::mem::size_of::<Cell>();
+
+/// A terminal screen with the styling of every cell
+#[derive(Debug, Clone)]
+pub struct StyledScreen {
+    cols: usize,
+    rows: usize,
+    grid: Grid,
+    /// The primary screen while the alternate one is shown
+    saved_primary: Option<Grid>,
+    row: usize,
+    /// `cols` once a character was printed in the last column: the next one
+    /// wraps
+    col: usize,
+    saved_cursor: (usize, usize),
+    cursor_visible: bool,
+    pen: Pen,
+}
+
+impl StyledScreen {
+    fn new(cols: usize, rows: usize) -> Self {
+        let cols = cols.max(1);
+        let rows = rows.max(1);
+        Self {
+            cols,
+            rows,
+            grid: blank_grid(cols, rows),
+            saved_primary: None,
+            row: 0,
+            col: 0,
+            saved_cursor: (0, 0),
+            cursor_visible: true,
+            pen: Pen::default(),
+        }
+    }
+
+    /// The screen drawn by `output` on a blank `cols`x`rows` terminal
+    pub fn replay(output: &str, cols: usize, rows: usize) -> Self {
+        let mut screen = Self::new(cols, rows);
+        screen.feed(output);
+        screen
+    }
+
+    /// Draws `output` on the screen as it is
+    fn feed(&mut self, output: &str) {
+        let mut chars = output.chars().peekable();
+        while let Some(ch) = chars.next() {
+            match ch {
+                '\x1b' => match chars.next() {
+                    Some('[') => self.csi(&mut chars),
+                    Some(']' | 'P' | '_' | '^') => skip_string(&mut chars),
+                    // Character set designations take one more character
+                    Some('(' | ')' | '*' | '+' | '#' | '%') => {
+                        chars.next();
+                    }
+                    Some('7') => self.saved_cursor = (self.row, self.col),
+                    Some('8') => (self.row, self.col) = self.saved_cursor,
+                    Some('D') => self.line_feed(),
+                    Some('E') => {
+                        self.col = 0;
+                        self.line_feed();
+                    }
+                    Some('M') => self.reverse_index(),
+                    Some('c') => *self = Self::new(self.cols, self.rows),
+                    _ => {}
+                },
+                '\u{9b}' => self.csi(&mut chars),
+                '\u{9d}' | '\u{90}' => skip_string(&mut chars),
+                '\r' => self.col = 0,
+                '\n' | '\x0b' | '\x0c' => self.line_feed(),
+                '\x08' => self.col = self.col.min(self.cols - 1).saturating_sub(1),
+                '\t' => self.col = ((self.col / 8 + 1) * 8).min(self.cols - 1),
+                ch if ch.is_control() => {}
+                ch => self.print(ch),
+            }
+        }
+    }
+
+    fn print(&mut self, ch: char) {
+        // A cell holds one character, so combining marks are dropped
+        let width = match ch.width() {
+            Some(0) | None => return,
+            Some(width) => width.min(self.cols),
+        };
+        // A double-width character does not fit in the last column alone
+        if self.col + width > self.cols {
+            self.col = 0;
+            self.line_feed();
+        }
+        let (row, col) = (self.row, self.col);
+        self.break_wide(row, col);
+        self.break_wide(row, col + width);
+        self.grid[row][col] = Cell { ch, pen: self.pen };
+        if width == 2 {
+            self.grid[row][col + 1] = Cell {
+                ch: WIDE_TAIL,
+                pen: self.pen,
+            };
+        }
+        self.col += width;
+    }
+
+    /// Blanks both halves of a double-width character split at `col`, as
+    /// about to be overwritten or erased on one side
+    fn break_wide(&mut self, row: usize, col: usize) {
+        if col == 0 || col >= self.cols || !self.grid[row][col].is_wide_tail() {
+            return;
+        }
+        let blank = Cell::blank(self.grid[row][col].pen);
+        self.grid[row][col - 1] = blank;
+        self.grid[row][col] = blank;
+    }
+
+    fn line_feed(&mut self) {
+        if self.row + 1 < self.rows {
+            self.row += 1;
+        } else {
+            self.scroll_up(0, 1);
+        }
+    }
+
+    fn reverse_index(&mut self) {
+        if self.row > 0 {
+            self.row -= 1;
+        } else {
+            self.scroll_down(0, 1);
+        }
+    }
+
+    /// Moves rows `top..` up by `count`, blanking the rows freed at the
+    /// bottom. Row buffers are reused rather than reallocated, which matters
+    /// on wide terminals printing fast.
+    fn scroll_up(&mut self, top: usize, count: usize) {
+        let count = count.min(self.rows - top);
+        self.grid[top..].rotate_left(count);
+        let blank = Cell::blank(self.pen);
+        for line in &mut self.grid[self.rows - count..] {
+            line.fill(blank);
+        }
+    }
+
+    /// Moves rows `top..` down by `count`, blanking the rows freed at `top`
+    fn scroll_down(&mut self, top: usize, count: usize) {
+        let count = count.min(self.rows - top);
+        self.grid[top..].rotate_right(count);
+        let blank = Cell::blank(self.pen);
+        for line in &mut self.grid[top..top + count] {
+            line.fill(blank);
+        }
+    }
+
+    /// Blanks columns `from..to` of the cursor's row
+    fn erase(&mut self, row: usize, from: usize, to: usize) {
+        let blank = Cell::blank(self.pen);
+        let to = to.min(self.cols);
+        self.break_wide(row, from.min(to));
+        self.break_wide(row, to);
+        for cell in &mut self.grid[row][from.min(to)..to] {
+            *cell = blank;
+        }
+    }
+
+    fn csi(&mut self, chars: &mut Peekable<impl Iterator<Item = char>>) {
+        let mut params = String::new();
+        let mut final_byte = None;
+        for ch in chars.by_ref() {
+            if ('\x40'..='\x7e').contains(&ch) {
+                final_byte = Some(ch);
+                break;
+            }
+            params.push(ch);
+        }
+        let Some(final_byte) = final_byte else {
+            return;
+        };
+        let private = params.starts_with(['?', '>', '=', '<']);
+        let numbers: Vec<u16> = params
+            .trim_start_matches(['?', '>', '=', '<'])
+            .split([';', ':'])
+            .map(|param| param.parse().unwrap_or(0))
+            .collect();
+        let numbers = if params.is_empty() { vec![] } else { numbers };
+        // First parameter, with 0 or none meaning `default`
+        let arg = |i: usize, default: usize| match numbers.get(i) {
+            Some(&n) if n > 0 => n as usize,
+            _ => default,
+        };
+        let col = self.col.min(self.cols - 1);
+
+        match (private, final_byte) {
+            (false, 'm') => self.pen.apply_sgr(&numbers),
+            (false, 'H' | 'f') => {
+                self.row = (arg(0, 1) - 1).min(self.rows - 1);
+                self.col = (arg(1, 1) - 1).min(self.cols - 1);
+            }
+            (false, 'A') => self.row = self.row.saturating_sub(arg(0, 1)),
+            (false, 'B' | 'e') => self.row = (self.row + arg(0, 1)).min(self.rows - 1),
+            (false, 'C' | 'a') => self.col = (col + arg(0, 1)).min(self.cols - 1),
+            (false, 'D') => self.col = col.saturating_sub(arg(0, 1)),
+            (false, 'E') => {
+                self.row = (self.row + arg(0, 1)).min(self.rows - 1);
+                self.col = 0;
+            }
+            (false, 'F') => {
+                self.row = self.row.saturating_sub(arg(0, 1));
+                self.col = 0;
+            }
+            (false, 'G' | '`') => self.col = (arg(0, 1) - 1).min(self.cols - 1),
+            (false, 'd') => self.row = (arg(0, 1) - 1).min(self.rows - 1),
+            (false, 'J') => {
+                let (from, to) = match arg(0, 0) {
+                    0 => {
+                        self.erase(self.row, col, self.cols);
+                        (self.row + 1, self.rows)
+                    }
+                    1 => {
+                        self.erase(self.row, 0, col + 1);
+                        (0, self.row)
+                    }
+                    _ => (0, self.rows),
+                };
+                let blank = Cell::blank(self.pen);
+                for line in &mut self.grid[from..to] {
+                    line.fill(blank);
+                }
+            }
+            (false, 'K') => match arg(0, 0) {
+                0 => self.erase(self.row, col, self.cols),
+                1 => self.erase(self.row, 0, col + 1),
+                _ => self.erase(self.row, 0, self.cols),
+            },
+            (false, 'X') => self.erase(self.row, col, col + arg(0, 1)),
+            (false, 'P') => {
+                let count = arg(0, 1).min(self.cols - col);
+                self.break_wide(self.row, col);
+                self.break_wide(self.row, col + count);
+                let line = &mut self.grid[self.row];
+                line.drain(col..col + count);
+                line.extend(std::iter::repeat(Cell::blank(self.pen)).take(count));
+            }
+            (false, '@') => {
+                let count = arg(0, 1).min(self.cols - col);
+                self.break_wide(self.row, col);
+                self.break_wide(self.row, self.cols - count);
+                let blank = Cell::blank(self.pen);
+                let line = &mut self.grid[self.row];
+                line.truncate(self.cols - count);
+                line.splice(col..col, std::iter::repeat(blank).take(count));
+            }
+            (false, 'L') => self.scroll_down(self.row, arg(0, 1)),
+            (false, 'M') => self.scroll_up(self.row, arg(0, 1)),
+            (false, 's') => self.saved_cursor = (self.row, self.col),
+            (false, 'u') => (self.row, self.col) = self.saved_cursor,
+            (true, 'h' | 'l') if numbers.contains(&25) => self.cursor_visible = final_byte == 'h',
+            (true, 'h') if numbers.iter().any(|&n| matches!(n, 47 | 1047 | 1049)) => {
+                if self.saved_primary.is_none() {
+                    let alternate = blank_grid(self.cols, self.rows);
+                    self.saved_primary = Some(std::mem::replace(&mut self.grid, alternate));
+                }
+            }
+            (true, 'l') if numbers.iter().any(|&n| matches!(n, 47 | 1047 | 1049)) => {
+                if let Some(primary) = self.saved_primary.take() {
+                    self.grid = primary;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Where the next character goes; a cursor past the last column, waiting
+    /// to wrap, is reported in it
+    pub fn cursor(&self) -> Cursor {
+        Cursor {
+            row: self.row + 1,
+            col: self.col.min(self.cols - 1) + 1,
+            visible: self.cursor_visible,
+        }
+    }
+
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    /// The character and pen of the cell at 0-based `row` and `col`; a
+    /// blank for the right half of a double-width character
+    pub fn cell(&self, row: usize, col: usize) -> (char, Pen) {
+        let cell = &self.grid[row][col];
+        match cell.is_wide_tail() {
+            true => (' ', cell.pen),
+            false => (cell.ch, cell.pen),
+        }
+    }
+
+    /// The cells of `row` up to the last one that is not a blank with the
+    /// default pen
+    fn visible(&self, row: usize) -> &[Cell] {
+        let line = &self.grid[row];
+        let end = line
+            .iter()
+            .rposition(|cell| cell.ch != ' ' || !cell.pen.is_default())
+            .map_or(0, |last| last + 1);
+        &line[..end]
+    }
+
+    /// The screen text without styling, one line per row
+    pub fn to_text(&self) -> String {
+        (0..self.rows)
+            .map(|row| text(self.visible(row)).trim_end().to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// The screen text with an SGR sequence wherever the pen changes. Every
+    /// line starts with the default pen and ends with a reset, so lines can
+    /// be taken on their own.
+    pub fn to_ansi(&self) -> String {
+        let mut lines = Vec::with_capacity(self.rows);
+        for row in 0..self.rows {
+            let mut line = String::new();
+            let mut pen = Pen::default();
+            for cell in self.visible(row) {
+                if cell.is_wide_tail() {
+                    continue;
+                }
+                if cell.pen != pen {
+                    pen = cell.pen;
+                    line.push_str(&pen.sgr());
+                }
+                line.push(cell.ch);
+            }
+            if !pen.is_default() {
+                line.push_str("\x1b[0m");
+            }
+            lines.push(line);
+        }
+        lines.join("\n")
+    }
+
+    /// The runs of equally styled cells of every non-blank row, as
+    /// `{row, text, runs: [{col, text, fg, bg, bold, ...}]}` with rows and
+    /// columns from 0. Runs with the default pen, default colors and unset
+    /// attributes are left out; palette colors are numbers and RGB ones
+    /// `"#rrggbb"` strings.
+    pub fn to_structured(&self) -> Value {
+        let mut lines = Vec::new();
+        for row in 0..self.rows {
+            let cells = self.visible(row);
+            if cells.is_empty() {
+                continue;
+            }
+            let mut runs = Vec::new();
+            let mut start = 0;
+            while start < cells.len() {
+                let pen = cells[start].pen;
+                let end = cells[start..]
+                    .iter()
+                    .position(|cell| cell.pen != pen)
+                    .map_or(cells.len(), |len| start + len);
+                if !pen.is_default() {
+                    let mut run = Map::new();
+                    run.insert("col".to_string(), json!(start));
+                    run.insert("text".to_string(), json!(text(&cells[start..end])));
+                    if let Some(fg) = pen.fg.json() {
+                        run.insert("fg".to_string(), fg);
+                    }
+                    if let Some(bg) = pen.bg.json() {
+                        run.insert("bg".to_string(), bg);
+                    }
+                    for (name, on, _) in pen.attributes() {
+                        if on {
+                            run.insert(name.to_string(), json!(true));
+                        }
+                    }
+                    runs.push(Value::Object(run));
+                }
+                start = end;
+            }
+            lines.push(json!({
+                "row": row,
+                "text": text(cells),
+                "runs": runs,
+            }));
+        }
+        Value::Array(lines)
+    }
+
+    pub fn render(&self, format: StyledFormat) -> StyledSnapshot {
+        match format {
+            StyledFormat::Ansi => StyledSnapshot {
+                text: self.to_ansi(),
+                lines: None,
+                cursor: self.cursor(),
+            },
+            StyledFormat::Structured => StyledSnapshot {
+                text: self.to_text(),
+                lines: Some(self.to_structured()),
+                cursor: self.cursor(),
+            },
+        }
+    }
+}
+
+/// A screen kept up to date with the output as it arrives, for the cursor
+/// position of plain snapshots. An escape sequence cut off at the end of a
+/// chunk waits for the next one.
+#[derive(Debug, Clone)]
+pub struct ScreenTracker {
+    screen: StyledScreen,
+    pending: String,
+}
+
+impl ScreenTracker {
+    /// Tracks from `screen`, e.g. one replayed after a resize
+    pub fn new(screen: StyledScreen) -> Self {
+        Self {
+            screen,
+            pending: String::new(),
+        }
+    }
+
+    pub fn blank(cols: usize, rows: usize) -> Self {
+        Self::new(StyledScreen::new(cols, rows))
+    }
+
+    pub fn feed(&mut self, output: &str) {
+        self.pending.push_str(output);
+        let complete = match unfinished_escape(&self.pending) {
+            Some(start) if self.pending.len() - start <= MAX_PENDING_ESCAPE => start,
+            // Nothing cut off, or a sequence too long to wait for
+            _ => self.pending.len(),
+        };
+        let rest = self.pending.split_off(complete);
+        self.screen.feed(&self.pending);
+        self.pending = rest;
+    }
+
+    pub fn cursor(&self) -> Cursor {
+        self.screen.cursor()
+    }
+}
+
+/// Longest escape sequence [`ScreenTracker`] holds back for the next chunk
+const MAX_PENDING_ESCAPE: usize = 4096;
+
+/// Where the escape sequence `output` ends in starts, if it is unfinished
+fn unfinished_escape(output: &str) -> Option<usize> {
+    let start = output.rfind(['\x1b', '\u{9b}', '\u{9d}', '\u{90}'])?;
+    let mut chars = output[start..].chars();
+    let finished = match chars.next()? {
+        '\x1b' => match chars.next() {
+            None => false,
+            Some('[') => chars.any(|ch| ('\x40'..='\x7e').contains(&ch)),
+            Some(']' | 'P' | '_' | '^') => chars.any(|ch| matches!(ch, '\x07' | '\u{9c}')),
+            Some('(' | ')' | '*' | '+' | '#' | '%') => chars.next().is_some(),
+            Some(_) => true,
+        },
+        '\u{9b}' => chars.any(|ch| ('\x40'..='\x7e').contains(&ch)),
+        _ => chars.any(|ch| matches!(ch, '\x07' | '\u{9c}')),
+    };
+    (!finished).then_some(start)
+}
+
+/// The characters of `cells`, one per character however wide
+fn text(cells: &[Cell]) -> String {
+    cells
+        .iter()
+        .filter(|cell| !cell.is_wide_tail())
+        .map(|cell| cell.ch)
+        .collect()
+}
+
+fn blank_grid(cols: usize, rows: usize) -> Grid {
+    vec![vec![Cell::blank(Pen::default()); cols]; rows]
+}
+
+/// Skips an OSC, DCS or similar string up to its BEL or ST terminator
+fn skip_string(chars: &mut Peekable<impl Iterator<Item = char>>) {
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\x07' | '\u{9c}' => return,
+            '\x1b' if chars.peek() == Some(&'\\') => {
+                chars.next();
+                return;
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay_keeps_colors_and_attributes() {
+        let screen = StyledScreen::replay(
+            "plain \x1b[1;31merror\x1b[0m\r\n\x1b[7m> item two \x1b[m rest\r\n\x1b[38;5;208;48;2;0;0;255mx",
+            20,
+            4,
+        );
+        assert_eq!(screen.to_text(), "plain error\n> item two  rest\nx\n");
+        assert_eq!(
+            screen.to_ansi(),
+            "plain \x1b[0;1;31merror\x1b[0m\n\x1b[0;7m> item two \x1b[0m rest\n\x1b[0;38;5;208;48;2;0;0;255mx\x1b[0m\n"
+        );
+
+        let lines = screen.to_structured();
+        assert_eq!(lines.as_array().unwrap().len(), 3);
+        assert_eq!(
+            lines[0]["runs"],
+            json!([{"col": 6, "text": "error", "fg": 1, "bold": true}])
+        );
+        assert_eq!(
+            lines[1]["runs"],
+            json!([{"col": 0, "text": "> item two ", "inverse": true}])
+        );
+        assert_eq!(lines[2]["runs"][0]["fg"], 208);
+        assert_eq!(lines[2]["runs"][0]["bg"], "#0000ff");
+    }
+
+    #[test]
+    fn test_replay_moves_erases_and_wraps() {
+        let screen = StyledScreen::replay(
+            "abcdefgh\x1b[2;3H\x1b[42mX\x1b[K\x1b[0m\u{9b}1;1Hz\x1b]0;title\x07",
+            5,
+            3,
+        );
+        // The erased rest of row 2 keeps the green background
+        assert_eq!(screen.to_text(), "zbcde\nfgX\n");
+        assert_eq!(
+            screen.to_structured()[1]["runs"],
+            json!([{"col": 2, "text": "X  ", "bg": 2}])
+        );
+
+        let scrolled = StyledScreen::replay("1\r\n2\r\n3\r\n4", 5, 3);
+        assert_eq!(scrolled.to_text(), "2\n3\n4");
+
+        // Inserting and deleting lines shift the rows below the cursor
+        let screen = StyledScreen::replay("1\r\n2\r\n3\x1b[2;1H\x1b[L", 5, 3);
+        assert_eq!(screen.to_text(), "1\n\n2");
+        let screen = StyledScreen::replay("1\r\n2\r\n3\x1b[1;1H\x1b[2M", 5, 3);
+        assert_eq!(screen.to_text(), "3\n\n");
+    }
+
+    #[test]
+    fn test_replay_large_terminal() {
+        // A grid costs this much per cell, twice over while the alternate
+        // screen is shown; the README's memory figures rely on it
+        assert_eq!(BYTES_PER_CELL, 20);
+
+        let (cols, rows) = (400, 150);
+        let mut output = String::new();
+        for line in 0..rows + 50 {
+            output.push_str(&format!("{:0width$}\r\n", line, width = cols));
+        }
+        output.push_str("\x1b[1mend\x1b[0m");
+        let screen = StyledScreen::replay(&output, cols, rows);
+        let text = screen.to_text();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), rows);
+        assert_eq!(lines[0], format!("{:0width$}", 51, width = cols));
+        assert_eq!(lines[rows - 1], "end");
+        assert_eq!(screen.cursor().row, rows);
+        assert!(screen.cell(rows - 1, 0).1.bold);
+    }
+
+    #[test]
+    fn test_replay_tracks_cursor() {
+        let screen = StyledScreen::replay("Password: ", 20, 3);
+        assert_eq!(
+            screen.cursor(),
+            Cursor {
+                row: 1,
+                col: 11,
+                visible: true
+            }
+        );
+
+        let screen = StyledScreen::replay("abcde\x1b[?25l", 5, 3);
+        assert_eq!(
+            screen.cursor(),
+            Cursor {
+                row: 1,
+                col: 5,
+                visible: false
+            }
+        );
+        let screen = StyledScreen::replay("\x1b[?25l\x1b[3;2H\x1b[?25h", 5, 3);
+        assert_eq!((screen.cursor().row, screen.cursor().col), (3, 2));
+        assert!(screen.cursor().visible);
+    }
+
+    #[test]
+    fn test_tracker_follows_output_split_anywhere() {
+        let output = "$ \x1b[1;32mls\x1b[0m\r\nfile\x1b]0;title\x07\x1b[3;4H\x1b[?25l";
+        let expected = StyledScreen::replay(output, 10, 4).cursor();
+        assert_eq!(
+            (expected.row, expected.col, expected.visible),
+            (3, 4, false)
+        );
+        for split in 0..=output.len() {
+            let mut tracker = ScreenTracker::blank(10, 4);
+            tracker.feed(&output[..split]);
+            tracker.feed(&output[split..]);
+            assert_eq!(tracker.cursor(), expected, "split at {}", split);
+        }
+
+        // A sequence that never ends is not held back forever
+        let mut tracker = ScreenTracker::blank(10, 4);
+        tracker.feed(&format!("\x1b]0;{}", "x".repeat(MAX_PENDING_ESCAPE)));
+        tracker.feed("ab");
+        assert!(tracker.pending.is_empty());
+    }
+
+    #[test]
+    fn test_replay_wide_characters() {
+        let screen = StyledScreen::replay("\x1b[1m日\x1b[0m本x", 6, 2);
+        assert_eq!(screen.to_text(), "日本x\n");
+        assert_eq!((screen.cursor().row, screen.cursor().col), (1, 6));
+        assert_eq!(screen.to_ansi(), "\x1b[0;1m日\x1b[0m本x\n");
+        let lines = screen.to_structured();
+        assert_eq!(lines[0]["text"], "日本x");
+        assert_eq!(
+            lines[0]["runs"],
+            json!([{"col": 0, "text": "日", "bold": true}])
+        );
+        assert_eq!(screen.cell(0, 1).0, ' ');
+
+        // One that would straddle the edge wraps whole
+        let screen = StyledScreen::replay("abcde日", 6, 2);
+        assert_eq!(screen.to_text(), "abcde\n日");
+
+        // Overwriting either half blanks the other
+        let screen = StyledScreen::replay("日本\x1b[1;2Hx", 6, 2);
+        assert_eq!(screen.to_text(), " x本\n");
+        let screen = StyledScreen::replay("日本\x1b[1;3Hx", 6, 2);
+        assert_eq!(screen.to_text(), "日x\n");
+        let screen = StyledScreen::replay("日本\x1b[1;2H\x1b[K", 6, 2);
+        assert_eq!(screen.to_text(), "\n");
+
+        // Combining marks take no cell
+        assert_eq!(StyledScreen::replay("e\u{301}x", 6, 2).to_text(), "ex\n");
+    }
+
+    #[test]
+    fn test_replay_alternate_screen() {
+        let output = "shell$ \x1b[?1049h\x1b[H\x1b[1mtop\x1b[0m";
+        let screen = StyledScreen::replay(output, 10, 2);
+        assert_eq!(screen.to_text(), "top\n");
+
+        let screen = StyledScreen::replay(&format!("{}\x1b[?1049l", output), 10, 2);
+        assert_eq!(screen.to_text(), "shell$\n");
+        assert_eq!(
+            screen.render(StyledFormat::Structured).lines.unwrap()[0]["runs"],
+            json!([])
+        );
+    }
+}
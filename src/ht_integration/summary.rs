@@ -0,0 +1,278 @@
+//! Compact triage summary of a command's output, for `summarize: true` on
+//! `ht_execute_command`. Built only from the snapshot annotations and a few
+//! location patterns, so an agent can decide cheaply whether the full output
+//! is worth reading.
+//!
+//! Files are taken from positions printed while an error is being reported:
+//! from an error or stack-trace line until the next warning, so rustc's
+//! `-->` lines and pytest's `path:line:` footer after an error count but the
+//! locations of warnings do not.
+
+use crate::ht_integration::annotations::Annotation;
+use regex::Regex;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// Most file positions listed in a summary
+const MAX_FILES: usize = 20;
+
+/// `path:line[:col]` positions as compilers, test runners and runtimes print
+/// them, most specific first; the first pattern matching a line is used
+const LOCATION_PATTERNS: &[&str] = &[
+    // rustc: `  --> src/main.rs:2:5`
+    r"^\s*--> (?P<path>[^\s:]+):(?P<line>\d+):(?P<col>\d+)",
+    // python tracebacks: `  File "app.py", line 3, in main`
+    r#"File "(?P<path>[^"]+)", line (?P<line>\d+)"#,
+    // gcc/clang and node frames: `main.c:3:5: error: ...`,
+    // `    at main (/app/index.js:3:9)`
+    r"(?P<path>[\w./\\-]*\w\.\w+):(?P<line>\d+):(?P<col>\d+)",
+    // pytest failure footers: `tests/test_app.py:12: AssertionError`
+    r"^(?P<path>[\w./\\-]*\w\.\w+):(?P<line>\d+): ",
+];
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CommandSummary {
+    /// Unknown while a command is judged finished by a fixed wait
+    pub exit_code: Option<i32>,
+    pub duration_ms: u64,
+    /// Lines with at least one error annotation
+    pub error_lines: usize,
+    pub warning_lines: usize,
+    pub first_error: Option<String>,
+    pub last_error: Option<String>,
+    pub files: Vec<FileLocation>,
+    pub output_bytes: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileLocation {
+    pub path: String,
+    pub line: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub column: Option<u32>,
+}
+
+/// Summarizes `output` using its `annotations`
+pub fn summarize(
+    output: &str,
+    annotations: &[Annotation],
+    duration: Duration,
+    exit_code: Option<i32>,
+) -> CommandSummary {
+    let lines_of = |kind: &str| -> BTreeSet<usize> {
+        annotations
+            .iter()
+            .filter(|annotation| annotation.kind == kind)
+            .flat_map(|annotation| annotation.line..=annotation.end_line.unwrap_or(annotation.line))
+            .collect()
+    };
+    let errors = lines_of("error");
+    let warnings = lines_of("warning");
+    let traces = lines_of("stack-trace");
+
+    let lines: Vec<&str> = output.lines().collect();
+    let mut files: Vec<FileLocation> = Vec::new();
+    let mut in_error = false;
+    for (index, line) in lines.iter().enumerate() {
+        let line_number = index + 1;
+        if errors.contains(&line_number) || traces.contains(&line_number) {
+            in_error = true;
+        } else if warnings.contains(&line_number) {
+            in_error = false;
+        }
+        if !in_error {
+            continue;
+        }
+        for location in locations(line) {
+            if files.len() < MAX_FILES && !files.contains(&location) {
+                files.push(location);
+            }
+        }
+    }
+
+    let error_text = |line_number: Option<&usize>| {
+        line_number
+            .and_then(|n| lines.get(n - 1))
+            .map(|line| line.trim().to_string())
+    };
+    CommandSummary {
+        exit_code,
+        duration_ms: duration.as_millis() as u64,
+        error_lines: errors.len(),
+        warning_lines: warnings.difference(&errors).count(),
+        first_error: error_text(errors.first()),
+        last_error: error_text(errors.last()),
+        files,
+        output_bytes: output.len(),
+    }
+}
+
+/// File positions on one line, by the first location pattern that matches
+fn locations(line: &str) -> Vec<FileLocation> {
+    static PATTERNS: OnceLock<Vec<Regex>> = OnceLock::new();
+    let patterns = PATTERNS.get_or_init(|| {
+        LOCATION_PATTERNS
+            .iter()
+            // Fixed patterns, covered by the tests below
+            .map(|pattern| Regex::new(pattern).expect("location pattern"))
+            .collect()
+    });
+
+    for pattern in patterns {
+        let found: Vec<FileLocation> = pattern
+            .captures_iter(line)
+            .filter_map(|captures| {
+                Some(FileLocation {
+                    path: captures["path"].to_string(),
+                    line: captures["line"].parse().ok()?,
+                    column: captures
+                        .name("col")
+                        .and_then(|col| col.as_str().parse().ok()),
+                })
+            })
+            .collect();
+        if !found.is_empty() {
+            return found;
+        }
+    }
+    Vec::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ht_integration::annotations::Annotator;
+
+    fn summary(output: &str) -> CommandSummary {
+        let annotations = Annotator::default().annotate(output);
+        summarize(output, &annotations, Duration::from_millis(1500), None)
+    }
+
+    fn at(path: &str, line: u32, column: Option<u32>) -> FileLocation {
+        FileLocation {
+            path: path.to_string(),
+            line,
+            column,
+        }
+    }
+
+    #[test]
+    fn test_rustc() {
+        let output = "\
+warning: unused variable: `x`
+ --> src/lib.rs:1:5
+  |
+error[E0308]: mismatched types
+ --> src/main.rs:2:18
+  |
+2 |     let n: u32 = \"one\";
+  |            ---   ^^^^^ expected `u32`, found `&str`
+
+error: could not compile `app` (bin \"app\") due to 1 previous error
+$ ";
+        let summary = summary(output);
+        assert_eq!(summary.error_lines, 2);
+        assert_eq!(summary.warning_lines, 1);
+        assert_eq!(
+            summary.first_error.as_deref(),
+            Some("error[E0308]: mismatched types")
+        );
+        assert_eq!(
+            summary.last_error.as_deref(),
+            Some("error: could not compile `app` (bin \"app\") due to 1 previous error")
+        );
+        // The warning's location is not an error position
+        assert_eq!(summary.files, [at("src/main.rs", 2, Some(18))]);
+        assert_eq!(summary.output_bytes, output.len());
+        assert_eq!(summary.duration_ms, 1500);
+    }
+
+    #[test]
+    fn test_gcc() {
+        let output = "\
+main.c: In function 'main':
+main.c:3:5: warning: implicit declaration of function 'foo'
+main.c:4:12: error: expected ';' before '}' token
+    4 |   return 0
+      |            ^
+util.h:9:1: error: unknown type name 'bool'";
+        let summary = summary(output);
+        assert_eq!(summary.error_lines, 2);
+        assert_eq!(summary.warning_lines, 1);
+        assert_eq!(
+            summary.files,
+            [at("main.c", 4, Some(12)), at("util.h", 9, Some(1))]
+        );
+    }
+
+    #[test]
+    fn test_pytest() {
+        let output = "\
+    def test_sum():
+>       assert add(1, 1) == 3
+E       assert 2 == 3
+E        +  where 2 = add(1, 1)
+
+tests/test_math.py:7: AssertionError
+FAILED tests/test_math.py::test_sum - assert 2 == 3";
+        let summary = summary(output);
+        assert_eq!(summary.error_lines, 3);
+        assert_eq!(
+            summary.first_error.as_deref(),
+            Some("E       assert 2 == 3")
+        );
+        assert_eq!(summary.files, [at("tests/test_math.py", 7, None)]);
+    }
+
+    #[test]
+    fn test_node() {
+        let output = "\
+/app/index.js:3
+  undefinedFn();
+TypeError: undefinedFn is not a function
+    at main (/app/index.js:3:3)
+    at Object.<anonymous> (/app/lib/run.js:10:1)
+    at Module._compile (node:internal/modules/cjs/loader:1105:14)";
+        let summary = summary(output);
+        assert_eq!(summary.error_lines, 1);
+        assert_eq!(
+            summary.files,
+            [
+                at("/app/index.js", 3, Some(3)),
+                at("/app/lib/run.js", 10, Some(1))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_python_traceback() {
+        let output = "\
+Traceback (most recent call last):
+  File \"app.py\", line 3, in <module>
+    main()
+  File \"lib/calc.py\", line 1, in main
+    def main(): 1 / 0
+ZeroDivisionError: division by zero";
+        let summary = summary(output);
+        assert_eq!(summary.error_lines, 1);
+        assert_eq!(
+            summary.last_error.as_deref(),
+            Some("ZeroDivisionError: division by zero")
+        );
+        assert_eq!(
+            summary.files,
+            [at("app.py", 3, None), at("lib/calc.py", 1, None)]
+        );
+    }
+
+    #[test]
+    fn test_clean_output() {
+        let summary = summary("$ ls\nCargo.toml  src\nListening on http://127.0.0.1:3000/\n$ ");
+        assert_eq!(summary.error_lines, 0);
+        assert_eq!(summary.first_error, None);
+        assert!(summary.files.is_empty());
+    }
+}
@@ -0,0 +1,219 @@
+//! Sessions that follow a file, for `ht_tail_file`.
+//!
+//! A tail session runs `tail` (`Get-Content -Wait` on Windows) on one file
+//! and is shared by every call naming that file. Its output lines are kept
+//! in a [`TailBuffer`] as they arrive, so lines that scrolled off the screen
+//! can still be paged through, and `filterPattern` is applied to the buffer
+//! when it is read. The session takes no input, and is closed once the file
+//! is gone.
+//!
+//! The command waits for a line on stdin before starting, with echo off, so
+//! the buffer can subscribe to the output first and misses none of it.
+
+use crate::ht_integration::control_sequences;
+use bytes::Bytes;
+use regex::Regex;
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// Lines printed to start with unless `lines` says otherwise
+pub const DEFAULT_TAIL_LINES: usize = 10;
+
+/// Largest `lines`
+pub const MAX_TAIL_LINES: usize = 10_000;
+
+/// Lines kept per tail session; older ones are dropped
+pub const TAIL_BUFFER_LINES: usize = 10_000;
+
+/// Most lines returned by one call
+pub const TAIL_PAGE_LINES: usize = 200;
+
+/// The command following `path`, printing its last `lines` lines first.
+/// Without `follow` it exits after them. Sessions run their command joined
+/// with spaces by a shell, so the path is quoted for it.
+pub fn tail_command(path: &str, lines: usize, follow: bool, windows: bool) -> Vec<String> {
+    if windows {
+        let script = format!(
+            "$null = [Console]::ReadLine(); Get-Content -LiteralPath '{}' -Tail {}{}",
+            path.replace('\'', "''"),
+            lines,
+            if follow { " -Wait" } else { "" }
+        );
+        return vec![
+            "powershell.exe".to_string(),
+            "-NoProfile".to_string(),
+            "-Command".to_string(),
+            script,
+        ];
+    }
+    // -F keeps following a file that is rotated or recreated
+    vec![format!(
+        "stty -echo; read -r _; exec tail -n {} {}'{}'",
+        lines,
+        if follow { "-F " } else { "" },
+        path.replace('\'', r"'\''")
+    )]
+}
+
+/// What makes a session a tail session
+#[derive(Debug, Clone)]
+pub struct TailSession {
+    /// The file, canonicalized; calls naming it share the session
+    pub path: PathBuf,
+    pub follow: bool,
+    pub buffer: Arc<TailBuffer>,
+}
+
+/// Lines printed by a tail session, numbered from 0 in the order they came
+#[derive(Debug, Default)]
+pub struct TailBuffer {
+    inner: Mutex<TailLines>,
+}
+
+#[derive(Debug, Default)]
+struct TailLines {
+    lines: VecDeque<String>,
+    /// Number of the first line in `lines`
+    first: u64,
+    /// Output after the last line end
+    partial: String,
+}
+
+/// Lines read from a [`TailBuffer`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TailPage {
+    /// Matching lines with their numbers
+    pub lines: Vec<(u64, String)>,
+    /// Where the next read continues
+    pub next_cursor: u64,
+    /// Lines past the cursor that were dropped from the buffer before this
+    /// read
+    pub dropped: u64,
+}
+
+impl TailBuffer {
+    /// Fills the buffer from `output` until the session ends
+    pub fn collect(self: &Arc<Self>, mut output: broadcast::Receiver<Bytes>) {
+        let buffer = Arc::clone(self);
+        tokio::spawn(async move {
+            loop {
+                match output.recv().await {
+                    Ok(chunk) => buffer.push(&chunk),
+                    // Lines lost to a slow reader are lost for good
+                    Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    /// Adds PTY output. Blank lines are skipped.
+    pub fn push(&self, chunk: &[u8]) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.partial.push_str(&String::from_utf8_lossy(chunk));
+        while let Some(end) = inner.partial.find('\n') {
+            let line: String = inner.partial.drain(..=end).collect();
+            let line = control_sequences::strip(&line).trim_end().to_string();
+            if line.is_empty() {
+                continue;
+            }
+            inner.lines.push_back(line);
+            if inner.lines.len() > TAIL_BUFFER_LINES {
+                inner.lines.pop_front();
+                inner.first += 1;
+            }
+        }
+    }
+
+    /// Number the next line will get
+    pub fn end(&self) -> u64 {
+        let inner = self.inner.lock().unwrap();
+        inner.first + inner.lines.len() as u64
+    }
+
+    /// Up to `limit` lines from `cursor` on that match `filter`
+    pub fn page(&self, cursor: u64, filter: Option<&Regex>, limit: usize) -> TailPage {
+        let inner = self.inner.lock().unwrap();
+        let start = cursor.max(inner.first);
+        let mut page = TailPage {
+            lines: Vec::new(),
+            next_cursor: start,
+            dropped: start - cursor.min(start),
+        };
+        let skip = (start - inner.first) as usize;
+        for (offset, line) in inner.lines.iter().enumerate().skip(skip) {
+            if page.lines.len() == limit {
+                break;
+            }
+            let number = inner.first + offset as u64;
+            page.next_cursor = number + 1;
+            if filter.map_or(true, |filter| filter.is_match(line)) {
+                page.lines.push((number, line.clone()));
+            }
+        }
+        page
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tail_command() {
+        assert_eq!(
+            tail_command("/var/log/app.log", 20, true, false),
+            ["stty -echo; read -r _; exec tail -n 20 -F '/var/log/app.log'"]
+        );
+        assert_eq!(
+            tail_command("/tmp/it's here", 5, false, false),
+            [r"stty -echo; read -r _; exec tail -n 5 '/tmp/it'\''s here'"]
+        );
+
+        let command = tail_command(r"C:\logs\it's.log", 5, true, true);
+        assert_eq!(command[0], "powershell.exe");
+        assert!(
+            command[3].ends_with(r"Get-Content -LiteralPath 'C:\logs\it''s.log' -Tail 5 -Wait"),
+            "{}",
+            command[3]
+        );
+        assert!(!tail_command("a.log", 5, false, true)[3].contains("-Wait"));
+    }
+
+    #[test]
+    fn test_buffer_splits_lines_across_chunks() {
+        let buffer = TailBuffer::default();
+        buffer.push(b"INFO start\r\nERROR dis");
+        buffer.push(b"k full\r\n\r\nINFO \x1b[1mdone\x1b[0m\r\npartial");
+        assert_eq!(buffer.end(), 3);
+
+        let page = buffer.page(0, None, 10);
+        let texts: Vec<&str> = page.lines.iter().map(|(_, text)| text.as_str()).collect();
+        assert_eq!(texts, ["INFO start", "ERROR disk full", "INFO done"]);
+        assert_eq!(page.next_cursor, 3);
+
+        let errors = Regex::new("ERROR").unwrap();
+        let page = buffer.page(0, Some(&errors), 10);
+        assert_eq!(page.lines, [(1, "ERROR disk full".to_string())]);
+        assert_eq!(page.next_cursor, 3);
+        assert_eq!(buffer.page(3, Some(&errors), 10).lines, []);
+    }
+
+    #[test]
+    fn test_page_limit_and_dropped_lines() {
+        let buffer = TailBuffer::default();
+        for n in 0..TAIL_BUFFER_LINES + 5 {
+            buffer.push(format!("line {}\n", n).as_bytes());
+        }
+        let page = buffer.page(0, None, 2);
+        assert_eq!(page.dropped, 5);
+        assert_eq!(
+            page.lines,
+            [(5, "line 5".to_string()), (6, "line 6".to_string())]
+        );
+        assert_eq!(page.next_cursor, 7);
+        assert_eq!(buffer.page(page.next_cursor, None, 1).dropped, 0);
+    }
+}
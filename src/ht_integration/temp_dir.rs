@@ -0,0 +1,222 @@
+//! Per-session scratch directories created under a configured root
+
+use crate::error::{HtMcpError, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// Environment variable holding the directory's path inside the session
+pub const SESSION_TMP_ENV_VAR: &str = "HT_SESSION_TMP";
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TempDirConfig {
+    /// Directory under which session temp dirs are created; defaults to
+    /// `ht-mcp-sessions` in the system temp dir
+    pub root: Option<PathBuf>,
+    /// Leave the directory in place for inspection when the session's
+    /// command exited with a non-zero status or session creation failed
+    pub keep_on_error: bool,
+}
+
+impl TempDirConfig {
+    pub fn root(&self) -> PathBuf {
+        self.root
+            .clone()
+            .unwrap_or_else(|| std::env::temp_dir().join("ht-mcp-sessions"))
+    }
+}
+
+/// A session's temporary directory. It is only ever removed through
+/// `remove`, which refuses paths outside the root it was created under.
+#[derive(Debug, Clone)]
+pub struct SessionTempDir {
+    path: PathBuf,
+    root: PathBuf,
+}
+
+impl SessionTempDir {
+    /// Creates `<root>/<session_id>`, creating the root if needed
+    pub fn create(root: &Path, session_id: &str) -> Result<Self> {
+        let io_error = |what: &str, path: &Path, e: std::io::Error| {
+            HtMcpError::Internal(format!("Failed to {} {}: {}", what, path.display(), e))
+        };
+
+        std::fs::create_dir_all(root).map_err(|e| io_error("create temp root", root, e))?;
+        let root = root
+            .canonicalize()
+            .map_err(|e| io_error("resolve temp root", root, e))?;
+        let path = root.join(session_id);
+        std::fs::create_dir(&path).map_err(|e| io_error("create temp dir", &path, e))?;
+
+        // Sessions may belong to different users; keep them out of each
+        // other's scratch space
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o700))
+                .map_err(|e| io_error("restrict temp dir", &path, e))?;
+        }
+
+        Ok(Self { path, root })
+    }
+
+    /// Hands the directory to the user a `runAs` session runs as
+    #[cfg(unix)]
+    pub fn set_owner(&self, uid: u32, gid: u32) -> Result<()> {
+        use nix::unistd::{chown, Gid, Uid};
+
+        chown(
+            &self.path,
+            Some(Uid::from_raw(uid)),
+            Some(Gid::from_raw(gid)),
+        )
+        .map_err(|e| {
+            HtMcpError::Internal(format!(
+                "Failed to change owner of temp dir {}: {}",
+                self.path.display(),
+                e
+            ))
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Deletes the directory and its contents. Fails without touching
+    /// anything unless the directory is a real (non-symlink) direct child of
+    /// the temp root.
+    pub fn remove(&self) -> Result<()> {
+        check_within_root(&self.path, &self.root)?;
+        std::fs::remove_dir_all(&self.path).map_err(|e| {
+            HtMcpError::Internal(format!(
+                "Failed to remove temp dir {}: {}",
+                self.path.display(),
+                e
+            ))
+        })
+    }
+
+    /// Removes the directory unless `keep` is set; failures are only logged
+    /// since the session is going away regardless
+    pub fn cleanup(&self, keep: bool) {
+        if keep {
+            info!(
+                "Keeping temp dir {} of failed session for inspection",
+                self.path.display()
+            );
+        } else if let Err(e) = self.remove() {
+            warn!("{}", e);
+        }
+    }
+}
+
+fn check_within_root(path: &Path, root: &Path) -> Result<()> {
+    let refuse = |reason: &str| {
+        Err(HtMcpError::Internal(format!(
+            "Refusing to remove {}: {}",
+            path.display(),
+            reason
+        )))
+    };
+
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) => return refuse(&e.to_string()),
+    };
+    if !metadata.is_dir() {
+        return refuse("not a directory");
+    }
+    let (Ok(path), Ok(root)) = (path.canonicalize(), root.canonicalize()) else {
+        return refuse("cannot resolve path");
+    };
+    if path.parent() != Some(root.as_path()) {
+        return refuse(&format!("not inside temp root {}", root.display()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!(
+            "ht-mcp-temp-dir-test-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&root);
+        root
+    }
+
+    #[test]
+    fn test_create_and_remove() {
+        let root = scratch_root("remove");
+        let dir = SessionTempDir::create(&root, "session-1").unwrap();
+        std::fs::write(dir.path().join("scratch.txt"), "data").unwrap();
+
+        dir.remove().unwrap();
+        assert!(!dir.path().exists());
+        assert!(root.exists(), "the root itself is kept");
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_refuses_paths_outside_root() {
+        let root = scratch_root("outside");
+        let outside = scratch_root("outside-victim");
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::create_dir_all(&root).unwrap();
+
+        let escaped = SessionTempDir {
+            path: root.join("..").join(outside.file_name().unwrap()),
+            root: root.clone(),
+        };
+        assert!(escaped.remove().is_err());
+        assert!(outside.exists());
+
+        let root_itself = SessionTempDir {
+            path: root.clone(),
+            root: root.clone(),
+        };
+        assert!(root_itself.remove().is_err());
+        assert!(root.exists());
+
+        std::fs::remove_dir_all(&root).unwrap();
+        std::fs::remove_dir_all(&outside).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_refuses_symlinks() {
+        let root = scratch_root("symlink");
+        let target = scratch_root("symlink-target");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::create_dir_all(&target).unwrap();
+        std::os::unix::fs::symlink(&target, root.join("session-1")).unwrap();
+
+        let linked = SessionTempDir {
+            path: root.join("session-1"),
+            root: root.clone(),
+        };
+        assert!(linked.remove().is_err());
+        assert!(target.exists());
+
+        std::fs::remove_dir_all(&root).unwrap();
+        std::fs::remove_dir_all(&target).unwrap();
+    }
+
+    #[test]
+    fn test_cleanup_keeps_dir_on_error() {
+        let root = scratch_root("keep");
+        let dir = SessionTempDir::create(&root, "session-1").unwrap();
+
+        dir.cleanup(true);
+        assert!(dir.path().exists());
+        dir.cleanup(false);
+        assert!(!dir.path().exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,118 @@
+//! Approximate token counts of text handed to clients, for `probeOnly` and
+//! `maxTokens`.
+//!
+//! The estimate is the byte length divided by four, rounded up: close for
+//! English and code under common BPE tokenizers, and an overestimate for
+//! runs of spaces and box-drawing characters, which is the safe side for a
+//! budget. There is no tokenizer here; clients needing an exact count should
+//! count the returned text themselves.
+
+/// Bytes per token assumed by [`estimate_tokens`]
+pub const BYTES_PER_TOKEN: usize = 4;
+
+/// Approximate number of tokens in `text`
+pub fn estimate_tokens(text: &str) -> usize {
+    (text.len() + BYTES_PER_TOKEN - 1) / BYTES_PER_TOKEN
+}
+
+/// Size of a text without the text, for `probeOnly`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TextSize {
+    pub bytes: usize,
+    pub lines: usize,
+    pub estimated_tokens: usize,
+}
+
+impl TextSize {
+    pub fn of(text: &str) -> Self {
+        Self {
+            bytes: text.len(),
+            lines: text.lines().count(),
+            estimated_tokens: estimate_tokens(text),
+        }
+    }
+
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "bytes": self.bytes,
+            "lineCount": self.lines,
+            "estimatedTokens": self.estimated_tokens
+        })
+    }
+}
+
+/// `text` cut to about `max_tokens`, keeping its end: the bottom of a
+/// terminal is the most recent output. Whole lines are dropped from the top;
+/// a last line too long on its own keeps its end. Returns the lines dropped
+/// entirely, `None` if nothing was cut.
+pub fn truncate_to_budget(text: &str, max_tokens: usize) -> (&str, Option<usize>) {
+    let budget = max_tokens.saturating_mul(BYTES_PER_TOKEN);
+    if text.len() <= budget {
+        return (text, None);
+    }
+
+    let mut start = text.len() - budget;
+    while !text.is_char_boundary(start) {
+        start += 1;
+    }
+    // Skip to the next whole line, unless the cut is in the last one
+    if start > 0 && text.as_bytes()[start - 1] != b'\n' {
+        if let Some(newline) = text[start..].find('\n') {
+            if start + newline + 1 < text.len() {
+                start += newline + 1;
+            }
+        }
+    }
+    let dropped = text[..start].matches('\n').count();
+    (&text[start..], Some(dropped))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate_tokens() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("abc"), 1);
+        assert_eq!(estimate_tokens("abcd"), 1);
+        assert_eq!(estimate_tokens("hello world"), 3);
+        // Bytes, not characters: "é" is two bytes, "─" three
+        assert_eq!(estimate_tokens("éé"), 1);
+        assert_eq!(estimate_tokens("────"), 3);
+
+        let size = TextSize::of("$ ls\nCargo.toml\nsrc\n");
+        assert_eq!(
+            size,
+            TextSize {
+                bytes: 20,
+                lines: 3,
+                estimated_tokens: 5
+            }
+        );
+    }
+
+    #[test]
+    fn test_truncate_to_budget_keeps_whole_lines_from_the_end() {
+        let text = "line one\nline two\nline three\n$ ";
+        assert_eq!(truncate_to_budget(text, 100), (text, None));
+
+        // 5 tokens, 20 bytes: "line three\n$ " is 13 bytes, "line two\n"
+        // would make it 22
+        let (kept, dropped) = truncate_to_budget(text, 5);
+        assert_eq!(kept, "line three\n$ ");
+        assert_eq!(dropped, Some(2));
+        assert!(estimate_tokens(kept) <= 5);
+    }
+
+    #[test]
+    fn test_truncate_to_budget_cuts_a_long_last_line() {
+        let text = "short\nxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx─end";
+        let (kept, dropped) = truncate_to_budget(text, 2);
+        assert!(kept.ends_with("─end"), "{:?}", kept);
+        assert!(kept.len() <= 8);
+        assert_eq!(dropped, Some(1));
+
+        assert_eq!(truncate_to_budget("abc", 0), ("", Some(0)));
+    }
+}
@@ -0,0 +1,551 @@
+//! Finding out which tools a session's shell has, for `ht_probe_tools`.
+//!
+//! Every tool is looked up by one command typed at the prompt, so a probe
+//! takes a single round trip however many tools it asks about. The command
+//! prints a tab-separated record per result, each starting with a marker
+//! unique to the probe. The typed line spells the marker with an empty `""`
+//! in the middle, so its echo is never taken for a record.
+//!
+//! Tools are resolved to files, never to the aliases and functions an
+//! interactive shell's rc files define (Debian's `grep` alias, say): bash
+//! uses `type -P`, zsh and ksh `whence -p`, fish `command -v`, which only
+//! searches `PATH`, and PowerShell `Get-Command` for applications, which
+//! finds what `where.exe` finds and also works off Windows. Other POSIX
+//! shells use `command -v`, and only an answer that is a path counts.
+//!
+//! Programs in [`VERSIONED_TOOLS`] are run with `--version`, reporting its
+//! exit status and the first lines of what it printed to stdout and stderr.
+//! That output is what tells the GNU, BSD and BusyBox utilities apart.
+
+use crate::error::{HtMcpError, Result};
+use crate::ht_integration::completion;
+use crate::ht_integration::control_sequences;
+use bytes::Bytes;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use uuid::Uuid;
+
+/// Tools probed when the call names none
+pub const DEFAULT_TOOLS: &[&str] = &[
+    "git", "jq", "rg", "python3", "node", "npm", "cargo", "make", "curl", "sed", "grep", "awk",
+];
+
+/// Tools run with `--version`; others are only looked up, as any program
+/// could do something other than print a version
+pub const VERSIONED_TOOLS: &[&str] = &[
+    "awk", "bash", "cargo", "curl", "date", "docker", "find", "gcc", "git", "go", "grep", "java",
+    "jq", "make", "node", "npm", "perl", "pip3", "python", "python3", "rg", "ruby", "rustc", "sed",
+    "tar", "zsh",
+];
+
+/// Utilities that come in GNU, BSD and BusyBox versions with different
+/// options; they are reported with a `flavor`
+const FLAVORED_TOOLS: &[&str] = &["awk", "date", "find", "grep", "sed", "tar"];
+
+/// Most tools one probe may ask about
+pub const MAX_TOOLS: usize = 64;
+
+/// Lines of a tool's `--version` output reported; BusyBox applets print
+/// their whole usage
+const MAX_VERSION_LINES: usize = 20;
+
+/// How long the probe may take to print its last record
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How the probe command is written for the session's shell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeShell {
+    Posix,
+    Fish,
+    PowerShell,
+}
+
+impl ProbeShell {
+    /// The dialect for a session started with `command`, if it runs a shell
+    /// the probe can be written for
+    pub fn for_command(command: &[String]) -> Option<Self> {
+        if completion::is_posix_shell(command) {
+            return Some(Self::Posix);
+        }
+        let program = command.first()?;
+        let name = program.rsplit(['/', '\\']).next().unwrap_or(program);
+        match name.strip_suffix(".exe").unwrap_or(name) {
+            "fish" => Some(Self::Fish),
+            "pwsh" | "powershell" => Some(Self::PowerShell),
+            _ => None,
+        }
+    }
+}
+
+/// A tool as the probe found it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ToolInfo {
+    pub present: bool,
+    pub path: Option<String>,
+    /// First line of `--version`, for [`VERSIONED_TOOLS`] that have one
+    pub version: Option<String>,
+    /// `gnu`, `bsd`, `busybox`, `other` or `unknown`, for utilities whose
+    /// options differ between them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flavor: Option<&'static str>,
+}
+
+/// The shell the session runs, as it reports itself
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ShellInfo {
+    pub name: String,
+    pub version: Option<String>,
+}
+
+/// What probes of a session found so far
+#[derive(Debug, Clone, Default)]
+pub struct ToolProbe {
+    pub shell: Option<ShellInfo>,
+    pub tools: BTreeMap<String, ToolInfo>,
+}
+
+/// One probe command and the marker its records start with
+#[derive(Debug, Clone)]
+pub struct Probe {
+    marker: String,
+    shell: ProbeShell,
+    tools: Vec<String>,
+}
+
+impl Probe {
+    /// A probe of `tools`, whose names have been checked to be plain
+    /// program names
+    pub fn new(shell: ProbeShell, tools: Vec<String>) -> Self {
+        Self {
+            marker: format!("__HT_PROBE_{}", Uuid::new_v4().simple()),
+            shell,
+            tools,
+        }
+    }
+
+    /// The command to type, on one line. It starts with a space, which keeps
+    /// it out of the history of shells ignoring such lines.
+    pub fn command(&self) -> String {
+        // Spelled so that the typed line does not contain the marker
+        let typed_marker = self.marker.replacen("_PROBE", "_\"\"PROBE", 1);
+        let versioned: Vec<&str> = self
+            .tools
+            .iter()
+            .map(String::as_str)
+            .filter(|tool| VERSIONED_TOOLS.contains(tool))
+            .collect();
+        match self.shell {
+            ProbeShell::Posix => {
+                let mut command = format!(
+                    " (m={}; for t in {}; do if [ -n \"$BASH_VERSION\" ]; then p=$(type -P \"$t\"); elif [ -n \"$ZSH_VERSION$KSH_VERSION\" ]; then p=$(whence -p \"$t\"); else p=$(command -v \"$t\"); fi 2>/dev/null; case $p in /*) ;; *) p= ;; esac; printf '%s\\ttool\\t%s\\t%s\\n' \"$m\" \"$t\" \"$p\";",
+                    typed_marker,
+                    self.tools.join(" ")
+                );
+                if !versioned.is_empty() {
+                    command.push_str(&format!(
+                        " case $t in {}) if [ -n \"$p\" ]; then v=$(\"$p\" --version 2>&1 </dev/null); printf '%s\\tstatus\\t%s\\t%s\\n' \"$m\" \"$t\" \"$?\"; i=0; printf '%s\\n' \"$v\" | while IFS= read -r l && [ $i -lt {} ]; do i=$((i+1)); printf '%s\\toutput\\t%s\\t%s\\n' \"$m\" \"$t\" \"$l\"; done; fi ;; esac;",
+                        versioned.join("|"),
+                        MAX_VERSION_LINES
+                    ));
+                }
+                command.push_str(" done; printf '%s\\tshell\\t%s\\t%s\\n' \"$m\" \"${BASH_VERSION:+bash}${ZSH_VERSION:+zsh}${KSH_VERSION:+ksh}\" \"$BASH_VERSION$ZSH_VERSION$KSH_VERSION\"; printf '%s\\tend\\n' \"$m\")");
+                command
+            }
+            ProbeShell::Fish => {
+                let mut command = format!(
+                    " begin; set -l m {}; for t in {}; set -l p (command -v $t 2>/dev/null); string match -q -- '/*' \"$p\"; or set p ''; printf '%s\\ttool\\t%s\\t%s\\n' $m $t \"$p\";",
+                    typed_marker,
+                    self.tools.join(" ")
+                );
+                if !versioned.is_empty() {
+                    command.push_str(&format!(
+                        " if contains -- $t {}; and test -n \"$p\"; set -l v ($p --version 2>&1 </dev/null); printf '%s\\tstatus\\t%s\\t%s\\n' $m $t $status; for l in $v[1..{}]; printf '%s\\toutput\\t%s\\t%s\\n' $m $t $l; end; end;",
+                        versioned.join(" "),
+                        MAX_VERSION_LINES
+                    ));
+                }
+                command.push_str(
+                    " end; printf '%s\\tshell\\tfish\\t%s\\n' $m $version; printf '%s\\tend\\n' $m; end",
+                );
+                command
+            }
+            ProbeShell::PowerShell => {
+                let (head, tail) = self.marker.split_at("__HT_".len());
+                let list = |tools: &[&str]| {
+                    tools
+                        .iter()
+                        .map(|tool| format!("'{}'", tool))
+                        .collect::<Vec<_>>()
+                        .join(",")
+                };
+                let tools: Vec<&str> = self.tools.iter().map(String::as_str).collect();
+                let mut command = format!(
+                    " & {{ $m = '{}' + '{}'; foreach ($t in @({})) {{ $p = (Get-Command $t -CommandType Application -ErrorAction SilentlyContinue | Select-Object -First 1).Source; \"$m`ttool`t$t`t$p\";",
+                    head,
+                    tail,
+                    list(&tools)
+                );
+                if !versioned.is_empty() {
+                    command.push_str(&format!(
+                        " if ($p -and @({}) -contains $t) {{ $v = & $p --version 2>&1 | Select-Object -First {}; \"$m`tstatus`t$t`t$LASTEXITCODE\"; foreach ($l in $v) {{ \"$m`toutput`t$t`t$l\" }} }}",
+                        list(&versioned),
+                        MAX_VERSION_LINES
+                    ));
+                }
+                command.push_str(
+                    " }; \"$m`tshell`tpowershell`t$($PSVersionTable.PSVersion)\"; \"$m`tend\" }",
+                );
+                command
+            }
+        }
+    }
+
+    /// Reads `output`, subscribed before the command was typed, until the
+    /// probe's last record, and returns everything read
+    pub async fn collect(
+        &self,
+        mut output: broadcast::Receiver<Bytes>,
+        timeout: Duration,
+    ) -> Result<String> {
+        let read = async {
+            let mut text = String::new();
+            loop {
+                match output.recv().await {
+                    Ok(chunk) => {
+                        text.push_str(&String::from_utf8_lossy(&chunk));
+                        if self.records(&text).any(|record| record == ["end"]) {
+                            return Ok(text);
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        return Err(HtMcpError::Internal(format!(
+                            "Probe output went by unread ({} chunks)",
+                            skipped
+                        )))
+                    }
+                    Err(RecvError::Closed) => {
+                        return Err(HtMcpError::SessionExited(
+                            "Session exited during the probe".to_string(),
+                        ))
+                    }
+                }
+            }
+        };
+        tokio::time::timeout(timeout, read).await.map_err(|_| {
+            HtMcpError::Timeout(format!(
+                "The probe did not finish within {}s",
+                timeout.as_secs()
+            ))
+        })?
+    }
+
+    /// What `output` says about the shell and each probed tool. A tool
+    /// without a record is an error, as its lookup went missing.
+    pub fn parse(
+        &self,
+        output: &str,
+        program: &str,
+    ) -> Result<(ShellInfo, Vec<(String, ToolInfo)>)> {
+        let mut paths: BTreeMap<&str, &str> = BTreeMap::new();
+        let mut statuses: BTreeMap<&str, &str> = BTreeMap::new();
+        let mut version_output: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        let mut shell = None;
+        let records: Vec<Vec<String>> = self.records(output).collect();
+        for record in &records {
+            match record.as_slice() {
+                [kind, tool, path] if kind == "tool" => {
+                    paths.insert(tool, path);
+                }
+                [kind, tool, status] if kind == "status" => {
+                    statuses.insert(tool, status);
+                }
+                [kind, tool, line] if kind == "output" => {
+                    version_output.entry(tool).or_default().push(line);
+                }
+                [kind, name, version] if kind == "shell" => shell = Some((name, version)),
+                _ => {}
+            }
+        }
+
+        let mut tools = Vec::new();
+        for tool in &self.tools {
+            let path = paths.get(tool.as_str()).ok_or_else(|| {
+                HtMcpError::Internal(format!("The probe printed nothing for {}", tool))
+            })?;
+            let present = !path.is_empty();
+            let ran = statuses.get(tool.as_str()).map(|status| *status == "0");
+            let lines = version_output
+                .get(tool.as_str())
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+            // A failed --version prints an error, not a version
+            let version = match ran {
+                Some(true) => lines
+                    .iter()
+                    .find(|line| !line.trim().is_empty())
+                    .map(|line| line.to_string()),
+                _ => None,
+            };
+            let flavor = (present && FLAVORED_TOOLS.contains(&tool.as_str()))
+                .then(|| flavor_of(ran.unwrap_or(false), lines));
+            tools.push((
+                tool.clone(),
+                ToolInfo {
+                    present,
+                    path: present.then(|| path.to_string()),
+                    version,
+                    flavor,
+                },
+            ));
+        }
+
+        // A POSIX shell that sets none of the version variables, such as
+        // dash, goes by its program name
+        let program = program.rsplit(['/', '\\']).next().unwrap_or(program);
+        let shell = match shell {
+            Some((name, version)) => ShellInfo {
+                name: if name.is_empty() {
+                    program.to_string()
+                } else {
+                    name.clone()
+                },
+                version: (!version.is_empty()).then(|| version.clone()),
+            },
+            None => {
+                return Err(HtMcpError::Internal(
+                    "The probe printed nothing about the shell".to_string(),
+                ))
+            }
+        };
+        Ok((shell, tools))
+    }
+
+    /// The fields after the marker of every record in `output`
+    fn records<'a>(&'a self, output: &'a str) -> impl Iterator<Item = Vec<String>> + 'a {
+        output.lines().filter_map(move |line| {
+            let line = control_sequences::strip(line);
+            let at = line.find(&self.marker)?;
+            let fields = line[at + self.marker.len()..].trim_end_matches(['\r', '\n']);
+            let fields = fields.strip_prefix('\t').unwrap_or(fields);
+            Some(
+                fields
+                    .splitn(3, '\t')
+                    .map(|field| field.trim_end().to_string())
+                    .collect(),
+            )
+        })
+    }
+}
+
+/// Which implementation of a utility printed `output` for `--version`,
+/// which `succeeded` or not. BusyBox applets reject `--version` with their
+/// usage, whose banner names BusyBox, except `sed`, which says it is not GNU
+/// sed; BSD utilities reject it with a usage line of their own.
+fn flavor_of(succeeded: bool, output: &[&str]) -> &'static str {
+    let says = |text: &str| output.iter().any(|line| line.contains(text));
+    if says("BusyBox") || says("not GNU") {
+        "busybox"
+    } else if says("GNU") {
+        "gnu"
+    } else if output.iter().all(|line| line.trim().is_empty()) {
+        "unknown"
+    } else if !succeeded {
+        "bsd"
+    } else {
+        "other"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn probe(shell: ProbeShell, tools: &[&str]) -> Probe {
+        Probe::new(shell, tools.iter().map(|tool| tool.to_string()).collect())
+    }
+
+    #[test]
+    fn test_for_command() {
+        let command = |program: &str| vec![program.to_string()];
+        assert_eq!(
+            ProbeShell::for_command(&command("/bin/bash")),
+            Some(ProbeShell::Posix)
+        );
+        assert_eq!(
+            ProbeShell::for_command(&command("/usr/bin/fish")),
+            Some(ProbeShell::Fish)
+        );
+        assert_eq!(
+            ProbeShell::for_command(&command("C:\\Program Files\\PowerShell\\7\\pwsh.exe")),
+            Some(ProbeShell::PowerShell)
+        );
+        assert_eq!(ProbeShell::for_command(&command("python3")), None);
+        assert_eq!(ProbeShell::for_command(&[]), None);
+    }
+
+    #[test]
+    fn test_typed_command_hides_the_marker() {
+        for shell in [ProbeShell::Posix, ProbeShell::Fish, ProbeShell::PowerShell] {
+            let probe = probe(shell, &["jq", "sed"]);
+            let command = probe.command();
+            assert!(!command.contains(&probe.marker), "{}", command);
+            assert!(!command.contains('\n'), "{}", command);
+            assert!(command.starts_with(' '));
+            assert!(command.contains("--version"), "{}", command);
+        }
+        // Nothing to get versions of, so no version loop
+        assert!(!probe(ProbeShell::Posix, &["htop"])
+            .command()
+            .contains("--version"));
+    }
+
+    /// Whether every `open` in `text` is closed by a later `close`
+    fn balanced(text: &str, open: char, close: char) -> bool {
+        let mut depth = 0usize;
+        for c in text.chars() {
+            if c == open {
+                depth += 1;
+            } else if c == close {
+                let Some(outer) = depth.checked_sub(1) else {
+                    return false;
+                };
+                depth = outer;
+            }
+        }
+        depth == 0
+    }
+
+    #[test]
+    fn test_powershell_command_balances() {
+        for tools in [&["jq", "sed"][..], &["htop"][..]] {
+            let command = probe(ProbeShell::PowerShell, tools).command();
+            assert!(balanced(&command, '{', '}'), "{}", command);
+            assert!(balanced(&command, '(', ')'), "{}", command);
+            assert!(command.starts_with(" & {"), "{}", command);
+            assert!(command.ends_with('}'), "{}", command);
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let probe = probe(ProbeShell::Posix, &["jq", "sed", "rg"]);
+        let m = &probe.marker;
+        let output = format!(
+            "$ {}\r\n{m}\ttool\tjq\t/usr/bin/jq\r\n{m}\tstatus\tjq\t0\r\n{m}\toutput\tjq\tjq-1.7.1\r\n\
+             {m}\ttool\tsed\t/usr/bin/sed\r\n{m}\tstatus\tsed\t1\r\n\
+             {m}\toutput\tsed\tsed: illegal option -- -\r\n\
+             {m}\toutput\tsed\tusage: sed script [-Ealnru] [-i extension] [file ...]\r\n\
+             {m}\ttool\trg\t\r\n{m}\tshell\tbash\t5.2.15(1)-release\r\n{m}\tend\r\n$ ",
+            probe.command(),
+            m = m
+        );
+        let (shell, tools) = probe.parse(&output, "/bin/bash").unwrap();
+        assert_eq!(shell.name, "bash");
+        assert_eq!(shell.version.as_deref(), Some("5.2.15(1)-release"));
+
+        let tools: BTreeMap<_, _> = tools.into_iter().collect();
+        assert_eq!(
+            tools["jq"],
+            ToolInfo {
+                present: true,
+                path: Some("/usr/bin/jq".to_string()),
+                version: Some("jq-1.7.1".to_string()),
+                flavor: None,
+            }
+        );
+        // A sed rejecting --version is BSD sed, and its error is no version
+        assert_eq!(tools["sed"].flavor, Some("bsd"));
+        assert_eq!(tools["sed"].version, None);
+        assert!(!tools["rg"].present);
+        assert_eq!(tools["rg"].path, None);
+
+        // A shell that names neither itself nor its version
+        let output = format!(
+            "{m}\ttool\tjq\t\n{m}\ttool\tsed\t\n{m}\ttool\trg\t\n{m}\tshell\t\t\n",
+            m = m
+        );
+        let (shell, _) = probe.parse(&output, "/usr/bin/dash").unwrap();
+        assert_eq!(shell.name, "dash");
+        assert_eq!(shell.version, None);
+
+        let err = probe
+            .parse(&format!("{}\ttool\tjq\t\n", m), "bash")
+            .unwrap_err();
+        assert!(err.to_string().contains("sed"), "{}", err);
+    }
+
+    #[test]
+    fn test_flavor_of() {
+        assert_eq!(flavor_of(true, &["sed (GNU sed) 4.9"]), "gnu");
+        assert_eq!(flavor_of(true, &["GNU Awk 5.2.1, API 3.2"]), "gnu");
+        // BusyBox applets reject --version, naming BusyBox below the error
+        assert_eq!(
+            flavor_of(
+                false,
+                &[
+                    "grep: unrecognized option: version",
+                    "BusyBox v1.36.1 (2023-07-27 17:12:24 UTC) multi-call binary.",
+                ]
+            ),
+            "busybox"
+        );
+        assert_eq!(
+            flavor_of(true, &["This is not GNU sed version 4.0"]),
+            "busybox"
+        );
+        assert_eq!(
+            flavor_of(false, &["sed: illegal option -- -", "usage: sed script"]),
+            "bsd"
+        );
+        assert_eq!(flavor_of(true, &["mawk 1.3.4 20200120"]), "other");
+        assert_eq!(flavor_of(false, &[]), "unknown");
+        assert_eq!(flavor_of(true, &[""]), "unknown");
+    }
+
+    #[test]
+    fn test_posix_lookups_only_accept_paths() {
+        let command = probe(ProbeShell::Posix, &["grep"]).command();
+        // bash's type -P never answers with an alias, and answers from
+        // command -v that are not paths are dropped
+        assert!(command.contains("type -P \"$t\""), "{}", command);
+        assert!(
+            command.contains("case $p in /*) ;; *) p= ;; esac"),
+            "{}",
+            command
+        );
+        // --version runs the file found, with stderr captured
+        assert!(
+            command.contains("v=$(\"$p\" --version 2>&1 </dev/null)"),
+            "{}",
+            command
+        );
+    }
+
+    #[tokio::test]
+    async fn test_collect_stops_at_the_end_record() {
+        let probe = probe(ProbeShell::Posix, &["jq"]);
+        let (output_tx, output_rx) = broadcast::channel(16);
+        output_tx
+            .send(Bytes::from(format!(
+                "{}\ttool\tjq\t\r\n{}",
+                probe.marker, probe.marker
+            )))
+            .unwrap();
+        output_tx.send(Bytes::from("\tend\r\n")).unwrap();
+        let text = probe
+            .collect(output_rx, Duration::from_secs(1))
+            .await
+            .unwrap();
+        assert!(text.ends_with("\tend\r\n"));
+
+        let (output_tx, output_rx) = broadcast::channel::<Bytes>(16);
+        let err = probe
+            .collect(output_rx, Duration::from_millis(50))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::Timeout(_)), "{}", err);
+        drop(output_tx);
+    }
+}
@@ -0,0 +1,142 @@
+//! Typing a server-local file into a session, for `ht_type_file`: seeding a
+//! REPL or an editor with more text than is comfortable to pass as keys.
+//!
+//! Only files under the config's `type_file_paths` can be read. Line endings
+//! are typed as `\r`, which is what the Enter key sends, and the text goes
+//! out in chunks split on character boundaries so slow readers keep up.
+
+use crate::error::{HtMcpError, Result};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Largest file `ht_type_file` will type
+pub const MAX_FILE_BYTES: u64 = 1024 * 1024;
+pub const DEFAULT_CHUNK_BYTES: usize = 256;
+pub const DEFAULT_CHUNK_DELAY: Duration = Duration::from_millis(10);
+
+const PASTE_START: &str = "\x1b[200~";
+const PASTE_END: &str = "\x1b[201~";
+
+/// `path` made absolute and checked against `allowed`; relative paths, in
+/// either, are taken from `root` (or the server's working directory)
+pub fn resolve_allowed(path: &str, allowed: &[PathBuf], root: Option<&Path>) -> Result<PathBuf> {
+    if allowed.is_empty() {
+        return Err(HtMcpError::InvalidRequest(
+            "ht_type_file reads only files under type_file_paths, and the config sets none"
+                .to_string(),
+        ));
+    }
+    let absolute = |path: &Path| match root {
+        Some(root) => root.join(path),
+        None => path.to_path_buf(),
+    };
+    let resolved = absolute(Path::new(path))
+        .canonicalize()
+        .map_err(|e| HtMcpError::InvalidRequest(format!("Cannot read {}: {}", path, e)))?;
+    // Entries that do not exist cannot contain the file
+    let permitted = allowed
+        .iter()
+        .filter_map(|entry| absolute(entry).canonicalize().ok())
+        .any(|entry| resolved.starts_with(entry));
+    if !permitted {
+        return Err(HtMcpError::InvalidRequest(format!(
+            "{} is not under type_file_paths",
+            resolved.display()
+        )));
+    }
+    Ok(resolved)
+}
+
+/// The file's text, refusing files over [`MAX_FILE_BYTES`] and non-UTF-8
+/// content
+pub fn read_text(path: &Path) -> Result<String> {
+    let metadata = std::fs::metadata(path).map_err(|e| {
+        HtMcpError::InvalidRequest(format!("Cannot read {}: {}", path.display(), e))
+    })?;
+    if !metadata.is_file() {
+        return Err(HtMcpError::InvalidRequest(format!(
+            "{} is not a file",
+            path.display()
+        )));
+    }
+    if metadata.len() > MAX_FILE_BYTES {
+        return Err(HtMcpError::InvalidRequest(format!(
+            "{} is {} bytes; ht_type_file types at most {}",
+            path.display(),
+            metadata.len(),
+            MAX_FILE_BYTES
+        )));
+    }
+    let bytes = std::fs::read(path).map_err(|e| {
+        HtMcpError::InvalidRequest(format!("Cannot read {}: {}", path.display(), e))
+    })?;
+    String::from_utf8(bytes)
+        .map_err(|_| HtMcpError::InvalidRequest(format!("{} is not UTF-8 text", path.display())))
+}
+
+/// `text` as input chunks of at most `chunk_bytes` (or one character, if
+/// that is longer), wrapped in bracketed-paste markers when asked
+pub fn chunks(text: &str, chunk_bytes: usize, bracketed_paste: bool) -> Vec<String> {
+    let typed = text.replace("\r\n", "\r").replace('\n', "\r");
+    let mut chunks = Vec::new();
+    let mut rest = typed.as_str();
+    while !rest.is_empty() {
+        let mut end = chunk_bytes.min(rest.len());
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        if end == 0 {
+            end = rest.chars().next().map_or(rest.len(), char::len_utf8);
+        }
+        let (chunk, tail) = rest.split_at(end);
+        chunks.push(chunk.to_string());
+        rest = tail;
+    }
+    if bracketed_paste {
+        chunks.insert(0, PASTE_START.to_string());
+        chunks.push(PASTE_END.to_string());
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunks() {
+        assert_eq!(chunks("ab\ncd\r\nef", 3, false), ["ab\r", "cd\r", "ef"]);
+        // Multi-byte characters are never split
+        assert_eq!(chunks("héllo", 2, false), ["h", "é", "ll", "o"]);
+        assert_eq!(chunks("日本", 1, false), ["日", "本"]);
+        assert_eq!(
+            chunks("x = 1\n", 256, true),
+            [PASTE_START, "x = 1\r", PASTE_END]
+        );
+        assert!(chunks("", 256, false).is_empty());
+    }
+
+    #[test]
+    fn test_resolve_allowed() {
+        let root = std::env::temp_dir().join(format!("ht-mcp-type-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(root.join("seeds")).unwrap();
+        std::fs::write(root.join("seeds/init.py"), "x = 1\n").unwrap();
+        std::fs::write(root.join("secret.txt"), "no").unwrap();
+        let root = root.canonicalize().unwrap();
+        let allowed = [PathBuf::from("seeds")];
+
+        assert_eq!(
+            resolve_allowed("seeds/init.py", &allowed, Some(&root)).unwrap(),
+            root.join("seeds/init.py")
+        );
+        for path in ["secret.txt", "seeds/../secret.txt", "seeds/missing.py"] {
+            let err = resolve_allowed(path, &allowed, Some(&root)).unwrap_err();
+            assert!(matches!(err, HtMcpError::InvalidRequest(_)), "{}", path);
+        }
+        assert!(resolve_allowed("seeds/init.py", &[], Some(&root)).is_err());
+
+        assert_eq!(read_text(&root.join("seeds/init.py")).unwrap(), "x = 1\n");
+        assert!(read_text(&root.join("seeds")).is_err());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,336 @@
+//! Input typed in a session's web viewer.
+//!
+//! HT's web server only streams output to viewers and drops whatever they
+//! send, so its WebSocket is no way into the PTY, and nothing it received
+//! would go through the checks MCP input goes through. The connection guard
+//! therefore reads what a viewer sends once its connection is upgraded to a
+//! WebSocket. Control frames go on to HT's server; messages are kept from it
+//! and read as input, in the shape of ht's stdio API:
+//!
+//! - `{"type": "input", "payload": "ls\r"}` types `payload` as it is
+//! - `{"type": "sendKeys", "keys": ["C-c"]}` sends key names, as
+//!   `ht_send_keys` does
+//!
+//! The input reaches the session manager as a [`ViewerInput`]. It applies the
+//! input policy of MCP input tools (tail and read-only sessions, interaction
+//! locks) and audits the input with source `web` before writing it to the
+//! PTY. Other messages are ignored.
+
+use crate::ht_integration::keys;
+use ht_core::command::InputSeq;
+use serde::Deserialize;
+use std::io;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+/// Viewer messages waiting for the session manager, across all sessions
+pub const VIEWER_INPUT_CAPACITY: usize = 256;
+
+/// Largest message a viewer may send; a bigger one closes its connection
+pub const MAX_MESSAGE_BYTES: usize = 64 * 1024;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+/// Opcodes from here up are control frames: close, ping and pong
+const OPCODE_CONTROL: u8 = 0x8;
+
+/// Input a web viewer sent to a session
+pub struct ViewerInput {
+    pub session_id: String,
+    /// The viewer's address; loopback for viewers arriving through a tunnel
+    pub peer: String,
+    /// What was sent, as `send_keys` reports its keys
+    pub keys: Vec<String>,
+    pub seqs: Vec<InputSeq>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum ViewerMessage {
+    Input { payload: String },
+    SendKeys { keys: Vec<String> },
+}
+
+impl ViewerMessage {
+    /// The keys to report and the input to write
+    fn into_input(self) -> (Vec<String>, Vec<InputSeq>) {
+        match self {
+            ViewerMessage::Input { payload } => {
+                let seq = InputSeq::Standard(payload.clone());
+                (vec![payload], vec![seq])
+            }
+            ViewerMessage::SendKeys { keys: names } => {
+                let seqs = names.iter().map(|name| keys::parse(name)).collect();
+                (names, seqs)
+            }
+        }
+    }
+}
+
+/// One frame a client sent
+#[derive(Debug)]
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    /// The payload, unmasked
+    payload: Vec<u8>,
+    /// The frame as it was sent, for passing it on unchanged
+    raw: Vec<u8>,
+}
+
+/// Splits what a client sends into frames. Client frames are always
+/// masked; one that is not is a protocol error.
+#[derive(Debug, Default)]
+struct FrameReader {
+    buf: Vec<u8>,
+}
+
+impl FrameReader {
+    fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// The next frame, once all of it has arrived
+    fn next_frame(&mut self) -> io::Result<Option<Frame>> {
+        let buf = &self.buf;
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+        let fin = buf[0] & 0x80 != 0;
+        let opcode = buf[0] & 0x0f;
+        if buf[1] & 0x80 == 0 {
+            return Err(invalid("unmasked frame from a viewer"));
+        }
+        let (len, header_len) = match buf[1] & 0x7f {
+            126 if buf.len() >= 4 => (u64::from(u16::from_be_bytes([buf[2], buf[3]])), 4),
+            127 if buf.len() >= 10 => (u64::from_be_bytes(buf[2..10].try_into().unwrap()), 10),
+            126 | 127 => return Ok(None),
+            len => (u64::from(len), 2),
+        };
+        if len > MAX_MESSAGE_BYTES as u64 {
+            return Err(invalid("message from a viewer is too large"));
+        }
+        let end = header_len + 4 + len as usize;
+        if buf.len() < end {
+            return Ok(None);
+        }
+        let mask = &buf[header_len..header_len + 4];
+        let payload = buf[header_len + 4..end]
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| byte ^ mask[i % 4])
+            .collect();
+        let raw = self.buf.drain(..end).collect();
+        Ok(Some(Frame {
+            fin,
+            opcode,
+            payload,
+            raw,
+        }))
+    }
+}
+
+/// The WebSocket of one viewer, as the connection guard reads it
+pub struct ViewerSocket {
+    session_id: String,
+    peer: String,
+    input_tx: mpsc::Sender<ViewerInput>,
+    reader: FrameReader,
+    /// The fragments of the message being received
+    message: Vec<u8>,
+}
+
+impl ViewerSocket {
+    pub fn new(session_id: String, peer: String, input_tx: mpsc::Sender<ViewerInput>) -> Self {
+        Self {
+            session_id,
+            peer,
+            input_tx,
+            reader: FrameReader::default(),
+            message: Vec::new(),
+        }
+    }
+
+    /// Reads `data`, the next bytes the viewer sent. Control frames are
+    /// written to `upstream`, HT's server; messages are handed to the
+    /// session manager. A protocol error ends the connection.
+    pub async fn feed<W: AsyncWrite + Unpin>(
+        &mut self,
+        data: &[u8],
+        upstream: &mut W,
+    ) -> io::Result<()> {
+        self.reader.push(data);
+        while let Some(frame) = self.reader.next_frame()? {
+            if frame.opcode >= OPCODE_CONTROL {
+                upstream.write_all(&frame.raw).await?;
+                continue;
+            }
+            match frame.opcode {
+                OPCODE_TEXT | OPCODE_BINARY => self.message = frame.payload,
+                OPCODE_CONTINUATION => {
+                    if self.message.len() + frame.payload.len() > MAX_MESSAGE_BYTES {
+                        return Err(invalid("message from a viewer is too large"));
+                    }
+                    self.message.extend(frame.payload);
+                }
+                _ => return Err(invalid("unknown frame from a viewer")),
+            }
+            if frame.fin {
+                let message = std::mem::take(&mut self.message);
+                self.submit(&message);
+            }
+        }
+        Ok(())
+    }
+
+    fn submit(&self, message: &[u8]) {
+        let message: ViewerMessage = match serde_json::from_slice(message) {
+            Ok(message) => message,
+            Err(e) => {
+                debug!(
+                    "Ignoring a message from viewer {} of session {}: {}",
+                    self.peer, self.session_id, e
+                );
+                return;
+            }
+        };
+        let (keys, seqs) = message.into_input();
+        let input = ViewerInput {
+            session_id: self.session_id.clone(),
+            peer: self.peer.clone(),
+            keys,
+            seqs,
+        };
+        // Never waits, so a busy session manager cannot hold up the output
+        // the guard forwards to the viewer
+        if let Err(e) = self.input_tx.try_send(input) {
+            warn!(
+                "Dropped input from viewer {} of session {}: {}",
+                self.peer, self.session_id, e
+            );
+        }
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// A frame as a client sends it: masked, with the shortest length encoding
+#[cfg(test)]
+pub fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mask = [0x37, 0xfa, 0x21, 0x3d];
+    let mut frame = vec![if fin { 0x80 } else { 0 } | opcode];
+    match payload.len() {
+        len @ 0..=125 => frame.push(0x80 | len as u8),
+        len @ 126..=0xffff => {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(0x80 | 127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(&mask);
+    frame.extend(
+        payload
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| byte ^ mask[i % 4]),
+    );
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn viewer_socket() -> (ViewerSocket, mpsc::Receiver<ViewerInput>) {
+        let (input_tx, input_rx) = mpsc::channel(8);
+        let socket = ViewerSocket::new(
+            "session-1".to_string(),
+            "127.0.0.1:5000".to_string(),
+            input_tx,
+        );
+        (socket, input_rx)
+    }
+
+    #[tokio::test]
+    async fn test_messages_become_input() {
+        let (mut socket, mut input_rx) = viewer_socket();
+        let mut upstream = Vec::new();
+        let sent = [
+            client_frame(true, OPCODE_TEXT, br#"{"type":"input","payload":"ls\r"}"#),
+            client_frame(
+                true,
+                OPCODE_BINARY,
+                br#"{"type":"sendKeys","keys":["C-c","Enter"]}"#,
+            ),
+        ]
+        .concat();
+        socket.feed(&sent, &mut upstream).await.unwrap();
+
+        let input = input_rx.try_recv().unwrap();
+        assert_eq!(input.session_id, "session-1");
+        assert_eq!(input.peer, "127.0.0.1:5000");
+        assert_eq!(input.keys, ["ls\r"]);
+        assert_eq!(input.seqs.len(), 1);
+        let input = input_rx.try_recv().unwrap();
+        assert_eq!(input.keys, ["C-c", "Enter"]);
+        assert_eq!(input.seqs.len(), 2);
+        // Messages are kept from HT's server
+        assert!(upstream.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_fragments_and_split_reads() {
+        let (mut socket, mut input_rx) = viewer_socket();
+        let mut upstream = Vec::new();
+        let sent = [
+            client_frame(false, OPCODE_TEXT, br#"{"type":"input","#),
+            client_frame(true, OPCODE_CONTINUATION, br#""payload":"pwd\r"}"#),
+        ]
+        .concat();
+        for byte in &sent {
+            socket.feed(&[*byte], &mut upstream).await.unwrap();
+        }
+        assert_eq!(input_rx.try_recv().unwrap().keys, ["pwd\r"]);
+        assert!(input_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_control_frames_go_upstream() {
+        let (mut socket, mut input_rx) = viewer_socket();
+        let mut upstream = Vec::new();
+        let ping = client_frame(true, 0x9, b"ping");
+        let close = client_frame(true, 0x8, &1000u16.to_be_bytes());
+        let sent = [
+            ping.clone(),
+            client_frame(true, OPCODE_TEXT, b"not json"),
+            client_frame(true, OPCODE_TEXT, br#"{"type":"resize","cols":80}"#),
+            close.clone(),
+        ]
+        .concat();
+        socket.feed(&sent, &mut upstream).await.unwrap();
+
+        assert_eq!(upstream, [ping, close].concat());
+        assert!(input_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_protocol_errors_end_the_connection() {
+        let (mut socket, _input_rx) = viewer_socket();
+        let mut unmasked = client_frame(true, OPCODE_TEXT, b"{}");
+        unmasked[1] &= 0x7f;
+        let err = socket.feed(&unmasked, &mut Vec::new()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let (mut socket, _input_rx) = viewer_socket();
+        let oversized = client_frame(true, OPCODE_TEXT, &vec![b' '; MAX_MESSAGE_BYTES + 1]);
+        let err = socket.feed(&oversized, &mut Vec::new()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,250 @@
+//! Idle sessions started ahead of time, so `create_session` can hand one out
+//! instead of waiting for a shell to start.
+//!
+//! The config's `[warm_pool]` names a template: how many sessions to keep
+//! and the command they run. A create request is served from the pool when
+//! it asks for nothing the pooled sessions were not started with (a web
+//! server, tunnel, working directory, ...); labels and a lease are applied
+//! on adoption and `init_commands` typed in. Taken sessions are replaced by
+//! a background task, and the pool is emptied when the server drains.
+
+use crate::error::{HtMcpError, Result};
+use crate::mcp::types::CreateSessionArgs;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// How often the background task tops the pool up
+pub const REFILL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Largest accepted pool size; each pooled session is a live process
+const MAX_POOL_SIZE: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WarmPoolConfig {
+    /// Idle sessions to keep ready; 0 disables the pool
+    pub size: usize,
+    /// Command the pooled sessions run; only requests for the same command
+    /// are served from the pool
+    pub command: Vec<String>,
+    /// Lines typed into a session when it is handed out
+    pub init_commands: Vec<String>,
+}
+
+impl Default for WarmPoolConfig {
+    fn default() -> Self {
+        Self {
+            size: 0,
+            command: vec!["bash".to_string()],
+            init_commands: Vec::new(),
+        }
+    }
+}
+
+impl WarmPoolConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.size > MAX_POOL_SIZE {
+            return Err(HtMcpError::Config(format!(
+                "warm_pool.size must be at most {}, got {}",
+                MAX_POOL_SIZE, self.size
+            )));
+        }
+        if self.size > 0 && self.command.is_empty() {
+            return Err(HtMcpError::Config(
+                "warm_pool.command must not be empty".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether a session created with `args` may be taken from the pool.
+    /// Labels, a lease, an input profile, `readOnly` and an idempotency key
+    /// are applied on adoption; anything affecting how the session was started rules the
+    /// pool out.
+    pub fn matches(&self, args: &CreateSessionArgs) -> bool {
+        let command_matches = match &args.command {
+            Some(command) => *command == self.command,
+            None => self.command == ["bash"],
+        };
+        self.size > 0
+            && command_matches
+            && !args.enable_web_server.unwrap_or(false)
+            && !args.enable_tunnel.unwrap_or(false)
+            && args.cwd.is_none()
+            && args.env.is_none()
+            && args.max_memory_mb.is_none()
+            && args.run_as.is_none()
+            && !args.temp_dir.unwrap_or(false)
+            && args.snapshot_interval_ms.is_none()
+            && !args.auto_detect_prompt.unwrap_or(false)
+            && !args.trace_mode.unwrap_or(false)
+            && args.retention.is_none()
+            && args.color_profile.is_none()
+            && args.cpu_affinity.is_none()
+            && args.preferred_port.is_none()
+            && args.cols.is_none()
+            && args.rows.is_none()
+            && args.output_webhook_url.is_none()
+    }
+}
+
+/// The idle sessions, oldest first, and how often requests found one
+#[derive(Debug)]
+pub struct WarmPool<T> {
+    idle: VecDeque<T>,
+    hits: u64,
+    misses: u64,
+}
+
+impl<T> Default for WarmPool<T> {
+    fn default() -> Self {
+        Self {
+            idle: VecDeque::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+}
+
+impl<T> WarmPool<T> {
+    /// The oldest idle session, counting a hit or a miss
+    pub fn take(&mut self) -> Option<T> {
+        let taken = self.idle.pop_front();
+        match taken {
+            Some(_) => self.hits += 1,
+            None => self.misses += 1,
+        }
+        taken
+    }
+
+    pub fn push(&mut self, session: T) {
+        self.idle.push_back(session);
+    }
+
+    /// Sessions missing to reach `size`
+    pub fn deficit(&self, size: usize) -> usize {
+        size.saturating_sub(self.idle.len())
+    }
+
+    /// Removes the idle sessions for which `keep` is false, returning them
+    pub fn remove_unless(&mut self, mut keep: impl FnMut(&T) -> bool) -> Vec<T> {
+        let mut removed = Vec::new();
+        for session in std::mem::take(&mut self.idle) {
+            if keep(&session) {
+                self.idle.push_back(session);
+            } else {
+                removed.push(session);
+            }
+        }
+        removed
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.idle.iter()
+    }
+
+    /// Removes every idle session, returning them
+    pub fn clear(&mut self) -> Vec<T> {
+        self.idle.drain(..).collect()
+    }
+
+    pub fn summary(&self, size: usize) -> Value {
+        json!({
+            "target": size,
+            "idle": self.idle.len(),
+            "hits": self.hits,
+            "misses": self.misses
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool_config() -> WarmPoolConfig {
+        WarmPoolConfig {
+            size: 2,
+            ..WarmPoolConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_matches() {
+        let config = pool_config();
+        assert!(config.matches(&CreateSessionArgs::default()));
+        assert!(config.matches(&CreateSessionArgs {
+            command: Some(vec!["bash".to_string()]),
+            label_color: Some("#ff6600".to_string()),
+            lease_secs: Some(60),
+            idempotency_key: Some("k".to_string()),
+            ..CreateSessionArgs::default()
+        }));
+
+        for args in [
+            CreateSessionArgs {
+                command: Some(vec!["python3".to_string()]),
+                ..CreateSessionArgs::default()
+            },
+            CreateSessionArgs {
+                enable_web_server: Some(true),
+                ..CreateSessionArgs::default()
+            },
+            CreateSessionArgs {
+                cwd: Some("/tmp".to_string()),
+                ..CreateSessionArgs::default()
+            },
+            CreateSessionArgs {
+                auto_detect_prompt: Some(true),
+                ..CreateSessionArgs::default()
+            },
+            CreateSessionArgs {
+                cols: Some(400),
+                ..CreateSessionArgs::default()
+            },
+        ] {
+            assert!(!config.matches(&args), "{:?}", args);
+        }
+        assert!(!WarmPoolConfig::default().matches(&CreateSessionArgs::default()));
+    }
+
+    #[test]
+    fn test_pool_counts_hits_and_misses() {
+        let mut pool = WarmPool::default();
+        assert_eq!(pool.deficit(2), 2);
+        pool.push(1);
+        pool.push(2);
+        assert_eq!(pool.deficit(2), 0);
+
+        assert_eq!(pool.take(), Some(1));
+        assert_eq!(pool.remove_unless(|&n| n != 2), [2]);
+        assert_eq!(pool.take(), None);
+        assert_eq!(
+            pool.summary(2),
+            json!({"target": 2, "idle": 0, "hits": 1, "misses": 1})
+        );
+
+        pool.push(3);
+        assert_eq!(pool.clear(), [3]);
+        assert_eq!(pool.deficit(2), 2);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(pool_config().validate().is_ok());
+        for config in [
+            WarmPoolConfig {
+                size: MAX_POOL_SIZE + 1,
+                ..WarmPoolConfig::default()
+            },
+            WarmPoolConfig {
+                command: Vec::new(),
+                ..pool_config()
+            },
+        ] {
+            assert!(matches!(config.validate(), Err(HtMcpError::Config(_))));
+        }
+    }
+}
@@ -0,0 +1,128 @@
+//! Binding a session web server's public listeners over IPv4 and/or IPv6.
+//!
+//! With no configured address the server listens on loopback, on both
+//! `127.0.0.1` and `::1` where the host has both, and only on `::1` on
+//! IPv6-only hosts. An unspecified IPv6 address (`::`) is bound dual-stack
+//! so IPv4 clients can connect too.
+
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener};
+
+/// Listen backlog, matching what std uses for `TcpListener::bind`
+const BACKLOG: i32 = 128;
+
+/// Binds the public listeners for `port` on `address`, or on loopback when
+/// unset
+pub fn bind(address: Option<IpAddr>, port: u16) -> io::Result<Vec<TcpListener>> {
+    if let Some(address) = address {
+        return Ok(vec![bind_addr(SocketAddr::new(address, port))?]);
+    }
+
+    match bind_addr(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port)) {
+        Ok(v4) => {
+            let mut listeners = vec![v4];
+            // Best effort: hosts without IPv6 just serve IPv4
+            if let Ok(v6) = bind_addr(SocketAddr::new(Ipv6Addr::LOCALHOST.into(), port)) {
+                listeners.push(v6);
+            }
+            Ok(listeners)
+        }
+        // IPv6-only host
+        Err(e) if e.kind() == io::ErrorKind::AddrNotAvailable => Ok(vec![bind_addr(
+            SocketAddr::new(Ipv6Addr::LOCALHOST.into(), port),
+        )?]),
+        Err(e) => Err(e),
+    }
+}
+
+/// Binds an ephemeral loopback port for traffic that never leaves the host,
+/// preferring IPv4
+pub fn bind_private() -> io::Result<TcpListener> {
+    bind_addr(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0))
+        .or_else(|_| bind_addr(SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 0)))
+}
+
+/// URL for viewing a web server listening on `addr` from this host. A
+/// wildcard address is reported as loopback of the same family; IPv6 hosts
+/// are bracketed.
+pub fn local_url(addr: SocketAddr) -> String {
+    let host = match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => Ipv4Addr::LOCALHOST.into(),
+        IpAddr::V6(ip) if ip.is_unspecified() => Ipv6Addr::LOCALHOST.into(),
+        ip => ip,
+    };
+    format!("http://{}", SocketAddr::new(host, addr.port()))
+}
+
+fn bind_addr(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        // Dual-stack only for the wildcard address; a specific IPv6 address
+        // cannot accept IPv4 connections anyway
+        socket.set_only_v6(!addr.ip().is_unspecified())?;
+    }
+    // Same as std's TcpListener::bind, so a closed session's port can be
+    // reused while old connections linger in TIME_WAIT
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ipv6_available() -> bool {
+        TcpListener::bind("[::1]:0").is_ok()
+    }
+
+    #[test]
+    fn test_local_url() {
+        assert_eq!(
+            local_url("127.0.0.1:3618".parse().unwrap()),
+            "http://127.0.0.1:3618"
+        );
+        assert_eq!(
+            local_url("[::1]:3618".parse().unwrap()),
+            "http://[::1]:3618"
+        );
+        assert_eq!(local_url("[::]:3618".parse().unwrap()), "http://[::1]:3618");
+        assert_eq!(
+            local_url("0.0.0.0:3618".parse().unwrap()),
+            "http://127.0.0.1:3618"
+        );
+    }
+
+    #[test]
+    fn test_default_binds_both_loopbacks() {
+        let port = bind_private().unwrap().local_addr().unwrap().port();
+        let listeners = bind(None, port).unwrap();
+        let addrs: Vec<SocketAddr> = listeners
+            .iter()
+            .map(|listener| listener.local_addr().unwrap())
+            .collect();
+
+        assert!(addrs.iter().all(|addr| addr.port() == port));
+        assert!(addrs.iter().all(|addr| addr.ip().is_loopback()));
+        if ipv6_available() {
+            assert_eq!(addrs.len(), 2, "{:?}", addrs);
+        }
+    }
+
+    #[test]
+    fn test_wildcard_ipv6_is_dual_stack() {
+        if !ipv6_available() {
+            eprintln!("skipping: no IPv6 on this host");
+            return;
+        }
+        let listener = bind(Some(Ipv6Addr::UNSPECIFIED.into()), 0)
+            .unwrap()
+            .remove(0);
+        let port = listener.local_addr().unwrap().port();
+        std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        std::net::TcpStream::connect(("::1", port)).unwrap();
+    }
+}
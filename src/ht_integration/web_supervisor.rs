@@ -0,0 +1,255 @@
+//! Keeps a session's web server up.
+//!
+//! A web server is two tasks: HT's HTTP server on a private port, and the
+//! connection guard owning the public port in front of it. If either stops
+//! while the session is alive, the viewer is gone but `webServerUrl` would
+//! still advertise it. The supervisor notices, stops the other task and
+//! starts both again, on the same public port while it is free and on
+//! another one otherwise. Restarts back off exponentially; after
+//! [`MAX_WEB_SERVER_RESTARTS`] the web server is marked failed and left
+//! down.
+
+use crate::error::{HtMcpError, Result};
+use crate::ht_integration::connection_guard::ConnectionGuard;
+use crate::ht_integration::events::{EventBus, SessionEvent};
+use crate::ht_integration::session_manager::WEB_SERVER_PORTS;
+use crate::ht_integration::web_listener;
+use ht_core::api::http;
+use ht_core::session::Client;
+use std::net::{IpAddr, TcpListener};
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::{AbortHandle, JoinHandle};
+use tracing::{error, info, warn};
+
+/// Restarts over a session's lifetime before its web server is given up on
+pub const MAX_WEB_SERVER_RESTARTS: u32 = 5;
+
+/// Wait before the first restart; doubled for each one after
+const FIRST_RESTART_DELAY: Duration = Duration::from_millis(50);
+
+/// Where a session's web server is and how it has fared, shared between the
+/// session and its supervisor
+#[derive(Debug)]
+pub struct WebServerStatus {
+    url: Mutex<String>,
+    port: AtomicU16,
+    restarts: AtomicU32,
+    failed: AtomicBool,
+    /// The running HT server task
+    server: Mutex<Option<AbortHandle>>,
+}
+
+impl WebServerStatus {
+    pub fn new(url: String, port: u16) -> Self {
+        Self {
+            url: Mutex::new(url),
+            port: AtomicU16::new(port),
+            restarts: AtomicU32::new(0),
+            failed: AtomicBool::new(false),
+            server: Mutex::new(None),
+        }
+    }
+
+    pub fn url(&self) -> String {
+        self.url.lock().unwrap().clone()
+    }
+
+    /// Public port, which changes when a restart found the old one taken
+    pub fn port(&self) -> u16 {
+        self.port.load(Ordering::Relaxed)
+    }
+
+    pub fn restarts(&self) -> u32 {
+        self.restarts.load(Ordering::Relaxed)
+    }
+
+    /// Whether the web server stopped and could not be restarted
+    pub fn failed(&self) -> bool {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    /// Stops the HT server task as a crash would
+    #[cfg(test)]
+    pub fn kill_server(&self) {
+        if let Some(server) = self.server.lock().unwrap().as_ref() {
+            server.abort();
+        }
+    }
+}
+
+/// The two tasks of a running web server; dropping it stops them
+pub struct Serving {
+    server: JoinHandle<()>,
+    guard: JoinHandle<()>,
+}
+
+impl Serving {
+    /// Stops whichever task is still running and waits until it has let go
+    /// of its listeners
+    async fn stop(&mut self) {
+        for task in [&mut self.server, &mut self.guard] {
+            if !task.is_finished() {
+                task.abort();
+                let _ = task.await;
+            }
+        }
+    }
+}
+
+impl Drop for Serving {
+    fn drop(&mut self) {
+        self.server.abort();
+        self.guard.abort();
+    }
+}
+
+/// Starts and restarts a session's web server
+pub struct WebSupervisor {
+    pub session_id: String,
+    pub status: Arc<WebServerStatus>,
+    pub bind_address: Option<IpAddr>,
+    pub guard: ConnectionGuard,
+    /// Where HT's server hands viewers to the session; closed when the
+    /// session ends
+    pub clients_tx: mpsc::Sender<Client>,
+    pub events: EventBus,
+}
+
+impl WebSupervisor {
+    /// Starts HT's server on a private port and the guard forwarding to it
+    /// from the public `listeners`
+    pub async fn start(&self, listeners: Vec<TcpListener>) -> Result<Serving> {
+        // HT's web server listens privately; the connection guard owns the
+        // public port and forwards connections within the limits
+        let upstream_listener = web_listener::bind_private()?;
+        let upstream_addr = upstream_listener.local_addr()?;
+        let listeners = listeners
+            .into_iter()
+            .map(|listener| {
+                listener.set_nonblocking(true)?;
+                tokio::net::TcpListener::from_std(listener)
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        let server_future = http::start(upstream_listener, self.clients_tx.clone())
+            .await
+            .map_err(|e| HtMcpError::HtLibrary(e.to_string()))?;
+        let server = tokio::spawn(async move {
+            if let Err(e) = server_future.await {
+                error!("HTTP server error: {}", e);
+            }
+        });
+        *self.status.server.lock().unwrap() = Some(server.abort_handle());
+        let guard = tokio::spawn(self.guard.clone().run(listeners, upstream_addr));
+        Ok(Serving { server, guard })
+    }
+
+    /// Watches `serving` until the session ends, restarting the web server
+    /// whenever one of its tasks stops
+    pub async fn run(self, mut serving: Serving) {
+        loop {
+            let stopped = tokio::select! {
+                // The session is gone; dropping `serving` stops the tasks
+                _ = self.clients_tx.closed() => return,
+                _ = &mut serving.server => "web server",
+                _ = &mut serving.guard => "connection guard",
+            };
+            warn!(
+                "The {} of session {} stopped unexpectedly",
+                stopped, self.session_id
+            );
+            self.events.publish(SessionEvent::WebServerStopped {
+                session_id: self.session_id.clone(),
+                component: stopped.to_string(),
+            });
+            serving.stop().await;
+
+            serving = loop {
+                let restarts = self.status.restarts();
+                if restarts >= MAX_WEB_SERVER_RESTARTS {
+                    self.give_up(restarts);
+                    return;
+                }
+                tokio::time::sleep(restart_delay(restarts)).await;
+                self.status.restarts.fetch_add(1, Ordering::Relaxed);
+                match self.restart().await {
+                    Ok(serving) => break serving,
+                    Err(e) => warn!(
+                        "Failed to restart the web server of session {}: {}",
+                        self.session_id, e
+                    ),
+                }
+            };
+        }
+    }
+
+    /// Starts the web server again on its port, or on another free one
+    async fn restart(&self) -> Result<Serving> {
+        let old_port = self.status.port();
+        let listeners = match web_listener::bind(self.bind_address, old_port) {
+            Ok(listeners) => listeners,
+            Err(e) => {
+                warn!(
+                    "Port {} of session {} is no longer free ({}); moving the web server",
+                    old_port, self.session_id, e
+                );
+                WEB_SERVER_PORTS
+                    .filter(|&port| port != old_port)
+                    .find_map(|port| web_listener::bind(self.bind_address, port).ok())
+                    .ok_or_else(|| HtMcpError::Internal("No available ports found".to_string()))?
+            }
+        };
+        let public_addr = listeners[0].local_addr()?;
+        let serving = self.start(listeners).await?;
+
+        let url = web_listener::local_url(public_addr);
+        *self.status.url.lock().unwrap() = url.clone();
+        self.status
+            .port
+            .store(public_addr.port(), Ordering::Relaxed);
+        info!(
+            "Restarted the web server of session {} on {}",
+            self.session_id, url
+        );
+        self.events.publish(SessionEvent::WebServerRestarted {
+            session_id: self.session_id.clone(),
+            url,
+            restarts: self.status.restarts(),
+            port_changed: public_addr.port() != old_port,
+        });
+        Ok(serving)
+    }
+
+    fn give_up(&self, restarts: u32) {
+        self.status.failed.store(true, Ordering::Relaxed);
+        error!(
+            "Giving up on the web server of session {} after {} restarts",
+            self.session_id, restarts
+        );
+        self.events.publish(SessionEvent::WebServerFailed {
+            session_id: self.session_id.clone(),
+            restarts,
+        });
+    }
+}
+
+/// Wait before restart number `restarts` (counting from zero)
+fn restart_delay(restarts: u32) -> Duration {
+    FIRST_RESTART_DELAY * 2u32.pow(restarts.min(MAX_WEB_SERVER_RESTARTS))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_restart_delay_doubles() {
+        let delays: Vec<u128> = (0..MAX_WEB_SERVER_RESTARTS)
+            .map(|restarts| restart_delay(restarts).as_millis())
+            .collect();
+        assert_eq!(delays, [50, 100, 200, 400, 800]);
+    }
+}
@@ -9,12 +9,16 @@
 #![allow(clippy::collapsible_if)] // Allow nested if statements for clarity
 #![allow(clippy::collapsible_match)] // Allow nested match statements for clarity
 
+pub mod config;
 pub mod error;
 pub mod ht_integration;
+pub mod management;
 pub mod mcp;
+pub mod telemetry;
 pub mod transport;
 pub mod tunnel;
 
 pub use tunnel::{TunnelConfig, TunnelInfo, TunnelManager};
 
+pub use config::HtMcpConfig;
 pub use error::{HtMcpError, Result};
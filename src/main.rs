@@ -11,13 +11,24 @@ use clap::Parser;
 use serde_json::{json, Value};
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tracing::{error, info, warn};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
 
+mod config;
 mod error;
 mod ht_integration;
+mod management;
 mod mcp;
+mod telemetry;
 mod transport;
 mod tunnel;
 
+use crate::config::{HtMcpConfig, STATE_TOKEN_ENV_VAR};
+use crate::error::HtMcpError;
+use crate::mcp::client_identity::ClientIdentity;
+use crate::mcp::progress::Progress;
 use crate::mcp::server::HtMcpServer;
 
 #[derive(Parser)]
@@ -31,27 +42,135 @@ struct Cli {
     /// Server name for MCP identification
     #[arg(long, default_value = "ht-mcp-server")]
     name: String,
+
+    /// Path to the TOML config file (defaults to $HT_MCP_CONFIG or ~/.config/ht-mcp/config.toml)
+    #[arg(long)]
+    config: Option<std::path::PathBuf>,
+
+    /// Project directory sessions start in; its .ht-mcp.toml is merged into the config (overrides workspace_root)
+    #[arg(long)]
+    workspace_root: Option<std::path::PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Print the config the server would load and check it; exits with 1
+    /// if it is invalid
+    ConfigCheck {
+        /// Print a JSON report instead
+        #[arg(long)]
+        json: bool,
+    },
+    /// Download a running server's state as a .tar.gz archive, for
+    /// `import-state` on another host; needs the server's
+    /// management_address and enable_admin_tools
+    ExportState {
+        /// File to write the archive to
+        #[arg(long)]
+        out: std::path::PathBuf,
+        /// Also export the values of the sessions' env variables; the server
+        /// must set allow_state_env_export
+        #[arg(long)]
+        include_env: bool,
+        /// Management address of the server (defaults to management_address from the config)
+        #[arg(long)]
+        server: Option<std::net::SocketAddr>,
+    },
+    /// Import an archive written by `export-state` into a running server
+    ImportState {
+        /// Archive written by export-state
+        file: std::path::PathBuf,
+        /// Move recording paths, written as OLD_DIR=NEW_DIR; recording files
+        /// in the archive are restored under NEW_DIR
+        #[arg(long, value_name = "OLD_DIR=NEW_DIR")]
+        recordings_remap: Option<String>,
+        /// Management address of the server (defaults to management_address from the config)
+        #[arg(long)]
+        server: Option<std::net::SocketAddr>,
+    },
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
+    match cli.command {
+        Some(Command::ConfigCheck { json }) => {
+            std::process::exit(config_check(cli.config.as_deref(), json));
+        }
+        Some(command @ (Command::ExportState { .. } | Command::ImportState { .. })) => {
+            return state_command(cli.config.as_deref(), command).await;
+        }
+        None => {}
+    }
+
+    // Claimed before anything else runs, so only JSON-RPC frames reach the
+    // client; stray prints go to stderr
+    let mcp_stdout = transport::stdout_guard::take_stdout();
+    transport::stdout_guard::install_panic_hook();
+
+    let mut config = HtMcpConfig::load(cli.config.as_deref())?;
+    if let Some(root) = cli.workspace_root {
+        config.workspace_root = Some(root);
+    }
+    let project_config = config.load_workspace()?;
 
     // Initialize logging to stderr (MCP protocol uses stdout for JSON-RPC)
-    let subscriber = tracing_subscriber::fmt()
-        .with_max_level(if cli.debug {
-            tracing::Level::DEBUG
-        } else {
-            tracing::Level::INFO
-        })
+    let fmt_layer = tracing_subscriber::fmt::layer()
         .with_writer(std::io::stderr)
-        .finish();
-    tracing::subscriber::set_global_default(subscriber)?;
+        .with_filter(if cli.debug {
+            LevelFilter::DEBUG
+        } else {
+            LevelFilter::INFO
+        });
+
+    #[cfg(feature = "otel")]
+    let (otel_layer, telemetry) = match telemetry::init(&config.telemetry)? {
+        Some((layer, telemetry)) => (Some(layer), Some(telemetry)),
+        None => (None, None),
+    };
+    #[cfg(not(feature = "otel"))]
+    let otel_layer: Option<tracing_subscriber::layer::Identity> = None;
+
+    tracing_subscriber::registry()
+        .with(fmt_layer)
+        .with(otel_layer)
+        .try_init()?;
 
     info!("Starting HT MCP Server v{}", env!("CARGO_PKG_VERSION"));
+    if let Some(project) = &project_config {
+        info!("Merged project config {}", project.path.display());
+        for section in &project.ignored {
+            warn!(
+                "Ignored {} in {}; set trust_project_config in the server config to use it",
+                section,
+                project.path.display()
+            );
+        }
+    }
+
+    #[cfg(not(feature = "otel"))]
+    if config.telemetry.endpoint().is_some() {
+        warn!("An OTLP endpoint is configured but ht-mcp was built without the otel feature");
+    }
 
     // Create MCP server
-    let mut server = HtMcpServer::new();
+    let management_address = config.management_address;
+    let mut server = HtMcpServer::with_config(config);
+    server.start_background_tasks();
+
+    if let Some(address) = management_address {
+        let events = server.events().await;
+        let downloads = server.downloads().await;
+        let sessions = server.state_access();
+        tokio::spawn(async move {
+            if let Err(e) = management::serve(address, events, downloads, sessions).await {
+                error!("{}", e);
+            }
+        });
+    }
 
     info!("HT MCP Server created successfully");
     info!("Server info: {:?}", server.server_info());
@@ -59,12 +178,38 @@ async fn main() -> anyhow::Result<()> {
     // Set up stdio transport for MCP protocol
     let stdin = tokio::io::stdin();
     let mut reader = BufReader::new(stdin);
-    let mut stdout = tokio::io::stdout();
+    let mut stdout: Box<dyn tokio::io::AsyncWrite + Unpin> = match mcp_stdout {
+        Ok(file) => Box::new(tokio::fs::File::from_std(file)),
+        Err(e) => {
+            warn!("Not guarding stdout against stray output: {}", e);
+            Box::new(tokio::io::stdout())
+        }
+    };
+
+    // Set once `ht_drain` has closed the remaining sessions
+    let mut shutdown = server.shutdown_signal();
+    let termination = termination_signal();
+    tokio::pin!(termination);
+    let mut terminated = false;
+
+    // Progress reported by tool calls, written while the call runs
+    let (notifications, mut progress) = tokio::sync::mpsc::unbounded_channel();
 
     let mut line = String::new();
     loop {
         line.clear();
-        match reader.read_line(&mut line).await {
+        let read = tokio::select! {
+            read = reader.read_line(&mut line) => read,
+            _ = shutdown.wait_for(|done| *done) => {
+                info!("Server drained");
+                break;
+            }
+            _ = &mut termination => {
+                terminated = true;
+                break;
+            }
+        };
+        match read {
             Ok(0) => {
                 // EOF
                 info!("Client disconnected");
@@ -81,7 +226,21 @@ async fn main() -> anyhow::Result<()> {
                 // Parse JSON-RPC request
                 match serde_json::from_str::<Value>(trimmed) {
                     Ok(request) => {
-                        let response = handle_request(&mut server, request).await;
+                        let handling = handle_request(&mut server, request, &notifications);
+                        tokio::pin!(handling);
+                        let response = loop {
+                            tokio::select! {
+                                response = &mut handling => break response,
+                                Some(notification) = progress.recv() => {
+                                    write_notification(&mut stdout, &notification).await;
+                                }
+                            }
+                        };
+                        // Progress reported just before the call ended goes
+                        // out ahead of its response
+                        while let Ok(notification) = progress.try_recv() {
+                            write_notification(&mut stdout, &notification).await;
+                        }
 
                         // Only send response if it's not null (i.e., not a notification)
                         if !response.is_null() {
@@ -127,11 +286,247 @@ async fn main() -> anyhow::Result<()> {
         }
     }
 
+    // The client is gone (EOF or a broken pipe) and no other can attach
+    // over stdio: close the sessions, after `reconnect_grace_secs` if set
+    if !terminated && !*shutdown.borrow() {
+        server.client_disconnected().await;
+        tokio::select! {
+            _ = shutdown.wait_for(|done| *done) => {}
+            _ = &mut termination => {}
+        }
+    }
+
+    // Kill what the sessions still run and stop the tunnels, so nothing
+    // outlives the server
+    server.shutdown().await;
+
     info!("HT MCP Server shutting down");
+    #[cfg(feature = "otel")]
+    if let Some(telemetry) = telemetry {
+        telemetry.shutdown();
+    }
+    Ok(())
+}
+
+/// Resolves on Ctrl-C, or SIGTERM on Unix
+async fn termination_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => info!("Received Ctrl-C"),
+        _ = terminate => info!("Received SIGTERM"),
+    }
+}
+
+/// Runs `export-state` or `import-state` against a running server's
+/// management endpoints
+async fn state_command(
+    config_path: Option<&std::path::Path>,
+    command: Command,
+) -> anyhow::Result<()> {
+    let base_url = |server: Option<std::net::SocketAddr>| -> anyhow::Result<String> {
+        let mut address = match server {
+            Some(address) => address,
+            None => HtMcpConfig::load(config_path)?
+                .management_address
+                .ok_or_else(|| {
+                    anyhow::anyhow!("No management_address in the config; pass --server")
+                })?,
+        };
+        // A server listening on every interface is reached on loopback
+        if address.ip().is_unspecified() {
+            address.set_ip(match address {
+                std::net::SocketAddr::V4(_) => std::net::Ipv4Addr::LOCALHOST.into(),
+                std::net::SocketAddr::V6(_) => std::net::Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        Ok(format!("http://{}/state", address))
+    };
+    // Not taken as an argument, where other users could read it in the
+    // process list
+    let token = match std::env::var(STATE_TOKEN_ENV_VAR) {
+        Ok(token) => token,
+        Err(_) => HtMcpConfig::load(config_path)?.state_token.ok_or_else(|| {
+            anyhow::anyhow!(
+                "No state_token in the config; set it or {}",
+                STATE_TOKEN_ENV_VAR
+            )
+        })?,
+    };
+    let client = reqwest::Client::new();
+    let failed = |response: reqwest::Response| async move {
+        let status = response.status();
+        let message = response.text().await.unwrap_or_default();
+        anyhow::anyhow!("Server answered {}: {}", status, message.trim())
+    };
+
+    match command {
+        Command::ExportState {
+            out,
+            include_env,
+            server,
+        } => {
+            let response = client
+                .get(base_url(server)?)
+                .bearer_auth(&token)
+                .query(&[("includeEnv", include_env)])
+                .send()
+                .await?;
+            if !response.status().is_success() {
+                return Err(failed(response).await);
+            }
+            let archive = response.bytes().await?;
+            let manifest = ht_integration::state::read_manifest(&archive[..], "the download")?;
+            std::fs::write(&out, &archive)?;
+            println!(
+                "Exported {} sessions and {} recordings to {} (format version {}{})",
+                manifest.sessions.len(),
+                manifest.recordings.len(),
+                out.display(),
+                manifest.format_version,
+                if include_env {
+                    ", with env values"
+                } else {
+                    ", env values left out"
+                }
+            );
+        }
+        Command::ImportState {
+            file,
+            recordings_remap,
+            server,
+        } => {
+            let mut query = Vec::new();
+            if let Some(remap) = &recordings_remap {
+                let (from, to) = remap
+                    .split_once('=')
+                    .ok_or_else(|| anyhow::anyhow!("--recordings-remap takes OLD_DIR=NEW_DIR"))?;
+                query.push(("recordingsRemapFrom", from));
+                query.push(("recordingsRemapTo", to));
+            }
+            let archive = std::fs::read(&file)?;
+            // Checked here first, so a bad file is reported before it is sent
+            ht_integration::state::read_manifest(&archive[..], &file.display().to_string())?;
+            let response = client
+                .post(base_url(server)?)
+                .bearer_auth(&token)
+                .query(&query)
+                .body(archive)
+                .send()
+                .await?;
+            if !response.status().is_success() {
+                return Err(failed(response).await);
+            }
+            let result: Value = response.json().await?;
+            println!("{}", format_tool_response("ht_import_state", &result));
+        }
+        Command::ConfigCheck { .. } => unreachable!("handled by config_check"),
+    }
     Ok(())
 }
 
-async fn handle_request(server: &mut HtMcpServer, request: Value) -> Value {
+/// Prints the config `HtMcpConfig::load` would use, with sensitive values
+/// redacted, and every problem with it. Returns the exit code.
+fn config_check(path: Option<&std::path::Path>, json: bool) -> i32 {
+    let source = HtMcpConfig::locate(path);
+    let parsed = match &source {
+        Some(path) => std::fs::read_to_string(path)
+            .map_err(|e| HtMcpError::Config(format!("Failed to read {}: {}", path.display(), e)))
+            .and_then(|contents| HtMcpConfig::parse(&contents)),
+        None => Ok(HtMcpConfig::default()),
+    };
+    let message = |e: HtMcpError| match e {
+        HtMcpError::Config(message) => message,
+        e => e.to_string(),
+    };
+    let (config, errors): (Option<Value>, Vec<(&str, String)>) = match parsed {
+        Ok(config) => (
+            Some(config.redacted()),
+            config
+                .problems()
+                .into_iter()
+                .map(|(field, e)| (field, message(e)))
+                .collect(),
+        ),
+        // Parse errors name the offending key themselves
+        Err(e) => (None, vec![("file", message(e))]),
+    };
+
+    if json {
+        let report = json!({
+            "source": source.as_ref().map(|path| path.display().to_string()),
+            "valid": errors.is_empty(),
+            "config": config,
+            "errors": errors
+                .iter()
+                .map(|(field, message)| json!({"field": field, "message": message}))
+                .collect::<Vec<_>>()
+        });
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    } else {
+        match &source {
+            Some(path) => println!("Config file: {}", path.display()),
+            None => println!("Config file: none found, using the defaults"),
+        }
+        if let Some(Value::Object(fields)) = &config {
+            for (field, value) in fields {
+                println!("  {} = {}", field, value);
+            }
+        }
+        if errors.is_empty() {
+            println!("\nThe config is valid.");
+        } else {
+            println!("\nThe config is invalid:");
+            for (field, message) in &errors {
+                println!("  {}: {}", field, message);
+            }
+        }
+    }
+    i32::from(!errors.is_empty())
+}
+
+/// Writes a notification to the client. A failed write is only logged: the
+/// response that follows finds a broken stdout too and ends the loop.
+async fn write_notification(
+    stdout: &mut (dyn tokio::io::AsyncWrite + Unpin),
+    notification: &Value,
+) {
+    let mut message = serde_json::to_string(notification).unwrap();
+    message.push('\n');
+    let written = async {
+        stdout.write_all(message.as_bytes()).await?;
+        stdout.flush().await
+    };
+    if let Err(e) = written.await {
+        error!("Failed to write notification: {}", e);
+    }
+}
+
+async fn handle_request(
+    server: &mut HtMcpServer,
+    request: Value,
+    notifications: &tokio::sync::mpsc::UnboundedSender<Value>,
+) -> Value {
     let method = request.get("method").and_then(|m| m.as_str()).unwrap_or("");
     let id = request.get("id");
     let params = request.get("params");
@@ -139,6 +534,7 @@ async fn handle_request(server: &mut HtMcpServer, request: Value) -> Value {
     match method {
         "initialize" => {
             info!("Handling initialize request");
+            server.set_client(ClientIdentity::from_initialize(params, None));
             json!({
                 "jsonrpc": "2.0",
                 "id": id,
@@ -174,25 +570,29 @@ async fn handle_request(server: &mut HtMcpServer, request: Value) -> Value {
             if let Some(params) = params {
                 if let Some(tool_name) = params.get("name").and_then(|n| n.as_str()) {
                     let arguments = params.get("arguments").cloned().unwrap_or(json!({}));
+                    let progress = Progress::requested(params, notifications);
 
-                    match server.handle_tool_call(tool_name, arguments).await {
+                    match server
+                        .handle_tool_call_with_progress(tool_name, arguments, progress)
+                        .await
+                    {
                         Ok(result) => {
                             let text_response = format_tool_response(tool_name, &result);
+                            let mut content = vec![json!({
+                                "type": "text",
+                                "text": text_response
+                            })];
+                            content.extend(image_content(&result));
                             json!({
                                 "jsonrpc": "2.0",
                                 "id": id,
                                 "result": {
-                                    "content": [
-                                        {
-                                            "type": "text",
-                                            "text": text_response
-                                        }
-                                    ]
+                                    "content": content
                                 }
                             })
                         }
                         Err(e) => {
-                            error!("Tool call failed: {}", e);
+                            // Failures are logged (and consolidated) by the tool stats middleware
                             json!({
                                 "jsonrpc": "2.0",
                                 "id": id,
@@ -238,9 +638,194 @@ async fn handle_request(server: &mut HtMcpServer, request: Value) -> Value {
     }
 }
 
+/// The " - By: client version (auth identity)" suffix of a session or
+/// tunnel line in the listings, or nothing when it has no `createdBy` stamp
+fn format_created_by(created_by: &serde_json::Value) -> String {
+    serde_json::from_value::<ClientIdentity>(created_by.clone())
+        .map(|client| format!(" - By: {}", client.label()))
+        .unwrap_or_default()
+}
+
+/// Where a paginated listing is, e.g. "Page 2 (45 total, more on page 3)"
+fn format_page(result: &serde_json::Value) -> String {
+    let page = result["page"].as_u64().unwrap_or(1);
+    let more = if result["hasMore"].as_bool().unwrap_or(false) {
+        format!(", more on page {}", page + 1)
+    } else {
+        String::new()
+    };
+    format!(
+        "\n\nPage {} ({} total{})",
+        page,
+        result["totalCount"].as_u64().unwrap_or(0),
+        more
+    )
+}
+
+/// Note on a `probeOnly` result whose text is over the call's `maxTokens`
+fn format_would_truncate(truncated: &serde_json::Value) -> &'static str {
+    if truncated.as_bool().unwrap_or(false) {
+        ", over maxTokens"
+    } else {
+        ""
+    }
+}
+
+/// `annotations` as a list under the output, or nothing when the call did
+/// not ask for them
+fn format_annotations(annotations: &serde_json::Value) -> String {
+    let Some(annotations) = annotations.as_array() else {
+        return String::new();
+    };
+    let entries: Vec<String> = annotations
+        .iter()
+        .map(|annotation| {
+            let line = annotation["line"].as_u64().unwrap_or(0);
+            let lines = match annotation["endLine"].as_u64() {
+                Some(end_line) if end_line != line => format!("{}-{}", line, end_line),
+                _ => line.to_string(),
+            };
+            format!(
+                "- line {} [{}]: {}",
+                lines,
+                annotation["kind"].as_str().unwrap_or("unknown"),
+                annotation["match"].as_str().unwrap_or("")
+            )
+        })
+        .collect();
+    format!(
+        "\n\nAnnotations ({}):\n{}",
+        entries.len(),
+        entries.join("\n")
+    )
+}
+
+fn format_summary(summary: &serde_json::Value) -> String {
+    if !summary.is_object() {
+        return String::new();
+    }
+    let mut lines = vec![format!(
+        "- {} error lines, {} warning lines, {} bytes of output in {} ms",
+        summary["errorLines"].as_u64().unwrap_or(0),
+        summary["warningLines"].as_u64().unwrap_or(0),
+        summary["outputBytes"].as_u64().unwrap_or(0),
+        summary["durationMs"].as_u64().unwrap_or(0)
+    )];
+    if let Some(first) = summary["firstError"].as_str() {
+        lines.push(format!("- first error: {}", first));
+    }
+    if let Some(last) = summary["lastError"].as_str() {
+        lines.push(format!("- last error: {}", last));
+    }
+    let files: Vec<String> = summary["files"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+        .iter()
+        .map(|file| {
+            let mut position = format!(
+                "{}:{}",
+                file["path"].as_str().unwrap_or("?"),
+                file["line"].as_u64().unwrap_or(0)
+            );
+            if let Some(column) = file["column"].as_u64() {
+                position.push_str(&format!(":{}", column));
+            }
+            position
+        })
+        .collect();
+    if !files.is_empty() {
+        lines.push(format!("- files: {}", files.join(", ")));
+    }
+    format!("\n\nSummary:\n{}", lines.join("\n"))
+}
+
+/// The provenance block of a snapshot, as a footer
+fn format_provenance(provenance: &serde_json::Value) -> String {
+    if provenance.is_null() {
+        return String::new();
+    }
+    let transforms: Vec<&str> = provenance["transforms"]
+        .as_array()
+        .map(|transforms| transforms.iter().filter_map(|t| t.as_str()).collect())
+        .unwrap_or_default();
+    let mut footer = format!(
+        "\n\nProvenance: ht-mcp session {} on {} at {}, screen version {}, sha256:{}",
+        provenance["sessionId"].as_str().unwrap_or("unknown"),
+        provenance["host"].as_str().unwrap_or("unknown"),
+        provenance["timestamp"].as_str().unwrap_or("unknown"),
+        provenance["screenVersion"],
+        provenance["hash"].as_str().unwrap_or("unknown")
+    );
+    if !transforms.is_empty() {
+        footer.push_str(&format!(" (after {})", transforms.join(", ")));
+    }
+    footer
+}
+
+/// An image content item for a result whose `mimeType` is exactly
+/// `image/png`, shown to the client beside the text
+fn image_content(result: &serde_json::Value) -> Option<serde_json::Value> {
+    if result["mimeType"].as_str() != Some("image/png") {
+        return None;
+    }
+    let png = result["png"].as_str()?;
+    Some(json!({
+        "type": "image",
+        "data": png,
+        "mimeType": "image/png"
+    }))
+}
+
+/// A job from `async`, `ht_get_job` or `ht_cancel_job`, with the call's
+/// result formatted as its tool's once the job finished
+fn format_job(result: &serde_json::Value) -> String {
+    let tool = result["tool"].as_str().unwrap_or("unknown");
+    let status = result["status"].as_str().unwrap_or("unknown");
+    let mut text = format!(
+        "Job {} ({}): {} after {} ms of {} ms",
+        result["jobId"].as_str().unwrap_or("unknown"),
+        tool,
+        status,
+        result["progress"]["elapsedMs"],
+        result["progress"]["timeoutMs"]
+    );
+    if result["cancelled"] == json!(false) {
+        text.push_str("\nThe job had already finished; nothing was cancelled.");
+    }
+    match result["interrupted"].as_bool() {
+        Some(true) => text.push_str("\nThe command was interrupted with Ctrl-C."),
+        Some(false) => text.push_str("\nThe command could not be interrupted; check the session."),
+        None => {}
+    }
+    match status {
+        "running" => text.push_str("\nPoll it with ht_get_job."),
+        "succeeded" => {
+            text.push_str("\n\n");
+            text.push_str(&format_tool_response(tool, &result["result"]));
+        }
+        "failed" => {
+            let error = &result["error"];
+            text.push_str(&format!(
+                "\n\nError ({}): {}\nHint: {}",
+                error["code"].as_str().unwrap_or("unknown"),
+                error["message"].as_str().unwrap_or("unknown error"),
+                error["hint"].as_str().unwrap_or("")
+            ));
+        }
+        _ => {}
+    }
+    text
+}
+
 /// Format tool response into human-readable text matching TypeScript implementation
 fn format_tool_response(tool_name: &str, result: &serde_json::Value) -> String {
     match tool_name {
+        // Started with async, or a job looked up
+        _ if result["jobId"].is_string() => format_job(result),
+        // The caller asked for the compressed payload; pass it through whole
+        _ if result["compressed"].is_object() => serde_json::to_string_pretty(result)
+            .unwrap_or_else(|_| "Error formatting result".to_string()),
         "ht_create_session" => {
             let session_id = result["sessionId"].as_str().unwrap_or("unknown");
             let web_server_enabled = result["webServerEnabled"].as_bool().unwrap_or(false);
@@ -256,11 +841,63 @@ fn format_tool_response(tool_name: &str, result: &serde_json::Value) -> String {
                 String::new()
             };
 
+            let temp_dir_info = result["tempDir"]
+                .as_str()
+                .map(|path| format!("\n\n📁 Temporary directory: {} ($HT_SESSION_TMP)", path))
+                .unwrap_or_default();
+
+            let warnings: String = result["warnings"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|warning| warning.as_str())
+                .map(|warning| format!("\n\n⚠️ {}", warning))
+                .collect();
+
+            let headline = if result["idempotentReplay"].as_bool().unwrap_or(false) {
+                "HT session already created for this idempotency key."
+            } else if result["fromPool"].as_bool().unwrap_or(false) {
+                "HT session created successfully (from the warm pool)!"
+            } else {
+                "HT session created successfully!"
+            };
+
             format!(
-                "HT session created successfully!\n\nSession ID: {}\n\nYou can now use this session ID with other HT tools to send commands and take snapshots.{}",
-                session_id, web_server_info
+                "{}\n\nSession ID: {}\n\nYou can now use this session ID with other HT tools to send commands and take snapshots.{}{}{}",
+                headline, session_id, web_server_info, temp_dir_info, warnings
             )
         }
+        "ht_create_session_with_tunnel" => {
+            format!(
+                "HT session created successfully!\n\nSession ID: {}\n\n🌐 View live terminal at: {}\n\n🔗 Public tunnel: {} (tunnel ID: {})",
+                result["sessionId"].as_str().unwrap_or("unknown"),
+                result["webServerUrl"].as_str().unwrap_or("unknown"),
+                result["tunnelUrl"].as_str().unwrap_or("unknown"),
+                result["tunnelId"].as_str().unwrap_or("unknown")
+            )
+        }
+        "ht_type_file" => format!(
+            "Typed {} bytes ({} lines) from {} into session {} in {} chunks",
+            result["bytes"].as_u64().unwrap_or(0),
+            result["lines"].as_u64().unwrap_or(0),
+            result["path"].as_str().unwrap_or("unknown"),
+            result["sessionId"].as_str().unwrap_or("unknown"),
+            result["chunks"].as_u64().unwrap_or(0)
+        ),
+        "ht_close_stdin" => format!(
+            "EOF sent to session {}",
+            result["sessionId"].as_str().unwrap_or("unknown")
+        ),
+        "ht_session_stdin_pipe" => format!(
+            "Piped {} bytes to session {}{}",
+            result["bytes"].as_u64().unwrap_or(0),
+            result["sessionId"].as_str().unwrap_or("unknown"),
+            if result["closed"].as_bool().unwrap_or(false) {
+                ", then EOF"
+            } else {
+                ""
+            }
+        ),
         "ht_send_keys" => {
             let session_id = result["sessionId"].as_str().unwrap_or("unknown");
             let keys = result["keys"]
@@ -272,28 +909,465 @@ fn format_tool_response(tool_name: &str, result: &serde_json::Value) -> String {
                 })
                 .unwrap_or_default();
 
+            let pacing = match result["inputProfile"].as_str() {
+                Some(profile) => format!(
+                    "\nTyped as {} in {} ms (seed {})",
+                    profile, result["typedMs"], result["seed"]
+                ),
+                None => String::new(),
+            };
+
             format!(
-                "Keys sent successfully to session {}\n\nKeys: {}",
+                "Keys sent successfully to session {}\n\nKeys: {}{}",
                 session_id,
-                serde_json::to_string(&keys).unwrap_or_else(|_| "[]".to_string())
+                serde_json::to_string(&keys).unwrap_or_else(|_| "[]".to_string()),
+                pacing
             )
         }
+        "ht_take_snapshot" if result["probeOnly"].as_bool().unwrap_or(false) => format!(
+            "Snapshot of session {}: {} bytes, {} lines, about {} tokens{} (screen version {})",
+            result["sessionId"].as_str().unwrap_or("unknown"),
+            result["bytes"],
+            result["lineCount"],
+            result["estimatedTokens"],
+            format_would_truncate(&result["truncated"]),
+            result["screenVersion"]
+        ),
         "ht_take_snapshot" => {
             let session_id = result["sessionId"].as_str().unwrap_or("unknown");
             let snapshot = result["snapshot"].as_str().unwrap_or("No snapshot data");
+            let wrapped = result["wrapWidth"]
+                .as_u64()
+                .map(|width| format!(", wrapped at {} columns", width))
+                .unwrap_or_default();
+            let truncated = result["omittedLines"]
+                .as_u64()
+                .map(|lines| format!(", {} lines omitted from the top to fit maxTokens", lines))
+                .unwrap_or_default();
+
+            let cursor = match (result["cursorRow"].as_u64(), result["cursorCol"].as_u64()) {
+                (Some(row), Some(col)) => format!(
+                    ", cursor at row {} col {}{}",
+                    row,
+                    col,
+                    if result["cursorVisible"] == false {
+                        ", hidden"
+                    } else {
+                        ""
+                    }
+                ),
+                _ => String::new(),
+            };
+            let styled_lines = match &result["styledLines"] {
+                serde_json::Value::Null => String::new(),
+                lines => format!(
+                    "\n\nStyled lines:\n```json\n{}\n```",
+                    serde_json::to_string(lines).unwrap_or_else(|_| "[]".to_string())
+                ),
+            };
 
             format!(
-                "Terminal Snapshot (Session: {})\n\n```\n{}\n```",
-                session_id, snapshot
+                "Terminal Snapshot (Session: {}{}{}{})\n\n```\n{}\n```{}{}{}",
+                session_id,
+                cursor,
+                wrapped,
+                truncated,
+                snapshot,
+                styled_lines,
+                format_annotations(&result["annotations"]),
+                format_provenance(&result["provenance"])
+            )
+        }
+        "ht_take_snapshot_as_png" => format!(
+            "Screenshot of session {}: {}x{} PNG at scale {}, attached as an image{}",
+            result["sessionId"].as_str().unwrap_or("unknown"),
+            result["width"],
+            result["height"],
+            result["scale"],
+            format_provenance(&result["provenance"])
+        ),
+        "ht_verify_snapshot" => {
+            let hash = result["hash"].as_str().unwrap_or("unknown");
+            let verdict = match (
+                result["known"].as_bool().unwrap_or(false),
+                result["contentMatches"].as_bool(),
+            ) {
+                (_, Some(false)) => format!(
+                    "❌ The text does not match hash {}: it hashes to {}, so it was changed",
+                    hash,
+                    result["contentHash"].as_str().unwrap_or("unknown")
+                ),
+                (false, _) => format!(
+                    "❓ Hash {} is unknown: no provenance block handed out or retained snapshot has it",
+                    hash
+                ),
+                (true, Some(true)) => format!("✅ Hash {} is known and the text matches it", hash),
+                (true, None) => format!("✅ Hash {} is known", hash),
+            };
+            let sources: Vec<String> = result["issued"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(|issued| {
+                    format!(
+                        "- handed out for session {} on {} at {} (screen version {})",
+                        issued["sessionId"].as_str().unwrap_or("unknown"),
+                        issued["host"].as_str().unwrap_or("unknown"),
+                        issued["timestamp"].as_str().unwrap_or("unknown"),
+                        issued["screenVersion"]
+                    )
+                })
+                .chain(result["history"].as_array().into_iter().flatten().map(|entry| {
+                    format!(
+                        "- in the snapshot history of session {}, taken at {}",
+                        entry["sessionId"].as_str().unwrap_or("unknown"),
+                        entry["takenAt"].as_str().unwrap_or("unknown")
+                    )
+                }))
+                .collect();
+            if sources.is_empty() {
+                verdict
+            } else {
+                format!("{}\n\n{}", verdict, sources.join("\n"))
+            }
+        }
+        "ht_take_baseline_snapshot" => format!(
+            "Baseline '{}' of session {} saved to {}",
+            result["baselineId"].as_str().unwrap_or("unknown"),
+            result["sessionId"].as_str().unwrap_or("unknown"),
+            result["path"].as_str().unwrap_or("unknown")
+        ),
+        "ht_compare_to_baseline" => {
+            let baseline_id = result["baselineId"].as_str().unwrap_or("unknown");
+            let default_diff = vec![];
+            let diff = result["diff"].as_array().unwrap_or(&default_diff);
+            if result["matches"].as_bool().unwrap_or(false) {
+                format!("✅ Screen matches baseline '{}'", baseline_id)
+            } else {
+                let side = |value: &serde_json::Value| match value.as_str() {
+                    Some(text) => format!("{:?}", text),
+                    None => "(no line)".to_string(),
+                };
+                let lines: Vec<String> = diff
+                    .iter()
+                    .map(|d| {
+                        format!(
+                            "Line {}:\n  expected: {}\n  actual:   {}",
+                            d["line"].as_u64().unwrap_or(0),
+                            side(&d["expected"]),
+                            side(&d["actual"])
+                        )
+                    })
+                    .collect();
+                format!(
+                    "❌ Screen differs from baseline '{}' on {} lines:\n\n{}",
+                    baseline_id,
+                    diff.len(),
+                    lines.join("\n")
+                )
+            }
+        }
+        "ht_classify_screen" => {
+            let session_id = result["sessionId"].as_str().unwrap_or("unknown");
+            let state = result["state"].as_str().unwrap_or("unknown");
+            let confidence = result["confidence"].as_f64().unwrap_or(0.0);
+            let default_evidence = vec![];
+            let evidence: Vec<String> = result["evidence"]
+                .as_array()
+                .unwrap_or(&default_evidence)
+                .iter()
+                .filter_map(|item| item.as_str())
+                .map(|item| format!("- {}", item))
+                .collect();
+
+            format!(
+                "Screen State (Session: {}): {} (confidence {:.2})\n\n{}",
+                session_id,
+                state,
+                confidence,
+                evidence.join("\n")
+            )
+        }
+        "ht_assert_screen" => {
+            let session_id = result["sessionId"].as_str().unwrap_or("unknown");
+            let default_results = vec![];
+            let results = result["results"].as_array().unwrap_or(&default_results);
+
+            let entries: Vec<String> = results
+                .iter()
+                .map(|entry| {
+                    let outcome = if entry["passed"].as_bool().unwrap_or(false) {
+                        "PASS".to_string()
+                    } else {
+                        match entry.get("actual") {
+                            Some(actual) => format!("FAIL (actual: {})", actual),
+                            None => "FAIL".to_string(),
+                        }
+                    };
+                    format!("- {} {}", outcome, entry["assertion"])
+                })
+                .collect();
+            let snapshot = result["snapshot"]
+                .as_str()
+                .map(|snapshot| format!("\n\nScreen:\n```\n{}\n```", snapshot))
+                .unwrap_or_default();
+
+            format!(
+                "Screen assertions {} (Session: {}, {} of {} failed)\n\n{}{}",
+                if result["passed"].as_bool().unwrap_or(false) {
+                    "passed"
+                } else {
+                    "failed"
+                },
+                session_id,
+                result["failures"].as_u64().unwrap_or(0),
+                results.len(),
+                entries.join("\n"),
+                snapshot
+            )
+        }
+        "ht_get_snapshot_history" if result["probeOnly"].as_bool().unwrap_or(false) => format!(
+            "Snapshot history of session {}: {} snapshots, {} bytes, {} lines, about {} tokens{} (screen version {})",
+            result["sessionId"].as_str().unwrap_or("unknown"),
+            result["snapshotCount"],
+            result["bytes"],
+            result["lineCount"],
+            result["estimatedTokens"],
+            format_would_truncate(&result["truncated"]),
+            result["screenVersion"]
+        ),
+        "ht_get_snapshot_history" => {
+            let session_id = result["sessionId"].as_str().unwrap_or("unknown");
+            let default_snapshots = vec![];
+            let snapshots = result["snapshots"].as_array().unwrap_or(&default_snapshots);
+
+            let entries: Vec<String> = snapshots
+                .iter()
+                .map(|entry| {
+                    format!(
+                        "{} ms ago:\n```\n{}\n```",
+                        entry["elapsedMs"].as_u64().unwrap_or(0),
+                        entry["snapshot"].as_str().unwrap_or("")
+                    )
+                })
+                .collect();
+
+            let omitted = match result["omittedSnapshots"].as_u64() {
+                Some(omitted) if omitted > 0 => {
+                    format!(", {} older omitted to fit maxTokens", omitted)
+                }
+                _ => String::new(),
+            };
+
+            format!(
+                "Snapshot History (Session: {}, {} snapshots{})\n\n{}",
+                session_id,
+                snapshots.len(),
+                omitted,
+                entries.join("\n\n")
+            )
+        }
+        "ht_get_session_metrics_history" => {
+            let session_id = result["sessionId"].as_str().unwrap_or("unknown");
+            let default_points = vec![];
+            let points = result["history"].as_array().unwrap_or(&default_points);
+
+            let entries: Vec<String> = points
+                .iter()
+                .map(|point| {
+                    let memory = point["memoryRssKb"]
+                        .as_u64()
+                        .map_or("n/a".to_string(), |kb| format!("{} KiB", kb));
+                    format!(
+                        "{}: {} snapshots, {} bytes in, {} bytes out, RSS {}",
+                        point["timestamp"].as_str().unwrap_or("unknown"),
+                        point["snapshotCount"].as_u64().unwrap_or(0),
+                        point["inputBytes"].as_u64().unwrap_or(0),
+                        point["outputBytes"].as_u64().unwrap_or(0),
+                        memory
+                    )
+                })
+                .collect();
+
+            format!(
+                "Metrics History (Session: {}, every {} s)\n\n{}",
+                session_id,
+                result["intervalSecs"].as_u64().unwrap_or(0),
+                entries.join("\n")
+            )
+        }
+        "ht_export_dockerfile" => {
+            let session_id = result["sessionId"].as_str().unwrap_or("unknown");
+            let dockerfile = result["dockerfile"].as_str().unwrap_or_default();
+            let build_args: Vec<&str> = result["buildArgs"]
+                .as_array()
+                .map(|args| args.iter().filter_map(|arg| arg.as_str()).collect())
+                .unwrap_or_default();
+            let build_args = if build_args.is_empty() {
+                String::new()
+            } else {
+                format!("\nBuild arguments: {}", build_args.join(", "))
+            };
+
+            format!(
+                "Dockerfile (Session: {})\n\n```dockerfile\n{}```\n\n{}{}",
+                session_id,
+                dockerfile,
+                result["note"].as_str().unwrap_or_default(),
+                build_args
             )
         }
         "ht_execute_command" => {
             let command = result["command"].as_str().unwrap_or("unknown");
             let output = result["output"].as_str().unwrap_or("No output");
+            let exited = if result["sessionExited"].as_bool().unwrap_or(false) {
+                match result["exitStatus"].as_i64() {
+                    Some(status) => format!(
+                        "\n\nThe session exited (status {}) before the command finished",
+                        status
+                    ),
+                    None => "\n\nThe session exited before the command finished".to_string(),
+                }
+            } else if result["timedOut"].as_bool().unwrap_or(false) {
+                "\n\nStill running: timed out waiting for the command to finish".to_string()
+            } else if let Some(code) = result["exitCode"].as_i64() {
+                format!("\n\nExit code: {}", code)
+            } else {
+                String::new()
+            };
 
+            let alias = match result["alias"]["invoked"].as_str() {
+                Some(invoked) => format!(" (alias: {})", invoked),
+                None => String::new(),
+            };
             format!(
-                "Command executed: {}\n\nTerminal Output:\n```\n{}\n```",
-                command, output
+                "Command executed: {}{}\n\nTerminal Output:\n```\n{}\n```{}{}{}",
+                command,
+                alias,
+                output,
+                exited,
+                format_annotations(&result["annotations"]),
+                format_summary(&result["summary"])
+            )
+        }
+        "ht_set_alias" => {
+            let name = result["name"].as_str().unwrap_or("unknown");
+            let session_id = result["sessionId"].as_str().unwrap_or("unknown");
+            match result["command"].as_str() {
+                Some(command) => format!(
+                    "Alias {} in session {} runs: {}",
+                    name, session_id, command
+                ),
+                None => format!("Alias {} removed from session {}", name, session_id),
+            }
+        }
+        "ht_check_command_exists" => {
+            let command = result["command"].as_str().unwrap_or("unknown");
+            let session_id = result["sessionId"].as_str().unwrap_or("unknown");
+            match result["path"].as_str() {
+                Some(path) => format!("{} is available in session {}: {}", command, session_id, path),
+                None => format!("{} is not available in session {}", command, session_id),
+            }
+        }
+        "ht_probe_tools" => {
+            let session_id = result["sessionId"].as_str().unwrap_or("unknown");
+            let shell = &result["shell"];
+            let mut lines = vec![format!(
+                "Shell in session {}: {} {}",
+                session_id,
+                shell["name"].as_str().unwrap_or("unknown"),
+                shell["version"].as_str().unwrap_or("(version unknown)")
+            )];
+            if let Some(tools) = result["tools"].as_object() {
+                for (tool, info) in tools {
+                    let line = match info["path"].as_str() {
+                        Some(path) => {
+                            let mut line = format!("✅ {}: {}", tool, path);
+                            if let Some(version) = info["version"].as_str() {
+                                line.push_str(&format!(" ({})", version));
+                            }
+                            if let Some(flavor) = info["flavor"].as_str() {
+                                line.push_str(&format!(" [{}]", flavor));
+                            }
+                            line
+                        }
+                        None => format!("❌ {}: not found", tool),
+                    };
+                    lines.push(line);
+                }
+            }
+            if result["cached"].as_bool().unwrap_or(false) {
+                lines.push("(from an earlier probe)".to_string());
+            }
+            lines.join("\n")
+        }
+        "ht_interactive_prompt" => {
+            let session_id = result["sessionId"].as_str().unwrap_or("unknown");
+            let snapshot = result["snapshot"].as_str().unwrap_or("No snapshot");
+            let outcome = if result["promptDetected"].as_bool().unwrap_or(false) {
+                "Prompt detected and answered"
+            } else {
+                "Prompt did not appear before the timeout; nothing was sent"
+            };
+            format!(
+                "{} in session {}\n\nTerminal Snapshot:\n```\n{}\n```",
+                outcome, session_id, snapshot
+            )
+        }
+        "ht_session_group_snapshot" => {
+            let group_id = result["groupId"].as_str().unwrap_or("unknown");
+            let default_snapshots = vec![];
+            let snapshots = result["snapshots"].as_array().unwrap_or(&default_snapshots);
+
+            let entries: Vec<String> = snapshots
+                .iter()
+                .map(|entry| {
+                    let session_id = entry["sessionId"].as_str().unwrap_or("unknown");
+                    match entry["error"].as_str() {
+                        Some(error) => format!("Session {}: failed: {}", session_id, error),
+                        None => format!(
+                            "Session {}:\n```\n{}\n```",
+                            session_id,
+                            entry["snapshot"].as_str().unwrap_or("")
+                        ),
+                    }
+                })
+                .collect();
+
+            format!(
+                "Group {} ({} snapshots, {} failed)\n\n{}",
+                group_id,
+                result["succeeded"],
+                result["failed"],
+                entries.join("\n\n")
+            )
+        }
+        "ht_parallel_execute" => {
+            let command = result["command"].as_str().unwrap_or("unknown");
+            let default_results = vec![];
+            let results = result["results"].as_array().unwrap_or(&default_results);
+
+            let entries: Vec<String> = results
+                .iter()
+                .map(|entry| {
+                    let session_id = entry["sessionId"].as_str().unwrap_or("unknown");
+                    match entry["error"].as_str() {
+                        Some(error) => format!("Session {}: failed: {}", session_id, error),
+                        None => format!(
+                            "Session {}:\n```\n{}\n```",
+                            session_id,
+                            entry["output"].as_str().unwrap_or("No output")
+                        ),
+                    }
+                })
+                .collect();
+
+            format!(
+                "Command executed in {} sessions ({} failed): {}\n\n{}",
+                results.len(),
+                result["failed"].as_u64().unwrap_or(0),
+                command,
+                entries.join("\n\n")
             )
         }
         "ht_list_sessions" => {
@@ -310,26 +1384,375 @@ fn format_tool_response(tool_name: &str, result: &serde_json::Value) -> String {
                         let id = session["id"].as_str().unwrap_or("unknown");
                         let is_alive = session["isAlive"].as_bool().unwrap_or(false);
                         let created_at = session["createdAt"].as_u64().unwrap_or(0);
+                        let icon = session["labelIcon"]
+                            .as_str()
+                            .map(|icon| format!("{} ", icon))
+                            .unwrap_or_default();
+                        let created_by = format_created_by(&session["createdBy"]);
 
                         format!(
-                            "- {} ({}) - Created: {}",
+                            "- {}{} ({}) - Created: {}{}",
+                            icon,
                             id,
                             if is_alive { "alive" } else { "dead" },
-                            created_at
+                            created_at,
+                            created_by
                         )
                     })
                     .collect();
 
                 format!(
-                    "Active HT Sessions ({}):\n\n{}",
+                    "Active HT Sessions ({}):\n\n{}{}",
                     count,
-                    session_list.join("\n")
+                    session_list.join("\n"),
+                    format_page(result)
+                )
+            }
+        }
+        "ht_get_session_info" => {
+            let size = match (result["cols"].as_u64(), result["rows"].as_u64()) {
+                (Some(cols), Some(rows)) => format!(", {}x{}", cols, rows),
+                _ => String::new(),
+            };
+            format!(
+                "Session {} ({}), up {} s{}\n\n```json\n{}\n```",
+                result["id"].as_str().unwrap_or("unknown"),
+                if result["isAlive"].as_bool().unwrap_or(false) {
+                    "alive"
+                } else {
+                    "dead"
+                },
+                result["uptimeSecs"].as_u64().unwrap_or(0),
+                size,
+                serde_json::to_string_pretty(result).unwrap_or_default()
+            )
+        }
+        "ht_generate_mcp_config" => format!(
+            "MCP client config ({} transport); add it to claude_desktop_config.json:\n\n```json\n{}\n```",
+            result["transport"].as_str().unwrap_or("stdio"),
+            result["config"].as_str().unwrap_or("{}")
+        ),
+        "ht_create_tunnel" => format!(
+            "Tunnel {} created.\n\n🔗 Public URL: {} -> localhost:{}",
+            result["tunnelId"].as_str().unwrap_or("unknown"),
+            result["url"].as_str().unwrap_or("unknown"),
+            result["localPort"].as_u64().unwrap_or(0)
+        ),
+        "ht_stop_tunnel" => match result["sessionId"].as_str() {
+            Some(session_id) => format!(
+                "Tunnel {} stopped; session {} keeps running without a public URL.",
+                result["tunnelId"].as_str().unwrap_or("unknown"),
+                session_id
+            ),
+            None => format!(
+                "Tunnel {} stopped.",
+                result["tunnelId"].as_str().unwrap_or("unknown")
+            ),
+        },
+        "ht_list_tunnels" => {
+            let default_tunnels = vec![];
+            let tunnels = result["tunnels"].as_array().unwrap_or(&default_tunnels);
+
+            if tunnels.is_empty() {
+                "Active Tunnels (0):\n\nNo active tunnels".to_string()
+            } else {
+                let tunnel_list: Vec<String> = tunnels
+                    .iter()
+                    .map(|tunnel| {
+                        format!(
+                            "- {} {} -> localhost:{} (session {}) - Created: {}{}{}",
+                            tunnel["id"].as_str().unwrap_or("unknown"),
+                            tunnel["url"].as_str().unwrap_or("unknown"),
+                            tunnel["localPort"].as_u64().unwrap_or(0),
+                            tunnel["sessionId"].as_str().unwrap_or("none"),
+                            tunnel["createdAt"].as_u64().unwrap_or(0),
+                            format_created_by(&tunnel["createdBy"]),
+                            if tunnel["isActive"] == false {
+                                " [not running]"
+                            } else {
+                                ""
+                            }
+                        )
+                    })
+                    .collect();
+
+                format!(
+                    "Active Tunnels ({}):\n\n{}{}",
+                    tunnels.len(),
+                    tunnel_list.join("\n"),
+                    format_page(result)
                 )
             }
         }
+        "ht_resource_report" => {
+            let default_sessions = vec![];
+            let sessions = result["sessions"].as_array().unwrap_or(&default_sessions);
+            let usage = |entry: &serde_json::Value| match entry["memoryRssKb"].as_u64() {
+                Some(rss_kb) => format!(
+                    "{} KiB RSS, {:.1}% CPU",
+                    rss_kb,
+                    entry["cpuPercent"].as_f64().unwrap_or(0.0)
+                ),
+                None => "usage unavailable".to_string(),
+            };
+
+            let session_list: Vec<String> = sessions
+                .iter()
+                .map(|session| {
+                    format!(
+                        "- {} (pid {}): {}, {} bytes output, up {}s",
+                        session["sessionId"].as_str().unwrap_or("unknown"),
+                        session["pid"]
+                            .as_u64()
+                            .map_or("?".to_string(), |pid| pid.to_string()),
+                        usage(session),
+                        session["outputBytes"].as_u64().unwrap_or(0),
+                        session["uptimeSecs"].as_u64().unwrap_or(0)
+                    )
+                })
+                .collect();
+
+            let totals = &result["totals"];
+            format!(
+                "Resource Report ({} sessions, {} ms old)\n\n{}\n\nTotal: {}, {} bytes output",
+                totals["sessionCount"].as_u64().unwrap_or(0),
+                result["ageMs"].as_u64().unwrap_or(0),
+                session_list.join("\n"),
+                usage(totals),
+                totals["outputBytes"].as_u64().unwrap_or(0)
+            )
+        }
+        "ht_reconnect_cloudflared" => {
+            format!(
+                "Tunnel of session {} reconnected.\n\n🔗 New public URL: {}\n(was: {})",
+                result["sessionId"].as_str().unwrap_or("unknown"),
+                result["tunnelUrl"].as_str().unwrap_or("unknown"),
+                result["previousTunnelUrl"].as_str().unwrap_or("none")
+            )
+        }
+        "ht_resize_terminal" => format!(
+            "Resized session {} from {}x{} to {}x{} (columns x rows)",
+            result["sessionId"].as_str().unwrap_or("unknown"),
+            result["oldSize"]["cols"].as_u64().unwrap_or(0),
+            result["oldSize"]["rows"].as_u64().unwrap_or(0),
+            result["newSize"]["cols"].as_u64().unwrap_or(0),
+            result["newSize"]["rows"].as_u64().unwrap_or(0)
+        ),
+        "ht_reserve_port" => format!(
+            "Port {} reserved for {}s; pass preferredPort: {} to ht_create_session to use it.",
+            result["port"].as_u64().unwrap_or(0),
+            result["expiresInMs"].as_u64().unwrap_or(0) / 1000,
+            result["port"].as_u64().unwrap_or(0)
+        ),
+        "ht_renew_lease" => {
+            let session_id = result["sessionId"].as_str().unwrap_or("unknown");
+            format!(
+                "Lease of session {} renewed: expires in {}s ({} renewals).",
+                session_id,
+                result["lease"]["expiresInMs"].as_u64().unwrap_or(0) / 1000,
+                result["lease"]["renewals"].as_u64().unwrap_or(0)
+            )
+        }
+        "ht_tail_file" => {
+            let session_id = result["sessionId"].as_str().unwrap_or("unknown");
+            let default_lines = vec![];
+            let lines = result["lines"].as_array().unwrap_or(&default_lines);
+            let mut text = format!(
+                "{} session {} tailing {}: {} line(s)",
+                if result["reused"].as_bool().unwrap_or(false) {
+                    "Reused"
+                } else {
+                    "Created"
+                },
+                session_id,
+                result["path"].as_str().unwrap_or("unknown"),
+                lines.len()
+            );
+            if let Some(dropped) = result["droppedLines"].as_u64().filter(|&n| n > 0) {
+                text.push_str(&format!(", {} dropped before this read", dropped));
+            }
+            text.push_str(&format!(
+                ". Next cursor: {}{}\n",
+                result["nextCursor"].as_u64().unwrap_or(0),
+                if result["hasMore"].as_bool().unwrap_or(false) {
+                    " (more lines waiting)"
+                } else {
+                    ""
+                }
+            ));
+            for line in lines {
+                text.push_str(&format!(
+                    "\n{:>6}  {}",
+                    line["line"].as_u64().unwrap_or(0),
+                    line["text"].as_str().unwrap_or("")
+                ));
+            }
+            text
+        }
+        "ht_acquire_lock" => {
+            let session_id = result["sessionId"].as_str().unwrap_or("unknown");
+            let mut text = format!(
+                "Session {} locked by {} until {}.",
+                session_id,
+                result["lock"]["holder"].as_str().unwrap_or("unknown"),
+                result["lock"]["expiresAt"].as_str().unwrap_or("unknown")
+            );
+            if let Some(previous) = result["previousHolder"].as_str() {
+                text.push_str(&format!(" Taken over from {}.", previous));
+            }
+            text
+        }
+        "ht_release_lock" => {
+            let session_id = result["sessionId"].as_str().unwrap_or("unknown");
+            match result["holder"].as_str() {
+                Some(holder) => format!("Released the lock of session {} held by {}.", session_id, holder),
+                None => format!("Session {} was not locked.", session_id),
+            }
+        }
+        "ht_get_connected_clients" => {
+            let default_clients = vec![];
+            let clients = result["clients"].as_array().unwrap_or(&default_clients);
+            let mut text = format!(
+                "{} viewer(s) connected to session {}",
+                result["count"].as_u64().unwrap_or(0),
+                result["sessionId"].as_str().unwrap_or("unknown")
+            );
+            for client in clients {
+                text.push_str(&format!(
+                    "\n  {} since {}",
+                    client["remoteAddr"].as_str().unwrap_or("unknown"),
+                    client["connectedAt"].as_str().unwrap_or("unknown")
+                ));
+            }
+            text
+        }
+        "ht_download_history" => match result["url"].as_str() {
+            Some(url) => format!(
+                "History ready at {} ({} bytes, CRC-32 {}); the link works once",
+                url,
+                result["totalBytes"].as_u64().unwrap_or(0),
+                result["totalCrc32"].as_str().unwrap_or("unknown")
+            ),
+            None => {
+                let next = match result["next"].as_u64() {
+                    Some(next) => format!("next cursor {}", next),
+                    None => "download complete".to_string(),
+                };
+                format!(
+                    "History download {}: bytes {}-{} of {} (CRC-32 {}, file CRC-32 {}), {}\n\n{}",
+                    result["token"].as_str().unwrap_or("unknown"),
+                    result["offset"].as_u64().unwrap_or(0),
+                    result["offset"].as_u64().unwrap_or(0) + result["bytes"].as_u64().unwrap_or(0),
+                    result["totalBytes"].as_u64().unwrap_or(0),
+                    result["crc32"].as_str().unwrap_or("unknown"),
+                    result["totalCrc32"].as_str().unwrap_or("unknown"),
+                    next,
+                    result["data"].as_str().unwrap_or("")
+                )
+            }
+        },
+        "ht_set_retention" => {
+            let retention = &result["retention"];
+            format!(
+                "Retention of session {} updated:\n- Snapshot history: {} of {} kept\n- Command history: {} of {} kept",
+                result["sessionId"].as_str().unwrap_or("unknown"),
+                retention["snapshotHistory"]["stored"].as_u64().unwrap_or(0),
+                retention["snapshotHistory"]["limit"].as_u64().unwrap_or(0),
+                retention["commandHistory"]["stored"].as_u64().unwrap_or(0),
+                retention["commandHistory"]["limit"].as_u64().unwrap_or(0)
+            )
+        }
         "ht_close_session" => {
             let session_id = result["sessionId"].as_str().unwrap_or("unknown");
-            format!("Session {} closed successfully.", session_id)
+            let mut stopped = Vec::new();
+            if result["webServerStopped"].as_bool().unwrap_or(false) {
+                stopped.push("web server");
+            }
+            if result["tunnelStopped"].as_bool().unwrap_or(false) {
+                stopped.push("tunnel");
+            }
+            if stopped.is_empty() {
+                format!("Session {} closed successfully.", session_id)
+            } else {
+                format!(
+                    "Session {} closed successfully; its {} stopped.",
+                    session_id,
+                    stopped.join(" and ")
+                )
+            }
+        }
+        "ht_bulk_close_sessions" => {
+            let default_entries = vec![];
+            let closed = result["closed"].as_array().unwrap_or(&default_entries);
+            let failed = result["failed"].as_array().unwrap_or(&default_entries);
+
+            let mut lines: Vec<String> = closed
+                .iter()
+                .map(|session_id| format!("- {}: closed", session_id.as_str().unwrap_or("unknown")))
+                .collect();
+            lines.extend(failed.iter().map(|entry| {
+                format!(
+                    "- {}: failed: {}",
+                    entry["sessionId"].as_str().unwrap_or("unknown"),
+                    entry["error"].as_str().unwrap_or("unknown error")
+                )
+            }));
+
+            format!(
+                "Closed {} sessions, {} failed\n\n{}",
+                closed.len(),
+                failed.len(),
+                lines.join("\n")
+            )
+        }
+        "ht_drain" => {
+            let draining = &result["draining"];
+            let exit = if draining["exitWhenEmpty"].as_bool().unwrap_or(false) {
+                " (or as soon as no sessions are left)"
+            } else {
+                ""
+            };
+            format!(
+                "Server draining: new sessions are refused. {} remaining sessions will be closed and the server will exit in {}s{}.",
+                result["sessionCount"].as_u64().unwrap_or(0),
+                draining["remainingMs"].as_u64().unwrap_or(0) / 1000,
+                exit
+            )
+        }
+        "ht_export_state" => format!(
+            "Exported {} sessions and {} recordings ({} files) to {} (format version {}{})",
+            result["sessions"].as_u64().unwrap_or(0),
+            result["recordings"].as_u64().unwrap_or(0),
+            result["recordingFiles"].as_u64().unwrap_or(0),
+            result["path"].as_str().unwrap_or("unknown"),
+            result["formatVersion"].as_u64().unwrap_or(0),
+            if result["envIncluded"] == true {
+                ", with env values"
+            } else {
+                ", env values left out"
+            }
+        ),
+        "ht_import_state" => {
+            let default_entries = vec![];
+            let sessions = result["sessions"].as_array().unwrap_or(&default_entries);
+            let warnings = result["warnings"].as_array().unwrap_or(&default_entries);
+            let mut text = format!(
+                "Imported {} sessions and {} recordings ({} files restored)",
+                sessions.len(),
+                result["recordings"].as_u64().unwrap_or(0),
+                result["recordingFiles"].as_u64().unwrap_or(0)
+            );
+            for session in sessions {
+                text.push_str(&format!(
+                    "\n  {} -> {}",
+                    session["sourceSessionId"].as_str().unwrap_or("unknown"),
+                    session["sessionId"].as_str().unwrap_or("unknown")
+                ));
+            }
+            for warning in warnings {
+                text.push_str(&format!("\n⚠️ {}", warning.as_str().unwrap_or("")));
+            }
+            text
         }
         _ => {
             // Fallback to JSON pretty print for unknown tools
@@ -0,0 +1,697 @@
+//! Management HTTP server, separate from the MCP transport.
+//!
+//! `GET /events` upgrades to a WebSocket that pushes every `SessionEvent` as
+//! a JSON text frame, `{"type": "session.created", "data": {...}}`. Pass
+//! `?session_id=...` to only receive events about that session.
+//!
+//! `GET /downloads/{token}` serves a history download started by
+//! `ht_download_history` with `viaUrl`, once. Downloads over
+//! `COMPRESSION_THRESHOLD_BYTES` are compressed for clients that send
+//! `Accept-Encoding: zstd` or `gzip`, zstd first.
+//!
+//! With `enable_admin_tools` and a `state_token`, `GET /state` returns the
+//! server's state archive and `POST /state` imports one sent as the body.
+//! Both need `Authorization: Bearer <state_token>`. `?includeEnv=true`
+//! exports env values too, if `allow_state_env_export` is set.
+//! `?recordingsRemapFrom=...&recordingsRemapTo=...` moves the recordings
+//! directory, to a target under `storage.recordings_dir`. The
+//! `export-state` and `import-state` subcommands use them.
+
+use crate::error::{HtMcpError, Result};
+use crate::ht_integration::events::{EventBus, SessionEvent};
+use crate::ht_integration::history_download::HistoryDownloads;
+use crate::ht_integration::state::{self, PathRemap};
+use crate::ht_integration::SessionManager;
+use crate::mcp::compression::{Compression, COMPRESSION_THRESHOLD_BYTES};
+use crate::mcp::types::HistoryFormat;
+use axum::body::Body;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{FromRef, Path, Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use futures::StreamExt;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, Mutex};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+/// Largest state archive `POST /state` accepts. The upload is written to a
+/// temporary file as it arrives, not held in memory.
+const MAX_STATE_UPLOAD_BYTES: usize = 64 * 1024 * 1024;
+
+/// What `/state` serves from, and what callers must present
+#[derive(Clone)]
+pub struct StateEndpoint {
+    pub sessions: Arc<Mutex<SessionManager>>,
+    /// Bearer token every request must carry
+    pub token: String,
+    /// Whether `includeEnv` may be asked for
+    pub allow_env_export: bool,
+}
+
+/// `/state`; `None` unless admin tools are enabled and `state_token` is set
+pub type StateAccess = Option<StateEndpoint>;
+
+#[derive(Debug, Deserialize)]
+pub struct EventsQuery {
+    pub session_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportStateQuery {
+    #[serde(default)]
+    pub include_env: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportStateQuery {
+    pub recordings_remap_from: Option<std::path::PathBuf>,
+    pub recordings_remap_to: Option<std::path::PathBuf>,
+}
+
+#[derive(Clone)]
+struct ManagementState {
+    events: EventBus,
+    downloads: HistoryDownloads,
+    sessions: StateAccess,
+}
+
+impl FromRef<ManagementState> for EventBus {
+    fn from_ref(state: &ManagementState) -> Self {
+        state.events.clone()
+    }
+}
+
+impl FromRef<ManagementState> for HistoryDownloads {
+    fn from_ref(state: &ManagementState) -> Self {
+        state.downloads.clone()
+    }
+}
+
+impl FromRef<ManagementState> for StateAccess {
+    fn from_ref(state: &ManagementState) -> Self {
+        state.sessions.clone()
+    }
+}
+
+pub fn router(events: EventBus, downloads: HistoryDownloads, sessions: StateAccess) -> Router {
+    Router::new()
+        .route("/events", get(events_handler))
+        .route("/downloads/:token", get(download_handler))
+        .route(
+            "/state",
+            get(export_state_handler).post(import_state_handler),
+        )
+        .with_state(ManagementState {
+            events,
+            downloads,
+            sessions,
+        })
+}
+
+/// Binds `address` and serves the management endpoints until the process exits
+pub async fn serve(
+    address: SocketAddr,
+    events: EventBus,
+    downloads: HistoryDownloads,
+    sessions: StateAccess,
+) -> Result<()> {
+    let listener = TcpListener::bind(address).await.map_err(|e| {
+        HtMcpError::Internal(format!(
+            "Failed to bind management server to {}: {}",
+            address, e
+        ))
+    })?;
+    info!("Management server listening on {}", address);
+    serve_on(listener, events, downloads, sessions).await
+}
+
+pub async fn serve_on(
+    listener: TcpListener,
+    events: EventBus,
+    downloads: HistoryDownloads,
+    sessions: StateAccess,
+) -> Result<()> {
+    axum::serve(listener, router(events, downloads, sessions))
+        .await
+        .map_err(|e| HtMcpError::Internal(format!("Management server error: {}", e)))
+}
+
+async fn events_handler(
+    ws: WebSocketUpgrade,
+    Query(query): Query<EventsQuery>,
+    State(events): State<EventBus>,
+) -> Response {
+    // Subscribe before the upgrade completes so no event published after the
+    // handshake is missed
+    let rx = events.subscribe();
+    ws.on_upgrade(move |socket| push_events(socket, rx, query.session_id))
+}
+
+async fn download_handler(
+    Path(token): Path<String>,
+    State(downloads): State<HistoryDownloads>,
+    headers: HeaderMap,
+) -> Response {
+    match downloads.take(&token) {
+        Some((format, content)) => {
+            let content_type = match format {
+                HistoryFormat::Plain => "text/plain; charset=utf-8",
+                HistoryFormat::Jsonl => "application/x-ndjson",
+            };
+            let encoding = [Compression::Zstd, Compression::Gzip]
+                .into_iter()
+                .find(|compression| accepts(&headers, compression.name()))
+                .filter(|_| content.len() > COMPRESSION_THRESHOLD_BYTES);
+            if let Some(compression) = encoding {
+                match compression.compress(content.as_bytes()) {
+                    Ok(compressed) => {
+                        return (
+                            [
+                                (header::CONTENT_TYPE, content_type),
+                                (header::CONTENT_ENCODING, compression.name()),
+                                (header::VARY, "accept-encoding"),
+                            ],
+                            compressed,
+                        )
+                            .into_response()
+                    }
+                    Err(e) => warn!("Sending download uncompressed: {}", e),
+                }
+            }
+            (
+                [
+                    (header::CONTENT_TYPE, content_type),
+                    (header::VARY, "accept-encoding"),
+                ],
+                content.to_string(),
+            )
+                .into_response()
+        }
+        None => (StatusCode::NOT_FOUND, "No such download\n").into_response(),
+    }
+}
+
+const STATE_NEEDS_ADMIN: &str =
+    "State export and import need enable_admin_tools and a state_token\n";
+
+/// The endpoint, for a request carrying its token
+fn state_endpoint(
+    access: StateAccess,
+    headers: &HeaderMap,
+) -> std::result::Result<StateEndpoint, Response> {
+    let Some(endpoint) = access else {
+        return Err((StatusCode::FORBIDDEN, STATE_NEEDS_ADMIN).into_response());
+    };
+    let presented = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if !presented.is_some_and(|token| same_token(token, &endpoint.token)) {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+            "Missing or wrong state token\n",
+        )
+            .into_response());
+    }
+    Ok(endpoint)
+}
+
+/// Compares tokens without stopping at the first difference, so response
+/// times do not tell how much of a guess was right
+fn same_token(presented: &str, token: &str) -> bool {
+    presented.len() == token.len()
+        && presented
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+async fn export_state_handler(
+    Query(query): Query<ExportStateQuery>,
+    State(access): State<StateAccess>,
+    headers: HeaderMap,
+) -> Response {
+    let endpoint = match state_endpoint(access, &headers) {
+        Ok(endpoint) => endpoint,
+        Err(response) => return response,
+    };
+    if query.include_env && !endpoint.allow_env_export {
+        return (
+            StatusCode::FORBIDDEN,
+            "includeEnv needs allow_state_env_export in the config\n",
+        )
+            .into_response();
+    }
+    let manifest = match endpoint
+        .sessions
+        .lock()
+        .await
+        .state_manifest(query.include_env)
+        .await
+    {
+        Ok(manifest) => manifest,
+        Err(e) => return state_error(e),
+    };
+    // Reads the recording files, so kept off the runtime's threads
+    let archive = tokio::task::spawn_blocking(move || {
+        let mut archive = Vec::new();
+        state::write_archive(&mut archive, &manifest).map(|_| archive)
+    })
+    .await;
+    match archive {
+        Ok(Ok(archive)) => (
+            [
+                (header::CONTENT_TYPE, "application/gzip"),
+                (
+                    header::CONTENT_DISPOSITION,
+                    "attachment; filename=\"state.tar.gz\"",
+                ),
+            ],
+            archive,
+        )
+            .into_response(),
+        Ok(Err(e)) => state_error(e.into()),
+        Err(e) => state_error(HtMcpError::Internal(e.to_string())),
+    }
+}
+
+async fn import_state_handler(
+    Query(query): Query<ImportStateQuery>,
+    State(access): State<StateAccess>,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    let endpoint = match state_endpoint(access, &headers) {
+        Ok(endpoint) => endpoint,
+        Err(response) => return response,
+    };
+    let remap = match (query.recordings_remap_from, query.recordings_remap_to) {
+        (Some(from), Some(to)) => Some(PathRemap { from, to }),
+        (None, None) => None,
+        _ => {
+            return (
+                StatusCode::BAD_REQUEST,
+                "recordingsRemapFrom and recordingsRemapTo go together\n",
+            )
+                .into_response()
+        }
+    };
+    let upload =
+        std::env::temp_dir().join(format!("ht-mcp-state-upload-{}.tar.gz", Uuid::new_v4()));
+    let imported = match receive_upload(body, &upload).await {
+        Ok(()) => {
+            endpoint
+                .sessions
+                .lock()
+                .await
+                .import_state_from(|| state::open(&upload), "request body", remap)
+                .await
+        }
+        Err(response) => {
+            let _ = tokio::fs::remove_file(&upload).await;
+            return response;
+        }
+    };
+    let _ = tokio::fs::remove_file(&upload).await;
+    match imported {
+        Ok(result) => Json(result).into_response(),
+        Err(e) => state_error(e),
+    }
+}
+
+/// Writes the request body to `path` as it arrives, refusing it once it
+/// grows past `MAX_STATE_UPLOAD_BYTES`
+async fn receive_upload(body: Body, path: &std::path::Path) -> std::result::Result<(), Response> {
+    let stored = |e: std::io::Error| {
+        state_error(HtMcpError::Internal(format!(
+            "Failed to store the upload: {}",
+            e
+        )))
+    };
+    let mut file = tokio::fs::File::create(path).await.map_err(stored)?;
+    let mut chunks = body.into_data_stream();
+    let mut received = 0;
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk.map_err(|e| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("Failed to read the upload: {}\n", e),
+            )
+                .into_response()
+        })?;
+        received += chunk.len();
+        if received > MAX_STATE_UPLOAD_BYTES {
+            return Err((
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!(
+                    "State archives over {} MiB are refused\n",
+                    MAX_STATE_UPLOAD_BYTES / (1024 * 1024)
+                ),
+            )
+                .into_response());
+        }
+        file.write_all(&chunk).await.map_err(stored)?;
+    }
+    file.flush().await.map_err(stored)
+}
+
+fn state_error(e: HtMcpError) -> Response {
+    let status = match e {
+        HtMcpError::InvalidRequest(_) | HtMcpError::ServerDraining(_) => StatusCode::BAD_REQUEST,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status, format!("{}\n", e)).into_response()
+}
+
+/// Whether the request's `Accept-Encoding` allows `coding`, i.e. lists it or
+/// `*` without `q=0`
+fn accepts(headers: &HeaderMap, coding: &str) -> bool {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut parts = coding.split(';').map(str::trim);
+            let name = parts.next().unwrap_or("");
+            let refused = parts.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            (name.eq_ignore_ascii_case(coding) || name == "*") && !refused
+        })
+}
+
+async fn push_events(
+    mut socket: WebSocket,
+    mut rx: broadcast::Receiver<SessionEvent>,
+    session_id: Option<String>,
+) {
+    loop {
+        tokio::select! {
+            event = rx.recv() => {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("Events client lagged, {} events dropped", skipped);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                if session_id.is_some() && event.session_id() != session_id.as_deref() {
+                    continue;
+                }
+                let frame = match serde_json::to_string(&event) {
+                    Ok(frame) => frame,
+                    Err(e) => {
+                        warn!("Failed to serialize {:?}: {}", event, e);
+                        continue;
+                    }
+                };
+                if socket.send(Message::Text(frame)).await.is_err() {
+                    break;
+                }
+            }
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                // Clients have nothing to say; pings are answered by axum
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+    debug!("Events client disconnected");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_tungstenite::tungstenite;
+
+    async fn start(events: EventBus) -> SocketAddr {
+        start_with(events, HistoryDownloads::default()).await
+    }
+
+    async fn start_with(events: EventBus, downloads: HistoryDownloads) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve_on(listener, events, downloads, None));
+        address
+    }
+
+    /// Status line and body of a plain HTTP/1.1 GET
+    async fn http_get(address: SocketAddr, path: &str) -> (String, String) {
+        let (head, body) = http_get_with(address, path, "").await;
+        (
+            head.lines().next().unwrap().to_string(),
+            String::from_utf8(body).unwrap(),
+        )
+    }
+
+    /// Head and raw body of a GET sending `extra_headers` (CRLF-terminated)
+    async fn http_get_with(
+        address: SocketAddr,
+        path: &str,
+        extra_headers: &str,
+    ) -> (String, Vec<u8>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n{}\r\n",
+            path, address, extra_headers
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        let split = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .unwrap();
+        let head = String::from_utf8(response[..split].to_vec()).unwrap();
+        (head, response[split + 4..].to_vec())
+    }
+
+    async fn next_frame(
+        client: &mut tokio_tungstenite::WebSocketStream<
+            tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+        >,
+    ) -> serde_json::Value {
+        let message = tokio::time::timeout(std::time::Duration::from_secs(5), client.next())
+            .await
+            .expect("an event frame")
+            .unwrap()
+            .unwrap();
+        match message {
+            tungstenite::Message::Text(text) => serde_json::from_str(&text).unwrap(),
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pushes_events_filtered_by_session() {
+        let events = EventBus::new();
+        let address = start(events.clone()).await;
+
+        let (mut all, _) = tokio_tungstenite::connect_async(format!("ws://{}/events", address))
+            .await
+            .unwrap();
+        let (mut only_b, _) =
+            tokio_tungstenite::connect_async(format!("ws://{}/events?session_id=b", address))
+                .await
+                .unwrap();
+
+        events.publish(SessionEvent::SessionCreated {
+            session_id: "a".to_string(),
+            created_by: None,
+        });
+        events.publish(SessionEvent::SessionCreated {
+            session_id: "b".to_string(),
+            created_by: None,
+        });
+
+        assert_eq!(
+            next_frame(&mut all).await,
+            serde_json::json!({"type": "session.created", "data": {"sessionId": "a"}})
+        );
+        assert_eq!(next_frame(&mut all).await["data"]["sessionId"], "b");
+        assert_eq!(next_frame(&mut only_b).await["data"]["sessionId"], "b");
+    }
+
+    #[tokio::test]
+    async fn test_download_is_served_once() {
+        let downloads = HistoryDownloads::default();
+        let address = start_with(EventBus::new(), downloads.clone()).await;
+        let content = "=== commands ===\n$ echo hi\n".to_string();
+        let info = downloads
+            .start("s1", HistoryFormat::Plain, content.clone(), true)
+            .unwrap();
+        let chunked = downloads
+            .start("s1", HistoryFormat::Plain, content.clone(), false)
+            .unwrap();
+
+        let (status, body) = http_get(address, &format!("/downloads/{}", info.token)).await;
+        assert!(status.contains("200"), "{}", status);
+        assert_eq!(body, content);
+        let (status, _) = http_get(address, &format!("/downloads/{}", info.token)).await;
+        assert!(status.contains("404"), "{}", status);
+        // Chunked downloads are not served over HTTP
+        let (status, _) = http_get(address, &format!("/downloads/{}", chunked.token)).await;
+        assert!(status.contains("404"), "{}", status);
+    }
+
+    #[tokio::test]
+    async fn test_large_download_is_compressed_when_accepted() {
+        let downloads = HistoryDownloads::default();
+        let address = start_with(EventBus::new(), downloads.clone()).await;
+        let content = "$ echo hi\nhi\n".repeat(COMPRESSION_THRESHOLD_BYTES / 8);
+        let start = || {
+            downloads
+                .start("s1", HistoryFormat::Plain, content.clone(), true)
+                .unwrap()
+                .token
+        };
+
+        let path = format!("/downloads/{}", start());
+        let (head, body) = http_get_with(address, &path, "Accept-Encoding: br, gzip\r\n").await;
+        assert!(
+            head.to_ascii_lowercase().contains("content-encoding: gzip"),
+            "{}",
+            head
+        );
+        assert!(body.len() < content.len() / 10);
+        assert_eq!(
+            crate::mcp::compression::gunzip(&body).unwrap(),
+            content.as_bytes()
+        );
+
+        // zstd is preferred when both are accepted
+        let path = format!("/downloads/{}", start());
+        let (head, body) = http_get_with(address, &path, "Accept-Encoding: gzip, zstd\r\n").await;
+        assert!(
+            head.to_ascii_lowercase().contains("content-encoding: zstd"),
+            "{}",
+            head
+        );
+        assert_eq!(zstd::decode_all(&body[..]).unwrap(), content.as_bytes());
+
+        // Refused or not offered: sent as is
+        for accept in ["Accept-Encoding: gzip;q=0\r\n", ""] {
+            let path = format!("/downloads/{}", start());
+            let (head, body) = http_get_with(address, &path, accept).await;
+            assert!(
+                !head.to_ascii_lowercase().contains("content-encoding"),
+                "{}",
+                head
+            );
+            assert_eq!(body, content.as_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_state_round_trip_needs_admin() {
+        let address = start(EventBus::new()).await;
+        let (status, _) = http_get(address, "/state").await;
+        assert!(status.contains("403"), "{}", status);
+
+        let manager = Arc::new(Mutex::new(SessionManager::new()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve_on(
+            listener,
+            EventBus::new(),
+            HistoryDownloads::default(),
+            Some(StateEndpoint {
+                sessions: manager,
+                token: "state-secret".to_string(),
+                allow_env_export: false,
+            }),
+        ));
+        let url = format!("http://{}/state", address);
+        let client = reqwest::Client::new();
+
+        // Neither endpoint answers without the token
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = client
+            .post(&url)
+            .bearer_auth("wrong-secret")
+            .body("not an archive")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        // Env values need allow_state_env_export
+        let response = client
+            .get(&url)
+            .bearer_auth("state-secret")
+            .query(&[("includeEnv", true)])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let archive = client
+            .get(&url)
+            .bearer_auth("state-secret")
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap()
+            .bytes()
+            .await
+            .unwrap();
+        let manifest = state::read_manifest(&archive[..], "download").unwrap();
+        assert!(manifest.sessions.is_empty());
+
+        // Recording files may only be written under recordings_dir, which
+        // this server does not have
+        let response = client
+            .post(&url)
+            .bearer_auth("state-secret")
+            .query(&[
+                ("recordingsRemapFrom", "/old"),
+                ("recordingsRemapTo", "/etc"),
+            ])
+            .body(archive.clone())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let imported: serde_json::Value = client
+            .post(&url)
+            .bearer_auth("state-secret")
+            .body(archive)
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(imported["sessions"], serde_json::json!([]));
+
+        let response = client
+            .post(&url)
+            .bearer_auth("state-secret")
+            .body("not an archive")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
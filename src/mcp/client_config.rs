@@ -0,0 +1,119 @@
+//! Snippets for adding this server to an MCP client such as Claude Desktop,
+//! for `ht_generate_mcp_config`
+
+use crate::config::CONFIG_ENV_VAR;
+use crate::error::{HtMcpError, Result};
+use crate::mcp::types::GenerateMcpConfigArgs;
+use serde_json::{json, Map, Value};
+
+/// Name the server is listed under in the client's `mcpServers`
+const SERVER_KEY: &str = "ht-mcp";
+
+/// The `mcpServers` entry for launching this binary, as pretty-printed JSON
+/// ready to paste into `claude_desktop_config.json`
+pub fn generate(args: &GenerateMcpConfigArgs) -> Result<Value> {
+    match args.transport.as_str() {
+        "stdio" => {}
+        // Accepted names, but this server has no listener for them yet
+        "socket" | "tcp" => {
+            return Err(HtMcpError::InvalidRequest(format!(
+                "ht-mcp only serves MCP over stdio; the {} transport is not available",
+                args.transport
+            )))
+        }
+        other => {
+            return Err(HtMcpError::InvalidRequest(format!(
+                "Unknown transport '{}': expected stdio, socket or tcp",
+                other
+            )))
+        }
+    }
+    if args.socket_path.is_some() || args.tcp_port.is_some() {
+        return Err(HtMcpError::InvalidRequest(
+            "socketPath and tcpPort only apply to the socket and tcp transports".to_string(),
+        ));
+    }
+
+    let command = std::env::current_exe()
+        .map(|exe| exe.display().to_string())
+        .unwrap_or_else(|_| "ht-mcp".to_string());
+    let config_path = std::env::var(CONFIG_ENV_VAR).ok();
+    let config = stdio_config(&command, config_path.as_deref());
+    Ok(json!({
+        "transport": args.transport,
+        "config": serde_json::to_string_pretty(&config)?
+    }))
+}
+
+/// A client launching `command` over stdio, passing on the config file the
+/// running server was pointed at with `$HT_MCP_CONFIG`
+fn stdio_config(command: &str, config_path: Option<&str>) -> Value {
+    let mut env = Map::new();
+    if let Some(path) = config_path {
+        env.insert(CONFIG_ENV_VAR.to_string(), json!(path));
+    }
+    json!({
+        "mcpServers": {
+            SERVER_KEY: {
+                "command": command,
+                "args": [],
+                "env": env
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(transport: &str) -> GenerateMcpConfigArgs {
+        GenerateMcpConfigArgs {
+            transport: transport.to_string(),
+            ..GenerateMcpConfigArgs::default()
+        }
+    }
+
+    #[test]
+    fn test_stdio_config() {
+        assert_eq!(
+            stdio_config("/usr/local/bin/ht-mcp", Some("/etc/ht-mcp.toml")),
+            json!({
+                "mcpServers": {
+                    "ht-mcp": {
+                        "command": "/usr/local/bin/ht-mcp",
+                        "args": [],
+                        "env": {"HT_MCP_CONFIG": "/etc/ht-mcp.toml"}
+                    }
+                }
+            })
+        );
+        assert_eq!(
+            stdio_config("ht-mcp", None)["mcpServers"]["ht-mcp"]["env"],
+            json!({})
+        );
+
+        // The snippet is a JSON string that parses back to the config
+        let result = generate(&args("stdio")).unwrap();
+        let config: Value = serde_json::from_str(result["config"].as_str().unwrap()).unwrap();
+        assert!(config["mcpServers"]["ht-mcp"]["command"].is_string());
+    }
+
+    #[test]
+    fn test_unavailable_transports() {
+        for transport in ["socket", "tcp", "http"] {
+            let err = generate(&args(transport)).unwrap_err();
+            assert!(
+                matches!(err, HtMcpError::InvalidRequest(_)),
+                "{}",
+                transport
+            );
+        }
+        let err = generate(&GenerateMcpConfigArgs {
+            tcp_port: Some(3000),
+            ..args("stdio")
+        })
+        .unwrap_err();
+        assert!(matches!(err, HtMcpError::InvalidRequest(_)));
+    }
+}
@@ -0,0 +1,83 @@
+//! Who is on the other end of an MCP connection, stamped onto what it
+//! creates as `createdBy` so a shared server can be audited.
+//!
+//! The stdio transport has a single client, identified by the `clientInfo`
+//! of its `initialize` request. Transports that serve several clients pass
+//! each connection's identity with its tool calls, see
+//! `HtMcpServer::handle_tool_call_as`.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Name reported for a client that sent no `clientInfo`
+const UNKNOWN_CLIENT: &str = "unknown";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientIdentity {
+    /// `clientInfo.name` from `initialize`
+    pub name: String,
+    /// `clientInfo.version` from `initialize`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// Principal the connection authenticated as, for transports that
+    /// authenticate; stdio has none
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth_identity: Option<String>,
+}
+
+impl ClientIdentity {
+    /// The identity in an `initialize` request's params
+    pub fn from_initialize(params: Option<&Value>, auth_identity: Option<String>) -> Self {
+        let client_info = params.map(|params| &params["clientInfo"]);
+        let field = |name: &str| {
+            client_info
+                .and_then(|info| info[name].as_str())
+                .filter(|value| !value.is_empty())
+                .map(str::to_string)
+        };
+        Self {
+            name: field("name").unwrap_or_else(|| UNKNOWN_CLIENT.to_string()),
+            version: field("version"),
+            auth_identity,
+        }
+    }
+
+    /// "name version (auth identity)", for text listings
+    pub fn label(&self) -> String {
+        let mut label = self.name.clone();
+        if let Some(version) = &self.version {
+            label.push_str(&format!(" {}", version));
+        }
+        if let Some(auth_identity) = &self.auth_identity {
+            label.push_str(&format!(" ({})", auth_identity));
+        }
+        label
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_from_initialize() {
+        let params = json!({
+            "protocolVersion": "2024-11-05",
+            "clientInfo": {"name": "claude-desktop", "version": "0.9.2"}
+        });
+        let identity = ClientIdentity::from_initialize(Some(&params), None);
+        assert_eq!(identity.name, "claude-desktop");
+        assert_eq!(identity.version.as_deref(), Some("0.9.2"));
+        assert_eq!(identity.label(), "claude-desktop 0.9.2");
+        assert_eq!(
+            serde_json::to_value(&identity).unwrap(),
+            json!({"name": "claude-desktop", "version": "0.9.2"})
+        );
+
+        let identity = ClientIdentity::from_initialize(Some(&json!({})), Some("alice".to_string()));
+        assert_eq!(identity.label(), "unknown (alice)");
+        assert_eq!(ClientIdentity::from_initialize(None, None).name, "unknown");
+    }
+}
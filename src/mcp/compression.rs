@@ -0,0 +1,270 @@
+//! Opt-in compression of large tool results.
+//!
+//! The heavy tools (snapshots, snapshot history, history downloads) accept
+//! a `compress` meta-field, `"gzip"` or `"zstd"`. When the result's JSON is
+//! larger than [`COMPRESSION_THRESHOLD_BYTES`], it is replaced by the
+//! compressed JSON, base64-encoded, with its size and CRC-32 and a note on
+//! decoding it. Smaller results, and results already in a compressed
+//! format, are returned as they are.
+
+use crate::error::{HtMcpError, Result};
+use crate::ht_integration::history_download::crc32;
+use base64::Engine;
+use serde_json::{json, Value};
+
+/// Results at most this large (as JSON) are never compressed
+pub const COMPRESSION_THRESHOLD_BYTES: usize = 16 * 1024;
+
+/// Tools that accept `compress`
+pub const COMPRESSIBLE_TOOLS: [&str; 4] = [
+    "ht_take_snapshot",
+    "ht_get_snapshot_history",
+    "ht_session_group_snapshot",
+    "ht_download_history",
+];
+
+/// Content types whose data compresses no further
+const COMPRESSED_CONTENT_TYPES: [&str; 4] = [
+    "image/png",
+    "image/jpeg",
+    "application/gzip",
+    "application/zstd",
+];
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const GZIP_DEFLATE: u8 = 8;
+/// deflate level; 6 is zlib's default trade-off
+const GZIP_LEVEL: u8 = 6;
+/// zstd level; 3 is the library's default
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    pub fn name(self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    pub fn compress(self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::Gzip => Ok(gzip(data)),
+            Compression::Zstd => Ok(zstd::bulk::compress(data, ZSTD_LEVEL)?),
+        }
+    }
+
+    fn decode_note(self) -> &'static str {
+        match self {
+            Compression::Gzip => "The result was compressed: base64-decode compressed.data, gunzip it and parse the JSON for the tool's usual result. originalBytes and crc32 (zlib's CRC-32) describe the decompressed JSON.",
+            Compression::Zstd => "The result was compressed: base64-decode compressed.data, decompress it with zstd (a single frame that records its size) and parse the JSON for the tool's usual result. originalBytes and crc32 (zlib's CRC-32) describe the decompressed JSON.",
+        }
+    }
+}
+
+/// The call's `compress` meta-field, refused for tools that do not accept it
+pub fn requested(tool_name: &str, arguments: &Value) -> Result<Option<Compression>> {
+    let value = match arguments.get("compress") {
+        None | Some(Value::Null) => return Ok(None),
+        Some(value) => value,
+    };
+    if !COMPRESSIBLE_TOOLS.contains(&tool_name) {
+        return Err(HtMcpError::InvalidRequest(format!(
+            "Invalid arguments: {} does not accept compress; it is accepted by {}",
+            tool_name,
+            COMPRESSIBLE_TOOLS.join(", ")
+        )));
+    }
+    match value.as_str() {
+        Some("gzip") => Ok(Some(Compression::Gzip)),
+        Some("zstd") => Ok(Some(Compression::Zstd)),
+        _ => Err(HtMcpError::InvalidRequest(format!(
+            "Invalid arguments: compress must be \"gzip\" or \"zstd\", got {}",
+            value
+        ))),
+    }
+}
+
+/// `result`, compressed when larger than `threshold` bytes of JSON
+pub fn compress_result(result: Value, compression: Compression, threshold: usize) -> Result<Value> {
+    if is_compressed_content(&result) {
+        return Ok(result);
+    }
+    let json = serde_json::to_vec(&result)?;
+    if json.len() <= threshold {
+        return Ok(result);
+    }
+    let compressed = compression.compress(&json)?;
+    let mut wrapped = json!({
+        "compressed": {
+            "encoding": compression.name(),
+            "data": base64::engine::general_purpose::STANDARD.encode(&compressed),
+            "originalBytes": json.len(),
+            "compressedBytes": compressed.len(),
+            "crc32": crc32(&json)
+        },
+        "note": compression.decode_note()
+    });
+    // Kept readable for logs and tracing
+    if let Some(session_id) = result.get("sessionId") {
+        wrapped["sessionId"] = session_id.clone();
+    }
+    Ok(wrapped)
+}
+
+/// Whether the result carries data in an already-compressed format
+fn is_compressed_content(result: &Value) -> bool {
+    ["contentType", "mimeType"].iter().any(|key| {
+        result[key]
+            .as_str()
+            .is_some_and(|content_type| COMPRESSED_CONTENT_TYPES.contains(&content_type))
+    })
+}
+
+/// `data` as a gzip member (RFC 1952) with no name or timestamp
+pub fn gzip(data: &[u8]) -> Vec<u8> {
+    let deflated = miniz_oxide::deflate::compress_to_vec(data, GZIP_LEVEL);
+    let mut out = Vec::with_capacity(deflated.len() + 18);
+    out.extend_from_slice(&GZIP_MAGIC);
+    // Method, no flags, no mtime, no extra flags, unknown OS
+    out.extend_from_slice(&[GZIP_DEFLATE, 0, 0, 0, 0, 0, 0, 255]);
+    out.extend_from_slice(&deflated);
+    out.extend_from_slice(&crc32(data).to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out
+}
+
+/// Decompresses a single gzip member as written by [`gzip`]
+pub fn gunzip(data: &[u8]) -> Result<Vec<u8>> {
+    let invalid = |what: &str| HtMcpError::InvalidRequest(format!("Invalid gzip data: {}", what));
+    if data.len() < 18 || data[..2] != GZIP_MAGIC || data[2] != GZIP_DEFLATE {
+        return Err(invalid("not a deflate gzip member"));
+    }
+    if data[3] != 0 {
+        return Err(invalid("header fields are not supported"));
+    }
+    let (body, trailer) = data[10..].split_at(data.len() - 18);
+    let out =
+        miniz_oxide::inflate::decompress_to_vec(body).map_err(|e| invalid(&format!("{:?}", e)))?;
+    let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+    let len = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+    if crc != crc32(&out) || len != out.len() as u32 {
+        return Err(invalid("checksum mismatch"));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decodes a compressed result back to the original
+    fn decode(wrapped: &Value) -> Value {
+        let compressed = &wrapped["compressed"];
+        let data = base64::engine::general_purpose::STANDARD
+            .decode(compressed["data"].as_str().unwrap())
+            .unwrap();
+        assert_eq!(compressed["compressedBytes"], data.len());
+        let json = match compressed["encoding"].as_str().unwrap() {
+            "gzip" => gunzip(&data).unwrap(),
+            "zstd" => zstd::decode_all(&data[..]).unwrap(),
+            other => panic!("unexpected encoding {}", other),
+        };
+        assert_eq!(compressed["originalBytes"], json.len());
+        assert_eq!(compressed["crc32"], crc32(&json));
+        serde_json::from_slice(&json).unwrap()
+    }
+
+    fn snapshot(lines: usize) -> Value {
+        json!({
+            "sessionId": "s1",
+            "snapshot": "$ ls -la\n".repeat(lines)
+        })
+    }
+
+    #[test]
+    fn test_gzip_round_trip() {
+        for data in [&b""[..], b"a", &b"repeated text ".repeat(1000)] {
+            let gzipped = gzip(data);
+            assert_eq!(gzipped[..2], GZIP_MAGIC);
+            assert_eq!(gunzip(&gzipped).unwrap(), data);
+        }
+
+        let mut corrupt = gzip(b"some text");
+        let last = corrupt.len() - 5;
+        corrupt[last] ^= 1;
+        assert!(gunzip(&corrupt).is_err());
+    }
+
+    #[test]
+    fn test_zstd_round_trip() {
+        for data in [&b""[..], b"a", &b"repeated text ".repeat(1000)] {
+            let compressed = Compression::Zstd.compress(data).unwrap();
+            assert_eq!(zstd::decode_all(&compressed[..]).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_compress_result_round_trip() {
+        let result = snapshot(5000);
+        for (compression, tool) in [(Compression::Gzip, "gunzip"), (Compression::Zstd, "zstd")] {
+            let wrapped = compress_result(result.clone(), compression, 1024).unwrap();
+            assert_eq!(wrapped["sessionId"], "s1");
+            assert_eq!(wrapped["compressed"]["encoding"], compression.name());
+            assert!(wrapped["note"].as_str().unwrap().contains(tool));
+            let compressed_bytes = wrapped["compressed"]["compressedBytes"].as_u64().unwrap();
+            assert!(
+                compressed_bytes < wrapped["compressed"]["originalBytes"].as_u64().unwrap() / 10
+            );
+            assert_eq!(decode(&wrapped), result);
+        }
+    }
+
+    #[test]
+    fn test_threshold_and_compressed_content() {
+        let small = snapshot(2);
+        let size = serde_json::to_vec(&small).unwrap().len();
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            assert_eq!(
+                compress_result(small.clone(), compression, size).unwrap(),
+                small
+            );
+            assert!(
+                compress_result(small.clone(), compression, size - 1).unwrap()["compressed"]
+                    .is_object()
+            );
+
+            let png = json!({"contentType": "image/png", "data": "iVBORw0KGgo".repeat(1000)});
+            assert_eq!(compress_result(png.clone(), compression, 0).unwrap(), png);
+        }
+    }
+
+    #[test]
+    fn test_requested() {
+        let args = |compress: Value| json!({"sessionId": "s1", "compress": compress});
+        assert_eq!(
+            requested("ht_take_snapshot", &json!({"sessionId": "s1"})).unwrap(),
+            None
+        );
+        assert_eq!(
+            requested("ht_take_snapshot", &args(json!("gzip"))).unwrap(),
+            Some(Compression::Gzip)
+        );
+        assert_eq!(
+            requested("ht_download_history", &args(json!("zstd"))).unwrap(),
+            Some(Compression::Zstd)
+        );
+        for (tool, compress) in [
+            ("ht_take_snapshot", json!("brotli")),
+            ("ht_send_keys", json!("gzip")),
+        ] {
+            let err = requested(tool, &args(compress)).unwrap_err();
+            assert!(matches!(err, HtMcpError::InvalidRequest(_)), "{}", err);
+        }
+    }
+}
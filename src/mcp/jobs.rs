@@ -0,0 +1,394 @@
+//! Background jobs for long tool calls.
+//!
+//! A job-capable tool called with `async: true` is started in the
+//! background and answered at once with a job ID. The client polls
+//! `ht_get_job` for the job's status and, once it finished, the tool's
+//! usual result or error; `ht_cancel_job` stops it. A finished job is kept
+//! for [`JOB_TTL`] after it ended, then forgotten.
+
+use crate::error::{HtMcpError, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::AbortHandle;
+use uuid::Uuid;
+
+/// How long a finished job's result stays available
+pub const JOB_TTL: Duration = Duration::from_secs(600);
+
+/// Jobs kept at once, running or finished
+const MAX_JOBS: usize = 64;
+
+/// Tools that accept `async`
+pub const JOB_TOOLS: [&str; 2] = ["ht_execute_command", "ht_download_history"];
+
+/// Whether the call's `async` meta-field asks for a job, refused for tools
+/// that do not accept it
+pub fn requested(tool_name: &str, arguments: &Value) -> Result<bool> {
+    let value = match arguments.get("async") {
+        None | Some(Value::Null) => return Ok(false),
+        Some(value) => value,
+    };
+    let Some(run_async) = value.as_bool() else {
+        return Err(HtMcpError::InvalidRequest(format!(
+            "Invalid arguments: async must be a boolean, got {}",
+            value
+        )));
+    };
+    if run_async && !JOB_TOOLS.contains(&tool_name) {
+        return Err(HtMcpError::InvalidRequest(format!(
+            "Invalid arguments: {} does not accept async; it is accepted by {}",
+            tool_name,
+            JOB_TOOLS.join(", ")
+        )));
+    }
+    Ok(run_async)
+}
+
+#[derive(Debug)]
+enum JobState {
+    Running,
+    Succeeded(Value),
+    Failed {
+        code: &'static str,
+        message: String,
+        hint: &'static str,
+    },
+    Cancelled,
+}
+
+impl JobState {
+    fn name(&self) -> &'static str {
+        match self {
+            JobState::Running => "running",
+            JobState::Succeeded(_) => "succeeded",
+            JobState::Failed { .. } => "failed",
+            JobState::Cancelled => "cancelled",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Job {
+    tool: String,
+    started_at: DateTime<Utc>,
+    started: Instant,
+    timeout: Duration,
+    state: JobState,
+    /// Set once the job's task is spawned; dropped when it finishes
+    abort: Option<AbortHandle>,
+    /// Session interrupted when the job is cancelled, for jobs running
+    /// something in a terminal
+    interrupts: Option<String>,
+    /// When the job finished
+    finished: Option<Instant>,
+}
+
+impl Job {
+    fn expired(&self, now: Instant, ttl: Duration) -> bool {
+        self.finished.is_some_and(|finished| finished + ttl <= now)
+    }
+
+    fn summary(&self, job_id: &str, now: Instant) -> Value {
+        let elapsed = self.finished.unwrap_or(now).duration_since(self.started);
+        let mut summary = json!({
+            "jobId": job_id,
+            "tool": self.tool,
+            "status": self.state.name(),
+            "startedAt": self.started_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+            "progress": {
+                "elapsedMs": elapsed.as_millis() as u64,
+                "timeoutMs": self.timeout.as_millis() as u64
+            }
+        });
+        match &self.state {
+            JobState::Running | JobState::Cancelled => {}
+            JobState::Succeeded(result) => summary["result"] = result.clone(),
+            JobState::Failed {
+                code,
+                message,
+                hint,
+            } => {
+                summary["error"] = json!({"code": code, "message": message, "hint": hint});
+            }
+        }
+        summary
+    }
+}
+
+/// The jobs started by `async` tool calls
+#[derive(Debug, Clone)]
+pub struct Jobs {
+    jobs: Arc<Mutex<HashMap<String, Job>>>,
+    ttl: Duration,
+}
+
+impl Default for Jobs {
+    fn default() -> Self {
+        Self::with_ttl(JOB_TTL)
+    }
+}
+
+impl Jobs {
+    pub fn with_ttl(ttl: Duration) -> Self {
+        Self {
+            jobs: Arc::default(),
+            ttl,
+        }
+    }
+
+    /// Records a running job for `tool` and returns its ID. When the
+    /// registry is full the oldest finished job makes room; running jobs
+    /// are never dropped.
+    pub fn start(
+        &self,
+        tool: &str,
+        timeout: Duration,
+        interrupts: Option<String>,
+    ) -> Result<String> {
+        let now = Instant::now();
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.retain(|_, job| !job.expired(now, self.ttl));
+        if jobs.len() >= MAX_JOBS {
+            let oldest = jobs
+                .iter()
+                .filter_map(|(job_id, job)| Some((job.finished?, job_id)))
+                .min()
+                .map(|(_, job_id)| job_id.clone());
+            match oldest {
+                Some(job_id) => {
+                    jobs.remove(&job_id);
+                }
+                None => {
+                    return Err(HtMcpError::ResourceExhausted(format!(
+                        "{} jobs are running; wait for one to finish or cancel one",
+                        MAX_JOBS
+                    )))
+                }
+            }
+        }
+
+        let job_id = Uuid::new_v4().simple().to_string();
+        jobs.insert(
+            job_id.clone(),
+            Job {
+                tool: tool.to_string(),
+                started_at: Utc::now(),
+                started: now,
+                timeout,
+                state: JobState::Running,
+                abort: None,
+                interrupts,
+                finished: None,
+            },
+        );
+        Ok(job_id)
+    }
+
+    /// Attaches the task running the job, so it can be cancelled
+    pub fn attach(&self, job_id: &str, abort: AbortHandle) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.get_mut(job_id) {
+            if matches!(job.state, JobState::Running) {
+                job.abort = Some(abort);
+            }
+        }
+    }
+
+    /// Records the outcome of a running job; ignored once it was cancelled
+    pub fn finish(&self, job_id: &str, result: Result<Value>) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let Some(job) = jobs.get_mut(job_id) else {
+            return;
+        };
+        if !matches!(job.state, JobState::Running) {
+            return;
+        }
+        job.state = match result {
+            Ok(value) => JobState::Succeeded(value),
+            Err(e) => JobState::Failed {
+                code: e.code(),
+                hint: e.hint(),
+                message: e.to_string(),
+            },
+        };
+        job.abort = None;
+        job.finished = Some(Instant::now());
+    }
+
+    /// The job's status, with its result or error once it finished
+    pub fn status(&self, job_id: &str) -> Result<Value> {
+        let now = Instant::now();
+        let jobs = self.jobs.lock().unwrap();
+        jobs.get(job_id)
+            .filter(|job| !job.expired(now, self.ttl))
+            .map(|job| job.summary(job_id, now))
+            .ok_or_else(|| Self::unknown(job_id))
+    }
+
+    /// The session cancelling the job would interrupt, while it runs
+    pub fn interrupts(&self, job_id: &str) -> Result<Option<String>> {
+        let now = Instant::now();
+        let jobs = self.jobs.lock().unwrap();
+        let job = jobs
+            .get(job_id)
+            .filter(|job| !job.expired(now, self.ttl))
+            .ok_or_else(|| Self::unknown(job_id))?;
+        Ok(match job.state {
+            JobState::Running => job.interrupts.clone(),
+            _ => None,
+        })
+    }
+
+    /// Stops a running job. Returns its status, with `cancelled` telling
+    /// whether this call stopped it, and the session to interrupt.
+    pub fn cancel(&self, job_id: &str) -> Result<(Value, Option<String>)> {
+        let now = Instant::now();
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs
+            .get_mut(job_id)
+            .filter(|job| !job.expired(now, self.ttl))
+            .ok_or_else(|| Self::unknown(job_id))?;
+        let cancelled = matches!(job.state, JobState::Running);
+        if cancelled {
+            if let Some(abort) = job.abort.take() {
+                abort.abort();
+            }
+            job.state = JobState::Cancelled;
+            job.finished = Some(now);
+        }
+        let mut summary = job.summary(job_id, now);
+        summary["cancelled"] = json!(cancelled);
+        let interrupts = if cancelled {
+            job.interrupts.clone()
+        } else {
+            None
+        };
+        Ok((summary, interrupts))
+    }
+
+    fn unknown(job_id: &str) -> HtMcpError {
+        HtMcpError::InvalidRequest(format!("No job {}; it may have expired", job_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_async_is_refused_for_other_tools() {
+        assert!(requested("ht_execute_command", &json!({"async": true})).unwrap());
+        assert!(!requested("ht_execute_command", &json!({})).unwrap());
+        // async: false is accepted anywhere and changes nothing
+        assert!(!requested("ht_take_snapshot", &json!({"async": false})).unwrap());
+        assert!(requested("ht_take_snapshot", &json!({"async": true})).is_err());
+        assert!(requested("ht_execute_command", &json!({"async": "yes"})).is_err());
+    }
+
+    #[test]
+    fn test_only_job_tools_advertise_async() {
+        for tool in crate::mcp::tools::get_tool_definitions() {
+            let name = tool["name"].as_str().unwrap();
+            assert_eq!(
+                tool["inputSchema"]["properties"].get("async").is_some(),
+                JOB_TOOLS.contains(&name),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_job_lifecycle() {
+        let jobs = Jobs::default();
+        let job_id = jobs
+            .start("ht_download_history", Duration::from_secs(30), None)
+            .unwrap();
+        let status = jobs.status(&job_id).unwrap();
+        assert_eq!(status["status"], "running");
+        assert_eq!(status["progress"]["timeoutMs"], 30000);
+        assert!(status.get("result").is_none());
+
+        jobs.finish(&job_id, Ok(json!({"token": "abc"})));
+        let status = jobs.status(&job_id).unwrap();
+        assert_eq!(status["status"], "succeeded");
+        assert_eq!(status["result"]["token"], "abc");
+
+        let job_id = jobs
+            .start("ht_execute_command", Duration::from_secs(30), None)
+            .unwrap();
+        jobs.finish(
+            &job_id,
+            Err(HtMcpError::SessionNotFound("gone".to_string())),
+        );
+        let status = jobs.status(&job_id).unwrap();
+        assert_eq!(status["status"], "failed");
+        assert_eq!(status["error"]["code"], "session_not_found");
+    }
+
+    #[test]
+    fn test_cancel() {
+        let jobs = Jobs::default();
+        let job_id = jobs
+            .start(
+                "ht_execute_command",
+                Duration::from_secs(30),
+                Some("session".to_string()),
+            )
+            .unwrap();
+        assert_eq!(
+            jobs.interrupts(&job_id).unwrap().as_deref(),
+            Some("session")
+        );
+        let (status, interrupts) = jobs.cancel(&job_id).unwrap();
+        assert_eq!(status["status"], "cancelled");
+        assert_eq!(status["cancelled"], true);
+        assert_eq!(interrupts.as_deref(), Some("session"));
+        assert_eq!(jobs.interrupts(&job_id).unwrap(), None);
+        assert!(jobs.interrupts("unknown").is_err());
+
+        // A late result does not overwrite the cancellation
+        jobs.finish(&job_id, Ok(json!({})));
+        assert_eq!(jobs.status(&job_id).unwrap()["status"], "cancelled");
+
+        // Cancelling again reports the job without interrupting anything
+        let (status, interrupts) = jobs.cancel(&job_id).unwrap();
+        assert_eq!(status["cancelled"], false);
+        assert!(interrupts.is_none());
+    }
+
+    #[test]
+    fn test_finished_jobs_expire() {
+        let jobs = Jobs::with_ttl(Duration::ZERO);
+        let job_id = jobs
+            .start("ht_download_history", Duration::from_secs(30), None)
+            .unwrap();
+        // Running jobs never expire
+        assert!(jobs.status(&job_id).is_ok());
+        jobs.finish(&job_id, Ok(json!({})));
+        let err = jobs.status(&job_id).unwrap_err();
+        assert!(err.to_string().contains("may have expired"));
+        assert!(jobs.cancel(&job_id).is_err());
+        assert!(jobs.status("unknown").is_err());
+    }
+
+    #[test]
+    fn test_registry_is_bounded() {
+        let jobs = Jobs::default();
+        let tool = "ht_download_history";
+        let timeout = Duration::from_secs(30);
+        let first = jobs.start(tool, timeout, None).unwrap();
+        jobs.finish(&first, Ok(json!({})));
+        for _ in 1..MAX_JOBS {
+            jobs.start(tool, timeout, None).unwrap();
+        }
+        // The finished job makes room, then only running jobs are left
+        jobs.start(tool, timeout, None).unwrap();
+        assert!(jobs.status(&first).is_err());
+        let err = jobs.start(tool, timeout, None).unwrap_err();
+        assert!(matches!(err, HtMcpError::ResourceExhausted(_)));
+    }
+}
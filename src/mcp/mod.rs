@@ -1,4 +1,10 @@
+pub mod client_config;
+pub mod client_identity;
+pub mod compression;
 pub mod handlers;
+pub mod jobs;
+pub mod progress;
 pub mod server;
+pub mod stats;
 pub mod tools;
 pub mod types;
@@ -0,0 +1,69 @@
+//! MCP progress notifications.
+//!
+//! A client that passes `_meta.progressToken` with a `tools/call` request
+//! gets `notifications/progress` messages carrying that token while the
+//! call runs. The tool reports through a [`Progress`]; the stdio loop
+//! writes what it reports ahead of the call's response.
+
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+
+/// Where a tool call reports its progress, for a client that asked for it
+#[derive(Clone)]
+pub struct Progress {
+    token: Value,
+    notifications: mpsc::UnboundedSender<Value>,
+}
+
+impl Progress {
+    /// The call's progress reporter, when the request carries a progress
+    /// token in its `_meta`
+    pub fn requested(params: &Value, notifications: &mpsc::UnboundedSender<Value>) -> Option<Self> {
+        let token = params.get("_meta")?.get("progressToken")?;
+        if token.is_null() {
+            return None;
+        }
+        Some(Self {
+            token: token.clone(),
+            notifications: notifications.clone(),
+        })
+    }
+
+    /// Reports `progress` steps done out of `total`. A client gone away is
+    /// not the tool's problem, so a closed channel is ignored.
+    pub fn report(&self, progress: u64, total: u64) {
+        let _ = self.notifications.send(json!({
+            "jsonrpc": "2.0",
+            "method": "notifications/progress",
+            "params": {
+                "progressToken": self.token,
+                "progress": progress,
+                "total": total
+            }
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress_needs_a_token() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        assert!(Progress::requested(&json!({"name": "ht_type_file"}), &tx).is_none());
+        assert!(Progress::requested(&json!({"_meta": {"progressToken": null}}), &tx).is_none());
+
+        let progress =
+            Progress::requested(&json!({"_meta": {"progressToken": "t1"}}), &tx).unwrap();
+        progress.report(2, 5);
+        assert_eq!(
+            rx.try_recv().unwrap(),
+            json!({
+                "jsonrpc": "2.0",
+                "method": "notifications/progress",
+                "params": {"progressToken": "t1", "progress": 2, "total": 5}
+            })
+        );
+    }
+}
@@ -1,11 +1,86 @@
+use crate::config::HtMcpConfig;
 use crate::error::{HtMcpError, Result};
+use crate::ht_integration::aliases::CommandAliases;
+use crate::ht_integration::command_class::CommandClassifier;
+use crate::ht_integration::events::EventBus;
+use crate::ht_integration::history_download::HistoryDownloads;
+use crate::ht_integration::session_manager::{PendingCommand, TunnelRetarget};
+use crate::ht_integration::warm_pool;
 use crate::ht_integration::SessionManager;
+use crate::management::{StateAccess, StateEndpoint};
+use crate::mcp::client_config;
+use crate::mcp::client_identity::ClientIdentity;
+use crate::mcp::compression::{self, Compression};
+use crate::mcp::jobs::{self, Jobs};
+use crate::mcp::progress::Progress;
+use crate::mcp::stats::ToolStats;
+use crate::mcp::types::{
+    CancelJobArgs, CreateSessionArgs, DownloadHistoryArgs, DrainArgs, ExecuteCommandArgs,
+    GetJobArgs,
+};
+use crate::telemetry;
+use crate::tunnel::config::VERIFY_BUDGET;
+use crate::tunnel::pool as tunnel_pool;
+use futures::future::{join_all, BoxFuture, FutureExt, Shared};
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::{watch, Mutex};
+use tracing::{error, info, warn, Instrument};
+
+/// How often expired idempotency keys are purged
+const IDEMPOTENCY_CLEANUP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often session leases are checked for expiry
+const LEASE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often tunnels are checked against their web server's port, which a
+/// web server restart may have moved
+const TUNNEL_RETARGET_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Tools refused unless `enable_admin_tools` is set
+const ADMIN_TOOLS: [&str; 3] = ["ht_drain", "ht_export_state", "ht_import_state"];
+
+/// How often a draining server checks whether it is done
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Time past a call's own `timeoutMs` wait left for the work around it,
+/// such as the final snapshot
+const TIMEOUT_MS_MARGIN: Duration = Duration::from_secs(5);
+
+/// A `create_session` call still running, shared by every caller with its
+/// idempotency key
+type InFlightCreate =
+    Shared<BoxFuture<'static, std::result::Result<serde_json::Value, Arc<HtMcpError>>>>;
 
 pub struct HtMcpServer {
     session_manager: Arc<Mutex<SessionManager>>,
+    /// Idempotency key -> creation in progress. Completed creations are
+    /// replayed by the session manager's idempotency cache instead.
+    in_flight_creates: Arc<std::sync::Mutex<HashMap<String, InFlightCreate>>>,
+    /// Calls started with `async`
+    jobs: Jobs,
+    default_tool_timeout: Duration,
+    /// Added to the time limit of calls that start a tunnel, which
+    /// `verify_tunnels` checks before handing it out
+    tunnel_verify_time: Duration,
+    /// Picks how long `ht_execute_command` waits when the call does not say
+    command_classifier: Arc<CommandClassifier>,
+    /// Expands aliases first, so their command's class decides the timeout
+    aliases: CommandAliases,
+    enable_admin_tools: bool,
+    /// Token the management server's `/state` endpoints require
+    state_token: Option<String>,
+    allow_state_env_export: bool,
+    /// How long sessions are kept once the client disconnects
+    reconnect_grace: Duration,
+    /// Flipped to `true` once draining finished and the process should exit
+    shutdown_tx: watch::Sender<bool>,
     server_info: ServerInfo,
+    stats: std::sync::Mutex<ToolStats>,
+    /// The stdio client, from its `initialize` request
+    client: Option<ClientIdentity>,
 }
 
 #[derive(Debug, Clone)]
@@ -16,12 +91,40 @@ pub struct ServerInfo {
 
 impl HtMcpServer {
     pub fn new() -> Self {
+        Self::with_config(HtMcpConfig::default())
+    }
+
+    pub fn with_config(config: HtMcpConfig) -> Self {
+        let default_tool_timeout = Duration::from_millis(config.default_tool_timeout_ms);
+        let tunnel_verify_time = if config.verify_tunnels {
+            VERIFY_BUDGET
+        } else {
+            Duration::ZERO
+        };
+        let enable_admin_tools = config.enable_admin_tools;
+        let state_token = config.state_token.clone();
+        let allow_state_env_export = config.allow_state_env_export;
+        let reconnect_grace = Duration::from_secs(config.reconnect_grace_secs);
+        let session_manager = SessionManager::with_config(config);
         Self {
-            session_manager: Arc::new(Mutex::new(SessionManager::new())),
+            default_tool_timeout,
+            tunnel_verify_time,
+            command_classifier: session_manager.command_classifier(),
+            aliases: session_manager.aliases(),
+            enable_admin_tools,
+            state_token,
+            allow_state_env_export,
+            reconnect_grace,
+            shutdown_tx: watch::channel(false).0,
+            session_manager: Arc::new(Mutex::new(session_manager)),
+            in_flight_creates: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            jobs: Jobs::default(),
             server_info: ServerInfo {
                 name: "ht-mcp-server".to_string(),
                 version: env!("CARGO_PKG_VERSION").to_string(),
             },
+            stats: std::sync::Mutex::new(ToolStats::new()),
+            client: None,
         }
     }
 
@@ -29,48 +132,1663 @@ impl HtMcpServer {
         &self.server_info
     }
 
+    /// Records the stdio client, stamped onto what `handle_tool_call` creates
+    pub fn set_client(&mut self, client: ClientIdentity) {
+        info!("Client is {}", client.label());
+        self.client = Some(client);
+    }
+
+    /// Becomes `true` when `ht_drain` finished and the server should exit,
+    /// or, after `client_disconnected`, once the sessions are closed
+    pub fn shutdown_signal(&self) -> watch::Receiver<bool> {
+        self.shutdown_tx.subscribe()
+    }
+
+    /// The bus session events are published on
+    pub async fn events(&self) -> EventBus {
+        self.session_manager.lock().await.events().clone()
+    }
+
+    /// History downloads, for the management server's HTTP handoff
+    pub async fn downloads(&self) -> HistoryDownloads {
+        self.session_manager.lock().await.downloads().clone()
+    }
+
+    /// What the management server's `/state` endpoints need. They are admin
+    /// tools like `ht_export_state`, served over HTTP only with a
+    /// `state_token` for callers to present.
+    pub fn state_access(&self) -> StateAccess {
+        let token = self
+            .state_token
+            .clone()
+            .filter(|_| self.enable_admin_tools)?;
+        Some(StateEndpoint {
+            sessions: Arc::clone(&self.session_manager),
+            token,
+            allow_env_export: self.allow_state_env_export,
+        })
+    }
+
+    /// Spawns periodic maintenance tasks; must be called from within a Tokio runtime
+    pub fn start_background_tasks(&self) {
+        let session_manager = Arc::clone(&self.session_manager);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(IDEMPOTENCY_CLEANUP_INTERVAL);
+            loop {
+                interval.tick().await;
+                let mut session_manager = session_manager.lock().await;
+                session_manager.purge_expired_idempotency_keys();
+                session_manager.purge_expired_port_reservations();
+            }
+        });
+
+        let session_manager = Arc::clone(&self.session_manager);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(LEASE_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                let mut session_manager = session_manager.lock().await;
+                session_manager.reap_expired_leases().await;
+                session_manager.reap_expired_locks();
+                session_manager.reap_vanished_tail_files().await;
+                session_manager.account_recordings();
+            }
+        });
+
+        let session_manager = Arc::clone(&self.session_manager);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(TUNNEL_RETARGET_INTERVAL);
+            loop {
+                interval.tick().await;
+                // Tunnels are started after the manager is released, like
+                // paced input, so sessions do not wait on them
+                let retargets = session_manager.lock().await.prepare_tunnel_retargets();
+                if retargets.is_empty() {
+                    continue;
+                }
+                let retargeted = join_all(retargets.into_iter().map(TunnelRetarget::run)).await;
+                let mut session_manager = session_manager.lock().await;
+                for retargeted in retargeted {
+                    session_manager.finish_tunnel_retarget(retargeted);
+                }
+            }
+        });
+
+        let session_manager = Arc::clone(&self.session_manager);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(tunnel_pool::REFILL_INTERVAL);
+            loop {
+                interval.tick().await;
+                // Started after the manager is released, like retargeted
+                // tunnels
+                let Some(refill) = session_manager.lock().await.prepare_tunnel_refill() else {
+                    continue;
+                };
+                let started = refill.run().await;
+                session_manager.lock().await.finish_tunnel_refill(started);
+            }
+        });
+
+        let session_manager = Arc::clone(&self.session_manager);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(warm_pool::REFILL_INTERVAL);
+            loop {
+                interval.tick().await;
+                // Started after the manager is released, like pooled tunnels
+                let Some(refill) = session_manager.lock().await.prepare_warm_pool_refill() else {
+                    continue;
+                };
+                let started = refill.run().await;
+                session_manager
+                    .lock()
+                    .await
+                    .finish_warm_pool_refill(started);
+            }
+        });
+
+        let session_manager = Arc::clone(&self.session_manager);
+        tokio::spawn(async move {
+            let Some(mut viewer_input) = session_manager.lock().await.take_viewer_input() else {
+                return;
+            };
+            while let Some(input) = viewer_input.recv().await {
+                let session_id = input.session_id.clone();
+                // Refused input is logged and published by the manager
+                let Ok(keys) = session_manager.lock().await.prepare_viewer_input(input) else {
+                    continue;
+                };
+                // Sent after the manager is released, like `send_keys`, and
+                // one message at a time so a viewer's keys stay in order
+                if let Err(e) = keys.send().await {
+                    warn!("Viewer input to session {} was not sent: {}", session_id, e);
+                }
+            }
+        });
+    }
+
+    /// Handles a tool call from the stdio client
     pub async fn handle_tool_call(
         &self,
         tool_name: &str,
         arguments: serde_json::Value,
     ) -> Result<serde_json::Value> {
+        self.handle_tool_call_as(self.client.as_ref(), tool_name, arguments)
+            .await
+    }
+
+    /// Like [`Self::handle_tool_call`], for a call that asked for progress
+    /// notifications
+    pub async fn handle_tool_call_with_progress(
+        &self,
+        tool_name: &str,
+        arguments: serde_json::Value,
+        progress: Option<Progress>,
+    ) -> Result<serde_json::Value> {
+        self.call_tool(self.client.as_ref(), tool_name, arguments, progress)
+            .await
+    }
+
+    /// Handles a tool call from `client`, recording per-tool statistics
+    /// around the dispatch and failing it with `HtMcpError::Timeout` if it
+    /// runs too long
+    pub async fn handle_tool_call_as(
+        &self,
+        client: Option<&ClientIdentity>,
+        tool_name: &str,
+        arguments: serde_json::Value,
+    ) -> Result<serde_json::Value> {
+        self.call_tool(client, tool_name, arguments, None).await
+    }
+
+    /// [`Self::handle_tool_call_as`], reporting to `progress` from the
+    /// tools that report any
+    async fn call_tool(
+        &self,
+        client: Option<&ClientIdentity>,
+        tool_name: &str,
+        arguments: serde_json::Value,
+        progress: Option<Progress>,
+    ) -> Result<serde_json::Value> {
+        if tool_name == "ht_stats" {
+            let mut summary = self.stats.lock().unwrap().summary();
+            let session_manager = self.session_manager.lock().await;
+            summary["warmPool"] = session_manager.warm_pool_summary();
+            summary["tunnelPool"] = session_manager.tunnel_pool_summary();
+            summary["sessionStartup"] = session_manager.startup_summary();
+            return Ok(summary);
+        }
+
+        let span = telemetry::tool_call_span(tool_name);
+        if let Some(session_id) = arguments.get("sessionId").and_then(|id| id.as_str()) {
+            span.record("session.id", session_id);
+        }
+
+        let started = Instant::now();
+        let meta = self
+            .tool_timeout(tool_name, &arguments)
+            .and_then(|timeout| {
+                Ok((
+                    timeout,
+                    compression::requested(tool_name, &arguments)?,
+                    jobs::requested(tool_name, &arguments)?,
+                ))
+            });
+        let result = match meta {
+            Ok((timeout, compress, true)) => {
+                self.start_job(tool_name, arguments, timeout, compress)
+                    .instrument(span.clone())
+                    .await
+            }
+            Ok((timeout, compress, false)) => {
+                let dispatch = self.dispatch_tool_call(client, tool_name, arguments, progress);
+                tokio::time::timeout(timeout, dispatch.instrument(span.clone()))
+                    .await
+                    .unwrap_or_else(|_| Err(timed_out(tool_name, timeout)))
+                    .and_then(|value| compress_result(value, compress))
+            }
+            Err(e) => Err(e),
+        };
+        let elapsed = started.elapsed();
+
+        span.record("duration_ms", elapsed.as_millis() as u64);
+        match &result {
+            Ok(value) => {
+                if let Some(session_id) = value.get("sessionId").and_then(|id| id.as_str()) {
+                    span.record("session.id", session_id);
+                }
+            }
+            Err(e) => {
+                span.record("error.code", e.code());
+            }
+        }
+
+        let mut stats = self.stats.lock().unwrap();
+        match &result {
+            Ok(_) => stats.record_success(tool_name, elapsed),
+            Err(e) => stats.record_error(tool_name, elapsed, e),
+        }
+
+        result
+    }
+
+    /// The call's `_timeout_ms` meta-field, or the configured default. A
+    /// call waiting longer through its own `timeoutMs` gets that long.
+    fn tool_timeout(&self, tool_name: &str, arguments: &serde_json::Value) -> Result<Duration> {
+        match arguments.get("_timeout_ms") {
+            None | Some(serde_json::Value::Null) => {
+                let own_wait = arguments
+                    .get("timeoutMs")
+                    .and_then(serde_json::Value::as_u64)
+                    .map(Duration::from_millis)
+                    .or_else(|| {
+                        arguments
+                            .get("timeoutSecs")
+                            .and_then(serde_json::Value::as_u64)
+                            .map(Duration::from_secs)
+                    })
+                    .or_else(|| self.command_class_timeout(tool_name, arguments))
+                    .map(|wait| wait.saturating_add(TIMEOUT_MS_MARGIN));
+                let timeout = own_wait.map_or(self.default_tool_timeout, |wait| {
+                    wait.max(self.default_tool_timeout)
+                });
+                Ok(timeout + self.tunnel_time(tool_name, arguments))
+            }
+            Some(value) => match value.as_u64() {
+                Some(ms) if ms > 0 => Ok(Duration::from_millis(ms)),
+                _ => Err(HtMcpError::InvalidRequest(format!(
+                    "Invalid arguments: _timeout_ms must be a positive integer, got {}",
+                    value
+                ))),
+            },
+        }
+    }
+
+    /// Extra time for a call that starts a tunnel, so verifying it does not
+    /// run into the time limit
+    fn tunnel_time(&self, tool_name: &str, arguments: &serde_json::Value) -> Duration {
+        let starts_tunnel = match tool_name {
+            "ht_create_session_with_tunnel" | "ht_create_tunnel" => true,
+            "ht_create_session" => arguments.get("enableTunnel") == Some(&serde_json::json!(true)),
+            _ => false,
+        };
+        if starts_tunnel {
+            self.tunnel_verify_time
+        } else {
+            Duration::ZERO
+        }
+    }
+
+    /// How long an `ht_execute_command` call without `timeoutMs` waits, from
+    /// its command class. Bad arguments are left for the tool to refuse.
+    fn command_class_timeout(
+        &self,
+        tool_name: &str,
+        arguments: &serde_json::Value,
+    ) -> Option<Duration> {
+        if tool_name != "ht_execute_command" {
+            return None;
+        }
+        let command = arguments.get("command")?.as_str()?;
+        let session_id = arguments.get("sessionId")?.as_str()?;
+        let expansion = self.aliases.expand(session_id, command);
+        let command = expansion.as_ref().map_or(command, |e| e.command.as_str());
+        let requested = arguments
+            .get("commandClass")
+            .and_then(serde_json::Value::as_str);
+        self.command_classifier
+            .for_call(command, requested)
+            .ok()
+            .map(|class| class.timeout)
+    }
+
+    async fn dispatch_tool_call(
+        &self,
+        client: Option<&ClientIdentity>,
+        tool_name: &str,
+        arguments: serde_json::Value,
+        progress: Option<Progress>,
+    ) -> Result<serde_json::Value> {
+        if tool_name == "ht_create_session" {
+            let args: CreateSessionArgs = parse_args(arguments)?;
+            let created_by = client.cloned();
+            return match args.idempotency_key.clone() {
+                Some(key) => self.create_session_idempotent(key, args, created_by).await,
+                None => {
+                    self.session_manager
+                        .lock()
+                        .await
+                        .create_session_by(args, created_by)
+                        .await
+                }
+            };
+        }
+
+        if ADMIN_TOOLS.contains(&tool_name) && !self.enable_admin_tools {
+            return Err(HtMcpError::InvalidRequest(format!(
+                "{} is an admin tool; set enable_admin_tools in the config to use it",
+                tool_name
+            )));
+        }
+
+        if tool_name == "ht_drain" {
+            return self.drain(parse_args(arguments)?).await;
+        }
+
+        if tool_name == "ht_get_job" {
+            return self
+                .jobs
+                .status(&parse_args::<GetJobArgs>(arguments)?.job_id);
+        }
+
+        if tool_name == "ht_cancel_job" {
+            return self.cancel_job(arguments).await;
+        }
+
+        if tool_name == "ht_generate_mcp_config" {
+            return client_config::generate(&parse_args(arguments)?);
+        }
+
+        if tool_name == "ht_send_keys" {
+            // Sent after the manager is released, so paced input does not
+            // hold up other sessions
+            let keys = {
+                let mut session_manager = self.session_manager.lock().await;
+                session_manager.check_input_allowed(tool_name, &arguments)?;
+                session_manager.prepare_keys(parse_args(arguments)?)?
+            };
+            return keys.send().await;
+        }
+
+        if tool_name == "ht_type_file" {
+            // Typed after the manager is released, like paced input
+            let file = {
+                let mut session_manager = self.session_manager.lock().await;
+                session_manager.check_input_allowed(tool_name, &arguments)?;
+                session_manager.prepare_type_file(parse_args(arguments)?)?
+            };
+            return file.send(progress).await;
+        }
+
+        if tool_name == "ht_execute_command" {
+            // Waited for after the manager is released, like paced input
+            let command = {
+                let mut session_manager = self.session_manager.lock().await;
+                session_manager.check_input_allowed(tool_name, &arguments)?;
+                session_manager.prepare_command(parse_args(arguments)?)?
+            };
+            return execute_command(&self.session_manager, command).await;
+        }
+
+        if tool_name == "ht_check_command_exists" {
+            // Looked up after the manager is released, like a command
+            let check = {
+                let mut session_manager = self.session_manager.lock().await;
+                session_manager.check_input_allowed(tool_name, &arguments)?;
+                session_manager.prepare_command_check(parse_args(arguments)?)?
+            };
+            return check.run().await;
+        }
+
+        if tool_name == "ht_probe_tools" {
+            // Typed and read after the manager is released, like a command
+            let probe = {
+                let mut session_manager = self.session_manager.lock().await;
+                session_manager.check_input_allowed(tool_name, &arguments)?;
+                session_manager.prepare_probe(parse_args(arguments)?)?
+            };
+            let finished = probe.run().await?;
+            return self.session_manager.lock().await.finish_probe(finished);
+        }
+
+        if tool_name == "ht_parallel_execute" {
+            // Run after the manager is released, like a single command
+            let parallel = {
+                let mut session_manager = self.session_manager.lock().await;
+                session_manager.check_input_allowed(tool_name, &arguments)?;
+                session_manager.prepare_parallel(parse_args(arguments)?)?
+            };
+            let finished = parallel.run().await;
+            return self.session_manager.lock().await.finish_parallel(finished);
+        }
+
+        if tool_name == "ht_interactive_prompt" {
+            // Watched after the manager is released, like paced input
+            let watch = {
+                let mut session_manager = self.session_manager.lock().await;
+                session_manager.check_input_allowed(tool_name, &arguments)?;
+                session_manager.prepare_interactive_prompt(parse_args(arguments)?)?
+            };
+            return watch.run().await;
+        }
+
         let mut session_manager = self.session_manager.lock().await;
+        session_manager.check_input_allowed(tool_name, &arguments)?;
 
         match tool_name {
-            "ht_create_session" => {
-                let args: crate::mcp::types::CreateSessionArgs = serde_json::from_value(arguments)
-                    .map_err(|e| HtMcpError::InvalidRequest(format!("Invalid arguments: {}", e)))?;
-                session_manager.create_session(args).await
-            }
-            "ht_send_keys" => {
-                let args: crate::mcp::types::SendKeysArgs = serde_json::from_value(arguments)
-                    .map_err(|e| HtMcpError::InvalidRequest(format!("Invalid arguments: {}", e)))?;
-                session_manager.send_keys(args).await
-            }
-            "ht_take_snapshot" => {
-                let args: crate::mcp::types::TakeSnapshotArgs = serde_json::from_value(arguments)
-                    .map_err(|e| {
-                    HtMcpError::InvalidRequest(format!("Invalid arguments: {}", e))
-                })?;
-                session_manager.take_snapshot(args).await
-            }
-            "ht_execute_command" => {
-                let args: crate::mcp::types::ExecuteCommandArgs = serde_json::from_value(arguments)
-                    .map_err(|e| HtMcpError::InvalidRequest(format!("Invalid arguments: {}", e)))?;
-                session_manager.execute_command(args).await
-            }
-            "ht_list_sessions" => session_manager.list_sessions().await,
-            "ht_close_session" => {
-                let args: crate::mcp::types::CloseSessionArgs = serde_json::from_value(arguments)
-                    .map_err(|e| {
-                    HtMcpError::InvalidRequest(format!("Invalid arguments: {}", e))
-                })?;
-                session_manager.close_session(args).await
+            "ht_create_session_with_tunnel" => {
+                session_manager
+                    .create_session_with_tunnel_by(parse_args(arguments)?, client.cloned())
+                    .await
+            }
+            "ht_close_stdin" => session_manager.close_stdin(parse_args(arguments)?).await,
+            "ht_session_stdin_pipe" => session_manager.pipe_stdin(parse_args(arguments)?).await,
+            "ht_take_snapshot" => session_manager.take_snapshot(parse_args(arguments)?).await,
+            "ht_take_snapshot_as_png" => {
+                session_manager
+                    .take_snapshot_as_png(parse_args(arguments)?)
+                    .await
+            }
+            "ht_verify_snapshot" => {
+                session_manager
+                    .verify_snapshot(parse_args(arguments)?)
+                    .await
+            }
+            "ht_take_baseline_snapshot" => {
+                session_manager
+                    .take_baseline_snapshot(parse_args(arguments)?)
+                    .await
+            }
+            "ht_compare_to_baseline" => {
+                session_manager
+                    .compare_to_baseline(parse_args(arguments)?)
+                    .await
+            }
+            "ht_classify_screen" => {
+                session_manager
+                    .classify_screen(parse_args(arguments)?)
+                    .await
             }
+            "ht_assert_screen" => session_manager.assert_screen(parse_args(arguments)?).await,
+            "ht_get_session_metrics_history" => {
+                session_manager.get_session_metrics_history(parse_args(arguments)?)
+            }
+            "ht_export_dockerfile" => session_manager.export_dockerfile(parse_args(arguments)?),
+            "ht_get_snapshot_history" => {
+                session_manager.get_snapshot_history(parse_args(arguments)?)
+            }
+            "ht_set_alias" => session_manager.set_alias(parse_args(arguments)?),
+            "ht_tail_file" => {
+                session_manager
+                    .tail_file(parse_args(arguments)?, client.cloned())
+                    .await
+            }
+            "ht_session_group_snapshot" => {
+                session_manager.group_snapshot(parse_args(arguments)?).await
+            }
+            "ht_list_sessions" => session_manager.list_sessions(parse_args(arguments)?).await,
+            "ht_get_session_info" => {
+                session_manager
+                    .get_session_info(parse_args(arguments)?)
+                    .await
+            }
+            "ht_create_tunnel" => session_manager.create_tunnel(parse_args(arguments)?).await,
+            "ht_list_tunnels" => session_manager.list_tunnels(parse_args(arguments)?),
+            "ht_stop_tunnel" => session_manager.stop_tunnel(parse_args(arguments)?).await,
+            "ht_resource_report" => session_manager.resource_report(parse_args(arguments)?),
+            "ht_reconnect_cloudflared" => {
+                session_manager
+                    .reconnect_tunnel(parse_args(arguments)?)
+                    .await
+            }
+            "ht_resize_terminal" => session_manager.resize_session(parse_args(arguments)?).await,
+            "ht_reserve_port" => session_manager.reserve_port(parse_args(arguments)?),
+            "ht_renew_lease" => session_manager.renew_lease(parse_args(arguments)?),
+            "ht_acquire_lock" => session_manager.acquire_lock(parse_args(arguments)?),
+            "ht_release_lock" => session_manager.release_lock(parse_args(arguments)?),
+            "ht_set_retention" => session_manager.set_retention(parse_args(arguments)?),
+            "ht_download_history" => session_manager.download_history(parse_args(arguments)?),
+            "ht_get_connected_clients" => {
+                session_manager.get_connected_clients(parse_args(arguments)?)
+            }
+            "ht_close_session" => session_manager.close_session(parse_args(arguments)?).await,
+            "ht_bulk_close_sessions" => session_manager.bulk_close(parse_args(arguments)?).await,
+            "ht_export_state" => session_manager.export_state(parse_args(arguments)?).await,
+            "ht_import_state" => session_manager.import_state(parse_args(arguments)?).await,
             _ => Err(HtMcpError::InvalidRequest(format!(
                 "Unknown tool: {}",
                 tool_name
             ))),
         }
     }
+
+    /// Starts a job-capable call in the background and answers with its
+    /// job. Locks and arguments are checked first, so those failures still
+    /// fail the call itself.
+    async fn start_job(
+        &self,
+        tool_name: &str,
+        arguments: serde_json::Value,
+        timeout: Duration,
+        compress: Option<Compression>,
+    ) -> Result<serde_json::Value> {
+        let call = {
+            let mut session_manager = self.session_manager.lock().await;
+            session_manager.check_input_allowed(tool_name, &arguments)?;
+            JobCall::parse(tool_name, arguments)?
+        };
+        let job_id = self.jobs.start(tool_name, timeout, call.interrupts())?;
+
+        let jobs = self.jobs.clone();
+        let session_manager = Arc::clone(&self.session_manager);
+        let tool_name = tool_name.to_string();
+        let id = job_id.clone();
+        let task = tokio::spawn(
+            async move {
+                let result = tokio::time::timeout(timeout, call.run(&session_manager))
+                    .await
+                    .unwrap_or_else(|_| Err(timed_out(&tool_name, timeout)))
+                    .and_then(|value| compress_result(value, compress));
+                jobs.finish(&id, result);
+            }
+            .in_current_span(),
+        );
+        self.jobs.attach(&job_id, task.abort_handle());
+        info!("Started job {} for {}", job_id, tool_name);
+        self.jobs.status(&job_id)
+    }
+
+    /// Cancels a job, interrupting the command it runs. The interrupt is
+    /// input, so a job in a locked session is only cancelled by the holder.
+    async fn cancel_job(&self, arguments: serde_json::Value) -> Result<serde_json::Value> {
+        let args: CancelJobArgs = parse_args(arguments.clone())?;
+        if let Some(session_id) = self.jobs.interrupts(&args.job_id)? {
+            let mut claimed = arguments;
+            claimed["sessionId"] = serde_json::json!(session_id);
+            self.session_manager
+                .lock()
+                .await
+                .check_input_allowed("ht_cancel_job", &claimed)?;
+        }
+        let (mut summary, interrupts) = self.jobs.cancel(&args.job_id)?;
+        if let Some(session_id) = interrupts {
+            // A job aborted while preparing under the manager releases it
+            // once its task is dropped; the wait for it is short
+            let handle = self.session_manager.lock().await.handle(&session_id);
+            let interrupted = match handle {
+                Ok(handle) => handle.send_keys(&["C-c"]).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = &interrupted {
+                warn!("Could not interrupt session {}: {}", session_id, e);
+            }
+            summary["interrupted"] = serde_json::json!(interrupted.is_ok());
+        }
+        Ok(summary)
+    }
+
+    /// Shuts the server down once the client is gone: sessions are kept for
+    /// `reconnect_grace_secs`, then closed with `ServerShutdown` as at the
+    /// end of a drain, and the shutdown signal fires. New sessions are
+    /// refused meanwhile; a server with no sessions left stops early.
+    pub async fn client_disconnected(&self) {
+        info!(
+            "Client disconnected; closing sessions in {:?}",
+            self.reconnect_grace
+        );
+        if let Err(e) = self.start_drain(self.reconnect_grace, true).await {
+            error!("Could not wait for a reconnect ({}); shutting down now", e);
+            self.shutdown().await;
+        }
+    }
+
+    /// Closes every session, killing what they run, and stops all tunnels
+    /// before the server exits
+    pub async fn shutdown(&self) {
+        self.session_manager.lock().await.shutdown().await;
+        let _ = self.shutdown_tx.send(true);
+    }
+
+    async fn drain(&self, args: DrainArgs) -> Result<serde_json::Value> {
+        self.start_drain(
+            Duration::from_secs(args.grace_secs),
+            args.exit_when_empty.unwrap_or(false),
+        )
+        .await
+    }
+
+    /// Puts the server into draining mode and, the first time, starts the
+    /// task that closes what is left at the end and signals shutdown
+    async fn start_drain(
+        &self,
+        grace: Duration,
+        exit_when_empty: bool,
+    ) -> Result<serde_json::Value> {
+        let mut session_manager = self.session_manager.lock().await;
+        let already_draining = session_manager.is_draining();
+        let result = session_manager.start_drain(grace, exit_when_empty)?;
+
+        if !already_draining {
+            let session_manager = Arc::clone(&self.session_manager);
+            let shutdown_tx = self.shutdown_tx.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(DRAIN_CHECK_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    if session_manager.lock().await.check_drain().await {
+                        break;
+                    }
+                }
+                info!("Drain complete, shutting down");
+                let _ = shutdown_tx.send(true);
+            });
+        }
+        Ok(result)
+    }
+
+    /// Creates a session for a call with an idempotency key. A call whose
+    /// key matches a creation still in progress waits for that creation and
+    /// gets its result marked `idempotentReplay`, so a client retrying a
+    /// timed-out call doesn't start a second session.
+    async fn create_session_idempotent(
+        &self,
+        key: String,
+        args: CreateSessionArgs,
+        created_by: Option<ClientIdentity>,
+    ) -> Result<serde_json::Value> {
+        let (creation, joined) = {
+            let mut in_flight = self.in_flight_creates.lock().unwrap();
+            match in_flight.get(&key) {
+                Some(creation) => (creation.clone(), true),
+                None => {
+                    let creation = self.spawn_create(key.clone(), args, created_by);
+                    in_flight.insert(key, creation.clone());
+                    (creation, false)
+                }
+            }
+        };
+
+        let mut result = creation.await.map_err(|e| replay_error(&e))?;
+        if joined {
+            result["idempotentReplay"] = serde_json::Value::Bool(true);
+        }
+        Ok(result)
+    }
+
+    /// Runs the creation on its own task, so it finishes (and lands in the
+    /// idempotency cache) even if every caller waiting on it times out or
+    /// goes away
+    fn spawn_create(
+        &self,
+        key: String,
+        args: CreateSessionArgs,
+        created_by: Option<ClientIdentity>,
+    ) -> InFlightCreate {
+        let session_manager = Arc::clone(&self.session_manager);
+        let in_flight_creates = Arc::clone(&self.in_flight_creates);
+        let task = tokio::spawn(async move {
+            let result = session_manager
+                .lock()
+                .await
+                .create_session_by(args, created_by)
+                .await;
+            // Only now that a created session is in the idempotency cache,
+            // so a later call finds it one way or the other
+            in_flight_creates.lock().unwrap().remove(&key);
+            result.map_err(Arc::new)
+        });
+        task.map(|joined| {
+            joined.unwrap_or_else(|e| {
+                Err(Arc::new(HtMcpError::Internal(format!(
+                    "Session creation task failed: {}",
+                    e
+                ))))
+            })
+        })
+        .boxed()
+        .shared()
+    }
+}
+
+/// A copy of a shared creation error for one of the callers waiting on it
+fn replay_error(err: &HtMcpError) -> HtMcpError {
+    match err {
+        HtMcpError::Mcp(msg) => HtMcpError::Mcp(msg.clone()),
+        HtMcpError::HtLibrary(msg) => HtMcpError::HtLibrary(msg.clone()),
+        HtMcpError::SessionNotFound(msg) => HtMcpError::SessionNotFound(msg.clone()),
+        HtMcpError::InvalidRequest(msg) => HtMcpError::InvalidRequest(msg.clone()),
+        HtMcpError::Internal(msg) => HtMcpError::Internal(msg.clone()),
+        HtMcpError::Config(msg) => HtMcpError::Config(msg.clone()),
+        HtMcpError::Timeout(msg) => HtMcpError::Timeout(msg.clone()),
+        HtMcpError::NetworkError(msg) => HtMcpError::NetworkError(msg.clone()),
+        HtMcpError::ServerDraining(msg) => HtMcpError::ServerDraining(msg.clone()),
+        HtMcpError::ResourceExhausted(msg) => HtMcpError::ResourceExhausted(msg.clone()),
+        HtMcpError::SessionExited(msg) => HtMcpError::SessionExited(msg.clone()),
+        HtMcpError::SessionLocked {
+            session_id,
+            holder,
+            expires_at,
+        } => HtMcpError::SessionLocked {
+            session_id: session_id.clone(),
+            holder: holder.clone(),
+            expires_at: expires_at.clone(),
+        },
+        HtMcpError::Serialization(_) | HtMcpError::Io(_) => HtMcpError::Internal(err.to_string()),
+    }
+}
+
+/// A job-capable tool call, parsed before its job is started
+enum JobCall {
+    ExecuteCommand(ExecuteCommandArgs),
+    DownloadHistory(DownloadHistoryArgs),
+}
+
+impl JobCall {
+    fn parse(tool_name: &str, arguments: serde_json::Value) -> Result<Self> {
+        match tool_name {
+            "ht_execute_command" => Ok(JobCall::ExecuteCommand(parse_args(arguments)?)),
+            "ht_download_history" => Ok(JobCall::DownloadHistory(parse_args(arguments)?)),
+            _ => Err(HtMcpError::InvalidRequest(format!(
+                "{} cannot run as a job",
+                tool_name
+            ))),
+        }
+    }
+
+    /// The session whose terminal runs the call, interrupted on cancel
+    fn interrupts(&self) -> Option<String> {
+        match self {
+            JobCall::ExecuteCommand(args) => Some(args.session_id.clone()),
+            JobCall::DownloadHistory(_) => None,
+        }
+    }
+
+    async fn run(self, session_manager: &Mutex<SessionManager>) -> Result<serde_json::Value> {
+        match self {
+            JobCall::ExecuteCommand(args) => {
+                let command = session_manager.lock().await.prepare_command(args)?;
+                execute_command(session_manager, command).await
+            }
+            JobCall::DownloadHistory(args) => session_manager.lock().await.download_history(args),
+        }
+    }
+}
+
+/// Runs a prepared command without holding the manager, which is taken
+/// again only to record the result
+async fn execute_command(
+    session_manager: &Mutex<SessionManager>,
+    command: PendingCommand,
+) -> Result<serde_json::Value> {
+    let finished = command.run().await?;
+    session_manager.lock().await.finish_command(finished)
+}
+
+fn timed_out(tool_name: &str, timeout: Duration) -> HtMcpError {
+    HtMcpError::Timeout(format!(
+        "{} did not complete within {} ms",
+        tool_name,
+        timeout.as_millis()
+    ))
+}
+
+/// Applies the call's `compress` meta-field to its result
+fn compress_result(
+    value: serde_json::Value,
+    compress: Option<Compression>,
+) -> Result<serde_json::Value> {
+    match compress {
+        Some(compress) => {
+            compression::compress_result(value, compress, compression::COMPRESSION_THRESHOLD_BYTES)
+        }
+        None => Ok(value),
+    }
+}
+
+/// Deserializes tool arguments into the tool's argument type
+fn parse_args<T: DeserializeOwned>(arguments: serde_json::Value) -> Result<T> {
+    serde_json::from_value(arguments)
+        .map_err(|e| HtMcpError::InvalidRequest(format!("Invalid arguments: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ht_integration::events::SessionEvent;
+    use serde_json::json;
+
+    #[test]
+    fn test_timeout_ms_extends_tool_timeout() {
+        let server = HtMcpServer::new();
+        let default = server.default_tool_timeout;
+        let tool = "ht_execute_command";
+        assert_eq!(
+            server
+                .tool_timeout(tool, &json!({"timeoutMs": 100}))
+                .unwrap(),
+            default
+        );
+        assert_eq!(
+            server
+                .tool_timeout(tool, &json!({"timeoutMs": 60000}))
+                .unwrap(),
+            Duration::from_secs(65)
+        );
+        // An explicit _timeout_ms wins
+        assert_eq!(
+            server
+                .tool_timeout(tool, &json!({"timeoutMs": 60000, "_timeout_ms": 50}))
+                .unwrap(),
+            Duration::from_millis(50)
+        );
+    }
+
+    #[test]
+    fn test_verify_tunnels_extends_tunnel_calls() {
+        let server = HtMcpServer::with_config(HtMcpConfig {
+            verify_tunnels: true,
+            ..HtMcpConfig::default()
+        });
+        let default = server.default_tool_timeout;
+        let extended = default + VERIFY_BUDGET;
+        let timeout = |tool, args| server.tool_timeout(tool, &args).unwrap();
+        assert_eq!(timeout("ht_create_tunnel", json!({"port": 8080})), extended);
+        assert_eq!(
+            timeout("ht_create_session_with_tunnel", json!({})),
+            extended
+        );
+        assert_eq!(
+            timeout("ht_create_session", json!({"enableTunnel": true})),
+            extended
+        );
+        assert_eq!(timeout("ht_create_session", json!({})), default);
+        assert_eq!(
+            timeout("ht_create_tunnel", json!({"port": 8080, "timeoutSecs": 60})),
+            Duration::from_secs(65) + VERIFY_BUDGET
+        );
+        // An explicit _timeout_ms still wins
+        assert_eq!(
+            timeout("ht_create_tunnel", json!({"_timeout_ms": 50})),
+            Duration::from_millis(50)
+        );
+        assert_eq!(
+            HtMcpServer::new()
+                .tool_timeout("ht_create_tunnel", &json!({}))
+                .unwrap(),
+            default
+        );
+    }
+
+    #[test]
+    fn test_command_class_extends_tool_timeout() {
+        let server = HtMcpServer::new();
+        let default = server.default_tool_timeout;
+        let tool = "ht_execute_command";
+        // Builds get the build class's ten minutes
+        assert_eq!(
+            server
+                .tool_timeout(tool, &json!({"command": "cargo build"}))
+                .unwrap(),
+            Duration::from_secs(605)
+        );
+        assert_eq!(
+            server
+                .tool_timeout(tool, &json!({"command": "ls"}))
+                .unwrap(),
+            default
+        );
+        // The call's own class and timeoutMs win over the command's
+        assert_eq!(
+            server
+                .tool_timeout(
+                    tool,
+                    &json!({"command": "./long-job.sh", "commandClass": "build"})
+                )
+                .unwrap(),
+            Duration::from_secs(605)
+        );
+        assert_eq!(
+            server
+                .tool_timeout(tool, &json!({"command": "cargo build", "timeoutMs": 1000}))
+                .unwrap(),
+            default
+        );
+        // Only execute_command has command classes
+        assert_eq!(
+            server
+                .tool_timeout("ht_send_keys", &json!({"command": "cargo build"}))
+                .unwrap(),
+            default
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tool_call_times_out() {
+        let server = HtMcpServer::new();
+        let created = server
+            .handle_tool_call("ht_create_session", json!({}))
+            .await
+            .unwrap();
+
+        // execute_command waits for the command, well past the limit
+        let err = server
+            .handle_tool_call(
+                "ht_execute_command",
+                json!({
+                    "sessionId": created["sessionId"],
+                    "command": "sleep 1",
+                    "_timeout_ms": 50
+                }),
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::Timeout(_)));
+
+        let err = server
+            .handle_tool_call("ht_list_sessions", json!({"_timeout_ms": 0}))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::InvalidRequest(_)));
+    }
+
+    #[tokio::test]
+    async fn test_retried_create_joins_creation_in_progress() {
+        let server = HtMcpServer::new();
+        let args = json!({"idempotencyKey": "retry-me"});
+
+        // Hold the manager so the creation can't finish before the retries
+        let busy = server.session_manager.lock().await;
+        let err = server
+            .handle_tool_call(
+                "ht_create_session",
+                json!({"idempotencyKey": "retry-me", "_timeout_ms": 50}),
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::Timeout(_)));
+
+        let (first_retry, second_retry, _) = tokio::join!(
+            server.handle_tool_call("ht_create_session", args.clone()),
+            server.handle_tool_call("ht_create_session", args.clone()),
+            async {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                drop(busy);
+            }
+        );
+        let first_retry = first_retry.unwrap();
+        let second_retry = second_retry.unwrap();
+        assert_eq!(first_retry["sessionId"], second_retry["sessionId"]);
+        assert_eq!(first_retry["idempotentReplay"], true);
+        assert_eq!(second_retry["idempotentReplay"], true);
+
+        // Once done, the key is answered from the idempotency cache
+        let replay = server
+            .handle_tool_call("ht_create_session", args)
+            .await
+            .unwrap();
+        assert_eq!(replay["sessionId"], first_retry["sessionId"]);
+        assert_eq!(replay["idempotentReplay"], true);
+
+        let sessions = server
+            .handle_tool_call("ht_list_sessions", json!({}))
+            .await
+            .unwrap();
+        assert_eq!(sessions["count"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_first_create_is_not_a_replay() {
+        let server = HtMcpServer::new();
+        let created = server
+            .handle_tool_call("ht_create_session", json!({"idempotencyKey": "fresh"}))
+            .await
+            .unwrap();
+        assert_eq!(created["idempotentReplay"], false);
+
+        let created = server
+            .handle_tool_call("ht_create_session", json!({}))
+            .await
+            .unwrap();
+        assert_eq!(created["idempotentReplay"], false);
+    }
+
+    fn client(name: &str, auth_identity: &str) -> ClientIdentity {
+        ClientIdentity {
+            name: name.to_string(),
+            version: Some("1.0".to_string()),
+            auth_identity: Some(auth_identity.to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sessions_are_stamped_with_their_client() {
+        let server = HtMcpServer::new();
+        let mut events = server.events().await.subscribe();
+        let (alice, bob) = (client("cursor", "alice"), client("claude-code", "bob"));
+
+        let by_alice = server
+            .handle_tool_call_as(Some(&alice), "ht_create_session", json!({}))
+            .await
+            .unwrap();
+        let by_bob = server
+            .handle_tool_call_as(
+                Some(&bob),
+                "ht_create_session",
+                json!({"idempotencyKey": "bob-1"}),
+            )
+            .await
+            .unwrap();
+
+        let listed = server
+            .handle_tool_call_as(Some(&alice), "ht_list_sessions", json!({}))
+            .await
+            .unwrap();
+        let created_by = |session_id: &serde_json::Value| {
+            listed["sessions"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|session| session["id"] == *session_id)
+                .map(|session| session["createdBy"].clone())
+                .unwrap()
+        };
+        assert_eq!(
+            created_by(&by_alice["sessionId"]),
+            json!({"name": "cursor", "version": "1.0", "authIdentity": "alice"})
+        );
+        assert_eq!(created_by(&by_bob["sessionId"])["authIdentity"], "bob");
+
+        server
+            .handle_tool_call_as(
+                Some(&alice),
+                "ht_close_session",
+                json!({"sessionId": by_bob["sessionId"]}),
+            )
+            .await
+            .unwrap();
+        let mut stamps = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            match event {
+                SessionEvent::SessionCreated { created_by, .. } => {
+                    stamps.push(("created", created_by.unwrap().auth_identity))
+                }
+                SessionEvent::SessionClosed { created_by, .. } => {
+                    stamps.push(("closed", created_by.unwrap().auth_identity))
+                }
+                _ => {}
+            }
+        }
+        // Closed by alice, but the record names bob as the creator
+        let name = |name: &str| Some(name.to_string());
+        assert_eq!(
+            stamps,
+            [
+                ("created", name("alice")),
+                ("created", name("bob")),
+                ("closed", name("bob"))
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stdio_client_is_stamped() {
+        let mut server = HtMcpServer::new();
+        let created = server
+            .handle_tool_call("ht_create_session", json!({}))
+            .await
+            .unwrap();
+        server.set_client(ClientIdentity::from_initialize(
+            Some(&json!({"clientInfo": {"name": "claude-desktop", "version": "0.9.2"}})),
+            None,
+        ));
+        let stamped = server
+            .handle_tool_call("ht_create_session", json!({}))
+            .await
+            .unwrap();
+
+        let listed = server
+            .handle_tool_call("ht_list_sessions", json!({}))
+            .await
+            .unwrap();
+        for session in listed["sessions"].as_array().unwrap() {
+            if session["id"] == created["sessionId"] {
+                assert!(session["createdBy"].is_null());
+            } else {
+                assert_eq!(session["id"], stamped["sessionId"]);
+                assert_eq!(
+                    session["createdBy"],
+                    json!({"name": "claude-desktop", "version": "0.9.2"})
+                );
+            }
+        }
+    }
+
+    /// Polls the job until it is no longer running
+    async fn wait_for_job(server: &HtMcpServer, job_id: &serde_json::Value) -> serde_json::Value {
+        let deadline = Instant::now() + Duration::from_secs(20);
+        loop {
+            let status = server
+                .handle_tool_call("ht_get_job", json!({"jobId": job_id}))
+                .await
+                .unwrap();
+            if status["status"] != "running" {
+                return status;
+            }
+            assert!(Instant::now() < deadline, "job {} did not finish", job_id);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_async_execute_command_runs_as_job() {
+        let server = HtMcpServer::new();
+        let created = server
+            .handle_tool_call("ht_create_session", json!({}))
+            .await
+            .unwrap();
+
+        let job = server
+            .handle_tool_call(
+                "ht_execute_command",
+                json!({
+                    "sessionId": created["sessionId"],
+                    "command": "sleep 0.5; echo job-done",
+                    "async": true
+                }),
+            )
+            .await
+            .unwrap();
+        assert_eq!(job["status"], "running");
+        assert_eq!(job["tool"], "ht_execute_command");
+
+        let status = wait_for_job(&server, &job["jobId"]).await;
+        assert_eq!(status["status"], "succeeded");
+        assert_eq!(status["result"]["exitCode"], 0);
+        assert!(status["result"]["output"]
+            .as_str()
+            .unwrap()
+            .contains("job-done"));
+
+        // Tools that cannot run as jobs refuse async
+        let err = server
+            .handle_tool_call(
+                "ht_take_snapshot",
+                json!({"sessionId": created["sessionId"], "async": true}),
+            )
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("does not accept async"));
+    }
+
+    #[tokio::test]
+    async fn test_cancel_job_interrupts_the_command() {
+        let server = HtMcpServer::new();
+        let created = server
+            .handle_tool_call("ht_create_session", json!({}))
+            .await
+            .unwrap();
+        let session_id = &created["sessionId"];
+        server
+            .handle_tool_call(
+                "ht_acquire_lock",
+                json!({"sessionId": session_id, "holder": "driver"}),
+            )
+            .await
+            .unwrap();
+
+        let job = server
+            .handle_tool_call(
+                "ht_execute_command",
+                json!({"sessionId": session_id, "command": "sleep 10000", "async": true, "holder": "driver"}),
+            )
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        // The interrupt is input, so only the lock's holder may send it
+        assert_eq!(
+            error_code(&server, "ht_cancel_job", json!({"jobId": job["jobId"]})).await,
+            "session_locked"
+        );
+        let status = server
+            .handle_tool_call("ht_get_job", json!({"jobId": job["jobId"]}))
+            .await
+            .unwrap();
+        assert_eq!(status["status"], "running");
+
+        let cancelled = server
+            .handle_tool_call(
+                "ht_cancel_job",
+                json!({"jobId": job["jobId"], "holder": "driver"}),
+            )
+            .await
+            .unwrap();
+        assert_eq!(cancelled["status"], "cancelled");
+        assert_eq!(cancelled["cancelled"], true);
+        assert_eq!(cancelled["interrupted"], true);
+        let status = wait_for_job(&server, &job["jobId"]).await;
+        assert_eq!(status["status"], "cancelled");
+
+        // The sleep was interrupted, so the shell takes commands again
+        let result = server
+            .handle_tool_call(
+                "ht_execute_command",
+                json!({"sessionId": session_id, "command": "echo after-cancel", "holder": "driver"}),
+            )
+            .await
+            .unwrap();
+        assert_eq!(result["timedOut"], false);
+        assert!(result["output"].as_str().unwrap().contains("after-cancel"));
+
+        let err = server
+            .handle_tool_call("ht_get_job", json!({"jobId": "unknown"}))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("may have expired"));
+    }
+
+    #[tokio::test]
+    async fn test_paced_input_does_not_hold_up_other_sessions() {
+        let server = HtMcpServer::new();
+        let typed = server
+            .handle_tool_call("ht_create_session", json!({"inputProfile": "slow-human"}))
+            .await
+            .unwrap();
+        let other = server
+            .handle_tool_call("ht_create_session", json!({}))
+            .await
+            .unwrap();
+
+        let (sent, listed) = tokio::join!(
+            server.handle_tool_call(
+                "ht_send_keys",
+                json!({"sessionId": typed["sessionId"], "keys": ["echo typed slowly"], "seed": 7})
+            ),
+            async {
+                // Let the typing start, then use the other session meanwhile
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                let started = Instant::now();
+                server
+                    .handle_tool_call("ht_take_snapshot", json!({"sessionId": other["sessionId"]}))
+                    .await
+                    .unwrap();
+                started.elapsed()
+            }
+        );
+        let sent = sent.unwrap();
+        assert_eq!(sent["inputProfile"], "slow-human");
+        assert_eq!(sent["seed"], 7);
+        let typed_ms = sent["typedMs"].as_u64().unwrap();
+        // 16 delays of at least 60 ms
+        assert!(typed_ms >= 960, "{}", typed_ms);
+        assert!(
+            listed < Duration::from_millis(typed_ms / 2),
+            "snapshot took {:?}",
+            listed
+        );
+    }
+
+    #[tokio::test]
+    async fn test_running_command_does_not_hold_up_other_sessions() {
+        let server = HtMcpServer::new();
+        let busy = server
+            .handle_tool_call("ht_create_session", json!({}))
+            .await
+            .unwrap();
+        let other = server
+            .handle_tool_call("ht_create_session", json!({}))
+            .await
+            .unwrap();
+
+        let (ran, snapshotted) = tokio::join!(
+            server.handle_tool_call(
+                "ht_execute_command",
+                json!({"sessionId": busy["sessionId"], "command": "sleep 2; echo slept"})
+            ),
+            async {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                let started = Instant::now();
+                server
+                    .handle_tool_call("ht_take_snapshot", json!({"sessionId": other["sessionId"]}))
+                    .await
+                    .unwrap();
+                started.elapsed()
+            }
+        );
+        let ran = ran.unwrap();
+        assert_eq!(ran["exitCode"], 0);
+        assert!(ran["output"].as_str().unwrap().contains("slept"));
+        assert!(
+            snapshotted < Duration::from_secs(1),
+            "snapshot took {:?}",
+            snapshotted
+        );
+    }
+
+    #[tokio::test]
+    async fn test_parallel_execute_does_not_hold_up_other_sessions() {
+        let server = HtMcpServer::new();
+        let busy = server
+            .handle_tool_call("ht_create_session", json!({}))
+            .await
+            .unwrap();
+        let other = server
+            .handle_tool_call("ht_create_session", json!({}))
+            .await
+            .unwrap();
+
+        let (ran, snapshotted) = tokio::join!(
+            server.handle_tool_call(
+                "ht_parallel_execute",
+                json!({"sessionIds": [busy["sessionId"]], "command": "sleep 2; echo slept"})
+            ),
+            async {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                let started = Instant::now();
+                server
+                    .handle_tool_call("ht_take_snapshot", json!({"sessionId": other["sessionId"]}))
+                    .await
+                    .unwrap();
+                started.elapsed()
+            }
+        );
+        let ran = ran.unwrap();
+        assert_eq!(ran["succeeded"], 1);
+        assert!(ran["results"][0]["output"]
+            .as_str()
+            .unwrap()
+            .contains("slept"));
+        assert!(
+            snapshotted < Duration::from_secs(1),
+            "snapshot took {:?}",
+            snapshotted
+        );
+    }
+
+    /// Error code of a failed tool call
+    async fn error_code(
+        server: &HtMcpServer,
+        tool_name: &str,
+        arguments: serde_json::Value,
+    ) -> String {
+        server
+            .handle_tool_call(tool_name, arguments)
+            .await
+            .unwrap_err()
+            .code()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn test_interaction_lock_guards_every_input_tool() {
+        let server = HtMcpServer::with_config(HtMcpConfig {
+            enable_admin_tools: true,
+            ..HtMcpConfig::default()
+        });
+        let mut events = server.events().await.subscribe();
+        let created = server
+            .handle_tool_call("ht_create_session", json!({"enableWebServer": false}))
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+
+        let acquired = server
+            .handle_tool_call(
+                "ht_acquire_lock",
+                json!({"sessionId": session_id, "holder": "driver", "ttlSecs": 60}),
+            )
+            .await
+            .unwrap();
+        assert_eq!(acquired["lock"]["holder"], "driver");
+        assert!(acquired["previousHolder"].is_null());
+
+        // Refused before their own arguments are looked at
+        for (tool_name, arguments) in [
+            ("ht_send_keys", json!({"keys": ["x"]})),
+            ("ht_type_file", json!({"path": "/dev/null"})),
+            ("ht_close_stdin", json!({})),
+            ("ht_session_stdin_pipe", json!({"data": "x"})),
+            ("ht_execute_command", json!({"command": "echo hi"})),
+            ("ht_check_command_exists", json!({"command": "jq"})),
+            ("ht_probe_tools", json!({})),
+            (
+                "ht_interactive_prompt",
+                json!({"promptPattern": "x", "response": "y"}),
+            ),
+            ("ht_resize_terminal", json!({"cols": 80, "rows": 24})),
+        ] {
+            let mut arguments = arguments;
+            arguments["sessionId"] = json!(session_id);
+            assert_eq!(
+                error_code(&server, tool_name, arguments.clone()).await,
+                "session_locked",
+                "{}",
+                tool_name
+            );
+            arguments["holder"] = json!("observer");
+            assert_eq!(
+                error_code(&server, tool_name, arguments).await,
+                "session_locked",
+                "{}",
+                tool_name
+            );
+        }
+        let err = server
+            .handle_tool_call(
+                "ht_parallel_execute",
+                json!({"sessionIds": [session_id], "command": "echo hi", "holder": "observer"}),
+            )
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, HtMcpError::SessionLocked { holder, .. } if holder == "driver"),
+            "{}",
+            err
+        );
+
+        // Reading stays open; the holder and an override get through
+        server
+            .handle_tool_call("ht_take_snapshot", json!({"sessionId": session_id}))
+            .await
+            .unwrap();
+        server
+            .handle_tool_call(
+                "ht_execute_command",
+                json!({"sessionId": session_id, "command": "echo driving", "holder": "driver"}),
+            )
+            .await
+            .unwrap();
+        server
+            .handle_tool_call(
+                "ht_send_keys",
+                json!({"sessionId": session_id, "keys": ["echo"], "holder": "observer", "override": true}),
+            )
+            .await
+            .unwrap();
+
+        // Taking the lock over needs override too
+        assert_eq!(
+            error_code(
+                &server,
+                "ht_acquire_lock",
+                json!({"sessionId": session_id, "holder": "observer"})
+            )
+            .await,
+            "session_locked"
+        );
+        let stolen = server
+            .handle_tool_call(
+                "ht_acquire_lock",
+                json!({"sessionId": session_id, "holder": "observer", "override": true}),
+            )
+            .await
+            .unwrap();
+        assert_eq!(stolen["previousHolder"], "driver");
+        assert_eq!(
+            error_code(
+                &server,
+                "ht_send_keys",
+                json!({"sessionId": session_id, "keys": ["x"], "holder": "driver"})
+            )
+            .await,
+            "session_locked"
+        );
+        let listed = server
+            .handle_tool_call("ht_list_sessions", json!({}))
+            .await
+            .unwrap();
+        assert_eq!(listed["sessions"][0]["lock"]["holder"], "observer");
+
+        assert_eq!(
+            error_code(
+                &server,
+                "ht_release_lock",
+                json!({"sessionId": session_id, "holder": "driver"})
+            )
+            .await,
+            "session_locked"
+        );
+        let released = server
+            .handle_tool_call(
+                "ht_release_lock",
+                json!({"sessionId": session_id, "holder": "observer"}),
+            )
+            .await
+            .unwrap();
+        assert_eq!(released["released"], true);
+        server
+            .handle_tool_call(
+                "ht_send_keys",
+                json!({"sessionId": session_id, "keys": ["x"]}),
+            )
+            .await
+            .unwrap();
+
+        // A lock frees itself after its TTL
+        server
+            .handle_tool_call(
+                "ht_acquire_lock",
+                json!({"sessionId": session_id, "holder": "driver", "ttlSecs": 1}),
+            )
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        server
+            .handle_tool_call(
+                "ht_send_keys",
+                json!({"sessionId": session_id, "keys": ["x"], "holder": "observer"}),
+            )
+            .await
+            .unwrap();
+
+        let mut lock_events = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            match event {
+                SessionEvent::LockAcquired { holder, .. } => {
+                    lock_events.push(format!("acquired {}", holder))
+                }
+                SessionEvent::LockStolen {
+                    holder,
+                    previous_holder,
+                    ..
+                } => lock_events.push(format!("stolen {} from {}", holder, previous_holder)),
+                SessionEvent::LockReleased { holder, reason, .. } => {
+                    lock_events.push(format!("released {} ({:?})", holder, reason))
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(
+            lock_events,
+            [
+                "acquired driver",
+                "stolen observer from driver",
+                "released observer (Released)",
+                "acquired driver",
+                "released driver (Expired)",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_locked_session_is_only_closed_by_its_holder() {
+        let server = HtMcpServer::new();
+        let created = server
+            .handle_tool_call(
+                "ht_create_session",
+                json!({"enableWebServer": false, "readOnly": true, "tags": {"team": "a"}}),
+            )
+            .await
+            .unwrap();
+        let session_id = created["sessionId"].as_str().unwrap().to_string();
+        server
+            .handle_tool_call(
+                "ht_acquire_lock",
+                json!({"sessionId": session_id, "holder": "driver"}),
+            )
+            .await
+            .unwrap();
+
+        for (tool_name, arguments) in [
+            (
+                "ht_close_session",
+                json!({"sessionId": session_id, "holder": "observer"}),
+            ),
+            (
+                "ht_bulk_close_sessions",
+                json!({"sessionIds": [session_id]}),
+            ),
+            ("ht_bulk_close_sessions", json!({"closeAll": true})),
+            (
+                "ht_bulk_close_sessions",
+                json!({"filterTags": {"team": "a"}, "holder": "observer"}),
+            ),
+        ] {
+            assert_eq!(
+                error_code(&server, tool_name, arguments.clone()).await,
+                "session_locked",
+                "{} {}",
+                tool_name,
+                arguments
+            );
+        }
+        let listed = server
+            .handle_tool_call("ht_list_sessions", json!({}))
+            .await
+            .unwrap();
+        assert_eq!(listed["sessions"].as_array().unwrap().len(), 1);
+
+        // Read-only takes no input, but the holder may still close it
+        let closed = server
+            .handle_tool_call(
+                "ht_close_session",
+                json!({"sessionId": session_id, "holder": "driver"}),
+            )
+            .await
+            .unwrap();
+        assert_eq!(closed["success"], true);
+    }
+
+    #[tokio::test]
+    async fn test_lock_override_needs_admin_tools() {
+        let server = HtMcpServer::new();
+        let created = server
+            .handle_tool_call("ht_create_session", json!({"enableWebServer": false}))
+            .await
+            .unwrap();
+        server
+            .handle_tool_call(
+                "ht_acquire_lock",
+                json!({"sessionId": created["sessionId"], "holder": "driver"}),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            error_code(
+                &server,
+                "ht_send_keys",
+                json!({"sessionId": created["sessionId"], "keys": ["x"], "override": true})
+            )
+            .await,
+            "invalid_request"
+        );
+        assert_eq!(
+            error_code(
+                &server,
+                "ht_acquire_lock",
+                json!({"sessionId": created["sessionId"], "holder": "observer", "override": true})
+            )
+            .await,
+            "invalid_request"
+        );
+    }
 }
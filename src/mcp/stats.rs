@@ -0,0 +1,236 @@
+use crate::error::HtMcpError;
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+use tracing::{debug, error, warn};
+
+/// Number of recent errors kept for the troubleshooting summary
+pub const RECENT_ERROR_LIMIT: usize = 10;
+
+/// Number of latency samples kept per tool for percentile calculation
+const LATENCY_SAMPLE_LIMIT: usize = 1000;
+
+/// Consecutive identical failures after which a consolidated warning is logged
+pub const REPEATED_ERROR_THRESHOLD: u32 = 5;
+
+/// A single failed tool call
+#[derive(Debug, Clone, Serialize)]
+pub struct RecordedError {
+    pub tool: String,
+    pub code: String,
+    pub message: String,
+    pub timestamp: String,
+}
+
+#[derive(Debug, Default)]
+struct ToolCounters {
+    calls: u64,
+    errors: u64,
+    errors_by_code: BTreeMap<String, u64>,
+    latencies_ms: VecDeque<u64>,
+    streak_code: Option<&'static str>,
+    streak_len: u32,
+}
+
+impl ToolCounters {
+    fn record_latency(&mut self, latency: Duration) {
+        if self.latencies_ms.len() == LATENCY_SAMPLE_LIMIT {
+            self.latencies_ms.pop_front();
+        }
+        self.latencies_ms.push_back(latency.as_millis() as u64);
+    }
+}
+
+/// In-memory per-tool call statistics maintained by the dispatch middleware
+#[derive(Debug, Default)]
+pub struct ToolStats {
+    tools: BTreeMap<String, ToolCounters>,
+    recent_errors: VecDeque<RecordedError>,
+}
+
+impl ToolStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a successful call, ending any error streak for the tool
+    pub fn record_success(&mut self, tool: &str, latency: Duration) {
+        let counters = self.tools.entry(tool.to_string()).or_default();
+        counters.calls += 1;
+        counters.record_latency(latency);
+        counters.streak_code = None;
+        counters.streak_len = 0;
+    }
+
+    /// Records a failed call and logs it.
+    ///
+    /// Repeats of the same error code are not logged individually; instead a
+    /// consolidated warning with a hint is emitted every
+    /// `REPEATED_ERROR_THRESHOLD` consecutive failures.
+    pub fn record_error(&mut self, tool: &str, latency: Duration, err: &HtMcpError) {
+        let code = err.code();
+        let counters = self.tools.entry(tool.to_string()).or_default();
+        counters.calls += 1;
+        counters.errors += 1;
+        counters.record_latency(latency);
+        *counters.errors_by_code.entry(code.to_string()).or_insert(0) += 1;
+
+        if counters.streak_code == Some(code) {
+            counters.streak_len += 1;
+        } else {
+            counters.streak_code = Some(code);
+            counters.streak_len = 1;
+        }
+
+        if counters.streak_len == 1 {
+            error!("Tool {} failed: {}", tool, err);
+        } else if counters.streak_len % REPEATED_ERROR_THRESHOLD == 0 {
+            warn!(
+                "Tool {} failed {} times in a row with {}: {} (hint: {})",
+                tool,
+                counters.streak_len,
+                code,
+                err,
+                err.hint()
+            );
+        } else {
+            debug!("Tool {} failed again with {}: {}", tool, code, err);
+        }
+
+        if self.recent_errors.len() == RECENT_ERROR_LIMIT {
+            self.recent_errors.pop_front();
+        }
+        self.recent_errors.push_back(RecordedError {
+            tool: tool.to_string(),
+            code: code.to_string(),
+            message: err.to_string(),
+            timestamp: chrono::Utc::now().to_rfc3339(),
+        });
+    }
+
+    /// Returns the number of calls recorded for a tool
+    pub fn calls(&self, tool: &str) -> u64 {
+        self.tools.get(tool).map(|c| c.calls).unwrap_or(0)
+    }
+
+    /// Returns the recent errors, oldest first
+    pub fn recent_errors(&self) -> impl Iterator<Item = &RecordedError> {
+        self.recent_errors.iter()
+    }
+
+    /// Builds the JSON summary returned by the `ht_stats` tool
+    pub fn summary(&self) -> serde_json::Value {
+        let tools: serde_json::Map<String, serde_json::Value> = self
+            .tools
+            .iter()
+            .map(|(name, counters)| {
+                let mut sorted: Vec<u64> = counters.latencies_ms.iter().copied().collect();
+                sorted.sort_unstable();
+                (
+                    name.clone(),
+                    serde_json::json!({
+                        "calls": counters.calls,
+                        "errors": counters.errors,
+                        "errorsByCode": counters.errors_by_code,
+                        "p50LatencyMs": percentile(&sorted, 50.0),
+                        "p95LatencyMs": percentile(&sorted, 95.0)
+                    }),
+                )
+            })
+            .collect();
+
+        serde_json::json!({
+            "tools": tools,
+            "recentErrors": self.recent_errors
+        })
+    }
+}
+
+/// Nearest-rank percentile over an already sorted slice
+pub fn percentile(sorted: &[u64], pct: f64) -> Option<u64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = ((pct / 100.0) * sorted.len() as f64).ceil() as usize;
+    Some(sorted[rank.clamp(1, sorted.len()) - 1])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aggregates_successes_and_failures() {
+        let mut stats = ToolStats::new();
+        for ms in [10, 20, 30, 40] {
+            stats.record_success("ht_take_snapshot", Duration::from_millis(ms));
+        }
+        stats.record_error(
+            "ht_take_snapshot",
+            Duration::from_millis(5000),
+            &HtMcpError::Internal("Snapshot request timed out".to_string()),
+        );
+        stats.record_error(
+            "ht_send_keys",
+            Duration::from_millis(1),
+            &HtMcpError::SessionNotFound("abc".to_string()),
+        );
+
+        let summary = stats.summary();
+        let snapshot = &summary["tools"]["ht_take_snapshot"];
+        assert_eq!(snapshot["calls"], 5);
+        assert_eq!(snapshot["errors"], 1);
+        assert_eq!(snapshot["errorsByCode"]["internal_error"], 1);
+        assert_eq!(snapshot["p50LatencyMs"], 30);
+        assert_eq!(snapshot["p95LatencyMs"], 5000);
+
+        let keys = &summary["tools"]["ht_send_keys"];
+        assert_eq!(keys["calls"], 1);
+        assert_eq!(keys["errorsByCode"]["session_not_found"], 1);
+
+        let recent = summary["recentErrors"].as_array().unwrap();
+        assert_eq!(recent.len(), 2);
+        assert_eq!(recent[1]["tool"], "ht_send_keys");
+        assert_eq!(recent[1]["code"], "session_not_found");
+        assert!(recent[1]["message"].as_str().unwrap().contains("abc"));
+    }
+
+    #[test]
+    fn test_recent_errors_are_bounded() {
+        let mut stats = ToolStats::new();
+        for i in 0..(RECENT_ERROR_LIMIT + 5) {
+            stats.record_error(
+                "ht_close_session",
+                Duration::from_millis(1),
+                &HtMcpError::SessionNotFound(format!("session-{}", i)),
+            );
+        }
+
+        let recent: Vec<_> = stats.recent_errors().collect();
+        assert_eq!(recent.len(), RECENT_ERROR_LIMIT);
+        assert!(recent[0].message.contains("session-5"));
+        assert_eq!(
+            stats.calls("ht_close_session"),
+            (RECENT_ERROR_LIMIT + 5) as u64
+        );
+    }
+
+    #[test]
+    fn test_success_resets_error_streak() {
+        let mut stats = ToolStats::new();
+        let err = HtMcpError::InvalidRequest("bad".to_string());
+        stats.record_error("ht_send_keys", Duration::ZERO, &err);
+        stats.record_error("ht_send_keys", Duration::ZERO, &err);
+        assert_eq!(stats.tools["ht_send_keys"].streak_len, 2);
+
+        stats.record_success("ht_send_keys", Duration::ZERO);
+        assert_eq!(stats.tools["ht_send_keys"].streak_len, 0);
+        assert!(stats.tools["ht_send_keys"].streak_code.is_none());
+    }
+
+    #[test]
+    fn test_percentile_empty() {
+        assert_eq!(percentile(&[], 50.0), None);
+        assert_eq!(percentile(&[7], 95.0), Some(7));
+    }
+}
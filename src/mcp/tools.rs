@@ -7,30 +7,240 @@ pub fn get_tool_definitions() -> Vec<serde_json::Value> {
             "description": "Create a new HT session",
             "inputSchema": create_session_schema()
         }),
+        serde_json::json!({
+            "name": "ht_create_session_with_tunnel",
+            "description": "Create an HT session with its web server and a public tunnel in one call; the session is closed again if the tunnel fails",
+            "inputSchema": create_session_with_tunnel_schema()
+        }),
         serde_json::json!({
             "name": "ht_send_keys",
             "description": "Send keys to an HT session",
             "inputSchema": send_keys_schema()
         }),
+        serde_json::json!({
+            "name": "ht_type_file",
+            "description": "Type the contents of a file on the server into the terminal, in chunks, as if pasted: for seeding a REPL or editor. Line endings are typed as Enter. Only files under the config's type_file_paths can be read.",
+            "inputSchema": type_file_schema()
+        }),
+        serde_json::json!({
+            "name": "ht_close_stdin",
+            "description": "Send the raw EOF byte (Ctrl-D, 0x04) to the terminal so programs reading until end of input (cat, sort) finish. More reliable than sending \"C-d\" with ht_send_keys. Takes effect at the start of a line; after a partially typed line it only submits that line.",
+            "inputSchema": close_stdin_schema()
+        }),
+        serde_json::json!({
+            "name": "ht_session_stdin_pipe",
+            "description": "Feed data to a command blocked reading stdin, such as one started with ht_create_session that waits for input. The data is written as is, multi-line text included, unlike ht_send_keys which simulates keystrokes; closeAfter then sends EOF so the command sees the end of its input.",
+            "inputSchema": pipe_stdin_schema()
+        }),
         serde_json::json!({
             "name": "ht_take_snapshot",
             "description": "Take a snapshot of the terminal state",
             "inputSchema": take_snapshot_schema()
         }),
+        serde_json::json!({
+            "name": "ht_take_snapshot_as_png",
+            "description": "Render the terminal screen as a PNG image, with its colors, attributes and cursor, for visual inspection of TUIs. Returns the image as an image content item and base64 in png",
+            "inputSchema": take_snapshot_as_png_schema()
+        }),
+        serde_json::json!({
+            "name": "ht_verify_snapshot",
+            "description": "Check the hash of a snapshot's provenance block (includeProvenance) against the provenance blocks this server handed out and the snapshot history of live sessions; pass the shared text to detect changes to it",
+            "inputSchema": verify_snapshot_schema()
+        }),
+        serde_json::json!({
+            "name": "ht_take_baseline_snapshot",
+            "description": "Store the current screen under a name, to compare later screens against with ht_compare_to_baseline",
+            "inputSchema": baseline_snapshot_schema()
+        }),
+        serde_json::json!({
+            "name": "ht_compare_to_baseline",
+            "description": "Compare the current screen line by line with a stored baseline; returns whether it matches and the lines that differ (expected vs actual)",
+            "inputSchema": compare_to_baseline_schema()
+        }),
+        serde_json::json!({
+            "name": "ht_classify_screen",
+            "description": "Classify what the terminal is showing (shell-prompt, running-command, full-screen-app, password-prompt, pager or unknown), with the evidence and a confidence",
+            "inputSchema": classify_screen_schema()
+        }),
+        serde_json::json!({
+            "name": "ht_assert_screen",
+            "description": "Check the screen against a list of assertions (contains, notContains, regex, lineEquals, cursorAt, sizeIs) taken from one capture; returns pass/fail per assertion with the actual values and an overall passed flag",
+            "inputSchema": assert_screen_schema()
+        }),
+        serde_json::json!({
+            "name": "ht_get_snapshot_history",
+            "description": "Get the periodic snapshots of a session created with snapshotIntervalMs, oldest first",
+            "inputSchema": get_snapshot_history_schema()
+        }),
+        serde_json::json!({
+            "name": "ht_get_connected_clients",
+            "description": "List the browser viewers (WebSocket clients) connected to a session's web server, with when they connected and their address. Viewers arriving through a tunnel show the tunnel's loopback address.",
+            "inputSchema": get_connected_clients_schema()
+        }),
+        serde_json::json!({
+            "name": "ht_download_history",
+            "description": "Download a session's whole retained history (periodic snapshots and command history) as plain text or JSON lines, in chunks of a chosen size. Start without a token; then pass the token and each chunk's next cursor until next is null. Chunks and the file carry CRC-32s, and a lost chunk is fetched again with the same cursor. With viaUrl, returns a one-time URL on the management server for the whole file instead.",
+            "inputSchema": download_history_schema()
+        }),
+        serde_json::json!({
+            "name": "ht_get_session_metrics_history",
+            "description": "Get a session's metrics (snapshot count, input and output bytes, memory RSS) sampled every 30 seconds over the last 5 minutes, oldest first, to spot trends such as growing memory",
+            "inputSchema": get_session_metrics_history_schema()
+        }),
+        serde_json::json!({
+            "name": "ht_export_dockerfile",
+            "description": "Generate a starting-point Dockerfile reproducing a session's environment: packages guessed from its command history (apt installs and well-known commands), its environment variables, current working directory and command. A heuristic to review, not a guaranteed reproduction; configured session_env variables become build arguments rather than having their values written out",
+            "inputSchema": export_dockerfile_schema()
+        }),
         serde_json::json!({
             "name": "ht_execute_command",
             "description": "Execute a command and return output",
             "inputSchema": execute_command_schema()
         }),
+        serde_json::json!({
+            "name": "ht_set_alias",
+            "description": "Set or remove a session's command alias: ht_execute_command runs the alias's command, with any arguments appended, when a command starts with its name. Session aliases shadow configured ones; expansion stops at an alias already expanded, so ls = \"ls --color\" works.",
+            "inputSchema": set_alias_schema()
+        }),
+        serde_json::json!({
+            "name": "ht_check_command_exists",
+            "description": "Check whether a program is available in a session's shell before running it; returns exists and the path command -v found it at",
+            "inputSchema": check_command_exists_schema()
+        }),
+        serde_json::json!({
+            "name": "ht_probe_tools",
+            "description": "Find out in one round trip which tools a session's shell has, e.g. jq, rg, python3, and whether sed is GNU or BSD; returns the shell with its version and each tool's path and version",
+            "inputSchema": probe_tools_schema()
+        }),
+        serde_json::json!({
+            "name": "ht_interactive_prompt",
+            "description": "Wait for a prompt such as a confirmation or password request to show up, then answer it",
+            "inputSchema": interactive_prompt_schema()
+        }),
+        serde_json::json!({
+            "name": "ht_tail_file",
+            "description": "Follow a server-local file in a read-only session shared by every call naming the file, and return its lines from cursor on, filtered by filterPattern. The session closes itself when the file is removed",
+            "inputSchema": tail_file_schema()
+        }),
+        serde_json::json!({
+            "name": "ht_session_group_snapshot",
+            "description": "Snapshot every session in a group (see groupId on ht_create_session) at once; sessions that fail are reported without holding up the rest",
+            "inputSchema": group_snapshot_schema()
+        }),
+        serde_json::json!({
+            "name": "ht_parallel_execute",
+            "description": "Execute the same command in several sessions at once and return each session's output",
+            "inputSchema": parallel_execute_schema()
+        }),
         serde_json::json!({
             "name": "ht_list_sessions",
-            "description": "List all active sessions",
+            "description": "List active sessions, newest first, one page at a time",
             "inputSchema": list_sessions_schema()
         }),
+        serde_json::json!({
+            "name": "ht_get_session_info",
+            "description": "Details of one session: what ht_list_sessions shows plus uptime, terminal size and input, output and snapshot counts",
+            "inputSchema": get_session_info_schema()
+        }),
+        serde_json::json!({
+            "name": "ht_resource_report",
+            "description": "Memory, CPU and output volume of every session's process, with totals, the terminal size limits with the screen memory they allow, and disk usage per storage category; refreshed at most every 5 seconds",
+            "inputSchema": resource_report_schema()
+        }),
+        serde_json::json!({
+            "name": "ht_generate_mcp_config",
+            "description": "Ready-to-paste MCP client config (claude_desktop_config.json) that launches this server",
+            "inputSchema": generate_mcp_config_schema()
+        }),
+        serde_json::json!({
+            "name": "ht_create_tunnel",
+            "description": "Expose a local port, such as a dev server started in a session, through a public tunnel of its own; returns the tunnel ID and URL. Only ports listed in the server's tunnel_ports config are allowed unless admin tools are enabled",
+            "inputSchema": create_tunnel_schema()
+        }),
+        serde_json::json!({
+            "name": "ht_list_tunnels",
+            "description": "List tunnels and their sessions, newest first, one page at a time, with whether each tunnel's client still runs (isActive) and, for pooled tunnels, whether each is idle or leased and how long it has been pooled",
+            "inputSchema": list_tunnels_schema()
+        }),
+        serde_json::json!({
+            "name": "ht_stop_tunnel",
+            "description": "Stop a tunnel by ID; a session it belonged to keeps running without a public URL",
+            "inputSchema": stop_tunnel_schema()
+        }),
+        serde_json::json!({
+            "name": "ht_reconnect_cloudflared",
+            "description": "Relaunch a session's dead cloudflared tunnel without closing the session; returns the new public URL",
+            "inputSchema": reconnect_tunnel_schema()
+        }),
+        serde_json::json!({
+            "name": "ht_resize_terminal",
+            "description": "Resize a session's terminal. The program in it is told the new size (SIGWINCH) and later snapshots use it. Returns the old and new size.",
+            "inputSchema": resize_schema()
+        }),
+        serde_json::json!({
+            "name": "ht_reserve_port",
+            "description": "Reserve a web server port for a session not created yet, so concurrent creations cannot pick the same one. Pass it as preferredPort to ht_create_session before the reservation expires.",
+            "inputSchema": reserve_port_schema()
+        }),
+        serde_json::json!({
+            "name": "ht_renew_lease",
+            "description": "Extend the lease of a session created with leaseSecs so it is not closed",
+            "inputSchema": renew_lease_schema()
+        }),
+        serde_json::json!({
+            "name": "ht_acquire_lock",
+            "description": "Take a session's advisory interaction lock for ttlSecs: input tools called without the same holder fail with SessionLocked until it is released or expires. Snapshots stay open to everyone",
+            "inputSchema": acquire_lock_schema()
+        }),
+        serde_json::json!({
+            "name": "ht_release_lock",
+            "description": "Release a session's interaction lock taken with ht_acquire_lock",
+            "inputSchema": release_lock_schema()
+        }),
+        serde_json::json!({
+            "name": "ht_set_retention",
+            "description": "Change how much history a session keeps (snapshot history, command history), up to the server's limits. Lowering a limit drops the oldest entries at once. Returns each limit with what is stored now.",
+            "inputSchema": set_retention_schema()
+        }),
         serde_json::json!({
             "name": "ht_close_session",
             "description": "Close an HT session",
             "inputSchema": close_session_schema()
         }),
+        serde_json::json!({
+            "name": "ht_bulk_close_sessions",
+            "description": "Close several HT sessions in one call: the listed ones, every session with closeAll, or those having all filterTags. Sessions that can be closed are, even if others fail; returns closed session IDs and failed ones with their error",
+            "inputSchema": bulk_close_sessions_schema()
+        }),
+        serde_json::json!({
+            "name": "ht_get_job",
+            "description": "Get the status of a job started by a call made with async: running, succeeded, failed or cancelled, elapsed time against its timeout, and once finished the call's result or error. Finished jobs are kept for 10 minutes",
+            "inputSchema": get_job_schema()
+        }),
+        serde_json::json!({
+            "name": "ht_cancel_job",
+            "description": "Stop a running job; a command run by ht_execute_command is interrupted with Ctrl-C",
+            "inputSchema": cancel_job_schema()
+        }),
+        serde_json::json!({
+            "name": "ht_drain",
+            "description": "Admin: stop accepting new sessions, let existing ones finish, then close them and exit after the grace period",
+            "inputSchema": drain_schema()
+        }),
+        serde_json::json!({
+            "name": "ht_export_state",
+            "description": "Admin: write the server's state (live sessions as definitions to recreate, recording records and files) to a versioned .tar.gz state archive for moving to a new host",
+            "inputSchema": export_state_schema()
+        }),
+        serde_json::json!({
+            "name": "ht_import_state",
+            "description": "Admin: validate a state archive from ht_export_state, recreate its sessions and adopt its recording records, optionally remapping the recordings directory and restoring recording files there",
+            "inputSchema": import_state_schema()
+        }),
+        serde_json::json!({
+            "name": "ht_stats",
+            "description": "Get per-tool call statistics and recent errors for troubleshooting",
+            "inputSchema": stats_schema()
+        }),
     ]
 }
@@ -1,13 +1,137 @@
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use std::collections::HashMap;
 
-#[derive(Debug, Deserialize)]
+use crate::ht_integration::annotations::AnnotationPatternConfig;
+use crate::ht_integration::assertions::Assertion;
+use crate::ht_integration::history_download;
+use crate::ht_integration::input_profile::InputProfile;
+use crate::ht_integration::port_reservation;
+use crate::ht_integration::retention::RetentionOverrides;
+use crate::ht_integration::session_manager::{MAX_TERMINAL_SIZE, MAX_TUNNEL_TIMEOUT_SECS};
+use crate::ht_integration::state::PathRemap;
+use crate::tunnel::config::TUNNEL_PROVIDERS;
+
+#[derive(Debug, Default, Deserialize)]
 pub struct CreateSessionArgs {
     pub command: Option<Vec<String>>,
+    /// Working directory; relative paths are resolved against the workspace
+    /// root when one is configured
+    pub cwd: Option<String>,
+    /// Environment variables for the command, over the server's and
+    /// `session_env`
+    pub env: Option<HashMap<String, String>>,
     #[serde(rename = "enableWebServer")]
     pub enable_web_server: Option<bool>,
     #[serde(rename = "enableTunnel")]
     pub enable_tunnel: Option<bool>,
+    #[serde(rename = "idempotencyKey")]
+    pub idempotency_key: Option<String>,
+    #[serde(rename = "maxMemoryMb")]
+    pub max_memory_mb: Option<u64>,
+    #[serde(rename = "maxConnections")]
+    pub max_connections: Option<usize>,
+    #[serde(rename = "maxConnectionsPerIp")]
+    pub max_connections_per_ip: Option<usize>,
+    #[serde(rename = "idleTimeoutSecs")]
+    pub idle_timeout_secs: Option<u64>,
+    #[serde(rename = "runAs")]
+    pub run_as: Option<RunAsArgs>,
+    #[serde(rename = "labelColor")]
+    pub label_color: Option<String>,
+    #[serde(rename = "labelIcon")]
+    pub label_icon: Option<String>,
+    /// Create a scratch directory for the session, exported as `$HT_SESSION_TMP`
+    #[serde(rename = "tempDir")]
+    pub temp_dir: Option<bool>,
+    /// Record a snapshot this often, for `ht_get_snapshot_history`
+    #[serde(rename = "snapshotIntervalMs")]
+    pub snapshot_interval_ms: Option<u64>,
+    /// Close the session after this many seconds unless renewed with
+    /// `ht_renew_lease`
+    #[serde(rename = "leaseSecs")]
+    pub lease_secs: Option<u64>,
+    /// Set the shell prompt to a unique marker and wait for it, at creation
+    /// and after each `execute_command`
+    #[serde(rename = "autoDetectPrompt")]
+    pub auto_detect_prompt: Option<bool>,
+    /// Log every byte to and from the PTY at trace level; needs
+    /// `allow_trace_mode` in the config
+    #[serde(rename = "traceMode")]
+    pub trace_mode: Option<bool>,
+    /// Lower retention limits than the server's for this session
+    pub retention: Option<RetentionOverrides>,
+    /// TERM and COLORTERM for the session; xterm-256color by default
+    #[serde(rename = "colorProfile")]
+    pub color_profile: Option<ColorProfile>,
+    /// Cores the PTY process may run on (Linux only)
+    #[serde(rename = "cpuAffinity")]
+    pub cpu_affinity: Option<Vec<usize>>,
+    /// Web server port, typically one reserved with `ht_reserve_port`
+    #[serde(rename = "preferredPort")]
+    pub preferred_port: Option<u16>,
+    /// How `send_keys` paces input unless a call picks a profile
+    #[serde(rename = "inputProfile")]
+    pub input_profile: Option<InputProfile>,
+    /// Group the session belongs to, for tools acting on a whole group
+    #[serde(rename = "groupId")]
+    pub group_id: Option<String>,
+    /// Free-form labels, for tools selecting sessions by tag
+    pub tags: Option<HashMap<String, String>>,
+    /// Terminal width; 120 by default
+    pub cols: Option<usize>,
+    /// Terminal height; 40 by default
+    pub rows: Option<usize>,
+    /// URL the session's output lines are posted to as they arrive
+    #[serde(rename = "outputWebhookUrl")]
+    pub output_webhook_url: Option<String>,
+    /// How often `output_webhook_url` gets the lines gathered since the last
+    /// post; 1000 by default
+    #[serde(rename = "outputWebhookIntervalMs")]
+    pub output_webhook_interval_ms: Option<u64>,
+    /// Refuse input from every source, for sessions shown to viewers who
+    /// must only watch
+    #[serde(rename = "readOnly")]
+    pub read_only: Option<bool>,
+    /// Name standing for `{session-name}` in the named tunnel's hostname;
+    /// the session id by default
+    #[serde(rename = "tunnelName")]
+    pub tunnel_name: Option<String>,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+/// Color support a session's programs are told about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ColorProfile {
+    /// `TERM=xterm`, 8 colors
+    #[serde(rename = "basic")]
+    Basic,
+    /// `TERM=xterm-256color`
+    #[serde(rename = "256color")]
+    Color256,
+    /// `TERM=xterm-256color` and `COLORTERM=truecolor`, 24-bit color.
+    /// `xterm-truecolor` has no terminfo entry on most systems.
+    #[serde(rename = "truecolor")]
+    TrueColor,
+}
+
+impl ColorProfile {
+    /// Environment variables that announce the profile
+    pub fn env(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            ColorProfile::Basic => &[("TERM", "xterm")],
+            ColorProfile::Color256 => &[("TERM", "xterm-256color")],
+            ColorProfile::TrueColor => &[("TERM", "xterm-256color"), ("COLORTERM", "truecolor")],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RunAsArgs {
+    pub user: String,
+    pub group: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -23,6 +147,45 @@ pub struct CreateSessionResult {
     pub tunnel_enabled: bool,
     #[serde(rename = "tunnelUrl")]
     pub tunnel_url: Option<String>,
+    #[serde(rename = "tempDir", skip_serializing_if = "Option::is_none")]
+    pub temp_dir: Option<String>,
+    /// The shell prompt, when set with `autoDetectPrompt`
+    #[serde(rename = "promptMarker", skip_serializing_if = "Option::is_none")]
+    pub prompt_marker: Option<String>,
+    /// Milliseconds from spawning the PTY to its first output, if there was
+    /// output by the time creation returned
+    #[serde(rename = "firstOutputMs")]
+    pub first_output_ms: Option<u64>,
+    /// Milliseconds from spawning the PTY to the prompt showing, with
+    /// `autoDetectPrompt`
+    #[serde(rename = "promptReadyMs")]
+    pub prompt_ready_ms: Option<u64>,
+    /// Set when the session was created by an earlier call with the same
+    /// `idempotencyKey`
+    #[serde(rename = "idempotentReplay")]
+    pub idempotent_replay: bool,
+    /// Set when an idle session from the warm pool was handed out
+    #[serde(rename = "fromPool")]
+    pub from_pool: bool,
+    /// Requested settings that could not be applied
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateSessionWithTunnelArgs {
+    pub command: Option<Vec<String>>,
+    /// Tunnel provider: `cloudflare` (default), `auto`, or `bore` and
+    /// `localhost.run` when the config's `tunnel_providers` enables them
+    #[serde(rename = "tunnelProvider")]
+    pub tunnel_provider: Option<String>,
+    /// How long to wait for the tunnel's public URL (default and maximum:
+    /// cloudflared's 30s startup limit)
+    #[serde(rename = "tunnelTimeoutMs")]
+    pub tunnel_timeout_ms: Option<u64>,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -30,12 +193,158 @@ pub struct SendKeysArgs {
     #[serde(rename = "sessionId")]
     pub session_id: String,
     pub keys: Vec<String>,
+    /// Overrides the session's `inputProfile` for this call
+    #[serde(rename = "inputProfile")]
+    pub input_profile: Option<InputProfile>,
+    /// Seed of a paced profile's delays, to repeat a call's pacing
+    pub seed: Option<u64>,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TypeFileArgs {
+    #[serde(rename = "sessionId")]
+    pub session_id: String,
+    /// Server-local file under `type_file_paths`
+    pub path: String,
+    #[serde(rename = "chunkBytes")]
+    pub chunk_bytes: Option<usize>,
+    #[serde(rename = "chunkDelayMs")]
+    pub chunk_delay_ms: Option<u64>,
+    /// Wrap the text in bracketed-paste markers
+    #[serde(rename = "bracketedPaste")]
+    pub bracketed_paste: Option<bool>,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CloseStdinArgs {
+    #[serde(rename = "sessionId")]
+    pub session_id: String,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PipeStdinArgs {
+    #[serde(rename = "sessionId")]
+    pub session_id: String,
+    /// Written to the PTY as is, without key names
+    pub data: String,
+    /// Send EOF (Ctrl-D) after the data
+    #[serde(rename = "closeAfter")]
+    pub close_after: Option<bool>,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SnapshotFormat {
+    /// The screen as rendered
+    Plain,
+    /// Trimmed lines, collapsed blank lines and shortened character runs
+    AiCompressed,
+    /// The screen with SGR sequences for its colors and attributes
+    Ansi,
+    /// The plain screen plus the styled runs of each line as JSON
+    Structured,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct TakeSnapshotArgs {
     #[serde(rename = "sessionId")]
     pub session_id: String,
+    /// How the snapshot text is returned; `plain` by default
+    pub format: Option<SnapshotFormat>,
+    /// Drop blank lines below the last line with content (default: false)
+    #[serde(rename = "trimTrailingBlankLines")]
+    pub trim_trailing_blank_lines: Option<bool>,
+    /// Mark lines matching error, warning, stack trace and URL patterns
+    pub annotate: Option<bool>,
+    /// Patterns used for this call on top of the configured ones
+    #[serde(rename = "annotatePatterns")]
+    pub annotate_patterns: Option<Vec<AnnotationPatternConfig>>,
+    /// Wrap lines longer than this many characters at word boundaries
+    #[serde(rename = "wordWrap")]
+    pub word_wrap: Option<usize>,
+    /// Return the snapshot's size and estimated tokens instead of the text
+    #[serde(rename = "probeOnly")]
+    pub probe_only: Option<bool>,
+    /// Cut the snapshot to about this many tokens, keeping its end
+    #[serde(rename = "maxTokens")]
+    pub max_tokens: Option<usize>,
+    /// Add where the snapshot came from and a hash of its content
+    #[serde(rename = "includeProvenance")]
+    pub include_provenance: Option<bool>,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TakeScreenshotArgs {
+    #[serde(rename = "sessionId")]
+    pub session_id: String,
+    /// Image size relative to 8x13 pixels per cell (default: 1)
+    pub scale: Option<f32>,
+    /// Add a provenance footer below the screen
+    #[serde(rename = "includeProvenance")]
+    pub include_provenance: Option<bool>,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VerifySnapshotArgs {
+    /// Hash from a provenance block
+    pub hash: String,
+    /// The snapshot text as shared, checked against the hash
+    pub text: Option<String>,
+    /// Only look at this session's snapshots
+    #[serde(rename = "sessionId")]
+    pub session_id: Option<String>,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BaselineSnapshotArgs {
+    #[serde(rename = "sessionId")]
+    pub session_id: String,
+    #[serde(rename = "baselineId")]
+    pub baseline_id: String,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CompareSnapshotArgs {
+    #[serde(rename = "sessionId")]
+    pub session_id: String,
+    #[serde(rename = "baselineId")]
+    pub baseline_id: String,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ClassifyScreenArgs {
+    #[serde(rename = "sessionId")]
+    pub session_id: String,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
 }
 
 #[derive(Debug, Serialize)]
@@ -43,6 +352,119 @@ pub struct SnapshotResult {
     #[serde(rename = "sessionId")]
     pub session_id: String,
     pub snapshot: String,
+    #[serde(rename = "lineCount")]
+    pub line_count: usize,
+    /// Lines with anything but whitespace on them
+    #[serde(rename = "nonEmptyLineCount")]
+    pub non_empty_line_count: usize,
+    /// Cursor row, from 1
+    #[serde(rename = "cursorRow")]
+    pub cursor_row: usize,
+    /// Cursor column, from 1
+    #[serde(rename = "cursorCol")]
+    pub cursor_col: usize,
+    /// False while the program hides the cursor
+    #[serde(rename = "cursorVisible")]
+    pub cursor_visible: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AssertScreenArgs {
+    #[serde(rename = "sessionId")]
+    pub session_id: String,
+    pub assertions: Vec<Assertion>,
+    /// Include the screen text when an assertion fails (default: true)
+    #[serde(rename = "attachSnapshotOnFailure")]
+    pub attach_snapshot_on_failure: Option<bool>,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GetSessionMetricsHistoryArgs {
+    #[serde(rename = "sessionId")]
+    pub session_id: String,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportDockerfileArgs {
+    #[serde(rename = "sessionId")]
+    pub session_id: String,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GetSnapshotHistoryArgs {
+    #[serde(rename = "sessionId")]
+    pub session_id: String,
+    /// Most snapshots to return, newest kept (default: 10)
+    pub limit: Option<usize>,
+    /// Only snapshots taken within this many milliseconds of now
+    #[serde(rename = "sinceMs")]
+    pub since_ms: Option<u64>,
+    /// Return the snapshots' size and estimated tokens instead of the text
+    #[serde(rename = "probeOnly")]
+    pub probe_only: Option<bool>,
+    /// Drop the oldest snapshots to stay within about this many tokens
+    #[serde(rename = "maxTokens")]
+    pub max_tokens: Option<usize>,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GetSessionInfoArgs {
+    #[serde(rename = "sessionId")]
+    pub session_id: String,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GetConnectedClientsArgs {
+    #[serde(rename = "sessionId")]
+    pub session_id: String,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HistoryFormat {
+    /// Snapshots under `=== snapshot <time> ===` headers, then the commands
+    Plain,
+    /// One JSON object per snapshot or command
+    Jsonl,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DownloadHistoryArgs {
+    #[serde(rename = "sessionId")]
+    pub session_id: String,
+    /// `plain` by default
+    pub format: Option<HistoryFormat>,
+    /// Most bytes per chunk
+    #[serde(rename = "chunkBytes")]
+    pub chunk_bytes: Option<usize>,
+    /// Download to continue; a new one is started without
+    pub token: Option<String>,
+    /// Byte offset to read from, as returned in `next` (default: 0)
+    pub cursor: Option<usize>,
+    /// Return a one-time URL on the management server instead of chunks
+    #[serde(rename = "viaUrl")]
+    pub via_url: Option<bool>,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -50,15 +472,400 @@ pub struct ExecuteCommandArgs {
     #[serde(rename = "sessionId")]
     pub session_id: String,
     pub command: String,
+    /// Keys sent after the command to submit it (default: `["Enter"]`)
+    pub terminator: Option<Vec<String>>,
+    /// Whether to send the terminator at all (default: true)
+    #[serde(rename = "appendTerminator")]
+    pub append_terminator: Option<bool>,
+    /// Mark output lines matching error, warning, stack trace and URL patterns
+    pub annotate: Option<bool>,
+    /// Patterns used for this call on top of the configured ones
+    #[serde(rename = "annotatePatterns")]
+    pub annotate_patterns: Option<Vec<AnnotationPatternConfig>>,
+    /// Add a `summary` of errors, warnings and file positions in the output
+    pub summarize: Option<bool>,
+    /// Wait until the command has finished instead of a fixed second
+    /// (default: true)
+    #[serde(rename = "waitForCompletion")]
+    pub wait_for_completion: Option<bool>,
+    /// How long to wait for the command to finish; by default the
+    /// command class's
+    #[serde(rename = "timeoutMs")]
+    pub completion_timeout_ms: Option<u64>,
+    /// How long to wait before taking the screen when the command's end
+    /// cannot be detected; by default the command class's
+    #[serde(rename = "quietPeriodMs")]
+    pub quiet_period_ms: Option<u64>,
+    /// Run under this command class instead of the one the command matches
+    #[serde(rename = "commandClass")]
+    pub command_class: Option<String>,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetAliasArgs {
+    #[serde(rename = "sessionId")]
+    pub session_id: String,
+    pub name: String,
+    /// What the alias runs; without it the session's alias is removed
+    pub command: Option<String>,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CheckCommandExistsArgs {
+    #[serde(rename = "sessionId")]
+    pub session_id: String,
+    /// Program name looked up with `command -v`
+    pub command: String,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProbeToolsArgs {
+    #[serde(rename = "sessionId")]
+    pub session_id: String,
+    /// Program names to look up; a default set of common tools when unset
+    pub tools: Option<Vec<String>>,
+    /// Probe again instead of answering from what earlier probes found
+    pub refresh: Option<bool>,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InteractivePromptArgs {
+    #[serde(rename = "sessionId")]
+    pub session_id: String,
+    /// Regex matched against the last line of the screen with content
+    #[serde(rename = "promptPattern")]
+    pub prompt_pattern: String,
+    /// Typed followed by Enter once the prompt shows up
+    pub response: String,
+    /// How long to watch for the prompt (default: 20000)
+    #[serde(rename = "timeoutMs")]
+    pub prompt_timeout_ms: Option<u64>,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TailFileArgs {
+    /// Server-local file to follow
+    pub path: String,
+    /// Lines printed to start with (default: 10); ignored when the file
+    /// already has a tail session
+    pub lines: Option<usize>,
+    /// Keep following the file (default: true)
+    pub follow: Option<bool>,
+    /// Regex a line must match to be returned
+    #[serde(rename = "filterPattern")]
+    pub filter_pattern: Option<String>,
+    /// Number of the first line to return, `nextCursor` of the previous
+    /// call (default: 0)
+    pub cursor: Option<u64>,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GroupSnapshotArgs {
+    #[serde(rename = "groupId")]
+    pub group_id: String,
+    /// How each snapshot's text is returned; `plain` by default
+    pub format: Option<SnapshotFormat>,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ParallelExecuteArgs {
+    #[serde(rename = "sessionIds")]
+    pub session_ids: Vec<String>,
+    pub command: String,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct GetResourceReportArgs {
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct GenerateMcpConfigArgs {
+    /// `stdio`, `socket` or `tcp`
+    pub transport: String,
+    #[serde(rename = "socketPath")]
+    pub socket_path: Option<String>,
+    #[serde(rename = "tcpPort")]
+    pub tcp_port: Option<u16>,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ListSessionsArgs {
+    /// 1-based page of sessions, newest first (default: 1)
+    pub page: Option<usize>,
+    /// Sessions per page (default: 20)
+    #[serde(rename = "pageSize")]
+    pub page_size: Option<usize>,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ListTunnelsArgs {
+    /// 1-based page of tunnels, newest first (default: 1)
+    pub page: Option<usize>,
+    /// Tunnels per page (default: 20)
+    #[serde(rename = "pageSize")]
+    pub page_size: Option<usize>,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateTunnelArgs {
+    /// Local port to expose, e.g. a dev server started in a session
+    pub port: u16,
+    /// How long the tunnel gets to come up; 30 by default
+    #[serde(rename = "timeoutSecs")]
+    pub timeout_secs: Option<u64>,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StopTunnelArgs {
+    #[serde(rename = "tunnelId")]
+    pub tunnel_id: String,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReconnectTunnelArgs {
+    #[serde(rename = "sessionId")]
+    pub session_id: String,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResizeArgs {
+    #[serde(rename = "sessionId")]
+    pub session_id: String,
+    pub cols: usize,
+    pub rows: usize,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReservePortArgs {
+    /// How long the port is held for a session to claim it
+    #[serde(rename = "ttlMs")]
+    pub ttl_ms: Option<u64>,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RenewLeaseArgs {
+    #[serde(rename = "sessionId")]
+    pub session_id: String,
+    #[serde(rename = "extendSecs")]
+    pub extend_secs: u64,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AcquireLockArgs {
+    #[serde(rename = "sessionId")]
+    pub session_id: String,
+    pub holder: String,
+    #[serde(rename = "ttlSecs")]
+    pub ttl_secs: Option<u64>,
+    /// Take the lock from another holder; admin only
+    #[serde(rename = "override")]
+    pub override_lock: Option<bool>,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReleaseLockArgs {
+    #[serde(rename = "sessionId")]
+    pub session_id: String,
+    pub holder: String,
+    /// Release another holder's lock; admin only
+    #[serde(rename = "override")]
+    pub override_lock: Option<bool>,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetRetentionArgs {
+    #[serde(rename = "sessionId")]
+    pub session_id: String,
+    #[serde(flatten)]
+    pub retention: RetentionOverrides,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportStateArgs {
+    /// Server-local file the state is written to
+    pub path: String,
+    /// Also write the values of the sessions' `env` variables
+    #[serde(rename = "includeEnv")]
+    pub include_env: Option<bool>,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportStateArgs {
+    /// Server-local state archive written by `ht_export_state`
+    pub path: String,
+    /// Where the exporting host's recordings directory is on this one
+    #[serde(rename = "recordingsRemap")]
+    pub recordings_remap: Option<PathRemap>,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DrainArgs {
+    #[serde(rename = "graceSecs")]
+    pub grace_secs: u64,
+    /// Exit as soon as no sessions are left instead of waiting out the grace
+    /// period
+    #[serde(rename = "exitWhenEmpty")]
+    pub exit_when_empty: Option<bool>,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct CloseSessionArgs {
     #[serde(rename = "sessionId")]
     pub session_id: String,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BulkCloseSessionsArgs {
+    #[serde(rename = "sessionIds")]
+    pub session_ids: Option<Vec<String>>,
+    /// Close every session
+    #[serde(rename = "closeAll")]
+    pub close_all: Option<bool>,
+    /// Only close sessions having all these tags
+    #[serde(rename = "filterTags")]
+    pub filter_tags: Option<HashMap<String, String>>,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GetJobArgs {
+    #[serde(rename = "jobId")]
+    pub job_id: String,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CancelJobArgs {
+    #[serde(rename = "jobId")]
+    pub job_id: String,
+    /// Per-call timeout; overrides `default_tool_timeout_ms`
+    #[serde(rename = "_timeout_ms")]
+    pub timeout_ms: Option<u64>,
 }
 
 // Schema generation functions
+
+/// Schema for the `_timeout_ms` meta-field accepted by every tool
+fn timeout_ms_property() -> Value {
+    json!({
+        "type": "integer",
+        "minimum": 1,
+        "description": "Abort the call after this many milliseconds (default from server config: 30000)"
+    })
+}
+
+/// The `compress` meta-field of the tools in `compression::COMPRESSIBLE_TOOLS`
+fn compress_property() -> Value {
+    json!({
+        "type": "string",
+        "enum": ["gzip", "zstd"],
+        "description": "Return a result over 16 KiB as base64 gzip- or zstd-compressed JSON with its size and CRC-32; decode it per the result's note"
+    })
+}
+/// The `async` meta-field of the tools in `jobs::JOB_TOOLS`
+fn async_property() -> Value {
+    json!({
+        "type": "boolean",
+        "description": "Run the call as a background job: it answers at once with a jobId to poll with ht_get_job for the result, or to stop with ht_cancel_job (default: false)"
+    })
+}
+
+/// The `holder` meta-field of the tools in `interaction_lock::INPUT_TOOLS`
+fn lock_holder_property() -> Value {
+    json!({
+        "type": "string",
+        "minLength": 1,
+        "description": "Holder name given to ht_acquire_lock; needed to send input to or close the session while it is locked"
+    })
+}
+
+/// The `override` meta-field of the input and lock tools
+fn lock_override_property() -> Value {
+    json!({
+        "type": "boolean",
+        "description": "Admin: ignore another holder's lock (needs enable_admin_tools)"
+    })
+}
+
 pub fn create_session_schema() -> Value {
     let default_command = if cfg!(windows) {
         "[\"powershell.exe\"]"
@@ -69,11 +876,21 @@ pub fn create_session_schema() -> Value {
     json!({
         "type": "object",
         "properties": {
+            "_timeout_ms": timeout_ms_property(),
             "command": {
                 "type": "array",
                 "items": {"type": "string"},
                 "description": format!("Command to run in the terminal (default: {})", default_command)
             },
+            "cwd": {
+                "type": "string",
+                "description": "Working directory for the command. Relative paths are resolved against the server's workspace root, which is also the default when one is configured"
+            },
+            "env": {
+                "type": "object",
+                "additionalProperties": {"type": "string"},
+                "description": "Environment variables for the command, e.g. {\"RUST_LOG\": \"debug\"}; they replace the server's variables and session_env entries of the same name"
+            },
             "enableWebServer": {
                 "type": "boolean",
                 "description": "Enable HT web server for live terminal preview (default: false)"
@@ -81,16 +898,154 @@ pub fn create_session_schema() -> Value {
             "enableTunnel": {
                 "type": "boolean",
                 "description": "Enable cloudflared tunnel for public access to web server (default: false)"
+            },
+            "idempotencyKey": {
+                "type": "string",
+                "description": "Client-chosen key; retrying with the same key within 60 seconds returns the original session instead of creating a new one, marked idempotentReplay, even while the original call is still running"
+            },
+            "maxMemoryMb": {
+                "type": "integer",
+                "minimum": 1,
+                "description": "Memory limit for the session's processes in MiB (Linux cgroup v2; requires use_cgroup_memory_limits in the server config)"
+            },
+            "maxConnections": {
+                "type": "integer",
+                "minimum": 1,
+                "description": "Maximum concurrent connections to the web server (default from server config: 64)"
+            },
+            "maxConnectionsPerIp": {
+                "type": "integer",
+                "minimum": 1,
+                "description": "Maximum concurrent web server connections from one IP; tunneled connections are not counted per IP (default from server config: 16)"
+            },
+            "idleTimeoutSecs": {
+                "type": "integer",
+                "minimum": 1,
+                "description": "Close web server connections idle for this many seconds (default from server config: 1800)"
+            },
+            "runAs": {
+                "type": "object",
+                "description": "Run the terminal process as this user (Unix only; the server must run as root unless this is its own user and group). The process gets the user's HOME, USER, LOGNAME and SHELL, a default PATH and the session's env, not the server's environment",
+                "properties": {
+                    "user": {"type": "string", "description": "User name"},
+                    "group": {"type": "string", "description": "Group name (default: the user's primary group)"}
+                },
+                "required": ["user"],
+                "additionalProperties": false
+            },
+            "labelColor": {
+                "type": "string",
+                "pattern": "^#([0-9a-fA-F]{3}|[0-9a-fA-F]{6})$",
+                "description": "Hex color for dashboards and session lists, e.g. \"#ff6600\""
+            },
+            "labelIcon": {
+                "type": "string",
+                "maxLength": 32,
+                "description": "Short icon for dashboards and session lists, e.g. an emoji or a name like \"rust\""
+            },
+            "tempDir": {
+                "type": "boolean",
+                "description": "Create a temporary directory for the session, exported as $HT_SESSION_TMP and deleted when the session closes (default: false)"
+            },
+            "snapshotIntervalMs": {
+                "type": "integer",
+                "minimum": 100,
+                "description": "Record a terminal snapshot this often so ht_get_snapshot_history can replay recent screens (default: off)"
+            },
+            "leaseSecs": {
+                "type": "integer",
+                "minimum": 1,
+                "description": "Close the session after this many seconds unless renewed with ht_renew_lease, however busy it is (default: no lease)"
+            },
+            "autoDetectPrompt": {
+                "type": "boolean",
+                "description": "Set the shell's PS1 to a unique marker and wait for it: creation returns once the shell is ready, and ht_execute_command waits for the prompt to come back instead of a fixed second. For POSIX shells (default: false)"
+            },
+            "traceMode": {
+                "type": "boolean",
+                "description": "Log every chunk of PTY input and output, escaped, at trace level for debugging; refused unless the server config sets allow_trace_mode (default: false)"
+            },
+            "retention": retention_properties(),
+            "colorProfile": {
+                "type": "string",
+                "enum": ["basic", "256color", "truecolor"],
+                "description": "Color support announced to programs in the session: basic sets TERM=xterm, 256color TERM=xterm-256color, truecolor TERM=xterm-256color and COLORTERM=truecolor (default: 256color)"
+            },
+            "cpuAffinity": {
+                "type": "array",
+                "items": {"type": "integer", "minimum": 0},
+                "minItems": 1,
+                "description": "Pin the session's process, and what it starts, to these CPU cores (numbered from 0). Linux only; elsewhere ignored with a warning (default: any core)"
+            },
+            "preferredPort": {
+                "type": "integer",
+                "minimum": 1,
+                "maximum": 65535,
+                "description": "Web server port, e.g. one reserved with ht_reserve_port, whose listener is used as is; needs enableWebServer (default: the first free port from 3618)"
+            },
+            "inputProfile": input_profile_property("How ht_send_keys paces input by default"),
+            "groupId": {
+                "type": "string",
+                "minLength": 1,
+                "description": "Group to put the session in, e.g. the services of one app, for ht_session_group_snapshot (default: no group)"
+            },
+            "tags": {
+                "type": "object",
+                "additionalProperties": {"type": "string"},
+                "description": "Labels such as {\"project\": \"web\"}, shown by ht_list_sessions and matched by ht_bulk_close_sessions filterTags"
+            },
+            "cols": {
+                "type": "integer",
+                "minimum": 1,
+                "maximum": MAX_TERMINAL_SIZE,
+                "description": "Terminal width in columns, e.g. 400 for wide logs; at most the server's max_cols, 500 by default (default: 120)"
+            },
+            "rows": {
+                "type": "integer",
+                "minimum": 1,
+                "maximum": MAX_TERMINAL_SIZE,
+                "description": "Terminal height in rows; at most the server's max_rows, 200 by default (default: 40)"
+            },
+            "outputWebhookUrl": {
+                "type": "string",
+                "description": "http(s) URL the session's output lines are POSTed to as {\"sessionId\", \"lines\", \"timestamp\"} (milliseconds), once per interval with output; a failed post is retried once"
+            },
+            "outputWebhookIntervalMs": {
+                "type": "integer",
+                "minimum": 100,
+                "maximum": 60000,
+                "description": "How often new output is posted to outputWebhookUrl (default: 1000)"
+            },
+            "readOnly": {
+                "type": "boolean",
+                "description": "Refuse all input, from input tools and from web viewers alike (default: false)"
+            },
+            "tunnelName": {
+                "type": "string",
+                "pattern": "^[a-z0-9]([a-z0-9-]{0,61}[a-z0-9])?$",
+                "description": "Name giving the tunnel its hostname when the server config sets [named_tunnel]; creating a session with the same name later gets the same hostname. Needs enableTunnel (default: the session id)"
             }
         },
         "additionalProperties": false
     })
 }
 
+/// `inputProfile` of create_session and send_keys
+fn input_profile_property(description: &str) -> Value {
+    json!({
+        "type": "string",
+        "enum": ["instant", "fast-human", "slow-human"],
+        "description": format!("{}. instant sends everything at once; fast-human and slow-human type text a character at a time with jittered delays and occasional pauses between words (default: instant)", description)
+    })
+}
+
 pub fn send_keys_schema() -> Value {
     json!({
         "type": "object",
         "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "holder": lock_holder_property(),
+            "override": lock_override_property(),
             "sessionId": {
                 "type": "string",
                 "description": "HT session ID"
@@ -98,7 +1053,13 @@ pub fn send_keys_schema() -> Value {
             "keys": {
                 "type": "array",
                 "items": {"type": "string"},
-                "description": "Array of keys to send (can include text and special keys like \"Enter\", \"Down\", etc.)"
+                "description": "Array of keys to send (can include text and special keys like \"Enter\", \"Down\", etc., and chords such as \"Ctrl-A\", \"Alt-F\" or \"Shift-F5\")"
+            },
+            "inputProfile": input_profile_property("How to pace this call's input (default: the session's inputProfile)"),
+            "seed": {
+                "type": "integer",
+                "minimum": 0,
+                "description": "Seed of the delays of a paced profile; the same seed and keys are paced the same way (default: random, returned in the result)"
             }
         },
         "required": ["sessionId", "keys"],
@@ -106,42 +1067,1069 @@ pub fn send_keys_schema() -> Value {
     })
 }
 
-pub fn take_snapshot_schema() -> Value {
+pub fn type_file_schema() -> Value {
     json!({
         "type": "object",
         "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "holder": lock_holder_property(),
+            "override": lock_override_property(),
             "sessionId": {
                 "type": "string",
                 "description": "HT session ID"
-            }
-        },
-        "required": ["sessionId"],
-        "additionalProperties": false
-    })
+            },
+            "path": {
+                "type": "string",
+                "description": "File on the server to type; must be under the config's type_file_paths. Relative paths are taken from the workspace root."
+            },
+            "chunkBytes": {
+                "type": "integer",
+                "minimum": 1,
+                "description": "Bytes typed at a time (default: 256)"
+            },
+            "chunkDelayMs": {
+                "type": "integer",
+                "minimum": 0,
+                "description": "Pause between chunks, for programs that read slowly (default: 10)"
+            },
+            "bracketedPaste": {
+                "type": "boolean",
+                "description": "Send the text as one bracketed paste, so editors and REPLs that support it don't auto-indent or run it line by line (default: false)"
+            }
+        },
+        "required": ["sessionId", "path"],
+        "additionalProperties": false
+    })
+}
+
+pub fn close_stdin_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "holder": lock_holder_property(),
+            "override": lock_override_property(),
+            "sessionId": {
+                "type": "string",
+                "description": "HT session ID"
+            }
+        },
+        "required": ["sessionId"],
+        "additionalProperties": false
+    })
+}
+
+pub fn pipe_stdin_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "holder": lock_holder_property(),
+            "override": lock_override_property(),
+            "sessionId": {
+                "type": "string",
+                "description": "HT session ID"
+            },
+            "data": {
+                "type": "string",
+                "description": "Input for the command, written as is: newlines end lines and nothing is read as a key name"
+            },
+            "closeAfter": {
+                "type": "boolean",
+                "description": "Send EOF (Ctrl-D) after the data so the command stops reading; sent twice when the data does not end with a newline (default: false)"
+            }
+        },
+        "required": ["sessionId", "data"],
+        "additionalProperties": false
+    })
+}
+
+pub fn baseline_snapshot_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "sessionId": {
+                "type": "string",
+                "description": "HT session ID"
+            },
+            "baselineId": {
+                "type": "string",
+                "description": "Name for the baseline (letters, digits, '-', '_', '.'); taking one with the same name replaces it"
+            }
+        },
+        "required": ["sessionId", "baselineId"],
+        "additionalProperties": false
+    })
+}
+
+pub fn compare_to_baseline_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "sessionId": {
+                "type": "string",
+                "description": "HT session ID"
+            },
+            "baselineId": {
+                "type": "string",
+                "description": "Baseline taken earlier with ht_take_baseline_snapshot"
+            }
+        },
+        "required": ["sessionId", "baselineId"],
+        "additionalProperties": false
+    })
+}
+
+pub fn classify_screen_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "sessionId": {
+                "type": "string",
+                "description": "HT session ID"
+            }
+        },
+        "required": ["sessionId"],
+        "additionalProperties": false
+    })
+}
+
+fn annotate_property() -> Value {
+    json!({
+        "type": "boolean",
+        "description": "Add annotations [{line, endLine?, kind, match}] for lines matching error, warning, stack trace and URL patterns (default: false, or true when annotatePatterns is given)"
+    })
+}
+
+fn annotate_patterns_property() -> Value {
+    json!({
+        "type": "array",
+        "items": {
+            "type": "object",
+            "properties": {
+                "kind": {"type": "string"},
+                "pattern": {"type": "string", "description": "Regular expression matched against each line"},
+                "group": {"type": "boolean", "description": "Merge matches on consecutive lines into one annotation"}
+            },
+            "required": ["kind", "pattern"],
+            "additionalProperties": false
+        },
+        "description": "Extra annotation patterns for this call"
+    })
+}
+
+pub fn take_snapshot_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "compress": compress_property(),
+            "sessionId": {
+                "type": "string",
+                "description": "HT session ID"
+            },
+            "format": {
+                "type": "string",
+                "enum": ["plain", "ai-compressed", "ansi", "structured"],
+                "description": "plain (default) returns the screen as is; ai-compressed trims lines, collapses blank lines and shortens long runs of a repeated character to save tokens; ansi re-encodes the screen with SGR escape sequences for its colors and attributes, each line ending with a reset; structured returns the plain screen plus styledLines: [{row, text, runs: [{col, text, fg, bg, bold, faint, italic, underline, blink, inverse, strikethrough}]}] for the non-blank rows (0-based), listing only styled runs and set attributes, with palette colors as numbers and RGB ones as \"#rrggbb\". wordWrap and annotate need plain or ai-compressed; maxTokens does not apply to structured"
+            },
+            "trimTrailingBlankLines": {
+                "type": "boolean",
+                "description": "Drop the blank lines below the last line with content, e.g. the unused rows of a tall terminal (default: false)"
+            },
+            "wordWrap": {
+                "type": "integer",
+                "minimum": 1,
+                "description": "Wrap lines longer than this many characters at word boundaries, for very wide terminals; shorter lines are left alone"
+            },
+            "probeOnly": {
+                "type": "boolean",
+                "description": "Return only the snapshot's bytes, lineCount, estimatedTokens (bytes/4), whether maxTokens would truncate it and the screenVersion, without the text, to decide whether to fetch it (default: false)"
+            },
+            "maxTokens": {
+                "type": "integer",
+                "minimum": 1,
+                "description": "Cut the snapshot to about this many tokens (bytes/4), dropping lines from the top; the result says truncated and how many lines were omitted"
+            },
+            "includeProvenance": include_provenance_property(),
+            "annotate": annotate_property(),
+            "annotatePatterns": annotate_patterns_property()
+        },
+        "required": ["sessionId"],
+        "additionalProperties": false
+    })
+}
+
+fn include_provenance_property() -> Value {
+    json!({
+        "type": "boolean",
+        "description": "Add a provenance block: sessionId, host, command, timestamp, screenVersion and hash, the first 16 hex digits of the SHA-256 of the plain text returned without trailing whitespace or trailing blank lines, plus the transforms (wordWrap, ai-compressed, maxTokens) applied before hashing. Check a shared snapshot later with ht_verify_snapshot (default: false)"
+    })
+}
+
+pub fn verify_snapshot_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "hash": {
+                "type": "string",
+                "description": "The hash of a provenance block, with or without its sha256: prefix"
+            },
+            "text": {
+                "type": "string",
+                "description": "The snapshot text as shared; it must hash to hash, or it was changed"
+            },
+            "sessionId": {
+                "type": "string",
+                "description": "Only look at this session's snapshots"
+            }
+        },
+        "required": ["hash"],
+        "additionalProperties": false
+    })
+}
+
+pub fn take_snapshot_as_png_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "sessionId": {
+                "type": "string",
+                "description": "HT session ID"
+            },
+            "scale": {
+                "type": "number",
+                "minimum": 0.5,
+                "maximum": 4,
+                "description": "Image size relative to 8x13 pixels per cell (default: 1); an 80x24 terminal is 640x312 pixels at scale 1"
+            },
+            "includeProvenance": include_provenance_property()
+        },
+        "required": ["sessionId"],
+        "additionalProperties": false
+    })
+}
+
+pub fn assert_screen_schema() -> Value {
+    let text = json!({"type": "string"});
+    let position = |a: &str, b: &str| {
+        json!({
+            "type": "object",
+            "properties": {
+                a: {"type": "integer", "minimum": 1},
+                b: {"type": "integer", "minimum": 1}
+            },
+            "required": [a, b],
+            "additionalProperties": false
+        })
+    };
+    let check = |name: &str, value: Value| {
+        json!({
+            "type": "object",
+            "properties": {name: value},
+            "required": [name],
+            "additionalProperties": false
+        })
+    };
+
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "sessionId": {
+                "type": "string",
+                "description": "HT session ID"
+            },
+            "assertions": {
+                "type": "array",
+                "minItems": 1,
+                "items": {
+                    "oneOf": [
+                        check("contains", text.clone()),
+                        check("notContains", text.clone()),
+                        check("regex", text.clone()),
+                        check("lineEquals", json!({
+                            "type": "object",
+                            "properties": {
+                                "line": {"type": "integer", "minimum": 1},
+                                "text": text
+                            },
+                            "required": ["line", "text"],
+                            "additionalProperties": false
+                        })),
+                        check("cursorAt", position("row", "col")),
+                        check("sizeIs", position("cols", "rows"))
+                    ]
+                },
+                "description": "Checks evaluated against one capture of the screen, e.g. {\"contains\": \"ready\"} or {\"lineEquals\": {\"line\": 1, \"text\": \"$ ls\"}}. Lines, rows and columns are 1-based; trailing spaces on a line are ignored."
+            },
+            "attachSnapshotOnFailure": {
+                "type": "boolean",
+                "description": "Include the screen text in the result when an assertion fails (default: true)"
+            }
+        },
+        "required": ["sessionId", "assertions"],
+        "additionalProperties": false
+    })
+}
+
+pub fn get_session_metrics_history_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "sessionId": {
+                "type": "string",
+                "description": "HT session ID"
+            }
+        },
+        "required": ["sessionId"],
+        "additionalProperties": false
+    })
+}
+
+pub fn export_dockerfile_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "sessionId": {
+                "type": "string",
+                "description": "HT session ID"
+            }
+        },
+        "required": ["sessionId"],
+        "additionalProperties": false
+    })
+}
+
+pub fn get_snapshot_history_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "compress": compress_property(),
+            "sessionId": {
+                "type": "string",
+                "description": "HT session ID"
+            },
+            "limit": {
+                "type": "integer",
+                "minimum": 1,
+                "description": "Maximum number of snapshots to return, newest kept (default: 10)"
+            },
+            "sinceMs": {
+                "type": "integer",
+                "minimum": 0,
+                "description": "Only return snapshots taken within this many milliseconds"
+            },
+            "probeOnly": {
+                "type": "boolean",
+                "description": "Return only the number of snapshots, their total bytes, lineCount and estimatedTokens (bytes/4), whether maxTokens would truncate them and the screenVersion, without the text (default: false)"
+            },
+            "maxTokens": {
+                "type": "integer",
+                "minimum": 1,
+                "description": "Keep the newest snapshots within about this many tokens (bytes/4), cutting the newest one if it alone is over; the result says truncated and how many snapshots were omitted"
+            }
+        },
+        "required": ["sessionId"],
+        "additionalProperties": false
+    })
+}
+
+pub fn get_session_info_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "sessionId": {
+                "type": "string",
+                "description": "HT session ID"
+            }
+        },
+        "required": ["sessionId"],
+        "additionalProperties": false
+    })
+}
+
+pub fn get_connected_clients_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "sessionId": {
+                "type": "string",
+                "description": "HT session ID"
+            }
+        },
+        "required": ["sessionId"],
+        "additionalProperties": false
+    })
+}
+
+pub fn download_history_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "async": async_property(),
+            "compress": compress_property(),
+            "sessionId": {
+                "type": "string",
+                "description": "HT session ID"
+            },
+            "format": {
+                "type": "string",
+                "enum": ["plain", "jsonl"],
+                "description": "plain: snapshots under headers, then the commands; jsonl: one JSON object per snapshot or command (default: plain)"
+            },
+            "chunkBytes": {
+                "type": "integer",
+                "minimum": history_download::MIN_CHUNK_BYTES,
+                "maximum": history_download::MAX_CHUNK_BYTES,
+                "description": "Most bytes per chunk; chunks end on character boundaries (default: 65536)"
+            },
+            "token": {
+                "type": "string",
+                "description": "Token of a download to continue; omit to start a new one, which captures the history as it is now"
+            },
+            "cursor": {
+                "type": "integer",
+                "minimum": 0,
+                "description": "Byte offset to read from: the previous chunk's next, or the same cursor again to retry a chunk that was lost (default: 0)"
+            },
+            "viaUrl": {
+                "type": "boolean",
+                "description": "Instead of chunks, return a URL on the management server that serves the whole file once; needs management_address in the config (default: false)"
+            }
+        },
+        "required": ["sessionId"],
+        "additionalProperties": false
+    })
+}
+
+pub fn execute_command_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "async": async_property(),
+            "holder": lock_holder_property(),
+            "override": lock_override_property(),
+            "sessionId": {
+                "type": "string",
+                "description": "HT session ID"
+            },
+            "command": {
+                "type": "string",
+                "description": "Command to execute in the terminal. A command starting with an alias (ht_set_alias or the config's aliases) runs the alias's command with the rest appended; the result then has alias: {name, invoked}"
+            },
+            "terminator": {
+                "type": "array",
+                "items": {"type": "string"},
+                "description": "Keys sent after the command to submit it, e.g. [\"Enter\", \"Enter\"] for a Python block or [\"C-d\"] (default: [\"Enter\"])"
+            },
+            "appendTerminator": {
+                "type": "boolean",
+                "description": "Send the terminator after the command; false only types the command without running it (default: true)"
+            },
+            "annotate": annotate_property(),
+            "annotatePatterns": annotate_patterns_property(),
+            "summarize": {
+                "type": "boolean",
+                "description": "Add a summary for triage: counts of error and warning lines, the first and last error, files at error positions (path:line:col), output size and duration (default: false)"
+            },
+            "waitForCompletion": {
+                "type": "boolean",
+                "description": "Wait until the command has finished and report its exitCode, by echoing a marker after it; needs a POSIX shell at the prompt. false waits the quiet period, for programs that are not shells (default: true)"
+            },
+            "timeoutMs": {
+                "type": "integer",
+                "minimum": 1,
+                "description": "How long to wait for the command to finish before returning the screen with timedOut (default: the command class's, 20000 for most commands and 600000 for builds). Longer waits also extend the call's timeout"
+            },
+            "quietPeriodMs": {
+                "type": "integer",
+                "minimum": 0,
+                "description": "How long to wait before taking the screen when the command's end cannot be detected (default: the command class's, 1000 for most commands)"
+            },
+            "commandClass": {
+                "type": "string",
+                "description": "Command class whose defaults apply, instead of the one picked from the command: default, build, interactive or one from the config. Commands in the interactive class, such as vim and less, are refused; pass \"default\" to run them anyway"
+            }
+        },
+        "required": ["sessionId", "command"],
+        "additionalProperties": false
+    })
+}
+
+pub fn create_session_with_tunnel_schema() -> Value {
+    let default_command = if cfg!(windows) {
+        "[\"powershell.exe\"]"
+    } else {
+        "[\"bash\"]"
+    };
+
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "command": {
+                "type": "array",
+                "items": {"type": "string"},
+                "description": format!("Command to run in the terminal (default: {})", default_command)
+            },
+            "tunnelProvider": {
+                "type": "string",
+                "enum": TUNNEL_PROVIDERS,
+                "description": "Tunnel provider (default: cloudflare). bore (unencrypted) and localhost.run only work when the server config enables them in tunnel_providers; auto only tries cloudflare"
+            },
+            "tunnelTimeoutMs": {
+                "type": "integer",
+                "minimum": 1,
+                "description": "Fail, closing the session, if the tunnel is not up within this many milliseconds (default: 30000)"
+            }
+        },
+        "additionalProperties": false
+    })
+}
+
+pub fn group_snapshot_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "compress": compress_property(),
+            "groupId": {
+                "type": "string",
+                "description": "Group given as groupId when the sessions were created"
+            },
+            "format": {
+                "type": "string",
+                "enum": ["plain", "ai-compressed", "ansi", "structured"],
+                "description": "plain (default) returns each screen as is; ai-compressed trims lines, collapses blank lines and shortens long runs of a repeated character; ansi and structured style each screen as ht_take_snapshot does, structured adding styledLines to each entry"
+            }
+        },
+        "required": ["groupId"],
+        "additionalProperties": false
+    })
+}
+
+pub fn parallel_execute_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "holder": lock_holder_property(),
+            "override": lock_override_property(),
+            "sessionIds": {
+                "type": "array",
+                "items": {"type": "string"},
+                "minItems": 1,
+                "uniqueItems": true,
+                "description": "HT session IDs to run the command in"
+            },
+            "command": {
+                "type": "string",
+                "description": "Command to execute in every session"
+            }
+        },
+        "required": ["sessionIds", "command"],
+        "additionalProperties": false
+    })
+}
+
+pub fn set_alias_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "sessionId": {
+                "type": "string",
+                "description": "HT session ID the alias applies to"
+            },
+            "name": {
+                "type": "string",
+                "pattern": "^[A-Za-z0-9_.][A-Za-z0-9_.-]*$",
+                "description": "Alias name, e.g. \"deploy-staging\"; letters, digits, '_', '.' and '-' only"
+            },
+            "command": {
+                "type": "string",
+                "description": "Command the alias runs; ht_execute_command appends any words after the alias name. Omit to remove the session's alias, uncovering a configured one of the same name"
+            }
+        },
+        "required": ["sessionId", "name"],
+        "additionalProperties": false
+    })
+}
+
+pub fn check_command_exists_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "holder": lock_holder_property(),
+            "override": lock_override_property(),
+            "sessionId": {
+                "type": "string",
+                "description": "HT session ID; the session must be at a POSIX shell prompt"
+            },
+            "command": {
+                "type": "string",
+                "pattern": "^[A-Za-z0-9_.][A-Za-z0-9_.-]*$",
+                "description": "Program name to look up, e.g. \"jq\"; letters, digits, '_', '.' and '-' only"
+            }
+        },
+        "required": ["sessionId", "command"],
+        "additionalProperties": false
+    })
+}
+
+pub fn probe_tools_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "holder": lock_holder_property(),
+            "override": lock_override_property(),
+            "sessionId": {
+                "type": "string",
+                "description": "HT session ID; the session must be at a bash, zsh, sh, fish or PowerShell prompt"
+            },
+            "tools": {
+                "type": "array",
+                "items": {
+                    "type": "string",
+                    "pattern": "^[A-Za-z0-9_.][A-Za-z0-9_.-]*$"
+                },
+                "minItems": 1,
+                "maxItems": 64,
+                "description": "Program names to look up, e.g. [\"jq\", \"rg\", \"sed\"]; defaults to git, jq, rg, python3, node, npm, cargo, make, curl, sed, grep and awk"
+            },
+            "refresh": {
+                "type": "boolean",
+                "description": "Probe again even for tools an earlier probe of the session found (default: false)"
+            }
+        },
+        "required": ["sessionId"],
+        "additionalProperties": false
+    })
 }
 
-pub fn execute_command_schema() -> Value {
+pub fn interactive_prompt_schema() -> Value {
     json!({
         "type": "object",
         "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "holder": lock_holder_property(),
+            "override": lock_override_property(),
             "sessionId": {
                 "type": "string",
                 "description": "HT session ID"
             },
-            "command": {
+            "promptPattern": {
                 "type": "string",
-                "description": "Command to execute in the terminal"
+                "description": "Regex for the prompt, matched against the last line of the screen with content, e.g. \"\\\\[Y/n\\\\]\" or \"(?i)password.*:\""
+            },
+            "response": {
+                "type": "string",
+                "description": "Text typed followed by Enter once the prompt shows up; not logged or returned"
+            },
+            "timeoutMs": {
+                "type": "integer",
+                "minimum": 1,
+                "description": "How long to watch for the prompt before returning with promptDetected false (default: 20000). Longer waits also extend the call's timeout"
             }
         },
-        "required": ["sessionId", "command"],
+        "required": ["sessionId", "promptPattern", "response"],
+        "additionalProperties": false
+    })
+}
+
+pub fn tail_file_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "path": {
+                "type": "string",
+                "description": "Server-local file to follow; calls naming the same file share one session"
+            },
+            "lines": {
+                "type": "integer",
+                "minimum": 1,
+                "maximum": 10000,
+                "description": "Lines of the file printed to start with (default: 10); ignored when reusing a session"
+            },
+            "follow": {
+                "type": "boolean",
+                "description": "Keep printing lines appended to the file (default: true); ignored when reusing a session"
+            },
+            "filterPattern": {
+                "type": "string",
+                "description": "Return only lines matching this regex"
+            },
+            "cursor": {
+                "type": "integer",
+                "minimum": 0,
+                "description": "Line number to continue from: nextCursor of the previous call (default: 0, the oldest line kept)"
+            }
+        },
+        "required": ["path"],
         "additionalProperties": false
     })
 }
 
+fn page_properties() -> (Value, Value) {
+    (
+        json!({
+            "type": "integer",
+            "minimum": 1,
+            "description": "1-based page, newest first (default: 1)"
+        }),
+        json!({
+            "type": "integer",
+            "minimum": 1,
+            "description": "Entries per page (default: 20)"
+        }),
+    )
+}
+
 pub fn list_sessions_schema() -> Value {
+    let (page, page_size) = page_properties();
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "page": page,
+            "pageSize": page_size
+        },
+        "additionalProperties": false
+    })
+}
+
+pub fn resource_report_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property()
+        },
+        "additionalProperties": false
+    })
+}
+
+pub fn generate_mcp_config_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "transport": {
+                "type": "string",
+                "enum": ["stdio", "socket", "tcp"],
+                "description": "How the client talks to the server; this build serves stdio only"
+            },
+            "socketPath": {
+                "type": "string",
+                "description": "Unix socket path, for the socket transport"
+            },
+            "tcpPort": {
+                "type": "integer",
+                "minimum": 1,
+                "maximum": 65535,
+                "description": "Port, for the tcp transport"
+            }
+        },
+        "required": ["transport"],
+        "additionalProperties": false
+    })
+}
+
+pub fn list_tunnels_schema() -> Value {
+    let (page, page_size) = page_properties();
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "page": page,
+            "pageSize": page_size
+        },
+        "additionalProperties": false
+    })
+}
+
+pub fn create_tunnel_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "port": {
+                "type": "integer",
+                "minimum": 1,
+                "maximum": 65535,
+                "description": "Local port to expose publicly, such as a dev server started in a session; any port, not only HT web servers"
+            },
+            "timeoutSecs": {
+                "type": "integer",
+                "minimum": 1,
+                "maximum": MAX_TUNNEL_TIMEOUT_SECS,
+                "description": "Fail if the tunnel is not up within this many seconds (default: 30)"
+            }
+        },
+        "required": ["port"],
+        "additionalProperties": false
+    })
+}
+
+pub fn stop_tunnel_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "tunnelId": {
+                "type": "string",
+                "description": "Tunnel to stop, as reported by ht_create_tunnel or ht_list_tunnels"
+            }
+        },
+        "required": ["tunnelId"],
+        "additionalProperties": false
+    })
+}
+
+pub fn reconnect_tunnel_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "sessionId": {
+                "type": "string",
+                "description": "HT session ID whose tunnel to relaunch"
+            }
+        },
+        "required": ["sessionId"],
+        "additionalProperties": false
+    })
+}
+
+pub fn resize_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "holder": lock_holder_property(),
+            "override": lock_override_property(),
+            "sessionId": {
+                "type": "string",
+                "description": "HT session ID"
+            },
+            "cols": {
+                "type": "integer",
+                "minimum": 1,
+                "maximum": MAX_TERMINAL_SIZE,
+                "description": "New width in columns; at most the server's max_cols, 500 by default"
+            },
+            "rows": {
+                "type": "integer",
+                "minimum": 1,
+                "maximum": MAX_TERMINAL_SIZE,
+                "description": "New height in rows; at most the server's max_rows, 200 by default"
+            }
+        },
+        "required": ["sessionId", "cols", "rows"],
+        "additionalProperties": false
+    })
+}
+
+pub fn reserve_port_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "ttlMs": {
+                "type": "integer",
+                "minimum": 1,
+                "maximum": port_reservation::MAX_RESERVATION_TTL.as_millis() as u64,
+                "description": "Milliseconds the port stays reserved for ht_create_session with preferredPort (default: 30000)"
+            }
+        },
+        "additionalProperties": false
+    })
+}
+
+pub fn renew_lease_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "sessionId": {
+                "type": "string",
+                "description": "HT session ID whose lease to renew"
+            },
+            "extendSecs": {
+                "type": "integer",
+                "minimum": 1,
+                "description": "Seconds to add to the lease"
+            }
+        },
+        "required": ["sessionId", "extendSecs"],
+        "additionalProperties": false
+    })
+}
+
+pub fn acquire_lock_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "sessionId": {
+                "type": "string",
+                "description": "HT session ID to lock"
+            },
+            "holder": {
+                "type": "string",
+                "minLength": 1,
+                "description": "Name of the agent taking the lock; pass it as holder to input tools while the lock is held"
+            },
+            "ttlSecs": {
+                "type": "integer",
+                "minimum": 1,
+                "maximum": 86400,
+                "description": "Seconds until the lock frees itself (default: 300); acquiring again as the holder restarts it"
+            },
+            "override": lock_override_property()
+        },
+        "required": ["sessionId", "holder"],
+        "additionalProperties": false
+    })
+}
+
+pub fn release_lock_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "sessionId": {
+                "type": "string",
+                "description": "HT session ID to unlock"
+            },
+            "holder": {
+                "type": "string",
+                "minLength": 1,
+                "description": "Name the lock was acquired with"
+            },
+            "override": lock_override_property()
+        },
+        "required": ["sessionId", "holder"],
+        "additionalProperties": false
+    })
+}
+
+/// Per-session retention limits, shared by `ht_create_session` and
+/// `ht_set_retention`
+fn retention_limit_properties() -> Value {
+    json!({
+        "snapshotHistory": {
+            "type": "integer",
+            "minimum": 1,
+            "description": "Periodic snapshots kept (see snapshotIntervalMs)"
+        },
+        "commandHistory": {
+            "type": "integer",
+            "minimum": 0,
+            "description": "Commands run through ht_execute_command kept in the history"
+        }
+    })
+}
+
+fn retention_properties() -> Value {
+    json!({
+        "type": "object",
+        "properties": retention_limit_properties(),
+        "additionalProperties": false,
+        "description": "How much history this session keeps; each limit defaults to, and may not exceed, the server's [retention] config"
+    })
+}
+
+pub fn set_retention_schema() -> Value {
+    let mut properties = retention_limit_properties();
+    properties["_timeout_ms"] = timeout_ms_property();
+    properties["sessionId"] = json!({
+        "type": "string",
+        "description": "HT session ID"
+    });
+    json!({
+        "type": "object",
+        "properties": properties,
+        "required": ["sessionId"],
+        "additionalProperties": false
+    })
+}
+
+pub fn drain_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "graceSecs": {
+                "type": "integer",
+                "minimum": 0,
+                "description": "Seconds existing sessions may keep running before they are closed and the server exits"
+            },
+            "exitWhenEmpty": {
+                "type": "boolean",
+                "description": "Exit as soon as the last session closes instead of at the end of the grace period (default: false)"
+            }
+        },
+        "required": ["graceSecs"],
+        "additionalProperties": false
+    })
+}
+
+pub fn export_state_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "path": {
+                "type": "string",
+                "description": "File on the server to write the .tar.gz state archive to"
+            },
+            "includeEnv": {
+                "type": "boolean",
+                "description": "Also write the values of the sessions' env variables, which may hold secrets; without it only their names are written and imported sessions start without them (default: false)"
+            }
+        },
+        "required": ["path"],
+        "additionalProperties": false
+    })
+}
+
+pub fn import_state_schema() -> Value {
     json!({
         "type": "object",
-        "properties": {},
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "path": {
+                "type": "string",
+                "description": "State archive on the server, written by ht_export_state"
+            },
+            "recordingsRemap": {
+                "type": "object",
+                "properties": {
+                    "from": {"type": "string", "description": "Recordings directory on the exporting host"},
+                    "to": {"type": "string", "description": "The same directory on this host"}
+                },
+                "required": ["from", "to"],
+                "additionalProperties": false,
+                "description": "Rewrite recording paths under `from` to `to`; recording files in the archive are restored under `to` where missing"
+            }
+        },
+        "required": ["path"],
         "additionalProperties": false
     })
 }
@@ -150,6 +2138,9 @@ pub fn close_session_schema() -> Value {
     json!({
         "type": "object",
         "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "holder": lock_holder_property(),
+            "override": lock_override_property(),
             "sessionId": {
                 "type": "string",
                 "description": "HT session ID to close"
@@ -159,3 +2150,72 @@ pub fn close_session_schema() -> Value {
         "additionalProperties": false
     })
 }
+
+pub fn bulk_close_sessions_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "holder": lock_holder_property(),
+            "override": lock_override_property(),
+            "sessionIds": {
+                "type": "array",
+                "items": {"type": "string"},
+                "description": "HT session IDs to close"
+            },
+            "closeAll": {
+                "type": "boolean",
+                "description": "Close every session instead of the listed ones"
+            },
+            "filterTags": {
+                "type": "object",
+                "additionalProperties": {"type": "string"},
+                "minProperties": 1,
+                "description": "Only close sessions created with all these tags; on its own, closes every session having them"
+            }
+        },
+        "additionalProperties": false
+    })
+}
+
+pub fn stats_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property()
+        },
+        "additionalProperties": false
+    })
+}
+
+pub fn get_job_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "jobId": {
+                "type": "string",
+                "description": "Job ID returned by a call made with async"
+            }
+        },
+        "required": ["jobId"],
+        "additionalProperties": false
+    })
+}
+
+pub fn cancel_job_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "_timeout_ms": timeout_ms_property(),
+            "holder": lock_holder_property(),
+            "override": lock_override_property(),
+            "jobId": {
+                "type": "string",
+                "description": "Job ID of the call to stop"
+            }
+        },
+        "required": ["jobId"],
+        "additionalProperties": false
+    })
+}
@@ -0,0 +1,154 @@
+//! OpenTelemetry export of tool call and session spans.
+//!
+//! Spans are always created at TRACE level under `SPAN_TARGET`, which the
+//! regular log output filters out, so they cost next to nothing unless the
+//! `otel` feature is enabled and an OTLP endpoint is configured.
+
+use serde::{Deserialize, Serialize};
+use tracing::Span;
+
+/// Target of the spans exported to OpenTelemetry
+pub const SPAN_TARGET: &str = "ht_mcp::otel";
+
+/// Standard OTLP environment variables, used when the config leaves the
+/// corresponding setting unset
+pub const OTLP_ENDPOINT_ENV_VAR: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+pub const SERVICE_NAME_ENV_VAR: &str = "OTEL_SERVICE_NAME";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    /// Base URL of an OTLP/HTTP collector, e.g. `http://localhost:4318`.
+    /// Export is disabled when neither this nor `$OTEL_EXPORTER_OTLP_ENDPOINT`
+    /// is set.
+    pub otlp_endpoint: Option<String>,
+    /// Defaults to `$OTEL_SERVICE_NAME`, then `ht-mcp`
+    pub service_name: Option<String>,
+    /// Fraction of traces to sample, from 0.0 to 1.0
+    pub sampling_ratio: f64,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        Self {
+            otlp_endpoint: None,
+            service_name: None,
+            sampling_ratio: 1.0,
+        }
+    }
+}
+
+impl TelemetryConfig {
+    /// The collector endpoint from the config or the environment
+    pub fn endpoint(&self) -> Option<String> {
+        self.otlp_endpoint
+            .clone()
+            .or_else(|| std::env::var(OTLP_ENDPOINT_ENV_VAR).ok())
+            .filter(|endpoint| !endpoint.is_empty())
+    }
+
+    pub fn service_name(&self) -> String {
+        self.service_name
+            .clone()
+            .or_else(|| std::env::var(SERVICE_NAME_ENV_VAR).ok())
+            .unwrap_or_else(|| "ht-mcp".to_string())
+    }
+}
+
+/// Span covering one tool call. `session.id`, `error.code` and `duration_ms`
+/// are recorded once known.
+pub fn tool_call_span(tool_name: &str) -> Span {
+    tracing::trace_span!(
+        target: SPAN_TARGET,
+        "tool_call",
+        otel.name = tool_name,
+        tool.name = tool_name,
+        session.id = tracing::field::Empty,
+        error.code = tracing::field::Empty,
+        duration_ms = tracing::field::Empty,
+    )
+}
+
+/// Span covering a session's lifetime; it ends when the last clone is dropped
+pub fn session_span(session_id: &str, command: &[String]) -> Span {
+    tracing::trace_span!(
+        target: SPAN_TARGET,
+        parent: None,
+        "session",
+        session.id = session_id,
+        session.command = command.join(" "),
+    )
+}
+
+#[cfg(feature = "otel")]
+pub use otel::init;
+
+#[cfg(feature = "otel")]
+pub mod otel {
+    use super::{TelemetryConfig, SPAN_TARGET};
+    use crate::error::{HtMcpError, Result};
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_otlp::{Protocol, WithExportConfig};
+    use opentelemetry_sdk::trace::{Sampler, SdkTracerProvider};
+    use opentelemetry_sdk::Resource;
+    use tracing::Subscriber;
+    use tracing_subscriber::filter::Targets;
+    use tracing_subscriber::registry::LookupSpan;
+    use tracing_subscriber::Layer;
+
+    /// Layer exporting spans to OpenTelemetry
+    pub type OtelLayer<S> = Box<dyn Layer<S> + Send + Sync>;
+
+    /// Keeps the exporter alive; `shutdown` flushes pending spans
+    pub struct Telemetry {
+        provider: SdkTracerProvider,
+    }
+
+    impl Telemetry {
+        pub fn shutdown(self) {
+            if let Err(e) = self.provider.shutdown() {
+                eprintln!("Failed to flush OpenTelemetry spans: {}", e);
+            }
+        }
+    }
+
+    /// Builds the OTLP exporter and a layer forwarding `SPAN_TARGET` spans to
+    /// it, or `None` when no endpoint is configured
+    pub fn init<S>(config: &TelemetryConfig) -> Result<Option<(OtelLayer<S>, Telemetry)>>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a> + Send + Sync,
+    {
+        let Some(endpoint) = config.endpoint() else {
+            return Ok(None);
+        };
+        if !(0.0..=1.0).contains(&config.sampling_ratio) {
+            return Err(HtMcpError::Config(format!(
+                "telemetry.sampling_ratio must be between 0.0 and 1.0, got {}",
+                config.sampling_ratio
+            )));
+        }
+
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .with_protocol(Protocol::HttpJson)
+            .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+            .build()
+            .map_err(|e| HtMcpError::Config(format!("Invalid OTLP exporter setup: {}", e)))?;
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+                config.sampling_ratio,
+            ))))
+            .with_resource(
+                Resource::builder()
+                    .with_service_name(config.service_name())
+                    .build(),
+            )
+            .build();
+
+        let layer = tracing_opentelemetry::layer()
+            .with_tracer(provider.tracer("ht-mcp"))
+            .with_filter(Targets::new().with_target(SPAN_TARGET, tracing::Level::TRACE));
+        Ok(Some((Box::new(layer), Telemetry { provider })))
+    }
+}
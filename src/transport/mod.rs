@@ -1 +1,2 @@
 pub mod stdio;
+pub mod stdout_guard;
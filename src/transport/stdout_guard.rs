@@ -0,0 +1,130 @@
+//! Keeps stdout for JSON-RPC frames in stdio mode.
+//!
+//! A single stray `println!`, from a dependency or a panic message, would
+//! corrupt the MCP stream. At startup the real stdout is duplicated into a
+//! private descriptor for the transport, and descriptor 1 is pointed at
+//! stderr, so anything else printing to stdout ends up in the log instead.
+
+use std::fs::File;
+use std::io;
+
+/// Takes the process's stdout for the MCP transport and sends everything
+/// else written to stdout to stderr from now on
+#[cfg(unix)]
+pub fn take_stdout() -> io::Result<File> {
+    use std::os::fd::AsRawFd;
+    redirect(io::stdout().as_raw_fd(), io::stderr().as_raw_fd())
+}
+
+/// Without descriptor redirection the transport writes to stdout directly,
+/// unguarded
+#[cfg(not(unix))]
+pub fn take_stdout() -> io::Result<File> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "stdout guarding is only implemented on Unix",
+    ))
+}
+
+/// Duplicates `target` into a new close-on-exec descriptor, which is
+/// returned, then makes `target` refer to what `replacement` refers to
+#[cfg(unix)]
+fn redirect(target: std::os::fd::RawFd, replacement: std::os::fd::RawFd) -> io::Result<File> {
+    use nix::fcntl::{fcntl, FcntlArg};
+    use std::os::fd::FromRawFd;
+
+    // Close-on-exec so session processes do not inherit the MCP stream
+    let private = fcntl(target, FcntlArg::F_DUPFD_CLOEXEC(0))?;
+    // SAFETY: `private` was just created and is owned by nothing else
+    let file = unsafe { File::from_raw_fd(private) };
+    nix::unistd::dup2(replacement, target)?;
+    Ok(file)
+}
+
+/// Logs panics through `tracing`, so they carry the span of the tool call
+/// or session they happened in and never reach stdout
+pub fn install_panic_hook() {
+    std::panic::set_hook(Box::new(|info| {
+        let location = info
+            .location()
+            .map(|location| format!(" at {}:{}", location.file(), location.line()))
+            .unwrap_or_default();
+        let message = info
+            .payload()
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| info.payload().downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("(non-string panic payload)");
+        let thread = std::thread::current();
+        tracing::error!(
+            "Thread '{}' panicked{}: {}",
+            thread.name().unwrap_or("<unnamed>"),
+            location,
+            message
+        );
+    }));
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::io::{Read, Seek, Write};
+    use std::os::fd::AsRawFd;
+
+    fn scratch_file(name: &str) -> File {
+        let path = std::env::temp_dir().join(format!("ht-mcp-{}-{}", name, uuid::Uuid::new_v4()));
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        file
+    }
+
+    fn contents(mut file: &File) -> String {
+        let mut text = String::new();
+        file.rewind().unwrap();
+        file.read_to_string(&mut text).unwrap();
+        text
+    }
+
+    #[test]
+    fn test_stray_writes_go_to_the_replacement() {
+        // Stand-ins for stdout and stderr, so the test harness's own
+        // streams are left alone
+        let stdout = scratch_file("stdout");
+        let stderr = scratch_file("stderr");
+
+        let mut transport = redirect(stdout.as_raw_fd(), stderr.as_raw_fd()).unwrap();
+        let flags =
+            nix::fcntl::fcntl(transport.as_raw_fd(), nix::fcntl::FcntlArg::F_GETFD).unwrap();
+        assert_ne!(flags & nix::libc::FD_CLOEXEC, 0);
+
+        // Something else writes to "stdout" from another thread while
+        // frames are sent
+        let mut stray = stdout.try_clone().unwrap();
+        let background = std::thread::spawn(move || {
+            for _ in 0..100 {
+                stray.write_all(b"warning: stray output\n").unwrap();
+            }
+        });
+        for id in 0..100 {
+            writeln!(transport, "{{\"jsonrpc\":\"2.0\",\"id\":{}}}", id).unwrap();
+        }
+        background.join().unwrap();
+
+        // The transport's file still refers to the original "stdout"
+        let frames = contents(&transport);
+        let lines: Vec<&str> = frames.lines().collect();
+        assert_eq!(lines.len(), 100);
+        for (id, line) in lines.iter().enumerate() {
+            let frame: serde_json::Value = serde_json::from_str(line).unwrap();
+            assert_eq!(frame["id"], id);
+        }
+        let logged = contents(&stderr);
+        assert_eq!(logged.lines().count(), 100);
+        assert!(logged.lines().all(|line| line == "warning: stray output"));
+    }
+}
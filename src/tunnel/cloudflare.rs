@@ -2,30 +2,45 @@ use crate::error::{HtMcpError, Result};
 use crate::tunnel::config::TunnelConfig;
 use regex::Regex;
 use std::process::Stdio;
-use tokio::io::{AsyncBufReadExt, BufReader};
+use std::time::SystemTime;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader, Lines};
 use tokio::process::{Child, Command};
 use tokio::time::{timeout, Duration};
-use tracing::{debug, error, info, warn};
+use tracing::{debug, error, info, trace, warn, Level};
 
 /// Manages a Cloudflare tunnel instance
 pub struct CloudflareTunnel {
     child: Child,
     pub url: String,
     pub local_port: u16,
+    /// `--protocol` the tunnel was started with, reused on restart
+    protocol: Option<String>,
+    /// Level cloudflared's output is logged at, reused on restart
+    output_level: Level,
+    created_at: SystemTime,
 }
 
 impl CloudflareTunnel {
     /// Creates a new Cloudflare tunnel for the specified port
-    /// Uses the simple TryCloudflare command: `cloudflared tunnel --url http://localhost:PORT`
-    pub async fn new_simple(port: u16) -> Result<Self> {
+    /// Uses the simple TryCloudflare command: `cloudflared tunnel --url http://localhost:PORT`,
+    /// with `--protocol` when one is given. cloudflared's output other than
+    /// the URL is logged at `output_level`.
+    pub async fn new_simple(
+        port: u16,
+        protocol: Option<&str>,
+        output_level: Level,
+    ) -> Result<Self> {
         info!("Starting cloudflared tunnel on port {}", port);
 
         // Build the simple command following TryCloudflare documentation
         let mut cmd = Command::new("cloudflared");
-        cmd.args(&["tunnel", "--url", &format!("http://localhost:{}", port)]);
+        cmd.args(Self::command_args(port, protocol));
 
         cmd.stdout(Stdio::piped());
         cmd.stderr(Stdio::piped());
+        // A caller that gives up waiting for the URL drops this future; don't
+        // leave cloudflared running behind it
+        cmd.kill_on_drop(true);
 
         // Spawn the process
         let mut child = cmd
@@ -38,30 +53,68 @@ impl CloudflareTunnel {
         })?;
 
         // Look for the tunnel URL in the output with 30 second timeout
+        let mut lines = BufReader::new(stderr).lines();
         let timeout_duration = Duration::from_secs(30);
-        let url = timeout(timeout_duration, Self::extract_tunnel_url(stderr))
-            .await
-            .map_err(|_| {
-                HtMcpError::Internal("Timeout waiting for tunnel URL after 30s".to_string())
-            })??;
+        let url = timeout(
+            timeout_duration,
+            Self::extract_tunnel_url(&mut lines, output_level),
+        )
+        .await
+        .map_err(|_| {
+            HtMcpError::NetworkError("Timeout waiting for tunnel URL after 30s".to_string())
+        })??;
 
         info!("Cloudflare tunnel established: {}", url);
 
+        // Keep reading so later progress is logged too, and cloudflared never
+        // blocks on (or dies writing to) an unread pipe
+        tokio::spawn(async move {
+            while let Ok(Some(line)) = lines.next_line().await {
+                log_output(output_level, &line);
+            }
+        });
+
         Ok(Self {
             child,
             url,
             local_port: port,
+            protocol: protocol.map(str::to_string),
+            output_level,
+            created_at: SystemTime::now(),
         })
     }
 
     /// Creates a new Cloudflare tunnel (legacy method for compatibility)
     pub async fn new(config: TunnelConfig) -> Result<Self> {
-        Self::new_simple(config.port).await
+        config.validate()?;
+        Self::new_simple(
+            config.port,
+            config.protocol.as_deref(),
+            config.cloudflared_output_level()?,
+        )
+        .await
+    }
+
+    /// Arguments for `cloudflared`
+    fn command_args(port: u16, protocol: Option<&str>) -> Vec<String> {
+        let mut args = vec![
+            "tunnel".to_string(),
+            "--url".to_string(),
+            format!("http://localhost:{}", port),
+        ];
+        if let Some(protocol) = protocol {
+            args.push("--protocol".to_string());
+            args.push(protocol.to_string());
+        }
+        args
     }
 
-    /// Extracts the tunnel URL from cloudflared's stderr output
-    async fn extract_tunnel_url(stderr: impl tokio::io::AsyncRead + Unpin) -> Result<String> {
-        let mut reader = BufReader::new(stderr).lines();
+    /// Reads cloudflared's stderr up to the line with the tunnel URL and
+    /// returns the URL; lines before it are logged at `output_level`
+    async fn extract_tunnel_url(
+        reader: &mut Lines<impl AsyncBufRead + Unpin>,
+        output_level: Level,
+    ) -> Result<String> {
         let url_regex = Regex::new(r"https://[a-zA-Z0-9-]+\.trycloudflare\.com")
             .map_err(|e| HtMcpError::Internal(format!("Invalid regex: {}", e)))?;
 
@@ -73,29 +126,42 @@ impl CloudflareTunnel {
         })? {
             attempts += 1;
             if attempts > MAX_ATTEMPTS {
-                return Err(HtMcpError::Internal(
+                return Err(HtMcpError::NetworkError(
                     "Too many attempts to find tunnel URL".to_string(),
                 ));
             }
 
-            debug!("cloudflared output: {}", line);
-
             // Look for the tunnel URL
             if let Some(url_match) = url_regex.find(&line) {
+                debug!("cloudflared output: {}", line);
                 return Ok(url_match.as_str().to_string());
             }
 
             // Also look for error messages
             if line.contains("error") || line.contains("failed") {
                 warn!("Cloudflared error: {}", line);
+            } else {
+                log_output(output_level, &line);
             }
         }
 
-        Err(HtMcpError::Internal(
+        // cloudflared exits without printing a URL when it cannot reach
+        // Cloudflare
+        Err(HtMcpError::NetworkError(
             "Could not find tunnel URL in cloudflared output".to_string(),
         ))
     }
 
+    /// Replaces the cloudflared process with a fresh one for the same port
+    /// and protocol, stopping the old one if it is still running. The new
+    /// process gets a new TryCloudflare URL.
+    pub async fn restart(&mut self) -> Result<()> {
+        self.stop().await?;
+        *self =
+            Self::new_simple(self.local_port, self.protocol.as_deref(), self.output_level).await?;
+        Ok(())
+    }
+
     /// Checks if the tunnel process is still running
     pub fn is_running(&mut self) -> bool {
         matches!(self.child.try_wait(), Ok(None))
@@ -111,6 +177,11 @@ impl CloudflareTunnel {
         self.local_port
     }
 
+    /// When the current cloudflared process was started
+    pub fn created_at(&self) -> SystemTime {
+        self.created_at
+    }
+
     /// Stops the tunnel
     pub async fn stop(&mut self) -> Result<()> {
         if self.is_running() {
@@ -135,6 +206,17 @@ impl CloudflareTunnel {
     }
 }
 
+/// Logs a line of cloudflared output at `level`
+fn log_output(level: Level, line: &str) {
+    match level {
+        Level::ERROR => error!("cloudflared output: {}", line),
+        Level::WARN => warn!("cloudflared output: {}", line),
+        Level::INFO => info!("cloudflared output: {}", line),
+        Level::DEBUG => debug!("cloudflared output: {}", line),
+        Level::TRACE => trace!("cloudflared output: {}", line),
+    }
+}
+
 impl Drop for CloudflareTunnel {
     fn drop(&mut self) {
         if self.is_running() {
@@ -149,6 +231,7 @@ impl Drop for CloudflareTunnel {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::tunnel::config::VERIFY_BUDGET;
 
     #[tokio::test]
     async fn test_config_creation() {
@@ -177,6 +260,54 @@ mod tests {
         assert_eq!(config.provider, Some("cloudflare".to_string()));
     }
 
+    #[test]
+    fn test_protocol_flag() {
+        assert_eq!(
+            CloudflareTunnel::command_args(8080, None),
+            ["tunnel", "--url", "http://localhost:8080"]
+        );
+        assert_eq!(
+            CloudflareTunnel::command_args(8080, Some("http2")),
+            [
+                "tunnel",
+                "--url",
+                "http://localhost:8080",
+                "--protocol",
+                "http2"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_protocol() {
+        for protocol in ["auto", "quic", "http2"] {
+            assert!(TunnelConfig::new(8080)
+                .with_protocol(protocol.to_string())
+                .validate()
+                .is_ok());
+        }
+        assert!(TunnelConfig::new(8080).validate().is_ok());
+
+        let err = TunnelConfig::new(8080)
+            .with_protocol("h3".to_string())
+            .validate()
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::InvalidRequest(_)));
+    }
+
+    #[test]
+    fn test_validate_provider() {
+        assert!(TunnelConfig::new(8080)
+            .with_provider("cloudflare".to_string())
+            .validate()
+            .is_ok());
+        let err = TunnelConfig::new(8080)
+            .with_provider("ngrok".to_string())
+            .validate()
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::InvalidRequest(_)));
+    }
+
     #[tokio::test]
     async fn test_url_regex() {
         let regex = Regex::new(r"https://[a-zA-Z0-9-]+\.trycloudflare\.com").unwrap();
@@ -190,4 +321,124 @@ mod tests {
         let found_url = regex.find(test_line_with_url).unwrap().as_str();
         assert_eq!(found_url, "https://abc123-def456.trycloudflare.com");
     }
+
+    #[tokio::test]
+    async fn test_extract_url_leaves_later_output_unread() {
+        let output: &[u8] = b"INF Requesting new quick Tunnel\nINF |  https://abc-def.trycloudflare.com  |\nINF Registered tunnel connection\n";
+        let mut lines = output.lines();
+        let url = CloudflareTunnel::extract_tunnel_url(&mut lines, Level::INFO)
+            .await
+            .unwrap();
+        assert_eq!(url, "https://abc-def.trycloudflare.com");
+        assert_eq!(
+            lines.next_line().await.unwrap().as_deref(),
+            Some("INF Registered tunnel connection")
+        );
+    }
+
+    #[test]
+    fn test_cloudflared_output_level() {
+        let config = TunnelConfig::new(8080);
+        assert_eq!(config.cloudflared_output_level().unwrap(), Level::DEBUG);
+
+        let config = config.with_log_cloudflared_output(true);
+        assert_eq!(config.cloudflared_output_level().unwrap(), Level::INFO);
+
+        let config = config.with_cloudflared_log_level("warn".to_string());
+        assert_eq!(config.cloudflared_output_level().unwrap(), Level::WARN);
+
+        let err = TunnelConfig::new(8080)
+            .with_cloudflared_log_level("loud".to_string())
+            .validate()
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::InvalidRequest(_)));
+    }
+
+    #[tokio::test]
+    async fn test_no_url_is_network_error() {
+        let output: &[u8] = b"ERR failed to request quick Tunnel: dial tcp: i/o timeout\n";
+        let err = CloudflareTunnel::extract_tunnel_url(&mut output.lines(), Level::DEBUG)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::NetworkError(_)));
+        assert_eq!(err.code(), "network_error");
+    }
+
+    #[tokio::test]
+    async fn test_verify_connection_waits_for_the_route() {
+        use axum::http::StatusCode;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        // Answers like a tunnel whose route comes up on the third request
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counted = requests.clone();
+        let app = axum::Router::new().route(
+            "/",
+            axum::routing::get(move || {
+                let counted = counted.clone();
+                async move {
+                    match counted.fetch_add(1, Ordering::SeqCst) {
+                        0 => StatusCode::from_u16(530).unwrap(),
+                        1 => StatusCode::BAD_GATEWAY,
+                        _ => StatusCode::NOT_FOUND,
+                    }
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let config = TunnelConfig::new(8080);
+        let err = config
+            .verify_connection(&url, 1, 10, VERIFY_BUDGET)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::NetworkError(_)), "{}", err);
+        assert!(err.to_string().contains("after 2 attempts"), "{}", err);
+
+        // Any answer from the local server counts, even a 404
+        config
+            .verify_connection(&url, 1, 10, VERIFY_BUDGET)
+            .await
+            .unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_verify_connection_fails_when_nothing_listens() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        drop(listener);
+
+        let err = TunnelConfig::new(8080)
+            .verify_connection(&url, 2, 10, VERIFY_BUDGET)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::NetworkError(_)), "{}", err);
+        assert!(err.to_string().contains("after 3 attempts"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_verify_connection_stops_at_its_budget() {
+        // Accepts connections and never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                held.push(stream);
+            }
+        });
+
+        let started = std::time::Instant::now();
+        let err = TunnelConfig::new(8080)
+            .verify_connection(&url, 100, 10, Duration::from_millis(300))
+            .await
+            .unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(matches!(err, HtMcpError::NetworkError(_)), "{}", err);
+        assert!(err.to_string().contains("within 300 ms"), "{}", err);
+    }
 }
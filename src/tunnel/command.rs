@@ -0,0 +1,307 @@
+use crate::error::{HtMcpError, Result};
+use regex::Regex;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::SystemTime;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader, Lines};
+use tokio::process::{Child, Command};
+use tokio::time::{timeout, Duration};
+use tracing::{debug, error, info, warn};
+
+/// How a tunnel client other than cloudflared is run: the command for a
+/// local port, and the pattern its public URL is printed in
+#[derive(Debug, Clone)]
+pub struct TunnelCommand {
+    /// Provider name reported in `TunnelInfo`
+    pub provider: &'static str,
+    pub program: String,
+    pub args: Vec<String>,
+    /// Matches the public URL in the client's stdout
+    pub url_pattern: &'static str,
+    /// Prefix added to the match, for clients that print a bare host
+    pub url_prefix: &'static str,
+}
+
+impl TunnelCommand {
+    /// `bore local PORT --to bore.pub`; bore prints the public port it was
+    /// given and serves plain TCP, so the URL is http
+    pub fn bore(port: u16) -> Self {
+        Self {
+            provider: "bore",
+            program: "bore".to_string(),
+            args: vec![
+                "local".to_string(),
+                port.to_string(),
+                "--to".to_string(),
+                "bore.pub".to_string(),
+            ],
+            url_pattern: r"bore\.pub:[0-9]+",
+            url_prefix: "http://",
+        }
+    }
+
+    /// A reverse SSH forward to localhost.run, which needs no client beyond
+    /// ssh and no account. ssh accepts only the host key in `known_hosts`,
+    /// so a server posing as localhost.run cannot take the forward.
+    pub fn localhost_run(port: u16, known_hosts: &Path) -> Self {
+        Self {
+            provider: "localhost.run",
+            program: "ssh".to_string(),
+            args: vec![
+                "-T".to_string(),
+                "-o".to_string(),
+                "StrictHostKeyChecking=yes".to_string(),
+                "-o".to_string(),
+                format!("UserKnownHostsFile={}", known_hosts.display()),
+                "-o".to_string(),
+                "GlobalKnownHostsFile=/dev/null".to_string(),
+                "-o".to_string(),
+                "ServerAliveInterval=30".to_string(),
+                "-R".to_string(),
+                format!("80:localhost:{}", port),
+                "nokey@localhost.run".to_string(),
+            ],
+            url_pattern: r"https://[a-zA-Z0-9-]+\.(lhr\.life|localhost\.run)",
+            url_prefix: "",
+        }
+    }
+}
+
+/// Writes localhost.run's pinned host key to a known_hosts file of the
+/// server's own. `host_key` is a known_hosts line; a bare `TYPE KEY` is
+/// taken to be localhost.run's.
+pub fn localhost_run_known_hosts(host_key: &str) -> Result<PathBuf> {
+    let host_key = host_key.trim();
+    let line = match host_key.split_whitespace().count() {
+        2 => format!("localhost.run {}", host_key),
+        _ => host_key.to_string(),
+    };
+    let path = std::env::temp_dir().join(format!(
+        "ht-mcp-{}-localhost.run.known_hosts",
+        std::process::id()
+    ));
+    std::fs::write(&path, format!("{}\n", line))
+        .map_err(|e| HtMcpError::Internal(format!("Failed to write {}: {}", path.display(), e)))?;
+    Ok(path)
+}
+
+/// A tunnel kept open by a client process that prints its public URL on
+/// stdout
+pub struct CommandTunnel {
+    command: TunnelCommand,
+    child: Child,
+    url: String,
+    local_port: u16,
+    /// Upper bound on waiting for the URL, reused on restart
+    startup_timeout: Duration,
+    created_at: SystemTime,
+}
+
+impl CommandTunnel {
+    pub async fn start(
+        command: TunnelCommand,
+        local_port: u16,
+        startup_timeout: Duration,
+    ) -> Result<Self> {
+        info!(
+            "Starting {} tunnel on port {}",
+            command.provider, local_port
+        );
+
+        let mut cmd = Command::new(&command.program);
+        cmd.args(&command.args);
+        // ssh closes the forward when its stdin ends; the pipe is held open
+        // by the child handle
+        cmd.stdin(Stdio::piped());
+        cmd.stdout(Stdio::piped());
+        cmd.stderr(Stdio::piped());
+        cmd.kill_on_drop(true);
+
+        let mut child = cmd.spawn().map_err(|e| {
+            HtMcpError::Internal(format!("Failed to spawn {}: {}", command.program, e))
+        })?;
+        let stdout = child.stdout.take().ok_or_else(|| {
+            HtMcpError::Internal(format!("Failed to capture {} stdout", command.program))
+        })?;
+        if let Some(stderr) = child.stderr.take() {
+            let provider = command.provider;
+            tokio::spawn(async move {
+                let mut lines = BufReader::new(stderr).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    debug!("{} output: {}", provider, line);
+                }
+            });
+        }
+
+        let mut lines = BufReader::new(stdout).lines();
+        let url = timeout(startup_timeout, Self::extract_url(&command, &mut lines))
+            .await
+            .map_err(|_| {
+                HtMcpError::NetworkError(format!(
+                    "Timeout waiting for {} tunnel URL after {}s",
+                    command.provider,
+                    startup_timeout.as_secs()
+                ))
+            })??;
+
+        info!("{} tunnel established: {}", command.provider, url);
+
+        let provider = command.provider;
+        tokio::spawn(async move {
+            while let Ok(Some(line)) = lines.next_line().await {
+                debug!("{} output: {}", provider, line);
+            }
+        });
+
+        Ok(Self {
+            command,
+            child,
+            url,
+            local_port,
+            startup_timeout,
+            created_at: SystemTime::now(),
+        })
+    }
+
+    /// Reads the client's output up to the line with the URL
+    async fn extract_url(
+        command: &TunnelCommand,
+        reader: &mut Lines<impl AsyncBufRead + Unpin>,
+    ) -> Result<String> {
+        let url_regex = Regex::new(command.url_pattern)
+            .map_err(|e| HtMcpError::Internal(format!("Invalid regex: {}", e)))?;
+        while let Some(line) = reader.next_line().await.map_err(|e| {
+            HtMcpError::Internal(format!("Failed to read {} output: {}", command.program, e))
+        })? {
+            if let Some(url_match) = url_regex.find(&line) {
+                debug!("{} output: {}", command.provider, line);
+                return Ok(format!("{}{}", command.url_prefix, url_match.as_str()));
+            }
+            debug!("{} output: {}", command.provider, line);
+        }
+        Err(HtMcpError::NetworkError(format!(
+            "{} exited without printing a tunnel URL",
+            command.program
+        )))
+    }
+
+    /// Replaces the client process with a fresh one for the same port; the
+    /// new process usually gets a new URL
+    pub async fn restart(&mut self) -> Result<()> {
+        self.stop().await?;
+        *self = Self::start(self.command.clone(), self.local_port, self.startup_timeout).await?;
+        Ok(())
+    }
+
+    pub fn is_running(&mut self) -> bool {
+        matches!(self.child.try_wait(), Ok(None))
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.local_port
+    }
+
+    pub fn provider(&self) -> &'static str {
+        self.command.provider
+    }
+
+    pub fn created_at(&self) -> SystemTime {
+        self.created_at
+    }
+
+    pub async fn stop(&mut self) -> Result<()> {
+        if self.is_running() {
+            info!("Stopping {} tunnel", self.command.provider);
+            if let Err(e) = self.child.start_kill() {
+                error!("Failed to kill {} process: {}", self.command.program, e);
+            }
+            if let Err(e) = self.child.wait().await {
+                error!("Error waiting for {} to exit: {}", self.command.program, e);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for CommandTunnel {
+    fn drop(&mut self) {
+        if self.is_running() {
+            warn!(
+                "{} tunnel being dropped while still running, attempting to kill",
+                self.command.provider
+            );
+            if let Err(e) = self.child.start_kill() {
+                error!(
+                    "Failed to kill {} process in Drop: {}",
+                    self.command.program, e
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn extract(command: TunnelCommand, output: &'static [u8]) -> Result<String> {
+        CommandTunnel::extract_url(&command, &mut output.lines()).await
+    }
+
+    #[tokio::test]
+    async fn test_extract_url() {
+        let url = extract(
+            TunnelCommand::bore(8080),
+            b"2024-01-01T12:00:00Z INFO bore_cli::client: connected to server remote_port=41235\n2024-01-01T12:00:00Z INFO bore_cli::client: listening at bore.pub:41235\n",
+        )
+        .await
+        .unwrap();
+        assert_eq!(url, "http://bore.pub:41235");
+
+        let url = extract(
+            TunnelCommand::localhost_run(8080, Path::new("/dev/null")),
+            b"** your connection id is abc **\n1a2b3c4d5e6f.lhr.life tunneled with tls termination, https://1a2b3c4d5e6f.lhr.life\n",
+        )
+        .await
+        .unwrap();
+        assert_eq!(url, "https://1a2b3c4d5e6f.lhr.life");
+
+        let err = extract(TunnelCommand::bore(8080), b"Error: server refused\n")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, HtMcpError::NetworkError(_)));
+    }
+
+    #[test]
+    fn test_localhost_run_host_key_is_pinned() {
+        let path = localhost_run_known_hosts("ssh-ed25519 AAAAC3Nza").unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "localhost.run ssh-ed25519 AAAAC3Nza\n"
+        );
+        let command = TunnelCommand::localhost_run(8080, &path);
+        assert!(command
+            .args
+            .contains(&"StrictHostKeyChecking=yes".to_string()));
+        assert!(command
+            .args
+            .contains(&format!("UserKnownHostsFile={}", path.display())));
+    }
+
+    #[tokio::test]
+    async fn test_missing_client_fails_to_spawn() {
+        let command = TunnelCommand {
+            program: "ht-mcp-no-such-tunnel-client".to_string(),
+            ..TunnelCommand::bore(8080)
+        };
+        let err = CommandTunnel::start(command, 8080, Duration::from_secs(1))
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("Failed to spawn"), "{}", err);
+    }
+}
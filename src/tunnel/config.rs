@@ -1,5 +1,36 @@
+use crate::error::{HtMcpError, Result};
+use crate::tunnel::named::NamedTunnelSpec;
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tracing::{debug, Level};
+
+/// Transport protocols accepted by `cloudflared --protocol`
+pub const TUNNEL_PROTOCOLS: [&str; 3] = ["auto", "quic", "http2"];
+
+/// Tunnel providers `TunnelManager` can start; `auto` tries the others in
+/// the order of [`AUTO_TUNNEL_PROVIDERS`]
+pub const TUNNEL_PROVIDERS: [&str; 4] = ["cloudflare", "bore", "localhost.run", "auto"];
+
+/// Providers tried by `auto`, in order
+pub const AUTO_TUNNEL_PROVIDERS: [&str; 1] = ["cloudflare"];
+
+/// Providers used only when the server config enables them and a session
+/// names them, never by `auto`: bore carries traffic unencrypted, and
+/// localhost.run is only as safe as the SSH host key it is pinned to
+pub const OPT_IN_TUNNEL_PROVIDERS: [&str; 2] = ["bore", "localhost.run"];
+
+/// Attempts and the pause between them when `verify_on_create` checks a new
+/// tunnel; a quick tunnel's hostname can take a few seconds to resolve
+pub const VERIFY_RETRIES: u8 = 10;
+pub const VERIFY_RETRY_DELAY_MS: u64 = 1000;
+
+/// How long one verification request may take
+const VERIFY_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long verifying a tunnel may take in all, retries included. Tool
+/// calls that start a tunnel get this much more time with `verify_tunnels`.
+pub const VERIFY_BUDGET: Duration = Duration::from_secs(20);
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TunnelConfig {
@@ -15,7 +46,8 @@ pub struct TunnelConfig {
     /// Enable verbose logging (legacy support)
     pub verbose: Option<bool>,
 
-    /// Tunnel provider (legacy support)
+    /// Tunnel provider: `cloudflare` (default), `bore`, `localhost.run`, or
+    /// `auto` to use the first of [`AUTO_TUNNEL_PROVIDERS`] that comes up
     pub provider: Option<String>,
 
     /// Authentication token for the tunnel service (legacy support)
@@ -23,6 +55,34 @@ pub struct TunnelConfig {
 
     /// Custom domain for the tunnel (legacy support)
     pub custom_domain: Option<String>,
+
+    /// Transport protocol for cloudflared (`auto`, `quic` or `http2`);
+    /// `http2` helps where firewalls block QUIC
+    #[serde(default)]
+    pub protocol: Option<String>,
+
+    /// Log cloudflared's stderr at `cloudflared_log_level` instead of debug
+    #[serde(default)]
+    pub log_cloudflared_output: bool,
+
+    /// `trace`, `debug`, `info`, `warn` or `error`; `info` when unset
+    #[serde(default)]
+    pub cloudflared_log_level: Option<String>,
+
+    /// Check with [`TunnelConfig::verify_connection`] that the tunnel URL
+    /// answers before the tunnel is handed out
+    #[serde(default)]
+    pub verify_on_create: bool,
+
+    /// The known_hosts line ssh checks localhost.run's host key against;
+    /// localhost.run tunnels cannot start without it
+    #[serde(default)]
+    pub localhost_run_host_key: Option<String>,
+
+    /// The named tunnel to start instead of a quick tunnel, falling back to
+    /// a quick tunnel when it cannot be set up; Cloudflare only
+    #[serde(default)]
+    pub named: Option<NamedTunnelSpec>,
 }
 
 impl TunnelConfig {
@@ -35,6 +95,12 @@ impl TunnelConfig {
             provider: Some("cloudflare".to_string()),
             auth_token: None,
             custom_domain: None,
+            protocol: None,
+            log_cloudflared_output: false,
+            cloudflared_log_level: None,
+            verify_on_create: false,
+            localhost_run_host_key: None,
+            named: None,
         }
     }
 
@@ -67,6 +133,153 @@ impl TunnelConfig {
         self.custom_domain = Some(custom_domain);
         self
     }
+
+    pub fn with_protocol(mut self, protocol: String) -> Self {
+        self.protocol = Some(protocol);
+        self
+    }
+
+    pub fn with_log_cloudflared_output(mut self, log_cloudflared_output: bool) -> Self {
+        self.log_cloudflared_output = log_cloudflared_output;
+        self
+    }
+
+    pub fn with_cloudflared_log_level(mut self, level: String) -> Self {
+        self.cloudflared_log_level = Some(level);
+        self
+    }
+
+    pub fn with_verify_on_create(mut self, verify_on_create: bool) -> Self {
+        self.verify_on_create = verify_on_create;
+        self
+    }
+
+    pub fn with_localhost_run_host_key(mut self, host_key: String) -> Self {
+        self.localhost_run_host_key = Some(host_key);
+        self
+    }
+
+    pub fn with_named(mut self, named: NamedTunnelSpec) -> Self {
+        self.named = Some(named);
+        self
+    }
+
+    /// Polls `tunnel_url` with GET requests until the tunnel forwards one,
+    /// trying `max_retries` more times `retry_delay_ms` apart, for no longer
+    /// than `budget` in all. Any response from the local server counts,
+    /// even an error status; failed requests and the gateway errors a
+    /// tunnel returns while its route is not up yet (502, 503, 504, and
+    /// Cloudflare's 530) do not.
+    pub async fn verify_connection(
+        &self,
+        tunnel_url: &str,
+        max_retries: u8,
+        retry_delay_ms: u64,
+        budget: Duration,
+    ) -> Result<()> {
+        let client = reqwest::Client::builder()
+            .build()
+            .map_err(|e| HtMcpError::Internal(format!("Failed to build HTTP client: {}", e)))?;
+        let deadline = Instant::now() + budget;
+        let mut last_failure = String::new();
+        for attempt in 0..=max_retries {
+            if attempt > 0 {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                tokio::time::sleep(Duration::from_millis(retry_delay_ms).min(remaining)).await;
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(HtMcpError::NetworkError(format!(
+                    "Tunnel {} to port {} did not answer within {} ms ({} attempts): {}",
+                    tunnel_url,
+                    self.port,
+                    budget.as_millis(),
+                    attempt,
+                    last_failure
+                )));
+            }
+            let request = client
+                .get(tunnel_url)
+                .timeout(remaining.min(VERIFY_REQUEST_TIMEOUT));
+            match request.send().await {
+                Ok(response) if !is_gateway_error(response.status().as_u16()) => return Ok(()),
+                Ok(response) => last_failure = format!("status {}", response.status()),
+                Err(e) => last_failure = e.to_string(),
+            }
+            debug!(
+                "Tunnel {} to port {} not ready ({}): {}",
+                tunnel_url,
+                self.port,
+                attempt + 1,
+                last_failure
+            );
+        }
+        Err(HtMcpError::NetworkError(format!(
+            "Tunnel {} to port {} did not answer after {} attempts: {}",
+            tunnel_url,
+            self.port,
+            u16::from(max_retries) + 1,
+            last_failure
+        )))
+    }
+
+    /// Level cloudflared's output lines (other than the URL) are logged at
+    pub fn cloudflared_output_level(&self) -> Result<Level> {
+        if !self.log_cloudflared_output {
+            return Ok(Level::DEBUG);
+        }
+        match &self.cloudflared_log_level {
+            Some(level) => parse_log_level(level),
+            None => Ok(Level::INFO),
+        }
+    }
+
+    /// Checks the settings cloudflared would reject
+    pub fn validate(&self) -> Result<()> {
+        if let Some(provider) = &self.provider {
+            validate_provider(provider)?;
+        }
+        if let Some(level) = &self.cloudflared_log_level {
+            parse_log_level(level)?;
+        }
+        if let Some(protocol) = &self.protocol {
+            if !TUNNEL_PROTOCOLS.contains(&protocol.as_str()) {
+                return Err(HtMcpError::InvalidRequest(format!(
+                    "Unsupported tunnel protocol '{}'; expected one of: {}",
+                    protocol,
+                    TUNNEL_PROTOCOLS.join(", ")
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks that `provider` is one `TunnelManager` supports
+pub fn validate_provider(provider: &str) -> Result<()> {
+    if !TUNNEL_PROVIDERS.contains(&provider) {
+        return Err(HtMcpError::InvalidRequest(format!(
+            "Unsupported tunnel provider '{}'; expected one of: {}",
+            provider,
+            TUNNEL_PROVIDERS.join(", ")
+        )));
+    }
+    Ok(())
+}
+
+/// Statuses a tunnel answers with while it cannot reach the local server
+fn is_gateway_error(status: u16) -> bool {
+    matches!(status, 502 | 503 | 504 | 530)
+}
+
+/// Parses a tracing level name such as `info`
+pub fn parse_log_level(level: &str) -> Result<Level> {
+    level.parse().map_err(|_| {
+        HtMcpError::InvalidRequest(format!(
+            "Unsupported log level '{}'; expected one of: trace, debug, info, warn, error",
+            level
+        ))
+    })
 }
 
 impl Default for TunnelConfig {
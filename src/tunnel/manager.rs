@@ -1,7 +1,9 @@
 use crate::error::{HtMcpError, Result};
-use crate::tunnel::cloudflare::CloudflareTunnel;
-use crate::tunnel::config::TunnelConfig;
+use crate::tunnel::config::{TunnelConfig, VERIFY_BUDGET, VERIFY_RETRIES, VERIFY_RETRY_DELAY_MS};
+use crate::tunnel::pool::{Relay, TunnelPool};
+use crate::tunnel::provider::{FallbackTunnelChain, Tunnel, TunnelProvider};
 use std::collections::HashMap;
+use std::time::SystemTime;
 use tracing::{error, info, warn};
 use uuid::Uuid;
 
@@ -11,85 +13,335 @@ pub struct TunnelInfo {
     pub id: String,
     pub url: String,
     pub local_port: u16,
+    /// Provider that started the tunnel; with `auto`, the first that
+    /// succeeded
     pub provider: String,
     pub created_at: std::time::SystemTime,
     pub is_active: bool,
+    /// Whether the URL survives a restart of the tunnel: true for named
+    /// tunnels, false for quick tunnels, which get a new random hostname
+    /// every time
+    pub hostname_stable: bool,
+    /// Every URL the tunnel has had, oldest first, with the time it was
+    /// assigned; the last one is `url`
+    pub tunnel_url_history: Vec<(String, SystemTime)>,
+}
+
+impl TunnelInfo {
+    /// `tunnel_url_history` as reported to clients
+    pub fn url_history_json(&self) -> serde_json::Value {
+        self.tunnel_url_history
+            .iter()
+            .map(|(url, assigned_at)| {
+                serde_json::json!({
+                    "url": url,
+                    "assignedAt": assigned_at.duration_since(std::time::UNIX_EPOCH)
+                        .unwrap_or_default().as_secs(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// A running tunnel and the URLs it had before
+struct ManagedTunnel {
+    tunnel: Box<dyn Tunnel>,
+    url_history: Vec<(String, SystemTime)>,
+    /// What a pooled tunnel forwards to, pointed at its session's port
+    relay: Option<Relay>,
+}
+
+impl ManagedTunnel {
+    fn new(tunnel: Box<dyn Tunnel>) -> Self {
+        // A pooled named tunnel has no URL until it is leased
+        let url_history = match tunnel.url() {
+            "" => Vec::new(),
+            url => vec![(url.to_string(), tunnel.created_at())],
+        };
+        Self {
+            tunnel,
+            url_history,
+            relay: None,
+        }
+    }
+
+    /// The port traffic ends up at: a pooled tunnel's relay target, or the
+    /// port the tunnel itself forwards to
+    fn local_port(&self) -> u16 {
+        self.relay
+            .as_ref()
+            .and_then(Relay::target)
+            .unwrap_or_else(|| self.tunnel.local_port())
+    }
+
+    /// Records the tunnel's URL if a restart changed it
+    fn record_url(&mut self) {
+        let url = self.tunnel.url();
+        if self.url_history.last().map(|(last, _)| last.as_str()) != Some(url) {
+            self.url_history.push((url.to_string(), SystemTime::now()));
+        }
+    }
+
+    /// The tunnel's information, checking whether its client still runs
+    fn info(&mut self, id: &str) -> TunnelInfo {
+        let is_active = self.tunnel.is_running();
+        let tunnel = self.tunnel.as_ref();
+        TunnelInfo {
+            id: id.to_string(),
+            url: tunnel.url().to_string(),
+            local_port: self.local_port(),
+            provider: tunnel.provider().to_string(),
+            created_at: tunnel.created_at(),
+            is_active,
+            hostname_stable: tunnel.hostname_stable(),
+            tunnel_url_history: self.url_history.clone(),
+        }
+    }
+}
+
+/// A tunnel taken out of its manager, to be started or stopped without
+/// holding it; [`TunnelManager::attach`] puts it back
+pub struct DetachedTunnel {
+    pub id: String,
+    tunnel: ManagedTunnel,
+}
+
+impl DetachedTunnel {
+    /// A new tunnel with a fresh id
+    pub fn new(tunnel: Box<dyn Tunnel>) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            tunnel: ManagedTunnel::new(tunnel),
+        }
+    }
+
+    /// Has the tunnel forward through `relay`, for the pool
+    pub fn with_relay(mut self, relay: Relay) -> Self {
+        self.tunnel.relay = Some(relay);
+        self
+    }
+
+    pub fn info(&mut self) -> TunnelInfo {
+        self.tunnel.info(&self.id)
+    }
+
+    pub async fn stop(mut self) -> Result<()> {
+        info!("Stopping tunnel: {}", self.id);
+        self.tunnel.tunnel.stop().await?;
+        info!("Tunnel stopped: {}", self.id);
+        Ok(())
+    }
 }
 
 /// Manages tunnel instances for the application
 pub struct TunnelManager {
-    tunnels: HashMap<String, Box<CloudflareTunnel>>,
+    tunnels: HashMap<String, ManagedTunnel>,
+    pool: TunnelPool,
 }
 
 impl TunnelManager {
     pub fn new() -> Self {
         Self {
             tunnels: HashMap::new(),
+            pool: TunnelPool::default(),
         }
     }
 
     /// Creates a simple tunnel for the specified port
     pub async fn create_simple_tunnel(&mut self, port: u16) -> Result<TunnelInfo> {
-        let tunnel_id = Uuid::new_v4().to_string();
+        self.create_tunnel(TunnelConfig::new(port)).await
+    }
 
-        info!("Creating cloudflare tunnel on port {}", port);
+    /// Creates a new tunnel and returns its information. With
+    /// `verify_on_create`, a tunnel whose URL does not answer is stopped and
+    /// its verification error returned.
+    pub async fn create_tunnel(&mut self, config: TunnelConfig) -> Result<TunnelInfo> {
+        let tunnel = Self::start(config).await?;
+        Ok(self.attach(tunnel))
+    }
 
-        let tunnel = CloudflareTunnel::new_simple(port).await?;
-        let tunnel_info = TunnelInfo {
-            id: tunnel_id.clone(),
-            url: tunnel.url().to_string(),
-            local_port: tunnel.local_port(),
-            provider: "cloudflare".to_string(),
-            created_at: std::time::SystemTime::now(),
-            is_active: true,
-        };
+    /// Starts a tunnel like [`Self::create_tunnel`] without adding it to a
+    /// manager
+    pub async fn start(config: TunnelConfig) -> Result<DetachedTunnel> {
+        config.validate()?;
+        let provider = config.provider.as_deref().unwrap_or("cloudflare");
 
-        self.tunnels.insert(tunnel_id, Box::new(tunnel));
+        info!("Creating {} tunnel on port {}", provider, config.port);
+
+        let tunnel = FallbackTunnelChain::for_config(&config)?
+            .start(&config)
+            .await?;
+        let mut tunnel = DetachedTunnel::new(tunnel);
+        let tunnel_info = tunnel.info();
+        if config.verify_on_create {
+            let verified = config
+                .verify_connection(
+                    &tunnel_info.url,
+                    VERIFY_RETRIES,
+                    VERIFY_RETRY_DELAY_MS,
+                    VERIFY_BUDGET,
+                )
+                .await;
+            if let Err(e) = verified {
+                if let Err(stop_error) = tunnel.stop().await {
+                    warn!("Failed to stop unverified tunnel: {}", stop_error);
+                }
+                return Err(e);
+            }
+        }
 
         info!(
             "Tunnel created successfully: {} -> {}",
             tunnel_info.local_port, tunnel_info.url
         );
-        Ok(tunnel_info)
+        Ok(tunnel)
     }
 
-    /// Creates a new tunnel and returns its information (legacy method)
-    pub async fn create_tunnel(&mut self, config: TunnelConfig) -> Result<TunnelInfo> {
-        self.create_simple_tunnel(config.port).await
+    /// Adds a started tunnel, returning its information
+    pub fn attach(&mut self, mut tunnel: DetachedTunnel) -> TunnelInfo {
+        let tunnel_info = tunnel.info();
+        self.tunnels.insert(tunnel.id, tunnel.tunnel);
+        tunnel_info
     }
 
-    /// Gets information about a specific tunnel
-    pub fn get_tunnel(&self, tunnel_id: &str) -> Option<TunnelInfo> {
-        self.tunnels.get(tunnel_id).map(|tunnel| TunnelInfo {
+    /// Takes a tunnel out, leaving it running
+    pub fn detach(&mut self, tunnel_id: &str) -> Option<DetachedTunnel> {
+        let tunnel = self.tunnels.remove(tunnel_id)?;
+        self.pool.forget(tunnel_id);
+        Some(DetachedTunnel {
             id: tunnel_id.to_string(),
-            url: tunnel.url().to_string(),
-            local_port: tunnel.local_port(),
-            provider: "cloudflare".to_string(), // Currently only cloudflare
-            created_at: std::time::SystemTime::now(), // TODO: Store actual creation time
-            is_active: true,                    // TODO: Check actual status
+            tunnel,
         })
     }
 
-    /// Lists all active tunnels
-    pub fn list_tunnels(&self) -> Vec<TunnelInfo> {
+    /// Relaunches a tunnel's client process, keeping its id, provider and
+    /// local port, and returns its information with the new URL
+    pub async fn restart_tunnel(&mut self, tunnel_id: &str) -> Result<TunnelInfo> {
+        let tunnel = self
+            .tunnels
+            .get_mut(tunnel_id)
+            .ok_or_else(|| HtMcpError::Internal(format!("Tunnel not found: {}", tunnel_id)))?;
+        info!("Restarting tunnel: {}", tunnel_id);
+        tunnel.tunnel.restart().await?;
+        tunnel.record_url();
+        Ok(tunnel.info(tunnel_id))
+    }
+
+    /// Puts `earlier` URLs ahead of a tunnel's own history, for a tunnel
+    /// that replaced another one
+    pub fn carry_url_history(&mut self, tunnel_id: &str, earlier: Vec<(String, SystemTime)>) {
+        if let Some(tunnel) = self.tunnels.get_mut(tunnel_id) {
+            tunnel.url_history.splice(0..0, earlier);
+        }
+    }
+
+    /// Adds a tunnel started with a relay to the idle pool
+    pub fn add_pooled(&mut self, tunnel: DetachedTunnel) {
+        let id = tunnel.id.clone();
+        self.attach(tunnel);
+        self.pool.add(id);
+    }
+
+    /// An idle pooled tunnel, now forwarding to `port` and, for a named
+    /// tunnel, reachable at `hostname`; `None` if the pool has none left or
+    /// routing failed, for the caller to start a tunnel of its own
+    pub async fn lease_pooled(&mut self, port: u16, hostname: Option<&str>) -> Option<TunnelInfo> {
+        let tunnels = &mut self.tunnels;
+        let (leased, dead) = self.pool.lease(|id| {
+            tunnels
+                .get_mut(id)
+                .is_some_and(|tunnel| tunnel.tunnel.is_running())
+        });
+        for id in dead {
+            // Its client already exited, so there is nothing to stop
+            warn!("Dropping pooled tunnel {}, whose client exited", id);
+            self.tunnels.remove(&id);
+        }
+        let id = leased?;
+        let tunnel = self.tunnels.get_mut(&id)?;
+        if let Some(hostname) = hostname {
+            if let Err(e) = tunnel.tunnel.route(hostname).await {
+                warn!(
+                    "Failed to route {} to pooled tunnel {}: {}",
+                    hostname, id, e
+                );
+                if let Err(e) = self.stop_tunnel(&id).await {
+                    warn!("Failed to stop tunnel {}: {}", id, e);
+                }
+                return None;
+            }
+            tunnel.record_url();
+        }
+        if let Some(relay) = &tunnel.relay {
+            relay.point_at(Some(port));
+        }
+        info!("Leased pooled tunnel {} for port {}", id, port);
+        Some(tunnel.info(&id))
+    }
+
+    /// Points a leased pooled tunnel at `port`, such as its session's web
+    /// server after a restart moved it; false if the tunnel is not pooled
+    pub fn repoint_pooled(&mut self, tunnel_id: &str, port: u16) -> bool {
+        if !self.pool.is_leased(tunnel_id) {
+            return false;
+        }
+        let relay = self
+            .tunnels
+            .get(tunnel_id)
+            .and_then(|tunnel| tunnel.relay.as_ref());
+        match relay {
+            Some(relay) => {
+                relay.point_at(Some(port));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Pooled tunnels missing to reach `size` idle ones
+    pub fn pool_deficit(&self, size: usize) -> usize {
+        self.pool.deficit(size)
+    }
+
+    /// A tunnel's pool state and age, see [`TunnelPool::entry`]
+    pub fn pool_entry(&self, tunnel_id: &str) -> Option<serde_json::Value> {
+        self.pool.entry(tunnel_id)
+    }
+
+    pub fn pool_summary(&self, size: usize) -> serde_json::Value {
+        self.pool.summary(size)
+    }
+
+    /// Gets information about a specific tunnel
+    pub fn get_tunnel(&mut self, tunnel_id: &str) -> Option<TunnelInfo> {
         self.tunnels
-            .iter()
-            .map(|(id, tunnel)| TunnelInfo {
-                id: id.clone(),
-                url: tunnel.url().to_string(),
-                local_port: tunnel.local_port(),
-                provider: "cloudflare".to_string(),
-                created_at: std::time::SystemTime::now(), // TODO: Store actual creation time
-                is_active: true,                          // TODO: Check actual status
-            })
+            .get_mut(tunnel_id)
+            .map(|tunnel| tunnel.info(tunnel_id))
+    }
+
+    /// The tunnel forwarding to local `port`, such as a session's web server
+    pub fn find_by_port(&mut self, port: u16) -> Option<TunnelInfo> {
+        self.tunnels
+            .iter_mut()
+            .find(|(_, tunnel)| tunnel.local_port() == port)
+            .map(|(id, tunnel)| tunnel.info(id))
+    }
+
+    /// Lists all tunnels, including ones whose client exited since the last
+    /// `health_check`
+    pub fn list_tunnels(&mut self) -> Vec<TunnelInfo> {
+        self.tunnels
+            .iter_mut()
+            .map(|(id, tunnel)| tunnel.info(id))
             .collect()
     }
 
     /// Stops and removes a tunnel
     pub async fn stop_tunnel(&mut self, tunnel_id: &str) -> Result<()> {
+        self.pool.forget(tunnel_id);
         if let Some(mut tunnel) = self.tunnels.remove(tunnel_id) {
             info!("Stopping tunnel: {}", tunnel_id);
-            tunnel.stop().await?;
+            tunnel.tunnel.stop().await?;
             info!("Tunnel stopped: {}", tunnel_id);
             Ok(())
         } else {
@@ -100,6 +352,16 @@ impl TunnelManager {
         }
     }
 
+    /// Stops the tunnel forwarding to local `port`, returning its id; `None`
+    /// if no tunnel forwards to it
+    pub async fn stop_tunnel_for_port(&mut self, port: u16) -> Result<Option<String>> {
+        let Some(tunnel) = self.find_by_port(port) else {
+            return Ok(None);
+        };
+        self.stop_tunnel(&tunnel.id).await?;
+        Ok(Some(tunnel.id))
+    }
+
     /// Stops all tunnels
     pub async fn stop_all_tunnels(&mut self) -> Result<()> {
         info!("Stopping all tunnels");
@@ -120,7 +382,7 @@ impl TunnelManager {
         let mut dead_tunnels = Vec::new();
 
         for (id, tunnel) in self.tunnels.iter_mut() {
-            if !tunnel.is_running() {
+            if !tunnel.tunnel.is_running() {
                 warn!("Tunnel {} is no longer running", id);
                 dead_tunnels.push(id.clone());
             }
@@ -128,6 +390,7 @@ impl TunnelManager {
 
         for id in dead_tunnels {
             self.tunnels.remove(&id);
+            self.pool.forget(&id);
             info!("Removed dead tunnel: {}", id);
         }
 
@@ -155,10 +418,12 @@ impl Drop for TunnelManager {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
 
     #[tokio::test]
     async fn test_tunnel_manager_creation() {
-        let manager = TunnelManager::new();
+        let mut manager = TunnelManager::new();
         assert_eq!(manager.tunnel_count(), 0);
         assert!(manager.list_tunnels().is_empty());
     }
@@ -187,6 +452,11 @@ mod tests {
             provider: "cloudflare".to_string(),
             created_at: std::time::SystemTime::now(),
             is_active: true,
+            hostname_stable: false,
+            tunnel_url_history: vec![(
+                "https://test.trycloudflare.com".to_string(),
+                std::time::SystemTime::UNIX_EPOCH,
+            )],
         };
 
         assert_eq!(info.id, "test-id");
@@ -194,5 +464,281 @@ mod tests {
         assert_eq!(info.local_port, 8080);
         assert_eq!(info.provider, "cloudflare");
         assert!(info.is_active);
+        assert_eq!(
+            info.url_history_json(),
+            serde_json::json!([{"url": "https://test.trycloudflare.com", "assignedAt": 0}])
+        );
+    }
+
+    /// A tunnel that gets the next numbered URL on each restart
+    struct NumberedTunnel {
+        url: String,
+        restarts: u32,
+        running: bool,
+    }
+
+    #[async_trait::async_trait]
+    impl Tunnel for NumberedTunnel {
+        fn provider(&self) -> &str {
+            "test"
+        }
+
+        fn url(&self) -> &str {
+            &self.url
+        }
+
+        fn local_port(&self) -> u16 {
+            8080
+        }
+
+        fn created_at(&self) -> SystemTime {
+            SystemTime::UNIX_EPOCH
+        }
+
+        fn is_running(&mut self) -> bool {
+            self.running
+        }
+
+        async fn restart(&mut self) -> Result<()> {
+            self.restarts += 1;
+            self.url = format!("https://tunnel-{}.example", self.restarts);
+            Ok(())
+        }
+
+        async fn stop(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn numbered_tunnel() -> ManagedTunnel {
+        ManagedTunnel::new(Box::new(NumberedTunnel {
+            url: "https://tunnel-0.example".to_string(),
+            restarts: 0,
+            running: true,
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_restarts_keep_url_history() {
+        let mut manager = TunnelManager::new();
+        manager.tunnels.insert("t".to_string(), numbered_tunnel());
+
+        manager.restart_tunnel("t").await.unwrap();
+        let info = manager.restart_tunnel("t").await.unwrap();
+        assert_eq!(info.url, "https://tunnel-2.example");
+        let urls: Vec<&str> = info
+            .tunnel_url_history
+            .iter()
+            .map(|(url, _)| url.as_str())
+            .collect();
+        assert_eq!(
+            urls,
+            [
+                "https://tunnel-0.example",
+                "https://tunnel-1.example",
+                "https://tunnel-2.example"
+            ]
+        );
+        assert_eq!(info.tunnel_url_history[0].1, SystemTime::UNIX_EPOCH);
+        assert!(info.tunnel_url_history[1].1 <= info.tunnel_url_history[2].1);
+        assert_eq!(
+            manager.get_tunnel("t").unwrap().tunnel_url_history,
+            info.tunnel_url_history
+        );
+
+        // A replacement tunnel starts from the history of the one it replaced
+        manager.tunnels.insert("u".to_string(), numbered_tunnel());
+        manager.carry_url_history("u", info.tunnel_url_history);
+        assert_eq!(manager.get_tunnel("u").unwrap().tunnel_url_history.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_tunnels_by_port() {
+        let mut manager = TunnelManager::new();
+        manager.tunnels.insert("t".to_string(), numbered_tunnel());
+
+        assert_eq!(manager.find_by_port(8080).unwrap().id, "t");
+        assert!(manager.find_by_port(8081).is_none());
+        assert!(manager.find_by_port(8080).unwrap().is_active);
+
+        assert_eq!(manager.stop_tunnel_for_port(8081).await.unwrap(), None);
+        assert_eq!(
+            manager.stop_tunnel_for_port(8080).await.unwrap().as_deref(),
+            Some("t")
+        );
+        assert_eq!(manager.tunnel_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_detached_tunnels_keep_their_id_and_history() {
+        let mut manager = TunnelManager::new();
+        manager.tunnels.insert("t".to_string(), numbered_tunnel());
+        manager.restart_tunnel("t").await.unwrap();
+
+        let mut detached = manager.detach("t").unwrap();
+        assert_eq!(manager.tunnel_count(), 0);
+        assert!(manager.detach("t").is_none());
+        assert_eq!(detached.info().tunnel_url_history.len(), 2);
+
+        let info = manager.attach(detached);
+        assert_eq!(info.id, "t");
+        assert_eq!(
+            manager.get_tunnel("t").unwrap().url,
+            "https://tunnel-1.example"
+        );
+
+        let fresh = DetachedTunnel::new(Box::new(NumberedTunnel {
+            url: "https://tunnel-0.example".to_string(),
+            restarts: 0,
+            running: true,
+        }));
+        assert_ne!(fresh.id, "t");
+        fresh.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_pooled_tunnels_are_leased_and_stopped() {
+        let mut manager = TunnelManager::new();
+        let pooled = DetachedTunnel::new(Box::new(NumberedTunnel {
+            url: "https://tunnel-0.example".to_string(),
+            restarts: 0,
+            running: true,
+        }))
+        .with_relay(Relay::bind().await.unwrap());
+        let pooled_id = pooled.id.clone();
+        manager.add_pooled(pooled);
+        assert_eq!(manager.pool_deficit(2), 1);
+        assert_eq!(manager.pool_entry(&pooled_id).unwrap()["state"], "idle");
+
+        let leased = manager.lease_pooled(3000, None).await.unwrap();
+        assert_eq!(leased.id, pooled_id);
+        assert_eq!(leased.local_port, 3000);
+        assert_eq!(manager.find_by_port(3000).unwrap().id, pooled_id);
+        assert_eq!(manager.pool_entry(&pooled_id).unwrap()["state"], "leased");
+        // Exhausted: the caller starts a tunnel of its own
+        assert!(manager.lease_pooled(3001, None).await.is_none());
+
+        assert!(manager.repoint_pooled(&pooled_id, 3002));
+        assert_eq!(manager.get_tunnel(&pooled_id).unwrap().local_port, 3002);
+        manager.tunnels.insert("t".to_string(), numbered_tunnel());
+        assert!(!manager.repoint_pooled("t", 3000));
+
+        // Its URL was handed out, so it is stopped rather than leased again
+        manager.stop_tunnel(&pooled_id).await.unwrap();
+        assert!(manager.get_tunnel(&pooled_id).is_none());
+        assert!(manager.pool_entry(&pooled_id).is_none());
+        assert_eq!(
+            manager.pool_summary(1),
+            serde_json::json!({"target": 1, "idle": 0, "leased": 0, "hits": 1, "misses": 1})
+        );
+        assert_eq!(manager.pool_deficit(1), 1);
+    }
+
+    /// A tunnel without a URL until a hostname is routed to it, like a
+    /// pooled named tunnel; routing `fail.example` fails
+    struct UnroutedTunnel {
+        url: String,
+        stopped: Arc<AtomicBool>,
+    }
+
+    #[async_trait::async_trait]
+    impl Tunnel for UnroutedTunnel {
+        fn provider(&self) -> &str {
+            "cloudflare"
+        }
+
+        fn url(&self) -> &str {
+            &self.url
+        }
+
+        fn local_port(&self) -> u16 {
+            8080
+        }
+
+        fn created_at(&self) -> SystemTime {
+            SystemTime::UNIX_EPOCH
+        }
+
+        fn is_running(&mut self) -> bool {
+            true
+        }
+
+        fn hostname_stable(&self) -> bool {
+            true
+        }
+
+        async fn route(&mut self, hostname: &str) -> Result<()> {
+            if hostname == "fail.example" {
+                return Err(HtMcpError::NetworkError("route failed".to_string()));
+            }
+            self.url = format!("https://{}", hostname);
+            Ok(())
+        }
+
+        async fn restart(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        async fn stop(&mut self) -> Result<()> {
+            self.stopped.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    async fn add_unrouted(manager: &mut TunnelManager) -> (String, Arc<AtomicBool>) {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let pooled = DetachedTunnel::new(Box::new(UnroutedTunnel {
+            url: String::new(),
+            stopped: stopped.clone(),
+        }))
+        .with_relay(Relay::bind().await.unwrap());
+        let id = pooled.id.clone();
+        manager.add_pooled(pooled);
+        (id, stopped)
+    }
+
+    #[tokio::test]
+    async fn test_pooled_named_tunnels_are_routed_when_leased() {
+        let mut manager = TunnelManager::new();
+        let (routed_id, _) = add_unrouted(&mut manager).await;
+        assert!(manager
+            .get_tunnel(&routed_id)
+            .unwrap()
+            .tunnel_url_history
+            .is_empty());
+
+        let leased = manager
+            .lease_pooled(3000, Some("demo.example"))
+            .await
+            .unwrap();
+        assert_eq!(leased.id, routed_id);
+        assert_eq!(leased.url, "https://demo.example");
+        assert!(leased.hostname_stable);
+        assert_eq!(leased.tunnel_url_history.len(), 1);
+
+        // One that cannot be routed is stopped, and the caller starts its own
+        let (failed_id, stopped) = add_unrouted(&mut manager).await;
+        assert!(manager
+            .lease_pooled(3001, Some("fail.example"))
+            .await
+            .is_none());
+        assert!(stopped.load(Ordering::SeqCst));
+        assert!(manager.get_tunnel(&failed_id).is_none());
+        assert_eq!(manager.pool_deficit(1), 1);
+    }
+
+    #[tokio::test]
+    async fn test_is_active_checks_the_client() {
+        let mut manager = TunnelManager::new();
+        manager.tunnels.insert(
+            "dead".to_string(),
+            ManagedTunnel::new(Box::new(NumberedTunnel {
+                url: "https://tunnel-0.example".to_string(),
+                restarts: 0,
+                running: false,
+            })),
+        );
+        assert!(!manager.get_tunnel("dead").unwrap().is_active);
+        assert!(!manager.list_tunnels()[0].is_active);
     }
 }
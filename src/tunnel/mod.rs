@@ -1,6 +1,13 @@
 pub mod cloudflare;
+pub mod command;
 pub mod config;
 pub mod manager;
+pub mod named;
+pub mod pool;
+pub mod provider;
 
+// Re-exported for library consumers; the binary does not use them directly
+#[allow(unused_imports)]
 pub use config::TunnelConfig;
-pub use manager::{TunnelInfo, TunnelManager};
+#[allow(unused_imports)]
+pub use manager::{DetachedTunnel, TunnelInfo, TunnelManager};
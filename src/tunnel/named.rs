@@ -0,0 +1,702 @@
+//! Cloudflare named tunnels, whose hostname survives restarts.
+//!
+//! A quick tunnel gets a random trycloudflare.com hostname every time its
+//! client starts, which breaks links already shared. With `[named_tunnel]`
+//! in the config, a session's tunnel is a named tunnel of the Cloudflare
+//! account `cloudflared tunnel login` authorized, reachable at the hostname
+//! `hostname_template` gives for the session's name. Starting one runs:
+//!
+//! 1. `cloudflared tunnel create`, unless the tunnel's credentials file is
+//!    already there from an earlier run
+//! 2. `cloudflared tunnel route dns --overwrite-dns`, pointing the hostname
+//!    at the tunnel
+//! 3. `cloudflared tunnel run`, the connector, kept running like a quick
+//!    tunnel's client
+//!
+//! Restarting the tunnel only restarts the connector, keeping the tunnel and
+//! its route. Stopping it deletes the tunnel with `cloudflared tunnel
+//! delete`. cloudflared cannot delete DNS records, so the hostname's record
+//! stays behind, answering with an error until a tunnel of the same name
+//! routes it again. A session created again under the same name therefore
+//! gets its old hostname back.
+//!
+//! When any step fails, the tunnel is started as a quick tunnel instead;
+//! see [`crate::tunnel::provider::FallbackTunnelChain::for_config`].
+//!
+//! Creating a tunnel and connecting to the edge is what takes time, so the
+//! tunnel pool keeps named tunnels that have no hostname yet
+//! ([`NamedTunnelConfig::for_pool`]). Leasing one only routes the session's
+//! hostname to it ([`NamedTunnel::route`]).
+
+use crate::error::{HtMcpError, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::SystemTime;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader, Lines};
+use tokio::process::{Child, Command};
+use tokio::time::{timeout, Duration};
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+/// Stands for a session's name in `hostname_template`
+pub const SESSION_NAME_PLACEHOLDER: &str = "{session-name}";
+
+/// Put in front of session names to name their tunnels, so the tunnels this
+/// server deletes are only ever its own
+const TUNNEL_NAME_PREFIX: &str = "ht-mcp-";
+
+/// What cloudflared logs once the connector reaches Cloudflare's edge
+const CONNECTED_MARKER: &str = "Registered tunnel connection";
+
+/// How long the connector may take to reach the edge
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The config's `[named_tunnel]` section
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NamedTunnelConfig {
+    /// Hostname of a session's tunnel, with `{session-name}` for the
+    /// session's `tunnelName`, e.g. `{session-name}.example.com`. Tunnels
+    /// are quick tunnels when unset.
+    pub hostname_template: Option<String>,
+    /// `cert.pem` written by `cloudflared tunnel login`; cloudflared looks
+    /// in its default places when unset
+    pub origin_cert: Option<PathBuf>,
+    /// Where the tunnels' credentials files are kept; `ht-mcp-tunnels` in
+    /// the system temp dir when unset
+    pub credentials_dir: Option<PathBuf>,
+}
+
+impl NamedTunnelConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.hostname_template.is_some()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let Some(template) = &self.hostname_template else {
+            return Ok(());
+        };
+        if template.matches(SESSION_NAME_PLACEHOLDER).count() != 1 {
+            return Err(HtMcpError::Config(format!(
+                "named_tunnel.hostname_template must contain {} once, got '{}'",
+                SESSION_NAME_PLACEHOLDER, template
+            )));
+        }
+        // Checked with a name that is itself valid, so only the template's
+        // own characters can fail
+        let hostname = template.replace(SESSION_NAME_PLACEHOLDER, "a");
+        if !is_hostname(&hostname) {
+            return Err(HtMcpError::Config(format!(
+                "named_tunnel.hostname_template must give a hostname such as {}.example.com, got '{}'",
+                SESSION_NAME_PLACEHOLDER, template
+            )));
+        }
+        Ok(())
+    }
+
+    /// The named tunnel of the session called `session_name`; `None` when
+    /// named tunnels are off
+    pub fn for_session(&self, session_name: &str) -> Result<Option<NamedTunnelSpec>> {
+        let Some(template) = &self.hostname_template else {
+            return Ok(None);
+        };
+        validate_session_name(session_name)?;
+        Ok(Some(NamedTunnelSpec {
+            name: format!("{}{}", TUNNEL_NAME_PREFIX, session_name),
+            hostname: Some(template.replace(SESSION_NAME_PLACEHOLDER, session_name)),
+            origin_cert: self.origin_cert.clone(),
+            credentials_dir: self.credentials_dir(),
+        }))
+    }
+
+    /// A named tunnel for the tunnel pool, with a name of its own and no
+    /// hostname until it is leased; `None` when named tunnels are off
+    pub fn for_pool(&self) -> Option<NamedTunnelSpec> {
+        self.is_enabled().then(|| NamedTunnelSpec {
+            name: format!(
+                "{}pool-{}",
+                TUNNEL_NAME_PREFIX,
+                &Uuid::new_v4().simple().to_string()[..12]
+            ),
+            hostname: None,
+            origin_cert: self.origin_cert.clone(),
+            credentials_dir: self.credentials_dir(),
+        })
+    }
+
+    fn credentials_dir(&self) -> PathBuf {
+        self.credentials_dir
+            .clone()
+            .unwrap_or_else(|| std::env::temp_dir().join("ht-mcp-tunnels"))
+    }
+}
+
+/// Checks that `name` can stand for `{session-name}`: one DNS label
+pub fn validate_session_name(name: &str) -> Result<()> {
+    if !is_dns_label(name) {
+        return Err(HtMcpError::InvalidRequest(format!(
+            "tunnelName must be 1 to 63 lowercase letters, digits and inner hyphens, got '{}'",
+            name
+        )));
+    }
+    Ok(())
+}
+
+fn is_dns_label(label: &str) -> bool {
+    (1..=63).contains(&label.len())
+        && label
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+        && !label.starts_with('-')
+        && !label.ends_with('-')
+}
+
+fn is_hostname(hostname: &str) -> bool {
+    hostname.len() <= 253
+        && hostname.split('.').count() >= 2
+        && hostname.split('.').all(is_dns_label)
+}
+
+/// One session's named tunnel, or a pooled one
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NamedTunnelSpec {
+    /// The tunnel's name in the Cloudflare account
+    pub name: String,
+    /// `None` for a pooled tunnel until it is leased
+    pub hostname: Option<String>,
+    pub origin_cert: Option<PathBuf>,
+    pub credentials_dir: PathBuf,
+}
+
+impl NamedTunnelSpec {
+    fn credentials_file(&self) -> PathBuf {
+        self.credentials_dir.join(format!("{}.json", self.name))
+    }
+}
+
+/// A named tunnel's connector, and the cloudflared commands that set the
+/// tunnel up and tear it down
+pub struct NamedTunnel {
+    spec: NamedTunnelSpec,
+    /// cloudflared, or a stand-in in tests
+    program: PathBuf,
+    child: Child,
+    /// Empty while the tunnel has no hostname
+    url: String,
+    local_port: u16,
+    /// `--protocol` the connector was started with, reused on restart
+    protocol: Option<String>,
+    created_at: SystemTime,
+}
+
+impl NamedTunnel {
+    /// Creates the tunnel unless its credentials are already there, routes
+    /// its hostname to it, if it has one, and starts its connector. When
+    /// routing or the connector fails, the tunnel is deleted again.
+    pub async fn start(
+        program: &Path,
+        spec: NamedTunnelSpec,
+        port: u16,
+        protocol: Option<&str>,
+    ) -> Result<Self> {
+        info!(
+            "Starting named tunnel {} for {} on port {}",
+            spec.name,
+            spec.hostname.as_deref().unwrap_or("the pool"),
+            port
+        );
+        let cli = Cloudflared {
+            program,
+            spec: &spec,
+        };
+        cli.create().await?;
+        let started = async {
+            if let Some(hostname) = &spec.hostname {
+                cli.route_dns(hostname).await?;
+            }
+            spawn_connector(program, &spec, port, protocol).await
+        }
+        .await;
+        let child = match started {
+            Ok(child) => child,
+            Err(e) => {
+                if let Err(delete_error) = cli.delete().await {
+                    warn!(
+                        "Failed to delete named tunnel {}: {}",
+                        spec.name, delete_error
+                    );
+                }
+                return Err(e);
+            }
+        };
+        let url = spec
+            .hostname
+            .as_ref()
+            .map(|hostname| format!("https://{}", hostname))
+            .unwrap_or_default();
+        info!("Named tunnel {} established: {}", spec.name, url);
+        Ok(Self {
+            spec,
+            program: program.to_path_buf(),
+            child,
+            url,
+            local_port: port,
+            protocol: protocol.map(str::to_string),
+            created_at: SystemTime::now(),
+        })
+    }
+
+    /// Replaces the connector with a fresh one; the tunnel, its route and
+    /// so its URL stay the same
+    pub async fn restart(&mut self) -> Result<()> {
+        self.stop_connector().await;
+        self.child = spawn_connector(
+            &self.program,
+            &self.spec,
+            self.local_port,
+            self.protocol.as_deref(),
+        )
+        .await?;
+        self.created_at = SystemTime::now();
+        Ok(())
+    }
+
+    /// Points `hostname` at the tunnel, making it the tunnel's URL, such as
+    /// a pooled tunnel's when it is leased
+    pub async fn route(&mut self, hostname: &str) -> Result<()> {
+        Cloudflared {
+            program: &self.program,
+            spec: &self.spec,
+        }
+        .route_dns(hostname)
+        .await?;
+        self.spec.hostname = Some(hostname.to_string());
+        self.url = format!("https://{}", hostname);
+        info!("Routed {} to named tunnel {}", hostname, self.spec.name);
+        Ok(())
+    }
+
+    pub fn is_running(&mut self) -> bool {
+        matches!(self.child.try_wait(), Ok(None))
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.local_port
+    }
+
+    pub fn created_at(&self) -> SystemTime {
+        self.created_at
+    }
+
+    /// Stops the connector and deletes the tunnel
+    pub async fn stop(&mut self) -> Result<()> {
+        self.stop_connector().await;
+        Cloudflared {
+            program: &self.program,
+            spec: &self.spec,
+        }
+        .delete()
+        .await
+    }
+
+    async fn stop_connector(&mut self) {
+        if self.is_running() {
+            info!("Stopping connector of named tunnel {}", self.spec.name);
+            if let Err(e) = self.child.start_kill() {
+                error!("Failed to kill cloudflared process: {}", e);
+            }
+            if let Err(e) = self.child.wait().await {
+                error!("Error waiting for cloudflared to exit: {}", e);
+            }
+        }
+    }
+}
+
+impl Drop for NamedTunnel {
+    fn drop(&mut self) {
+        if self.is_running() {
+            warn!(
+                "Named tunnel {} being dropped while still running, attempting to kill",
+                self.spec.name
+            );
+            if let Err(e) = self.child.start_kill() {
+                error!("Failed to kill cloudflared process in Drop: {}", e);
+            }
+        }
+    }
+}
+
+/// The one-off cloudflared commands for a tunnel
+struct Cloudflared<'a> {
+    program: &'a Path,
+    spec: &'a NamedTunnelSpec,
+}
+
+impl Cloudflared<'_> {
+    /// `cloudflared tunnel create`, writing the credentials file the
+    /// connector needs; skipped when that file exists
+    async fn create(&self) -> Result<()> {
+        let credentials = self.spec.credentials_file();
+        if credentials.exists() {
+            debug!("Reusing named tunnel {}", self.spec.name);
+            return Ok(());
+        }
+        std::fs::create_dir_all(&self.spec.credentials_dir).map_err(|e| {
+            HtMcpError::Internal(format!(
+                "Failed to create {}: {}",
+                self.spec.credentials_dir.display(),
+                e
+            ))
+        })?;
+        self.run(&[
+            "create",
+            "--credentials-file",
+            &credentials.display().to_string(),
+            &self.spec.name,
+        ])
+        .await
+    }
+
+    /// `cloudflared tunnel route dns`, taking `hostname` over from whatever
+    /// record it had
+    async fn route_dns(&self, hostname: &str) -> Result<()> {
+        self.run(&["route", "dns", "--overwrite-dns", &self.spec.name, hostname])
+            .await
+    }
+
+    /// `cloudflared tunnel delete`, and the credentials file with it
+    async fn delete(&self) -> Result<()> {
+        self.run(&["delete", "-f", &self.spec.name]).await?;
+        let credentials = self.spec.credentials_file();
+        if let Err(e) = std::fs::remove_file(&credentials) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!("Failed to remove {}: {}", credentials.display(), e);
+            }
+        }
+        info!("Deleted named tunnel {}", self.spec.name);
+        Ok(())
+    }
+
+    /// Runs `cloudflared tunnel [--origincert CERT] ARGS`
+    async fn run(&self, args: &[&str]) -> Result<()> {
+        let mut cmd = Command::new(self.program);
+        cmd.arg("tunnel");
+        if let Some(cert) = &self.spec.origin_cert {
+            cmd.arg("--origincert").arg(cert);
+        }
+        cmd.args(args);
+        cmd.stdin(Stdio::null());
+        cmd.kill_on_drop(true);
+        debug!("Running cloudflared tunnel {}", args.join(" "));
+        let output = cmd.output().await.map_err(|e| {
+            HtMcpError::Internal(format!("Failed to spawn {}: {}", self.program.display(), e))
+        })?;
+        if !output.status.success() {
+            return Err(HtMcpError::NetworkError(format!(
+                "cloudflared tunnel {} failed ({}): {}",
+                args[0],
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Starts the connector and waits until it reached Cloudflare's edge
+async fn spawn_connector(
+    program: &Path,
+    spec: &NamedTunnelSpec,
+    port: u16,
+    protocol: Option<&str>,
+) -> Result<Child> {
+    let mut cmd = Command::new(program);
+    cmd.args(connector_args(spec, port, protocol));
+    cmd.stdout(Stdio::null());
+    cmd.stderr(Stdio::piped());
+    cmd.kill_on_drop(true);
+    let mut child = cmd.spawn().map_err(|e| {
+        HtMcpError::Internal(format!("Failed to spawn {}: {}", program.display(), e))
+    })?;
+    let stderr = child
+        .stderr
+        .take()
+        .ok_or_else(|| HtMcpError::Internal("Failed to capture cloudflared stderr".to_string()))?;
+
+    let mut lines = BufReader::new(stderr).lines();
+    timeout(CONNECT_TIMEOUT, wait_connected(&mut lines))
+        .await
+        .map_err(|_| {
+            HtMcpError::NetworkError(format!(
+                "Named tunnel {} did not connect within {}s",
+                spec.name,
+                CONNECT_TIMEOUT.as_secs()
+            ))
+        })??;
+
+    // Keep reading so cloudflared never blocks on an unread pipe
+    tokio::spawn(async move {
+        while let Ok(Some(line)) = lines.next_line().await {
+            debug!("cloudflared output: {}", line);
+        }
+    });
+    Ok(child)
+}
+
+/// Arguments for the connector: the tunnel's one route goes to `port`
+fn connector_args(spec: &NamedTunnelSpec, port: u16, protocol: Option<&str>) -> Vec<String> {
+    let mut args = vec![
+        "tunnel".to_string(),
+        "--url".to_string(),
+        format!("http://localhost:{}", port),
+    ];
+    if let Some(protocol) = protocol {
+        args.push("--protocol".to_string());
+        args.push(protocol.to_string());
+    }
+    args.extend([
+        "run".to_string(),
+        "--credentials-file".to_string(),
+        spec.credentials_file().display().to_string(),
+        spec.name.clone(),
+    ]);
+    args
+}
+
+/// Reads cloudflared's stderr up to the line saying it connected
+async fn wait_connected(reader: &mut Lines<impl AsyncBufRead + Unpin>) -> Result<()> {
+    while let Some(line) = reader
+        .next_line()
+        .await
+        .map_err(|e| HtMcpError::Internal(format!("Failed to read cloudflared output: {}", e)))?
+    {
+        debug!("cloudflared output: {}", line);
+        if line.contains(CONNECTED_MARKER) {
+            return Ok(());
+        }
+    }
+    Err(HtMcpError::NetworkError(
+        "cloudflared exited before the named tunnel connected".to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn named_config(dir: &Path) -> NamedTunnelConfig {
+        NamedTunnelConfig {
+            hostname_template: Some("{session-name}.example.com".to_string()),
+            origin_cert: None,
+            credentials_dir: Some(dir.to_path_buf()),
+        }
+    }
+
+    /// A stand-in for cloudflared that logs its arguments, fails `route`
+    /// when `fail_route` is set, and runs connectors until killed
+    fn fake_cloudflared(dir: &Path, fail_route: bool) -> (PathBuf, PathBuf) {
+        use std::os::unix::fs::PermissionsExt;
+
+        let log = dir.join("calls.log");
+        let program = dir.join("cloudflared");
+        let route = if fail_route {
+            "echo 'failed to add route: code 1003' >&2; exit 1"
+        } else {
+            "exit 0"
+        };
+        std::fs::write(
+            &program,
+            format!(
+                "#!/bin/sh\necho \"$*\" >> '{}'\ncase \"$*\" in\n  *' route '*) {} ;;\n  *' run '*) echo 'INF Registered tunnel connection connIndex=0' >&2; exec sleep 60 ;;\nesac\n",
+                log.display(),
+                route
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o755)).unwrap();
+        (program, log)
+    }
+
+    fn calls(log: &Path) -> Vec<String> {
+        std::fs::read_to_string(log)
+            .unwrap_or_default()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn test_validate() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(named_config(dir.path()).validate().is_ok());
+        assert!(NamedTunnelConfig::default().validate().is_ok());
+        for template in [
+            "example.com",
+            "{session-name}",
+            "{session-name}.{session-name}.example.com",
+            "{session-name}.exa mple.com",
+        ] {
+            let config = NamedTunnelConfig {
+                hostname_template: Some(template.to_string()),
+                ..NamedTunnelConfig::default()
+            };
+            assert!(config.validate().is_err(), "{}", template);
+        }
+    }
+
+    #[test]
+    fn test_for_session() {
+        let dir = tempfile::tempdir().unwrap();
+        let spec = named_config(dir.path())
+            .for_session("demo")
+            .unwrap()
+            .unwrap();
+        assert_eq!(spec.name, "ht-mcp-demo");
+        assert_eq!(spec.hostname.as_deref(), Some("demo.example.com"));
+        assert_eq!(spec.credentials_file(), dir.path().join("ht-mcp-demo.json"));
+
+        assert!(NamedTunnelConfig::default()
+            .for_session("demo")
+            .unwrap()
+            .is_none());
+        for name in ["", "Demo", "-demo", "demo-", "a.b", &"a".repeat(64)] {
+            assert!(
+                named_config(dir.path()).for_session(name).is_err(),
+                "{}",
+                name
+            );
+        }
+        // Session ids are UUIDs, which are valid names
+        assert!(validate_session_name(&uuid::Uuid::new_v4().to_string()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_lifecycle_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let (program, log) = fake_cloudflared(dir.path(), false);
+        let spec = named_config(dir.path())
+            .for_session("demo")
+            .unwrap()
+            .unwrap();
+        let credentials = spec.credentials_file().display().to_string();
+
+        let mut tunnel = NamedTunnel::start(&program, spec.clone(), 8080, Some("http2"))
+            .await
+            .unwrap();
+        assert_eq!(tunnel.url(), "https://demo.example.com");
+        assert!(tunnel.is_running());
+        let run = format!(
+            "tunnel --url http://localhost:8080 --protocol http2 run --credentials-file {} ht-mcp-demo",
+            credentials
+        );
+        assert_eq!(
+            calls(&log),
+            [
+                format!(
+                    "tunnel create --credentials-file {} ht-mcp-demo",
+                    credentials
+                ),
+                "tunnel route dns --overwrite-dns ht-mcp-demo demo.example.com".to_string(),
+                run.clone(),
+            ]
+        );
+
+        // A restart only replaces the connector
+        tunnel.restart().await.unwrap();
+        assert_eq!(tunnel.url(), "https://demo.example.com");
+        assert!(tunnel.is_running());
+        assert_eq!(calls(&log)[3..], [run]);
+
+        tunnel.stop().await.unwrap();
+        assert!(!tunnel.is_running());
+        assert_eq!(calls(&log)[4..], ["tunnel delete -f ht-mcp-demo"]);
+    }
+
+    #[tokio::test]
+    async fn test_pooled_tunnels_are_routed_when_leased() {
+        let dir = tempfile::tempdir().unwrap();
+        let (program, log) = fake_cloudflared(dir.path(), false);
+        let spec = named_config(dir.path()).for_pool().unwrap();
+        assert!(spec.name.starts_with("ht-mcp-pool-"), "{}", spec.name);
+        assert_ne!(named_config(dir.path()).for_pool().unwrap().name, spec.name);
+        assert!(NamedTunnelConfig::default().for_pool().is_none());
+        let name = spec.name.clone();
+
+        // Created and connected, but not routed anywhere
+        let mut tunnel = NamedTunnel::start(&program, spec, 8080, None)
+            .await
+            .unwrap();
+        assert_eq!(tunnel.url(), "");
+        let calls_before = calls(&log);
+        assert_eq!(calls_before.len(), 2);
+        assert!(calls_before[0].starts_with("tunnel create "));
+        assert!(calls_before[1].contains(" run "));
+
+        tunnel.route("demo.example.com").await.unwrap();
+        assert_eq!(tunnel.url(), "https://demo.example.com");
+        assert_eq!(
+            calls(&log)[2..],
+            [format!(
+                "tunnel route dns --overwrite-dns {} demo.example.com",
+                name
+            )]
+        );
+        tunnel.stop().await.unwrap();
+        assert_eq!(calls(&log)[3..], [format!("tunnel delete -f {}", name)]);
+    }
+
+    #[tokio::test]
+    async fn test_existing_credentials_are_reused() {
+        let dir = tempfile::tempdir().unwrap();
+        let (program, log) = fake_cloudflared(dir.path(), false);
+        let spec = NamedTunnelSpec {
+            origin_cert: Some(dir.path().join("cert.pem")),
+            ..named_config(dir.path())
+                .for_session("demo")
+                .unwrap()
+                .unwrap()
+        };
+        std::fs::write(spec.credentials_file(), "{}").unwrap();
+
+        let mut tunnel = NamedTunnel::start(&program, spec, 8080, None)
+            .await
+            .unwrap();
+        let calls = calls(&log);
+        assert_eq!(calls.len(), 2);
+        assert_eq!(
+            calls[0],
+            format!(
+                "tunnel --origincert {} route dns --overwrite-dns ht-mcp-demo demo.example.com",
+                dir.path().join("cert.pem").display()
+            )
+        );
+        assert!(calls[1].contains(" run "), "{}", calls[1]);
+        tunnel.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_route_failure_deletes_the_tunnel() {
+        let dir = tempfile::tempdir().unwrap();
+        let (program, log) = fake_cloudflared(dir.path(), true);
+        let spec = named_config(dir.path())
+            .for_session("demo")
+            .unwrap()
+            .unwrap();
+
+        let err = NamedTunnel::start(&program, spec, 8080, None)
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("code 1003"), "{}", err);
+        let calls = calls(&log);
+        assert_eq!(calls.len(), 3);
+        assert!(calls[0].starts_with("tunnel create "), "{}", calls[0]);
+        assert!(calls[1].starts_with("tunnel route dns "), "{}", calls[1]);
+        assert_eq!(calls[2], "tunnel delete -f ht-mcp-demo");
+    }
+}
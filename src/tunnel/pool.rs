@@ -0,0 +1,280 @@
+//! Tunnels started ahead of time, so a session asking for one gets it
+//! without waiting seconds for the provider's client to come up.
+//!
+//! The config's `[tunnel_pool]` sets how many idle tunnels to keep. A
+//! pooled tunnel does not forward to a session directly but to a [`Relay`]
+//! on a port of its own, which is pointed at the web server of the session
+//! leasing the tunnel. Closing the session stops the tunnel, as its URL was
+//! handed out and must not reach the next session; the pool refills in the
+//! background. A request finding the pool empty starts a tunnel of its own
+//! as before. Pooled tunnels are started with the server's default
+//! provider, so a session naming another one is never served from the pool.
+//!
+//! With `[named_tunnel]`, pooled tunnels are named tunnels without a
+//! hostname, and leasing one routes the session's hostname to it.
+
+use crate::error::{HtMcpError, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tracing::debug;
+
+/// How often the background task tops the pool up
+pub const REFILL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Largest accepted pool size; each pooled tunnel is a live client process
+/// holding a public URL
+const MAX_POOL_SIZE: usize = 8;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TunnelPoolConfig {
+    /// Idle tunnels to keep ready; 0 disables the pool
+    pub size: usize,
+}
+
+impl TunnelPoolConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.size > MAX_POOL_SIZE {
+            return Err(HtMcpError::Config(format!(
+                "tunnel_pool.size must be at most {}, got {}",
+                MAX_POOL_SIZE, self.size
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// A local listener forwarding each connection to the port it currently
+/// points at, and refusing connections while it points nowhere
+pub struct Relay {
+    port: u16,
+    /// 0 while pointing nowhere
+    target: Arc<AtomicU16>,
+    task: JoinHandle<()>,
+}
+
+impl Relay {
+    /// Listens on a free loopback port, pointing nowhere
+    pub async fn bind() -> Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let port = listener.local_addr()?.port();
+        let target = Arc::new(AtomicU16::new(0));
+        let task = tokio::spawn(forward(listener, target.clone()));
+        Ok(Self { port, target, task })
+    }
+
+    /// The port the tunnel in front of the relay forwards to
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn target(&self) -> Option<u16> {
+        match self.target.load(Ordering::Relaxed) {
+            0 => None,
+            port => Some(port),
+        }
+    }
+
+    /// Sends new connections to `target`; ones already open stay where they
+    /// are
+    pub fn point_at(&self, target: Option<u16>) {
+        self.target.store(target.unwrap_or(0), Ordering::Relaxed);
+    }
+}
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn forward(listener: TcpListener, target: Arc<AtomicU16>) {
+    loop {
+        let mut inbound = match listener.accept().await {
+            Ok((inbound, _)) => inbound,
+            Err(e) => {
+                // Such as running out of file descriptors; give them time
+                debug!("Relay failed to accept a connection: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let port = target.load(Ordering::Relaxed);
+        if port == 0 {
+            continue;
+        }
+        tokio::spawn(async move {
+            // `localhost`, as web servers may listen on IPv4, IPv6 or both
+            match TcpStream::connect(("localhost", port)).await {
+                Ok(mut outbound) => {
+                    let _ = tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await;
+                }
+                Err(e) => debug!("Relay could not reach port {}: {}", port, e),
+            }
+        });
+    }
+}
+
+/// Which of a manager's tunnels are pooled, by id, and how often a request
+/// found one idle
+#[derive(Debug, Default)]
+pub struct TunnelPool {
+    /// Oldest first, with when each joined the pool
+    idle: VecDeque<(String, Instant)>,
+    leased: HashMap<String, Instant>,
+    hits: u64,
+    misses: u64,
+}
+
+impl TunnelPool {
+    pub fn add(&mut self, tunnel_id: String) {
+        self.idle.push_back((tunnel_id, Instant::now()));
+    }
+
+    /// The oldest idle tunnel whose client `running` says is still up,
+    /// counting a hit or a miss; the dead ones passed over are returned for
+    /// the caller to remove
+    pub fn lease(
+        &mut self,
+        mut running: impl FnMut(&str) -> bool,
+    ) -> (Option<String>, Vec<String>) {
+        let mut dead = Vec::new();
+        while let Some((tunnel_id, pooled_at)) = self.idle.pop_front() {
+            if running(&tunnel_id) {
+                self.hits += 1;
+                self.leased.insert(tunnel_id.clone(), pooled_at);
+                return (Some(tunnel_id), dead);
+            }
+            dead.push(tunnel_id);
+        }
+        self.misses += 1;
+        (None, dead)
+    }
+
+    /// Drops a tunnel that stopped from the pool
+    pub fn forget(&mut self, tunnel_id: &str) {
+        self.idle.retain(|(id, _)| id != tunnel_id);
+        self.leased.remove(tunnel_id);
+    }
+
+    pub fn is_leased(&self, tunnel_id: &str) -> bool {
+        self.leased.contains_key(tunnel_id)
+    }
+
+    /// Tunnels missing to reach `size` idle ones
+    pub fn deficit(&self, size: usize) -> usize {
+        size.saturating_sub(self.idle.len())
+    }
+
+    /// Whether a tunnel is idle or leased and how long it has been pooled;
+    /// `None` if it is not pooled
+    pub fn entry(&self, tunnel_id: &str) -> Option<Value> {
+        let (state, pooled_at) = match self.leased.get(tunnel_id) {
+            Some(pooled_at) => ("leased", pooled_at),
+            None => ("idle", &self.idle.iter().find(|(id, _)| id == tunnel_id)?.1),
+        };
+        Some(json!({
+            "state": state,
+            "ageSecs": pooled_at.elapsed().as_secs()
+        }))
+    }
+
+    pub fn summary(&self, size: usize) -> Value {
+        json!({
+            "target": size,
+            "idle": self.idle.len(),
+            "leased": self.leased.len(),
+            "hits": self.hits,
+            "misses": self.misses
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_validate() {
+        assert!(TunnelPoolConfig { size: 2 }.validate().is_ok());
+        assert!(TunnelPoolConfig {
+            size: MAX_POOL_SIZE + 1
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn test_lease_and_exhaustion() {
+        let mut pool = TunnelPool::default();
+        pool.add("a".to_string());
+        pool.add("b".to_string());
+        pool.add("c".to_string());
+        assert_eq!(pool.deficit(4), 1);
+
+        // A tunnel whose client died is passed over and reported
+        let (leased, dead) = pool.lease(|id| id != "a");
+        assert_eq!(leased.as_deref(), Some("b"));
+        assert_eq!(dead, ["a"]);
+        assert!(pool.is_leased("b"));
+        assert_eq!(pool.entry("b").unwrap()["state"], "leased");
+        assert_eq!(pool.entry("c").unwrap()["state"], "idle");
+        assert!(pool.entry("a").is_none());
+
+        assert_eq!(pool.lease(|_| true).0.as_deref(), Some("c"));
+        assert_eq!(pool.lease(|_| true), (None, Vec::new()));
+        assert_eq!(
+            pool.summary(3),
+            json!({"target": 3, "idle": 0, "leased": 2, "hits": 2, "misses": 1})
+        );
+
+        // Leased tunnels are stopped and forgotten, never leased again
+        pool.forget("b");
+        pool.forget("c");
+        assert!(!pool.is_leased("b"));
+        assert_eq!(pool.summary(3)["leased"], 0);
+        assert_eq!(pool.deficit(3), 3);
+        pool.add("d".to_string());
+        assert_eq!(pool.lease(|_| true).0.as_deref(), Some("d"));
+    }
+
+    #[tokio::test]
+    async fn test_relay_forwards_to_its_target() {
+        let upstream = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let upstream_port = upstream.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = upstream.accept().await {
+                let _ = stream.write_all(b"upstream").await;
+            }
+        });
+        let relay = Relay::bind().await.unwrap();
+        let read_through = || async {
+            let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, relay.port()))
+                .await
+                .unwrap();
+            let mut received = Vec::new();
+            let _ = tokio::time::timeout(Duration::from_secs(2), stream.read_to_end(&mut received))
+                .await;
+            received
+        };
+
+        // Pointing nowhere, connections are closed at once
+        assert_eq!(relay.target(), None);
+        assert!(read_through().await.is_empty());
+
+        relay.point_at(Some(upstream_port));
+        assert_eq!(relay.target(), Some(upstream_port));
+        assert_eq!(read_through().await, b"upstream");
+
+        relay.point_at(None);
+        assert!(read_through().await.is_empty());
+    }
+}
@@ -0,0 +1,496 @@
+use crate::error::{HtMcpError, Result};
+use crate::tunnel::cloudflare::CloudflareTunnel;
+use crate::tunnel::command::{self, CommandTunnel, TunnelCommand};
+use crate::tunnel::config::{TunnelConfig, AUTO_TUNNEL_PROVIDERS};
+use crate::tunnel::named::NamedTunnel;
+use async_trait::async_trait;
+use std::path::Path;
+use std::time::SystemTime;
+use tokio::time::Duration;
+use tracing::{info, warn};
+
+/// A running tunnel, whichever provider started it
+#[async_trait]
+pub trait Tunnel: Send + Sync {
+    /// Name of the provider that started the tunnel
+    fn provider(&self) -> &str;
+    fn url(&self) -> &str;
+    fn local_port(&self) -> u16;
+    fn created_at(&self) -> SystemTime;
+    fn is_running(&mut self) -> bool;
+    /// Whether `url` stays the same across restarts
+    fn hostname_stable(&self) -> bool {
+        false
+    }
+    /// Points `hostname` at the tunnel, making it the tunnel's URL; only
+    /// named tunnels can take a hostname
+    async fn route(&mut self, hostname: &str) -> Result<()> {
+        Err(HtMcpError::InvalidRequest(format!(
+            "{} tunnels cannot be routed to {}",
+            self.provider(),
+            hostname
+        )))
+    }
+    /// Relaunches the tunnel with the same provider and port
+    async fn restart(&mut self) -> Result<()>;
+    async fn stop(&mut self) -> Result<()>;
+}
+
+/// Starts tunnels
+#[async_trait]
+pub trait TunnelProvider: Send + Sync {
+    async fn start(&self, config: &TunnelConfig) -> Result<Box<dyn Tunnel>>;
+}
+
+/// Starts tunnels with one named provider
+#[async_trait]
+pub trait TunnelProviderFactory: Send + Sync {
+    fn name(&self) -> &'static str;
+    async fn create(&self, config: &TunnelConfig) -> Result<Box<dyn Tunnel>>;
+}
+
+/// Tries each provider in order and returns the first tunnel that comes
+/// up, for networks where some providers are blocked
+pub struct FallbackTunnelChain {
+    pub providers: Vec<Box<dyn TunnelProviderFactory>>,
+}
+
+impl FallbackTunnelChain {
+    /// The chain for `TunnelConfig.provider`: `auto` tries every provider,
+    /// any other name just that one
+    pub fn for_provider(name: &str) -> Result<Self> {
+        let names: &[&str] = if name == "auto" {
+            &AUTO_TUNNEL_PROVIDERS
+        } else {
+            std::slice::from_ref(&name)
+        };
+        let providers = names
+            .iter()
+            .map(|name| {
+                factory(name).ok_or_else(|| {
+                    HtMcpError::InvalidRequest(format!("Unsupported tunnel provider '{}'", name))
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { providers })
+    }
+
+    /// The chain for `config`: [`FallbackTunnelChain::for_provider`], with
+    /// a session's named tunnel tried first when its provider is
+    /// Cloudflare, so a named tunnel that cannot be set up falls back to a
+    /// quick tunnel. A pooled named tunnel, which gets its hostname only
+    /// when leased, has no fallback: a quick tunnel could not take one.
+    pub fn for_config(config: &TunnelConfig) -> Result<Self> {
+        let provider = config.provider.as_deref().unwrap_or("cloudflare");
+        let mut chain = Self::for_provider(provider)?;
+        match &config.named {
+            Some(named) if named.hostname.is_none() => {
+                chain.providers = vec![Box::new(NamedFactory)];
+            }
+            Some(_) if matches!(provider, "cloudflare" | "auto") => {
+                chain.providers.insert(0, Box::new(NamedFactory));
+            }
+            _ => {}
+        }
+        Ok(chain)
+    }
+}
+
+#[async_trait]
+impl TunnelProvider for FallbackTunnelChain {
+    async fn start(&self, config: &TunnelConfig) -> Result<Box<dyn Tunnel>> {
+        let mut failures = Vec::new();
+        for provider in &self.providers {
+            match provider.create(config).await {
+                Ok(tunnel) => return Ok(tunnel),
+                Err(e) => {
+                    if self.providers.len() > 1 {
+                        warn!("Tunnel provider {} failed: {}", provider.name(), e);
+                    }
+                    failures.push((provider.name(), e));
+                }
+            }
+        }
+        if failures.len() == 1 {
+            return Err(failures.pop().unwrap().1);
+        }
+        let network = failures
+            .iter()
+            .any(|(_, e)| matches!(e, HtMcpError::NetworkError(_)));
+        let message = format!(
+            "No tunnel provider succeeded: {}",
+            failures
+                .iter()
+                .map(|(name, e)| format!("{}: {}", name, e))
+                .collect::<Vec<_>>()
+                .join("; ")
+        );
+        Err(if network {
+            HtMcpError::NetworkError(message)
+        } else {
+            HtMcpError::Internal(message)
+        })
+    }
+}
+
+/// The factory for a provider name, other than `auto`
+pub fn factory(name: &str) -> Option<Box<dyn TunnelProviderFactory>> {
+    match name {
+        "cloudflare" => Some(Box::new(CloudflareFactory)),
+        "bore" => Some(Box::new(CommandFactory {
+            name: "bore",
+            command: |config| Ok(TunnelCommand::bore(config.port)),
+        })),
+        "localhost.run" => Some(Box::new(CommandFactory {
+            name: "localhost.run",
+            command: localhost_run_command,
+        })),
+        _ => None,
+    }
+}
+
+struct CloudflareFactory;
+
+#[async_trait]
+impl TunnelProviderFactory for CloudflareFactory {
+    fn name(&self) -> &'static str {
+        "cloudflare"
+    }
+
+    async fn create(&self, config: &TunnelConfig) -> Result<Box<dyn Tunnel>> {
+        let tunnel = CloudflareTunnel::new_simple(
+            config.port,
+            config.protocol.as_deref(),
+            config.cloudflared_output_level()?,
+        )
+        .await?;
+        Ok(Box::new(tunnel))
+    }
+}
+
+/// Cloudflare named tunnels, for configs with a `named` tunnel
+struct NamedFactory;
+
+#[async_trait]
+impl TunnelProviderFactory for NamedFactory {
+    fn name(&self) -> &'static str {
+        "cloudflare-named"
+    }
+
+    async fn create(&self, config: &TunnelConfig) -> Result<Box<dyn Tunnel>> {
+        let spec = config.named.clone().ok_or_else(|| {
+            HtMcpError::Config("No named tunnel in the tunnel config".to_string())
+        })?;
+        let program = config
+            .bin_path
+            .as_deref()
+            .unwrap_or(Path::new("cloudflared"));
+        let tunnel =
+            NamedTunnel::start(program, spec, config.port, config.protocol.as_deref()).await?;
+        Ok(Box::new(tunnel))
+    }
+}
+
+/// Providers run through a client command, see [`TunnelCommand`]
+struct CommandFactory {
+    name: &'static str,
+    command: fn(&TunnelConfig) -> Result<TunnelCommand>,
+}
+
+fn localhost_run_command(config: &TunnelConfig) -> Result<TunnelCommand> {
+    let host_key = config.localhost_run_host_key.as_deref().ok_or_else(|| {
+        HtMcpError::Config(
+            "localhost.run tunnels need localhost_run_host_key in the config".to_string(),
+        )
+    })?;
+    let known_hosts = command::localhost_run_known_hosts(host_key)?;
+    Ok(TunnelCommand::localhost_run(config.port, &known_hosts))
+}
+
+#[async_trait]
+impl TunnelProviderFactory for CommandFactory {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    async fn create(&self, config: &TunnelConfig) -> Result<Box<dyn Tunnel>> {
+        info!("Creating {} tunnel on port {}", self.name, config.port);
+        let startup_timeout = Duration::from_secs(config.timeout_secs.unwrap_or(30));
+        let tunnel =
+            CommandTunnel::start((self.command)(config)?, config.port, startup_timeout).await?;
+        Ok(Box::new(tunnel))
+    }
+}
+
+#[async_trait]
+impl Tunnel for CloudflareTunnel {
+    fn provider(&self) -> &str {
+        "cloudflare"
+    }
+
+    fn url(&self) -> &str {
+        CloudflareTunnel::url(self)
+    }
+
+    fn local_port(&self) -> u16 {
+        CloudflareTunnel::local_port(self)
+    }
+
+    fn created_at(&self) -> SystemTime {
+        CloudflareTunnel::created_at(self)
+    }
+
+    fn is_running(&mut self) -> bool {
+        CloudflareTunnel::is_running(self)
+    }
+
+    async fn restart(&mut self) -> Result<()> {
+        CloudflareTunnel::restart(self).await
+    }
+
+    async fn stop(&mut self) -> Result<()> {
+        CloudflareTunnel::stop(self).await
+    }
+}
+
+#[async_trait]
+impl Tunnel for NamedTunnel {
+    fn provider(&self) -> &str {
+        "cloudflare"
+    }
+
+    fn url(&self) -> &str {
+        NamedTunnel::url(self)
+    }
+
+    fn local_port(&self) -> u16 {
+        NamedTunnel::local_port(self)
+    }
+
+    fn created_at(&self) -> SystemTime {
+        NamedTunnel::created_at(self)
+    }
+
+    fn is_running(&mut self) -> bool {
+        NamedTunnel::is_running(self)
+    }
+
+    fn hostname_stable(&self) -> bool {
+        true
+    }
+
+    async fn route(&mut self, hostname: &str) -> Result<()> {
+        NamedTunnel::route(self, hostname).await
+    }
+
+    async fn restart(&mut self) -> Result<()> {
+        NamedTunnel::restart(self).await
+    }
+
+    async fn stop(&mut self) -> Result<()> {
+        NamedTunnel::stop(self).await
+    }
+}
+
+#[async_trait]
+impl Tunnel for CommandTunnel {
+    fn provider(&self) -> &str {
+        CommandTunnel::provider(self)
+    }
+
+    fn url(&self) -> &str {
+        CommandTunnel::url(self)
+    }
+
+    fn local_port(&self) -> u16 {
+        CommandTunnel::local_port(self)
+    }
+
+    fn created_at(&self) -> SystemTime {
+        CommandTunnel::created_at(self)
+    }
+
+    fn is_running(&mut self) -> bool {
+        CommandTunnel::is_running(self)
+    }
+
+    async fn restart(&mut self) -> Result<()> {
+        CommandTunnel::restart(self).await
+    }
+
+    async fn stop(&mut self) -> Result<()> {
+        CommandTunnel::stop(self).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::named::NamedTunnelConfig;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    struct FakeTunnel {
+        provider: &'static str,
+        port: u16,
+    }
+
+    #[async_trait]
+    impl Tunnel for FakeTunnel {
+        fn provider(&self) -> &str {
+            self.provider
+        }
+
+        fn url(&self) -> &str {
+            "https://example.test"
+        }
+
+        fn local_port(&self) -> u16 {
+            self.port
+        }
+
+        fn created_at(&self) -> SystemTime {
+            SystemTime::UNIX_EPOCH
+        }
+
+        fn is_running(&mut self) -> bool {
+            true
+        }
+
+        async fn restart(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        async fn stop(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    type MakeError = fn(String) -> HtMcpError;
+
+    /// Fails with `error` when set, counting attempts
+    struct FakeFactory {
+        name: &'static str,
+        error: Option<MakeError>,
+        attempts: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl TunnelProviderFactory for FakeFactory {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        async fn create(&self, config: &TunnelConfig) -> Result<Box<dyn Tunnel>> {
+            self.attempts.fetch_add(1, Ordering::SeqCst);
+            match self.error {
+                Some(error) => Err(error(format!("{} is blocked", self.name))),
+                None => Ok(Box::new(FakeTunnel {
+                    provider: self.name,
+                    port: config.port,
+                })),
+            }
+        }
+    }
+
+    fn fake_chain(
+        providers: &[(&'static str, Option<MakeError>)],
+    ) -> (FallbackTunnelChain, Arc<AtomicUsize>) {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let providers = providers
+            .iter()
+            .map(|&(name, error)| {
+                Box::new(FakeFactory {
+                    name,
+                    error,
+                    attempts: attempts.clone(),
+                }) as Box<dyn TunnelProviderFactory>
+            })
+            .collect();
+        (FallbackTunnelChain { providers }, attempts)
+    }
+
+    #[tokio::test]
+    async fn test_first_success_wins() {
+        let (chain, attempts) = fake_chain(&[
+            ("cloudflare", Some(HtMcpError::NetworkError)),
+            ("bore", None),
+            ("localhost.run", None),
+        ]);
+        let tunnel = chain.start(&TunnelConfig::new(8080)).await.unwrap();
+        assert_eq!(tunnel.provider(), "bore");
+        assert_eq!(tunnel.local_port(), 8080);
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_all_failures_are_reported() {
+        let (chain, _) = fake_chain(&[
+            ("cloudflare", Some(HtMcpError::Internal)),
+            ("bore", Some(HtMcpError::NetworkError)),
+        ]);
+        let err = chain.start(&TunnelConfig::new(8080)).await.err().unwrap();
+        assert!(matches!(err, HtMcpError::NetworkError(_)));
+        let message = err.to_string();
+        assert!(message.contains("cloudflare: "), "{}", message);
+        assert!(message.contains("bore is blocked"), "{}", message);
+
+        // A single provider's error is passed through unchanged
+        let (chain, _) = fake_chain(&[("cloudflare", Some(HtMcpError::Internal))]);
+        let err = chain.start(&TunnelConfig::new(8080)).await.err().unwrap();
+        assert_eq!(err.to_string(), "Internal error: cloudflare is blocked");
+    }
+
+    #[test]
+    fn test_for_provider() {
+        let names = |chain: FallbackTunnelChain| {
+            chain
+                .providers
+                .iter()
+                .map(|provider| provider.name())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(FallbackTunnelChain::for_provider("auto").unwrap()),
+            AUTO_TUNNEL_PROVIDERS
+        );
+        assert_eq!(
+            names(FallbackTunnelChain::for_provider("bore").unwrap()),
+            ["bore"]
+        );
+        assert!(FallbackTunnelChain::for_provider("ngrok").is_err());
+    }
+
+    #[test]
+    fn test_for_config_tries_named_tunnels_first() {
+        let names = |config: &TunnelConfig| {
+            FallbackTunnelChain::for_config(config)
+                .unwrap()
+                .providers
+                .iter()
+                .map(|provider| provider.name())
+                .collect::<Vec<_>>()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let named_config = NamedTunnelConfig {
+            hostname_template: Some("{session-name}.example.com".to_string()),
+            origin_cert: None,
+            credentials_dir: Some(dir.path().to_path_buf()),
+        };
+        let named = named_config.for_session("demo").unwrap().unwrap();
+
+        let config = TunnelConfig::new(8080);
+        assert_eq!(names(&config), ["cloudflare"]);
+        let config = config.with_named(named);
+        assert_eq!(names(&config), ["cloudflare-named", "cloudflare"]);
+        let config = config.with_provider("auto".to_string());
+        assert_eq!(names(&config), ["cloudflare-named", "cloudflare"]);
+        // A session naming another provider gets just that one
+        let config = config.with_provider("bore".to_string());
+        assert_eq!(names(&config), ["bore"]);
+
+        // A pooled named tunnel could not fall back
+        let config = TunnelConfig::new(8080).with_named(named_config.for_pool().unwrap());
+        assert_eq!(names(&config), ["cloudflare-named"]);
+    }
+}
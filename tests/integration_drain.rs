@@ -0,0 +1,193 @@
+//! `ht_drain`: new work is refused, existing sessions keep working, and what
+//! is left is closed when the grace period ends.
+
+use ht_mcp::ht_integration::events::{SessionEvent, TerminationReason};
+use ht_mcp::mcp::server::HtMcpServer;
+use ht_mcp::{HtMcpConfig, HtMcpError};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+fn admin_server() -> HtMcpServer {
+    HtMcpServer::with_config(HtMcpConfig {
+        enable_admin_tools: true,
+        ..HtMcpConfig::default()
+    })
+}
+
+async fn create_session(server: &HtMcpServer) -> String {
+    let created = server
+        .handle_tool_call("ht_create_session", json!({"command": ["sh"]}))
+        .await
+        .unwrap();
+    created["sessionId"].as_str().unwrap().to_string()
+}
+
+async fn wait_for_shutdown(server: &HtMcpServer, within: Duration) {
+    let mut shutdown = server.shutdown_signal();
+    tokio::time::timeout(within, shutdown.wait_for(|done| *done))
+        .await
+        .expect("server should finish draining")
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_drain_requires_admin_tools() {
+    let server = HtMcpServer::new();
+    let err = server
+        .handle_tool_call("ht_drain", json!({"graceSecs": 1}))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, HtMcpError::InvalidRequest(_)));
+
+    // Not draining, so sessions are still accepted
+    create_session(&server).await;
+}
+
+#[tokio::test]
+async fn test_drain_refuses_new_work_and_closes_the_rest() {
+    let server = admin_server();
+    let mut events = server.events().await.subscribe();
+    let session_id = create_session(&server).await;
+
+    let drained = server
+        .handle_tool_call("ht_drain", json!({"graceSecs": 1}))
+        .await
+        .unwrap();
+    assert_eq!(drained["sessionCount"], 1);
+    assert_eq!(drained["draining"]["exitWhenEmpty"], false);
+
+    let err = server
+        .handle_tool_call("ht_create_session", json!({}))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, HtMcpError::ServerDraining(_)));
+    assert_eq!(err.code(), "server_draining");
+
+    // The existing session keeps working during the grace period
+    server
+        .handle_tool_call(
+            "ht_send_keys",
+            json!({"sessionId": session_id, "keys": ["echo still-here", "Enter"]}),
+        )
+        .await
+        .unwrap();
+    let listed = server
+        .handle_tool_call("ht_list_sessions", json!({}))
+        .await
+        .unwrap();
+    assert_eq!(listed["count"], 1);
+    assert!(listed["draining"].is_object());
+
+    wait_for_shutdown(&server, Duration::from_secs(5)).await;
+    let listed = server
+        .handle_tool_call("ht_list_sessions", json!({}))
+        .await
+        .unwrap();
+    assert_eq!(listed["count"], 0);
+
+    let mut saw_draining = false;
+    let mut closed_reason = None;
+    while let Ok(event) = events.try_recv() {
+        match event {
+            SessionEvent::ServerDraining { session_count, .. } => {
+                assert_eq!(session_count, 1);
+                saw_draining = true;
+            }
+            SessionEvent::SessionClosed {
+                session_id: closed,
+                reason,
+                ..
+            } if closed == session_id => closed_reason = Some(reason),
+            _ => {}
+        }
+    }
+    assert!(saw_draining);
+    assert_eq!(closed_reason, Some(TerminationReason::ServerShutdown));
+}
+
+#[tokio::test]
+async fn test_drain_exits_when_empty() {
+    let server = admin_server();
+    let session_id = create_session(&server).await;
+
+    server
+        .handle_tool_call("ht_drain", json!({"graceSecs": 600, "exitWhenEmpty": true}))
+        .await
+        .unwrap();
+    assert!(
+        !*server.shutdown_signal().borrow(),
+        "a session is still open"
+    );
+
+    let closed: Value = server
+        .handle_tool_call("ht_close_session", json!({"sessionId": session_id}))
+        .await
+        .unwrap();
+    assert_eq!(closed["success"], true);
+
+    wait_for_shutdown(&server, Duration::from_secs(5)).await;
+}
+
+#[tokio::test]
+async fn test_client_disconnect_closes_sessions() {
+    // No admin tools needed: the server shuts itself down
+    let server = HtMcpServer::new();
+    let mut events = server.events().await.subscribe();
+    let session_id = create_session(&server).await;
+
+    server.client_disconnected().await;
+    wait_for_shutdown(&server, Duration::from_secs(5)).await;
+    let listed = server
+        .handle_tool_call("ht_list_sessions", json!({}))
+        .await
+        .unwrap();
+    assert_eq!(listed["count"], 0);
+
+    let mut closed_reason = None;
+    while let Ok(event) = events.try_recv() {
+        if let SessionEvent::SessionClosed {
+            session_id: closed,
+            reason,
+            ..
+        } = event
+        {
+            if closed == session_id {
+                closed_reason = Some(reason);
+            }
+        }
+    }
+    assert_eq!(closed_reason, Some(TerminationReason::ServerShutdown));
+}
+
+#[tokio::test]
+async fn test_client_disconnect_keeps_sessions_for_reconnect_grace() {
+    let server = HtMcpServer::with_config(HtMcpConfig {
+        reconnect_grace_secs: 1,
+        ..HtMcpConfig::default()
+    });
+    let session_id = create_session(&server).await;
+
+    server.client_disconnected().await;
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(!*server.shutdown_signal().borrow());
+    // The session still works, but no new ones start
+    server
+        .handle_tool_call(
+            "ht_send_keys",
+            json!({"sessionId": session_id, "keys": ["echo still-here", "Enter"]}),
+        )
+        .await
+        .unwrap();
+    let err = server
+        .handle_tool_call("ht_create_session", json!({}))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, HtMcpError::ServerDraining(_)));
+
+    wait_for_shutdown(&server, Duration::from_secs(5)).await;
+    let listed = server
+        .handle_tool_call("ht_list_sessions", json!({}))
+        .await
+        .unwrap();
+    assert_eq!(listed["count"], 0);
+}
@@ -161,3 +161,81 @@ async fn test_create_session_tool() {
     // Clean up
     child.kill().expect("Failed to kill child process");
 }
+
+/// Starts the server with a config file `name` holding `config`, creates a
+/// session over stdio, then closes stdin
+fn disconnect_after_session(name: &str, config: &str) -> std::process::Child {
+    let config_path =
+        std::env::temp_dir().join(format!("ht-mcp-{}-{}.toml", name, std::process::id()));
+    std::fs::write(&config_path, config).unwrap();
+    #[allow(clippy::zombie_processes)]
+    let mut child = Command::new("cargo")
+        .args(["run", "--"])
+        .env("HT_MCP_CONFIG", &config_path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("Failed to start ht-mcp server");
+
+    let mut stdin = child.stdin.take().expect("Failed to get stdin");
+    let mut reader = BufReader::new(child.stdout.take().expect("Failed to get stdout"));
+    for request in [
+        json!({
+            "jsonrpc": "2.0", "id": 1, "method": "initialize",
+            "params": {"protocolVersion": "2024-11-05", "capabilities": {}, "clientInfo": {"name": "test", "version": "1.0"}}
+        }),
+        json!({
+            "jsonrpc": "2.0", "id": 2, "method": "tools/call",
+            "params": {"name": "ht_create_session", "arguments": {"command": ["sh"]}}
+        }),
+    ] {
+        let msg = serde_json::to_string(&request).unwrap() + "\n";
+        stdin.write_all(msg.as_bytes()).unwrap();
+        stdin.flush().unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let response: Value = serde_json::from_str(line.trim()).unwrap();
+        assert!(response["error"].is_null(), "{}", response);
+    }
+
+    // The client goes away
+    drop(stdin);
+    let _ = std::fs::remove_file(&config_path);
+    child
+}
+
+fn wait_for_exit(child: &mut std::process::Child, within: std::time::Duration) -> bool {
+    let started = std::time::Instant::now();
+    while started.elapsed() < within {
+        if child.try_wait().unwrap().is_some() {
+            return true;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    false
+}
+
+#[tokio::test]
+async fn test_stdin_eof_shuts_down() {
+    let mut child = disconnect_after_session("eof", "");
+    let exited = wait_for_exit(&mut child, std::time::Duration::from_secs(10));
+    if !exited {
+        child.kill().unwrap();
+    }
+    assert!(exited, "server should exit once stdin is closed");
+}
+
+#[tokio::test]
+async fn test_stdin_eof_keeps_sessions_for_reconnect_grace() {
+    let mut child = disconnect_after_session("eof-grace", "reconnect_grace_secs = 3");
+    assert!(
+        !wait_for_exit(&mut child, std::time::Duration::from_secs(1)),
+        "sessions should outlive the client for the grace period"
+    );
+    let exited = wait_for_exit(&mut child, std::time::Duration::from_secs(10));
+    if !exited {
+        child.kill().unwrap();
+    }
+    assert!(exited, "server should exit after the grace period");
+}
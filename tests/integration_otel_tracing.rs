@@ -0,0 +1,160 @@
+//! Exports spans to an in-process OTLP/HTTP collector stub and checks them.
+//! Run with `cargo test --features otel --test integration_otel_tracing`.
+#![cfg(feature = "otel")]
+
+use ht_mcp::mcp::server::HtMcpServer;
+use ht_mcp::telemetry::{self, TelemetryConfig};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Registry;
+
+#[derive(Debug)]
+struct ExportedSpan {
+    name: String,
+    attributes: HashMap<String, Value>,
+}
+
+/// Minimal OTLP/HTTP JSON collector: accepts `POST /v1/traces` and keeps the
+/// spans it receives
+fn start_collector() -> (String, Arc<Mutex<Vec<ExportedSpan>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    let spans = Arc::new(Mutex::new(Vec::new()));
+    let received = spans.clone();
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" || line.is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+
+            let request: Value = serde_json::from_slice(&body).unwrap();
+            received.lock().unwrap().extend(parse_spans(&request));
+
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: 2\r\n\r\n{}")
+                .unwrap();
+        }
+    });
+
+    (endpoint, spans)
+}
+
+fn parse_spans(request: &Value) -> Vec<ExportedSpan> {
+    let mut spans = Vec::new();
+    for resource_spans in request["resourceSpans"].as_array().into_iter().flatten() {
+        for scope_spans in resource_spans["scopeSpans"]
+            .as_array()
+            .into_iter()
+            .flatten()
+        {
+            for span in scope_spans["spans"].as_array().into_iter().flatten() {
+                let attributes = span["attributes"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .map(|attribute| {
+                        let value = &attribute["value"];
+                        let value = value
+                            .get("stringValue")
+                            .or_else(|| value.get("intValue"))
+                            .cloned()
+                            .unwrap_or(Value::Null);
+                        (attribute["key"].as_str().unwrap().to_string(), value)
+                    })
+                    .collect();
+                spans.push(ExportedSpan {
+                    name: span["name"].as_str().unwrap().to_string(),
+                    attributes,
+                });
+            }
+        }
+    }
+    spans
+}
+
+#[tokio::test]
+async fn test_exports_tool_call_and_session_spans() {
+    let (endpoint, spans) = start_collector();
+    let config = TelemetryConfig {
+        otlp_endpoint: Some(endpoint),
+        service_name: Some("ht-mcp-test".to_string()),
+        sampling_ratio: 1.0,
+    };
+    let (layer, telemetry) = telemetry::init::<Registry>(&config)
+        .unwrap()
+        .expect("an endpoint is configured");
+    let _guard = tracing::subscriber::set_default(Registry::default().with(layer));
+
+    let server = HtMcpServer::new();
+    let created = server
+        .handle_tool_call("ht_create_session", json!({}))
+        .await
+        .unwrap();
+    let session_id = created["sessionId"].as_str().unwrap().to_string();
+    server
+        .handle_tool_call(
+            "ht_execute_command",
+            json!({"sessionId": session_id, "command": "true"}),
+        )
+        .await
+        .unwrap();
+    server
+        .handle_tool_call("ht_close_session", json!({"sessionId": session_id}))
+        .await
+        .unwrap();
+    server
+        .handle_tool_call("ht_close_session", json!({"sessionId": "missing"}))
+        .await
+        .unwrap_err();
+
+    tokio::task::spawn_blocking(move || telemetry.shutdown())
+        .await
+        .unwrap();
+
+    let spans = spans.lock().unwrap();
+    let named = |name: &str| -> Vec<&ExportedSpan> {
+        spans.iter().filter(|span| span.name == name).collect()
+    };
+
+    for tool in ["ht_create_session", "ht_execute_command"] {
+        let span = named(tool)[0];
+        assert_eq!(span.attributes["tool.name"], json!(tool));
+        assert_eq!(span.attributes["session.id"], json!(session_id));
+        assert!(span.attributes.contains_key("duration_ms"));
+        assert!(!span.attributes.contains_key("error.code"));
+    }
+
+    let closes = named("ht_close_session");
+    assert_eq!(closes.len(), 2);
+    assert!(closes
+        .iter()
+        .any(|span| span.attributes["session.id"] == json!(session_id)));
+    assert!(closes.iter().any(|span| {
+        span.attributes["session.id"] == json!("missing")
+            && span.attributes["error.code"] == json!("session_not_found")
+    }));
+
+    let sessions = named("session");
+    assert_eq!(sessions.len(), 1, "the session span ends on close");
+    assert_eq!(sessions[0].attributes["session.id"], json!(session_id));
+}